@@ -0,0 +1,271 @@
+//! assetname.rs -- names of impostor assets.
+//! Part of the Animats impostor system
+//!
+//! Impostor assets are self-identifying from their names. The generator
+//! writes the name, it becomes the inventory name in SL/OS, and the in-world
+//! uploader sends it back so the server knows which tile the asset is for.
+//!
+//! Format:
+//!
+//!     RS_tag_x_y_sx_sy_sz_offset_lod_vizgroup_waterlevel_hash
+//!
+//! The prefix is the kind of asset: RS sculpt, RM mesh, RTn base texture n,
+//! REn emissive texture n, RA texture atlas. The tag is a short form of the grid name.
+//!
+//! An atlas is shared by a block of tiles. Its name has the location of the
+//! block, the size of one tile, and the mask of slots used. An atlas has no
+//! heights, so the mask takes the place of the height range in the name.
+//! Parsed, it is in its own field, and the height range is zero.
+//! Older names have no tag. Both forms parse.
+//!
+//! SL inventory names are limited to 63 characters. Names which would be
+//! longer are shortened, always the same way: first the grid tag, then the
+//! precision of the scale, offset, and water level.
+//!
+//! Shared water tile assets have no location, and are named separately.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use crate::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// What the asset is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    /// Geometry as a sculpt texture
+    Sculpt,
+    /// Geometry as a mesh
+    Mesh,
+    /// Base color texture, by face
+    Texture(u8),
+    /// Emissive texture, by face
+    Emissive(u8),
+    /// Base color textures of a block of tiles. See textureatlas.
+    Atlas,
+}
+
+impl AssetKind {
+    /// Name prefix. Face numbers are one digit.
+    pub(crate) fn prefix(&self) -> String {
+        match self {
+            AssetKind::Sculpt => "RS".to_string(),
+            AssetKind::Mesh => "RM".to_string(),
+            AssetKind::Texture(n) => format!("RT{}", n),
+            AssetKind::Emissive(n) => format!("RE{}", n),
+            AssetKind::Atlas => "RA".to_string(),
+        }
+    }
+
+    /// From name prefix. Valid prefix values are RS, RM, RTn, REn, and RA.
+    fn new_from_prefix(prefix: &str) -> Result<Self, Error> {
+        let face = || -> Result<u8, Error> {
+            match prefix[2..].parse() {
+                Ok(n) if prefix.len() == 3 => Ok(n),
+                _ => Err(Error::BadRequest(format!("Invalid face number in asset name prefix: {}", prefix))),
+            }
+        };
+        match prefix.get(0..2) {
+            Some("RS") if prefix.len() == 2 => Ok(AssetKind::Sculpt),
+            Some("RM") if prefix.len() == 2 => Ok(AssetKind::Mesh),
+            Some("RA") if prefix.len() == 2 => Ok(AssetKind::Atlas),
+            Some("RT") => Ok(AssetKind::Texture(face()?)),
+            Some("RE") => Ok(AssetKind::Emissive(face()?)),
+            _ => Err(Error::BadRequest(format!("Invalid asset name prefix: {}", prefix))),
+        }
+    }
+}
+
+/// Everything encoded in an impostor asset name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetName {
+    /// What the asset is
+    pub kind: AssetKind,
+    /// Short form of grid name, lowercase letters and digits. May be empty.
+    pub grid_tag: String,
+    /// Position of tile in world, meters.
+    pub region_loc: [u32; 2],
+    /// Size of tile, meters.
+    pub region_size: [u32; 2],
+    /// Height range of terrain, meters.
+    pub scale_z: f32,
+    /// Lowest terrain height, meters.
+    pub offset: f32,
+    /// Impostor LOD. 0 is highest level of detail.
+    pub lod: u8,
+    /// Visibility group
+    pub viz_group: u32,
+    /// Water level, meters.
+    pub water_level: f32,
+    /// Hash of asset content
+    pub hash: u32,
+    /// Atlas slots in use. Zero for other kinds.
+    pub slot_mask: u16,
+}
+
+impl AssetName {
+    /// Longest inventory name SL allows.
+    pub const MAX_LEN: usize = 63;
+    /// Longest grid tag.
+    pub const GRID_TAG_LEN: usize = 6;
+
+    /// Grid tag for a grid name.
+    pub fn grid_tag(grid: &str) -> String {
+        grid.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_lowercase())
+            .take(Self::GRID_TAG_LEN)
+            .collect()
+    }
+
+    /// Shorten to fit in MAX_LEN, if necessary.
+    /// Heights are always rounded to two decimal places.
+    pub fn fit(mut self) -> Result<Self, Error> {
+        let round = |v: f32, decimals: i32| {
+            let m = 10.0f32.powi(decimals);
+            (v * m).round() / m
+        };
+        for decimals in (0..=2).rev() {
+            self.scale_z = round(self.scale_z, decimals);
+            self.offset = round(self.offset, decimals);
+            self.water_level = round(self.water_level, decimals);
+            while self.to_string().len() > Self::MAX_LEN && !self.grid_tag.is_empty() {
+                self.grid_tag.pop();
+            }
+            if self.to_string().len() <= Self::MAX_LEN {
+                return Ok(self);
+            }
+        }
+        Err(Error::OutOfRange(format!("Asset name is too long: {}", self)))
+    }
+}
+
+impl fmt::Display for AssetName {
+    /// Name as used in SL/OS. Heights are printed as short as they will parse back exactly.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_", self.kind.prefix())?;
+        if !self.grid_tag.is_empty() {
+            write!(f, "{}_", self.grid_tag)?;
+        }
+        write!(f, "{}_{}_{}_{}_", self.region_loc[0], self.region_loc[1], self.region_size[0], self.region_size[1])?;
+        if self.kind == AssetKind::Atlas {
+            write!(f, "{}_", self.slot_mask)?;
+        } else {
+            write!(f, "{}_", self.scale_z)?;
+        }
+        write!(f, "{}_{}_{}_{}_{:08x}", self.offset, self.lod, self.viz_group, self.water_level, self.hash)
+    }
+}
+
+impl FromStr for AssetName {
+    type Err = Error;
+
+    /// Parse a name. Accepts names with and without a grid tag.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        //  Without tag, 11 fields. With tag, 12.
+        const FIELD_COUNT: usize = 11;
+        let mut fields: Vec<&str> = s.split('_').collect();
+        let grid_tag = match fields.len() {
+            FIELD_COUNT => String::new(),
+            n if n == FIELD_COUNT + 1 => {
+                let tag = fields.remove(1);
+                if tag.is_empty() || tag.len() > Self::GRID_TAG_LEN || tag != Self::grid_tag(tag) {
+                    return Err(Error::BadRequest(format!("Invalid grid tag in asset name: {}", s)));
+                }
+                tag.to_string()
+            }
+            _ => return Err(Error::BadRequest(format!("Asset name did not contain {} fields: {}", FIELD_COUNT, s))),
+        };
+        fn field<T: FromStr>(s: &str, fields: &[&str], n: usize) -> Result<T, Error> {
+            fields[n].parse().map_err(|_| Error::BadRequest(format!("Invalid field {} in asset name: {}", n, s)))
+        }
+        let hash = u32::from_str_radix(fields[10], 16)
+            .map_err(|_| Error::BadRequest(format!("Invalid hash in asset name: {}", s)))?;
+        let kind = AssetKind::new_from_prefix(fields[0])?;
+        let (scale_z, slot_mask) = if kind == AssetKind::Atlas {
+            (0.0, field(s, &fields, 5)?)
+        } else {
+            (field(s, &fields, 5)?, 0)
+        };
+        Ok(Self {
+            kind,
+            grid_tag,
+            region_loc: [field(s, &fields, 1)?, field(s, &fields, 2)?],
+            region_size: [field(s, &fields, 3)?, field(s, &fields, 4)?],
+            scale_z,
+            offset: field(s, &fields, 6)?,
+            lod: field(s, &fields, 7)?,
+            viz_group: field(s, &fields, 8)?,
+            water_level: field(s, &fields, 9)?,
+            hash,
+            slot_mask,
+        })
+    }
+}
+
+#[test]
+fn test_asset_name() {
+    let name = AssetName {
+        kind: AssetKind::Sculpt,
+        grid_tag: AssetName::grid_tag("Agni"),
+        region_loc: [462592, 306944],
+        region_size: [256, 256],
+        scale_z: 35.118,
+        offset: 20.5,
+        lod: 1,
+        viz_group: 3,
+        water_level: 20.0,
+        hash: 0x00ab12cd,
+        slot_mask: 0,
+    }.fit().expect("fit");
+    assert_eq!(name.to_string(), "RS_agni_462592_306944_256_256_35.12_20.5_1_3_20_00ab12cd");
+    //  All kinds round trip, and one region's assets all have different names.
+    let kinds = [AssetKind::Sculpt, AssetKind::Mesh, AssetKind::Texture(0), AssetKind::Texture(7), AssetKind::Emissive(3), AssetKind::Atlas];
+    //  An atlas has a slot mask instead of a height range.
+    let of_kind = |kind: AssetKind| match kind {
+        AssetKind::Atlas => AssetName { kind, scale_z: 0.0, slot_mask: 0x0f0f, ..name.clone() },
+        _ => AssetName { kind, ..name.clone() },
+    };
+    let names: Vec<String> = kinds.iter().map(|kind| of_kind(*kind).to_string()).collect();
+    for (kind, s) in kinds.iter().zip(&names) {
+        let parsed: AssetName = s.parse().expect("parse");
+        assert_eq!(parsed.kind, *kind);
+        assert_eq!(parsed, of_kind(*kind));
+        assert_eq!(&parsed.to_string(), s);
+    }
+    assert_eq!(names[5], "RA_agni_462592_306944_256_256_3855_20.5_1_3_20_00ab12cd");
+    assert_eq!(names.iter().collect::<std::collections::HashSet<_>>().len(), kinds.len());
+    //  Older names, without a tag, still parse.
+    let old: AssetName = "RT0_462592_306944_256_256_35.12_20.50_1_3_20.00_00ab12cd".parse().expect("old name");
+    assert_eq!(old, AssetName { kind: AssetKind::Texture(0), grid_tag: String::new(), ..name.clone() });
+    //  Long grid names are cut to the tag length.
+    assert_eq!(AssetName::grid_tag("Kitely Market Grid"), "kitely");
+    //  Huge varregion far out, with many decimals, is shortened, and still round trips.
+    let long = AssetName {
+        kind: AssetKind::Emissive(9),
+        grid_tag: AssetName::grid_tag("osgrid.org"),
+        region_loc: [16777216, 16777216],
+        region_size: [8192, 8192],
+        scale_z: 4095.999,
+        offset: -123.456,
+        lod: 9,
+        viz_group: 999,
+        water_level: -123.456,
+        hash: 0xffffffff,
+        slot_mask: 0,
+    }.fit().expect("fit long");
+    let s = long.to_string();
+    assert!(s.len() <= AssetName::MAX_LEN, "{} is {} long", s, s.len());
+    assert_eq!(s.parse::<AssetName>().expect("parse long"), long);
+    assert_eq!(long.clone().fit().expect("refit"), long);
+    //  Past that, it's an error.
+    assert!(matches!(AssetName { region_loc: [u32::MAX, u32::MAX], ..long }.fit(), Err(Error::OutOfRange(_))));
+    //  Bad names
+    for bad in ["", "RS_1_2", "RX_1_2_256_256_1_0_0_0_20_00000000", "RT_1_2_256_256_1_0_0_0_20_00000000",
+        "RS_Agni_1_2_256_256_1_0_0_0_20_00000000", "RA0_1_2_256_256_1_0_0_0_20_00000000", "RS_1_2_256_256_1_0_0_0_20_nothex", "RS_1_2_256_256_x_0_0_0_20_00000000",
+        "RA_1_2_256_256_1.5_0_0_0_0_00000000", "RA_1_2_256_256_65536_0_0_0_0_00000000"] {
+        assert!(matches!(bad.parse::<AssetName>(), Err(Error::BadRequest(_))), "{}", bad);
+    }
+}
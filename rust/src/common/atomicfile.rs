@@ -0,0 +1,169 @@
+//! atomicfile.rs -- write output files so they're either complete or not there.
+//! Part of the Animats impostor system
+//!
+//! Generated assets are uploaded by name, and their hashes go into the
+//! database. A crash partway through writing one would leave a truncated
+//! file with a good name. So files are written to "NAME.tmp.PID", synced,
+//! and renamed into place. Rename within a directory is atomic, so
+//! another process, or another thread writing the same directory, sees
+//! the old file or the new one, never part of one.
+//!
+//! The content is hashed as it's written, so the hash costs no second pass.
+//!
+//! Temporary files left by a crash are removed by remove_stale_tmp_files
+//! when the generator starts.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::Error;
+use image::RgbImage;
+use image::codecs::png::PngEncoder;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Marks a temporary file. Followed by the process ID.
+const TMP_MARKER: &str = ".tmp.";
+
+/// A file written into place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrittenFile {
+    /// Final path
+    pub path: PathBuf,
+    /// SHA-256 of the content, as lowercase hex. Same as hashing::hash_file on the final file.
+    pub hash: String,
+    /// Bytes written
+    pub len: u64,
+}
+
+/// Passes writes through, hashing them.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Temporary path for writing this file, in the same directory, so the rename is atomic.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!("{}{}", TMP_MARKER, std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Write a file atomically. write_content writes the whole content.
+/// On any failure, the temporary file is removed and nothing is at path that wasn't there before.
+pub fn write_atomic(path: &Path, write_content: impl FnOnce(&mut dyn Write) -> Result<(), Error>) -> Result<WrittenFile, Error> {
+    let tmp = tmp_path(path);
+    let result = (|| -> Result<WrittenFile, Error> {
+        let file = std::fs::File::create(&tmp)?;
+        let mut writer = HashingWriter { inner: std::io::BufWriter::new(file), hasher: Sha256::new(), len: 0 };
+        write_content(&mut writer)?;
+        writer.flush()?;
+        let HashingWriter { inner, hasher, len } = writer;
+        let file = inner.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp, path)?;
+        Ok(WrittenFile { path: path.to_path_buf(), hash: hex::encode(hasher.finalize()), len })
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Write bytes to a file atomically.
+pub fn write_bytes_atomic(path: &Path, bytes: &[u8]) -> Result<WrittenFile, Error> {
+    write_atomic(path, |w| Ok(w.write_all(bytes)?))
+}
+
+/// Save an image as PNG, atomically.
+pub fn save_png_atomic(path: &Path, image: &RgbImage) -> Result<WrittenFile, Error> {
+    write_atomic(path, |w| {
+        image.write_with_encoder(PngEncoder::new(w)).map_err(|e| match e {
+            image::ImageError::IoError(e) => Error::Io(e),
+            e => Error::Io(std::io::Error::other(e)),
+        })
+    })
+}
+
+/// Remove temporary files left in this directory by interrupted writes. Returns how many.
+/// Only names ending in ".tmp." and a process ID are touched.
+pub fn remove_stale_tmp_files(dir: &Path) -> Result<usize, Error> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_tmp = name.rsplit_once(TMP_MARKER)
+            .is_some_and(|(base, pid)| !base.is_empty() && !pid.is_empty() && pid.chars().all(|c| c.is_ascii_digit()));
+        if is_tmp && entry.file_type()?.is_file() {
+            std::fs::remove_file(entry.path())?;
+            log::warn!("Removed \"{}\", left by an interrupted write.", entry.path().display());
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[test]
+fn test_write_atomic() {
+    use crate::hashing::{hash_bytes, hash_file};
+    let dir = std::env::temp_dir().join(format!("atomicfile_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    //  The hash returned is the hash of what's in the file.
+    let path = dir.join("asset.bin");
+    let written = write_bytes_atomic(&path, b"abc").expect("write");
+    assert_eq!(written.hash, hash_bytes(b"abc"));
+    assert_eq!(written.hash, hash_file(&path).unwrap());
+    assert_eq!(written.len, 3);
+    assert!(!tmp_path(&path).exists());
+    //  PNGs too.
+    let image = RgbImage::from_fn(8, 8, |x, y| image::Rgb([x as u8 * 30, y as u8 * 30, 128]));
+    let png_path = dir.join("texture.png");
+    let written = save_png_atomic(&png_path, &image).expect("png");
+    assert_eq!(written.hash, hash_file(&png_path).unwrap());
+    assert_eq!(image::open(&png_path).unwrap().to_rgb8(), image);
+    //  A failed write leaves the old file alone, and no temporary file.
+    let failed = write_atomic(&path, |w| {
+        w.write_all(b"partial")?;
+        Err(Error::BadRequest("interrupted".to_string()))
+    });
+    assert!(failed.is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+    assert!(!tmp_path(&path).exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_remove_stale_tmp_files() {
+    let dir = std::env::temp_dir().join(format!("atomicfile_stale_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    //  A crash partway through leaves the temporary file.
+    std::fs::write(dir.join("RS_agni_0_0.png.tmp.12345"), b"trunc").unwrap();
+    std::fs::write(tmp_path(&dir.join("route.json")), b"{").unwrap();
+    //  Real output, and names which only look a bit like temporary files, stay.
+    for keep in ["RS_agni_0_0.png", "notes.tmp.txt", ".tmp.123", "x.tmp."] {
+        std::fs::write(dir.join(keep), b"keep").unwrap();
+    }
+    assert_eq!(remove_stale_tmp_files(&dir).unwrap(), 2);
+    let mut left: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+    left.sort();
+    assert_eq!(left, vec![".tmp.123", "RS_agni_0_0.png", "notes.tmp.txt", "x.tmp."]);
+    assert_eq!(remove_stale_tmp_files(&dir).unwrap(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
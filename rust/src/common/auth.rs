@@ -7,12 +7,34 @@
 //! Later processing turns that into objects viewable in world via the
 //! region impostor system.
 //!
+//! Uploads are signed. The LSL script sends two headers:
+//!
+//!     X-Authtoken-Name: UPLOADER_1
+//!     X-Authtoken-Hash: hex SHA-256 of (secret || body)
+//!
+//! The secret for each token name is in the credentials file,
+//! as AUTH_UPLOADER_1 = secret. This is the scheme eventlogger used, with SHA-256.
+//!
+//! A GET has no body, so requests that need authorization sign the
+//! query string instead, exactly as sent, after the "?".
+//!
+//! Admin actions, such as deleting an impostor, need an admin token. Its secret
+//! is ADMIN_AUTH_name = secret, so no upload token can sign one, and an admin
+//! token can't sign uploads.
+//!
+//! Open Simulator sends the same X-SecondLife headers, but the shard is
+//! the grid's own name. Region coordinates are only unique within a grid,
+//! so uploads must say which grid they are for, and that must match the
+//! shard the request came from.
+//!
 //! License: LGPL.
 //! Animats
 //! August, 2025.
 //
 use anyhow::{Error, anyhow};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use crate::Request;
+use crate::grid::canonical;
 /*
 use common::Credentials;
 use common::init_fcgi;
@@ -25,9 +47,26 @@ use mysql::{PooledConn, params};
 
 */
 
-/// Environment variables for obtaining owner info.
-/// ***ADD VALUES FOR OPEN SIMULATOR***
-const OWNER_NAME_PARAMS: [&str;1] = ["HTTP_X_SECONDLIFE_OWNER_NAME"];
+/// Headers for obtaining owner info. Second Life, then Open Simulator variants.
+const OWNER_NAME_HEADERS: [&str;2] = ["X-SecondLife-Owner-Name", "X-OpenSim-Owner-Name"];
+/// Header with the grid the request came from.
+const SHARD_HEADER: &str = "X-SecondLife-Shard";
+/// Header with the region the request came from, "Name (x, y)", in meters.
+const REGION_HEADER: &str = "X-SecondLife-Region";
+/// Second Life shard names, and the grid names used in uploads.
+const SL_SHARDS: [(&str, &str);2] = [("production", "agni"), ("testing", "aditi")];
+/// Last name given to Second Life users who only have a user name.
+const DEFAULT_LAST_NAME: &str = "Resident";
+/// Header with the name of the signing token.
+const AUTH_TOKEN_NAME_HEADER: &str = "X-Authtoken-Name";
+/// Header with the signature, hex SHA-256 of (secret || body).
+const AUTH_TOKEN_HASH_HEADER: &str = "X-Authtoken-Hash";
+/// Credentials key prefix for token secrets.
+const AUTH_SECRET_PREFIX: &str = "AUTH_";
+/// Credentials key prefix for admin token secrets.
+const ADMIN_AUTH_SECRET_PREFIX: &str = "ADMIN_AUTH_";
+/// Longest token name accepted.
+const MAX_TOKEN_NAME_LEN: usize = 63;
 
 
 pub enum AuthorizeType {
@@ -35,19 +74,316 @@ pub enum AuthorizeType {
     UploadTerrain,
     /// Upload impostors. Can add and upload impostor data.
     UploadImpostors,
+    /// List retired assets. The list drives deleting them.
+    RetiredAssets,
+    /// Delete an impostor. Admin tokens only.
+    AdminDelete,
+    /// Read operator-only debug data, such as impostor provenance. Admin tokens only.
+    Debug,
+}
+
+impl AuthorizeType {
+    /// Credentials key prefix for secrets of tokens which can do this.
+    fn secret_prefix(&self) -> &'static str {
+        match self {
+            AuthorizeType::AdminDelete | AuthorizeType::Debug => ADMIN_AUTH_SECRET_PREFIX,
+            _ => AUTH_SECRET_PREFIX,
+        }
+    }
 }
 
 pub struct Authorizer {
 }
 
+/// Where a request came from, from the headers the simulator adds.
+/// Everything is optional, because requests from outside a simulator have none of it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RequestOrigin {
+    /// Owner of the object, canonical form
+    pub owner_name: Option<String>,
+    /// Shard, as sent
+    pub shard: Option<String>,
+    /// Region name
+    pub region_name: Option<String>,
+    /// Region corner, meters
+    pub region_corner: Option<[u32;2]>,
+}
+
+impl RequestOrigin {
+    /// Origin from the request headers.
+    pub fn new_from_request(request: &Request) -> Self {
+        let owner_name = OWNER_NAME_HEADERS.iter()
+            .find_map(|&h| request.header(h))
+            .map(|s| canonical_owner_name(s))
+            .filter(|s| !s.is_empty());
+        let shard = request.header(SHARD_HEADER).map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+        let region = request.header(REGION_HEADER).and_then(parse_region_header);
+        Self {
+            owner_name,
+            shard,
+            region_name: region.as_ref().map(|r| r.0.clone()),
+            region_corner: region.map(|r| r.1),
+        }
+    }
+
+    /// Grid name for the shard, as used in uploads.
+    /// Second Life's shards have grid names. Open Simulator's shard is the grid name.
+    pub fn grid(&self) -> Option<String> {
+        let shard = self.shard.as_ref()?.to_lowercase();
+        Some(SL_SHARDS.iter().find(|(s, _)| *s == shard).map(|(_, g)| g.to_string()).unwrap_or(shard))
+    }
+
+    /// Check the grid an upload says it is for against the shard it came from.
+    /// With no shard header, there's nothing to check against.
+    pub fn check_grid(&self, grid: &str) -> Result<(), Error> {
+        match self.grid() {
+            Some(origin_grid) if origin_grid != canonical(grid) => {
+                log::warn!("Upload for grid \"{}\" came from shard {:?}, region {:?}", grid, self.shard, self.region_name);
+                Err(anyhow!("Upload is for grid \"{}\" but came from grid \"{}\"", grid, origin_grid))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Authorizer {
-    /// External caller requests permission to do something.
-    pub fn authorize(auth_type: AuthorizeType, env: &HashMap<String, String>, params: &HashMap<String, String>) -> Result<String, Error> {
-        if let Some(owner_name) =  OWNER_NAME_PARAMS.iter().find_map(|&s| params.get(s)) {
-            log::info!("Request is from an object owned by {}", owner_name);
-            Ok(owner_name.trim().to_string())   
-        } else {
-            Err(anyhow!("This request is not from Second Life/Open Simulator"))
+    /// External caller requests permission to do something, with a signed body.
+    /// Secrets are looked up by key, AUTH_ followed by the token name, or ADMIN_AUTH_ for admin actions.
+    /// Returns the owner name, or the token name if there is no owner.
+    pub fn authorize_signed(auth_type: AuthorizeType, request: &Request, secrets: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+        Self::authorize_signed_over(auth_type, request, &request.standard_input, secrets)
+    }
+
+    /// External caller requests permission to do something, with a signed query string.
+    /// For GET requests, which have no body to sign.
+    pub fn authorize_signed_query(auth_type: AuthorizeType, request: &Request, secrets: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+        let query_string = request.params.as_ref().and_then(|p| p.get("QUERY_STRING")).map(|s| s.as_str()).unwrap_or("");
+        Self::authorize_signed_over(auth_type, request, query_string.as_bytes(), secrets)
+    }
+
+    /// Name of the token a request is signed with, upper case, if any.
+    /// For keeping per-token state after authorize_signed has checked it.
+    pub fn token_name(request: &Request) -> Option<String> {
+        request.header(AUTH_TOKEN_NAME_HEADER).map(|s| s.trim().to_uppercase()).filter(|s| !s.is_empty())
+    }
+
+    /// Signature for signed bytes, as a client sends it in X-Authtoken-Hash.
+    /// For tools and tests which play the part of the LSL script.
+    pub fn sign(secret: &str, signed: &[u8]) -> String {
+        hex::encode(hash_with_secret(secret.as_bytes(), signed))
+    }
+
+    /// Authorize with a signature over these bytes.
+    fn authorize_signed_over(auth_type: AuthorizeType, request: &Request, signed: &[u8], secrets: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+        let token_name = request.header(AUTH_TOKEN_NAME_HEADER).map(|s| s.trim());
+        match Self::check_signature(auth_type.secret_prefix(), token_name, request, signed, secrets) {
+            Ok(token_name) => {
+                let owner_name = RequestOrigin::new_from_request(request).owner_name;
+                log::info!("{} authorized by token \"{}\", owner {:?}", auth_type, token_name, owner_name);
+                Ok(owner_name.unwrap_or(token_name))
+            }
+            Err(e) => {
+                //  Token name only. Never the secret.
+                log::warn!("{} rejected, token {:?}: {}", auth_type, token_name, e);
+                Err(e)
+            }
         }
     }
+
+    /// Check the signature headers against the signed bytes. Returns the token name.
+    fn check_signature(secret_prefix: &str, token_name: Option<&str>, request: &Request, signed: &[u8], secrets: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+        let token_name = token_name.ok_or_else(|| anyhow!("No {} header", AUTH_TOKEN_NAME_HEADER))?;
+        let hash_sent = request.header(AUTH_TOKEN_HASH_HEADER).ok_or_else(|| anyhow!("No {} header", AUTH_TOKEN_HASH_HEADER))?;
+        if token_name.is_empty() || token_name.len() > MAX_TOKEN_NAME_LEN || !token_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(anyhow!("Authorization token name is not valid"));
+        }
+        let secret = secrets(&format!("{}{}", secret_prefix, token_name.to_uppercase()))
+            .ok_or_else(|| anyhow!("Authorization token \"{}\" not recognized", token_name))?;
+        let hash_sent = hex::decode(hash_sent.trim()).map_err(|_| anyhow!("{} is not hex", AUTH_TOKEN_HASH_HEADER))?;
+        if !constant_time_eq(&hash_with_secret(secret.as_bytes(), signed), &hash_sent) {
+            return Err(anyhow!("Authorization token \"{}\" failed to validate", token_name));
+        }
+        Ok(token_name.to_string())
+    }
+}
+
+impl std::fmt::Display for AuthorizeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthorizeType::UploadTerrain => write!(f, "Terrain upload"),
+            AuthorizeType::UploadImpostors => write!(f, "Impostor upload"),
+            AuthorizeType::RetiredAssets => write!(f, "Retired asset list"),
+            AuthorizeType::AdminDelete => write!(f, "Impostor deletion"),
+            AuthorizeType::Debug => write!(f, "Debug download"),
+        }
+    }
+}
+
+/// Parse the region header, "Name (x, y)", coordinates in meters.
+fn parse_region_header(s: &str) -> Option<(String, [u32;2])> {
+    let (name, coords) = s.trim().rsplit_once('(')?;
+    let (x, y) = coords.strip_suffix(')')?.split_once(',')?;
+    //  Coordinates are sometimes sent as floats.
+    let coord = |c: &str| c.trim().parse::<f64>().ok().filter(|v| *v >= 0.0 && *v <= u32::MAX as f64).map(|v| v as u32);
+    Some((name.trim().to_string(), [coord(x)?, coord(y)?]))
+}
+
+/// One canonical form for owner names, for the creator column.
+/// "joe.smith", "Joe Smith", and "JOE  SMITH" are all "Joe Smith".
+/// A user name alone, "joe", is "Joe Resident", as Second Life shows it.
+/// An Open Simulator hypergrid suffix, "@grid.example.com:8002", is kept, in lower case.
+pub fn canonical_owner_name(name: &str) -> String {
+    let (name, home) = match name.trim().split_once('@') {
+        Some((name, home)) => (name, Some(home.trim().to_lowercase())),
+        None => (name.trim(), None),
+    };
+    let capitalize = |s: &str| {
+        let mut chars = s.chars();
+        chars.next().map(|c| c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect::<String>()).unwrap_or_default()
+    };
+    let mut parts: Vec<String> = name.split(|c: char| c == '.' || c.is_whitespace()).filter(|s| !s.is_empty()).map(capitalize).collect();
+    if parts.len() == 1 {
+        parts.push(DEFAULT_LAST_NAME.to_string());
+    }
+    let canonical = parts.join(" ");
+    match home {
+        Some(home) if !home.is_empty() => format!("{}@{}", canonical, home),
+        _ => canonical,
+    }
+}
+
+/// SHA-256 of secret followed by body.
+fn hash_with_secret(secret: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    hasher.update(body);
+    hasher.finalize().to_vec()
+}
+
+/// Compare without stopping at the first difference, so timing doesn't reveal the hash.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Request with these params and body, as the FCGI reader builds it.
+#[cfg(test)]
+fn test_request(pairs: &[(&str, &str)], body: &[u8]) -> Request {
+    let mut request = Request::new();
+    request.params = Some(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+    request.standard_input = body.to_vec();
+    request
+}
+
+#[test]
+fn test_authorize_signed() {
+    let secrets = |k: &str| if k == "AUTH_UPLOADER_1" { Some("sekrit".to_string()) } else { None };
+    let body = br#"{"name":"Vallone"}"#;
+    let good_hash = hex::encode(hash_with_secret(b"sekrit", body));
+    let params = |pairs: &[(&str, &str)]| test_request(pairs, body);
+    //  Known value, SHA-256 of "abc".
+    assert_eq!(hex::encode(hash_with_secret(b"ab", b"c")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    //  Good hash, headers as FCGI delivers them.
+    let good = params(&[("HTTP_X_AUTHTOKEN_NAME", "UPLOADER_1"), ("HTTP_X_AUTHTOKEN_HASH", &good_hash), ("HTTP_X_SECONDLIFE_OWNER_NAME", "Joe Resident ")]);
+    assert_eq!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &good, secrets).expect("good hash"), "Joe Resident");
+    //  Lower case params, upper case hex, and no owner.
+    let plain = params(&[("http_x_authtoken_name", "UPLOADER_1"), ("http_x_authtoken_hash", &good_hash.to_uppercase())]);
+    assert_eq!(Authorizer::authorize_signed(AuthorizeType::UploadImpostors, &plain, secrets).expect("header names"), "UPLOADER_1");
+    //  Wrong hash, or right hash for another body.
+    let wrong_hash = hex::encode(hash_with_secret(b"guess", body));
+    let wrong = params(&[("HTTP_X_AUTHTOKEN_NAME", "UPLOADER_1"), ("HTTP_X_AUTHTOKEN_HASH", &wrong_hash)]);
+    let msg = Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &wrong, secrets).err().expect("wrong hash accepted").to_string();
+    assert!(!msg.contains("sekrit"), "{}", msg);
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &test_request(&[("HTTP_X_AUTHTOKEN_NAME", "UPLOADER_1"), ("HTTP_X_AUTHTOKEN_HASH", &good_hash)], b"{}"), secrets).is_err());
+    let not_hex = params(&[("HTTP_X_AUTHTOKEN_NAME", "UPLOADER_1"), ("HTTP_X_AUTHTOKEN_HASH", "xyzzy")]);
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &not_hex, secrets).is_err());
+    //  Unknown token name, or one that tries to reach another key.
+    for name in ["UPLOADER_2", "", "../DB_PASS", "DB PASS"] {
+        let unknown = params(&[("HTTP_X_AUTHTOKEN_NAME", name), ("HTTP_X_AUTHTOKEN_HASH", &good_hash)]);
+        assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &unknown, secrets).is_err(), "{}", name);
+    }
+    //  Missing headers. Owner name alone is no longer enough.
+    let owner_only = params(&[("HTTP_X_SECONDLIFE_OWNER_NAME", "Joe Resident")]);
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &owner_only, secrets).is_err());
+    let no_hash = params(&[("HTTP_X_AUTHTOKEN_NAME", "UPLOADER_1")]);
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &no_hash, secrets).is_err());
+    //  Only equal bytes compare equal.
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+}
+
+#[test]
+fn test_authorize_signed_query() {
+    let secrets = |k: &str| if k == "AUTH_CLEANUP_1" { Some("sekrit".to_string()) } else { None };
+    let query = "grid=agni&mode=retired&since=2026-03-01";
+    let good_hash = hex::encode(hash_with_secret(b"sekrit", query.as_bytes()));
+    let request = |q: &str, hash: &str| test_request(&[("QUERY_STRING", q), ("HTTP_X_AUTHTOKEN_NAME", "CLEANUP_1"), ("HTTP_X_AUTHTOKEN_HASH", hash)], b"");
+    assert_eq!(Authorizer::authorize_signed_query(AuthorizeType::RetiredAssets, &request(query, &good_hash), secrets).unwrap(), "CLEANUP_1");
+    //  Another query with the same signature.
+    assert!(Authorizer::authorize_signed_query(AuthorizeType::RetiredAssets, &request("grid=agni&mode=retired", &good_hash), secrets).is_err());
+    //  A signed body doesn't sign the query.
+    assert!(Authorizer::authorize_signed(AuthorizeType::RetiredAssets, &request(query, &good_hash), secrets).is_err());
+    let no_query = test_request(&[("HTTP_X_AUTHTOKEN_NAME", "CLEANUP_1"), ("HTTP_X_AUTHTOKEN_HASH", &good_hash)], b"");
+    assert!(Authorizer::authorize_signed_query(AuthorizeType::RetiredAssets, &no_query, secrets).is_err());
+}
+
+#[test]
+fn test_authorize_admin() {
+    let secrets = |k: &str| match k {
+        "AUTH_UPLOADER_1" => Some("upload".to_string()),
+        "ADMIN_AUTH_ADMIN_1" => Some("admin".to_string()),
+        _ => None,
+    };
+    let body = br#"{"action":"delete","grid":"agni","region_loc":[256,512],"impostor_lod":0}"#;
+    let signed = |name: &str, secret: &[u8]| test_request(&[("HTTP_X_AUTHTOKEN_NAME", name),
+        ("HTTP_X_AUTHTOKEN_HASH", &hex::encode(hash_with_secret(secret, body)))], body);
+    assert_eq!(Authorizer::authorize_signed(AuthorizeType::AdminDelete, &signed("admin_1", b"admin"), secrets).unwrap(), "admin_1");
+    //  An upload token, correctly signed, can't delete. An admin token can't upload.
+    assert!(Authorizer::authorize_signed(AuthorizeType::AdminDelete, &signed("UPLOADER_1", b"upload"), secrets).is_err());
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadImpostors, &signed("ADMIN_1", b"admin"), secrets).is_err());
+    //  Nor can a token name reach the admin secret through the upload prefix.
+    assert!(Authorizer::authorize_signed(AuthorizeType::AdminDelete, &signed("ADMIN_1", b"upload"), secrets).is_err());
+}
+
+#[test]
+fn test_request_origin() {
+    let params = |pairs: &[(&str, &str)]| test_request(pairs, b"");
+    //  Second Life, as FCGI delivers it.
+    let sl = params(&[("HTTP_X_SECONDLIFE_SHARD", "Production"), ("HTTP_X_SECONDLIFE_OWNER_NAME", "joe.smith"),
+        ("HTTP_X_SECONDLIFE_REGION", "Vallone (462592, 306944)"), ("HTTP_X_SECONDLIFE_OWNER_KEY", "4b0c6e3a-1f2e-4a5b-9c8d-7e6f5a4b3c2d")]);
+    let origin = RequestOrigin::new_from_request(&sl);
+    assert_eq!(origin.owner_name.as_deref(), Some("Joe Smith"));
+    assert_eq!(origin.region_name.as_deref(), Some("Vallone"));
+    assert_eq!(origin.region_corner, Some([462592, 306944]));
+    assert_eq!(origin.grid().as_deref(), Some("agni"));
+    assert!(origin.check_grid("agni").is_ok());
+    assert!(origin.check_grid("Agni").is_ok());
+    assert!(origin.check_grid("aditi").is_err());
+    assert!(origin.check_grid("osgrid").is_err());
+    //  Beta grid.
+    let beta = params(&[("HTTP_X_SECONDLIFE_SHARD", "Testing"), ("HTTP_X_SECONDLIFE_OWNER_NAME", "Joe Resident")]);
+    assert!(RequestOrigin::new_from_request(&beta).check_grid("aditi").is_ok());
+    assert!(RequestOrigin::new_from_request(&beta).check_grid("agni").is_err());
+    //  Open Simulator, with its owner header, float coordinates, and a hypergrid owner.
+    let os = params(&[("HTTP_X_SECONDLIFE_SHARD", "OSgrid"), ("HTTP_X_OPENSIM_OWNER_NAME", "Jane.Doe@Hg.Example.com:8002"),
+        ("HTTP_X_SECONDLIFE_REGION", "Wright Plaza (256000.0, 256000.0)")]);
+    let origin = RequestOrigin::new_from_request(&os);
+    assert_eq!(origin.owner_name.as_deref(), Some("Jane Doe@hg.example.com:8002"));
+    assert_eq!(origin.region_name.as_deref(), Some("Wright Plaza"));
+    assert_eq!(origin.region_corner, Some([256000, 256000]));
+    assert!(origin.check_grid("osgrid").is_ok());
+    assert!(origin.check_grid("agni").is_err());
+    //  Not from a simulator. Nothing to check.
+    let origin = RequestOrigin::new_from_request(&params(&[("REQUEST_METHOD", "POST")]));
+    assert_eq!(origin, RequestOrigin::default());
+    assert!(origin.check_grid("agni").is_ok());
+    //  Owner name forms.
+    assert_eq!(canonical_owner_name("Joe Smith"), "Joe Smith");
+    assert_eq!(canonical_owner_name(" JOE  SMITH "), "Joe Smith");
+    assert_eq!(canonical_owner_name("joe"), "Joe Resident");
+    assert_eq!(canonical_owner_name("joe.resident"), "Joe Resident");
+    //  Bad region headers.
+    assert!(parse_region_header("Vallone").is_none());
+    assert!(parse_region_header("Vallone (1, two)").is_none());
+    assert!(parse_region_header("Vallone (-256, 0)").is_none());
 }
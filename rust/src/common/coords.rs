@@ -0,0 +1,172 @@
+//! coords.rs -- region coordinates, with their units in the type.
+//! Part of the Animats impostor system
+//!
+//! Region positions show up in two units. The database, the impostor tables,
+//! and llGetRegionCorner use meters from the grid origin, (290304, 268288).
+//! Map tiles and some uploaders use region grid units, meters / 256, (1134, 1048).
+//! Mixing them up doesn't fail, it just puts regions in the wrong place.
+//! So positions which cross module boundaries carry their unit in the type,
+//! and converting is explicit.
+//!
+//! Deployed LSL uploaders send region grid units and don't say so. Newer
+//! ones send meters, from llGetRegionCorner. When an upload gives no units,
+//! they're told apart by size: both coordinates below REGION_GRID_UNITS_LIMIT
+//! is region grid units, converted to meters with a warning in the log.
+//! A sender can say which units it means with CoordUnits, and then
+//! nothing is guessed.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::Error;
+use serde::{Deserialize, Serialize};
+
+/// Meters per region grid unit.
+pub const REGION_GRID_METERS: u32 = 256;
+
+/// Coordinates with no units, both below this, are taken to be region grid units.
+/// Meters that small would be within 256 regions of the grid origin, where no real grid puts regions.
+pub const REGION_GRID_UNITS_LIMIT: u32 = 65536;
+
+/// Units of region coordinates, as whoever sent them says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CoordUnits {
+    /// Meters from the grid origin, as llGetRegionCorner gives them
+    #[default]
+    Meters,
+    /// Region grid units, meters / 256
+    Regions,
+}
+
+impl CoordUnits {
+    /// From a command line or query parameter, "meters" or "regions".
+    pub fn new_from_param(s: &str) -> Result<Self, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "meters" => Ok(CoordUnits::Meters),
+            "regions" => Ok(CoordUnits::Regions),
+            _ => Err(Error::BadRequest(format!("Coordinate units \"{}\" are not \"meters\" or \"regions\"", s))),
+        }
+    }
+}
+
+/// Position on the grid, meters from the grid origin. What the database stores.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GlobalMeters(pub u32);
+
+/// Position on the grid, in region grid units. Meters / 256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RegionGridCoord(pub u32);
+
+impl GlobalMeters {
+    /// Value in meters.
+    pub fn meters(self) -> u32 {
+        self.0
+    }
+
+    /// On a region grid boundary.
+    pub fn is_aligned(self) -> bool {
+        self.0 % REGION_GRID_METERS == 0
+    }
+
+    /// To region grid units. Must be on a region grid boundary.
+    pub fn to_region_grid(self) -> Result<RegionGridCoord, Error> {
+        if !self.is_aligned() {
+            return Err(Error::OutOfRange(format!("{} m is not a multiple of {} m", self.0, REGION_GRID_METERS)));
+        }
+        Ok(RegionGridCoord(self.0 / REGION_GRID_METERS))
+    }
+}
+
+impl RegionGridCoord {
+    /// Value in region grid units.
+    pub fn index(self) -> u32 {
+        self.0
+    }
+
+    /// To meters. Fails if that doesn't fit.
+    pub fn to_meters(self) -> Result<GlobalMeters, Error> {
+        self.0.checked_mul(REGION_GRID_METERS)
+            .map(GlobalMeters)
+            .ok_or_else(|| Error::OutOfRange(format!("Region grid coordinate {} is too big", self.0)))
+    }
+}
+
+impl std::fmt::Display for GlobalMeters {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}m", self.0)
+    }
+}
+
+impl std::fmt::Display for RegionGridCoord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}r", self.0)
+    }
+}
+
+/// Region coordinates to meters, in the units the sender gave.
+/// With no units, both below REGION_GRID_UNITS_LIMIT is region grid units, and anything else is meters.
+/// Meters must be on a region grid boundary.
+pub fn region_coords_to_meters(coords: [u32; 2], units: Option<CoordUnits>) -> Result<[GlobalMeters; 2], Error> {
+    let units = units.unwrap_or_else(|| {
+        if coords.iter().all(|&c| c < REGION_GRID_UNITS_LIMIT) {
+            log::warn!("Region coordinates ({}, {}) have no units and are small, so taken to be region grid units. Converted to ({}, {}).",
+                coords[0], coords[1], coords[0].saturating_mul(REGION_GRID_METERS), coords[1].saturating_mul(REGION_GRID_METERS));
+            CoordUnits::Regions
+        } else {
+            CoordUnits::Meters
+        }
+    });
+    match units {
+        CoordUnits::Regions => {
+            let [x, y] = coords.map(RegionGridCoord);
+            Ok([x.to_meters()?, y.to_meters()?])
+        }
+        CoordUnits::Meters => {
+            let [x, y] = coords.map(GlobalMeters);
+            if !(x.is_aligned() && y.is_aligned()) {
+                return Err(Error::BadRequest(format!("Region coordinates ({}, {}) are not meters on a region boundary", coords[0], coords[1])));
+            }
+            Ok([x, y])
+        }
+    }
+}
+
+#[test]
+fn test_coord_conversions() {
+    assert_eq!(RegionGridCoord(1807).to_meters().unwrap(), GlobalMeters(462592));
+    assert_eq!(GlobalMeters(462592).to_region_grid().unwrap(), RegionGridCoord(1807));
+    assert!(matches!(GlobalMeters(462593).to_region_grid(), Err(Error::OutOfRange(_))));
+    assert!(matches!(RegionGridCoord(u32::MAX / 256 + 1).to_meters(), Err(Error::OutOfRange(_))));
+    assert_eq!(GlobalMeters(256000).to_string(), "256000m");
+    //  Serializes as a plain number.
+    assert_eq!(serde_json::to_string(&[GlobalMeters(290304), GlobalMeters(268288)]).unwrap(), "[290304,268288]");
+}
+
+#[test]
+fn test_region_coords_to_meters() {
+    let kraken = [GlobalMeters(290304), GlobalMeters(268288)];
+    //  Either unit, when the sender says which.
+    assert_eq!(region_coords_to_meters([1134, 1048], Some(CoordUnits::Regions)).unwrap(), kraken);
+    assert_eq!(region_coords_to_meters([290304, 268288], Some(CoordUnits::Meters)).unwrap(), kraken);
+    //  Small meters are fine when they're said to be meters.
+    assert_eq!(region_coords_to_meters([512, 1024], Some(CoordUnits::Meters)).unwrap(), [GlobalMeters(512), GlobalMeters(1024)]);
+    //  With no units, told apart by size.
+    assert_eq!(region_coords_to_meters([290304, 268288], None).unwrap(), kraken);
+    assert_eq!(region_coords_to_meters([1134, 1048], None).unwrap(), kraken);
+    assert_eq!(region_coords_to_meters([1024, 1024], None).unwrap(), [GlobalMeters(262144), GlobalMeters(262144)]);
+    //  One big coordinate means meters, so the small one must be aligned too.
+    assert_eq!(region_coords_to_meters([512, 268288], None).unwrap(), [GlobalMeters(512), GlobalMeters(268288)]);
+    assert!(matches!(region_coords_to_meters([1807, 268288], None), Err(Error::BadRequest(_))));
+    //  Meters must be aligned, and region grid units must fit.
+    assert!(matches!(region_coords_to_meters([290305, 268288], None), Err(Error::BadRequest(_))));
+    assert!(matches!(region_coords_to_meters([1807, 268288], Some(CoordUnits::Meters)), Err(Error::BadRequest(_))));
+    assert!(matches!(region_coords_to_meters([u32::MAX, 0], Some(CoordUnits::Regions)), Err(Error::OutOfRange(_))));
+    //  Units as sent.
+    assert_eq!(serde_json::from_str::<CoordUnits>(r#""regions""#).unwrap(), CoordUnits::Regions);
+    assert_eq!(CoordUnits::new_from_param(" Meters ").unwrap(), CoordUnits::Meters);
+    assert!(CoordUnits::new_from_param("feet").is_err());
+}
@@ -0,0 +1,240 @@
+//! coverage.rs -- which parts of a grid have impostors, at each LOD.
+//! Part of the Animats impostor system
+//!
+//! A cheap overview of a grid, for the uploader script and for operators.
+//! For each LOD, the tiles with impostors are a bitmap over the bounding box
+//! of those tiles, one bit per tile, X fastest, south to north.
+//! The bitmap is run-length encoded, because grids are mostly big
+//! solid areas and big gaps.
+//!
+//! Runs alternate, empty first, so a bitmap starting with a populated
+//! tile starts with a zero length run.
+//!
+//! Impostor locations come from uploads, so a stray one far away can make
+//! the bounding box enormous. Runs are made from the sorted tiles, never a
+//! whole bitmap, and a bounding box too big for u32 runs is an error.
+//! LOD 0 impostors of deleted regions are left out.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::params;
+use crate::db::Db;
+use crate::grid::canonical;
+use crate::NOT_DELETED_IMPOSTOR;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Where each impostor is. One indexed query per grid.
+const SQL_COVERAGE: &str = r"SELECT region_loc_x, region_loc_y, region_size_x, region_size_y, impostor_lod
+    FROM region_impostors
+    WHERE grid = :grid AND ";
+
+/// Size of a LOD 0 region, meters.
+const REGION_SIZE: u32 = 256;
+/// Highest LOD mapped. Anything above is a bad row.
+const MAX_COVERAGE_LOD: u8 = 16;
+/// Most tiles one impostor can cover. An 8192 meter varregion at LOD 0.
+const MAX_TILES_PER_IMPOSTOR: u64 = 32 * 32;
+/// Largest bounding box, in tiles, so every run fits in a u32.
+const MAX_COVERAGE_TILES: u64 = u32::MAX as u64;
+
+/// Run lengths for a bitmap. Runs alternate, empty first.
+pub fn rle_encode(bits: &[bool]) -> Vec<u32> {
+    let mut runs = Vec::new();
+    let mut current = false;
+    let mut run = 0;
+    for &bit in bits {
+        if bit != current {
+            runs.push(run);
+            current = bit;
+            run = 0;
+        }
+        run += 1;
+    }
+    if run > 0 {
+        runs.push(run);
+    }
+    runs
+}
+
+/// Bitmap from run lengths. Inverse of rle_encode.
+pub fn rle_decode(runs: &[u32]) -> Vec<bool> {
+    runs.iter()
+        .enumerate()
+        .flat_map(|(n, &run)| std::iter::repeat_n(n % 2 == 1, run as usize))
+        .collect()
+}
+
+/// Tiles with impostors, at one LOD.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LodCoverage {
+    /// Level of detail
+    pub lod: u8,
+    /// Tile size at this LOD, meters.
+    pub tile_size: u32,
+    /// Southwest corner of the bounding box, meters.
+    pub origin: [u32; 2],
+    /// Bounding box size, tiles.
+    pub tiles: [u32; 2],
+    /// Run-length encoded bitmap, X fastest, south to north.
+    pub runs: Vec<u32>,
+}
+
+impl LodCoverage {
+    /// Coverage for these tiles, as (x, y) indices in units of tile_size. There must be some.
+    /// Same runs as rle_encode of the bitmap, but memory goes with the tiles, not the bounding box.
+    fn new(lod: u8, tile_size: u32, cells: &[(u32, u32)]) -> Result<Self, Error> {
+        let min_x = cells.iter().map(|c| c.0).min().ok_or_else(|| anyhow!("No tiles at LOD {}", lod))?;
+        let min_y = cells.iter().map(|c| c.1).min().unwrap_or(0);
+        let max_x = cells.iter().map(|c| c.0).max().unwrap_or(0);
+        let max_y = cells.iter().map(|c| c.1).max().unwrap_or(0);
+        let width = (max_x - min_x) as u64 + 1;
+        let total = width * ((max_y - min_y) as u64 + 1);
+        if total > MAX_COVERAGE_TILES {
+            return Err(anyhow!("LOD {} coverage spans {} by {} tiles, more than {}", lod, width, total / width, MAX_COVERAGE_TILES));
+        }
+        let tiles = [width as u32, (total / width) as u32];
+        let mut indices: Vec<u64> = cells.iter().map(|&(x, y)| (y - min_y) as u64 * width + (x - min_x) as u64).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        //  Each stretch of consecutive tiles is a populated run, after the empty run before it.
+        let mut runs = Vec::new();
+        let mut next = 0;
+        let mut stretch = indices.iter().copied().peekable();
+        while let Some(start) = stretch.next() {
+            let mut end = start + 1;
+            while stretch.next_if_eq(&end).is_some() {
+                end += 1;
+            }
+            runs.push((start - next) as u32);
+            runs.push((end - start) as u32);
+            next = end;
+        }
+        if next < total {
+            runs.push((total - next) as u32);
+        }
+        Ok(Self { lod, tile_size, origin: [min_x * tile_size, min_y * tile_size], tiles, runs })
+    }
+
+    /// Is there an impostor covering this location, in meters?
+    pub fn is_covered(&self, x: u32, y: u32) -> bool {
+        let (Some(dx), Some(dy)) = (x.checked_sub(self.origin[0]), y.checked_sub(self.origin[1])) else {
+            return false;
+        };
+        let (ix, iy) = (dx / self.tile_size, dy / self.tile_size);
+        if ix >= self.tiles[0] || iy >= self.tiles[1] {
+            return false;
+        }
+        //  Walk the runs. Decoding them could be a huge bitmap.
+        let index = iy as u64 * self.tiles[0] as u64 + ix as u64;
+        let mut run_end = 0;
+        for (n, &run) in self.runs.iter().enumerate() {
+            run_end += run as u64;
+            if index < run_end {
+                return n % 2 == 1;
+            }
+        }
+        false
+    }
+}
+
+/// Coverage of one grid, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoverageReply {
+    /// Grid name
+    pub grid: String,
+    /// One per LOD with any impostors, lowest LOD first.
+    pub lods: Vec<LodCoverage>,
+}
+
+impl CoverageReply {
+    /// From impostor locations and sizes, as (region_loc, region_size, lod).
+    /// A varregion covers every tile it overlaps. Impostors with impossible sizes or LODs are left out, with a warning.
+    pub fn new(grid: &str, impostors: &[([u32; 2], [u32; 2], u8)]) -> Result<Self, Error> {
+        let mut by_lod: BTreeMap<u8, Vec<(u32, u32)>> = BTreeMap::new();
+        for &(loc, size, lod) in impostors {
+            if lod > MAX_COVERAGE_LOD {
+                log::warn!("Coverage of \"{}\": impostor at {:?} has LOD {}, left out.", grid, loc, lod);
+                continue;
+            }
+            let tile_size = REGION_SIZE << lod;
+            let first = [loc[0] / tile_size, loc[1] / tile_size];
+            let end = [0, 1].map(|i| (loc[i] as u64 + size[i].max(1) as u64).div_ceil(tile_size as u64) as u32);
+            if (end[0] - first[0]) as u64 * (end[1] - first[1]) as u64 > MAX_TILES_PER_IMPOSTOR {
+                log::warn!("Coverage of \"{}\": impostor at {:?} has size {:?}, left out.", grid, loc, size);
+                continue;
+            }
+            let cells = by_lod.entry(lod).or_default();
+            for x in first[0]..end[0] {
+                for y in first[1]..end[1] {
+                    cells.push((x, y));
+                }
+            }
+        }
+        let lods = by_lod.into_iter().map(|(lod, cells)| LodCoverage::new(lod, REGION_SIZE << lod, &cells)).collect::<Result<_, _>>()?;
+        Ok(Self { grid: canonical(grid), lods })
+    }
+}
+
+/// Coverage of a grid, from region_impostors.
+pub fn get_coverage(conn: &mut dyn Db, grid: &str) -> Result<CoverageReply, Error> {
+    let sql = format!("{}{}", SQL_COVERAGE, NOT_DELETED_IMPOSTOR);
+    let impostors = conn.exec_map(&sql, params! { "grid" => canonical(grid) }, |row| {
+        Ok(([row.get(0)?, row.get(1)?], [row.get(2)?, row.get(3)?], row.get(4)?))
+    })?;
+    CoverageReply::new(grid, &impostors)
+}
+
+#[test]
+fn test_rle_round_trip() {
+    let cases: [&[bool]; 6] = [&[], &[false], &[true], &[true, true, false, true], &[false, false, true, true, true, false], &[true; 1000]];
+    for bits in cases {
+        let runs = rle_encode(bits);
+        assert_eq!(rle_decode(&runs), bits, "{:?}", runs);
+    }
+    //  Empty first, so a leading populated tile gives a zero run.
+    assert_eq!(rle_encode(&[true, true, false, true]), vec![0, 2, 1, 1]);
+    assert_eq!(rle_encode(&[false, false, true]), vec![2, 1]);
+}
+
+#[test]
+fn test_coverage_reply() {
+    //  One LOD 0 region, one varregion, one LOD 1 tile.
+    let reply = CoverageReply::new("Agni", &[([1024, 1024], [256, 256], 0), ([1536, 1024], [512, 512], 0), ([1024, 1024], [512, 512], 1)]).unwrap();
+    assert_eq!(reply.grid, "agni");
+    assert_eq!(reply.lods.len(), 2);
+    let lod_0 = &reply.lods[0];
+    assert_eq!((lod_0.origin, lod_0.tiles), ([1024, 1024], [4, 2]));
+    //  Varregion covers four cells. The gap between is empty.
+    assert_eq!(rle_decode(&lod_0.runs), vec![true, false, true, true, false, false, true, true]);
+    assert!(lod_0.is_covered(1100, 1100) && lod_0.is_covered(1800, 1300));
+    assert!(!lod_0.is_covered(1300, 1100) && !lod_0.is_covered(1100, 1300) && !lod_0.is_covered(0, 0));
+    let lod_1 = &reply.lods[1];
+    assert_eq!((lod_1.lod, lod_1.tile_size, lod_1.origin, lod_1.tiles, lod_1.runs.clone()), (1, 512, [1024, 1024], [1, 1], vec![0, 1]));
+    //  Nothing at all.
+    assert!(CoverageReply::new("agni", &[]).unwrap().lods.is_empty());
+    //  Same runs as encoding the whole bitmap, including a trailing gap.
+    let cells = [(0, 1), (1, 0), (3, 0), (2, 0), (1, 0)];
+    let coverage = LodCoverage::new(0, 256, &cells).unwrap();
+    let mut bits = vec![false; 8];
+    cells.iter().for_each(|&(x, y)| bits[y as usize * 4 + x as usize] = true);
+    assert_eq!(coverage.runs, rle_encode(&bits));
+    assert!((0..8u32).all(|n| coverage.is_covered(n % 4 * 256, n / 4 * 256) == bits[n as usize]));
+}
+
+#[test]
+fn test_coverage_far_apart() {
+    //  Two regions a million tiles apart. Runs, not a bitmap.
+    let reply = CoverageReply::new("agni", &[([0, 0], [256, 256], 0), ([256_000_000, 0], [256, 256], 0)]).unwrap();
+    assert_eq!((reply.lods[0].tiles, reply.lods[0].runs.clone()), ([1_000_001, 1], vec![0, 1, 999_999, 1]));
+    assert!(reply.lods[0].is_covered(256_000_100, 100) && !reply.lods[0].is_covered(128_000_000, 0));
+    //  Opposite corners of the world is too big to map.
+    let corners = [([0, 0], [256, 256], 0), ([u32::MAX - 255, u32::MAX - 255], [256, 256], 0)];
+    assert!(CoverageReply::new("agni", &corners).is_err());
+    //  Impossible sizes and LODs are left out, not looped over.
+    let bad = [([0, 0], [u32::MAX, u32::MAX], 0), ([0, 0], [256, 256], 200)];
+    assert!(CoverageReply::new("agni", &bad).unwrap().lods.is_empty());
+}
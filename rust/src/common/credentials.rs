@@ -2,54 +2,106 @@
 //!
 //! Minimal low-security solution. Credentials are plain text
 //! but not in a diirectory visible to the web server.
+//!
+//! Any key can be overridden by an environment variable of the same name,
+//! with an optional prefix, so containers can inject secrets.
+//!
+//! The file is looked for in MAPTOOLS_CREDENTIALS_DIR if that is set.
+//! Otherwise, in the current directory and its parents, up to the home directory.
+//!
+//! Long-running programs can watch the file instead, so rotated secrets
+//! take effect without a restart. See WatchedCredentials.
 
 use anyhow::{Error, anyhow};
 use envie::Envie;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Environment variable naming the one directory to look for credentials in.
+pub const CREDENTIALS_DIR_ENV: &str = "MAPTOOLS_CREDENTIALS_DIR";
+/// Most directories searched, starting with the current one.
+const MAX_SEARCH_DEPTH: usize = 16;
+/// Default shortest time between checks of a watched file for changes.
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Loosest permissions a credentials file should have.
+#[cfg(unix)]
+const CREDENTIALS_MAX_MODE: u32 = 0o640;
 
 /// Key/value store for credentials
 pub struct Credentials {
     /// The credentials
     creds: Envie,
+    /// Prefix for environment variable overrides. None if no overrides.
+    env_prefix: Option<String>,
 }
 
 impl Credentials {
     /// Find credentials file.
-    /// Look in parent directories.
+    /// If MAPTOOLS_CREDENTIALS_DIR is set, look only there.
+    /// Otherwise look in parent directories, stopping at the home directory.
     fn find_credentials(filename: &str) -> Result<PathBuf, Error> {
-        let mut wd = std::env::current_dir()?; // start at current directory
-        // Go up the tree. Prevent runaway.
-        for _ in 0..100 {
-            //  Valid directory?
-            if !wd.exists() {
-                return Err(anyhow!(
-                    "Tried all parent directories without finding credentials."
-                ));
-            }
+        if let Some(dir) = std::env::var_os(CREDENTIALS_DIR_ENV).filter(|d| !d.is_empty()) {
+            return Self::find_credentials_in_dir(filename, Path::new(&dir));
+        }
+        let home = std::env::var_os("HOME").filter(|d| !d.is_empty()).map(PathBuf::from);
+        Self::find_credentials_from(filename, &std::env::current_dir()?, home.as_deref(), MAX_SEARCH_DEPTH)
+    }
+
+    /// Look for the file in this directory only.
+    fn find_credentials_in_dir(filename: &str, dir: &Path) -> Result<PathBuf, Error> {
+        let cred_path = dir.join(filename);
+        if cred_path.is_file() {
+            Ok(cred_path)
+        } else {
+            Err(anyhow!("Credentials file {:?} not found in {:?}", filename, dir))
+        }
+    }
+
+    /// Look in start and its parents, up to depth directories.
+    /// Stops after stop_at, if the walk reaches it.
+    fn find_credentials_from(filename: &str, start: &Path, stop_at: Option<&Path>, depth: usize) -> Result<PathBuf, Error> {
+        //  An absolute filename is used as is.
+        if Path::new(filename).is_absolute() {
+            return Self::find_credentials_in_dir(filename, Path::new("/"));
+        }
+        let mut wd = Some(start);
+        for _ in 0..depth {
+            let Some(dir) = wd else {
+                return Err(anyhow!("Could not find credentials file {:?} in {:?} or any directory above it", filename, start));
+            };
             //  Is it in this directory
-            let mut cred_path = wd.clone();
-            cred_path.push(filename);
-            if cred_path.exists() {
+            let cred_path = dir.join(filename);
+            if cred_path.is_file() {
                 return Ok(cred_path);
             }
+            if stop_at.is_some_and(|stop| stop == dir) {
+                return Err(anyhow!("Could not find credentials file {:?} between {:?} and {:?}", filename, start, dir));
+            }
             //  No, try parent directory.
-            wd = wd
-                .parent()
-                .ok_or_else(|| {
-                    anyhow!(
-                        "Could not find credentials file {:?} in directory tree.",
-                        filename
-                    )
-                })?
-                .to_path_buf();
+            wd = dir.parent();
         }
-        Err(anyhow!("Link loop in directory tree above {:?}", wd))
+        Err(anyhow!("Could not find credentials file {:?} within {} directories above {:?}", filename, depth, start))
     }
 
-    /// Usual new.
-    /// Initializes the credentials
-    pub fn new(filename: &str) -> Result<Self, Error> {
-        let path = Self::find_credentials(filename)?;
+    /// Credentials files hold passwords. Warn if anyone but owner and group can read them.
+    #[cfg(unix)]
+    fn check_permissions(path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & !CREDENTIALS_MAX_MODE != 0 {
+                log::warn!("Credentials file {:?} has permissions {:o}, should be no more than {:o}", path, mode, CREDENTIALS_MAX_MODE);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn check_permissions(_path: &Path) {}
+
+    /// Load a credentials file found by one of the find functions.
+    fn load(path: PathBuf) -> Result<Self, Error> {
+        Self::check_permissions(&path);
         let creds = match Envie::load_with_path(path.to_str().ok_or_else(|| {
             anyhow!(
                 "Credentials filename {:?} has illegal UTF-8 characters.",
@@ -61,14 +113,168 @@ impl Credentials {
                 return Err(anyhow!("Error loading credentials: {}", s));
             }
         };
-        Ok(Self { creds })
+        Ok(Self { creds, env_prefix: None })
     }
+
+    /// Usual new.
+    /// Initializes the credentials
+    pub fn new(filename: &str) -> Result<Self, Error> {
+        Self::load(Self::find_credentials(filename)?)
+    }
+
+    /// New, looking only in the given directory.
+    pub fn new_in_dir(filename: &str, dir: &Path) -> Result<Self, Error> {
+        Self::load(Self::find_credentials_in_dir(filename, dir)?)
+    }
+
+    /// New, with environment variable overrides.
+    /// If prefix + key is set in the environment, that value is used instead of the file's.
+    pub fn new_with_env(filename: &str, prefix: &str) -> Result<Self, Error> {
+        Ok(Self { env_prefix: Some(prefix.to_string()), ..Self::new(filename)? })
+    }
+
+    /// Watch the file for changes. See WatchedCredentials.
+    pub fn watch(filename: &str) -> Result<WatchedCredentials, Error> {
+        WatchedCredentials::new(Self::find_credentials(filename)?, None)
+    }
+
+    /// Watch the file for changes, with environment variable overrides, as new_with_env.
+    pub fn watch_with_env(filename: &str, prefix: &str) -> Result<WatchedCredentials, Error> {
+        WatchedCredentials::new(Self::find_credentials(filename)?, Some(prefix.to_string()))
+    }
+
     //  Get value 	for key.
     pub fn get(&self, key: &str) -> Option<String> {
-        self.creds.get(key)
+        self.env_prefix.as_ref()
+            .and_then(|prefix| std::env::var(format!("{}{}", prefix, key)).ok())
+            .or_else(|| self.creds.get(key))
+    }
+
+    /// All these keys must be present. Errors name every missing key.
+    pub fn require(&self, keys: &[&str]) -> Result<(), Error> {
+        let missing: Vec<&str> = keys.iter().copied().filter(|k| self.get(k).is_none()).collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Credentials are missing {}", missing.join(", ")))
+        }
+    }
+}
+
+/// Modification time of a file, if it can be had.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// What a watched file held when last loaded.
+struct WatchState {
+    /// The values
+    creds: Credentials,
+    /// File modification time when loaded
+    modified: Option<SystemTime>,
+    /// When the file was last checked for a change
+    last_check: Instant,
+}
+
+/// Credentials which are re-read when the file changes.
+///
+/// The file's modification time is recorded at load. get_fresh checks it
+/// at most once per check interval, and reload checks it now. If it changed,
+/// the file is read again. If that fails, as when the file is half written,
+/// the old values stay, and the next check tries again.
+///
+/// Clones share the values, so a reload through one is seen by all.
+#[derive(Clone)]
+pub struct WatchedCredentials {
+    /// The file, as found at load
+    path: PathBuf,
+    /// Shortest time between checks, for get_fresh
+    check_interval: Duration,
+    /// Current values
+    state: Arc<Mutex<WatchState>>,
+}
+
+impl WatchedCredentials {
+    /// Load a file found by one of the find functions, and note its time.
+    fn new(path: PathBuf, env_prefix: Option<String>) -> Result<Self, Error> {
+        //  Time before reading, so a write during the read is seen as a change.
+        let modified = modified_time(&path);
+        let creds = Credentials { env_prefix, ..Credentials::load(path.clone())? };
+        let state = WatchState { creds, modified, last_check: Instant::now() };
+        Ok(Self { path, check_interval: DEFAULT_CHECK_INTERVAL, state: Arc::new(Mutex::new(state)) })
+    }
+
+    /// Check for changes no more often than this in get_fresh.
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    /// The file being watched.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Value for key, as last loaded. Doesn't check the file.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().expect("credentials lock").creds.get(key)
+    }
+
+    /// Value for key, re-reading the file first if it has changed.
+    /// The file is checked at most once per check interval.
+    pub fn get_fresh(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().expect("credentials lock");
+        if state.last_check.elapsed() >= self.check_interval {
+            if let Err(e) = self.check(&mut state) {
+                log::warn!("Keeping previous credentials: {:?}", e);
+            }
+        }
+        state.creds.get(key)
+    }
+
+    /// Re-read the file now if it has changed, such as between requests.
+    /// True if new values were loaded. On error, the old values stay.
+    pub fn reload(&self) -> Result<bool, Error> {
+        self.check(&mut self.state.lock().expect("credentials lock"))
+    }
+
+    /// All these keys must be present, as last loaded.
+    pub fn require(&self, keys: &[&str]) -> Result<(), Error> {
+        self.state.lock().expect("credentials lock").creds.require(keys)
+    }
+
+    /// Re-read the file if its time changed.
+    fn check(&self, state: &mut WatchState) -> Result<bool, Error> {
+        state.last_check = Instant::now();
+        let modified = modified_time(&self.path);
+        if modified == state.modified {
+            return Ok(false);
+        }
+        let creds = Credentials::load(self.path.clone()).map_err(|e| anyhow!("Reloading {:?}: {}", self.path, e))?;
+        state.creds = Credentials { env_prefix: state.creds.env_prefix.clone(), ..creds };
+        state.modified = modified;
+        log::info!("Credentials file {:?} changed, reloaded.", self.path);
+        Ok(true)
     }
 }
 
+/// Names of the environment variables, sorted, for logging.
+/// Never the values. Credentials can come from the environment, and the logs are web-visible.
+pub fn environment_names() -> Vec<String> {
+    let mut names: Vec<String> = std::env::vars_os().map(|(name, _)| name.to_string_lossy().into_owned()).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_environment_names() {
+    //  Names, and nothing else. Other tests set variables, so the count can't be checked.
+    let names = environment_names();
+    assert!(!names.is_empty());
+    assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(names.iter().all(|name| !name.contains('=')));
+}
+
 #[test]
 fn test_credentials() {
     //  Test finding of file
@@ -83,3 +289,105 @@ fn test_credentials() {
     );
     assert_eq!(Some("bar".to_string()), creds.get("DEMO2"));
 }
+
+#[test]
+fn test_credentials_env() {
+    /// Sets an environment variable, removes it when dropped.
+    struct EnvVar(&'static str);
+    impl EnvVar {
+        fn set(key: &'static str, value: &str) -> Self {
+            //  Safe enough in a test. Keys are unique to this test.
+            unsafe { std::env::set_var(key, value) };
+            Self(key)
+        }
+    }
+    impl Drop for EnvVar {
+        fn drop(&mut self) {
+            unsafe { std::env::remove_var(self.0) };
+        }
+    }
+    let path = std::env::temp_dir().join(format!("test_credentials_env_{}.txt", std::process::id()));
+    std::fs::write(&path, "CREDTEST_USER = terrain\nCREDTEST_PASS = fromfile\n").expect("write temp credentials");
+    let filename = path.to_str().expect("temp path");
+    //  File only.
+    let creds = Credentials::new_with_env(filename, "CREDTEST_ENV_").expect("credentials");
+    assert_eq!(creds.get("CREDTEST_PASS").as_deref(), Some("fromfile"));
+    assert!(creds.require(&["CREDTEST_USER", "CREDTEST_PASS"]).is_ok());
+    //  Environment overrides the file, and supplies keys the file doesn't have.
+    {
+        let _pass = EnvVar::set("CREDTEST_ENV_CREDTEST_PASS", "fromenv");
+        let _host = EnvVar::set("CREDTEST_ENV_CREDTEST_HOST", "db.example.com");
+        assert_eq!(creds.get("CREDTEST_PASS").as_deref(), Some("fromenv"));
+        assert_eq!(creds.get("CREDTEST_HOST").as_deref(), Some("db.example.com"));
+        assert_eq!(creds.get("CREDTEST_USER").as_deref(), Some("terrain"));
+        //  Not without a prefix set.
+        let file_only = Credentials::new(filename).expect("credentials");
+        assert_eq!(file_only.get("CREDTEST_PASS").as_deref(), Some("fromfile"));
+    }
+    assert_eq!(creds.get("CREDTEST_PASS").as_deref(), Some("fromfile"));
+    //  Missing keys are all named.
+    let msg = creds.require(&["CREDTEST_USER", "CREDTEST_HOST", "CREDTEST_NAME"]).unwrap_err().to_string();
+    assert!(msg.contains("CREDTEST_HOST, CREDTEST_NAME"), "{}", msg);
+    assert!(!msg.contains("CREDTEST_USER"), "{}", msg);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_find_credentials() {
+    //  Tree: top/creds.txt, top/a/b/, top/a/other.txt
+    let top = std::env::temp_dir().join(format!("test_find_credentials_{}", std::process::id()));
+    let deep = top.join("a").join("b");
+    std::fs::create_dir_all(&deep).expect("create temp tree");
+    std::fs::write(top.join("creds.txt"), "DEMO1 = top\n").expect("write creds");
+    std::fs::write(top.join("a").join("other.txt"), "DEMO1 = a\n").expect("write other");
+    //  Walked up from below.
+    assert_eq!(Credentials::find_credentials_from("creds.txt", &deep, None, MAX_SEARCH_DEPTH).expect("walked"), top.join("creds.txt"));
+    assert_eq!(Credentials::find_credentials_from("other.txt", &deep, None, MAX_SEARCH_DEPTH).expect("walked"), top.join("a").join("other.txt"));
+    //  Not above the stop directory, or past the depth limit.
+    assert!(Credentials::find_credentials_from("creds.txt", &deep, Some(&top.join("a")), MAX_SEARCH_DEPTH).is_err());
+    assert!(Credentials::find_credentials_from("creds.txt", &deep, None, 2).is_err());
+    assert!(Credentials::find_credentials_from("creds.txt", &deep, None, 3).is_ok());
+    //  Not anywhere. Walks off the top of the file system without looping.
+    let msg = Credentials::find_credentials_from("no_such_credentials_file.txt", &deep, None, 1000).unwrap_err().to_string();
+    assert!(msg.contains("no_such_credentials_file.txt"), "{}", msg);
+    //  Anchored: only that directory.
+    let creds = Credentials::new_in_dir("creds.txt", &top).expect("anchored");
+    assert_eq!(creds.get("DEMO1").as_deref(), Some("top"));
+    assert!(Credentials::new_in_dir("creds.txt", &deep).is_err());
+    assert!(Credentials::new_in_dir("a", &top).is_err());
+    let _ = std::fs::remove_dir_all(&top);
+}
+
+#[test]
+fn test_watched_credentials() {
+    let path = std::env::temp_dir().join(format!("test_watched_credentials_{}.txt", std::process::id()));
+    //  Rewrite the file, with a distinct time, since some file systems only keep seconds.
+    let write = |contents: &str, secs: u64| {
+        std::fs::write(&path, contents).expect("write temp credentials");
+        let file = std::fs::File::options().write(true).open(&path).expect("open temp credentials");
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_775_000_000 + secs)).expect("set time");
+    };
+    write("AUTH_UPLOADER_1 = oldsecret\nDB_PASS = oldpass\n", 0);
+    let interval = Duration::from_millis(200);
+    let creds = Credentials::watch(path.to_str().expect("temp path")).expect("watch").with_check_interval(interval);
+    let shared = creds.clone();
+    assert_eq!(creds.get_fresh("AUTH_UPLOADER_1").as_deref(), Some("oldsecret"));
+    //  Rotated. Not seen until the interval is up.
+    write("AUTH_UPLOADER_1 = newsecret\nDB_PASS = newpass\n", 1);
+    assert_eq!(creds.get_fresh("AUTH_UPLOADER_1").as_deref(), Some("oldsecret"));
+    std::thread::sleep(interval + Duration::from_millis(50));
+    assert_eq!(creds.get_fresh("AUTH_UPLOADER_1").as_deref(), Some("newsecret"));
+    //  Clones see it too, and the old value is gone.
+    assert_eq!(shared.get("DB_PASS").as_deref(), Some("newpass"));
+    assert!(shared.require(&["AUTH_UPLOADER_1", "DB_PASS"]).is_ok());
+    //  Explicit reload doesn't wait. Unchanged file, no reload.
+    assert!(!shared.reload().expect("unchanged"));
+    write("DB_PASS = thirdpass\n", 2);
+    assert!(shared.reload().expect("changed"));
+    assert_eq!(creds.get("DB_PASS").as_deref(), Some("thirdpass"));
+    assert_eq!(creds.get("AUTH_UPLOADER_1"), None);
+    //  File gone. Old values stay.
+    let _ = std::fs::remove_file(&path);
+    assert!(creds.reload().is_err());
+    assert_eq!(creds.get("DB_PASS").as_deref(), Some("thirdpass"));
+}
@@ -0,0 +1,386 @@
+//! db.rs -- connect to the MySQL database.
+//! Part of the Animats impostor system
+//!
+//! All the programs connect the same way, from a credentials file.
+//! The credentials file must contain
+//!
+//!     DB_USER = username
+//!     DB_PASS = databasepassword
+//!     DB_HOST = hostname
+//!     DB_PORT = portnumber (optional, defaults to 3306)
+//!     DB_NAME = databasename
+//!
+//! Any of these can be overridden by an environment variable with the
+//! MAPTOOLS_ prefix, such as MAPTOOLS_DB_PASS.
+//!
+//! Dreamhost's MySQL sometimes refuses connections for a few seconds,
+//! so connecting retries, with backoff.
+//!
+//! Servers connect through watched credentials, with connect_watched.
+//! After a connection error, the file is checked again, and if the
+//! database settings changed, such as a rotated password, the next
+//! connection comes from a new pool with the new settings.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::prelude::Queryable;
+use mysql::{OptsBuilder, Pool, PooledConn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::{Credentials, WatchedCredentials};
+
+pub mod access;
+pub mod migrations;
+
+pub use access::{Db, DbRow, DbValue, FakeDb, FromDbValue, SharedFakeDb};
+
+/// Prefix for environment variables which override credentials.
+pub const CREDENTIALS_ENV_PREFIX: &str = "MAPTOOLS_";
+/// Keys a credentials file must have to connect.
+pub const REQUIRED_DB_KEYS: [&str; 4] = ["DB_HOST", "DB_USER", "DB_PASS", "DB_NAME"];
+/// MySQL default port
+const DEFAULT_PORT: u16 = 3306;
+/// Connection attempts before giving up.
+const CONNECT_TRIES: usize = 5;
+/// Wait after the first failed attempt. Doubles each time.
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Database connection settings, from a credentials file.
+/// No Debug, so the password doesn't end up in logs.
+#[derive(Clone, PartialEq)]
+pub struct DbSettings {
+    /// Host name or IP address
+    pub host: String,
+    /// TCP port
+    pub port: u16,
+    /// User name
+    pub user: String,
+    /// Password
+    pub pass: String,
+    /// Database name
+    pub db_name: String,
+}
+
+impl DbSettings {
+    /// Settings from a key lookup. Errors name every missing key.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let missing: Vec<&str> = REQUIRED_DB_KEYS.iter().copied().filter(|k| get(k).is_none()).collect();
+        if !missing.is_empty() {
+            return Err(anyhow!("Database credentials are missing {}", missing.join(", ")));
+        }
+        let port = match get("DB_PORT") {
+            Some(port) => port.trim().parse::<u16>().map_err(|e| anyhow!("DB_PORT \"{}\" is not a port number: {}", port, e))?,
+            None => DEFAULT_PORT,
+        };
+        let value = |k: &str| get(k).expect("Checked above");
+        Ok(Self {
+            host: value("DB_HOST"),
+            port,
+            user: value("DB_USER"),
+            pass: value("DB_PASS"),
+            db_name: value("DB_NAME"),
+        })
+    }
+
+    /// Settings from a credentials file.
+    pub fn new_from_credentials(creds: &Credentials) -> Result<Self, Error> {
+        Self::new_from_lookup(|k| creds.get(k))
+    }
+
+    /// MySQL options for these settings.
+    pub fn opts(&self) -> OptsBuilder {
+        OptsBuilder::new()
+            //  Dreamhost is still using old authentication
+            .secure_auth(false)
+            .ip_or_hostname(Some(self.host.clone()))
+            .tcp_port(self.port)
+            .user(Some(self.user.clone()))
+            .pass(Some(self.pass.clone()))
+            .db_name(Some(self.db_name.clone()))
+    }
+}
+
+/// MySQL error codes which mean the connection is gone, not that the SQL was bad.
+const CONNECTION_LOST_CODES: [u16; 6] = [
+    1053,   // ER_SERVER_SHUTDOWN
+    1927,   // ER_CONNECTION_KILLED
+    2006,   // CR_SERVER_GONE_ERROR, usually wait_timeout
+    2013,   // CR_SERVER_LOST
+    2055,   // CR_SERVER_LOST_EXTENDED
+    4031,   // ER_CLIENT_INTERACTION_TIMEOUT
+];
+
+/// Did this MySQL error happen because the connection was lost?
+fn is_mysql_connection_lost(e: &mysql::Error) -> bool {
+    match e {
+        mysql::Error::IoError(_) => true,
+        mysql::Error::DriverError(mysql::DriverError::ConnectionClosed) => true,
+        mysql::Error::MySqlError(e) => CONNECTION_LOST_CODES.contains(&e.code),
+        _ => false,
+    }
+}
+
+/// Did this error happen because the database connection was lost?
+/// Such errors are worth one retry on a new connection. SQL errors are not.
+pub fn is_connection_lost(e: &Error) -> bool {
+    e.chain().any(|cause| cause.downcast_ref::<mysql::Error>().is_some_and(is_mysql_connection_lost))
+}
+
+/// MySQL error codes for a transaction which lost a lock conflict. It was rolled back, or
+/// should be, and running it again usually works.
+const LOCK_CONFLICT_CODES: [u16; 2] = [
+    1205,   // ER_LOCK_WAIT_TIMEOUT
+    1213,   // ER_LOCK_DEADLOCK
+];
+
+/// Did this error happen because a transaction lost a lock conflict with another one?
+/// Such transactions are worth running again, a few times.
+pub fn is_lock_conflict(e: &Error) -> bool {
+    e.chain().any(|cause| matches!(cause.downcast_ref::<mysql::Error>(), Some(mysql::Error::MySqlError(e)) if LOCK_CONFLICT_CODES.contains(&e.code)))
+}
+
+/// Where connections come from. A Pool, or a WatchedPool.
+/// Tests of handlers use a SharedFakeDb.
+pub trait ConnSource {
+    /// What get_conn returns.
+    type Conn: Db + 'static;
+
+    /// A connection.
+    fn get_conn(&self) -> Result<Self::Conn, Error>;
+
+    /// A connection from here was lost. The default does nothing.
+    fn connection_lost(&self) {}
+}
+
+impl ConnSource for Pool {
+    type Conn = PooledConn;
+
+    fn get_conn(&self) -> Result<PooledConn, Error> {
+        Ok(Pool::get_conn(self)?)
+    }
+}
+
+/// A pool which follows a watched credentials file.
+/// When a connection fails, the file is checked, and if the database
+/// settings changed, the pool is replaced with one using the new settings.
+/// Clones share the pool.
+#[derive(Clone)]
+pub struct WatchedPool {
+    /// Where the settings come from
+    creds: WatchedCredentials,
+    /// The pool, and the settings it was made with
+    current: Arc<Mutex<(Pool, DbSettings)>>,
+}
+
+impl WatchedPool {
+    /// The pool in use now.
+    pub fn pool(&self) -> Pool {
+        self.current.lock().expect("pool lock").0.clone()
+    }
+
+    /// A connection. If that fails, and the settings have changed, one from a new pool.
+    pub fn get_conn(&self) -> Result<PooledConn, Error> {
+        match self.pool().get_conn() {
+            Ok(conn) => Ok(conn),
+            Err(e) => match self.refresh() {
+                Ok(true) => Ok(self.pool().get_conn()?),
+                Ok(false) => Err(e.into()),
+                Err(refresh_error) => {
+                    log::warn!("Unable to use new database credentials: {:?}", refresh_error);
+                    Err(e.into())
+                }
+            },
+        }
+    }
+
+    /// Check the credentials file, and make a new pool if the database settings changed.
+    /// True if there's a new pool.
+    fn refresh(&self) -> Result<bool, Error> {
+        if let Err(e) = self.creds.reload() {
+            log::warn!("Keeping previous credentials: {:?}", e);
+        }
+        let settings = DbSettings::new_from_lookup(|k| self.creds.get(k)).map_err(|e| anyhow!("{:?}: {}", self.creds.path(), e))?;
+        if settings == self.current.lock().expect("pool lock").1 {
+            return Ok(false);
+        }
+        //  One try. The caller is already failing, and a retry loop would stall it.
+        let pool = try_connect(&settings)?;
+        log::info!("Database credentials changed. Reconnected to database {} on {}:{}.", settings.db_name, settings.host, settings.port);
+        *self.current.lock().expect("pool lock") = (pool, settings);
+        Ok(true)
+    }
+}
+
+impl ConnSource for WatchedPool {
+    type Conn = PooledConn;
+
+    fn get_conn(&self) -> Result<PooledConn, Error> {
+        WatchedPool::get_conn(self)
+    }
+
+    /// Pick up new settings before the next connection, if there are any.
+    fn connection_lost(&self) {
+        if let Err(e) = self.refresh() {
+            log::warn!("Unable to use new database credentials: {:?}", e);
+        }
+    }
+}
+
+impl ConnSource for SharedFakeDb {
+    type Conn = SharedFakeDb;
+
+    /// Every connection is the same FakeDb.
+    fn get_conn(&self) -> Result<SharedFakeDb, Error> {
+        Ok(self.clone())
+    }
+}
+
+/// Run f with a connection from the pool.
+/// If the connection was lost, run it once more with a new connection.
+pub fn with_conn<P: ConnSource, T>(pool: &P, mut f: impl FnMut(&mut P::Conn) -> Result<T, Error>) -> Result<T, Error> {
+    let mut conn = pool.get_conn()?;
+    match f(&mut conn) {
+        Err(e) if is_connection_lost(&e) => {
+            log::warn!("Database connection lost, retrying once: {:?}", e);
+            drop(conn);
+            pool.connection_lost();
+            let mut conn = pool.get_conn()?;
+            f(&mut conn)
+        }
+        result => result,
+    }
+}
+
+/// Replace a long-lived connection if the server has dropped it.
+/// For long runs, between units of work.
+pub fn refresh_conn(pool: &impl ConnSource<Conn = PooledConn>, conn: &mut PooledConn) -> Result<(), Error> {
+    match conn.query_drop("SELECT 1") {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let e = Error::from(e);
+            if !is_connection_lost(&e) {
+                return Err(e);
+            }
+            log::warn!("Database connection lost, reconnecting: {:?}", e);
+            pool.connection_lost();
+            *conn = pool.get_conn()?;
+            Ok(())
+        }
+    }
+}
+
+/// Delay before retry N, counting from 0.
+fn retry_delay(retry: usize) -> Duration {
+    FIRST_RETRY_DELAY * (1 << retry.min(16)) as u32
+}
+
+/// Connect once and check the server answers.
+fn try_connect(settings: &DbSettings) -> Result<Pool, Error> {
+    let pool = Pool::new(settings.opts())?;
+    pool.get_conn()?.query_drop("SELECT 1")?;
+    Ok(pool)
+}
+
+/// Connect using a credentials file, retrying if the server
+/// isn't answering.
+/// The file is searched for in parent directories, as with Credentials.
+pub fn connect(creds_file: &str) -> Result<Pool, Error> {
+    let creds = Credentials::new_with_env(creds_file, CREDENTIALS_ENV_PREFIX)?;
+    creds.require(&REQUIRED_DB_KEYS).map_err(|e| anyhow!("{}: {}", creds_file, e))?;
+    let settings = DbSettings::new_from_credentials(&creds).map_err(|e| anyhow!("{}: {}", creds_file, e))?;
+    drop(creds);
+    connect_with_settings(&settings)
+}
+
+/// Connect using watched credentials, retrying as connect does.
+/// Connections from the pool follow changes to the credentials file.
+pub fn connect_watched(creds: &WatchedCredentials) -> Result<WatchedPool, Error> {
+    creds.require(&REQUIRED_DB_KEYS).map_err(|e| anyhow!("{:?}: {}", creds.path(), e))?;
+    let settings = DbSettings::new_from_lookup(|k| creds.get(k)).map_err(|e| anyhow!("{:?}: {}", creds.path(), e))?;
+    let pool = connect_with_settings(&settings)?;
+    Ok(WatchedPool { creds: creds.clone(), current: Arc::new(Mutex::new((pool, settings))) })
+}
+
+/// Connect, retrying if the server isn't answering.
+fn connect_with_settings(settings: &DbSettings) -> Result<Pool, Error> {
+    let mut retry = 0;
+    loop {
+        match try_connect(settings) {
+            Ok(pool) => {
+                log::info!("Connected to database {} on {}:{}.", settings.db_name, settings.host, settings.port);
+                return Ok(pool);
+            }
+            Err(e) if retry + 1 < CONNECT_TRIES => {
+                let delay = retry_delay(retry);
+                log::warn!("Database connection to {}:{} failed, retrying in {:?}: {:?}", settings.host, settings.port, delay, e);
+                std::thread::sleep(delay);
+                retry += 1;
+            }
+            Err(e) => {
+                return Err(anyhow!("Unable to connect to database on {}:{} after {} tries: {:?}", settings.host, settings.port, CONNECT_TRIES, e));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_db_settings() {
+    use std::collections::HashMap;
+    let creds = |pairs: &[(&str, &str)]| -> HashMap<String, String> { pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() };
+    let full = creds(&[("DB_HOST", "mysql.example.com"), ("DB_USER", "terrain"), ("DB_PASS", "secret"), ("DB_NAME", "animats")]);
+    let settings = DbSettings::new_from_lookup(|k| full.get(k).cloned()).expect("settings");
+    assert_eq!(settings.host, "mysql.example.com");
+    assert_eq!(settings.user, "terrain");
+    assert_eq!(settings.pass, "secret");
+    assert_eq!(settings.db_name, "animats");
+    assert_eq!(settings.port, DEFAULT_PORT);
+    //  Explicit port.
+    let mut with_port = full.clone();
+    with_port.insert("DB_PORT".to_string(), " 3307 ".to_string());
+    assert_eq!(DbSettings::new_from_lookup(|k| with_port.get(k).cloned()).expect("settings").port, 3307);
+    with_port.insert("DB_PORT".to_string(), "mysql".to_string());
+    let msg = DbSettings::new_from_lookup(|k| with_port.get(k).cloned()).err().expect("bad port accepted").to_string();
+    assert!(msg.contains("DB_PORT \"mysql\""), "{}", msg);
+    //  Missing keys are all named.
+    let partial = creds(&[("DB_HOST", "mysql.example.com"), ("DB_PASS", "secret")]);
+    let msg = DbSettings::new_from_lookup(|k| partial.get(k).cloned()).err().expect("missing keys accepted").to_string();
+    assert!(msg.contains("DB_USER, DB_NAME"), "{}", msg);
+    assert!(!msg.contains("DB_HOST"), "{}", msg);
+    //  Backoff doubles.
+    assert_eq!(retry_delay(0), FIRST_RETRY_DELAY);
+    assert_eq!(retry_delay(3), FIRST_RETRY_DELAY * 8);
+}
+
+#[test]
+fn test_is_connection_lost() {
+    let mysql_error = |code: u16| mysql::Error::MySqlError(mysql::MySqlError { state: "HY000".to_string(), message: format!("Error {}", code), code });
+    //  Connection gone.
+    for code in [2006, 2013, 4031] {
+        assert!(is_connection_lost(&Error::from(mysql_error(code))), "{}", code);
+    }
+    let broken_pipe = mysql::Error::IoError(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Broken pipe"));
+    assert!(is_connection_lost(&Error::from(broken_pipe)));
+    assert!(is_connection_lost(&Error::from(mysql::Error::DriverError(mysql::DriverError::ConnectionClosed))));
+    //  Still recognized with context added.
+    assert!(is_connection_lost(&Error::from(mysql_error(2006)).context("Inserting impostor")));
+    //  Bad SQL, duplicate key, unknown column, lock wait: not worth a retry.
+    for code in [1064, 1062, 1054, 1205] {
+        assert!(!is_connection_lost(&Error::from(mysql_error(code))), "{}", code);
+    }
+    assert!(!is_connection_lost(&anyhow!("Not a database error")));
+}
+
+#[test]
+fn test_is_lock_conflict() {
+    let mysql_error = |code: u16| mysql::Error::MySqlError(mysql::MySqlError { state: "40001".to_string(), message: format!("Error {}", code), code });
+    for code in [1205, 1213] {
+        assert!(is_lock_conflict(&Error::from(mysql_error(code)).context("Uploading")), "{}", code);
+    }
+    for code in [1062, 2006] {
+        assert!(!is_lock_conflict(&Error::from(mysql_error(code))), "{}", code);
+    }
+    assert!(!is_lock_conflict(&anyhow!("Deadlock found when trying to get lock")));
+}
@@ -0,0 +1,374 @@
+//! access.rs -- narrow interface to the database, so logic using it can be tested.
+//! Part of the Animats impostor system
+//!
+//! Handlers and the generator talk to MySQL through the Db trait, not
+//! through PooledConn directly. Rows come back as plain values, converted
+//! by column. Tests use FakeDb, which records statements and returns
+//! canned rows, and needs no server.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::prelude::Queryable;
+use mysql::{Params, PooledConn};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// One column value, as returned by the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbValue {
+    /// SQL NULL
+    Null,
+    /// Signed integer
+    Int(i64),
+    /// Unsigned integer
+    UInt(u64),
+    /// FLOAT or DOUBLE
+    Float(f64),
+    /// Strings, blobs, JSON, and dates, as bytes.
+    Bytes(Vec<u8>),
+}
+
+impl DbValue {
+    /// Text value, for canned rows.
+    pub fn text(s: &str) -> Self {
+        DbValue::Bytes(s.as_bytes().to_vec())
+    }
+
+    /// As a string, for parsing numbers sent as text.
+    fn as_str(&self) -> Result<&str, Error> {
+        match self {
+            DbValue::Bytes(b) => Ok(std::str::from_utf8(b)?),
+            _ => Err(anyhow!("{:?} is not text", self)),
+        }
+    }
+}
+
+impl From<mysql::Value> for DbValue {
+    fn from(value: mysql::Value) -> Self {
+        match value {
+            mysql::Value::NULL => DbValue::Null,
+            mysql::Value::Int(n) => DbValue::Int(n),
+            mysql::Value::UInt(n) => DbValue::UInt(n),
+            mysql::Value::Float(f) => DbValue::Float(f as f64),
+            mysql::Value::Double(f) => DbValue::Float(f),
+            mysql::Value::Bytes(b) => DbValue::Bytes(b),
+            mysql::Value::Date(y, mo, d, h, mi, s, _) => DbValue::text(&format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", y, mo, d, h, mi, s)),
+            mysql::Value::Time(neg, days, h, mi, s, _) => {
+                let hours = days * 24 + h as u32;
+                DbValue::text(&format!("{}{:02}:{:02}:{:02}", if neg { "-" } else { "" }, hours, mi, s))
+            }
+        }
+    }
+}
+
+/// Conversion from a column value.
+pub trait FromDbValue: Sized {
+    fn from_db_value(value: &DbValue) -> Result<Self, Error>;
+}
+
+/// Integers. Out of range is an error, not a wraparound.
+macro_rules! from_db_int {
+    ($($t:ty),*) => { $(
+        impl FromDbValue for $t {
+            fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+                match value {
+                    DbValue::Int(n) => Ok(<$t>::try_from(*n)?),
+                    DbValue::UInt(n) => Ok(<$t>::try_from(*n)?),
+                    DbValue::Bytes(_) => Ok(value.as_str()?.trim().parse()?),
+                    _ => Err(anyhow!("{:?} is not an integer", value)),
+                }
+            }
+        }
+    )* }
+}
+from_db_int!(u8, u16, u32, u64, usize, i32, i64);
+
+/// Floats. Integer columns convert.
+macro_rules! from_db_float {
+    ($($t:ty),*) => { $(
+        impl FromDbValue for $t {
+            fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+                match value {
+                    DbValue::Float(f) => Ok(*f as $t),
+                    DbValue::Int(n) => Ok(*n as $t),
+                    DbValue::UInt(n) => Ok(*n as $t),
+                    DbValue::Bytes(_) => Ok(value.as_str()?.trim().parse()?),
+                    _ => Err(anyhow!("{:?} is not a number", value)),
+                }
+            }
+        }
+    )* }
+}
+from_db_float!(f32, f64);
+
+impl FromDbValue for bool {
+    /// MySQL booleans are TINYINT.
+    fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+        Ok(u8::from_db_value(value)? != 0)
+    }
+}
+
+impl FromDbValue for String {
+    fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+        Ok(value.as_str()?.to_string())
+    }
+}
+
+impl FromDbValue for Vec<u8> {
+    fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+        match value {
+            DbValue::Bytes(b) => Ok(b.clone()),
+            _ => Err(anyhow!("{:?} is not a blob", value)),
+        }
+    }
+}
+
+impl FromDbValue for DbValue {
+    /// As is.
+    fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: FromDbValue> FromDbValue for Option<T> {
+    /// NULL is None.
+    fn from_db_value(value: &DbValue) -> Result<Self, Error> {
+        match value {
+            DbValue::Null => Ok(None),
+            _ => Ok(Some(T::from_db_value(value)?)),
+        }
+    }
+}
+
+/// One row, columns in SELECT order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbRow(pub Vec<DbValue>);
+
+impl DbRow {
+    /// Column n, converted. Errors name the column.
+    pub fn get<T: FromDbValue>(&self, n: usize) -> Result<T, Error> {
+        let value = self.0.get(n).ok_or_else(|| anyhow!("Row has {} columns, no column {}", self.0.len(), n))?;
+        T::from_db_value(value).map_err(|e| anyhow!("Column {}: {}", n, e))
+    }
+}
+
+/// What handlers and the generator need from the database.
+pub trait Db {
+    /// Run a statement which returns no rows. Returns rows affected.
+    fn exec_drop(&mut self, sql: &str, params: Params) -> Result<u64, Error>;
+    /// Run a query. Returns all rows.
+    fn exec_rows(&mut self, sql: &str, params: Params) -> Result<Vec<DbRow>, Error>;
+    /// Run a query, passing each row to f as it arrives, for results too big to hold.
+    /// Stops at the first error. The default reads all the rows first.
+    fn exec_each(&mut self, sql: &str, params: Params, f: &mut dyn FnMut(DbRow) -> Result<(), Error>) -> Result<(), Error> {
+        self.exec_rows(sql, params)?.into_iter().try_for_each(f)
+    }
+    /// Start a transaction.
+    fn start_transaction(&mut self) -> Result<(), Error>;
+    /// Commit the transaction.
+    fn commit(&mut self) -> Result<(), Error>;
+    /// Roll back the transaction.
+    fn rollback(&mut self) -> Result<(), Error>;
+}
+
+impl dyn Db + '_ {
+    /// Run a query, converting each row.
+    pub fn exec_map<T>(&mut self, sql: &str, params: Params, f: impl FnMut(DbRow) -> Result<T, Error>) -> Result<Vec<T>, Error> {
+        self.exec_rows(sql, params)?.into_iter().map(f).collect()
+    }
+
+    /// Run a query which returns at most one row.
+    pub fn exec_first(&mut self, sql: &str, params: Params) -> Result<Option<DbRow>, Error> {
+        Ok(self.exec_rows(sql, params)?.into_iter().next())
+    }
+}
+
+impl Db for PooledConn {
+    fn exec_drop(&mut self, sql: &str, params: Params) -> Result<u64, Error> {
+        let result = self.exec_iter(sql, params)?;
+        Ok(result.affected_rows())
+    }
+
+    fn exec_rows(&mut self, sql: &str, params: Params) -> Result<Vec<DbRow>, Error> {
+        let rows: Vec<mysql::Row> = self.exec(sql, params)?;
+        Ok(rows.into_iter().map(|row| DbRow(row.unwrap().into_iter().map(DbValue::from).collect())).collect())
+    }
+
+    fn exec_each(&mut self, sql: &str, params: Params, f: &mut dyn FnMut(DbRow) -> Result<(), Error>) -> Result<(), Error> {
+        //  Rows are read from the server as iterated. Any left when f fails are read and dropped.
+        for row in self.exec_iter(sql, params)? {
+            f(DbRow(row?.unwrap().into_iter().map(DbValue::from).collect()))?;
+        }
+        Ok(())
+    }
+
+    fn start_transaction(&mut self) -> Result<(), Error> {
+        Ok(self.query_drop("START TRANSACTION")?)
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        Ok(self.query_drop("COMMIT")?)
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        Ok(self.query_drop("ROLLBACK")?)
+    }
+}
+
+/// Stand-in for the database, for tests.
+/// Records every statement, with its parameters. Queries return canned results in order,
+/// or no rows when those run out. A statement containing fail_on fails.
+#[derive(Debug, Default)]
+pub struct FakeDb {
+    /// Statements run, with whitespace collapsed. Transactions are START TRANSACTION, COMMIT, and ROLLBACK.
+    pub statements: Vec<(String, Params)>,
+    /// Rows for each query, in order.
+    pub results: VecDeque<Vec<DbRow>>,
+    /// Rows affected, for every exec_drop.
+    pub affected_rows: u64,
+    /// Fail any statement containing this.
+    pub fail_on: Option<String>,
+}
+
+impl FakeDb {
+    /// Queries will return these, in order.
+    pub fn new_with_results(results: Vec<Vec<DbRow>>) -> Self {
+        Self { results: results.into(), ..Default::default() }
+    }
+
+    /// Just the SQL of each statement run.
+    pub fn sql(&self) -> Vec<&str> {
+        self.statements.iter().map(|(sql, _)| sql.as_str()).collect()
+    }
+
+    /// Record a statement, failing if asked to.
+    fn record(&mut self, sql: &str, params: Params) -> Result<(), Error> {
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+        let fail = self.fail_on.as_ref().is_some_and(|f| sql.contains(f.as_str()));
+        self.statements.push((sql.clone(), params));
+        if fail {
+            return Err(anyhow!("FakeDb: failing statement: {}", sql));
+        }
+        Ok(())
+    }
+}
+
+impl Db for FakeDb {
+    fn exec_drop(&mut self, sql: &str, params: Params) -> Result<u64, Error> {
+        self.record(sql, params)?;
+        Ok(self.affected_rows)
+    }
+
+    fn exec_rows(&mut self, sql: &str, params: Params) -> Result<Vec<DbRow>, Error> {
+        self.record(sql, params)?;
+        Ok(self.results.pop_front().unwrap_or_default())
+    }
+
+    fn start_transaction(&mut self) -> Result<(), Error> {
+        self.record("START TRANSACTION", Params::Empty)
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.record("COMMIT", Params::Empty)
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        self.record("ROLLBACK", Params::Empty)
+    }
+}
+
+/// A FakeDb shared by its clones, for code which gets connections from a pool, such as handlers.
+/// Each clone is a connection. All of them record into, and take results from, the one FakeDb.
+#[derive(Debug, Clone, Default)]
+pub struct SharedFakeDb(Arc<Mutex<FakeDb>>);
+
+impl SharedFakeDb {
+    /// Share this FakeDb.
+    pub fn new(fake: FakeDb) -> Self {
+        Self(Arc::new(Mutex::new(fake)))
+    }
+
+    /// The FakeDb, to check what was run.
+    pub fn lock(&self) -> MutexGuard<'_, FakeDb> {
+        self.0.lock().expect("FakeDb lock")
+    }
+}
+
+impl Db for SharedFakeDb {
+    fn exec_drop(&mut self, sql: &str, params: Params) -> Result<u64, Error> {
+        self.lock().exec_drop(sql, params)
+    }
+
+    fn exec_rows(&mut self, sql: &str, params: Params) -> Result<Vec<DbRow>, Error> {
+        self.lock().exec_rows(sql, params)
+    }
+
+    fn start_transaction(&mut self) -> Result<(), Error> {
+        self.lock().start_transaction()
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.lock().commit()
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        self.lock().rollback()
+    }
+}
+
+#[test]
+fn test_db_row_conversion() {
+    let row = DbRow(vec![DbValue::UInt(256), DbValue::Int(-3), DbValue::Float(20.5), DbValue::text("Ahern"),
+        DbValue::Null, DbValue::text(" 42 "), DbValue::Bytes(vec![0, 255]), DbValue::Int(1)]);
+    assert_eq!(row.get::<u32>(0).unwrap(), 256);
+    assert_eq!(row.get::<f32>(0).unwrap(), 256.0);
+    assert_eq!(row.get::<i32>(1).unwrap(), -3);
+    assert_eq!(row.get::<f32>(2).unwrap(), 20.5);
+    assert_eq!(row.get::<String>(3).unwrap(), "Ahern");
+    assert_eq!(row.get::<Option<String>>(3).unwrap(), Some("Ahern".to_string()));
+    assert_eq!(row.get::<Option<String>>(4).unwrap(), None);
+    assert_eq!(row.get::<u32>(5).unwrap(), 42);
+    assert_eq!(row.get::<Vec<u8>>(6).unwrap(), vec![0, 255]);
+    assert!(row.get::<bool>(7).unwrap());
+    //  Errors name the column.
+    assert!(row.get::<u8>(0).unwrap_err().to_string().starts_with("Column 0:"));
+    assert!(row.get::<u32>(1).is_err());
+    assert!(row.get::<u32>(4).is_err());
+    assert!(row.get::<String>(2).is_err());
+    assert!(row.get::<u32>(8).unwrap_err().to_string().contains("no column 8"));
+}
+
+#[test]
+fn test_fake_db() {
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::UInt(7)])]]);
+    fake.affected_rows = 2;
+    fake.fail_on = Some("DELETE".to_string());
+    let db: &mut dyn Db = &mut fake;
+    let counts = db.exec_map("SELECT   COUNT(*)\n FROM t", Params::Empty, |row| row.get::<u32>(0)).unwrap();
+    assert_eq!(counts, vec![7]);
+    assert!(db.exec_first("SELECT COUNT(*) FROM t", Params::Empty).unwrap().is_none());
+    db.start_transaction().unwrap();
+    assert_eq!(db.exec_drop("UPDATE t SET x = 1", Params::Empty).unwrap(), 2);
+    assert!(db.exec_drop("DELETE FROM t", Params::Empty).is_err());
+    db.rollback().unwrap();
+    assert_eq!(fake.sql(), vec!["SELECT COUNT(*) FROM t", "SELECT COUNT(*) FROM t", "START TRANSACTION",
+        "UPDATE t SET x = 1", "DELETE FROM t", "ROLLBACK"]);
+    //  Shared. Clones record into the same FakeDb, and results come out in order whichever asks.
+    let shared = SharedFakeDb::new(FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::UInt(1)])], vec![DbRow(vec![DbValue::UInt(2)])]]));
+    let (mut first, mut second) = (shared.clone(), shared.clone());
+    assert_eq!((&mut second as &mut dyn Db).exec_map("SELECT x", Params::Empty, |row| row.get::<u32>(0)).unwrap(), vec![1]);
+    assert_eq!((&mut first as &mut dyn Db).exec_map("SELECT y", Params::Empty, |row| row.get::<u32>(0)).unwrap(), vec![2]);
+    assert_eq!(shared.lock().sql(), vec!["SELECT x", "SELECT y"]);
+    //  Row at a time. An error from the caller stops it.
+    let mut fake = FakeDb::new_with_results(vec![(1..=3).map(|n| DbRow(vec![DbValue::UInt(n)])).collect()]);
+    let mut seen = Vec::new();
+    let result = fake.exec_each("SELECT n", Params::Empty, &mut |row| {
+        seen.push(row.get::<u32>(0)?);
+        if seen.len() == 2 { Err(anyhow!("enough")) } else { Ok(()) }
+    });
+    assert!(result.is_err());
+    assert_eq!(seen, vec![1, 2]);
+}
@@ -0,0 +1,528 @@
+//! migrations.rs -- create and update the database tables.
+//! Part of the Animats impostor system
+//!
+//! The schema is an ordered list of migrations. Each has a version
+//! number, one more than the one before. The schema_version table
+//! records which have been applied. Migrating applies the ones that
+//! haven't been, in order.
+//!
+//! Steps are idempotent where MySQL allows, so a migration interrupted
+//! partway can be run again.
+//!
+//! Never edit a migration once it's in use. Add a new one.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::prelude::Queryable;
+use mysql::{params, PooledConn};
+
+/// Environment variable which turns on the schema check in the responders.
+pub const SCHEMA_CHECK_ENV: &str = "IMPOSTOR_SCHEMA_CHECK";
+
+/// Which migrations have been applied.
+const SQL_CREATE_SCHEMA_VERSION: &str = r"CREATE TABLE IF NOT EXISTS schema_version (
+    version INT NOT NULL,
+    description VARCHAR(100) NOT NULL,
+    applied TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (version)
+)";
+/// Current version. NULL if nothing applied.
+const SQL_GET_VERSION: &str = r"SELECT MAX(version) FROM schema_version";
+/// Record a migration as applied.
+const SQL_SET_VERSION: &str = r"INSERT INTO schema_version (version, description) VALUES (:version, :description)";
+
+/// Raw terrain heights. Updated by an LSL script that visits regions.
+const SQL_CREATE_RAW_TERRAIN_HEIGHTS: &str = r"CREATE TABLE IF NOT EXISTS raw_terrain_heights (
+    grid VARCHAR(40) NOT NULL,
+    region_loc_x INT NOT NULL,
+    region_loc_y INT NOT NULL,
+    region_size_x INT NOT NULL,
+    region_size_y INT NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    scale FLOAT NOT NULL,
+    offset FLOAT NOT NULL,
+    samples_x INT NOT NULL,
+    samples_y INT NOT NULL,
+    elevs MEDIUMBLOB NOT NULL,
+    water_level FLOAT NOT NULL,
+    creator VARCHAR(63) NOT NULL,
+    creation_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    confirmer VARCHAR(63) DEFAULT NULL,
+    confirmation_time TIMESTAMP DEFAULT NULL,
+    last_uploaded TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX (grid, region_loc_x, region_loc_y),
+    INDEX(name)
+)";
+
+/// Impostor information. What the viewer needs to draw an impostor.
+const SQL_CREATE_REGION_IMPOSTORS: &str = r"CREATE TABLE IF NOT EXISTS region_impostors (
+    grid VARCHAR(40) NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    region_loc_x INT NOT NULL,
+    region_loc_y INT NOT NULL,
+    region_size_x INT NOT NULL,
+    region_size_y INT NOT NULL,
+    scale_x INT NOT NULL,
+    scale_y INT NOT NULL,
+    scale_z FLOAT NOT NULL,
+    elevation_offset FLOAT NOT NULL,
+    impostor_lod TINYINT NOT NULL,
+    viz_group INT NOT NULL,
+    uniqueness_viz_group INT DEFAULT NULL,
+    mesh_uuid CHAR(36) DEFAULT NULL,
+    mesh_hash CHAR(8) DEFAULT NULL,
+    sculpt_uuid CHAR(36) DEFAULT NULL,
+    sculpt_hash CHAR(8) DEFAULT NULL,
+    water_height FLOAT NOT NULL,
+    creator VARCHAR(63) NOT NULL,
+    creation_time TIMESTAMP NOT NULL,
+    faces_json JSON NOT NULL,
+    UNIQUE INDEX (grid, region_loc_x, region_loc_y, impostor_lod, uniqueness_viz_group),
+    INDEX(grid, viz_group),
+    INDEX(name)
+)";
+
+/// Region textures. Used to hold texture information which needs to be matched to geometry.
+const SQL_CREATE_TILE_ASSETS: &str = r"CREATE TABLE IF NOT EXISTS tile_assets (
+    grid VARCHAR(40) NOT NULL,
+    region_loc_x INT NOT NULL,
+    region_loc_y INT NOT NULL,
+    region_size_x INT NOT NULL,
+    region_size_y INT NOT NULL,
+    impostor_lod TINYINT NOT NULL,
+    viz_group INT NOT NULL,
+    asset_name VARCHAR(63) NOT NULL,
+    asset_type VARCHAR(20) NOT NULL,
+    texture_index SMALLINT DEFAULT NULL,
+    asset_uuid CHAR(36) NOT NULL,
+    asset_hash CHAR(8) NOT NULL,
+    creation_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX (grid, region_loc_x, region_loc_y, impostor_lod, viz_group, texture_index),
+    UNIQUE INDEX (grid, asset_name)
+)";
+
+/// The next version of region_impostors, filled in as assets are uploaded.
+/// When all UUIDs for a grid are present, it's copied over region_impostors.
+const SQL_CREATE_INITIAL_IMPOSTORS: &str = r"CREATE TABLE IF NOT EXISTS initial_impostors LIKE region_impostors";
+
+/// When the terrain was last uploaded. In the table as created by version 1, but not in tables
+/// created before there were migrations, which version 1 leaves alone.
+/// MySQL has no ADD COLUMN IF NOT EXISTS, so the ALTER is prepared only if the column is missing.
+/// Statements run on one connection, so the session variables carry from one to the next.
+const SQL_FIND_LAST_UPLOADED: &str = r"SET @missing_last_uploaded = (SELECT COUNT(*) = 0 FROM information_schema.COLUMNS
+    WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'raw_terrain_heights' AND COLUMN_NAME = 'last_uploaded')";
+const SQL_ADD_LAST_UPLOADED_IF_MISSING: &str = r"SET @guarded_sql = IF(@missing_last_uploaded,
+    'ALTER TABLE raw_terrain_heights ADD COLUMN last_uploaded TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP', 'DO 0')";
+const SQL_PREPARE_GUARDED: &str = r"PREPARE guarded_statement FROM @guarded_sql";
+const SQL_EXECUTE_GUARDED: &str = r"EXECUTE guarded_statement";
+const SQL_DEALLOCATE_GUARDED: &str = r"DEALLOCATE PREPARE guarded_statement";
+/// Rows which just got the column were last uploaded when created, not now.
+const SQL_FILL_LAST_UPLOADED: &str = r"UPDATE raw_terrain_heights SET last_uploaded = creation_time WHERE @missing_last_uploaded";
+
+/// When the terrain was last uploaded or confirmed unchanged. For finding regions needing a re-survey.
+/// MySQL has no ADD COLUMN IF NOT EXISTS, so this one can't be run twice.
+const SQL_ADD_LAST_CONFIRMED: &str = r"ALTER TABLE raw_terrain_heights ADD COLUMN last_confirmed TIMESTAMP NULL DEFAULT NULL";
+/// Existing rows were last seen when last confirmed, or else when uploaded.
+const SQL_FILL_LAST_CONFIRMED: &str = r"UPDATE raw_terrain_heights SET last_confirmed = COALESCE(confirmation_time, last_uploaded) WHERE last_confirmed IS NULL";
+
+/// Regions gone from the grid. Kept, so a later upload can bring them back.
+const SQL_ADD_DELETED: &str = r"ALTER TABLE raw_terrain_heights
+    ADD COLUMN deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN deletion_time TIMESTAMP NULL DEFAULT NULL";
+
+/// Generation of each impostor, bumped when its assets change, so viewers can tell a cached impostor is stale.
+/// Indexed for "everything newer than" queries. initial_impostors gets the same column in the same place,
+/// because promotion copies rows with SELECT *.
+const SQL_ADD_GENERATION: &str = r"ALTER TABLE region_impostors
+    ADD COLUMN generation INT UNSIGNED NOT NULL DEFAULT 0,
+    ADD INDEX (grid, generation)";
+const SQL_ADD_INITIAL_GENERATION: &str = r"ALTER TABLE initial_impostors
+    ADD COLUMN generation INT UNSIGNED NOT NULL DEFAULT 0,
+    ADD INDEX (grid, generation)";
+
+/// Highest water level in a lower LOD tile whose regions have different water levels.
+/// water_height is the lowest. NULL when they're all the same.
+const SQL_ADD_WATER_HEIGHT_MAX: &str = r"ALTER TABLE region_impostors ADD COLUMN water_height_max FLOAT NULL DEFAULT NULL";
+const SQL_ADD_INITIAL_WATER_HEIGHT_MAX: &str = r"ALTER TABLE initial_impostors ADD COLUMN water_height_max FLOAT NULL DEFAULT NULL";
+
+/// Grid names are stored in canonical lower case form. Older rows may not be.
+/// Where "Agni" and "agni" rows are the same region, keep the newer one, then lower case the rest.
+/// Compared BINARY, so this works with case sensitive and case insensitive collations.
+const SQL_MERGE_RAW_TERRAIN_GRID_CASE: &str = r"DELETE r FROM raw_terrain_heights AS r
+    JOIN raw_terrain_heights AS keep ON LOWER(TRIM(keep.grid)) = LOWER(TRIM(r.grid))
+        AND keep.region_loc_x = r.region_loc_x AND keep.region_loc_y = r.region_loc_y
+        AND (keep.last_uploaded, BINARY keep.grid) > (r.last_uploaded, BINARY r.grid)";
+const SQL_LOWER_RAW_TERRAIN_GRID: &str = r"UPDATE raw_terrain_heights SET grid = LOWER(TRIM(grid))
+    WHERE BINARY grid <> BINARY LOWER(TRIM(grid))";
+const SQL_MERGE_IMPOSTOR_GRID_CASE: &str = r"DELETE r FROM region_impostors AS r
+    JOIN region_impostors AS keep ON LOWER(TRIM(keep.grid)) = LOWER(TRIM(r.grid))
+        AND keep.region_loc_x = r.region_loc_x AND keep.region_loc_y = r.region_loc_y AND keep.impostor_lod = r.impostor_lod
+        AND keep.uniqueness_viz_group <=> r.uniqueness_viz_group
+        AND (keep.creation_time, BINARY keep.grid) > (r.creation_time, BINARY r.grid)";
+const SQL_LOWER_IMPOSTOR_GRID: &str = r"UPDATE region_impostors SET grid = LOWER(TRIM(grid))
+    WHERE BINARY grid <> BINARY LOWER(TRIM(grid))";
+const SQL_MERGE_INITIAL_GRID_CASE: &str = r"DELETE r FROM initial_impostors AS r
+    JOIN initial_impostors AS keep ON LOWER(TRIM(keep.grid)) = LOWER(TRIM(r.grid))
+        AND keep.region_loc_x = r.region_loc_x AND keep.region_loc_y = r.region_loc_y AND keep.impostor_lod = r.impostor_lod
+        AND keep.uniqueness_viz_group <=> r.uniqueness_viz_group
+        AND (keep.creation_time, BINARY keep.grid) > (r.creation_time, BINARY r.grid)";
+const SQL_LOWER_INITIAL_GRID: &str = r"UPDATE initial_impostors SET grid = LOWER(TRIM(grid))
+    WHERE BINARY grid <> BINARY LOWER(TRIM(grid))";
+
+/// Impostor assets no longer used, for the in-world cleanup script to delete.
+const SQL_CREATE_RETIRED_ASSETS: &str = r"CREATE TABLE IF NOT EXISTS retired_assets (
+    grid VARCHAR(40) NOT NULL,
+    asset_uuid CHAR(36) NOT NULL,
+    asset_kind VARCHAR(8) NOT NULL,
+    retirement_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX (grid, asset_uuid),
+    INDEX(grid, retirement_time)
+)";
+
+/// Impostors changed by each promotion, for viewers following a viz group. Append only, pruned by age.
+const SQL_CREATE_IMPOSTOR_CHANGES: &str = r"CREATE TABLE IF NOT EXISTS impostor_changes (
+    grid VARCHAR(40) NOT NULL,
+    viz_group INT NOT NULL,
+    region_loc_x INT NOT NULL,
+    region_loc_y INT NOT NULL,
+    impostor_lod TINYINT NOT NULL,
+    generation INT NOT NULL,
+    removed BOOLEAN NOT NULL DEFAULT FALSE,
+    change_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX(grid, viz_group, change_time),
+    INDEX(grid, viz_group, generation),
+    INDEX(grid, change_time)
+)";
+
+/// Regions known to exist but perhaps not surveyed yet, from a region list.
+/// The generator makes flat placeholder impostors for those with no raw terrain.
+const SQL_CREATE_KNOWN_REGIONS: &str = r"CREATE TABLE IF NOT EXISTS known_regions (
+    grid VARCHAR(40) NOT NULL,
+    region_loc_x INT NOT NULL,
+    region_loc_y INT NOT NULL,
+    region_size_x INT NOT NULL,
+    region_size_y INT NOT NULL,
+    name VARCHAR(100) NOT NULL,
+    source VARCHAR(100) NOT NULL,
+    creation_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE INDEX (grid, region_loc_x, region_loc_y)
+)";
+/// Impostors for known regions with no terrain yet, so the viewer can show them dimmed.
+const SQL_ADD_PLACEHOLDER: &str = r"ALTER TABLE region_impostors ADD COLUMN placeholder BOOLEAN NOT NULL DEFAULT FALSE";
+const SQL_ADD_INITIAL_PLACEHOLDER: &str = r"ALTER TABLE initial_impostors ADD COLUMN placeholder BOOLEAN NOT NULL DEFAULT FALSE";
+
+/// Sample spacing, meters, when the uploader sent one. NULL means derived from the sample counts.
+const SQL_ADD_SAMPLE_SPACING_X: &str = r"ALTER TABLE raw_terrain_heights ADD COLUMN sample_spacing_x FLOAT NULL DEFAULT NULL";
+const SQL_ADD_SAMPLE_SPACING_Y: &str = r"ALTER TABLE raw_terrain_heights ADD COLUMN sample_spacing_y FLOAT NULL DEFAULT NULL";
+
+/// Recommended camera distance for switching to each impostor, from the generator. 0 for older impostors.
+const SQL_ADD_LOD_DISTANCE: &str = r"ALTER TABLE region_impostors ADD COLUMN lod_distance FLOAT NOT NULL DEFAULT 0";
+const SQL_ADD_INITIAL_LOD_DISTANCE: &str = r"ALTER TABLE initial_impostors ADD COLUMN lod_distance FLOAT NOT NULL DEFAULT 0";
+
+/// Impostors for tiles entirely under water, which have no assets.
+const SQL_ADD_WATER_ONLY: &str = r"ALTER TABLE region_impostors ADD COLUMN water_only BOOLEAN NOT NULL DEFAULT FALSE";
+const SQL_ADD_INITIAL_WATER_ONLY: &str = r"ALTER TABLE initial_impostors ADD COLUMN water_only BOOLEAN NOT NULL DEFAULT FALSE";
+
+/// Which generator, settings, and raw terrain made each impostor, as JSON. NULL for older impostors.
+const SQL_ADD_PROVENANCE: &str = r"ALTER TABLE region_impostors ADD COLUMN provenance_json TEXT NULL DEFAULT NULL";
+const SQL_ADD_INITIAL_PROVENANCE: &str = r"ALTER TABLE initial_impostors ADD COLUMN provenance_json TEXT NULL DEFAULT NULL";
+
+/// Stored elevations from the legacy encoding, byte / 256 * scale, to the current one, byte / 255 * scale.
+/// Only the scale changes, so every sample decodes to the same elevation as before.
+const SQL_RESCALE_LEGACY_ELEVS: &str = r"UPDATE raw_terrain_heights SET scale = scale * 255 / 256";
+
+/// Hash of the height field and generation parameters each tile was built from, for skipping
+/// unchanged tiles. NULL for older impostors, which are rebuilt once, reusing their assets.
+const SQL_ADD_TERRAIN_HASH: &str = r"ALTER TABLE region_impostors ADD COLUMN terrain_hash CHAR(64) NULL DEFAULT NULL";
+const SQL_ADD_INITIAL_TERRAIN_HASH: &str = r"ALTER TABLE initial_impostors ADD COLUMN terrain_hash CHAR(64) NULL DEFAULT NULL";
+
+/// Placeholder impostors have no known water height. Older ones were given the default.
+const SQL_NULLABLE_WATER_HEIGHT: &str = r"ALTER TABLE region_impostors MODIFY COLUMN water_height FLOAT NULL DEFAULT NULL";
+const SQL_NULLABLE_INITIAL_WATER_HEIGHT: &str = r"ALTER TABLE initial_impostors MODIFY COLUMN water_height FLOAT NULL DEFAULT NULL";
+const SQL_CLEAR_PLACEHOLDER_WATER_HEIGHT: &str = r"UPDATE region_impostors SET water_height = NULL WHERE placeholder";
+const SQL_CLEAR_INITIAL_PLACEHOLDER_WATER_HEIGHT: &str = r"UPDATE initial_impostors SET water_height = NULL WHERE placeholder";
+
+/// Each grid's generation serial, bumped by every change to its live impostors.
+const SQL_CREATE_GRID_GENERATIONS: &str = r"CREATE TABLE IF NOT EXISTS grid_generations (
+    grid VARCHAR(40) NOT NULL PRIMARY KEY,
+    generation INT UNSIGNED NOT NULL
+)";
+/// Start each grid above every generation viewers have seen, per-tile ones and removals included.
+const SQL_FILL_GRID_GENERATIONS: &str = r"INSERT IGNORE INTO grid_generations (grid, generation)
+    SELECT grid, MAX(generation) FROM
+        (SELECT grid, generation FROM region_impostors UNION ALL SELECT grid, generation FROM impostor_changes) AS seen
+    GROUP BY grid";
+
+/// One step in the schema's history.
+#[derive(Debug)]
+pub struct Migration {
+    /// Schema version after this migration.
+    pub version: u32,
+    /// What it does, for schema_version and logs.
+    pub description: &'static str,
+    /// SQL statements, run in order.
+    pub statements: &'static [&'static str],
+}
+
+/// All migrations, in order.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Terrain, impostor, and tile asset tables",
+        statements: &[SQL_CREATE_RAW_TERRAIN_HEIGHTS, SQL_CREATE_REGION_IMPOSTORS, SQL_CREATE_TILE_ASSETS],
+    },
+    Migration {
+        version: 2,
+        description: "initial_impostors, for promotion",
+        statements: &[SQL_CREATE_INITIAL_IMPOSTORS],
+    },
+    Migration {
+        version: 3,
+        description: "raw_terrain_heights.last_uploaded, for databases older than the migrations",
+        statements: &[SQL_FIND_LAST_UPLOADED, SQL_ADD_LAST_UPLOADED_IF_MISSING, SQL_PREPARE_GUARDED, SQL_EXECUTE_GUARDED, SQL_DEALLOCATE_GUARDED,
+            SQL_FILL_LAST_UPLOADED],
+    },
+    Migration {
+        version: 4,
+        description: "raw_terrain_heights.last_confirmed, for staleness",
+        statements: &[SQL_ADD_LAST_CONFIRMED, SQL_FILL_LAST_CONFIRMED],
+    },
+    Migration {
+        version: 5,
+        description: "raw_terrain_heights.deleted, for regions gone from the grid",
+        statements: &[SQL_ADD_DELETED],
+    },
+    Migration {
+        version: 6,
+        description: "Impostor generation, for viewer cache refresh",
+        statements: &[SQL_ADD_GENERATION, SQL_ADD_INITIAL_GENERATION],
+    },
+    Migration {
+        version: 7,
+        description: "Impostor water_height_max, for non-uniform water levels",
+        statements: &[SQL_ADD_WATER_HEIGHT_MAX, SQL_ADD_INITIAL_WATER_HEIGHT_MAX],
+    },
+    Migration {
+        version: 8,
+        description: "Lower case grid names, merging rows which differ only in case",
+        statements: &[SQL_MERGE_RAW_TERRAIN_GRID_CASE, SQL_LOWER_RAW_TERRAIN_GRID, SQL_MERGE_IMPOSTOR_GRID_CASE,
+            SQL_LOWER_IMPOSTOR_GRID, SQL_MERGE_INITIAL_GRID_CASE, SQL_LOWER_INITIAL_GRID],
+    },
+    Migration {
+        version: 9,
+        description: "Retired impostor assets",
+        statements: &[SQL_CREATE_RETIRED_ASSETS],
+    },
+    Migration {
+        version: 10,
+        description: "Impostor change feed",
+        statements: &[SQL_CREATE_IMPOSTOR_CHANGES],
+    },
+    Migration {
+        version: 11,
+        description: "Known regions, and placeholder impostors for them",
+        statements: &[SQL_CREATE_KNOWN_REGIONS, SQL_ADD_PLACEHOLDER, SQL_ADD_INITIAL_PLACEHOLDER],
+    },
+    Migration {
+        version: 12,
+        description: "Raw terrain sample spacing",
+        statements: &[SQL_ADD_SAMPLE_SPACING_X, SQL_ADD_SAMPLE_SPACING_Y],
+    },
+    Migration {
+        version: 13,
+        description: "Impostor lod_distance, for viewer LOD selection",
+        statements: &[SQL_ADD_LOD_DISTANCE, SQL_ADD_INITIAL_LOD_DISTANCE],
+    },
+    Migration {
+        version: 14,
+        description: "Water-only impostors, with no assets",
+        statements: &[SQL_ADD_WATER_ONLY, SQL_ADD_INITIAL_WATER_ONLY],
+    },
+    Migration {
+        version: 15,
+        description: "Impostor provenance, for tracing impostors to the generator run",
+        statements: &[SQL_ADD_PROVENANCE, SQL_ADD_INITIAL_PROVENANCE],
+    },
+    Migration {
+        version: 16,
+        description: "Grid generation serial, so generations compare across tiles",
+        statements: &[SQL_CREATE_GRID_GENERATIONS, SQL_FILL_GRID_GENERATIONS],
+    },
+    Migration {
+        version: 17,
+        description: "Raw terrain elevations in the 255 step encoding",
+        statements: &[SQL_RESCALE_LEGACY_ELEVS],
+    },
+    Migration {
+        version: 18,
+        description: "Terrain hash, for skipping unchanged tiles",
+        statements: &[SQL_ADD_TERRAIN_HASH, SQL_ADD_INITIAL_TERRAIN_HASH],
+    },
+    Migration {
+        version: 19,
+        description: "No water height for placeholder impostors",
+        statements: &[SQL_NULLABLE_WATER_HEIGHT, SQL_NULLABLE_INITIAL_WATER_HEIGHT, SQL_CLEAR_PLACEHOLDER_WATER_HEIGHT,
+            SQL_CLEAR_INITIAL_PLACEHOLDER_WATER_HEIGHT],
+    },
+];
+
+/// What a migrate run did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedMigrations {
+    /// Schema version before
+    pub from: u32,
+    /// Schema version after
+    pub to: u32,
+    /// Versions applied, in order
+    pub applied: Vec<u32>,
+}
+
+impl std::fmt::Display for AppliedMigrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.applied.is_empty() {
+            write!(f, "Schema is up to date at version {}.", self.to)
+        } else {
+            write!(f, "Schema migrated from version {} to {}, applied {:?}.", self.from, self.to, self.applied)
+        }
+    }
+}
+
+/// Versions must start at 1 and go up by 1, with no gaps or repeats.
+fn check_order(migrations: &[Migration]) -> Result<(), Error> {
+    for (n, migration) in migrations.iter().enumerate() {
+        if migration.version as usize != n + 1 {
+            return Err(anyhow!("Migration \"{}\" has version {}, expected {}", migration.description, migration.version, n + 1));
+        }
+    }
+    Ok(())
+}
+
+/// Migrations not yet applied to a schema at this version, in order.
+fn pending(migrations: &'static [Migration], current: u32) -> Result<&'static [Migration], Error> {
+    check_order(migrations)?;
+    if current as usize > migrations.len() {
+        return Err(anyhow!("Database schema version {} is newer than this program, which knows up to {}", current, migrations.len()));
+    }
+    Ok(&migrations[current as usize..])
+}
+
+/// Newest schema version this program knows about.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Current schema version. 0 if nothing has been applied.
+pub fn current_version(conn: &mut PooledConn) -> Result<u32, Error> {
+    conn.query_drop(SQL_CREATE_SCHEMA_VERSION)?;
+    let version: Option<Option<u32>> = conn.query_first(SQL_GET_VERSION)?;
+    Ok(version.flatten().unwrap_or(0))
+}
+
+/// Bring the schema up to date.
+/// Running this again does nothing.
+pub fn migrate(conn: &mut PooledConn) -> Result<AppliedMigrations, Error> {
+    let from = current_version(conn)?;
+    let mut applied = Vec::new();
+    for migration in pending(MIGRATIONS, from)? {
+        log::info!("Applying schema migration {}: {}", migration.version, migration.description);
+        //  MySQL commits DDL statements immediately, so there's no transaction here.
+        for statement in migration.statements {
+            conn.query_drop(statement)
+                .map_err(|e| anyhow!("Schema migration {} failed: {}\nSQL: {}", migration.version, e, statement))?;
+        }
+        conn.exec_drop(SQL_SET_VERSION, params! { "version" => migration.version, "description" => migration.description })?;
+        applied.push(migration.version);
+    }
+    let report = AppliedMigrations { from, to: from.max(applied.last().copied().unwrap_or(0)), applied };
+    log::info!("{}", report);
+    Ok(report)
+}
+
+/// Check the schema is current, if the environment asks for it.
+/// For the responders, which never migrate. Logs, but doesn't fail,
+/// because old-schema requests may still work.
+pub fn check_schema_if_enabled(conn: &mut PooledConn) {
+    if std::env::var(SCHEMA_CHECK_ENV).map(|v| v.trim().is_empty() || v.trim() == "0").unwrap_or(true) {
+        return;
+    }
+    match current_version(conn) {
+        Ok(version) if version < latest_version() => log::error!(
+            "DATABASE SCHEMA IS OUT OF DATE: version {}, this program needs {}. Run generateterrain --migrate.",
+            version, latest_version()),
+        Ok(version) if version > latest_version() => log::warn!(
+            "Database schema version {} is newer than this program, which knows up to {}.", version, latest_version()),
+        Ok(_) => log::info!("Database schema is up to date."),
+        Err(e) => log::error!("Unable to check database schema version: {:?}", e),
+    }
+}
+
+#[test]
+fn test_migration_order() {
+    //  The real list is in order.
+    assert!(check_order(MIGRATIONS).is_ok());
+    assert_eq!(latest_version(), MIGRATIONS.len() as u32);
+    //  Everything from scratch, in order.
+    let all: Vec<u32> = pending(MIGRATIONS, 0).expect("pending").iter().map(|m| m.version).collect();
+    assert_eq!(all, (1..=latest_version()).collect::<Vec<u32>>());
+    //  Region impostors must exist before a table LIKE it.
+    let statements: Vec<&str> = pending(MIGRATIONS, 0).unwrap().iter().flat_map(|m| m.statements.iter().copied()).collect();
+    let position = |table: &str| statements.iter().position(|s| s.contains(&format!("EXISTS {} ", table))).expect(table);
+    assert!(position("region_impostors") < position("initial_impostors"));
+    //  Only the rest, part way.
+    let rest: Vec<u32> = pending(MIGRATIONS, 1).unwrap().iter().map(|m| m.version).collect();
+    assert_eq!(rest, (2..=latest_version()).collect::<Vec<u32>>());
+    //  Second run does nothing.
+    assert!(pending(MIGRATIONS, latest_version()).unwrap().is_empty());
+    //  Schema from a newer program.
+    assert!(pending(MIGRATIONS, latest_version() + 1).is_err());
+    //  Every table creation can be run again.
+    for statement in statements.iter().filter(|s| s.starts_with("CREATE")) {
+        assert!(statement.starts_with("CREATE TABLE IF NOT EXISTS "), "{}", statement);
+    }
+    //  Columns are added after the table is created.
+    assert!(statements.iter().position(|s| s.contains("ADD COLUMN last_confirmed")).expect("last_confirmed") > position("raw_terrain_heights"));
+    //  last_uploaded is added, only where it's missing, before anything uses it.
+    let uses = statements.iter().position(|s| s.contains("last_uploaded") && !s.contains("@missing_last_uploaded") && !s.contains("CREATE TABLE")).expect("uses");
+    assert!(statements.iter().position(|s| s.contains("ADD COLUMN last_uploaded")).expect("last_uploaded") < uses);
+    assert!(statements.iter().all(|s| !s.contains(" AFTER ")));
+    let guarded = MIGRATIONS.iter().find(|m| m.version == 3).unwrap().statements;
+    assert_eq!(guarded, &[SQL_FIND_LAST_UPLOADED, SQL_ADD_LAST_UPLOADED_IF_MISSING, SQL_PREPARE_GUARDED, SQL_EXECUTE_GUARDED,
+        SQL_DEALLOCATE_GUARDED, SQL_FILL_LAST_UPLOADED]);
+    assert!(SQL_ADD_LAST_UPLOADED_IF_MISSING.contains("IF(@missing_last_uploaded,"));
+    //  Out of order, gaps, and repeats are caught.
+    static BAD: &[Migration] = &[
+        Migration { version: 1, description: "one", statements: &[] },
+        Migration { version: 3, description: "three", statements: &[] },
+    ];
+    assert!(pending(BAD, 0).is_err());
+    static REPEAT: &[Migration] = &[
+        Migration { version: 1, description: "one", statements: &[] },
+        Migration { version: 1, description: "one again", statements: &[] },
+    ];
+    assert!(check_order(REPEAT).is_err());
+}
+
+#[test]
+fn test_applied_migrations_display() {
+    let none = AppliedMigrations { from: 2, to: 2, applied: Vec::new() };
+    assert_eq!(none.to_string(), "Schema is up to date at version 2.");
+    let some = AppliedMigrations { from: 0, to: 2, applied: vec![1, 2] };
+    assert_eq!(some.to_string(), "Schema migrated from version 0 to 2, applied [1, 2].");
+}
+
+#[test]
+fn test_grid_case_merge() {
+    //  Each table is merged before it's lower cased, or the lower casing would hit the unique index.
+    let statements = MIGRATIONS.iter().find(|m| m.version == 8).unwrap().statements;
+    for table in ["raw_terrain_heights", "region_impostors", "initial_impostors"] {
+        let merge = statements.iter().position(|s| s.starts_with(&format!("DELETE r FROM {} ", table))).expect(table);
+        let lower = statements.iter().position(|s| s.starts_with(&format!("UPDATE {} SET grid = LOWER(TRIM(grid))", table))).expect(table);
+        assert!(merge < lower, "{}", table);
+        //  Exactly one row of a group survives, so the comparison must be strict and total.
+        assert!(statements[merge].contains(", BINARY keep.grid) > ("), "{}", table);
+    }
+}
+
@@ -0,0 +1,335 @@
+//! dbcheck.rs -- consistency check of the raw terrain table.
+//! Part of the Animats impostor system
+//!
+//! Rows in raw_terrain_heights have accumulated from several generations
+//! of the uploader. Some predate samples_x and samples_y, some have grid
+//! names in whatever case the uploader sent, and a few are just wrong.
+//! This finds such rows, and optionally fixes the ones that can be fixed.
+//!
+//! Fixable: missing or wrong sample counts, when the blob is a perfect square,
+//! and grid names not in canonical form. Not fixable: anything else.
+//! Those regions need a re-survey.
+//!
+//! The checking is all here, on rows already read. The SQL is a thin wrapper.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::Error;
+use mysql::params;
+use crate::db::Db;
+use crate::grid::canonical;
+
+/// Everything needed to check a row. Not the blob itself, just its length.
+const SQL_SELECT_ROWS: &str = r"SELECT grid, region_loc_x, region_loc_y, region_size_x, region_size_y,
+        samples_x, samples_y, COALESCE(LENGTH(elevs), 0)
+    FROM raw_terrain_heights
+    ORDER BY grid, region_loc_x, region_loc_y";
+
+/// Set sample counts for one row. BINARY, so case variants of the grid name are separate rows.
+const SQL_REPAIR_SAMPLES: &str = r"UPDATE raw_terrain_heights SET samples_x = :samples_x, samples_y = :samples_y
+    WHERE BINARY grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+
+/// Canonical grid name for one row. IGNORE, so a row whose canonical twin
+/// already exists is left alone rather than failing the whole repair.
+const SQL_REPAIR_GRID: &str = r"UPDATE IGNORE raw_terrain_heights SET grid = :canonical
+    WHERE BINARY grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+
+/// Region sizes are multiples of this, meters.
+const REGION_SIZE_UNIT: u32 = 256;
+
+/// One row of raw_terrain_heights, as far as checking is concerned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawTerrainRow {
+    /// Grid name, as stored
+    pub grid: String,
+    /// Location in world, meters
+    pub region_loc: [u32; 2],
+    /// Size, meters
+    pub region_size: [u32; 2],
+    /// samples_x and samples_y. NULL in some very old rows.
+    pub samples: [Option<u32>; 2],
+    /// Length of the elevs blob, bytes
+    pub elevs_len: usize,
+}
+
+/// Something wrong with a row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowProblem {
+    /// Blob length is not samples_x * samples_y.
+    BlobLength { samples: [u32; 2], elevs_len: usize },
+    /// samples_x or samples_y is zero or NULL.
+    NoSamples { elevs_len: usize },
+    /// Grid name is not in canonical form.
+    GridNotCanonical { canonical: String },
+    /// Region size is not a multiple of 256.
+    SizeNotMultiple { region_size: [u32; 2] },
+}
+
+impl std::fmt::Display for RowProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RowProblem::BlobLength { samples, elevs_len } =>
+                write!(f, "blob is {} bytes, but samples are {} x {}", elevs_len, samples[0], samples[1]),
+            RowProblem::NoSamples { elevs_len } => write!(f, "no sample counts, blob is {} bytes", elevs_len),
+            RowProblem::GridNotCanonical { canonical } => write!(f, "grid name is not canonical, should be \"{}\"", canonical),
+            RowProblem::SizeNotMultiple { region_size } =>
+                write!(f, "size {} x {} is not a multiple of {}", region_size[0], region_size[1], REGION_SIZE_UNIT),
+        }
+    }
+}
+
+/// What a repair would change in a row.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RowRepair {
+    /// New samples_x and samples_y, both this.
+    pub samples: Option<u32>,
+    /// New grid name.
+    pub grid: Option<String>,
+}
+
+/// Problems with one row, and the repair for them, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowFinding {
+    /// Grid name, as stored
+    pub grid: String,
+    /// Location in world, meters
+    pub region_loc: [u32; 2],
+    /// What's wrong
+    pub problems: Vec<RowProblem>,
+    /// What can be fixed. None if nothing can.
+    pub repair: Option<RowRepair>,
+}
+
+impl RowFinding {
+    /// Problems with this row, and the fix. None if nothing is wrong.
+    pub fn new_from_row(row: &RawTerrainRow) -> Option<Self> {
+        let problems = check_row(row);
+        if problems.is_empty() {
+            return None;
+        }
+        let repair = repair_for(row, &problems);
+        Some(Self { grid: row.grid.clone(), region_loc: row.region_loc, problems, repair })
+    }
+}
+
+/// Everything wrong with a row. Empty if it's fine.
+pub fn check_row(row: &RawTerrainRow) -> Vec<RowProblem> {
+    let mut problems = Vec::new();
+    match row.samples {
+        [Some(x), Some(y)] if x > 0 && y > 0 => {
+            if (x as usize) * (y as usize) != row.elevs_len {
+                problems.push(RowProblem::BlobLength { samples: [x, y], elevs_len: row.elevs_len });
+            }
+        }
+        _ => problems.push(RowProblem::NoSamples { elevs_len: row.elevs_len }),
+    }
+    let canonical = canonical(&row.grid);
+    if canonical != row.grid {
+        problems.push(RowProblem::GridNotCanonical { canonical });
+    }
+    if row.region_size.iter().any(|&s| s == 0 || s % REGION_SIZE_UNIT != 0) {
+        problems.push(RowProblem::SizeNotMultiple { region_size: row.region_size });
+    }
+    problems
+}
+
+/// The fix for these problems, if any of them can be fixed.
+/// Sample counts can only be inferred for a square blob. Varregions with
+/// shared edges, such as 65 x 129, are never square, and need a re-survey.
+pub fn repair_for(row: &RawTerrainRow, problems: &[RowProblem]) -> Option<RowRepair> {
+    let mut repair = RowRepair::default();
+    for problem in problems {
+        match problem {
+            RowProblem::BlobLength { .. } | RowProblem::NoSamples { .. } => repair.samples = square_side(row.elevs_len),
+            RowProblem::GridNotCanonical { canonical } if !canonical.is_empty() => repair.grid = Some(canonical.clone()),
+            _ => {}
+        }
+    }
+    (repair != RowRepair::default()).then_some(repair)
+}
+
+/// Side of a square with this many samples. None if not a square, or empty.
+fn square_side(len: usize) -> Option<u32> {
+    let side = (len as f64).sqrt().round() as usize;
+    (side > 0 && side * side == len).then_some(side as u32)
+}
+
+/// Results of checking the whole table.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DbCheckReport {
+    /// Rows looked at
+    pub rows_checked: usize,
+    /// Rows with problems, in table order
+    pub findings: Vec<RowFinding>,
+    /// Rows repaired
+    pub repaired: usize,
+    /// Repairs attempted which changed nothing. Usually a canonical twin already exists.
+    pub repair_failed: usize,
+}
+
+impl DbCheckReport {
+    /// Check these rows. No repairs yet.
+    pub fn new_from_rows(rows: &[RawTerrainRow]) -> Self {
+        Self {
+            rows_checked: rows.len(),
+            findings: rows.iter().filter_map(RowFinding::new_from_row).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Rows with problems that can't be repaired.
+    pub fn unrepairable(&self) -> usize {
+        self.findings.iter().filter(|finding| finding.repair.is_none()).count()
+    }
+}
+
+impl std::fmt::Display for DbCheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for finding in &self.findings {
+            let problems: Vec<String> = finding.problems.iter().map(|p| p.to_string()).collect();
+            let fix = if finding.repair.is_some() { "" } else { " (can't repair)" };
+            writeln!(f, "\"{}\" ({}, {}): {}{}", finding.grid, finding.region_loc[0], finding.region_loc[1], problems.join("; "), fix)?;
+        }
+        write!(f, "Checked {} rows, {} with problems, {} can't be repaired", self.rows_checked, self.findings.len(), self.unrepairable())?;
+        if self.repaired > 0 || self.repair_failed > 0 {
+            write!(f, ", repaired {}, repair failed {}", self.repaired, self.repair_failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply one row's repair. Samples first, while the row still has its old grid name.
+/// True if the row changed.
+fn apply_repair(conn: &mut dyn Db, finding: &RowFinding, repair: &RowRepair) -> Result<bool, Error> {
+    let mut changed = 0;
+    if let Some(side) = repair.samples {
+        changed += conn.exec_drop(SQL_REPAIR_SAMPLES, params! {
+            "samples_x" => side, "samples_y" => side, "grid" => &finding.grid,
+            "region_loc_x" => finding.region_loc[0], "region_loc_y" => finding.region_loc[1] })?;
+    }
+    if let Some(canonical) = &repair.grid {
+        changed += conn.exec_drop(SQL_REPAIR_GRID, params! {
+            "canonical" => canonical, "grid" => &finding.grid,
+            "region_loc_x" => finding.region_loc[0], "region_loc_y" => finding.region_loc[1] })?;
+    }
+    Ok(changed > 0)
+}
+
+/// Check raw_terrain_heights, and repair what can be repaired if asked.
+pub fn check_raw_terrain(conn: &mut dyn Db, repair: bool) -> Result<DbCheckReport, Error> {
+    let rows = conn.exec_map(SQL_SELECT_ROWS, mysql::Params::Empty, |row| Ok(RawTerrainRow {
+        grid: row.get(0)?,
+        region_loc: [row.get(1)?, row.get(2)?],
+        region_size: [row.get(3)?, row.get(4)?],
+        samples: [row.get(5)?, row.get(6)?],
+        elevs_len: row.get(7)?,
+    }))?;
+    let mut report = DbCheckReport::new_from_rows(&rows);
+    if repair {
+        for finding in &report.findings {
+            let Some(fix) = &finding.repair else { continue };
+            if apply_repair(conn, finding, fix)? {
+                log::info!("Repaired \"{}\" ({}, {}): {:?}", finding.grid, finding.region_loc[0], finding.region_loc[1], fix);
+                report.repaired += 1;
+            } else {
+                log::warn!("Repair of \"{}\" ({}, {}) changed nothing.", finding.grid, finding.region_loc[0], finding.region_loc[1]);
+                report.repair_failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+fn test_row(grid: &str, size: u32, samples: [Option<u32>; 2], elevs_len: usize) -> RawTerrainRow {
+    RawTerrainRow { grid: grid.to_string(), region_loc: [256000, 256000], region_size: [size, size], samples, elevs_len }
+}
+
+#[test]
+fn test_check_row() {
+    //  Good rows, square and varregion with shared edges.
+    assert!(check_row(&test_row("agni", 256, [Some(256), Some(256)], 65536)).is_empty());
+    assert!(check_row(&test_row("osgrid", 512, [Some(65), Some(129)], 65 * 129)).is_empty());
+    //  Each problem alone.
+    assert_eq!(check_row(&test_row("agni", 256, [Some(256), Some(256)], 4096)),
+        vec![RowProblem::BlobLength { samples: [256, 256], elevs_len: 4096 }]);
+    assert_eq!(check_row(&test_row("agni", 256, [None, Some(64)], 4096)), vec![RowProblem::NoSamples { elevs_len: 4096 }]);
+    assert_eq!(check_row(&test_row("agni", 256, [Some(0), Some(0)], 0)), vec![RowProblem::NoSamples { elevs_len: 0 }]);
+    assert_eq!(check_row(&test_row(" Agni", 256, [Some(64), Some(64)], 4096)),
+        vec![RowProblem::GridNotCanonical { canonical: "agni".to_string() }]);
+    assert_eq!(check_row(&test_row("agni", 300, [Some(64), Some(64)], 4096)),
+        vec![RowProblem::SizeNotMultiple { region_size: [300, 300] }]);
+    assert_eq!(check_row(&test_row("agni", 0, [Some(64), Some(64)], 4096)).len(), 1);
+    //  Everything at once.
+    assert_eq!(check_row(&test_row("AGNI", 100, [None, None], 4096)).len(), 3);
+}
+
+#[test]
+fn test_repair_for() {
+    let repair = |row: &RawTerrainRow| repair_for(row, &check_row(row));
+    //  Square blob, samples inferred.
+    assert_eq!(repair(&test_row("agni", 256, [None, None], 4096)), Some(RowRepair { samples: Some(64), grid: None }));
+    assert_eq!(repair(&test_row("agni", 256, [Some(256), Some(256)], 4096)), Some(RowRepair { samples: Some(64), grid: None }));
+    //  Not square, or empty. Can't tell.
+    assert_eq!(repair(&test_row("agni", 512, [Some(0), Some(0)], 65 * 129)), None);
+    assert_eq!(repair(&test_row("agni", 256, [None, None], 0)), None);
+    //  Grid name only, and both.
+    assert_eq!(repair(&test_row("Agni", 256, [Some(64), Some(64)], 4096)), Some(RowRepair { samples: None, grid: Some("agni".to_string()) }));
+    assert_eq!(repair(&test_row("Agni", 256, [None, None], 4096)), Some(RowRepair { samples: Some(64), grid: Some("agni".to_string()) }));
+    //  A grid name that's all whitespace has nowhere to go.
+    assert_eq!(repair(&test_row("  ", 256, [Some(64), Some(64)], 4096)), None);
+    //  Size can't be repaired.
+    assert_eq!(repair(&test_row("agni", 300, [Some(64), Some(64)], 4096)), None);
+    assert_eq!(square_side(1), Some(1));
+    assert_eq!(square_side(65536), Some(256));
+    assert_eq!(square_side(65535), None);
+}
+
+#[test]
+fn test_db_check_report() {
+    let rows = vec![
+        test_row("agni", 256, [Some(256), Some(256)], 65536),
+        test_row("Agni", 256, [None, None], 4096),
+        test_row("osgrid", 512, [Some(0), Some(0)], 65 * 129),
+        test_row("osgrid", 512, [Some(65), Some(129)], 65 * 129),
+    ];
+    let report = DbCheckReport::new_from_rows(&rows);
+    assert_eq!(report.rows_checked, 4);
+    assert_eq!(report.findings.len(), 2);
+    assert_eq!(report.unrepairable(), 1);
+    let text = report.to_string();
+    assert!(text.contains("\"Agni\" (256000, 256000): no sample counts, blob is 4096 bytes; grid name is not canonical, should be \"agni\"\n"), "{}", text);
+    assert!(text.contains("\"osgrid\" (256000, 256000): no sample counts, blob is 8385 bytes (can't repair)\n"), "{}", text);
+    assert!(text.ends_with("Checked 4 rows, 2 with problems, 1 can't be repaired"), "{}", text);
+    assert!(DbCheckReport::new_from_rows(&[]).findings.is_empty());
+}
+
+#[test]
+fn test_check_raw_terrain() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let row = |grid: &str, samples: DbValue, len: u32| DbRow(vec![DbValue::text(grid), DbValue::UInt(1024), DbValue::UInt(2048),
+        DbValue::UInt(256), DbValue::UInt(256), samples.clone(), samples, DbValue::UInt(len as u64)]);
+    let rows = vec![row("agni", DbValue::UInt(64), 4096), row("Agni", DbValue::Null, 4096), row("agni", DbValue::UInt(0), 65 * 129)];
+    //  Check only. Nothing written.
+    let mut fake = FakeDb::new_with_results(vec![rows.clone()]);
+    let report = check_raw_terrain(&mut fake, false).unwrap();
+    assert_eq!((report.findings.len(), report.repaired), (2, 0));
+    assert_eq!(fake.sql().len(), 1);
+    //  Repair. Samples, then grid name, for the fixable row only.
+    let mut fake = FakeDb::new_with_results(vec![rows.clone()]);
+    fake.affected_rows = 1;
+    let report = check_raw_terrain(&mut fake, true).unwrap();
+    assert_eq!((report.repaired, report.repair_failed), (1, 0));
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 3);
+    assert!(sql[1].starts_with("UPDATE raw_terrain_heights SET samples_x = :samples_x"), "{}", sql[1]);
+    assert!(sql[2].starts_with("UPDATE IGNORE raw_terrain_heights SET grid = :canonical"), "{}", sql[2]);
+    assert!(sql[2].contains("WHERE BINARY grid = :grid"), "{}", sql[2]);
+    //  Canonical twin already there, so nothing changes.
+    let mut fake = FakeDb::new_with_results(vec![rows]);
+    let report = check_raw_terrain(&mut fake, true).unwrap();
+    assert_eq!((report.repaired, report.repair_failed), (0, 1));
+    assert!(report.to_string().ends_with(", repaired 0, repair failed 1"));
+}
@@ -0,0 +1,145 @@
+//! error.rs -- errors from the common crate.
+//! Part of the Animats impostor system
+//!
+//! Responders need to tell a bad upload, which is the client's problem,
+//! from a database failure, which is ours. So common functions return
+//! this instead of anyhow::Error, and status_for maps it to an HTTP status.
+//! Binaries can keep using anyhow. This converts into it.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use std::fmt;
+
+/// Errors from the common crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Elevation data is missing or ragged.
+    ElevationFormat(String),
+    /// Array dimensions are empty or don't match the data.
+    Dimensions(String),
+    /// JSON didn't parse.
+    JsonParse(serde_json::Error),
+    /// Hex elevations didn't decode.
+    HexDecode(hex::FromHexError),
+    /// A number doesn't fit.
+    OutOfRange(String),
+    /// Well-formed, but not an acceptable request.
+    BadRequest(String),
+    /// Body isn't a content type we take.
+    UnsupportedMediaType(String),
+    /// Conflicts with an earlier request, such as a replay of one.
+    Conflict(String),
+    /// Database failure.
+    Sql(mysql::Error),
+    /// File or socket failure.
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// HTTP status for a reply reporting this error.
+    /// The client sent something wrong, 400, or we failed, 500.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Error::UnsupportedMediaType(_) => 415,
+            Error::Conflict(_) => 409,
+            Error::ElevationFormat(_) | Error::Dimensions(_) | Error::JsonParse(_) | Error::HexDecode(_)
+                | Error::OutOfRange(_) | Error::BadRequest(_) => 400,
+            Error::Sql(_) | Error::Io(_) => 500,
+        }
+    }
+}
+
+/// HTTP status for a reply reporting this error.
+/// Uses the first common::Error in the chain. Anything else is a server error.
+pub fn status_for(e: &anyhow::Error) -> u16 {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+        .map(|e| e.http_status())
+        .unwrap_or(500)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ElevationFormat(msg) => write!(f, "Bad elevation data: {}", msg),
+            Error::Dimensions(msg) => write!(f, "Bad dimensions: {}", msg),
+            Error::JsonParse(e) => write!(f, "JSON parse error: {}", e),
+            Error::HexDecode(e) => write!(f, "Hex decode error: {}", e),
+            Error::OutOfRange(msg) => write!(f, "Out of range: {}", msg),
+            Error::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            Error::UnsupportedMediaType(msg) => write!(f, "Unsupported media type: {}", msg),
+            Error::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            Error::Sql(e) => write!(f, "Database error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::JsonParse(e) => Some(e),
+            Error::HexDecode(e) => Some(e),
+            Error::Sql(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::JsonParse(e)
+    }
+}
+
+impl From<hex::FromHexError> for Error {
+    fn from(e: hex::FromHexError) -> Self {
+        Error::HexDecode(e)
+    }
+}
+
+impl From<mysql::Error> for Error {
+    fn from(e: mysql::Error) -> Self {
+        Error::Sql(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<std::num::TryFromIntError> for Error {
+    fn from(e: std::num::TryFromIntError) -> Self {
+        Error::OutOfRange(e.to_string())
+    }
+}
+
+impl From<array2d::Error> for Error {
+    fn from(e: array2d::Error) -> Self {
+        Error::Dimensions(format!("{:?}", e))
+    }
+}
+
+#[test]
+fn test_status_for() {
+    use anyhow::Context;
+    assert_eq!(Error::ElevationFormat("Elevation data is missing".to_string()).http_status(), 400);
+    assert_eq!(Error::Io(std::io::Error::other("Broken pipe")).http_status(), 500);
+    assert_eq!(Error::UnsupportedMediaType("image/png".to_string()).http_status(), 415);
+    assert_eq!(Error::Conflict("Replayed".to_string()).http_status(), 409);
+    //  Found through anyhow context.
+    let client: Result<(), Error> = Err(Error::Dimensions("(0, 0)".to_string()));
+    assert_eq!(status_for(&client.context("Parsing upload").unwrap_err()), 400);
+    let sql = mysql::Error::MySqlError(mysql::MySqlError { state: "HY000".to_string(), message: "Gone".to_string(), code: 2006 });
+    let server = anyhow::Error::from(Error::from(sql));
+    assert_eq!(status_for(&server), 500);
+    //  The database error is still visible in the chain.
+    assert!(server.chain().any(|cause| cause.downcast_ref::<mysql::Error>().is_some()));
+    //  Not ours.
+    assert_eq!(status_for(&anyhow::anyhow!("Something else")), 500);
+}
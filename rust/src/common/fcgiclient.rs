@@ -0,0 +1,283 @@
+//! fcgiclient.rs -- the web server's side of FCGI, for testing responders.
+//! Part of the Animats impostor system
+//!
+//! Builds transactions the way a web server sends them, and reads back
+//! the reply. Used by the fcgi_client example and the integration tests,
+//! to check minifcgi against something other than itself.
+//!
+//! SimulatedHeaders plays the part of an in-world script and the web server
+//! together, for running uploads through a handler without either.
+//!
+//! Records are split at 8192 bytes and padded to a multiple of 8, as Apache does.
+//! The reader accepts records with or without padding, since minifcgi doesn't pad.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use std::io::{BufRead, ErrorKind};
+use crate::{Authorizer, Request};
+
+/// FCGI record types used here.
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+/// Most content in one record. Long content is split, as web servers do.
+const MAX_RECORD_CONTENT: usize = 8192;
+
+/// One FCGI record, padded to a multiple of 8.
+pub fn fcgi_record(rec_type: u8, id: u16, content: &[u8]) -> Vec<u8> {
+    assert!(content.len() <= u16::MAX as usize);
+    let padding = (8 - content.len() % 8) % 8;
+    let mut b = vec![1, rec_type];
+    b.extend(id.to_be_bytes());
+    b.extend((content.len() as u16).to_be_bytes());
+    b.extend([padding as u8, 0]);
+    b.extend(content);
+    b.extend(vec![0; padding]);
+    b
+}
+
+/// A name or value length. Long ones take 4 bytes, with the high bit set.
+fn encode_length(n: usize) -> Vec<u8> {
+    if n < 128 {
+        vec![n as u8]
+    } else {
+        (n as u32 | 0x8000_0000).to_be_bytes().to_vec()
+    }
+}
+
+/// Params, as name-value pairs.
+pub fn encode_params(params: &[(&str, &str)]) -> Vec<u8> {
+    let mut b = Vec::new();
+    for (name, value) in params {
+        b.extend(encode_length(name.len()));
+        b.extend(encode_length(value.len()));
+        b.extend(name.as_bytes());
+        b.extend(value.as_bytes());
+    }
+    b
+}
+
+/// A complete responder transaction, as the web server sends it.
+pub fn fcgi_transaction(id: u16, params: &[(&str, &str)], stdin: &[u8]) -> Vec<u8> {
+    //  Role 1 is Responder. Flags 0 closes the connection afterwards.
+    let mut b = fcgi_record(FCGI_BEGIN_REQUEST, id, &[0, 1, 0, 0, 0, 0, 0, 0]);
+    for chunk in encode_params(params).chunks(MAX_RECORD_CONTENT) {
+        b.extend(fcgi_record(FCGI_PARAMS, id, chunk));
+    }
+    b.extend(fcgi_record(FCGI_PARAMS, id, &[]));
+    for chunk in stdin.chunks(MAX_RECORD_CONTENT) {
+        b.extend(fcgi_record(FCGI_STDIN, id, chunk));
+    }
+    b.extend(fcgi_record(FCGI_STDIN, id, &[]));
+    b
+}
+
+/// Param name for an HTTP header, as a web server passes it on.
+/// "X-SecondLife-Owner-Name" is HTTP_X_SECONDLIFE_OWNER_NAME.
+pub fn header_param(name: &str) -> String {
+    format!("HTTP_{}", name.trim().to_uppercase().replace('-', "_"))
+}
+
+/// Headers an upload from an in-world script arrives with.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedHeaders {
+    /// Object owner, X-SecondLife-Owner-Name
+    pub owner_name: String,
+    /// Shard, X-SecondLife-Shard, such as "Production". None to leave the grid unchecked.
+    pub shard: Option<String>,
+    /// Region name and corner in meters, X-SecondLife-Region
+    pub region: Option<(String, [u32; 2])>,
+    /// Token name and secret. The body is signed with them, in X-Authtoken-Name and X-Authtoken-Hash.
+    pub token: Option<(String, String)>,
+}
+
+impl SimulatedHeaders {
+    /// HTTP headers, in the order the simulator sends them.
+    pub fn headers(&self, body: &[u8]) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("X-SecondLife-Owner-Name", self.owner_name.clone())];
+        if let Some(shard) = &self.shard {
+            headers.push(("X-SecondLife-Shard", shard.clone()));
+        }
+        if let Some((name, [x, y])) = &self.region {
+            headers.push(("X-SecondLife-Region", format!("{} ({}, {})", name, x, y)));
+        }
+        if let Some((token_name, secret)) = &self.token {
+            headers.push(("X-Authtoken-Name", token_name.clone()));
+            headers.push(("X-Authtoken-Hash", Authorizer::sign(secret, body)));
+        }
+        headers
+    }
+
+    /// Params for a JSON POST of this body, as Apache passes them to a responder.
+    pub fn post_params(&self, body: &[u8]) -> Vec<(String, String)> {
+        let mut params = vec![
+            ("REQUEST_METHOD".to_string(), "POST".to_string()),
+            ("CONTENT_TYPE".to_string(), "application/json".to_string()),
+            ("CONTENT_LENGTH".to_string(), body.len().to_string()),
+            ("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string()),
+            ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ];
+        params.extend(self.headers(body).into_iter().map(|(name, value)| (header_param(name), value)));
+        params
+    }
+
+    /// The request minifcgi would build from a POST of this body. For calling a handler directly.
+    pub fn post_request(&self, id: u16, body: &[u8]) -> Request {
+        let mut request = Request::new_with_params(id, self.post_params(body).into_iter().collect());
+        request.standard_input = body.to_vec();
+        request
+    }
+}
+
+/// A responder's reply, taken apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FcgiReply {
+    /// HTTP status, from the Status header. 200 if none.
+    pub status: u16,
+    /// HTTP header lines, as sent, without line ends.
+    pub headers: Vec<String>,
+    /// Body bytes
+    pub body: Vec<u8>,
+    /// Anything sent on Stderr
+    pub stderr: Vec<u8>,
+    /// Stdout records, including the empty ones
+    pub stdout_records: usize,
+}
+
+impl FcgiReply {
+    /// Value of a header, by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+
+    /// Split Stdout into header and body.
+    /// The header ends at the first blank line, with or without carriage returns.
+    fn new_from_stdout(stdout: Vec<u8>, stderr: Vec<u8>, stdout_records: usize) -> Result<Self, Error> {
+        let end = |pattern: &[u8]| stdout.windows(pattern.len()).position(|w| w == pattern).map(|n| (n, n + pattern.len()));
+        let (header_end, body_start) = [end(b"\r\n\r\n"), end(b"\n\n")].into_iter().flatten().min()
+            .ok_or_else(|| anyhow!("No end of HTTP header: {}", String::from_utf8_lossy(&stdout[..stdout.len().min(200)])))?;
+        let header = std::str::from_utf8(&stdout[..header_end])?;
+        let headers: Vec<String> = header.split('\n').map(|line| line.trim_end_matches('\r').to_string()).filter(|line| !line.is_empty()).collect();
+        let mut reply = Self { status: 200, headers, body: stdout[body_start..].to_vec(), stderr, stdout_records };
+        if let Some(status) = reply.header("Status") {
+            let code = status.split_whitespace().next().unwrap_or_default();
+            reply.status = code.parse().map_err(|_| anyhow!("Bad status line: \"{}\"", status))?;
+        }
+        Ok(reply)
+    }
+}
+
+/// Read one reply to transaction id, through its end of request record.
+pub fn read_reply(instream: &mut impl BufRead, id: u16) -> Result<FcgiReply, Error> {
+    let (mut stdout, mut stderr, mut stdout_records) = (Vec::new(), Vec::new(), 0);
+    loop {
+        let mut header = [0u8; 8];
+        match instream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(anyhow!("Connection closed before end of request")),
+            Err(e) => return Err(e.into()),
+        }
+        if header[0] != 1 {
+            return Err(anyhow!("FCGI version {}, expected 1", header[0]));
+        }
+        let rec_id = u16::from_be_bytes([header[2], header[3]]);
+        let mut content = vec![0; u16::from_be_bytes([header[4], header[5]]) as usize + header[6] as usize];
+        instream.read_exact(&mut content)?;
+        content.truncate(content.len() - header[6] as usize);
+        if rec_id != id {
+            return Err(anyhow!("Reply record for request {}, expected {}", rec_id, id));
+        }
+        match header[1] {
+            FCGI_STDOUT => {
+                stdout.extend(content);
+                stdout_records += 1;
+            }
+            FCGI_STDERR => stderr.extend(content),
+            FCGI_END_REQUEST => return FcgiReply::new_from_stdout(stdout, stderr, stdout_records),
+            rec_type => return Err(anyhow!("Unexpected FCGI record type {}", rec_type)),
+        }
+    }
+}
+
+#[test]
+fn test_encode_params() {
+    //  Short lengths are one byte, long ones four, high bit set.
+    assert_eq!(encode_params(&[("AB", "xyz")]), b"\x02\x03ABxyz");
+    let long = "v".repeat(300);
+    let b = encode_params(&[("K", &long)]);
+    assert_eq!(&b[..5], &[1, 0x80, 0, 1, 44]);
+    assert_eq!(b.len(), 5 + 1 + 300);
+    //  minifcgi reads back what was written.
+    let params = crate::Request::build_params(&encode_params(&[("QUERY_STRING", "a=1"), ("HTTP_X_LONG", &long), ("EMPTY", "")])).unwrap();
+    assert_eq!(params.get("HTTP_X_LONG"), Some(&long));
+    assert_eq!(params.get("EMPTY").map(|s| s.as_str()), Some(""));
+    assert_eq!(params.len(), 3);
+}
+
+#[test]
+fn test_simulated_headers() {
+    use crate::{AuthorizeType, RequestOrigin};
+    assert_eq!(header_param(" X-SecondLife-Owner-Name"), "HTTP_X_SECONDLIFE_OWNER_NAME");
+    let body = br#"{"grid":"agni"}"#;
+    let simulated = SimulatedHeaders { owner_name: "joe.smith".to_string(), shard: Some("Production".to_string()),
+        region: Some(("Ahern".to_string(), [256000, 256512])), token: Some(("UPLOADER_1".to_string(), "sekrit".to_string())) };
+    let params = simulated.post_params(body);
+    let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    assert_eq!(param("CONTENT_LENGTH"), Some("15"));
+    assert_eq!(param("HTTP_X_SECONDLIFE_REGION"), Some("Ahern (256000, 256512)"));
+    assert_eq!(param("HTTP_X_AUTHTOKEN_HASH").map(|h| h.len()), Some(64));
+    //  What the handler sees is what the script sent.
+    let request = simulated.post_request(1, body);
+    assert_eq!(request.header("X-SecondLife-Shard"), Some("Production"));
+    assert!(request.check_json_content().is_ok());
+    let origin = RequestOrigin::new_from_request(&request);
+    assert_eq!((origin.owner_name.as_deref(), origin.grid().as_deref(), origin.region_corner), (Some("Joe Smith"), Some("agni"), Some([256000, 256512])));
+    //  The signature validates, and only for this body.
+    let secrets = |k: &str| if k == "AUTH_UPLOADER_1" { Some("sekrit".to_string()) } else { None };
+    assert_eq!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &request, secrets).unwrap(), "Joe Smith");
+    let mut tampered = simulated.post_request(1, body);
+    tampered.standard_input = br#"{"grid":"aditi"}"#.to_vec();
+    assert!(Authorizer::authorize_signed(AuthorizeType::UploadTerrain, &tampered, secrets).is_err());
+    //  No token, no signature headers. Params read back through minifcgi's decoder.
+    let unsigned = SimulatedHeaders { owner_name: "Test Uploader".to_string(), ..Default::default() };
+    let pairs = unsigned.post_params(b"{}");
+    let pairs: Vec<(&str, &str)> = pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let decoded = Request::build_params(&encode_params(&pairs)).unwrap();
+    assert_eq!(decoded.len(), 6);
+    assert_eq!(decoded.get("HTTP_X_SECONDLIFE_OWNER_NAME").map(|s| s.as_str()), Some("Test Uploader"));
+}
+
+#[test]
+fn test_read_reply() {
+    //  Unpadded, header ended minifcgi's way, a Stderr record, then the end.
+    let mut b = Vec::new();
+    for (rec_type, content) in [(FCGI_STDOUT, &b"Status: 418 Teapot\r\nContent-Type: text/plain\n\nshort"[..]), (FCGI_STDOUT, b" and stout"),
+        (FCGI_STDERR, b"steam"), (FCGI_STDOUT, b""), (FCGI_END_REQUEST, &[0, 0])] {
+        b.extend([1, rec_type, 0, 7]);
+        b.extend((content.len() as u16).to_be_bytes());
+        b.extend([0, 0]);
+        b.extend(content);
+    }
+    let reply = read_reply(&mut std::io::Cursor::new(b.clone()), 7).unwrap();
+    assert_eq!(reply.status, 418);
+    assert_eq!(reply.header("content-type"), Some("text/plain"));
+    assert_eq!(reply.body, b"short and stout");
+    assert_eq!(reply.stderr, b"steam");
+    assert_eq!(reply.stdout_records, 3);
+    //  Wrong request, or cut off before the end.
+    assert!(read_reply(&mut std::io::Cursor::new(b.clone()), 8).is_err());
+    assert!(read_reply(&mut std::io::Cursor::new(b[..b.len() - 10].to_vec()), 7).is_err());
+    //  Padded records, as fcgi_record makes them, read the same.
+    let padded = [fcgi_record(FCGI_STDOUT, 2, b"X-A: 1\r\n\r\nbody"), fcgi_record(FCGI_END_REQUEST, 2, &[0; 8])].concat();
+    let reply = read_reply(&mut std::io::Cursor::new(padded), 2).unwrap();
+    assert_eq!((reply.status, reply.body.as_slice(), reply.header("X-A")), (200, &b"body"[..], Some("1")));
+}
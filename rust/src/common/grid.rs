@@ -0,0 +1,73 @@
+//! grid.rs -- canonical grid names.
+//! Part of the Animats impostor system
+//!
+//! Grid names arrive from uploaded JSON, query strings, and the command line,
+//! in whatever case and encoding the sender used. "Agni", "agni ", and "%41gni"
+//! are all the same grid. Everything is stored and looked up by the canonical form,
+//! so each ingress point calls canonical once.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use unicode_normalization::UnicodeNormalization;
+
+/// Canonical form of a grid name. Percent-decoded, trimmed, lower case, Unicode NFC.
+pub fn canonical(name: &str) -> String {
+    percent_decode(name).trim().to_lowercase().nfc().collect()
+}
+
+/// Decode %XX escapes. Anything that isn't a valid escape is left alone.
+/// Bytes that don't make UTF-8 become U+FFFD.
+fn percent_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let escaped = (b[i] == b'%')
+            .then(|| b.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .filter(|hex| hex.bytes().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[test]
+fn test_canonical() {
+    assert_eq!(canonical("agni"), "agni");
+    assert_eq!(canonical("Agni"), "agni");
+    assert_eq!(canonical("  AGNI\t"), "agni");
+    assert_eq!(canonical("OSGrid"), "osgrid");
+    //  Percent-encoded, from a query string.
+    assert_eq!(canonical("%41gni"), "agni");
+    assert_eq!(canonical("My%20Grid"), "my grid");
+    assert_eq!(canonical("%20agni%20"), "agni");
+    assert_eq!(canonical("K%C3%A4se"), "käse");
+    //  Not escapes.
+    assert_eq!(canonical("100%"), "100%");
+    assert_eq!(canonical("50%zz"), "50%zz");
+    assert_eq!(canonical("%4"), "%4");
+    assert_eq!(canonical(""), "");
+    //  Already canonical stays put.
+    assert_eq!(canonical(&canonical("  %41GNI ")), "agni");
+}
+
+#[test]
+fn test_canonical_nfc() {
+    //  e + combining acute is the same grid as precomposed é.
+    assert_eq!(canonical("Caf\u{0065}\u{0301}"), "caf\u{00e9}");
+    assert_eq!(canonical("CAF\u{00c9}"), "caf\u{00e9}");
+    assert_eq!(canonical("Caf%65%CC%81"), "caf\u{00e9}");
+}
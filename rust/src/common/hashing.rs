@@ -0,0 +1,117 @@
+//! hashing.rs -- content hashes for change detection.
+//! Part of the Animats impostor system
+//!
+//! Asset names, the stored sculpt and texture hashes, and the check
+//! for unchanged tiles all compare hashes made on different runs, maybe
+//! by different builds. So these are SHA-256, which is stable, not the
+//! standard library hasher, which is not.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use crate::{Error, HeightField};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+/// Generation parameters which change what is made from a height field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenParams {
+    /// Sculpt image size, pixels on a side
+    pub sculpt_dim: usize,
+    /// Texture image size, pixels on a side
+    pub texture_size: u32,
+}
+
+/// SHA-256 as lowercase hex.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// First 32 bits of the SHA-256, for asset names, which have no room for more.
+/// Same as the first 8 hex digits of hash_bytes.
+pub fn short_hash(bytes: &[u8]) -> u32 {
+    let digest = Sha256::digest(bytes);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// SHA-256 of a file, as lowercase hex.
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Hash of a height field and the parameters used to make assets from it.
+/// Heights are quantized to a byte over the field's own range, rounding,
+/// and the range and water level to centimeters, so float noise doesn't change the hash.
+/// Unlike the upload encoding, this maps the highest point to 255 exactly, so
+/// quantizing, restoring, and quantizing again gives the same bytes.
+pub fn hash_height_field(height_field: &HeightField, params: &GenParams) -> Result<String, Error> {
+    let (scale, offset) = height_field.get_scale_offset()?;
+    let (nx, ny) = height_field.dims();
+    let cm = |v: f32| ((v * 100.0).round() as i64).to_le_bytes();
+    let mut canonical = Vec::with_capacity(nx * ny + 64);
+    for v in [nx as u64, ny as u64, height_field.size_x as u64, height_field.size_y as u64, params.sculpt_dim as u64, params.texture_size as u64] {
+        canonical.extend_from_slice(&v.to_le_bytes());
+    }
+    canonical.extend_from_slice(&cm(scale));
+    canonical.extend_from_slice(&cm(offset));
+    canonical.extend_from_slice(&cm(height_field.water_level));
+    for x in 0..nx {
+        for y in 0..ny {
+            let z = if scale > 0.001 { (height_field.sample(x, y) - offset) / scale } else { 0.0 };
+            canonical.push((z * 255.0).round().clamp(0.0, 255.0) as u8);
+        }
+    }
+    Ok(hash_bytes(&canonical))
+}
+
+#[test]
+fn test_hash_bytes() {
+    assert_eq!(hash_bytes(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(short_hash(b"abc"), 0xba7816bf);
+    let path = std::env::temp_dir().join(format!("hashing_test_{}.txt", std::process::id()));
+    std::fs::write(&path, b"abc").unwrap();
+    assert_eq!(hash_file(&path).unwrap(), hash_bytes(b"abc"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_hash_height_field() {
+    let params = GenParams { sculpt_dim: 64, texture_size: 256 };
+    let height = |x: usize, y: usize| 20.0 + (x * 7 + y * 3) as f32 * 0.37;
+    let original = HeightField::new_from_fn(5, 7, 256, 256, 20.0, height).unwrap();
+    let hash = hash_height_field(&original, &params).unwrap();
+    //  Quantize to bytes and restore. Samples move, but not enough to matter.
+    let (scale, offset) = original.get_scale_offset().unwrap();
+    let restored = HeightField::new_from_fn(5, 7, 256, 256, 20.0, |x, y| {
+        let q = ((original.sample(x, y) - offset) / scale * 255.0).round();
+        q / 255.0 * scale + offset
+    }).unwrap();
+    assert_ne!(restored, original);
+    assert_eq!(hash_height_field(&restored, &params).unwrap(), hash);
+    //  Float noise
+    let noisy = HeightField::new_from_fn(5, 7, 256, 256, 20.0, |x, y| height(x, y) * (1.0 + 1e-7)).unwrap();
+    assert_eq!(hash_height_field(&noisy, &params).unwrap(), hash);
+    //  Any sample changing by a step changes the hash.
+    for (cx, cy) in (0..5).flat_map(|x| (0..7).map(move |y| (x, y))) {
+        let changed = HeightField::new_from_fn(5, 7, 256, 256, 20.0, |x, y| {
+            height(x, y) + if (x, y) == (cx, cy) { scale / 128.0 } else { 0.0 }
+        }).unwrap();
+        assert_ne!(hash_height_field(&changed, &params).unwrap(), hash, "({}, {})", cx, cy);
+    }
+    //  So do the parameters and the water level.
+    assert_ne!(hash_height_field(&original, &GenParams { sculpt_dim: 32, ..params.clone() }).unwrap(), hash);
+    let wetter = HeightField::new_from_fn(5, 7, 256, 256, 21.0, height).unwrap();
+    assert_ne!(hash_height_field(&wetter, &params).unwrap(), hash);
+}
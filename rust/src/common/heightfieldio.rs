@@ -0,0 +1,274 @@
+//! heightfieldio.rs -- height fields to and from ordinary file formats.
+//! Part of the Animats impostor system
+//!
+//! So terrain can be looked at in image tools and sent to
+//! terrain editors, and brought back.
+//!
+//! PNG-16 is 16-bit grayscale. The full 0..65535 range covers the
+//! terrain's min..max, and the scale and offset needed to get meters
+//! back are in a tEXt chunk. North is up, as in diagnostic maps.
+//!
+//! R32 is raw little-endian f32 meters, one row per Y, starting at
+//! Y = 0, X fastest. That's the Terragen and OpenSim .r32 layout.
+//! There's no header, so dimensions must be supplied on load.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use image::{ImageFormat, Luma};
+use std::io::{Cursor, Write};
+use std::path::Path;
+use crate::HeightField;
+
+/// Keyword for our tEXt chunk.
+const PNG_TEXT_KEYWORD: &str = "HeightField";
+/// PNG file signature.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+/// Largest 16-bit sample value.
+const PNG16_MAX: f32 = 65535.0;
+
+/// What goes in the tEXt chunk.
+#[derive(Debug, Clone, PartialEq)]
+struct Png16Info {
+    scale: f32,
+    offset: f32,
+    size_x: u32,
+    size_y: u32,
+    water_level: f32,
+}
+
+impl Png16Info {
+    /// As tEXt chunk text.
+    fn to_text(&self) -> String {
+        format!("scale={} offset={} size_x={} size_y={} water_level={}", self.scale, self.offset, self.size_x, self.size_y, self.water_level)
+    }
+
+    /// From tEXt chunk text. All fields are required.
+    fn parse(s: &str) -> Result<Self, Error> {
+        let field = |name: &str| -> Result<&str, Error> {
+            s.split_whitespace()
+                .find_map(|item| item.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+                .ok_or_else(|| anyhow!("Height field PNG text \"{}\" has no {}", s, name))
+        };
+        let bad = |name: &str, e: &dyn std::fmt::Display| anyhow!("Height field PNG text \"{}\": bad {}: {}", s, name, e);
+        Ok(Self {
+            scale: field("scale")?.parse().map_err(|e| bad("scale", &e))?,
+            offset: field("offset")?.parse().map_err(|e| bad("offset", &e))?,
+            size_x: field("size_x")?.parse().map_err(|e| bad("size_x", &e))?,
+            size_y: field("size_y")?.parse().map_err(|e| bad("size_y", &e))?,
+            water_level: field("water_level")?.parse().map_err(|e| bad("water_level", &e))?,
+        })
+    }
+}
+
+/// CRC-32 as used in PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// One PNG chunk: length, type, data, CRC of type and data.
+fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// Chunks of a PNG file, as (type, data). Checks the signature and lengths, not CRCs.
+fn png_chunks(png: &[u8]) -> Result<Vec<(&[u8], &[u8])>, Error> {
+    if !png.starts_with(&PNG_SIGNATURE) {
+        return Err(anyhow!("Not a PNG file"));
+    }
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < png.len() {
+        if pos + 12 > png.len() {
+            return Err(anyhow!("PNG file truncated at byte {}", pos));
+        }
+        let len = u32::from_be_bytes(png[pos..pos + 4].try_into()?) as usize;
+        let end = pos + 12 + len;
+        if end > png.len() {
+            return Err(anyhow!("PNG chunk at byte {} runs past end of file", pos));
+        }
+        chunks.push((&png[pos + 4..pos + 8], &png[pos + 8..pos + 8 + len]));
+        pos = end;
+    }
+    Ok(chunks)
+}
+
+/// Add a tEXt chunk right after IHDR, which must come first.
+fn png_insert_text(png: &[u8], keyword: &str, text: &str) -> Result<Vec<u8>, Error> {
+    let chunks = png_chunks(png)?;
+    match chunks.first() {
+        Some((chunk_type, _)) if *chunk_type == b"IHDR" => {}
+        _ => return Err(anyhow!("PNG file does not start with IHDR")),
+    }
+    let ihdr_end = PNG_SIGNATURE.len() + 12 + chunks[0].1.len();
+    let mut data = Vec::with_capacity(keyword.len() + 1 + text.len());
+    data.extend_from_slice(keyword.as_bytes());
+    data.push(0);
+    data.extend_from_slice(text.as_bytes());
+    let mut out = Vec::with_capacity(png.len() + data.len() + 12);
+    out.extend_from_slice(&png[..ihdr_end]);
+    out.extend_from_slice(&png_chunk(b"tEXt", &data));
+    out.extend_from_slice(&png[ihdr_end..]);
+    Ok(out)
+}
+
+/// Text of the first tEXt chunk with this keyword.
+fn png_find_text(png: &[u8], keyword: &str) -> Result<Option<String>, Error> {
+    for (chunk_type, data) in png_chunks(png)? {
+        if chunk_type != b"tEXt" {
+            continue;
+        }
+        if let Some(split) = data.iter().position(|&b| b == 0) {
+            if &data[..split] == keyword.as_bytes() {
+                //  tEXt is Latin-1. Ours is ASCII.
+                return Ok(Some(String::from_utf8_lossy(&data[split + 1..]).to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+impl HeightField {
+    /// Save as a 16-bit grayscale PNG, with scale and offset in a tEXt chunk.
+    pub fn save_png16(&self, path: &Path) -> Result<(), Error> {
+        let png = self.to_png16()?;
+        std::fs::write(path, png).map_err(|e| anyhow!("Unable to write \"{}\": {:?}", path.display(), e))?;
+        Ok(())
+    }
+
+    /// As the bytes of a 16-bit grayscale PNG, as save_png16 writes it.
+    pub fn to_png16(&self) -> Result<Vec<u8>, Error> {
+        let (nx, ny) = self.dims();
+        let (min, max) = self.min_max();
+        //  A flat height field still needs a usable scale.
+        let scale = if max > min { max - min } else { 1.0 };
+        let info = Png16Info { scale, offset: min, size_x: self.size_x, size_y: self.size_y, water_level: self.water_level };
+        let image = image::ImageBuffer::<Luma<u16>, Vec<u16>>::from_fn(nx as u32, ny as u32, |x, y| {
+            let z = self.sample(x as usize, ny - 1 - y as usize);
+            Luma([(((z - info.offset) / info.scale) * PNG16_MAX).round().clamp(0.0, PNG16_MAX) as u16])
+        });
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        png_insert_text(&png, PNG_TEXT_KEYWORD, &info.to_text())
+    }
+
+    /// Load a 16-bit grayscale PNG written by save_png16.
+    pub fn load_png16(path: &Path) -> Result<Self, Error> {
+        let png = std::fs::read(path).map_err(|e| anyhow!("Unable to read \"{}\": {:?}", path.display(), e))?;
+        let text = png_find_text(&png, PNG_TEXT_KEYWORD)
+            .map_err(|e| anyhow!("\"{}\": {}", path.display(), e))?
+            .ok_or_else(|| anyhow!("\"{}\" has no {} text chunk. Not written by save_png16?", path.display(), PNG_TEXT_KEYWORD))?;
+        let info = Png16Info::parse(&text)?;
+        let image = image::load_from_memory_with_format(&png, ImageFormat::Png)?.to_luma16();
+        let (nx, ny) = (image.width() as usize, image.height() as usize);
+        Ok(HeightField::new_from_fn(nx, ny, info.size_x, info.size_y, info.water_level, |x, y| {
+            let v = image.get_pixel(x as u32, (ny - 1 - y) as u32).0[0];
+            (v as f32 / PNG16_MAX) * info.scale + info.offset
+        })?)
+    }
+
+    /// Save as raw little-endian f32, rows of X, starting at Y = 0.
+    pub fn save_r32(&self, path: &Path) -> Result<(), Error> {
+        let (nx, ny) = self.dims();
+        let mut raw = Vec::with_capacity(nx * ny * 4);
+        for y in 0..ny {
+            for x in 0..nx {
+                raw.extend_from_slice(&self.sample(x, y).to_le_bytes());
+            }
+        }
+        let mut file = std::fs::File::create(path).map_err(|e| anyhow!("Unable to create \"{}\": {:?}", path.display(), e))?;
+        file.write_all(&raw)?;
+        Ok(())
+    }
+
+    /// Load raw little-endian f32, as written by save_r32.
+    /// The file has no header, so the caller supplies the dimensions.
+    pub fn load_r32(path: &Path, samples_x: usize, samples_y: usize, size_x: u32, size_y: u32, water_level: f32) -> Result<Self, Error> {
+        let raw = std::fs::read(path).map_err(|e| anyhow!("Unable to read \"{}\": {:?}", path.display(), e))?;
+        let expected = samples_x * samples_y * 4;
+        if raw.len() != expected {
+            return Err(anyhow!(
+                "\"{}\" is {} bytes, but ({}, {}) f32 samples need {} bytes",
+                path.display(), raw.len(), samples_x, samples_y, expected));
+        }
+        Ok(HeightField::new_from_fn(samples_x, samples_y, size_x, size_y, water_level, |x, y| {
+            let pos = (y * samples_x + x) * 4;
+            f32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]])
+        })?)
+    }
+}
+
+/// A height field with some shape to it, for the round trip tests.
+#[cfg(test)]
+fn test_height_field() -> HeightField {
+    HeightField::new_from_fn(17, 9, 256, 128, 20.0, |x, y| 18.5 + (x as f32 * 0.7).sin() * 12.0 + y as f32 * 1.25).unwrap()
+}
+
+#[test]
+fn test_png16_round_trip() {
+    let dir = std::env::temp_dir().join(format!("heightfieldio_png16_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("terrain.png");
+    let original = test_height_field();
+    original.save_png16(&path).expect("save png16");
+    //  Our chunk is there and the file is still well formed.
+    let png = std::fs::read(&path).unwrap();
+    assert!(png_find_text(&png, PNG_TEXT_KEYWORD).unwrap().is_some());
+    let loaded = HeightField::load_png16(&path).expect("load png16");
+    assert_eq!(loaded.dims(), original.dims());
+    assert_eq!((loaded.size_x, loaded.size_y, loaded.water_level), (256, 128, 20.0));
+    //  Within half a quantization step, plus f32 rounding.
+    let (min, max) = original.min_max();
+    let step = (max - min) / PNG16_MAX;
+    let (nx, ny) = original.dims();
+    for x in 0..nx {
+        for y in 0..ny {
+            let err = (loaded.sample(x, y) - original.sample(x, y)).abs();
+            assert!(err < step, "({}, {}) error {} step {}", x, y, err, step);
+        }
+    }
+    //  A PNG without our chunk, just signature and IHDR.
+    std::fs::write(&path, &png[..PNG_SIGNATURE.len() + 25]).unwrap();
+    assert!(HeightField::load_png16(&path).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_r32_round_trip() {
+    let dir = std::env::temp_dir().join(format!("heightfieldio_r32_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("terrain.r32");
+    let original = test_height_field();
+    original.save_r32(&path).expect("save r32");
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 17 * 9 * 4);
+    let loaded = HeightField::load_r32(&path, 17, 9, 256, 128, 20.0).expect("load r32");
+    assert_eq!(loaded, original);
+    //  Wrong dimensions are caught.
+    let msg = HeightField::load_r32(&path, 17, 17, 256, 256, 20.0).err().expect("wrong size accepted").to_string();
+    assert!(msg.contains("612 bytes"), "{}", msg);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_png_text_chunk() {
+    //  Known CRC, from the PNG spec's IEND chunk.
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    let info = Png16Info { scale: 42.5, offset: -3.25, size_x: 512, size_y: 256, water_level: 20.0 };
+    assert_eq!(Png16Info::parse(&info.to_text()).unwrap(), info);
+    assert!(Png16Info::parse("scale=1 offset=0").is_err());
+    assert!(png_insert_text(b"GIF89a", PNG_TEXT_KEYWORD, "x").is_err());
+}
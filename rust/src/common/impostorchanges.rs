@@ -0,0 +1,313 @@
+//! impostorchanges.rs -- which impostors changed, by viz group, for viewers.
+//! Part of the Animats impostor system
+//!
+//! A viewer in a region of viz group N wants to know when any impostor
+//! in that group changes, without downloading the whole group again.
+//! Each promotion appends a row to impostor_changes for each impostor added,
+//! replaced by a new generation, or removed. Those rows are written in the
+//! promotion's transaction, so a change is never visible before its data.
+//!
+//! An impostor which moves to another viz group is a removal from the old group
+//! and an addition to the new one, so viewers in both hear about it.
+//!
+//! Viewers ask for changes since a time, or since the highest generation they
+//! have. So a generation is grid-wide, not per tile: grid_generations holds a
+//! serial for each grid, bumped inside every promotion, upload, and admin
+//! deletion. Promotion stamps each tile it adds, replaces, or moves, and each
+//! removal, with the new serial. Anything changed after a viewer's copy is
+//! then above every generation that copy has, whichever tile it's on.
+//!
+//! Rows older than CHANGE_RETENTION_DAYS are pruned at each promotion.
+//! A viewer away longer than that should fetch the whole group.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::params;
+use serde::Serialize;
+use std::collections::HashMap;
+use crate::db::Db;
+use crate::grid::canonical;
+use crate::parse_since;
+
+/// How long change rows are kept.
+pub const CHANGE_RETENTION_DAYS: u32 = 90;
+/// Most changes returned. Older ones are left out, and the reply says so.
+pub const MAX_CHANGES_RETURNED: usize = 1000;
+
+/// Bump a grid's serial. A grid new to the table starts above its live impostors.
+const SQL_BUMP_GRID_GENERATION: &str = r"INSERT INTO grid_generations (grid, generation)
+    SELECT :grid, COALESCE(MAX(generation), 0) + 1 FROM region_impostors WHERE grid = :grid
+    ON DUPLICATE KEY UPDATE generation = grid_generations.generation + 1";
+/// A grid's serial.
+const SQL_GRID_GENERATION: &str = r"SELECT generation FROM grid_generations WHERE grid = :grid";
+/// Give the promotion's serial to each next generation tile not live as it is, in the same viz group.
+/// The generator gives a rebuilt tile with new assets a generation no live copy has, so it's stamped too.
+const SQL_STAMP_GENERATION: &str = r"UPDATE initial_impostors AS n LEFT JOIN region_impostors AS o
+    ON o.grid = n.grid AND o.region_loc_x = n.region_loc_x AND o.region_loc_y = n.region_loc_y
+    AND o.impostor_lod = n.impostor_lod AND o.viz_group = n.viz_group AND o.generation = n.generation
+    SET n.generation = :generation WHERE n.grid = :grid AND o.grid IS NULL";
+/// Tiles of the live generation.
+const SQL_LIVE_TILES: &str = r"SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation FROM region_impostors WHERE grid = :grid";
+/// Tiles of the next generation.
+const SQL_NEW_TILES: &str = r"SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation FROM initial_impostors WHERE grid = :grid";
+/// Record one change.
+const SQL_INSERT_CHANGE: &str = r"INSERT INTO impostor_changes (grid, viz_group, region_loc_x, region_loc_y, impostor_lod, generation, removed, change_time)
+    VALUES (:grid, :viz_group, :region_loc_x, :region_loc_y, :impostor_lod, :generation, :removed, NOW())";
+/// Forget old changes on a grid.
+const SQL_PRUNE_CHANGES: &str = r"DELETE FROM impostor_changes WHERE grid = :grid AND change_time < NOW() - INTERVAL :days DAY";
+/// Columns of a change, oldest last. One more than the limit is fetched, to tell if there were more.
+const SQL_CHANGES_SINCE_TIME: &str = r"SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation, removed, change_time
+    FROM impostor_changes WHERE grid = :grid AND viz_group = :viz_group AND change_time > :since
+    ORDER BY change_time DESC, generation DESC, region_loc_x, region_loc_y, impostor_lod LIMIT 1001";
+const SQL_CHANGES_SINCE_GENERATION: &str = r"SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation, removed, change_time
+    FROM impostor_changes WHERE grid = :grid AND viz_group = :viz_group AND generation > :since_generation
+    ORDER BY change_time DESC, generation DESC, region_loc_x, region_loc_y, impostor_lod LIMIT 1001";
+
+/// One impostor tile, as a change is recorded against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TileKey {
+    /// Viz group
+    pub viz_group: u32,
+    /// Location in world (meters)
+    pub region_loc: [u32; 2],
+    /// Level of detail
+    pub lod: u8,
+}
+
+/// One change, between two generations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TileChange {
+    /// Which tile
+    pub key: TileKey,
+    /// Its new generation. For a removal, the promotion's.
+    pub generation: u32,
+    /// No impostor there any more
+    pub removed: bool,
+}
+
+/// Changes from the old tiles to the new ones, each a tile and its generation.
+/// A tile whose generation is the same has the same assets, so it hasn't changed.
+/// Removals get removal_generation, the promotion's serial. Sorted by tile.
+pub fn changes_between(old: &[(TileKey, u32)], new: &[(TileKey, u32)], removal_generation: u32) -> Vec<TileChange> {
+    let old_generations: HashMap<TileKey, u32> = old.iter().copied().collect();
+    let new_generations: HashMap<TileKey, u32> = new.iter().copied().collect();
+    let mut changes: Vec<TileChange> = new_generations.iter()
+        .filter(|(key, generation)| old_generations.get(key) != Some(generation))
+        .map(|(key, generation)| TileChange { key: *key, generation: *generation, removed: false })
+        .chain(old_generations.keys()
+            .filter(|key| !new_generations.contains_key(key))
+            .map(|key| TileChange { key: *key, generation: removal_generation, removed: true }))
+        .collect();
+    changes.sort();
+    changes
+}
+
+/// Bump the grid's serial and return it. Call inside the transaction making the change,
+/// which holds the grid's row until commit, so changes to one grid are numbered in commit order.
+pub fn next_grid_generation(conn: &mut dyn Db, grid: &str) -> Result<u32, Error> {
+    let grid = canonical(grid);
+    conn.exec_drop(SQL_BUMP_GRID_GENERATION, params! { "grid" => &grid })?;
+    conn.exec_first(SQL_GRID_GENERATION, params! { "grid" => &grid })?
+        .map(|row| row.get(0))
+        .ok_or_else(|| anyhow!("Grid \"{}\" has no generation serial after bumping it", grid))?
+}
+
+/// A grid's tiles in one of the impostor tables.
+fn grid_tiles(conn: &mut dyn Db, sql: &str, grid: &str) -> Result<Vec<(TileKey, u32)>, Error> {
+    conn.exec_map(sql, params! { "grid" => grid }, |row| Ok((
+        TileKey { viz_group: row.get(0)?, region_loc: [row.get(1)?, row.get(2)?], lod: row.get(3)? },
+        row.get(4)?)))
+}
+
+/// Give the next generation the grid's next serial, record what it changes, and prune old changes.
+/// Call inside promotion's transaction, before region_impostors is replaced.
+/// Returns the number of changes recorded.
+pub fn record_impostor_changes(conn: &mut dyn Db, grid: &str) -> Result<usize, Error> {
+    let grid = canonical(grid);
+    let generation = next_grid_generation(conn, &grid)?;
+    let stamped = conn.exec_drop(SQL_STAMP_GENERATION, params! { "grid" => &grid, "generation" => generation })?;
+    log::info!("Grid \"{}\": generation {}, given to {} new or changed impostors.", grid, generation, stamped);
+    let old = grid_tiles(conn, SQL_LIVE_TILES, &grid)?;
+    let new = grid_tiles(conn, SQL_NEW_TILES, &grid)?;
+    let changes = changes_between(&old, &new, generation);
+    for change in &changes {
+        insert_change(conn, &grid, change)?;
+    }
+    let pruned = conn.exec_drop(SQL_PRUNE_CHANGES, params! { "grid" => &grid, "days" => CHANGE_RETENTION_DAYS })?;
+    log::info!("Grid \"{}\": {} impostor changes recorded, {} old ones pruned.", grid, changes.len(), pruned);
+    Ok(changes.len())
+}
+
+/// Record the removal of one live impostor, outside promotion, such as an admin deletion.
+/// Call inside the deletion's transaction, with the generation from next_grid_generation.
+pub fn record_removal(conn: &mut dyn Db, grid: &str, key: TileKey, generation: u32) -> Result<(), Error> {
+    insert_change(conn, &canonical(grid), &TileChange { key, generation, removed: true })
+}
+
+/// Append one change row.
+fn insert_change(conn: &mut dyn Db, grid: &str, change: &TileChange) -> Result<(), Error> {
+    conn.exec_drop(SQL_INSERT_CHANGE, params! {
+        "grid" => grid,
+        "viz_group" => change.key.viz_group,
+        "region_loc_x" => change.key.region_loc[0],
+        "region_loc_y" => change.key.region_loc[1],
+        "impostor_lod" => change.key.lod,
+        "generation" => change.generation,
+        "removed" => change.removed,
+    })?;
+    Ok(())
+}
+
+/// Where a viewer's copy of a group is up to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangesSince {
+    /// "YYYY-MM-DD HH:MM:SS", UTC, from parse_since
+    Time(String),
+    /// Highest generation the viewer has
+    Generation(u32),
+}
+
+impl ChangesSince {
+    /// From the changes_since parameter. All digits is a generation, anything else a time.
+    pub fn new_from_param(s: &str) -> Result<Self, crate::Error> {
+        let s = s.trim();
+        if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            let generation = s.parse().map_err(|_| crate::Error::BadRequest(format!("Generation {} is too big", s)))?;
+            return Ok(ChangesSince::Generation(generation));
+        }
+        Ok(ChangesSince::Time(parse_since(Some(s))?))
+    }
+}
+
+/// One change, as sent to the viewer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImpostorChange {
+    /// Location in world (meters)
+    pub region_loc: [u32; 2],
+    /// Level of detail
+    pub lod: u8,
+    /// Grid generation of the change
+    pub generation: u32,
+    /// No impostor there any more
+    pub removed: bool,
+    /// When promoted, "YYYY-MM-DD HH:MM:SS", UTC.
+    pub change_time: String,
+}
+
+/// A viz group's changes, oldest first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImpostorChangesReply {
+    /// Grid name
+    pub grid: String,
+    /// Viz group
+    pub viz_group: u32,
+    /// Changes, oldest first
+    pub changes: Vec<ImpostorChange>,
+    /// There were more than MAX_CHANGES_RETURNED. Only the latest are here. Fetch the whole group.
+    pub truncated: bool,
+}
+
+/// A viz group's changes since a time or generation. At most the latest MAX_CHANGES_RETURNED.
+pub fn get_impostor_changes(conn: &mut dyn Db, grid: &str, viz_group: u32, since: &ChangesSince) -> Result<ImpostorChangesReply, Error> {
+    let grid = canonical(grid);
+    let (sql, params) = match since {
+        ChangesSince::Time(since) => (SQL_CHANGES_SINCE_TIME, params! { "grid" => &grid, "viz_group" => viz_group, "since" => since }),
+        ChangesSince::Generation(generation) =>
+            (SQL_CHANGES_SINCE_GENERATION, params! { "grid" => &grid, "viz_group" => viz_group, "since_generation" => generation }),
+    };
+    let mut changes = conn.exec_map(sql, params, |row| Ok(ImpostorChange {
+        region_loc: [row.get(1)?, row.get(2)?],
+        lod: row.get(3)?,
+        generation: row.get(4)?,
+        removed: row.get(5)?,
+        change_time: row.get(6)?,
+    }))?;
+    let truncated = changes.len() > MAX_CHANGES_RETURNED;
+    changes.truncate(MAX_CHANGES_RETURNED);
+    changes.reverse();
+    Ok(ImpostorChangesReply { grid, viz_group, changes, truncated })
+}
+
+#[cfg(test)]
+fn test_tile(viz_group: u32, x: u32, lod: u8) -> TileKey {
+    TileKey { viz_group, region_loc: [x, 0], lod }
+}
+
+#[test]
+fn test_changes_between() {
+    let old = vec![(test_tile(1, 0, 0), 3), (test_tile(1, 256, 0), 3), (test_tile(1, 512, 0), 2), (test_tile(1, 0, 1), 3), (test_tile(2, 1024, 0), 1)];
+    //  Promotion 4. 256 regenerated, 512 gone, 768 new, 1024 moved to group 3, LOD 1 unchanged.
+    let new = vec![(test_tile(1, 0, 0), 3), (test_tile(1, 256, 0), 4), (test_tile(1, 768, 0), 4), (test_tile(1, 0, 1), 3), (test_tile(3, 1024, 0), 4)];
+    let changes = changes_between(&old, &new, 4);
+    let summary: Vec<(u32, u32, u32, bool)> = changes.iter().map(|c| (c.key.viz_group, c.key.region_loc[0], c.generation, c.removed)).collect();
+    assert_eq!(summary, vec![(1, 256, 4, false), (1, 512, 4, true), (1, 768, 4, false), (2, 1024, 4, true), (3, 1024, 4, false)]);
+    //  Same generation, nothing changed.
+    assert!(changes_between(&old, &old, 4).is_empty());
+    //  First promotion, everything's new.
+    assert_eq!(changes_between(&[], &new, 4).len(), new.len());
+    //  Everything removed, at the promotion's generation.
+    assert!(changes_between(&old, &[], 4).iter().all(|c| c.removed && c.generation == 4));
+}
+
+#[test]
+fn test_changes_since() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    assert_eq!(ChangesSince::new_from_param("12").unwrap(), ChangesSince::Generation(12));
+    assert_eq!(ChangesSince::new_from_param("2026-03-01").unwrap(), ChangesSince::Time("2026-03-01 00:00:00".to_string()));
+    for bad in ["", "99999999999", "-1", "soon"] {
+        assert_eq!(ChangesSince::new_from_param(bad).expect_err(bad).http_status(), 400, "{}", bad);
+    }
+    //  Newest first from the database, oldest first in the reply, and only the latest MAX_CHANGES_RETURNED.
+    let row = |n: u64| DbRow(vec![DbValue::UInt(7), DbValue::UInt(n * 256), DbValue::UInt(0), DbValue::UInt(0), DbValue::UInt(n),
+        DbValue::Int(0), DbValue::text(&format!("2026-03-01 00:{:02}:{:02}", n / 60 % 60, n % 60))]);
+    let rows: Vec<DbRow> = (0..=MAX_CHANGES_RETURNED as u64).rev().map(row).collect();
+    let mut fake = FakeDb::new_with_results(vec![rows, vec![row(5)]]);
+    let reply = get_impostor_changes(&mut fake, "Agni", 7, &ChangesSince::Generation(0)).unwrap();
+    assert!(reply.truncated);
+    assert_eq!(reply.changes.len(), MAX_CHANGES_RETURNED);
+    assert_eq!(reply.changes.first().unwrap().generation, 1);
+    assert_eq!(reply.changes.last().unwrap().generation, MAX_CHANGES_RETURNED as u32);
+    assert!(fake.sql()[0].contains("generation > :since_generation"));
+    let reply = get_impostor_changes(&mut fake, "Agni", 7, &ChangesSince::Time("2026-03-01 00:00:00".to_string())).unwrap();
+    assert!(!reply.truncated);
+    assert_eq!((reply.grid.as_str(), reply.viz_group, reply.changes.len()), ("agni", 7, 1));
+    assert!(fake.sql()[1].contains("change_time > :since"));
+}
+
+#[test]
+fn test_record_impostor_changes() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let row = |x: u64, generation: u64| DbRow(vec![DbValue::UInt(1), DbValue::UInt(x), DbValue::UInt(0), DbValue::UInt(0), DbValue::UInt(generation)]);
+    //  Grid serial 9. One tile regenerated and stamped, one unchanged.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::UInt(9)])], vec![row(0, 1), row(256, 1)], vec![row(0, 9), row(256, 1)]]);
+    assert_eq!(record_impostor_changes(&mut fake, "Agni").unwrap(), 1);
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 7);
+    assert!(sql[0].starts_with("INSERT INTO grid_generations"));
+    assert!(sql[1].starts_with("SELECT generation FROM grid_generations"));
+    assert!(sql[2].starts_with("UPDATE initial_impostors AS n LEFT JOIN region_impostors AS o"));
+    assert!(sql[5].starts_with("INSERT INTO impostor_changes"));
+    assert!(sql[6].starts_with("DELETE FROM impostor_changes WHERE grid = :grid AND change_time <"));
+    for n in [2, 5] {
+        match &fake.statements[n].1 {
+            mysql::Params::Named(named) => assert_eq!(named.get("generation".as_bytes()), Some(&mysql::Value::from(9u32))),
+            _ => panic!("Expected named parameters"),
+        }
+    }
+}
+
+#[test]
+fn test_generation_is_grid_wide() {
+    //  A tile rebuilt rarely, next to one rebuilt often. The viewer has promotion 7, the
+    //  busy tile's. Promotion 8 rebuilds the quiet tile, which had generation 2. Per tile, it
+    //  would be 3, below what the viewer has. Grid-wide, it's 8, and the viewer hears of it.
+    let old = vec![(test_tile(1, 0, 0), 2), (test_tile(1, 256, 0), 7)];
+    let new = vec![(test_tile(1, 0, 0), 8), (test_tile(1, 256, 0), 7)];
+    let viewer_has = old.iter().map(|(_, generation)| *generation).max().unwrap();
+    let changes = changes_between(&old, &new, 8);
+    assert_eq!(changes.len(), 1);
+    assert!(changes.iter().all(|c| c.generation > viewer_has));
+}
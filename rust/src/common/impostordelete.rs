@@ -0,0 +1,212 @@
+//! impostordelete.rs -- remove one bad impostor, by admin request.
+//! Part of the Animats impostor system
+//!
+//! An early buggy upload can leave an impostor at the wrong coordinates,
+//! and nothing later replaces it. An admin can delete it with
+//!
+//!     {"action":"delete","grid":"agni","region_loc":[x,y],"impostor_lod":L,
+//!      "nonce":"N","sent_at":UNIXTIME}
+//!
+//! signed with an admin token, sent to uploadimpostor. The nonce and sent_at
+//! are checked as for signed uploads, so a captured request can't be sent
+//! again. See replayguard. Rows at that location and LOD go from
+//! region_impostors and initial_impostors, so the next promotion doesn't bring
+//! it back, and from tile_assets, so a half finished upload doesn't either.
+//! In the same transaction, viewers following the viz group get a removal in
+//! impostor_changes, and assets nothing else uses go on the retired_assets
+//! list for the cleanup script.
+//!
+//! The reply has the rows as they were, so a deletion can be undone by
+//! uploading the impostor again.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::Error;
+use mysql::params;
+use serde::{Deserialize, Serialize};
+use crate::db::Db;
+use crate::grid::canonical;
+use crate::{RegionImpostorData, REGION_IMPOSTOR_COLUMNS, TileKey, faces_to_json, impostor_assets, next_grid_generation,
+    record_removal, retire_unused, uuid_opt_to_string};
+
+/// The only action for now.
+const DELETE_ACTION: &str = "delete";
+/// Which rows. Same in both tables.
+const WHERE_TILE: &str = "WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND impostor_lod = :impostor_lod";
+
+/// An admin deletion request, as sent.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImpostorDeleteRequest {
+    /// Must be "delete"
+    pub action: String,
+    /// Grid name
+    pub grid: String,
+    /// Southwest corner, meters
+    pub region_loc: [u32; 2],
+    /// Level of detail
+    pub impostor_lod: u8,
+    /// Different for every request, against replays
+    pub nonce: Option<String>,
+    /// When sent, Unix time in seconds
+    pub sent_at: Option<i64>,
+}
+
+impl ImpostorDeleteRequest {
+    /// Parse and check. Anything wrong is a bad request.
+    pub fn new_from_json(s: &str) -> Result<Self, crate::Error> {
+        let request: Self = serde_json::from_str(s).map_err(|e| crate::Error::BadRequest(format!("Admin request is not valid: {}", e)))?;
+        if request.action != DELETE_ACTION {
+            return Err(crate::Error::BadRequest(format!("Admin action \"{}\" is not \"{}\"", request.action, DELETE_ACTION)));
+        }
+        if canonical(&request.grid).is_empty() {
+            return Err(crate::Error::BadRequest("Admin request has no grid".to_string()));
+        }
+        Ok(request)
+    }
+}
+
+/// What was deleted.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImpostorDeleteReply {
+    /// Grid name
+    pub grid: String,
+    /// Southwest corner, meters
+    pub region_loc: [u32; 2],
+    /// Level of detail
+    pub impostor_lod: u8,
+    /// Live impostors deleted, as they were.
+    pub deleted: Vec<RegionImpostorData>,
+    /// Next generation impostors deleted, as they were.
+    pub deleted_initial: Vec<RegionImpostorData>,
+    /// Assets put on the retired list
+    pub retired_assets: usize,
+}
+
+/// Delete the impostors at a location and LOD from both tables, as one transaction.
+/// None if there were none. Any error rolls back.
+pub fn delete_impostor(conn: &mut dyn Db, request: &ImpostorDeleteRequest) -> Result<Option<ImpostorDeleteReply>, Error> {
+    conn.start_transaction()?;
+    match delete_impostor_in_transaction(conn, request) {
+        Ok(reply) => {
+            conn.commit()?;
+            Ok(reply)
+        }
+        Err(e) => {
+            conn.rollback()?;
+            Err(e)
+        }
+    }
+}
+
+/// Delete, inside the transaction.
+fn delete_impostor_in_transaction(conn: &mut dyn Db, request: &ImpostorDeleteRequest) -> Result<Option<ImpostorDeleteReply>, Error> {
+    let grid = canonical(&request.grid);
+    let tile_params = || params! {
+        "grid" => &grid,
+        "region_loc_x" => request.region_loc[0],
+        "region_loc_y" => request.region_loc[1],
+        "impostor_lod" => request.impostor_lod,
+    };
+    let mut select = |table: &str| -> Result<Vec<RegionImpostorData>, Error> {
+        let sql = format!("SELECT {} FROM {} {} FOR UPDATE", REGION_IMPOSTOR_COLUMNS, table, WHERE_TILE);
+        conn.exec_rows(&sql, tile_params())?.iter().map(RegionImpostorData::from_db_row).collect()
+    };
+    let deleted = select("region_impostors")?;
+    let deleted_initial = select("initial_impostors")?;
+    if deleted.is_empty() && deleted_initial.is_empty() {
+        log::info!("Nothing to delete on \"{}\" at {:?}, LOD {}.", grid, request.region_loc, request.impostor_lod);
+        return Ok(None);
+    }
+    let removal_generation = next_grid_generation(conn, &grid)?;
+    conn.exec_drop(&format!("DELETE FROM initial_impostors {}", WHERE_TILE), tile_params())?;
+    conn.exec_drop(&format!("DELETE FROM region_impostors {}", WHERE_TILE), tile_params())?;
+    conn.exec_drop(&format!("DELETE FROM tile_assets {}", WHERE_TILE), tile_params())?;
+    for impostor in &deleted {
+        let key = TileKey { viz_group: impostor.viz_group, region_loc: request.region_loc, lod: request.impostor_lod };
+        record_removal(conn, &grid, key, removal_generation)?;
+    }
+    let mut assets = Vec::new();
+    for impostor in deleted.iter().chain(&deleted_initial) {
+        assets.extend(impostor_assets(uuid_opt_to_string(impostor.sculpt_uuid), uuid_opt_to_string(impostor.mesh_uuid), &faces_to_json(&impostor.faces)?)?);
+    }
+    let retired_assets = retire_unused(conn, &grid, &assets)?;
+    log::warn!("Deleted impostors on \"{}\" at {:?}, LOD {}: {} live, {} next generation, {} assets retired.",
+        grid, request.region_loc, request.impostor_lod, deleted.len(), deleted_initial.len(), retired_assets);
+    Ok(Some(ImpostorDeleteReply { grid, region_loc: request.region_loc, impostor_lod: request.impostor_lod, deleted, deleted_initial, retired_assets }))
+}
+
+#[cfg(test)]
+fn test_request() -> ImpostorDeleteRequest {
+    ImpostorDeleteRequest::new_from_json(r#"{"action":"delete","grid":"Agni","region_loc":[256000,256512],"impostor_lod":0,"nonce":"d1","sent_at":1775000000}"#).unwrap()
+}
+
+#[test]
+fn test_delete_request_parse() {
+    let request = test_request();
+    assert_eq!((request.region_loc, request.impostor_lod), ([256000, 256512], 0));
+    assert_eq!((request.nonce.as_deref(), request.sent_at), (Some("d1"), Some(1_775_000_000)));
+    //  Checked like an upload's. Once only, and only when fresh.
+    let mut guard = crate::ReplayGuard::new(true, crate::REPLAY_CACHE_SIZE);
+    guard.check("ADMIN_1", request.nonce.as_deref(), request.sent_at, 1_775_000_010).expect("fresh");
+    assert!(guard.check("ADMIN_1", request.nonce.as_deref(), request.sent_at, 1_775_000_010).is_err());
+    let unsent = ImpostorDeleteRequest::new_from_json(r#"{"action":"delete","grid":"agni","region_loc":[0,0],"impostor_lod":0}"#).unwrap();
+    assert!(guard.check("ADMIN_1", unsent.nonce.as_deref(), unsent.sent_at, 1_775_000_010).is_err());
+    for bad in [r#"{"action":"drop","grid":"agni","region_loc":[0,0],"impostor_lod":0}"#,
+        r#"{"action":"delete","grid":" ","region_loc":[0,0],"impostor_lod":0}"#,
+        r#"{"action":"delete","grid":"agni","region_loc":[0,0]}"#,
+        r#"{"action":"delete","grid":"agni","region_loc":[0,0],"impostor_lod":0,"viz_group":3}"#,
+        r#"[{"asset_name":"RS","asset_uuid":"","grid":"agni"}]"#] {
+        assert_eq!(ImpostorDeleteRequest::new_from_json(bad).expect_err(bad).http_status(), 400, "{}", bad);
+    }
+}
+
+#[test]
+fn test_delete_not_found() {
+    use crate::db::FakeDb;
+    let mut fake = FakeDb::default();
+    assert!(delete_impostor(&mut fake, &test_request()).unwrap().is_none());
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 4);
+    assert_eq!((sql[0], sql[3]), ("START TRANSACTION", "COMMIT"));
+    assert!(sql.iter().all(|s| !s.starts_with("DELETE")), "{:?}", sql);
+}
+
+#[test]
+fn test_delete_statements() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let row = || DbRow(vec![DbValue::text("agni"), DbValue::UInt(256000), DbValue::UInt(256512), DbValue::text("Ahern"),
+        DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::Float(40.5), DbValue::Float(12.25),
+        DbValue::UInt(0), DbValue::UInt(3), DbValue::Null, DbValue::text("4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01"), DbValue::Float(20.0),
+        DbValue::text("creator"), DbValue::text("2026-03-01 00:00:00"), DbValue::text("[]"), DbValue::UInt(4), DbValue::Null, DbValue::UInt(0), DbValue::Float(1744.0),
+        DbValue::UInt(0)]);
+    //  Live and next generation rows, the grid's new serial, then nothing else using the sculpt.
+    let mut fake = FakeDb::new_with_results(vec![vec![row()], vec![row()], vec![DbRow(vec![DbValue::UInt(10)])]]);
+    let reply = delete_impostor(&mut fake, &test_request()).unwrap().expect("deleted");
+    assert_eq!((reply.grid.as_str(), reply.deleted.len(), reply.deleted_initial.len(), reply.retired_assets), ("agni", 1, 1, 1));
+    assert_eq!(reply.deleted[0].name.as_deref(), Some("Ahern"));
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 13, "{:?}", sql);
+    assert_eq!((sql[0], sql[12]), ("START TRANSACTION", "COMMIT"));
+    assert!(sql[1].starts_with("SELECT grid,") && sql[1].contains("FROM region_impostors WHERE grid = :grid") && sql[1].ends_with("FOR UPDATE"));
+    assert!(sql[2].contains("FROM initial_impostors WHERE"));
+    assert!(sql[3].starts_with("INSERT INTO grid_generations"));
+    assert!(sql[4].starts_with("SELECT generation FROM grid_generations"));
+    assert!(sql[5].starts_with("DELETE FROM initial_impostors WHERE grid = :grid AND region_loc_x = :region_loc_x"));
+    assert!(sql[6].starts_with("DELETE FROM region_impostors WHERE"));
+    assert!(sql[7].starts_with("DELETE FROM tile_assets WHERE grid = :grid AND region_loc_x = :region_loc_x"));
+    assert!(sql[8].starts_with("INSERT INTO impostor_changes"));
+    assert!(sql[11].starts_with("INSERT IGNORE INTO retired_assets"));
+    //  Removal has the grid's new serial.
+    match &fake.statements[8].1 {
+        mysql::Params::Named(named) => assert_eq!(named.get("generation".as_bytes()), Some(&mysql::Value::from(10u32))),
+        _ => panic!("Expected named parameters"),
+    }
+    //  A failure part way rolls back.
+    let mut fake = FakeDb::new_with_results(vec![vec![], vec![row()], vec![DbRow(vec![DbValue::UInt(10)])]]);
+    fake.fail_on = Some("DELETE FROM".to_string());
+    assert!(delete_impostor(&mut fake, &test_request()).is_err());
+    assert_eq!(fake.sql().last(), Some(&"ROLLBACK"));
+}
@@ -0,0 +1,314 @@
+//! impostorexport.rs -- region_impostors as CSV or GeoJSON, for offline analysis.
+//! Part of the Animats impostor system
+//!
+//! For looking at the impostor table in a spreadsheet or a GIS tool,
+//! without writing SQL. Rows are written out one at a time, in LOD order,
+//! then west to east, then south to north.
+//!
+//! CSV has a header line, and always these columns, in this order:
+//!
+//!     grid, region_loc_x, region_loc_y, region_size_x, region_size_y, name,
+//!     impostor_lod, viz_group, generation, placeholder,
+//!     scale_x, scale_y, scale_z, elevation_offset, water_height, water_height_max,
+//!     sculpt_uuid, mesh_uuid, face_count,
+//!     base_texture_uuid_0, emissive_texture_uuid_0, ... base_texture_uuid_7, emissive_texture_uuid_7,
+//!     provenance_json
+//!
+//! Locations and sizes are meters. Absent values, and UUIDs not uploaded yet, are empty.
+//! provenance_json says which generator run made the impostor, and from what terrain.
+//! Faces past the 8th are counted in face_count but not listed. Fields are
+//! quoted as RFC 4180 says, so names with commas and quotes come through.
+//!
+//! GeoJSON is one FeatureCollection, with a Feature for each impostor. Its
+//! geometry is the impostor's rectangle in grid meters, not longitude and
+//! latitude. GIS tools show that as a flat map, which is what's wanted
+//! for coverage. Properties are the CSV columns, less the faces and provenance.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::{anyhow, Error};
+use crate::db::Db;
+use crate::grid::canonical;
+use crate::{RegionImpostorData, PROVENANCE_COLUMN, REGION_IMPOSTOR_COLUMNS};
+use mysql::params;
+use serde_json::json;
+use std::io::Write;
+use uuid::Uuid;
+
+/// Faces listed in CSV. SL prims have at most 8.
+pub const MAX_EXPORT_FACES: usize = 8;
+
+/// CSV columns before the faces.
+const CSV_FIXED_COLUMNS: [&str; 19] = ["grid", "region_loc_x", "region_loc_y", "region_size_x", "region_size_y", "name",
+    "impostor_lod", "viz_group", "generation", "placeholder",
+    "scale_x", "scale_y", "scale_z", "elevation_offset", "water_height", "water_height_max",
+    "sculpt_uuid", "mesh_uuid", "face_count"];
+
+/// Output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    GeoJson,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = Error;
+    /// "csv" or "geojson", any case.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "geojson" => Ok(Self::GeoJson),
+            _ => Err(anyhow!("Export format \"{}\" is not csv or geojson", s)),
+        }
+    }
+}
+
+/// Which impostors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportTable {
+    /// Live, region_impostors
+    Live,
+    /// Next generation, not promoted yet, initial_impostors
+    Initial,
+}
+
+impl ExportTable {
+    /// SQL table name.
+    fn table_name(&self) -> &'static str {
+        match self {
+            ExportTable::Live => "region_impostors",
+            ExportTable::Initial => "initial_impostors",
+        }
+    }
+}
+
+/// A CSV field, quoted if it must be.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) || s.trim() != s {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// The CSV header line, with newline.
+pub fn csv_header() -> String {
+    let faces = (0..MAX_EXPORT_FACES).flat_map(|n| [format!("base_texture_uuid_{}", n), format!("emissive_texture_uuid_{}", n)]);
+    let columns: Vec<String> = CSV_FIXED_COLUMNS.iter().map(|c| c.to_string()).chain(faces).chain([PROVENANCE_COLUMN.to_string()]).collect();
+    format!("{}\n", columns.join(","))
+}
+
+/// Optional value as a field. None is empty.
+fn opt_field<T: ToString>(v: Option<T>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// UUID as a field. Nil, not uploaded yet, is empty.
+fn uuid_field(u: Option<Uuid>) -> String {
+    opt_field(u.filter(|u| !u.is_nil()))
+}
+
+/// One impostor as a CSV line, with newline. Always as many fields as the header.
+pub fn csv_row(impostor: &RegionImpostorData) -> String {
+    let mut fields = vec![
+        csv_field(&impostor.grid),
+        impostor.region_loc[0].meters().to_string(),
+        impostor.region_loc[1].meters().to_string(),
+        impostor.region_size[0].to_string(),
+        impostor.region_size[1].to_string(),
+        csv_field(impostor.name.as_deref().unwrap_or("")),
+        impostor.impostor_lod.to_string(),
+        impostor.viz_group.to_string(),
+        impostor.generation.to_string(),
+        impostor.placeholder.to_string(),
+        impostor.scale[0].to_string(),
+        impostor.scale[1].to_string(),
+        impostor.scale[2].to_string(),
+        impostor.elevation_offset.to_string(),
+        opt_field(impostor.water_height),
+        opt_field(impostor.water_height_max),
+        uuid_field(impostor.sculpt_uuid),
+        uuid_field(impostor.mesh_uuid),
+        impostor.faces.len().to_string(),
+    ];
+    for n in 0..MAX_EXPORT_FACES {
+        let face = impostor.faces.get(n);
+        fields.push(uuid_field(face.map(|f| f.base_texture_uuid)));
+        fields.push(uuid_field(face.and_then(|f| f.emissive_texture_uuid)));
+    }
+    fields.push(csv_field(impostor.provenance_json.as_deref().unwrap_or("")));
+    format!("{}\n", fields.join(","))
+}
+
+/// One impostor as a GeoJSON Feature. The rectangle runs counterclockwise from the southwest corner.
+pub fn geojson_feature(impostor: &RegionImpostorData) -> serde_json::Value {
+    let [x0, y0] = impostor.region_loc.map(|c| c.meters() as u64);
+    let [x1, y1] = [x0 + impostor.region_size[0] as u64, y0 + impostor.region_size[1] as u64];
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [[[x0, y0], [x1, y0], [x1, y1], [x0, y1], [x0, y0]]],
+        },
+        "properties": {
+            "grid": impostor.grid,
+            "region_loc_x": x0,
+            "region_loc_y": y0,
+            "region_size_x": impostor.region_size[0],
+            "region_size_y": impostor.region_size[1],
+            "name": impostor.name,
+            "impostor_lod": impostor.impostor_lod,
+            "viz_group": impostor.viz_group,
+            "generation": impostor.generation,
+            "placeholder": impostor.placeholder,
+            "scale_x": impostor.scale[0],
+            "scale_y": impostor.scale[1],
+            "scale_z": impostor.scale[2],
+            "elevation_offset": impostor.elevation_offset,
+            "water_height": impostor.water_height,
+            "water_height_max": impostor.water_height_max,
+            "sculpt_uuid": impostor.sculpt_uuid.filter(|u| !u.is_nil()),
+            "mesh_uuid": impostor.mesh_uuid.filter(|u| !u.is_nil()),
+            "face_count": impostor.faces.len(),
+        },
+    })
+}
+
+/// Writes impostors out as they come.
+pub struct ImpostorExporter<'a> {
+    /// Where to
+    out: &'a mut dyn Write,
+    /// How
+    format: ExportFormat,
+    /// Impostors so far
+    count: usize,
+}
+
+impl<'a> ImpostorExporter<'a> {
+    /// Start, with the CSV header or the GeoJSON opening.
+    pub fn new(out: &'a mut dyn Write, format: ExportFormat) -> Result<Self, Error> {
+        match format {
+            ExportFormat::Csv => out.write_all(csv_header().as_bytes())?,
+            ExportFormat::GeoJson => out.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[")?,
+        }
+        Ok(Self { out, format, count: 0 })
+    }
+
+    /// One impostor.
+    pub fn add(&mut self, impostor: &RegionImpostorData) -> Result<(), Error> {
+        match self.format {
+            ExportFormat::Csv => self.out.write_all(csv_row(impostor).as_bytes())?,
+            ExportFormat::GeoJson => {
+                if self.count > 0 {
+                    self.out.write_all(b",")?;
+                }
+                self.out.write_all(b"\n")?;
+                serde_json::to_writer(&mut *self.out, &geojson_feature(impostor))?;
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Done. Returns the number of impostors written.
+    pub fn finish(self) -> Result<usize, Error> {
+        if self.format == ExportFormat::GeoJson {
+            self.out.write_all(b"\n]}\n")?;
+        }
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Write a grid's impostors, each as its row comes in from the database. Returns how many.
+pub fn export_impostors(conn: &mut dyn Db, grid: &str, table: ExportTable, format: ExportFormat, out: &mut dyn Write) -> Result<usize, Error> {
+    let sql = format!("SELECT {}, {} FROM {} WHERE grid = :grid ORDER BY impostor_lod, region_loc_x, region_loc_y",
+        REGION_IMPOSTOR_COLUMNS, PROVENANCE_COLUMN, table.table_name());
+    let mut exporter = ImpostorExporter::new(out, format)?;
+    conn.exec_each(&sql, params! { "grid" => canonical(grid) }, &mut |row| exporter.add(&RegionImpostorData::from_db_row_with_provenance(&row)?))?;
+    exporter.finish()
+}
+
+#[cfg(test)]
+fn test_impostor(x: u32, y: u32, name: Option<&str>, faces: usize) -> RegionImpostorData {
+    use crate::{GlobalMeters, RegionImpostorFaceData};
+    RegionImpostorData {
+        region_loc: [GlobalMeters(x), GlobalMeters(y)], region_size: [256, 512], scale: [256.0, 512.0, 30.5], impostor_lod: 0, lod_distance: 2170.0, viz_group: 3,
+        sculpt_uuid: Some(Uuid::from_u128(1)), sculpt_hash: None, mesh_uuid: None, mesh_hash: None, elevation_offset: 0.0,
+        water_height: Some(20.0), water_height_max: None, name: name.map(|n| n.to_string()), grid: "agni".to_string(),
+        faces: (0..faces).map(|n| RegionImpostorFaceData { base_texture_uuid: Uuid::from_u128(0x100 + n as u128), emissive_texture_uuid: None,
+            base_texture_hash: String::new(), emissive_texture_hash: None, atlas_uv: None }).collect(),
+        generation: 2, placeholder: false, water_only: false, provenance_json: None, terrain_hash: None,
+    }
+}
+
+#[test]
+fn test_csv_export() {
+    //  Commas, quotes, and line breaks in names are quoted, and nothing else is.
+    assert_eq!(csv_field("Vallone"), "Vallone");
+    assert_eq!(csv_field("Smith, Jones"), "\"Smith, Jones\"");
+    assert_eq!(csv_field("The \"Big\" Island"), "\"The \"\"Big\"\" Island\"");
+    assert_eq!(csv_field("Two\nLines"), "\"Two\nLines\"");
+    assert_eq!(csv_field(" Padded"), "\" Padded\"");
+    let header = csv_header();
+    let columns = header.trim_end().split(',').count();
+    assert_eq!(columns, CSV_FIXED_COLUMNS.len() + 2 * MAX_EXPORT_FACES + 1);
+    assert!(header.starts_with("grid,region_loc_x,region_loc_y,region_size_x,region_size_y,name,"));
+    assert!(header.trim_end().ends_with("base_texture_uuid_7,emissive_texture_uuid_7,provenance_json"));
+    //  Same number of fields every line, whatever the name and face count.
+    let impostor = test_impostor(256, 512, Some("Smith, \"Jones\""), 2);
+    let row = csv_row(&impostor);
+    assert!(row.starts_with("agni,256,512,256,512,\"Smith, \"\"Jones\"\"\",0,3,2,false,256,512,30.5,0,20,,00000000-0000-0000-0000-000000000001,,2,"), "{}", row);
+    assert!(row.contains(",00000000-0000-0000-0000-000000000100,,00000000-0000-0000-0000-000000000101,,"), "{}", row);
+    //  Unquoted, the name's comma adds one field.
+    assert_eq!(row.trim_end().replace("\"\"", "").split(',').count(), columns + 1);
+    let many_faces = csv_row(&test_impostor(0, 0, None, 10));
+    assert_eq!(many_faces.trim_end().split(',').count(), columns);
+    assert!(many_faces.contains(",10,"));
+    //  Provenance is last, quoted, since it's JSON with commas.
+    let with_provenance = RegionImpostorData { provenance_json: Some(r#"{"generator_version":"0.1.0","built_at":1775000000}"#.to_string()), ..test_impostor(0, 0, None, 0) };
+    assert!(csv_row(&with_provenance).ends_with(",\"{\"\"generator_version\"\":\"\"0.1.0\"\",\"\"built_at\"\":1775000000}\"\n"));
+    assert!(many_faces.ends_with(",\n"));
+}
+
+#[test]
+fn test_geojson_export() {
+    let impostors = [test_impostor(256, 512, Some("Smith, \"Jones\""), 1), test_impostor(0, 0, None, 0)];
+    let mut out = Vec::new();
+    let mut exporter = ImpostorExporter::new(&mut out, ExportFormat::GeoJson).unwrap();
+    for impostor in &impostors {
+        exporter.add(impostor).unwrap();
+    }
+    assert_eq!(exporter.finish().unwrap(), 2);
+    //  One valid document, with a rectangle for each impostor.
+    let collection: serde_json::Value = serde_json::from_slice(&out).expect("valid JSON");
+    assert_eq!(collection["type"], "FeatureCollection");
+    let features = collection["features"].as_array().unwrap();
+    assert_eq!(features.len(), 2);
+    assert_eq!(features[0]["geometry"]["coordinates"], json!([[[256, 512], [512, 512], [512, 1024], [256, 1024], [256, 512]]]));
+    assert_eq!(features[0]["properties"]["name"], "Smith, \"Jones\"");
+    assert_eq!(features[0]["properties"]["face_count"], 1);
+    assert!(features[1]["properties"]["name"].is_null());
+    //  Empty is still a valid document.
+    let mut out = Vec::new();
+    assert_eq!(ImpostorExporter::new(&mut out, ExportFormat::GeoJson).unwrap().finish().unwrap(), 0);
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&out).unwrap()["features"], json!([]));
+    assert_eq!("GeoJSON".parse::<ExportFormat>().unwrap(), ExportFormat::GeoJson);
+    assert!("kml".parse::<ExportFormat>().is_err());
+}
+
+#[test]
+fn test_export_impostors() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    //  No rows, just the header. One query, on the table asked for.
+    let mut fake = FakeDb::default();
+    let mut out = Vec::new();
+    assert_eq!(export_impostors(&mut fake, "Agni", ExportTable::Initial, ExportFormat::Csv, &mut out).unwrap(), 0);
+    assert_eq!(String::from_utf8(out).unwrap(), csv_header());
+    assert!(fake.sql()[0].contains("FROM initial_impostors WHERE grid = :grid"), "{:?}", fake.sql());
+    //  A bad row stops the export where it is.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::text("agni")])]]);
+    assert!(export_impostors(&mut fake, "agni", ExportTable::Live, ExportFormat::Csv, &mut Vec::new()).is_err());
+}
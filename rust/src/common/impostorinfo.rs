@@ -21,6 +21,8 @@
 //
 use anyhow::{anyhow, Error};
 use uuid::Uuid;
+use crate::db::DbRow;
+use crate::GlobalMeters;
 use serde;
 use serde::{Deserialize, Serialize};
 /// The data stored in the database for a region impostor.
@@ -34,14 +36,18 @@ use serde::{Deserialize, Serialize};
 /// Mesh impostor objects are aligned with the world coordinate system.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegionImpostorData {
-    /// Where it is in the world.
-    pub region_loc: [u32;2],
+    /// Where it is in the world, meters.
+    pub region_loc: [GlobalMeters; 2],
     /// Size of the region (always 256,256 for SL)
     pub region_size: [u32;2],
     /// Scale of the impostor mesh object (because mesh objects are all scaled -0.5 .. 0.5
     pub scale: [f32;3],
     /// Impostor level of detail. 0=1 region, 1=4 regions, etc.
     pub impostor_lod: RegionImpostorLod,
+    /// Recommended camera distance, meters, beyond which this tile is detailed enough.
+    /// Zero for impostors generated before this was computed.
+    #[serde(default)]
+    pub lod_distance: f32,
     /// Viz group ID. You can only see objects with the same viz group ID as your own.
     /// This indicates reachability without a teleport.
     /// Viz groups are generally in order of decreasing
@@ -58,80 +64,225 @@ pub struct RegionImpostorData {
     /// Should be zero unless this is a mountain range.
     pub elevation_offset: f32,
     /// Water height. Water is optional.
+    /// For a lower LOD tile, the lowest water height of its regions.
+    /// None for a placeholder, whose water height isn't known.
     pub water_height: Option<f32>,
+    /// Highest water height of a lower LOD tile's regions, only if they differ.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub water_height_max: Option<f32>,
     /// Name - name of region, if available. Mostly for debug.
     pub name: Option<String>,
     /// Grid -- name of associated grid
     pub grid: String,
     /// Faces (as JSON)
     pub faces: Vec<RegionImpostorFaceData>,
+    /// Bumped each time this impostor's assets change, so viewers can tell their cached copy is stale.
+    #[serde(default)]
+    pub generation: u32,
+    /// Flat stand-in for a region known to exist but not surveyed yet. Viewers may show it dimmed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub placeholder: bool,
+    /// All at or below water level, so no assets. Viewers show their own water plane, and fetch nothing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub water_only: bool,
+    /// Which generator, settings, and raw terrain made this, as JSON. For operators, never sent to viewers.
+    /// Only read by from_db_row_with_provenance.
+    #[serde(skip)]
+    pub provenance_json: Option<String>,
+    /// Hash of the height field and generation parameters a LOD 0 tile was built from.
+    /// The generator compares it to skip unchanged tiles. Never sent to viewers.
+    #[serde(skip)]
+    pub terrain_hash: Option<String>,
 }
 
 pub type RegionImpostorLod = u8;
 
+/// Default quality for recommended LOD distances. Distance is this many tile sizes, plus the elevation range.
+pub const DEFAULT_LOD_QUALITY: f32 = 4.0;
+
+/// Recommended camera distance, meters, beyond which a tile of this scale is detailed enough.
+/// Nearer than this, a viewer should show the smaller tiles inside it.
+///
+/// A tile has the same number of height samples at every LOD, so their spacing, and the error
+/// on screen, grows with the tile's size. Rugged terrain loses the most between samples, so
+/// the elevation range adds to it. Higher quality moves the switch farther out.
+pub fn lod_distance(scale: [f32; 3], quality: f32) -> f32 {
+    quality * (scale[0].max(scale[1]) + scale[2].max(0.0))
+}
+
+/// UUID to the string form stored in SQL.
+/// The nil UUID is SL's NULL_KEY, "no asset", so it's stored as NULL.
+pub fn uuid_opt_to_string(u: Option<Uuid>) -> Option<String> {
+    u.filter(|u| !u.is_nil()).map(|u| u.to_string())
+}
+
+/// UUID from the string form stored in SQL.
+/// NULL, empty, and the nil UUID are all None. Anything else must parse.
+pub fn string_opt_to_uuid(s: Option<String>) -> Result<Option<Uuid>, Error> {
+    match s {
+        None => Ok(None),
+        Some(s) if s.trim().is_empty() => Ok(None),
+        Some(s) => {
+            let u = Uuid::try_parse(s.trim()).map_err(|e| anyhow!("Invalid UUID \"{}\": {}", s, e))?;
+            Ok(Some(u).filter(|u| !u.is_nil()))
+        }
+    }
+}
+
+/// Columns of region_impostors, in the order RegionImpostorData::from_db_row reads them.
+pub const REGION_IMPOSTOR_COLUMNS: &str = "grid, region_loc_x, region_loc_y, name, region_size_x, region_size_y, scale_x, scale_y, scale_z, \
+    elevation_offset, impostor_lod, viz_group, mesh_uuid, sculpt_uuid, water_height, creator, creation_time, faces_json, generation, water_height_max, placeholder, lod_distance, \
+    water_only";
+
+/// Column after REGION_IMPOSTOR_COLUMNS for operator tools. Never selected for viewers.
+pub const PROVENANCE_COLUMN: &str = "provenance_json";
+
 impl RegionImpostorData {
+    /// From a row selecting REGION_IMPOSTOR_COLUMNS.
+    pub fn from_db_row(row: &DbRow) -> Result<Self, Error> {
+        //  Faces is JSON as a string and must be parsed.
+        let faces_json: String = row.get(17)?;
+        let faces = faces_from_json(&faces_json)?;
+        Ok(RegionImpostorData {
+            //  These are non-null in the SQL table definition, so NULL is an error.
+            grid: row.get(0)?,
+            region_loc: [GlobalMeters(row.get(1)?), GlobalMeters(row.get(2)?)],
+            name: row.get(3)?,
+            region_size: [row.get(4)?, row.get(5)?],
+            scale: [row.get::<u32>(6)? as f32, row.get::<u32>(7)? as f32, row.get(8)?],
+            elevation_offset: row.get(9)?,
+            impostor_lod: row.get(10)?,
+            lod_distance: row.get(21)?,
+            viz_group: row.get(11)?,
+            mesh_uuid: string_opt_to_uuid(row.get(12)?)?,
+            sculpt_uuid: string_opt_to_uuid(row.get(13)?)?,
+            water_height: row.get(14)?,
+            water_height_max: row.get(19)?,
+            //  Fields not used by the viewer
+            mesh_hash: None,
+            sculpt_hash: None,
+            faces,
+            generation: row.get(18)?,
+            placeholder: row.get(20)?,
+            water_only: row.get(22)?,
+            provenance_json: None,
+            terrain_hash: None,
+        })
+    }
+
+    /// From a row selecting REGION_IMPOSTOR_COLUMNS, then PROVENANCE_COLUMN.
+    pub fn from_db_row_with_provenance(row: &DbRow) -> Result<Self, Error> {
+        Ok(RegionImpostorData { provenance_json: row.get(23)?, ..Self::from_db_row(row)? })
+    }
 }
 /// Data for each face.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct RegionImpostorFaceData {
     /// Base texture for old non-material objects.
+    /// Nil if not uploaded yet.
+    #[serde(default)]
     pub base_texture_uuid: Uuid,
     /// Emissive texture, to show what's lit at night.
     /// For now, this is future expansion.
     pub emissive_texture_uuid: Option<Uuid>,
     /// Hash to avoid unnecessary asset uploads.
+    /// Absent in the old unversioned faces_json format.
+    #[serde(default)]
     pub base_texture_hash: String,
     /// Hash to avoid unnecessary asset uploads
     pub emissive_texture_hash: Option<String>,
+    /// If the base texture is a shared atlas, the part of it for this face,
+    /// as UV coordinates [u_min, v_min, u_max, v_max]. Absent if the texture is the face's own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atlas_uv: Option<[f32; 4]>,
+}
+
+/// faces_json as stored in SQL.
+/// The old format was a bare array of faces. This one has a version, so
+/// schema changes can be detected.
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionedFaces {
+    /// Format version
+    v: u32,
+    /// The faces, in texture index order
+    faces: Vec<RegionImpostorFaceData>,
 }
 
 impl RegionImpostorFaceData {
-    /// Make JSON from array of tuples.
-    /// Tuples are in order but may be sparse.
-    /// JSON must be an array in texture index order but rows can be empty.
-    /// This requires excessive wrangling.
-    pub fn json_from_tuples(tuples: &Vec<(usize, String, String, String)>) -> Result<serde_json::Value, Error> {
-        const MAX_TEXTURES: usize = 8;
-        let mut base_textures: [Option<String>;MAX_TEXTURES] = Default::default();
-        let mut emissive_textures: [Option<String>;MAX_TEXTURES] = Default::default();
-        for (texture_index, texture_uuid, _texture_hash, asset_type) in tuples {
+    /// Version of faces_json written by faces_to_json.
+    pub const FACES_JSON_VERSION: u32 = 1;
+    /// Most textures per impostor.
+    pub const MAX_TEXTURES: usize = 8;
+
+    /// Make faces from tuples of (texture index, UUID, hash, asset type).
+    /// Tuples can be in any order. Texture indices must start at 0 and
+    /// have no gaps, because faces are an array in texture index order.
+    pub fn from_texture_tuples(tuples: &[(usize, String, String, String)]) -> Result<Vec<RegionImpostorFaceData>, Error> {
+        let mut base_textures: [Option<(Uuid, String)>; Self::MAX_TEXTURES] = Default::default();
+        let mut emissive_textures: [Option<(Uuid, String)>; Self::MAX_TEXTURES] = Default::default();
+        for (texture_index, texture_uuid, texture_hash, asset_type) in tuples {
             let arr = match asset_type.as_str() {
                 "BaseTexture" => &mut base_textures,
                 "EmissiveTexture" => &mut emissive_textures,
                 _ => { return Err(anyhow!("Invalid asset type for face data: {}", asset_type)); }
             };
-            if *texture_index >= MAX_TEXTURES {
+            if *texture_index >= Self::MAX_TEXTURES {
                 return Err(anyhow!("Out of range texture index {} asset type for face data: {}", texture_index, asset_type));
             }
             if arr[*texture_index].is_some() {
                 return Err(anyhow!("Duplicate texture index {} asset type for face data: {}", texture_index, asset_type)); 
             }
-            arr[*texture_index] = Some(texture_uuid.to_string());
+            let uuid = Uuid::try_parse(texture_uuid).map_err(|e| anyhow!("Invalid UUID \"{}\" for texture index {}: {}", texture_uuid, texture_index, e))?;
+            arr[*texture_index] = Some((uuid, texture_hash.clone()));
         }
-        //  Now we have arrays of tuples. Convert to a vec of structs, stopping at the last non-empty.
-        let mut face_data = Vec::new();
-        let cnt = MAX_TEXTURES; // Cover all the slots
-        for n in 0..cnt {
-            //  Stop at first empty slot. 
-            //  Sparse texture usage not supported.
-            if base_textures[n].is_none() {
-                break;
-            }
-            let mut vals = serde_json::Map::new();
-            let mut inserter = |k: &str, v: &str| { vals.insert(k.to_string(), serde_json::Value::String(v.to_string())) };
-            //  Not putting the hashes in the JSON because the viewer does not need them.
-            if let Some(v) = &base_textures[n] {
-                inserter("base_texture_uuid", v);
-            }
-            if let Some(v) = &emissive_textures[n] {
-                inserter("emissive_texture_uuid", v);
-            }
-            face_data.push(serde_json::Value::Object(vals));
+        //  Faces run up to the first empty base texture slot.
+        let face_count = base_textures.iter().take_while(|t| t.is_some()).count();
+        //  Sparse texture usage not supported.
+        if let Some(used) = (face_count..Self::MAX_TEXTURES).find(|&n| base_textures[n].is_some()) {
+            return Err(anyhow!("Sparse texture slots: base texture {} is present but base texture {} is missing", used, face_count));
+        }
+        if let Some(used) = (face_count..Self::MAX_TEXTURES).find(|&n| emissive_textures[n].is_some()) {
+            return Err(anyhow!("Emissive texture {} has no base texture", used));
         }
-        let face_json = serde_json::Value::Array(face_data);
-        log::debug!("Face JSON: {:?}", face_json);
-        Ok(face_json)
-    }    
+        let face_data = base_textures
+            .into_iter()
+            .zip(emissive_textures)
+            .take(face_count)
+            .map(|(base, emissive)| {
+                let (base_texture_uuid, base_texture_hash) = base.expect("Counted above");
+                RegionImpostorFaceData {
+                    base_texture_uuid,
+                    base_texture_hash,
+                    emissive_texture_uuid: emissive.as_ref().map(|e| e.0),
+                    emissive_texture_hash: emissive.map(|e| e.1),
+                    atlas_uv: None,
+                }
+            })
+            .collect();
+        log::debug!("Face data: {:?}", face_data);
+        Ok(face_data)
+    }
+}
+
+/// Faces to JSON, for the faces_json SQL column.
+pub fn faces_to_json(faces: &[RegionImpostorFaceData]) -> Result<String, Error> {
+    Ok(serde_json::to_string(&VersionedFaces { v: RegionImpostorFaceData::FACES_JSON_VERSION, faces: faces.to_vec() })?)
+}
+
+/// Faces from the faces_json SQL column.
+/// Reads the versioned format and the old unversioned one.
+pub fn faces_from_json(faces_json: &str) -> Result<Vec<RegionImpostorFaceData>, Error> {
+    let value: serde_json::Value = serde_json::from_str(faces_json)?;
+    if value.is_array() {
+        //  Old format, from before versioning.
+        return Ok(serde_json::from_value(value)?);
+    }
+    let version = value.get("v").and_then(|v| v.as_u64()).ok_or_else(|| anyhow!("faces_json has no version number"))?;
+    if version != RegionImpostorFaceData::FACES_JSON_VERSION as u64 {
+        return Err(anyhow!("faces_json version {} not supported, expected {}", version, RegionImpostorFaceData::FACES_JSON_VERSION));
+    }
+    let versioned: VersionedFaces = serde_json::from_value(value)?;
+    Ok(versioned.faces)
 }
 
 /// What's returned to a caller via a REST request
@@ -146,6 +297,324 @@ pub struct RegionImpostorReply {
 }
 
 impl RegionImpostorReply {
-    /// Version of this interface
+    /// Version of this interface, if the viewer doesn't ask for one.
     pub const REGION_IMPOSTOR_INFO_VERSION: u32 = 1;
+    /// Oldest version we can send.
+    pub const MIN_REGION_IMPOSTOR_INFO_VERSION: u32 = 1;
+    /// Newest version we can send.
+    pub const MAX_REGION_IMPOSTOR_INFO_VERSION: u32 = 2;
+}
+
+/// Version 1 of one face, as sent to the viewer. No atlas UVs.
+#[derive(Serialize)]
+struct RegionImpostorFaceDataV1<'a> {
+    base_texture_uuid: &'a Uuid,
+    emissive_texture_uuid: &'a Option<Uuid>,
+    base_texture_hash: &'a str,
+    emissive_texture_hash: &'a Option<String>,
+}
+
+impl<'a> From<&'a RegionImpostorFaceData> for RegionImpostorFaceDataV1<'a> {
+    fn from(d: &'a RegionImpostorFaceData) -> Self {
+        Self {
+            base_texture_uuid: &d.base_texture_uuid,
+            emissive_texture_uuid: &d.emissive_texture_uuid,
+            base_texture_hash: &d.base_texture_hash,
+            emissive_texture_hash: &d.emissive_texture_hash,
+        }
+    }
+}
+
+/// Version 1 of one impostor, as sent to the viewer.
+#[derive(Serialize)]
+struct RegionImpostorDataV1<'a> {
+    region_loc: &'a [GlobalMeters; 2],
+    region_size: &'a [u32;2],
+    scale: &'a [f32;3],
+    impostor_lod: RegionImpostorLod,
+    viz_group: u32,
+    sculpt_uuid: &'a Option<Uuid>,
+    sculpt_hash: &'a Option<String>,
+    mesh_uuid: &'a Option<Uuid>,
+    mesh_hash: &'a Option<String>,
+    elevation_offset: f32,
+    water_height: &'a Option<f32>,
+    name: &'a Option<String>,
+    grid: &'a str,
+    faces: Vec<RegionImpostorFaceDataV1<'a>>,
+}
+
+impl<'a> From<&'a RegionImpostorData> for RegionImpostorDataV1<'a> {
+    fn from(d: &'a RegionImpostorData) -> Self {
+        Self {
+            region_loc: &d.region_loc,
+            region_size: &d.region_size,
+            scale: &d.scale,
+            impostor_lod: d.impostor_lod,
+            viz_group: d.viz_group,
+            sculpt_uuid: &d.sculpt_uuid,
+            sculpt_hash: &d.sculpt_hash,
+            mesh_uuid: &d.mesh_uuid,
+            mesh_hash: &d.mesh_hash,
+            elevation_offset: d.elevation_offset,
+            water_height: &d.water_height,
+            name: &d.name,
+            grid: &d.grid,
+            faces: d.faces.iter().map(RegionImpostorFaceDataV1::from).collect(),
+        }
+    }
+}
+
+/// Version 1 of the reply.
+#[derive(Serialize)]
+struct RegionImpostorReplyV1<'a> {
+    version: u32,
+    impostors: Vec<RegionImpostorDataV1<'a>>,
+    errors: &'a [String],
+}
+
+/// Formats a reply in the version the viewer asked for.
+/// Old viewers break on fields they don't know, so each version
+/// gets only the fields it had.
+///
+/// - Version 1: the impostor fields viewers had before versions, faces included.
+///   No water-only tiles. They have no assets, and a version 1 viewer would try to fetch them.
+/// - Version 2: adds generation, LOD distance, placeholder and water-only tiles, and atlas UVs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplyFormatter {
+    /// Version to send
+    version: u32,
+}
+
+impl ReplyFormatter {
+    /// Formatter for this version. Error if we can't send it.
+    pub fn new(version: u32) -> Result<Self, Error> {
+        let range = RegionImpostorReply::MIN_REGION_IMPOSTOR_INFO_VERSION..=RegionImpostorReply::MAX_REGION_IMPOSTOR_INFO_VERSION;
+        if !range.contains(&version) {
+            return Err(anyhow!("Version {} not supported. Supported versions are {} to {}.", version, range.start(), range.end()));
+        }
+        Ok(Self { version })
+    }
+    
+    /// Formatter for a "version" parameter. Default version if none.
+    pub fn new_from_param(version_opt: Option<&str>) -> Result<Self, Error> {
+        match version_opt {
+            None => Self::new(RegionImpostorReply::REGION_IMPOSTOR_INFO_VERSION),
+            Some(v) => Self::new(v.trim().parse().map_err(|_| anyhow!("Version \"{}\" is not a number.", v))?),
+        }
+    }
+    
+    /// The version being sent.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    
+    /// The reply as JSON. The reply's own version is ignored.
+    pub fn format(&self, reply: &RegionImpostorReply) -> Result<String, Error> {
+        if self.version == 1 {
+            let reply_v1 = RegionImpostorReplyV1 {
+                version: self.version,
+                impostors: reply.impostors.iter().filter(|d| !d.water_only).map(RegionImpostorDataV1::from).collect(),
+                errors: &reply.errors,
+            };
+            Ok(serde_json::to_string(&reply_v1)?)
+        } else {
+            Ok(serde_json::to_string(&RegionImpostorReply { version: self.version, ..reply.clone() })?)
+        }
+    }
+}
+
+#[test]
+fn test_uuid_string_conversions() {
+    let u = Uuid::parse_str("64604b5c-461e-dd72-52a9-3d464abf78aa").unwrap();
+    //  Round trip.
+    assert_eq!(uuid_opt_to_string(Some(u)), Some("64604b5c-461e-dd72-52a9-3d464abf78aa".to_string()));
+    assert_eq!(string_opt_to_uuid(uuid_opt_to_string(Some(u))).unwrap(), Some(u));
+    //  Upper case and whitespace are OK.
+    assert_eq!(string_opt_to_uuid(Some(" 64604B5C-461E-DD72-52A9-3D464ABF78AA ".to_string())).unwrap(), Some(u));
+    //  None passes through.
+    assert_eq!(uuid_opt_to_string(None), None);
+    assert_eq!(string_opt_to_uuid(None).unwrap(), None);
+    assert_eq!(string_opt_to_uuid(Some("".to_string())).unwrap(), None);
+    //  Nil UUID is no UUID.
+    assert_eq!(uuid_opt_to_string(Some(Uuid::nil())), None);
+    assert_eq!(string_opt_to_uuid(Some(Uuid::nil().to_string())).unwrap(), None);
+    //  Malformed strings are errors, not None.
+    for bad in ["junk", "64604b5c-461e-dd72-52a9", "64604b5c-461e-dd72-52a9-3d464abf78ag"] {
+        let err = string_opt_to_uuid(Some(bad.to_string())).expect_err("malformed UUID accepted");
+        assert!(format!("{}", err).contains(bad));
+    }
+}
+
+#[test]
+fn test_faces_json() {
+    const UUID_A: &str = "4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01";
+    const UUID_B: &str = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+    let tuple = |ix: usize, uuid: &str, hash: &str, asset_type: &str| (ix, uuid.to_string(), hash.to_string(), asset_type.to_string());
+    //  Out of order input, emissive on face 1 only.
+    let tuples = vec![
+        tuple(1, UUID_B, "bbbb", "BaseTexture"),
+        tuple(1, UUID_A, "eeee", "EmissiveTexture"),
+        tuple(0, UUID_A, "aaaa", "BaseTexture"),
+    ];
+    let faces = RegionImpostorFaceData::from_texture_tuples(&tuples).expect("faces");
+    assert_eq!(faces.len(), 2);
+    assert_eq!(faces[0].base_texture_uuid, Uuid::parse_str(UUID_A).unwrap());
+    assert_eq!(faces[0].base_texture_hash, "aaaa");
+    assert_eq!(faces[0].emissive_texture_uuid, None);
+    assert_eq!(faces[1].base_texture_uuid, Uuid::parse_str(UUID_B).unwrap());
+    assert_eq!(faces[1].emissive_texture_uuid, Some(Uuid::parse_str(UUID_A).unwrap()));
+    assert_eq!(faces[1].emissive_texture_hash, Some("eeee".to_string()));
+    //  Round trip.
+    let json = faces_to_json(&faces).expect("to JSON");
+    assert!(json.starts_with(r#"{"v":1,"#), "{}", json);
+    assert_eq!(faces_from_json(&json).expect("from JSON"), faces);
+    //  Faces with their own textures are stored exactly as before atlases.
+    assert_eq!(json, format!(concat!(r#"{{"v":1,"faces":[{{"base_texture_uuid":"{}","emissive_texture_uuid":null,"base_texture_hash":"aaaa","#,
+        r#""emissive_texture_hash":null}},{{"base_texture_uuid":"{}","emissive_texture_uuid":"{}","base_texture_hash":"bbbb","emissive_texture_hash":"eeee"}}]}}"#),
+        UUID_A, UUID_B, UUID_A));
+    //  Atlas sub-rectangles round trip.
+    let mut atlas_faces = faces.clone();
+    atlas_faces[0].atlas_uv = Some([0.0, 0.75, 0.25, 1.0]);
+    let atlas_json = faces_to_json(&atlas_faces).expect("to JSON");
+    assert!(atlas_json.contains(r#""atlas_uv":[0.0,0.75,0.25,1.0]"#), "{}", atlas_json);
+    assert_eq!(faces_from_json(&atlas_json).expect("from JSON"), atlas_faces);
+    //  Sparse slots are rejected, saying which.
+    let err = RegionImpostorFaceData::from_texture_tuples(&[tuple(0, UUID_A, "aaaa", "BaseTexture"), tuple(2, UUID_B, "bbbb", "BaseTexture")])
+        .expect_err("sparse accepted").to_string();
+    assert!(err.contains("base texture 2 is present but base texture 1 is missing"), "{}", err);
+    let err = RegionImpostorFaceData::from_texture_tuples(&[tuple(0, UUID_A, "aaaa", "BaseTexture"), tuple(1, UUID_B, "bbbb", "EmissiveTexture")])
+        .expect_err("emissive without base accepted").to_string();
+    assert!(err.contains("Emissive texture 1 has no base texture"), "{}", err);
+    assert!(RegionImpostorFaceData::from_texture_tuples(&[tuple(0, "junk", "aaaa", "BaseTexture")]).is_err());
+    //  Old unversioned format, with no hashes.
+    let old = format!(r#"[{{"base_texture_uuid":"{}"}},{{"base_texture_uuid":"{}","emissive_texture_uuid":"{}"}}]"#, UUID_A, UUID_B, UUID_A);
+    let faces = faces_from_json(&old).expect("old format");
+    assert_eq!(faces.len(), 2);
+    assert_eq!(faces[1].emissive_texture_uuid, Some(Uuid::parse_str(UUID_A).unwrap()));
+    assert!(faces[0].base_texture_hash.is_empty());
+    //  Unknown versions are detected.
+    let err = faces_from_json(r#"{"v":2,"faces":[]}"#).expect_err("future version accepted").to_string();
+    assert!(err.contains("version 2"), "{}", err);
+    assert!(faces_from_json(r#"{"faces":[]}"#).is_err());
+}
+
+#[test]
+fn test_reply_formatter() {
+    const UUID_A: &str = "4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01";
+    const UUID_B: &str = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+    let reply = RegionImpostorReply {
+        version: 0,
+        impostors: vec![RegionImpostorData {
+            region_loc: [GlobalMeters(290304), GlobalMeters(268288)],
+            region_size: [256, 256],
+            scale: [256.0, 256.0, 25.5],
+            impostor_lod: 0,
+            lod_distance: 1126.0,
+            viz_group: 3,
+            sculpt_uuid: Some(Uuid::parse_str(UUID_A).unwrap()),
+            sculpt_hash: None,
+            mesh_uuid: None,
+            mesh_hash: None,
+            elevation_offset: 0.0,
+            water_height: Some(20.0),
+            water_height_max: None,
+            name: Some("Kraken".to_string()),
+            grid: "agni".to_string(),
+            faces: vec![RegionImpostorFaceData {
+                base_texture_uuid: Uuid::parse_str(UUID_B).unwrap(),
+                emissive_texture_uuid: Some(Uuid::parse_str(UUID_A).unwrap()),
+                base_texture_hash: "bbbb".to_string(),
+                emissive_texture_hash: Some("eeee".to_string()),
+                atlas_uv: None,
+            }],
+            generation: 7,
+            placeholder: false,
+            water_only: false,
+            provenance_json: Some(r#"{"generator_version":"0.1.0"}"#.to_string()),
+            terrain_hash: None,
+        }],
+        errors: vec!["bad row".to_string()],
+    };
+    //  Version 1 is pinned. If this changes, old viewers may break.
+    let v1 = ReplyFormatter::new_from_param(None).expect("default version");
+    assert_eq!(v1.version(), 1);
+    assert_eq!(v1.format(&reply).expect("v1"), concat!(
+        r#"{"version":1,"impostors":[{"region_loc":[290304,268288],"region_size":[256,256],"scale":[256.0,256.0,25.5],"#,
+        r#""impostor_lod":0,"viz_group":3,"sculpt_uuid":"4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01","sculpt_hash":null,"#,
+        r#""mesh_uuid":null,"mesh_hash":null,"elevation_offset":0.0,"water_height":20.0,"name":"Kraken","grid":"agni","#,
+        r#""faces":[{"base_texture_uuid":"a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d","emissive_texture_uuid":"4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01","#,
+        r#""base_texture_hash":"bbbb","emissive_texture_hash":"eeee"}]}],"#,
+        r#""errors":["bad row"]}"#));
+    //  Faces read back from version 1 as they were.
+    let read: serde_json::Value = serde_json::from_str(&v1.format(&reply).expect("v1")).expect("v1 JSON");
+    let faces: Vec<RegionImpostorFaceData> = serde_json::from_value(read["impostors"][0]["faces"].clone()).expect("v1 faces");
+    assert_eq!(faces, reply.impostors[0].faces);
+    //  Version 2 has faces, with emissive textures.
+    let v2 = ReplyFormatter::new_from_param(Some("2")).expect("v2");
+    let json = v2.format(&reply).expect("v2");
+    assert!(json.starts_with(r#"{"version":2,"#), "{}", json);
+    assert!(json.contains(r#""faces":[{"base_texture_uuid":"a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d","emissive_texture_uuid":"4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01","#), "{}", json);
+    assert!(json.contains(r#""generation":7"#), "{}", json);
+    assert!(json.contains(r#""impostor_lod":0,"lod_distance":1126.0,"#), "{}", json);
+    assert!(!v1.format(&reply).expect("v1").contains("lod_distance"));
+    //  Uniform water has no highest water height. Non-uniform has both.
+    assert!(json.contains(r#""water_height":20.0,"#) && !json.contains("water_height_max"), "{}", json);
+    let mut reply = reply;
+    reply.impostors[0].water_height_max = Some(25.0);
+    let json = v2.format(&reply).expect("v2");
+    assert!(json.contains(r#""water_height":20.0,"water_height_max":25.0,"#), "{}", json);
+    //  Placeholders are marked. Real terrain doesn't say so.
+    assert!(!json.contains("placeholder"), "{}", json);
+    reply.impostors[0].placeholder = true;
+    let json = v2.format(&reply).expect("v2");
+    assert!(json.contains(r#""placeholder":true"#), "{}", json);
+    assert!(!v1.format(&reply).expect("v1").contains("placeholder"));
+    //  So are water-only tiles, which have nothing to fetch.
+    assert!(!json.contains("water_only"), "{}", json);
+    reply.impostors[0].water_only = true;
+    let json = v2.format(&reply).expect("v2");
+    assert!(json.contains(r#""placeholder":true,"water_only":true}"#), "{}", json);
+    //  Version 1 leaves them out. Its viewers would try to fetch assets which don't exist.
+    let v1_count = |reply: &RegionImpostorReply| {
+        let read: serde_json::Value = serde_json::from_str(&v1.format(reply).expect("v1")).expect("v1 JSON");
+        read["impostors"].as_array().expect("impostors").len()
+    };
+    assert_eq!(v1_count(&reply), 0);
+    assert!(!v1.format(&reply).expect("v1").contains("water_only"));
+    let mut land = reply.impostors[0].clone();
+    land.water_only = false;
+    reply.impostors.push(land);
+    assert_eq!(v1_count(&reply), 1);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&v2.format(&reply).expect("v2")).expect("v2 JSON")["impostors"].as_array().unwrap().len(), 2);
+    reply.impostors.pop();
+    //  A face on a shared atlas carries its part of it.
+    reply.impostors[0].faces[0].atlas_uv = Some([0.25, 0.5, 0.5, 0.75]);
+    let json = v2.format(&reply).expect("v2");
+    assert!(json.contains(r#""emissive_texture_hash":"eeee","atlas_uv":[0.25,0.5,0.5,0.75]}"#), "{}", json);
+    assert!(!v1.format(&reply).expect("v1").contains("atlas_uv"));
+    //  Out of range or junk versions say what is supported.
+    for bad in ["0", "3", "junk"] {
+        assert!(ReplyFormatter::new_from_param(Some(bad)).is_err(), "{}", bad);
+    }
+    let msg = ReplyFormatter::new(99).unwrap_err().to_string();
+    assert!(msg.contains("Supported versions are 1 to 2"), "{}", msg);
+    //  Provenance is for operators, never viewers, in any version.
+    for formatter in [&v1, &v2] {
+        assert!(!formatter.format(&reply).expect("format").contains("provenance"));
+        assert!(!formatter.format(&reply).expect("format").contains("generator_version"));
+    }
+}
+
+#[test]
+fn test_lod_distance() {
+    //  Flat 256m region.
+    assert_eq!(lod_distance([256.0, 256.0, 0.0], DEFAULT_LOD_QUALITY), 1024.0);
+    //  Mountainous one. The elevation range pushes the switch out.
+    assert_eq!(lod_distance([256.0, 256.0, 180.0], DEFAULT_LOD_QUALITY), 1744.0);
+    //  LOD 3 composite of 8x8 regions.
+    assert_eq!(lod_distance([2048.0, 2048.0, 95.5], DEFAULT_LOD_QUALITY), 8574.0);
+    //  Quality scales it. Varregions go by the longer side.
+    assert_eq!(lod_distance([256.0, 256.0, 0.0], 2.0), 512.0);
+    assert_eq!(lod_distance([512.0, 256.0, 20.0], 1.0), 532.0);
 }
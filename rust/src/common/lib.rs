@@ -1,17 +1,66 @@
 //!     Parts common to both server and generator sides
 mod credentials;
+mod error;
 mod fcgisocketsetup;
 mod minifcgi;
+mod router;
+mod fcgiclient;
 mod uploadedregioninfo;
+mod heightfieldio;
+mod rawterrain;
+mod staleness;
+mod coverage;
+mod dbcheck;
+mod retiredassets;
+mod impostorchanges;
 mod impostorinfo;
 mod testlogger;
 mod auth;
+mod regiondata;
+mod assetname;
+mod coords;
+mod atomicfile;
+mod requiredparams;
+mod textureatlas;
+mod replayguard;
+mod impostorexport;
+mod responsecache;
+mod ratelimit;
+mod impostordelete;
+pub mod db;
+pub mod metrics;
+pub mod hashing;
+pub mod grid;
 
-pub use credentials::Credentials;
+pub use credentials::{Credentials, WatchedCredentials, environment_names};
+pub use error::{Error, status_for};
 pub use fcgisocketsetup::init_fcgi;
-pub use minifcgi::{Handler, Request, Response, run};
-pub use uploadedregioninfo::{UploadedRegionInfo, HeightField};
-pub use uploadedregioninfo::{elev_min_max_to_scale_offset, elev_to_u8, u8_to_elev};
-pub use impostorinfo::{RegionImpostorReply, RegionImpostorData, RegionImpostorFaceData, RegionImpostorLod};
-pub use testlogger::{test_logger};
-pub use auth::{Authorizer, AuthorizeType};
+pub use fcgiclient::{FcgiReply, SimulatedHeaders, encode_params, fcgi_record, fcgi_transaction, header_param, read_reply};
+pub use minifcgi::{Handler, Request, Response, ResponseSink, run, run_with_metrics, run_duplex, serve};
+pub use minifcgi::{FcgiParseError, FcgiParser, FcgiRecord};
+pub use router::Router;
+pub use uploadedregioninfo::{UploadedRegionInfo, UploadedRegionInfoBuilder, HeightField, HeightFieldDiff, RegionDeletion, TerrainUpload};
+pub use rawterrain::{RawTerrainHeights, mark_region_deleted, rename_region, NOT_DELETED_IMPOSTOR};
+pub use staleness::{RegionAge, StalenessBuckets, StaleRegionsReply, get_region_ages};
+pub use coverage::{CoverageReply, LodCoverage, get_coverage, rle_encode, rle_decode};
+pub use dbcheck::{DbCheckReport, RawTerrainRow, RowFinding, RowProblem, RowRepair, check_raw_terrain, check_row, repair_for};
+pub use retiredassets::{RetiredAsset, RetiredAssetsReply, get_retired_assets, impostor_assets, parse_since, purge_retired_assets, retire_superseded, retire_unused, retired_between};
+pub use impostorchanges::{ChangesSince, ImpostorChange, ImpostorChangesReply, TileChange, TileKey, changes_between, get_impostor_changes, next_grid_generation, record_impostor_changes, record_removal,
+    CHANGE_RETENTION_DAYS, MAX_CHANGES_RETURNED};
+pub use uploadedregioninfo::{elev_min_max_to_scale_offset, elev_to_u8, u8_to_elev, scale_from_legacy, scale_to_legacy, LEGACY_ELEV_STEPS, MIN_ELEV_SCALE};
+pub use impostorinfo::{RegionImpostorReply, RegionImpostorData, RegionImpostorFaceData, RegionImpostorLod, ReplyFormatter, PROVENANCE_COLUMN, REGION_IMPOSTOR_COLUMNS};
+pub use impostorinfo::{DEFAULT_LOD_QUALITY, lod_distance};
+pub use impostorinfo::{uuid_opt_to_string, string_opt_to_uuid, faces_to_json, faces_from_json};
+pub use testlogger::{test_logger, test_logger_capture, LogCapture};
+pub use auth::{Authorizer, AuthorizeType, RequestOrigin, canonical_owner_name, constant_time_eq};
+pub use regiondata::{RegionData, get_group_bounds, group_area, group_bounds, group_tile_size, test_region};
+pub use assetname::{AssetName, AssetKind};
+pub use atomicfile::{WrittenFile, remove_stale_tmp_files, save_png_atomic, tmp_path, write_atomic, write_bytes_atomic};
+pub use requiredparams::{RequiredParams, query_string};
+pub use textureatlas::{AtlasSlot, ATLAS_SIZE, ATLAS_SLOT_SIZE, ATLAS_TILES_ACROSS, MAX_ATLAS_TILES, slot_mask, slots_in_mask};
+pub use replayguard::{ReplayGuard, MAX_CLOCK_SKEW_SECS, REPLAY_CACHE_SIZE, unix_time_now};
+pub use impostorexport::{ExportFormat, ExportTable, ImpostorExporter, MAX_EXPORT_FACES, csv_field, csv_header, csv_row, export_impostors, geojson_feature};
+pub use responsecache::{CachedResponse, ResponseCache, ResponseCacheKey, etag_for, etag_matches, generation_watermark};
+pub use ratelimit::{RateLimiter, DEFAULT_BURST, DEFAULT_RATE_PER_MINUTE, MAX_RATE_LIMIT_CLIENTS};
+pub use impostordelete::{ImpostorDeleteReply, ImpostorDeleteRequest, delete_impostor};
+pub use coords::{CoordUnits, GlobalMeters, RegionGridCoord, region_coords_to_meters, REGION_GRID_METERS, REGION_GRID_UNITS_LIMIT};
@@ -0,0 +1,583 @@
+//! metrics.rs -- request counters for the FCGI responders.
+//! Part of the Animats impostor system
+//!
+//! Each responder process counts requests, status codes, bytes,
+//! errors, and handler time. Counters are atomics behind an Arc, so clones
+//! share them. A snapshot can be written as JSON, or as Prometheus-style
+//! text, and is logged every few minutes if a log interval is set.
+//!
+//! Handlers mark the phases of a request, such as parse, auth, and SQL,
+//! on the request's PhaseTimer. A request which is too big or too slow
+//! for the limits in the credentials file gets one warning line with
+//! the time in each phase.
+//!
+//! Connections have hard limits too. A peer that stops sending for the
+//! read timeout is dropped, and a request bigger than the byte limit is
+//! refused before it is read in.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets, milliseconds.
+/// Slower requests go in a final bucket with no bound.
+const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+/// HTTP status codes counted individually. Others count as 0.
+const STATUS_RANGE: std::ops::Range<usize> = 100..600;
+/// Responders log a snapshot this often.
+pub const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Credentials file key for the slow request limit, milliseconds.
+pub const SLOW_REQUEST_MS_KEY: &str = "SLOW_REQUEST_MS";
+/// Credentials file key for the large request limit, body bytes.
+pub const LARGE_REQUEST_BYTES_KEY: &str = "LARGE_REQUEST_BYTES";
+/// Credentials file key for the connection read timeout, seconds. 0 for none.
+pub const READ_TIMEOUT_SECS_KEY: &str = "READ_TIMEOUT_SECS";
+/// Credentials file key for the most bytes buffered for one request. 0 for no limit.
+pub const MAX_REQUEST_BYTES_KEY: &str = "MAX_REQUEST_BYTES";
+/// Default connection read timeout.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default most bytes buffered for one request, params and body.
+pub const DEFAULT_MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+/// Prefix for Prometheus metric names.
+const PROMETHEUS_PREFIX: &str = "maptools";
+
+/// The counters.
+#[derive(Debug)]
+struct Counters {
+    requests: AtomicU64,
+    /// Indexed by status code - 100. The extra one is for codes out of range.
+    status_counts: Vec<AtomicU64>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    sql_errors: AtomicU64,
+    auth_failures: AtomicU64,
+    /// Connections ended by an error, not EOF
+    connection_errors: AtomicU64,
+    /// Replies served from a response cache, and not
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// Non-cumulative. One more than LATENCY_BUCKETS_MS.
+    latency_buckets: Vec<AtomicU64>,
+    /// Total handler time, microseconds
+    latency_sum_us: AtomicU64,
+    /// When counting started
+    start_time: Instant,
+    /// Log a snapshot this often, if set.
+    log_interval: Option<Duration>,
+    /// Seconds after start_time of the last logged snapshot.
+    last_log_secs: AtomicU64,
+}
+
+/// Request metrics, shared by clones.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+    /// Requests over these get logged
+    limits: RequestLimits,
+}
+
+/// Requests bigger or slower than this get a warning with the phase times.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestLimits {
+    /// Request body size
+    pub body_bytes: Option<usize>,
+    /// Total handling time
+    pub duration: Option<Duration>,
+}
+
+/// Hard limits on each connection. Requests over them are not served.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionLimits {
+    /// Longest wait for input. A stalled peer is dropped after this.
+    pub read_timeout: Option<Duration>,
+    /// Most bytes of params and body buffered for one request.
+    pub max_request_bytes: Option<usize>,
+}
+
+/// Time spent in each phase of a request. The handler marks the end of each phase.
+#[derive(Debug)]
+pub struct PhaseTimer {
+    /// When the request started
+    start: Instant,
+    /// End of the last phase marked
+    last: Cell<Instant>,
+    /// Phases in order. Repeated names add up.
+    phases: RefCell<Vec<(&'static str, Duration)>>,
+}
+
+/// One latency histogram bucket.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencyBucket {
+    /// Upper bound, milliseconds. None for the last bucket.
+    pub le_ms: Option<u64>,
+    /// Requests in this bucket. Not cumulative.
+    pub count: u64,
+}
+
+/// The counters at one moment.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub uptime_secs: u64,
+    pub requests: u64,
+    /// Requests by HTTP status. Only codes seen.
+    pub status_counts: BTreeMap<u16, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub sql_errors: u64,
+    pub auth_failures: u64,
+    pub connection_errors: u64,
+    /// Cacheable requests answered from the response cache, and from the database.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub latency_buckets: Vec<LatencyBucket>,
+    /// Total handler time, milliseconds
+    pub latency_sum_ms: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    /// Usual new. All zero.
+    pub fn new() -> Self {
+        Self::new_with_log_interval(None)
+    }
+
+    /// Also log a snapshot every log_interval, checked as requests are observed.
+    pub fn new_with_log_interval(log_interval: Option<Duration>) -> Self {
+        let zeros = |n: usize| (0..n).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            counters: Arc::new(Counters {
+                requests: AtomicU64::new(0),
+                status_counts: zeros(STATUS_RANGE.len() + 1),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+                sql_errors: AtomicU64::new(0),
+                auth_failures: AtomicU64::new(0),
+                connection_errors: AtomicU64::new(0),
+                cache_hits: AtomicU64::new(0),
+                cache_misses: AtomicU64::new(0),
+                latency_buckets: zeros(LATENCY_BUCKETS_MS.len() + 1),
+                latency_sum_us: AtomicU64::new(0),
+                start_time: Instant::now(),
+                log_interval,
+                last_log_secs: AtomicU64::new(0),
+            }),
+            limits: RequestLimits::default(),
+        }
+    }
+
+    /// Also warn about requests over these limits.
+    pub fn with_limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Log one warning line if this request was over the limits.
+    pub fn check_limits(&self, body_bytes: usize, params_bytes: usize, phase_timer: &PhaseTimer) {
+        if let Some(msg) = self.limits.over_limit_message(body_bytes, params_bytes, phase_timer) {
+            log::warn!("{}", msg);
+        }
+    }
+
+    /// Count one handled request.
+    pub fn observe_request(&self, status: usize, bytes_in: usize, bytes_out: usize, duration: Duration) {
+        let c = &self.counters;
+        c.requests.fetch_add(1, Ordering::Relaxed);
+        let status_index = if STATUS_RANGE.contains(&status) { status - STATUS_RANGE.start } else { STATUS_RANGE.len() };
+        c.status_counts[status_index].fetch_add(1, Ordering::Relaxed);
+        c.bytes_in.fetch_add(bytes_in as u64, Ordering::Relaxed);
+        c.bytes_out.fetch_add(bytes_out as u64, Ordering::Relaxed);
+        let ms = duration.as_millis();
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&le| ms <= le as u128).unwrap_or(LATENCY_BUCKETS_MS.len());
+        c.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        c.latency_sum_us.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.log_if_due();
+    }
+
+    /// Count a failed authorization.
+    pub fn observe_auth_failure(&self) {
+        self.counters.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection ended by an error, such as a bad record or a failed write.
+    pub fn observe_connection_error(&self, e: &Error) {
+        self.counters.connection_errors.fetch_add(1, Ordering::Relaxed);
+        self.observe_error(e);
+    }
+
+    /// Count a cacheable request, answered from the cache or not.
+    pub fn observe_cache(&self, hit: bool) {
+        let counter = if hit { &self.counters.cache_hits } else { &self.counters.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an error which stopped a request. Only database errors are counted.
+    pub fn observe_error(&self, e: &Error) {
+        if e.chain().any(|cause| cause.downcast_ref::<mysql::Error>().is_some()) {
+            self.counters.sql_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Current values.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let c = &self.counters;
+        let status_counts = c.status_counts[..STATUS_RANGE.len()].iter().enumerate()
+            .map(|(i, n)| ((i + STATUS_RANGE.start) as u16, n.load(Ordering::Relaxed)))
+            .chain(std::iter::once((0, c.status_counts[STATUS_RANGE.len()].load(Ordering::Relaxed))))
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        let latency_buckets = c.latency_buckets.iter().enumerate()
+            .map(|(i, n)| LatencyBucket { le_ms: LATENCY_BUCKETS_MS.get(i).copied(), count: n.load(Ordering::Relaxed) })
+            .collect();
+        MetricsSnapshot {
+            uptime_secs: c.start_time.elapsed().as_secs(),
+            requests: c.requests.load(Ordering::Relaxed),
+            status_counts,
+            bytes_in: c.bytes_in.load(Ordering::Relaxed),
+            bytes_out: c.bytes_out.load(Ordering::Relaxed),
+            sql_errors: c.sql_errors.load(Ordering::Relaxed),
+            auth_failures: c.auth_failures.load(Ordering::Relaxed),
+            connection_errors: c.connection_errors.load(Ordering::Relaxed),
+            cache_hits: c.cache_hits.load(Ordering::Relaxed),
+            cache_misses: c.cache_misses.load(Ordering::Relaxed),
+            latency_buckets,
+            latency_sum_ms: c.latency_sum_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+
+    /// Log a snapshot if the log interval has passed. Only one thread logs each time.
+    fn log_if_due(&self) {
+        let c = &self.counters;
+        if let Some(interval) = c.log_interval {
+            let now_secs = c.start_time.elapsed().as_secs();
+            let last = c.last_log_secs.load(Ordering::Relaxed);
+            if now_secs >= last + interval.as_secs().max(1)
+                && c.last_log_secs.compare_exchange(last, now_secs, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                match self.snapshot().to_json() {
+                    Ok(json) => log::info!("Metrics: {}", json),
+                    Err(e) => log::error!("Metrics not logged: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// As JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// As Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        //  Writing to a String can't fail.
+        let mut counter = |name: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(s, "# HELP {}_{} {}", PROMETHEUS_PREFIX, name, help);
+            let _ = writeln!(s, "# TYPE {}_{} counter", PROMETHEUS_PREFIX, name);
+            for (labels, value) in samples {
+                let _ = writeln!(s, "{}_{}{} {}", PROMETHEUS_PREFIX, name, labels, value);
+            }
+        };
+        counter("requests_total", "Requests handled.", &[(String::new(), self.requests)]);
+        let statuses: Vec<(String, u64)> = self.status_counts.iter().map(|(status, n)| (format!("{{status=\"{}\"}}", status), *n)).collect();
+        counter("responses_total", "Responses by HTTP status.", &statuses);
+        counter("bytes_in_total", "Request body bytes.", &[(String::new(), self.bytes_in)]);
+        counter("bytes_out_total", "Response bytes.", &[(String::new(), self.bytes_out)]);
+        counter("sql_errors_total", "Requests failed by database errors.", &[(String::new(), self.sql_errors)]);
+        counter("auth_failures_total", "Requests refused authorization.", &[(String::new(), self.auth_failures)]);
+        counter("connection_errors_total", "Connections ended by errors.", &[(String::new(), self.connection_errors)]);
+        counter("cache_hits_total", "Replies from the response cache.", &[(String::new(), self.cache_hits)]);
+        counter("cache_misses_total", "Cacheable replies not in the response cache.", &[(String::new(), self.cache_misses)]);
+        //  Histogram buckets are cumulative, in seconds.
+        let name = format!("{}_request_duration_seconds", PROMETHEUS_PREFIX);
+        let _ = writeln!(s, "# HELP {} Handler time.", name);
+        let _ = writeln!(s, "# TYPE {} histogram", name);
+        let mut total = 0;
+        for bucket in &self.latency_buckets {
+            total += bucket.count;
+            let le = bucket.le_ms.map(|ms| format!("{}", ms as f64 / 1000.0)).unwrap_or_else(|| "+Inf".to_string());
+            let _ = writeln!(s, "{}_bucket{{le=\"{}\"}} {}", name, le, total);
+        }
+        let _ = writeln!(s, "{}_sum {}", name, self.latency_sum_ms / 1000.0);
+        let _ = writeln!(s, "{}_count {}", name, total);
+        s
+    }
+}
+
+impl RequestLimits {
+    /// Limits from a key lookup, such as a credentials file. Missing keys mean no limit.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let number = |key: &str| -> Result<Option<u64>, Error> {
+            get(key).map(|v| v.trim().parse::<u64>().map_err(|e| anyhow!("{} \"{}\" is not a number: {}", key, v, e))).transpose()
+        };
+        Ok(Self {
+            body_bytes: number(LARGE_REQUEST_BYTES_KEY)?.map(|n| n as usize),
+            duration: number(SLOW_REQUEST_MS_KEY)?.map(Duration::from_millis),
+        })
+    }
+
+    /// The warning for a request over the limits, if it was.
+    pub fn over_limit_message(&self, body_bytes: usize, params_bytes: usize, phase_timer: &PhaseTimer) -> Option<String> {
+        let total = phase_timer.total();
+        let too_big = self.body_bytes.is_some_and(|limit| body_bytes > limit);
+        let too_slow = self.duration.is_some_and(|limit| total > limit);
+        if !too_big && !too_slow {
+            return None;
+        }
+        Some(format!("{} request: body {} bytes, params {} bytes, total {} ms: {}",
+            if too_slow { "Slow" } else { "Large" }, body_bytes, params_bytes, total.as_millis(), phase_timer))
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self { read_timeout: Some(DEFAULT_READ_TIMEOUT), max_request_bytes: Some(DEFAULT_MAX_REQUEST_BYTES) }
+    }
+}
+
+impl ConnectionLimits {
+    /// Limits from a key lookup, such as a credentials file. Missing keys get the defaults, and 0 means no limit.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let number = |key: &str| -> Result<Option<u64>, Error> {
+            get(key).map(|v| v.trim().parse::<u64>().map_err(|e| anyhow!("{} \"{}\" is not a number: {}", key, v, e))).transpose()
+        };
+        let default = Self::default();
+        Ok(Self {
+            read_timeout: match number(READ_TIMEOUT_SECS_KEY)? {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => default.read_timeout,
+            },
+            max_request_bytes: match number(MAX_REQUEST_BYTES_KEY)? {
+                Some(0) => None,
+                Some(n) => Some(n as usize),
+                None => default.max_request_bytes,
+            },
+        })
+    }
+}
+
+impl Default for PhaseTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhaseTimer {
+    /// Start timing now.
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self { start: now, last: Cell::new(now), phases: RefCell::new(Vec::new()) }
+    }
+
+    /// The phase with this name ends now.
+    pub fn mark(&self, name: &'static str) {
+        self.mark_at(name, Instant::now());
+    }
+
+    /// The phase with this name ends at this time.
+    fn mark_at(&self, name: &'static str, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last.get());
+        self.last.set(now);
+        let mut phases = self.phases.borrow_mut();
+        match phases.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, duration)) => *duration += elapsed,
+            None => phases.push((name, elapsed)),
+        }
+    }
+
+    /// Phases so far, in order.
+    pub fn phases(&self) -> Vec<(&'static str, Duration)> {
+        self.phases.borrow().clone()
+    }
+
+    /// Start to the end of the last phase. The sum of the phases.
+    pub fn total(&self) -> Duration {
+        self.last.get().duration_since(self.start)
+    }
+}
+
+impl std::fmt::Display for PhaseTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let phases: Vec<String> = self.phases.borrow().iter().map(|(name, duration)| format!("{} {} ms", name, duration.as_millis())).collect();
+        write!(f, "{}", phases.join(", "))
+    }
+}
+
+#[test]
+fn test_metrics_concurrent() {
+    let metrics = Metrics::new();
+    let threads: Vec<_> = (0..8).map(|i| {
+        let metrics = metrics.clone();
+        std::thread::spawn(move || {
+            for _ in 0..1000 {
+                metrics.observe_request(if i % 2 == 0 { 200 } else { 500 }, 10, 100, Duration::from_millis(i));
+                metrics.observe_auth_failure();
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.requests, 8000);
+    assert_eq!(snapshot.status_counts, [(200, 4000), (500, 4000)].into_iter().collect());
+    assert_eq!((snapshot.bytes_in, snapshot.bytes_out, snapshot.auth_failures), (80000, 800000, 8000));
+    //  0..=5 ms in the first bucket, 6 and 7 ms in the second.
+    assert_eq!(snapshot.latency_buckets[0], LatencyBucket { le_ms: Some(5), count: 6000 });
+    assert_eq!(snapshot.latency_buckets[1], LatencyBucket { le_ms: Some(10), count: 2000 });
+    assert_eq!(snapshot.latency_buckets.iter().map(|b| b.count).sum::<u64>(), 8000);
+    assert_eq!(snapshot.latency_sum_ms, 28000.0);
+}
+
+#[test]
+fn test_metrics_errors() {
+    let metrics = Metrics::new();
+    let mysql_error = mysql::Error::MySqlError(mysql::MySqlError { state: "HY000".to_string(), message: "Gone".to_string(), code: 2006 });
+    metrics.observe_error(&Error::from(mysql_error).context("Inserting"));
+    metrics.observe_error(&anyhow::anyhow!("Bad JSON"));
+    assert_eq!(metrics.snapshot().sql_errors, 1);
+    metrics.observe_connection_error(&anyhow::anyhow!("Bad FCGI record"));
+    assert_eq!((metrics.snapshot().connection_errors, metrics.snapshot().sql_errors), (1, 1));
+    //  Odd status codes are counted as 0.
+    metrics.observe_request(999, 0, 0, Duration::from_secs(60));
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.status_counts, [(0, 1)].into_iter().collect());
+    assert_eq!(snapshot.latency_buckets.last().unwrap(), &LatencyBucket { le_ms: None, count: 1 });
+}
+
+#[test]
+fn test_metrics_prometheus() {
+    let metrics = Metrics::new();
+    metrics.observe_request(200, 100, 2000, Duration::from_millis(3));
+    metrics.observe_request(200, 100, 2000, Duration::from_millis(40));
+    metrics.observe_request(401, 50, 80, Duration::from_millis(1));
+    metrics.observe_auth_failure();
+    metrics.observe_cache(true);
+    metrics.observe_cache(true);
+    metrics.observe_cache(false);
+    let mut snapshot = metrics.snapshot();
+    snapshot.latency_sum_ms = 44.0;
+    let text = snapshot.to_prometheus();
+    let expected = "\
+# HELP maptools_requests_total Requests handled.
+# TYPE maptools_requests_total counter
+maptools_requests_total 3
+# HELP maptools_responses_total Responses by HTTP status.
+# TYPE maptools_responses_total counter
+maptools_responses_total{status=\"200\"} 2
+maptools_responses_total{status=\"401\"} 1
+# HELP maptools_bytes_in_total Request body bytes.
+# TYPE maptools_bytes_in_total counter
+maptools_bytes_in_total 250
+# HELP maptools_bytes_out_total Response bytes.
+# TYPE maptools_bytes_out_total counter
+maptools_bytes_out_total 4080
+# HELP maptools_sql_errors_total Requests failed by database errors.
+# TYPE maptools_sql_errors_total counter
+maptools_sql_errors_total 0
+# HELP maptools_auth_failures_total Requests refused authorization.
+# TYPE maptools_auth_failures_total counter
+maptools_auth_failures_total 1
+# HELP maptools_connection_errors_total Connections ended by errors.
+# TYPE maptools_connection_errors_total counter
+maptools_connection_errors_total 0
+# HELP maptools_cache_hits_total Replies from the response cache.
+# TYPE maptools_cache_hits_total counter
+maptools_cache_hits_total 2
+# HELP maptools_cache_misses_total Cacheable replies not in the response cache.
+# TYPE maptools_cache_misses_total counter
+maptools_cache_misses_total 1
+# HELP maptools_request_duration_seconds Handler time.
+# TYPE maptools_request_duration_seconds histogram
+maptools_request_duration_seconds_bucket{le=\"0.005\"} 2
+maptools_request_duration_seconds_bucket{le=\"0.01\"} 2
+maptools_request_duration_seconds_bucket{le=\"0.025\"} 2
+maptools_request_duration_seconds_bucket{le=\"0.05\"} 3
+maptools_request_duration_seconds_bucket{le=\"0.1\"} 3
+maptools_request_duration_seconds_bucket{le=\"0.25\"} 3
+maptools_request_duration_seconds_bucket{le=\"0.5\"} 3
+maptools_request_duration_seconds_bucket{le=\"1\"} 3
+maptools_request_duration_seconds_bucket{le=\"2.5\"} 3
+maptools_request_duration_seconds_bucket{le=\"5\"} 3
+maptools_request_duration_seconds_bucket{le=\"10\"} 3
+maptools_request_duration_seconds_bucket{le=\"+Inf\"} 3
+maptools_request_duration_seconds_sum 0.044
+maptools_request_duration_seconds_count 3
+";
+    assert_eq!(text, expected);
+}
+
+#[test]
+fn test_phase_timer() {
+    let timer = PhaseTimer::new();
+    std::thread::sleep(Duration::from_millis(20));
+    timer.mark("parse");
+    std::thread::sleep(Duration::from_millis(10));
+    timer.mark("sql");
+    timer.mark("write");
+    std::thread::sleep(Duration::from_millis(10));
+    timer.mark("sql");
+    let elapsed = timer.start.elapsed();
+    let phases = timer.phases();
+    assert_eq!(phases.iter().map(|(name, _)| *name).collect::<Vec<_>>(), vec!["parse", "sql", "write"]);
+    assert!(phases[0].1 >= Duration::from_millis(20));
+    assert!(phases[1].1 >= Duration::from_millis(20));
+    //  Phases add up to the total, which is about the elapsed time.
+    let sum: Duration = phases.iter().map(|(_, d)| *d).sum();
+    assert_eq!(sum, timer.total());
+    assert!(elapsed - sum < Duration::from_millis(50), "{:?} vs {:?}", elapsed, sum);
+}
+
+#[test]
+fn test_request_limits() {
+    let lookup = |pairs: &'static [(&str, &str)]| move |k: &str| pairs.iter().find(|(key, _)| *key == k).map(|(_, v)| v.to_string());
+    let limits = RequestLimits::new_from_lookup(lookup(&[("SLOW_REQUEST_MS", " 1000 "), ("LARGE_REQUEST_BYTES", "100000")])).unwrap();
+    assert_eq!(limits, RequestLimits { body_bytes: Some(100000), duration: Some(Duration::from_millis(1000)) });
+    assert_eq!(RequestLimits::new_from_lookup(lookup(&[])).unwrap(), RequestLimits::default());
+    assert!(RequestLimits::new_from_lookup(lookup(&[("SLOW_REQUEST_MS", "slow")])).is_err());
+    //  Phases at known times.
+    let timer = PhaseTimer::new();
+    timer.mark_at("parse", timer.start + Duration::from_millis(30));
+    timer.mark_at("auth", timer.start + Duration::from_millis(32));
+    timer.mark_at("sql", timer.start + Duration::from_millis(1532));
+    timer.mark_at("write", timer.start + Duration::from_millis(1540));
+    assert_eq!(limits.over_limit_message(5000, 800, &timer).as_deref(),
+        Some("Slow request: body 5000 bytes, params 800 bytes, total 1540 ms: parse 30 ms, auth 2 ms, sql 1500 ms, write 8 ms"));
+    //  Fast but big.
+    let fast = PhaseTimer::new();
+    fast.mark_at("parse", fast.start + Duration::from_millis(5));
+    assert_eq!(limits.over_limit_message(200000, 800, &fast).as_deref(),
+        Some("Large request: body 200000 bytes, params 800 bytes, total 5 ms: parse 5 ms"));
+    //  Under both limits, or no limits.
+    assert_eq!(limits.over_limit_message(5000, 800, &fast), None);
+    assert_eq!(RequestLimits::default().over_limit_message(200000, 800, &timer), None);
+}
+
+#[test]
+fn test_connection_limits() {
+    let lookup = |pairs: &'static [(&str, &str)]| move |k: &str| pairs.iter().find(|(key, _)| *key == k).map(|(_, v)| v.to_string());
+    //  Unset is the defaults, not unlimited.
+    assert_eq!(ConnectionLimits::new_from_lookup(lookup(&[])).unwrap(),
+        ConnectionLimits { read_timeout: Some(Duration::from_secs(30)), max_request_bytes: Some(DEFAULT_MAX_REQUEST_BYTES) });
+    let limits = ConnectionLimits::new_from_lookup(lookup(&[("READ_TIMEOUT_SECS", " 5 "), ("MAX_REQUEST_BYTES", "100000")])).unwrap();
+    assert_eq!(limits, ConnectionLimits { read_timeout: Some(Duration::from_secs(5)), max_request_bytes: Some(100000) });
+    //  Zero turns a limit off.
+    let limits = ConnectionLimits::new_from_lookup(lookup(&[("READ_TIMEOUT_SECS", "0"), ("MAX_REQUEST_BYTES", "0")])).unwrap();
+    assert_eq!(limits, ConnectionLimits { read_timeout: None, max_request_bytes: None });
+    assert!(ConnectionLimits::new_from_lookup(lookup(&[("READ_TIMEOUT_SECS", "forever")])).is_err());
+}
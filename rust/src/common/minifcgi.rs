@@ -11,6 +11,34 @@
 //!        minifcgi::run(|_|{}, handler)
 //!    }
 //!
+//! run() reads requests and writes responses on one thread.
+//! run_duplex() reads records on one thread and writes responses on another,
+//! with the handler in between writing to a ResponseSink. Then the web server
+//! can keep sending us Stdin while we're still sending a big response,
+//! without both pipes filling up.
+//!
+//! serve() accepts connections from the web server one after another, and
+//! runs each with run_duplex. Each connection has a read timeout, so a peer
+//! which stops sending, even part way through a record, is logged and
+//! dropped, and the next connection is served. Requests over the size limit
+//! get a 413 before their content is read in.
+//!
+//! A handler can look at the body as it arrives, one Stdin record at a time,
+//! through on_stdin_chunk. If that fails, the reply goes out at once, from
+//! stdin_rejected, and the rest of the body is read and discarded. The client
+//! gets its error without waiting for a big upload to finish. That only helps
+//! if the web server passes the body along unbuffered.
+//!
+//! on_params is called once the params are in, before any of the body.
+//! A Router uses that to pick a handler by path, so one responder can
+//! serve several endpoints.
+//!
+//! Web servers pass header bytes through as sent, so a param value may not
+//! be UTF-8. That doesn't fail the request. The value is decoded lossily and
+//! its key listed in malformed_params, for handlers that care.
+//!
+//! Records are parsed by FcgiParser, which is fed bytes and does no I/O.
+//! Input cut off part way through a record is an FcgiParseError, not EOF.
 //!
 //! Since this code is intended to support only Apache mod_fcgid, it
 //! does not currently support "multiplexing", where
@@ -48,8 +76,13 @@
 use anyhow::{Error, Result, anyhow};
 use num_derive::{FromPrimitive, ToPrimitive}; // Derive the FromPrimitive trait
 use num_traits::{FromPrimitive, ToPrimitive};
+use std::cell::{Cell, OnceCell};
 use std::collections::HashMap;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::time::Instant;
+use crate::metrics::{ConnectionLimits, Metrics, PhaseTimer};
 /// Trait for callback
 pub trait Handler {
     /// caller must provide handler fn
@@ -59,6 +92,30 @@ pub trait Handler {
         request: &Request,
         env: &HashMap<String, String>,
     ) -> Result<(), Error>;
+
+    /// Called once the params are complete, before any Stdin.
+    /// Lets a handler that passes requests on pick where this one goes. The default does nothing.
+    fn on_params(&mut self, _request: &Request) {}
+
+    /// Called as each Stdin record arrives, before the request is complete.
+    /// The Request buffers the whole body regardless, so the default does nothing.
+    /// An error here rejects the request early, through stdin_rejected.
+    fn on_stdin_chunk(&mut self, _chunk: &[u8]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Reply to a request rejected by on_stdin_chunk. The handler is not called for it.
+    /// Params are complete, but standard_input has only what arrived so far.
+    /// The default is a text reply, with the status from status_for.
+    fn stdin_rejected(&mut self, out: &mut dyn Write, request: &Request, error: &Error) -> Result<(), Error> {
+        let status = crate::status_for(error).into();
+        let msg = format!("Request rejected: {}", error);
+        Response::write_response(out, request, Response::http_response("text/plain", status, &msg).as_slice(), msg.as_bytes())
+    }
+
+    /// Called after each request is answered, before the next is read.
+    /// A place to pick up changed settings, such as rotated secrets. The default does nothing.
+    fn between_requests(&mut self) {}
 }
 
 /// Type of transaction. Only Responder is implemented.
@@ -101,8 +158,34 @@ enum FcgiRecType {
     UnknownType = 11,
 }
 
+/// Input that isn't a well-formed FCGI record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FcgiParseError {
+    /// Record type byte is not one we know.
+    InvalidRecordType(u8),
+    /// Input ended part way through a header.
+    TruncatedHeader { received: usize },
+    /// Input ended before all the content and padding arrived.
+    TruncatedContent { expected: usize, received: usize },
+    /// The request's records add up to more than the limit, bytes.
+    RequestTooLarge { limit: usize },
+}
+
+impl std::fmt::Display for FcgiParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FcgiParseError::InvalidRecordType(rec_type) => write!(f, "Invalid FCGI record type: {}", rec_type),
+            FcgiParseError::TruncatedHeader { received } => write!(f, "Input ended after {} of {} FCGI header bytes", received, FcgiHeader::FCGI_HEADER_LENGTH),
+            FcgiParseError::TruncatedContent { expected, received } => write!(f, "Input ended after {} of {} FCGI content bytes", received, expected),
+            FcgiParseError::RequestTooLarge { limit } => write!(f, "FCGI request is over the limit of {} bytes", limit),
+        }
+    }
+}
+
+impl std::error::Error for FcgiParseError {}
+
 /// FCGI header record, deserialized.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FcgiHeader {
     version: u8,
     /// Record type. Usually BeginRequest.
@@ -125,10 +208,10 @@ impl FcgiHeader {
         let header = FcgiHeader {
             version: b[0],
             rec_type: FcgiRecType::from_u8(b[1])
-                .ok_or_else(|| anyhow!("Invalid FCGI record type: {}", b[1]))?,
+                .ok_or(FcgiParseError::InvalidRecordType(b[1]))?,
             id: u16::from_be_bytes(<[u8; 2]>::try_from(&b[2..4]).unwrap()),
             content_length,
-            padding_length: b[7],
+            padding_length: b[6],
         };
         if header.padding_length != Self::calc_padding_length(content_length) {
             log::error!(
@@ -166,7 +249,7 @@ impl FcgiHeader {
 /// FcgiRecord -- one header and its data.
 ///
 /// Input is a stream of these.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FcgiRecord {
     /// The header
     header: FcgiHeader,
@@ -174,44 +257,165 @@ pub struct FcgiRecord {
     content: Option<Vec<u8>>,
 }
 
-impl FcgiRecord {
-    /// Read one record from stream.
-    /// If Option<Request> is none, EOF has been reached.
-    pub fn new_from_stream(instream: &mut impl BufRead) -> Result<Option<Self>, Error> {
-        // Read header
-        let mut header_bytes: [u8; FcgiHeader::FCGI_HEADER_LENGTH] = Default::default();
-        log::debug!("About to read {} header bytes.", header_bytes.len());
-        match instream.read_exact(&mut header_bytes) {
-            Ok(_) => {} // read expected data
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    return Ok(None); // Normal EOF exit - end of file at correct point
+/// Where the parser is within a record.
+#[derive(Debug)]
+enum ParseState {
+    /// Reading a header. Bytes so far.
+    Header(Vec<u8>),
+    /// Reading content, then skipping padding.
+    Body { header: FcgiHeader, content: Vec<u8>, padding_left: usize },
+}
+
+/// FCGI record parser. Fed bytes as they arrive, in any size pieces.
+/// Does no I/O, so it can parse from anything, and be tested without streams.
+/// With a limit, content is counted from each BeginRequest, and a request over it
+/// is refused at the header of the record that would go over, before its content is buffered.
+#[derive(Debug)]
+pub struct FcgiParser {
+    /// Part of a record, waiting for more input.
+    state: ParseState,
+    /// Most content bytes in one request, if limited.
+    max_request_bytes: Option<usize>,
+    /// Content bytes in the current request.
+    request_bytes: usize,
+}
+
+impl Default for FcgiParser {
+    fn default() -> Self {
+        Self { state: ParseState::Header(Vec::with_capacity(FcgiHeader::FCGI_HEADER_LENGTH)), max_request_bytes: None, request_bytes: 0 }
+    }
+}
+
+impl FcgiParser {
+    /// Usual new. No limit on request size.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A parser which refuses requests over this many content bytes.
+    pub fn new_with_limit(max_request_bytes: Option<usize>) -> Self {
+        Self { max_request_bytes, ..Self::default() }
+    }
+
+    /// Count a record's content against the request limit.
+    fn count_request_bytes(&mut self, header: &FcgiHeader) -> Result<(), FcgiParseError> {
+        if header.rec_type == FcgiRecType::BeginRequest {
+            self.request_bytes = 0;
+        }
+        self.request_bytes += header.content_length as usize;
+        match self.max_request_bytes {
+            Some(limit) if self.request_bytes > limit => Err(FcgiParseError::RequestTooLarge { limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the next record from a stream. None at EOF.
+    /// Only that record's bytes are taken from the stream.
+    pub fn read_record(&mut self, instream: &mut impl BufRead) -> Result<Option<FcgiRecord>, Error> {
+        loop {
+            let available = match instream.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if available.is_empty() {
+                //  Normal EOF exit, if at the end of a record.
+                self.finish()?;
+                return Ok(None);
+            }
+            let (used, record) = self.push_one(available)?;
+            instream.consume(used);
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+    }
+
+    /// Feed bytes. Returns the records they complete, in order.
+    /// Anything left over is kept for the next push.
+    pub fn push(&mut self, mut input: &[u8]) -> Result<Vec<FcgiRecord>, Error> {
+        let mut records = Vec::new();
+        loop {
+            let (used, record) = self.push_one(input)?;
+            input = &input[used..];
+            match record {
+                Some(record) => records.push(record),
+                None => return Ok(records),
+            }
+        }
+    }
+
+    /// Feed bytes, stopping at the end of the first record completed.
+    /// Returns the bytes used, and that record, if any. All of input is used if no record completes.
+    pub fn push_one(&mut self, input: &[u8]) -> Result<(usize, Option<FcgiRecord>), Error> {
+        let mut used = 0;
+        loop {
+            if let Some(record) = self.take_complete() {
+                return Ok((used, Some(record)));
+            }
+            if used == input.len() {
+                return Ok((used, None));
+            }
+            let rest = &input[used..];
+            match &mut self.state {
+                ParseState::Header(bytes) => {
+                    let take = (FcgiHeader::FCGI_HEADER_LENGTH - bytes.len()).min(rest.len());
+                    bytes.extend_from_slice(&rest[..take]);
+                    used += take;
+                    if bytes.len() == FcgiHeader::FCGI_HEADER_LENGTH {
+                        let header = FcgiHeader::new_from_bytes(&<[u8; FcgiHeader::FCGI_HEADER_LENGTH]>::try_from(bytes.as_slice()).unwrap())?;
+                        log::debug!("header: {:?}", header);
+                        self.count_request_bytes(&header)?;
+                        //  Padding is only read after content, as mod_fcgid sends it.
+                        let padding_left = if header.content_length > 0 { header.padding_length as usize } else { 0 };
+                        self.state = ParseState::Body { content: Vec::with_capacity(header.content_length as usize), header, padding_left };
+                    }
+                }
+                ParseState::Body { header, content, padding_left } => {
+                    let take = (header.content_length as usize - content.len()).min(rest.len());
+                    content.extend_from_slice(&rest[..take]);
+                    let skip = if content.len() == header.content_length as usize { (*padding_left).min(rest.len() - take) } else { 0 };
+                    *padding_left -= skip;
+                    used += take + skip;
                 }
-                return Err(e.into());
             }
         }
-        let header = FcgiHeader::new_from_bytes(&header_bytes)?;
-        log::debug!("header: {:?}", header);
-        // Read content
-        let mut content_bytes = vec![0; header.content_length as usize];
-        if header.content_length > 0 {
-            log::debug!("About to read {} content bytes", content_bytes.len());
-            instream.read_exact(&mut content_bytes)?;
-            log::debug!(
-                "Content: {:?}",
-                String::from_utf8_lossy(&content_bytes[0..content_bytes.len().min(200)].to_vec())
-            );
-            let padding_length = header.padding_length;
-            if padding_length > 0 {
-                let mut padding_bytes = vec![0; padding_length as usize];
-                log::debug!("About to read {} padding bytes", padding_bytes.len());
-                instream.read_exact(&mut padding_bytes)?;
+    }
+
+    /// The record just finished, if any. Then a new header is next.
+    fn take_complete(&mut self) -> Option<FcgiRecord> {
+        match &self.state {
+            ParseState::Body { header, content, padding_left } if content.len() == header.content_length as usize && *padding_left == 0 => {}
+            _ => return None,
+        }
+        match std::mem::take(self).state {
+            ParseState::Body { header, content, .. } => {
+                log::debug!("Content: {:?}", String::from_utf8_lossy(&content[0..content.len().min(200)]));
+                Some(FcgiRecord { header, content: Some(content) })
+            }
+            ParseState::Header(_) => None,
+        }
+    }
+
+    /// Input has ended. An error if that was part way through a record.
+    pub fn finish(&self) -> Result<(), FcgiParseError> {
+        match &self.state {
+            ParseState::Header(bytes) if bytes.is_empty() => Ok(()),
+            ParseState::Header(bytes) => Err(FcgiParseError::TruncatedHeader { received: bytes.len() }),
+            ParseState::Body { header, content, padding_left } => {
+                let expected = header.content_length as usize + if header.content_length > 0 { header.padding_length as usize } else { 0 };
+                Err(FcgiParseError::TruncatedContent { expected, received: expected - (header.content_length as usize - content.len()) - padding_left })
             }
         }
-        Ok(Some(Self {
-            header,
-            content: Some(content_bytes.to_vec()),
-        }))
+    }
+}
+
+impl FcgiRecord {
+    /// Read one record from stream.
+    /// If Option<Request> is none, EOF has been reached.
+    /// EOF part way through a record is an FcgiParseError.
+    pub fn new_from_stream(instream: &mut impl BufRead) -> Result<Option<Self>, Error> {
+        FcgiParser::new().read_record(instream)
     }
 
     /// Take content for use elsewhere
@@ -229,8 +433,18 @@ pub struct Request {
     param_bytes: Vec<u8>,
     /// Params, as a key-value store
     pub params: Option<HashMap<String, String>>,
+    /// Keys of params whose values weren't valid UTF-8, and were decoded lossily.
+    malformed_params: Vec<String>,
     /// Standard input - the actual content, if any. Usually from a POST request.
     pub standard_input: Vec<u8>,
+    /// HTTP headers, built from params on first use.
+    headers: OnceCell<HashMap<String, String>>,
+    /// HTTP status of the response sent, if any.
+    response_status: Cell<Option<usize>>,
+    /// Bytes of response sent, as FCGI records.
+    response_bytes: Cell<usize>,
+    /// Time in each phase of handling
+    phase_timer: PhaseTimer,
 }
 
 impl Request {
@@ -241,9 +455,93 @@ impl Request {
             param_bytes: Vec::new(),
             standard_input: Vec::new(),
             params: None,
+            malformed_params: Vec::new(),
+            headers: OnceCell::new(),
+            response_status: Cell::new(None),
+            response_bytes: Cell::new(0),
+            phase_timer: PhaseTimer::new(),
         }
     }
 
+    /// A complete request with these params and no content.
+    /// For testing handlers without FCGI.
+    pub fn new_with_params(id: u16, params: HashMap<String, String>) -> Request {
+        Self {
+            id: Some(id),
+            params: Some(params),
+            ..Self::new()
+        }
+    }
+
+    /// Keys of params whose values weren't valid UTF-8.
+    /// Those values have U+FFFD in place of the bad bytes. Handlers which use them can reject the request.
+    pub fn malformed_params(&self) -> &[String] {
+        &self.malformed_params
+    }
+
+    /// HTTP status of the response sent, if any.
+    pub fn response_status(&self) -> Option<usize> {
+        self.response_status.get()
+    }
+
+    /// Bytes of response sent, as FCGI records.
+    pub fn response_bytes(&self) -> usize {
+        self.response_bytes.get()
+    }
+
+    /// Handlers mark the end of each phase here, for slow request logging.
+    pub fn phase_timer(&self) -> &PhaseTimer {
+        &self.phase_timer
+    }
+
+    /// HTTP request headers, by header name.
+    /// FCGI delivers headers as params, HTTP_X_SECONDLIFE_OWNER_NAME for X-Secondlife-Owner-Name.
+    /// Built once, after the params are complete.
+    pub fn headers(&self) -> &HashMap<String, String> {
+        self.headers.get_or_init(|| {
+            self.params.iter()
+                .flatten()
+                .filter_map(|(k, v)| header_name(k).map(|name| (name, v.clone())))
+                .collect()
+        })
+    }
+
+    /// One HTTP request header. The name is not case sensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let headers = self.headers();
+        headers.get(name)
+            .or_else(|| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v))
+            .map(|v| v.as_str())
+    }
+
+    /// Check that the body is JSON, before trying to parse it.
+    /// CONTENT_TYPE must be application/json, or text/plain, which LSL sometimes sends,
+    /// with charset utf-8 if there's a charset. Otherwise a 415 error.
+    /// A CONTENT_LENGTH that doesn't match what arrived is only logged. That's a truncated POST.
+    pub fn check_json_content(&self) -> Result<(), crate::Error> {
+        let param = |name: &str| self.params.as_ref().and_then(|params| params.get(name)).map(|v| v.trim());
+        let content_type = param("CONTENT_TYPE").unwrap_or("");
+        let mut parts = content_type.split(';').map(|part| part.trim());
+        let media_type = parts.next().unwrap_or("").to_lowercase();
+        if media_type != "application/json" && media_type != "text/plain" {
+            return Err(crate::Error::UnsupportedMediaType(format!("Received \"{}\", expected \"application/json\"", content_type)));
+        }
+        for part in parts {
+            if let Some((name, value)) = part.split_once('=') {
+                let value = value.trim().trim_matches('"').to_lowercase();
+                if name.trim().eq_ignore_ascii_case("charset") && value != "utf-8" && value != "utf8" {
+                    return Err(crate::Error::UnsupportedMediaType(format!("Received \"{}\", expected charset \"utf-8\"", content_type)));
+                }
+            }
+        }
+        if let Some(content_length) = param("CONTENT_LENGTH") {
+            if content_length.parse::<usize>().ok() != Some(self.standard_input.len()) {
+                log::warn!("CONTENT_LENGTH is {} but {} bytes arrived. Truncated POST?", content_length, self.standard_input.len());
+            }
+        }
+        Ok(())
+    }
+
     /// True if ready to execute request.
     pub fn add_record(&mut self, mut rec: FcgiRecord) -> Result<bool, Error> {
         //  Check that we're not in multiplex mode
@@ -263,6 +561,12 @@ impl Request {
             }
 
             FcgiRecType::Params => {
+                //  A zero-length block means the params are complete.
+                //  Built now, so they're there while Stdin arrives.
+                if rec.header.content_length == 0 {
+                    self.finish_params()?;
+                    return Ok(false);
+                }
                 // More param bytes
                 let content = rec
                     .content
@@ -274,7 +578,9 @@ impl Request {
             FcgiRecType::Stdin => {
                 //  A zero-length block means we have a complete request .
                 if rec.header.content_length == 0 {
-                    self.params = Some(Self::build_params(&self.param_bytes)?);
+                    if self.params.is_none() {
+                        self.finish_params()?;
+                    }
                     log::debug!("Params: {:?}", self.params);
                     //  Request now gets processed.
                     return Ok(true);
@@ -341,8 +647,9 @@ impl Request {
         }
     }
 
-    /// Fetch FCGI param field of requested length. Read N bytes, convert to UTF-8. Error if bad UTF-8.
-    fn fetch_field<'a>(cnt: usize, mut pos: impl Iterator<Item = &'a u8>) -> Result<String, Error> {
+    /// Fetch FCGI param field of requested length. Read N bytes, as sent.
+    /// Web servers pass header bytes through unchecked, so they may not be UTF-8.
+    fn fetch_field<'a>(cnt: usize, mut pos: impl Iterator<Item = &'a u8>) -> Result<Vec<u8>, Error> {
         let mut b = Vec::with_capacity(cnt);
         for _ in 0..cnt {
             let ch = pos
@@ -350,14 +657,14 @@ impl Request {
                 .ok_or_else(|| anyhow!("FCGI responder: EOF reading param field"))?;
             b.push(*ch);
         }
-        Ok(String::from_utf8(b)?.to_string())
+        Ok(b)
     }
 
     /// "FastCGI transmits a name-value pair as the length of the name, followed by the length of the value, followed by the name, followed by the value.
     /// Lengths of 127 bytes and less can be encoded in one byte, while longer lengths are always encoded in four bytes" - FCGI spec
     fn fetch_name_value_pair<'a>(
         mut pos: impl Iterator<Item = &'a u8>,
-    ) -> Result<Option<(String, String)>, Error> {
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
         if let Some(kcnt) = Self::fetch_field_length(&mut pos)? {
             if let Some(vcnt) = Self::fetch_field_length(&mut pos)? {
                 Ok(Some((
@@ -376,18 +683,69 @@ impl Request {
 
     /// Build key-value list from special format.
     pub fn build_params(b: &[u8]) -> Result<HashMap<String, String>, Error> {
+        Ok(Self::build_params_flagged(b)?.0)
+    }
+
+    /// Build key-value list from special format, and list the keys whose values weren't UTF-8.
+    /// Those values are decoded lossily. A key that isn't UTF-8 can't be looked up, so its pair is dropped.
+    /// Only a malformed encoding is an error.
+    pub fn build_params_flagged(b: &[u8]) -> Result<(HashMap<String, String>, Vec<String>), Error> {
         log::debug!(
             "Param bytes: {:?}",
             String::from_utf8_lossy(&b[0..b.len().min(2000)].to_vec())
         );
         let mut m = HashMap::new();
+        let mut malformed = Vec::new();
         let mut pos = b.iter();
         while let Some((k, v)) = Self::fetch_name_value_pair(&mut pos)? {
+            let k = match String::from_utf8(k) {
+                Ok(k) => k,
+                Err(e) => {
+                    log::warn!("Param name {:?} is not UTF-8, dropped.", String::from_utf8_lossy(e.as_bytes()));
+                    continue;
+                }
+            };
+            let v = match String::from_utf8(v) {
+                Ok(v) => v,
+                Err(e) => {
+                    let v = String::from_utf8_lossy(e.as_bytes()).to_string();
+                    log::warn!("Param \"{}\" is not UTF-8: \"{}\"", k, v);
+                    malformed.push(k.clone());
+                    v
+                }
+            };
             log::debug!("Param: \"{}\" = \"{}\"", k, v);
             m.insert(k, v);
         }
-        Ok(m)
+        malformed.sort();
+        malformed.dedup();
+        Ok((m, malformed))
+    }
+
+    /// Params are complete. Decode them.
+    fn finish_params(&mut self) -> Result<(), Error> {
+        let (params, malformed) = Self::build_params_flagged(&self.param_bytes)?;
+        self.params = Some(params);
+        self.malformed_params = malformed;
+        Ok(())
+    }
+}
+
+/// HTTP header name for an FCGI param, if it is a header.
+/// HTTP_X_SECONDLIFE_OWNER_NAME becomes X-Secondlife-Owner-Name.
+pub fn header_name(param: &str) -> Option<String> {
+    const PREFIX: &str = "HTTP_";
+    if param.len() <= PREFIX.len() || !param[..PREFIX.len()].eq_ignore_ascii_case(PREFIX) {
+        return None;
     }
+    let name = param[PREFIX.len()..].split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            chars.next().map(|c| c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect::<String>()).unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    Some(name)
 }
 
 /// Response -- sends back a response to a request.
@@ -398,13 +756,14 @@ impl Response {
     /// mod_fcgid isn't padding its messages to us.
     const PAD_RESPONSES: bool = false;
 
-    /// Write one response record.
+    /// Write one response record. Returns the bytes written, header and padding included.
+    /// All of it is written, however short the writes, or the FCGI stream would lose sync.
     fn write_response_record(
         out: &mut dyn Write,
         request: &Request,
         rec_type: FcgiRecType,
         b: &[u8],
-    ) -> Result<(), Error> {
+    ) -> Result<usize, Error> {
         assert!(b.len() < u16::MAX.into());
         let padding_length = if Self::PAD_RESPONSES {
             //  Rounds up to 8 bytes
@@ -425,17 +784,18 @@ impl Response {
             String::from_utf8_lossy(&b[0..b.len().min(200)].to_vec())
         );
         //  Write header
-        out.write(&header.to_bytes())?;
+        let header_bytes = header.to_bytes();
+        out.write_all(&header_bytes)?;
         //  Write data
         if b.len() > 0 {
-            out.write(b)?;
+            out.write_all(b)?;
         }
         //  Write padding
         if header.padding_length > 0 {
             let padding_bytes = vec![0; header.padding_length as usize];
-            out.write(&padding_bytes)?;
+            out.write_all(&padding_bytes)?;
         }
-        Ok(())
+        Ok(header_bytes.len() + b.len() + header.padding_length as usize)
     }
 
     /// Write entire response.
@@ -448,38 +808,39 @@ impl Response {
         header_fields: &[String],
         b: &[u8],
     ) -> Result<(), Error> {
+        //  Whatever the handler didn't mark.
+        request.phase_timer.mark("other");
         //  Send header fields
         let header_fields_group = header_fields.join("\r\n") + "\n\n";
         log::info!("Response header: {}", header_fields_group);
-        Self::write_response_record(
-            out,
-            request,
-            FcgiRecType::Stdout,
-            &header_fields_group.as_bytes(),
-        )?;
+        //  Remember what was sent, for metrics.
+        let status = header_fields.iter()
+            .find_map(|field| field.strip_prefix("Status: "))
+            .and_then(|field| field.split_whitespace().next())
+            .and_then(|code| code.parse::<usize>().ok());
+        request.response_status.set(status.or(Some(200)));
+        //  Bytes as sent, FCGI records included. Counted as we go, so a failed write still counts what got out.
+        let mut write_record = |rec_type: FcgiRecType, b: &[u8]| -> Result<(), Error> {
+            let written = Self::write_response_record(out, request, rec_type, b)?;
+            request.response_bytes.set(request.response_bytes.get() + written);
+            Ok(())
+        };
+        write_record(FcgiRecType::Stdout, header_fields_group.as_bytes())?;
         //  End of HTTP header record.
-        Self::write_response_record(out, request, FcgiRecType::Stdout, "".as_bytes())?;
+        write_record(FcgiRecType::Stdout, "".as_bytes())?;
         //  Only send this much data at once to avoid clogging pipe.
         //  The connection to the parent process is two pipes in opposite directions and deadlock is possible.
+        //  run_duplex avoids that by reading on a separate thread.
         const CHUNK_SIZE: usize = 2048;
         for i in (0..b.len()).step_by(CHUNK_SIZE) {
-            Self::write_response_record(
-                out,
-                request,
-                FcgiRecType::Stdout,
-                &b[i..(i + CHUNK_SIZE).min(b.len())],
-            )?;
+            write_record(FcgiRecType::Stdout, &b[i..(i + CHUNK_SIZE).min(b.len())])?;
         }
         //  End of data record.
-        Self::write_response_record(out, request, FcgiRecType::Stdout, &[])?;
+        write_record(FcgiRecType::Stdout, &[])?;
         // End of transaction record.
-        Self::write_response_record(
-            out,
-            request,
-            FcgiRecType::EndRequest,
-            &[0, FcgiStatus::RequestComplete.to_u8().unwrap()],
-        )?;
+        write_record(FcgiRecType::EndRequest, &[0, FcgiStatus::RequestComplete.to_u8().unwrap()])?;
         out.flush()?;
+        request.phase_timer.mark("write");
         Ok(())
     }
 
@@ -492,27 +853,120 @@ impl Response {
     }
 }
 
+/// What the handler sends to the writer thread.
+enum SinkMessage {
+    Data(Vec<u8>),
+    Flush,
+}
+
+/// Response output for run_duplex. The handler writes here, and a writer thread
+/// does the actual writing, so a slow reader of our output doesn't stop us reading input.
+pub struct ResponseSink {
+    sender: mpsc::SyncSender<SinkMessage>,
+}
+
+impl ResponseSink {
+    /// Messages queued before the handler has to wait for the writer thread.
+    const QUEUE_LENGTH: usize = 64;
+
+    /// A sink, and the writer thread that empties it into out.
+    /// The thread finishes when the sink is dropped.
+    fn new_with_writer<W: Write + Send + 'static>(mut out: W) -> (Self, std::thread::JoinHandle<Result<(), Error>>) {
+        let (sender, receiver) = mpsc::sync_channel(Self::QUEUE_LENGTH);
+        let writer = std::thread::spawn(move || -> Result<(), Error> {
+            for msg in receiver {
+                match msg {
+                    SinkMessage::Data(b) => out.write_all(&b)?,
+                    SinkMessage::Flush => out.flush()?,
+                }
+            }
+            out.flush()?;
+            Ok(())
+        });
+        (Self { sender }, writer)
+    }
+
+    /// The writer thread has quit, which only happens on a write error.
+    fn writer_gone() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::BrokenPipe, "FCGI writer thread has stopped")
+    }
+}
+
+impl Write for ResponseSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender.send(SinkMessage::Data(buf.to_vec())).map_err(|_| Self::writer_gone())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sender.send(SinkMessage::Flush).map_err(|_| Self::writer_gone())
+    }
+}
+
 /// Read and run one transaction.
+/// Each Stdin record goes to the handler's on_stdin_chunk as it arrives.
+/// If that rejects the request, the reply goes out immediately, and the rest of
+/// the request's records are read and dropped.
 /// Errors here result in a 500 error with a message.
 fn run_one<T: Handler>(
-    instream: &mut impl BufRead,
+    next_record: &mut impl FnMut() -> Result<Option<FcgiRecord>, Error>,
     out: &mut dyn Write,
     request: &mut Request,
     handler: &mut T,
     env: &HashMap<String, String>,
+    metrics: &Metrics,
 ) -> Result<bool, Error> {
+    let start = Instant::now();
+    //  Body bytes seen, including any dropped after a rejection.
+    let mut stdin_bytes = 0;
+    let mut rejected = false;
+    let mut params_seen = request.params.is_some();
     loop {
-        if let Some(rec) = FcgiRecord::new_from_stream(instream)? {
-            if !request.add_record(rec)? {
-                continue;
+        let Some(rec) = next_record()? else {
+            return Ok(true); // normal EOF
+        };
+        let before = request.standard_input.len();
+        let complete = request.add_record(rec)?;
+        stdin_bytes += request.standard_input.len() - before;
+        if !params_seen && request.params.is_some() {
+            params_seen = true;
+            handler.on_params(request);
+        }
+        if rejected {
+            //  Already replied. Just read to the end of the request.
+            request.standard_input.clear();
+        } else if request.standard_input.len() > before {
+            if let Err(e) = handler.on_stdin_chunk(&request.standard_input[before..]) {
+                log::warn!("Request rejected after {} bytes: {:?}", stdin_bytes, e);
+                rejected = true;
+                request.phase_timer = PhaseTimer::new();
+                let result = handler.stdin_rejected(out, request, &e);
+                metrics.observe_error(&e);
+                result?;
             }
-            // We have enough records to handle the request.
-            handler.handler(out, &request, &env)?;
+        }
+        if complete {
             break;
-        } else {
-            return Ok(true); // normal EOF
         }
     }
+    if rejected {
+        metrics.observe_request(request.response_status().unwrap_or(500), stdin_bytes, request.response_bytes(), start.elapsed());
+        return Ok(false);
+    }
+    // We have enough records to handle the request.
+    request.response_status.set(None);
+    request.response_bytes.set(0);
+    request.phase_timer = PhaseTimer::new();
+    let start = Instant::now();
+    let result = handler.handler(out, &request, &env);
+    //  A handler error becomes a 500 reply.
+    let status = if result.is_ok() { request.response_status().unwrap_or(500) } else { 500 };
+    metrics.observe_request(status, request.standard_input.len(), request.response_bytes(), start.elapsed());
+    metrics.check_limits(request.standard_input.len(), request.param_bytes.len(), &request.phase_timer);
+    if let Err(e) = &result {
+        metrics.observe_error(e);
+    }
+    result?;
     Ok(false)
 }
 
@@ -521,16 +975,121 @@ pub fn run<T: Handler>(
     instream: &mut impl BufRead,
     out: &mut dyn Write,
     handler: &mut T,
+) -> Result<(), Error> {
+    run_with_metrics(instream, out, handler, &Metrics::new())
+}
+
+/// The main loop, counting each request in metrics.
+/// Requests over the default size limit are refused.
+pub fn run_with_metrics<T: Handler>(
+    instream: &mut impl BufRead,
+    out: &mut dyn Write,
+    handler: &mut T,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    let mut parser = FcgiParser::new_with_limit(ConnectionLimits::default().max_request_bytes);
+    run_loop(&mut || parser.read_record(instream), out, handler, metrics)
+}
+
+/// The main loop, with records read on one thread and responses written on another.
+/// The handler runs on the calling thread. Requests over the default size limit are refused.
+pub fn run_duplex<T: Handler, R: BufRead + Send + 'static, W: Write + Send + 'static>(
+    instream: R,
+    out: W,
+    handler: &mut T,
+    metrics: &Metrics,
+) -> Result<(), Error> {
+    run_duplex_with_limits(instream, out, handler, metrics, &ConnectionLimits::default())
+}
+
+/// Serve connections from the web server, one after another, until accept fails.
+/// Each connection gets the read timeout before anything is read, so a peer which
+/// stops sending part way through a record is dropped, and the next connection served.
+/// A connection which fails, with a bad record or a write to a peer that went away,
+/// is logged and counted, and the next connection served.
+pub fn serve<T: Handler>(
+    listener: &UnixListener,
+    handler: &mut T,
+    metrics: &Metrics,
+    limits: &ConnectionLimits,
+) -> Result<(), Error> {
+    loop {
+        let (socket, _addr) = listener.accept()?;
+        match serve_connection(socket, handler, metrics, limits) {
+            Ok(()) => log::info!("FCGI connection closed. Waiting for the next one."),
+            Err(e) => {
+                log::error!("FCGI connection failed. Waiting for the next one: {:?}", e);
+                metrics.observe_connection_error(&e);
+            }
+        }
+    }
+}
+
+/// One connection from the web server, until EOF or an error.
+fn serve_connection<T: Handler>(socket: UnixStream, handler: &mut T, metrics: &Metrics, limits: &ConnectionLimits) -> Result<(), Error> {
+    socket.set_read_timeout(limits.read_timeout)?;
+    let control = socket.try_clone()?;
+    let outsocket = socket.try_clone()?;
+    let result = run_duplex_with_limits(BufReader::new(socket), BufWriter::new(outsocket), handler, metrics, limits);
+    //  Closes both directions, which also ends the reader thread if it's still waiting.
+    let _ = control.shutdown(std::net::Shutdown::Both);
+    result
+}
+
+/// run_duplex, with these limits.
+fn run_duplex_with_limits<T: Handler, R: BufRead + Send + 'static, W: Write + Send + 'static>(
+    mut instream: R,
+    out: W,
+    handler: &mut T,
+    metrics: &Metrics,
+    limits: &ConnectionLimits,
+) -> Result<(), Error> {
+    let (record_sender, records) = mpsc::channel();
+    let mut parser = FcgiParser::new_with_limit(limits.max_request_bytes);
+    //  Reader thread. Never joined. If we quit early it's blocked on a read, and it ends with the
+    //  read timeout, the connection's shutdown, or the process.
+    std::thread::spawn(move || loop {
+        let rec = parser.read_record(&mut instream);
+        let done = !matches!(rec, Ok(Some(_)));
+        if record_sender.send(rec).is_err() || done {
+            break;
+        }
+    });
+    let (mut sink, writer) = ResponseSink::new_with_writer(out);
+    let mut next_record = || match records.recv() {
+        Ok(rec) => rec,
+        Err(_) => Err(anyhow!("FCGI reader thread stopped")),
+    };
+    let result = run_loop(&mut next_record, &mut sink, handler, metrics);
+    //  Let the writer finish sending what's queued.
+    drop(sink);
+    let written = writer.join().map_err(|_| anyhow!("FCGI writer thread panicked"))?;
+    result.and(written)
+}
+
+/// Run transactions until EOF, getting records from next_record.
+fn run_loop<T: Handler>(
+    next_record: &mut impl FnMut() -> Result<Option<FcgiRecord>, Error>,
+    out: &mut dyn Write,
+    handler: &mut T,
+    metrics: &Metrics,
 ) -> Result<(), Error> {
     let env = std::env::vars().map(|(k, v)| (k, v)).collect();
-    let mut request = Request::new();
     loop {
-        match run_one(instream, out, &mut request, handler, &env) {
+        //  Each transaction starts fresh, so pipelined requests don't pile up Stdin.
+        let mut request = Request::new();
+        match run_one(next_record, out, &mut request, handler, &env, metrics) {
             Ok(done) => {
                 if done {
                     //  Normal end of this task.
                     break;
                 }
+                handler.between_requests();
+            }
+            Err(e) if is_timeout(&e) => {
+                //  Peer stopped sending. Nobody to reply to, so just close.
+                log::warn!("FCGI connection closed, no input within the read timeout: {}", e);
+                break;
             }
             Err(e) => {
                 //  Error occured. Try to get it back to the caller.
@@ -538,12 +1097,16 @@ pub fn run<T: Handler>(
                 log::error!("{}", msg);
                 if request.id.is_some() {
                     //  We have enough info to reply with an error
-                    let error_response = Response::http_response("text", 500, msg.as_str());
+                    let status = match e.downcast_ref::<FcgiParseError>() {
+                        Some(FcgiParseError::RequestTooLarge { .. }) => 413,
+                        _ => 500,
+                    };
+                    let error_response = Response::http_response("text", status, msg.as_str());
                     Response::write_response(out, &request, error_response.as_slice(), &[])?;
                     break;
                 } else {
-                    //  Failed so early we can't reply with an error.
-                    panic!("FCGI responder failed before first record parsed: {}", msg);
+                    //  Failed so early we can't reply with an error. Only the caller can decide what to do.
+                    return Err(e.context("FCGI responder failed before first record parsed"));
                 }
             }
         }
@@ -551,6 +1114,13 @@ pub fn run<T: Handler>(
     Ok(())
 }
 
+/// A read timed out. Sockets report that as WouldBlock or TimedOut, depending on the platform.
+fn is_timeout(e: &Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+}
+
 #[test]
 fn basic_io() {
     use std::io::{BufReader, Write};
@@ -625,3 +1195,532 @@ fn basic_io() {
     let mut test_handler = TestHandler::new();
     run(&mut instream, &mut out, &mut test_handler).expect("Run failed");
 }
+
+#[test]
+fn test_headers() {
+    assert_eq!(header_name("HTTP_X_SECONDLIFE_OWNER_NAME").as_deref(), Some("X-Secondlife-Owner-Name"));
+    assert_eq!(header_name("HTTP_HOST").as_deref(), Some("Host"));
+    //  Lower case, and repeated underscores.
+    assert_eq!(header_name("http_x_authtoken_hash").as_deref(), Some("X-Authtoken-Hash"));
+    assert_eq!(header_name("HTTP_IF__NONE_MATCH_").as_deref(), Some("If--None-Match-"));
+    //  Not headers.
+    assert_eq!(header_name("QUERY_STRING"), None);
+    assert_eq!(header_name("HTTP_"), None);
+    assert_eq!(header_name("HTTPS"), None);
+    let mut request = Request::new();
+    request.params = Some([("HTTP_X_SECONDLIFE_SHARD", "Production"), ("HTTP_IF_NONE_MATCH", "\"abc\""), ("REQUEST_METHOD", "GET")]
+        .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+    assert_eq!(request.headers().len(), 2);
+    assert_eq!(request.header("X-Secondlife-Shard"), Some("Production"));
+    assert_eq!(request.header("X-SecondLife-Shard"), Some("Production"));
+    assert_eq!(request.header("if-none-match"), Some("\"abc\""));
+    assert_eq!(request.header("Request-Method"), None);
+}
+
+#[test]
+fn test_header_padding_byte() {
+    //  Padding length is byte 6. Byte 7 is reserved, and ignored.
+    let b = [1, FcgiRecType::Stdout.to_u8().unwrap(), 0x12, 0x34, 0, 3, 5, 0xff];
+    let header = FcgiHeader::new_from_bytes(&b).expect("header");
+    assert_eq!(header, FcgiHeader { version: 1, rec_type: FcgiRecType::Stdout, id: 0x1234, content_length: 3, padding_length: 5 });
+    //  Written back, the reserved byte is zero.
+    assert_eq!(header.to_bytes(), [1, FcgiRecType::Stdout.to_u8().unwrap(), 0x12, 0x34, 0, 3, 5, 0]);
+    assert_eq!(FcgiHeader::new_from_bytes(&header.to_bytes()).expect("round trip"), header);
+}
+
+#[test]
+fn test_padding_mismatch() {
+    //  mod_fcgid doesn't pad. That's logged, but the header is still accepted.
+    let capture = crate::test_logger_capture();
+    let unpadded = [1, FcgiRecType::Stdin.to_u8().unwrap(), 0, 1, 0, 3, 0, 0];
+    let header = FcgiHeader::new_from_bytes(&unpadded).expect("unpadded header");
+    assert_eq!(header.content_length, 3);
+    assert!(capture.contains(log::Level::Error, "Received padding length 0, calculated padding length 5"), "{:?}", capture.records());
+    //  Padded correctly, nothing to complain about.
+    capture.clear();
+    let padded = [1, FcgiRecType::Stdin.to_u8().unwrap(), 0, 1, 0, 3, 5, 0];
+    FcgiHeader::new_from_bytes(&padded).expect("padded header");
+    assert!(!capture.records().iter().any(|(level, _)| *level == log::Level::Error), "{:?}", capture.records());
+}
+
+#[test]
+fn test_parser_any_split() {
+    //  A request as mod_fcgid sends it, then a padded record, as other web servers send.
+    let padded_header = FcgiHeader { version: 1, rec_type: FcgiRecType::Stdin, id: 2, content_length: 3, padding_length: 5 };
+    let padded = [padded_header.to_bytes().as_slice(), &b"abc"[..], &[0u8; 5][..]].concat();
+    let transaction = [test_request_bytes(1, &[("SCRIPT_NAME", "/status.fcgi"), ("CONTENT_LENGTH", "11")], b"hello world"), padded].concat();
+    let expected = FcgiParser::new().push(&transaction).expect("whole transaction");
+    let types: Vec<FcgiRecType> = expected.iter().map(|rec| rec.header.rec_type.clone()).collect();
+    assert_eq!(types, vec![FcgiRecType::BeginRequest, FcgiRecType::Params, FcgiRecType::Params, FcgiRecType::Stdin, FcgiRecType::Stdin,
+        FcgiRecType::Stdin]);
+    assert_eq!(expected[3].content.as_deref(), Some(&b"hello world"[..]));
+    assert_eq!(expected[5].content.as_deref(), Some(&b"abc"[..]));
+    //  The stream reader gets the same, however little its buffer holds.
+    for capacity in 1..=9 {
+        let mut stream = std::io::BufReader::with_capacity(capacity, transaction.as_slice());
+        let mut records = Vec::new();
+        while let Some(rec) = FcgiRecord::new_from_stream(&mut stream).expect("stream") {
+            records.push(rec);
+        }
+        assert_eq!(records, expected, "buffer capacity {}", capacity);
+    }
+    //  Split in two at every point.
+    for split in 0..=transaction.len() {
+        let mut parser = FcgiParser::new();
+        let mut records = parser.push(&transaction[..split]).unwrap();
+        records.extend(parser.push(&transaction[split..]).unwrap());
+        assert_eq!(parser.finish(), Ok(()));
+        assert_eq!(records, expected, "split at {}", split);
+    }
+    //  One byte at a time.
+    let mut parser = FcgiParser::new();
+    let records: Vec<FcgiRecord> = transaction.iter().flat_map(|b| parser.push(std::slice::from_ref(b)).unwrap()).collect();
+    assert_eq!(records, expected);
+    //  Random sizes, empty pieces included. Xorshift, fixed seed, so failures repeat.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..200 {
+        let mut parser = FcgiParser::new();
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while pos < transaction.len() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let end = (pos + (state % 24) as usize).min(transaction.len());
+            records.extend(parser.push(&transaction[pos..end]).unwrap());
+            pos = end;
+        }
+        assert_eq!(parser.finish(), Ok(()));
+        assert_eq!(records, expected);
+    }
+}
+
+#[test]
+fn test_parser_malformed() {
+    let parse_error = |e: &Error| e.downcast_ref::<FcgiParseError>().cloned();
+    //  Nothing at all is a clean EOF.
+    assert!(FcgiRecord::new_from_stream(&mut std::io::Cursor::new(Vec::new())).unwrap().is_none());
+    //  Cut off in the second header. That's an error, not EOF.
+    let request = test_request_bytes(1, &[("SCRIPT_NAME", "/status.fcgi")], b"body");
+    let mut stream = std::io::Cursor::new(request[..FcgiHeader::FCGI_HEADER_LENGTH + 8 + 3].to_vec());
+    assert!(FcgiRecord::new_from_stream(&mut stream).unwrap().is_some());
+    let e = FcgiRecord::new_from_stream(&mut stream).unwrap_err();
+    assert_eq!(parse_error(&e), Some(FcgiParseError::TruncatedHeader { received: 3 }));
+    assert_eq!(e.to_string(), "Input ended after 3 of 8 FCGI header bytes");
+    //  Content length larger than the input.
+    let header = FcgiHeader { version: 1, rec_type: FcgiRecType::Stdin, id: 1, content_length: 1000, padding_length: 0 };
+    let short = [header.to_bytes().as_slice(), &b"only this"[..]].concat();
+    let e = FcgiRecord::new_from_stream(&mut std::io::Cursor::new(short.clone())).unwrap_err();
+    assert_eq!(parse_error(&e), Some(FcgiParseError::TruncatedContent { expected: 1000, received: 9 }));
+    let mut parser = FcgiParser::new();
+    assert!(parser.push(&short).unwrap().is_empty());
+    assert_eq!(parser.finish(), Err(FcgiParseError::TruncatedContent { expected: 1000, received: 9 }));
+    //  Missing padding counts too.
+    let header = FcgiHeader { version: 1, rec_type: FcgiRecType::Stdin, id: 1, content_length: 3, padding_length: 5 };
+    let mut parser = FcgiParser::new();
+    assert!(parser.push(&[header.to_bytes().as_slice(), &b"abc"[..], &[0u8; 2][..]].concat()).unwrap().is_empty());
+    assert_eq!(parser.finish(), Err(FcgiParseError::TruncatedContent { expected: 8, received: 5 }));
+    //  Unknown record type.
+    let mut bad = header.to_bytes();
+    bad[1] = 99;
+    let e = FcgiParser::new().push(&bad).unwrap_err();
+    assert_eq!(parse_error(&e), Some(FcgiParseError::InvalidRecordType(99)));
+}
+
+#[test]
+fn test_parser_request_limit() {
+    let request = |id: u16, body: &[u8]| test_request_bytes(id, &[("SCRIPT_NAME", "/status.fcgi")], body);
+    //  The begin record's 8 bytes, the params, and the body all count.
+    let content = |records: Vec<Vec<u8>>| records.iter().map(|rec| rec.len() - FcgiHeader::FCGI_HEADER_LENGTH).sum::<usize>();
+    let limit = content(test_request_records(1, &[("SCRIPT_NAME", "/status.fcgi")], &[])) + 100;
+    //  At the limit is fine, and each request is counted on its own.
+    let mut parser = FcgiParser::new_with_limit(Some(limit));
+    let records = parser.push(&[request(1, &[0; 100]), request(2, &[0; 100])].concat()).expect("at limit");
+    assert_eq!(records.len(), 10);
+    //  One more byte is refused at that record's header, before its content arrives.
+    let over = request(3, &[0; 101]);
+    let stdin_start = over.len() - 8 - (8 + 101);
+    let e = FcgiParser::new_with_limit(Some(limit)).push(&over[..stdin_start + 8]).unwrap_err();
+    assert_eq!(e.downcast_ref::<FcgiParseError>(), Some(&FcgiParseError::RequestTooLarge { limit }));
+    assert!(FcgiParser::new().push(&over).is_ok());
+}
+
+#[test]
+fn test_check_json_content() {
+    let request = |content_type: Option<&str>, content_length: &str, body: &str| {
+        let mut params: HashMap<String, String> = [("CONTENT_LENGTH".to_string(), content_length.to_string())].into_iter().collect();
+        if let Some(content_type) = content_type {
+            params.insert("CONTENT_TYPE".to_string(), content_type.to_string());
+        }
+        Request { standard_input: body.as_bytes().to_vec(), ..Request::new_with_params(1, params) }
+    };
+    let capture = crate::test_logger_capture();
+    for ok in ["application/json", "application/json; charset=utf-8", "Application/JSON;charset=UTF-8", "text/plain; charset=utf-8",
+        "text/plain", "application/json; charset=\"utf8\""] {
+        request(Some(ok), "2", "{}").check_json_content().unwrap_or_else(|e| panic!("{}: {}", ok, e));
+    }
+    assert!(capture.records().is_empty(), "{:?}", capture.records());
+    for bad in [Some("application/x-www-form-urlencoded"), Some("text/html; charset=utf-8"), Some("application/json; charset=iso-8859-1"),
+        Some("text/plain; charset=windows-1252"), Some(""), None] {
+        let e = request(bad, "2", "{}").check_json_content().expect_err(&format!("{:?} accepted", bad));
+        assert_eq!(e.http_status(), 415);
+        assert!(e.to_string().contains(&format!("Received \"{}\"", bad.unwrap_or(""))), "{}", e);
+    }
+    //  Truncated POST. Logged, but passed on to the parser.
+    request(Some("application/json"), "2000", "{\"grid\":").check_json_content().expect("length mismatch rejected");
+    assert!(capture.contains(log::Level::Warn, "CONTENT_LENGTH is 2000 but 8 bytes arrived"), "{:?}", capture.records());
+}
+
+/// One complete request, as the web server sends it. For tests.
+#[cfg(test)]
+pub(crate) fn test_request_bytes(id: u16, params: &[(&str, &str)], stdin: &[u8]) -> Vec<u8> {
+    test_request_records(id, params, &[stdin]).concat()
+}
+
+/// One complete request, one Vec per record, with the body in several Stdin records. For tests.
+#[cfg(test)]
+fn test_request_records(id: u16, params: &[(&str, &str)], stdin_chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+    let params: Vec<(&[u8], &[u8])> = params.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())).collect();
+    test_raw_request_records(id, &params, stdin_chunks)
+}
+
+/// One complete request, with params as raw bytes, which need not be UTF-8. For tests.
+#[cfg(test)]
+fn test_raw_request_records(id: u16, params: &[(&[u8], &[u8])], stdin_chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+    let record = |rec_type: FcgiRecType, content: &[u8]| {
+        let header = FcgiHeader { version: 1, rec_type, id, content_length: content.len() as u16, padding_length: 0 };
+        [header.to_bytes().as_slice(), content].concat()
+    };
+    let param_bytes: Vec<u8> = params.iter()
+        .flat_map(|(k, v)| [&[k.len() as u8, v.len() as u8][..], k, v].concat())
+        .collect();
+    let mut records = vec![record(FcgiRecType::BeginRequest, &[0, 1, 0, 0, 0, 0, 0, 0]), record(FcgiRecType::Params, &param_bytes),
+        record(FcgiRecType::Params, &[])];
+    records.extend(stdin_chunks.iter().map(|chunk| record(FcgiRecType::Stdin, chunk)));
+    records.push(record(FcgiRecType::Stdin, &[]));
+    records
+}
+
+#[test]
+fn test_run_duplex_pipelined() {
+    use std::io::{BufReader, Cursor, Read};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    //  Input pipe. Counts bytes taken from it.
+    struct PipeReader {
+        chunks: mpsc::Receiver<Vec<u8>>,
+        current: Cursor<Vec<u8>>,
+        consumed: Arc<AtomicUsize>,
+    }
+    impl Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                let n = self.current.read(buf)?;
+                if n > 0 {
+                    self.consumed.fetch_add(n, Ordering::SeqCst);
+                    return Ok(n);
+                }
+                match self.chunks.recv() {
+                    Ok(chunk) => self.current = Cursor::new(chunk),
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+    //  Output pipe. Nobody reads it until the gate opens.
+    struct GatedWriter {
+        gate: Option<mpsc::Receiver<()>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+    impl Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(gate) = self.gate.take() {
+                let _ = gate.recv();
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    //  Big replies, much more than the sink queue holds.
+    struct BigReplyHandler {
+        stdins: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+    impl Handler for BigReplyHandler {
+        fn handler(&mut self, out: &mut dyn Write, request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+            self.stdins.lock().unwrap().push(request.standard_input.clone());
+            let http_response = Response::http_response("text/plain", 200, "OK");
+            Response::write_response(out, request, http_response.as_slice(), &vec![b'x'; 500_000])
+        }
+    }
+    let (input, chunks) = mpsc::channel();
+    let (open_gate, gate) = mpsc::channel();
+    let consumed = Arc::new(AtomicUsize::new(0));
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let stdins = Arc::new(Mutex::new(Vec::new()));
+    let instream = BufReader::new(PipeReader { chunks, current: Cursor::new(Vec::new()), consumed: consumed.clone() });
+    let out = GatedWriter { gate: Some(gate), written: written.clone() };
+    let mut handler = BigReplyHandler { stdins: stdins.clone() };
+    let runner = std::thread::spawn(move || run_duplex(instream, out, &mut handler, &Metrics::new()));
+    let first = test_request_bytes(1, &[("REQUEST_METHOD", "POST")], b"first");
+    let second = test_request_bytes(1, &[("REQUEST_METHOD", "POST")], b"second");
+    input.send(first.clone()).unwrap();
+    //  Wait until the first response is stuck in the output pipe.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while stdins.lock().unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "first request never handled");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    //  The second request still gets read while the first response can't go anywhere.
+    input.send(second.clone()).unwrap();
+    while consumed.load(Ordering::SeqCst) < first.len() + second.len() {
+        assert!(Instant::now() < deadline, "second request not read while first response was streaming");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(written.lock().unwrap().is_empty());
+    assert_eq!(stdins.lock().unwrap().len(), 1);
+    //  Let the output drain, and end the input.
+    open_gate.send(()).unwrap();
+    drop(input);
+    runner.join().unwrap().expect("run_duplex");
+    assert_eq!(*stdins.lock().unwrap(), vec![b"first".to_vec(), b"second".to_vec()]);
+    //  Both responses, complete and in order.
+    let written = written.lock().unwrap().clone();
+    let mut replies = BufReader::new(Cursor::new(written));
+    let (mut stdout_bytes, mut ends) = (0, 0);
+    while let Some(rec) = FcgiRecord::new_from_stream(&mut replies).unwrap() {
+        match rec.header.rec_type {
+            FcgiRecType::Stdout => stdout_bytes += rec.header.content_length as usize,
+            FcgiRecType::EndRequest => ends += 1,
+            _ => panic!("unexpected record {:?}", rec.header),
+        }
+    }
+    assert_eq!(ends, 2);
+    assert!(stdout_bytes > 2 * 500_000);
+}
+
+#[test]
+fn test_stdin_chunk_rejection() {
+    use std::collections::VecDeque;
+    use std::io::{BufRead, Cursor, Read};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    //  Hands out one record at a time, noting how much reply had been written when each was taken.
+    struct RecordFeed {
+        records: VecDeque<Vec<u8>>,
+        current: Cursor<Vec<u8>>,
+        written: Rc<RefCell<Vec<u8>>>,
+        written_at_record: Vec<usize>,
+    }
+    impl Read for RecordFeed {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = Read::read(&mut self.fill_buf()?, buf)?;
+            self.consume(n);
+            Ok(n)
+        }
+    }
+    impl BufRead for RecordFeed {
+        fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+            if self.current.position() as usize == self.current.get_ref().len() {
+                if let Some(record) = self.records.pop_front() {
+                    self.written_at_record.push(self.written.borrow().len());
+                    self.current = Cursor::new(record);
+                }
+            }
+            self.current.fill_buf()
+        }
+        fn consume(&mut self, amt: usize) {
+            self.current.consume(amt)
+        }
+    }
+    struct SharedOut(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedOut {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    //  Wants JSON. Decides from the first byte.
+    #[derive(Default)]
+    struct JsonOnlyHandler {
+        chunks: usize,
+        bodies: Vec<Vec<u8>>,
+    }
+    impl Handler for JsonOnlyHandler {
+        fn handler(&mut self, out: &mut dyn Write, request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+            self.chunks = 0;
+            self.bodies.push(request.standard_input.clone());
+            Response::write_response(out, request, Response::http_response("text/plain", 200, "OK").as_slice(), b"OK")
+        }
+        fn on_stdin_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+            self.chunks += 1;
+            if self.chunks == 1 && chunk.first() != Some(&b'{') {
+                return Err(crate::Error::BadRequest("Not JSON".to_string()).into());
+            }
+            Ok(())
+        }
+        fn stdin_rejected(&mut self, out: &mut dyn Write, request: &Request, error: &Error) -> Result<(), Error> {
+            assert_eq!(self.chunks, 1);
+            assert_eq!(request.params.as_ref().and_then(|p| p.get("REQUEST_METHOD")).map(|m| m.as_str()), Some("POST"));
+            self.chunks = 0;
+            let msg = error.to_string();
+            Response::write_response(out, request, Response::http_response("text/plain", 400, &msg).as_slice(), msg.as_bytes())
+        }
+    }
+    //  A big body that isn't JSON, in 50 records, then a good request.
+    let bad_body = vec![b'<'; 50 * 1000];
+    let bad_chunks: Vec<&[u8]> = bad_body.chunks(1000).collect();
+    let bad = test_request_records(1, &[("REQUEST_METHOD", "POST")], &bad_chunks);
+    let good = test_request_records(1, &[("REQUEST_METHOD", "POST")], &[b"{\"a\":", b"1}"]);
+    let written = Rc::new(RefCell::new(Vec::new()));
+    let mut feed = RecordFeed { records: bad.iter().chain(good.iter()).cloned().collect(), current: Cursor::new(Vec::new()),
+        written: written.clone(), written_at_record: Vec::new() };
+    let mut handler = JsonOnlyHandler::default();
+    let metrics = Metrics::new();
+    run_with_metrics(&mut feed, &mut SharedOut(written.clone()), &mut handler, &metrics).expect("run");
+    //  Records 0..3 are begin and params, 3 is the first chunk. The reply was out before the second chunk was read.
+    assert_eq!(feed.written_at_record[3], 0);
+    assert!(feed.written_at_record[4] > 0);
+    //  The rest of the bad request was dropped, and the good one handled whole.
+    assert_eq!(handler.bodies, vec![b"{\"a\":1}".to_vec()]);
+    let mut replies = std::io::BufReader::new(Cursor::new(written.borrow().clone()));
+    let mut stdout = Vec::new();
+    while let Some(mut rec) = FcgiRecord::new_from_stream(&mut replies).unwrap() {
+        if rec.header.rec_type == FcgiRecType::Stdout {
+            stdout.extend(rec.take_content().unwrap_or_default());
+        }
+    }
+    let stdout = String::from_utf8_lossy(&stdout);
+    assert!(stdout.starts_with("Status: 400 Bad request: Not JSON"), "{}", stdout);
+    assert!(stdout.contains("Status: 200 OK"), "{}", stdout);
+}
+
+#[test]
+fn test_stdin_rejected_default() {
+    //  Handlers that don't say otherwise get a text reply with the error's status.
+    struct Rejecter;
+    impl Handler for Rejecter {
+        fn handler(&mut self, _out: &mut dyn Write, _request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+            panic!("Rejected request was handled");
+        }
+        fn on_stdin_chunk(&mut self, _chunk: &[u8]) -> Result<(), Error> {
+            Err(crate::Error::UnsupportedMediaType("XML".to_string()).into())
+        }
+    }
+    let bytes = test_request_records(7, &[], &[b"<xml>", b"</xml>"]).concat();
+    let mut out = Vec::new();
+    run(&mut std::io::Cursor::new(bytes), &mut out, &mut Rejecter).expect("run");
+    let reply = String::from_utf8_lossy(&out);
+    assert!(reply.contains("Status: 415 Request rejected: Unsupported media type: XML"), "{}", reply);
+}
+
+#[test]
+fn test_serve_after_bad_connection() {
+    use std::io::Read;
+    struct OkHandler;
+    impl Handler for OkHandler {
+        fn handler(&mut self, out: &mut dyn Write, request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+            Response::write_response(out, request, Response::http_response("text/plain", 200, "OK").as_slice(), b"OK")
+        }
+    }
+    let path = std::env::temp_dir().join(format!("minifcgi_serve_test_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let metrics = Metrics::new();
+    let served = metrics.clone();
+    //  Never returns. Ends with the test process.
+    std::thread::spawn(move || serve(&listener, &mut OkHandler, &served, &ConnectionLimits::default()));
+    //  Garbage before any request. Nothing can be replied, and the connection is dropped.
+    let header = FcgiHeader { version: 1, rec_type: FcgiRecType::Stdin, id: 1, content_length: 0, padding_length: 0 };
+    let mut bad = header.to_bytes();
+    bad[1] = 99;
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(&bad).unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert!(reply.is_empty());
+    //  The next connection is still served.
+    let mut client = UnixStream::connect(&path).unwrap();
+    client.write_all(&test_request_bytes(1, &[("REQUEST_METHOD", "GET")], b"")).unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert!(String::from_utf8_lossy(&reply).contains("Status: 200 OK"));
+    assert_eq!(metrics.snapshot().connection_errors, 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_malformed_params() {
+    use std::io::Cursor;
+    //  Remembers what the handler saw.
+    #[derive(Default)]
+    struct ParamsHandler {
+        seen: Vec<(HashMap<String, String>, Vec<String>)>,
+    }
+    impl Handler for ParamsHandler {
+        fn handler(&mut self, out: &mut dyn Write, request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+            self.seen.push((request.params.clone().unwrap_or_default(), request.malformed_params().to_vec()));
+            Response::write_response(out, request, Response::http_response("text/plain", 200, "OK").as_slice(), b"OK")
+        }
+    }
+    let capture = crate::test_logger_capture();
+    //  One bad byte in a header value, and one in a key.
+    let params: [(&[u8], &[u8]); 4] = [(b"REQUEST_METHOD", b"POST"), (b"HTTP_USER_AGENT", b"Second Life LSL/\xff2025"),
+        (b"HTTP_BAD\xfeNAME", b"x"), (b"HTTP_X_SECONDLIFE_SHARD", b"Production")];
+    let input = test_raw_request_records(1, &params, &[b"{}"]).concat();
+    let mut out = Vec::new();
+    let mut handler = ParamsHandler::default();
+    run(&mut Cursor::new(input), &mut out, &mut handler).expect("run");
+    //  The request still completes.
+    assert_eq!(handler.seen.len(), 1);
+    let (params, malformed) = &handler.seen[0];
+    assert_eq!(malformed, &vec!["HTTP_USER_AGENT".to_string()]);
+    assert_eq!(params.get("HTTP_USER_AGENT").map(|s| s.as_str()), Some("Second Life LSL/\u{FFFD}2025"));
+    assert_eq!(params.get("HTTP_X_SECONDLIFE_SHARD").map(|s| s.as_str()), Some("Production"));
+    assert_eq!(params.len(), 3, "{:?}", params);
+    assert!(String::from_utf8_lossy(&out).contains("Status: 200 OK"));
+    assert!(capture.contains(log::Level::Warn, "Param \"HTTP_USER_AGENT\" is not UTF-8"), "{:?}", capture.records());
+    assert!(capture.contains(log::Level::Warn, "is not UTF-8, dropped"), "{:?}", capture.records());
+    //  Clean params flag nothing.
+    let (_, malformed) = Request::build_params_flagged(&[3, 1, b'K', b'E', b'Y', b'v']).unwrap();
+    assert!(malformed.is_empty());
+    //  A bad encoding is still an error.
+    assert!(Request::build_params(&[3, 10, b'K', b'E', b'Y', b'v']).is_err());
+}
+
+#[test]
+fn test_short_writes() {
+    //  Takes at most 3 bytes per call, like a nearly full pipe.
+    struct Trickle(Vec<u8>);
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let body: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    let headers = Response::http_response("application/octet-stream", 200, "OK");
+    let request = Request::new_with_params(7, HashMap::new());
+    let mut whole = Vec::new();
+    Response::write_response(&mut whole, &request, headers.as_slice(), &body).expect("write");
+    assert_eq!(request.response_bytes(), whole.len());
+    let request = Request::new_with_params(7, HashMap::new());
+    let mut trickle = Trickle(Vec::new());
+    Response::write_response(&mut trickle, &request, headers.as_slice(), &body).expect("short writes");
+    assert_eq!(trickle.0, whole);
+    assert_eq!(request.response_bytes(), trickle.0.len());
+    //  And it parses back intact.
+    let reply = crate::read_reply(&mut std::io::Cursor::new(trickle.0), 7).expect("reply");
+    assert_eq!((reply.status, reply.body), (200, body));
+}
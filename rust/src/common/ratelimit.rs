@@ -0,0 +1,171 @@
+//! ratelimit.rs -- per client limits on request rate.
+//! Part of the Animats impostor system
+//!
+//! Raw terrain downloads are unauthenticated, and each one is a database
+//! read and maybe a PNG encode. So each client gets a token bucket. It holds
+//! up to a burst of requests, and refills at a steady rate. A request with
+//! the bucket empty gets a 429, and the client should wait and try again.
+//!
+//! Clients are told apart by address, REMOTE_ADDR, as the web server passes it.
+//! At most MAX_RATE_LIMIT_CLIENTS are remembered. When full, clients whose
+//! buckets have refilled are forgotten first, since forgetting them changes
+//! nothing, then the least recently seen.
+//!
+//! The rate and burst can be set in the credentials file:
+//!
+//!     TERRAIN_RATE_PER_MINUTE = 120
+//!     TERRAIN_BURST = 30
+//!
+//! A rate of 0 turns limiting off.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Requests per minute per client, if not configured.
+pub const DEFAULT_RATE_PER_MINUTE: u32 = 120;
+/// Requests a client can make at once, if not configured.
+pub const DEFAULT_BURST: u32 = 30;
+/// Clients remembered.
+pub const MAX_RATE_LIMIT_CLIENTS: usize = 4096;
+/// Credentials key for the rate, requests per minute. 0 turns limiting off.
+const RATE_KEY: &str = "TERRAIN_RATE_PER_MINUTE";
+/// Credentials key for the burst size.
+const BURST_KEY: &str = "TERRAIN_BURST";
+
+/// One client's bucket.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Requests available, as of last
+    tokens: f64,
+    /// When tokens was last brought up to date
+    last: Instant,
+}
+
+/// Token buckets, by client.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Refill rate, tokens per second. 0 is no limit.
+    rate: f64,
+    /// Bucket size
+    burst: f64,
+    /// Buckets by client address
+    clients: HashMap<String, Bucket>,
+    /// Most clients remembered
+    capacity: usize,
+}
+
+impl RateLimiter {
+    /// Usual new. Rate is requests per minute.
+    pub fn new(rate_per_minute: u32, burst: u32, capacity: usize) -> Self {
+        Self { rate: rate_per_minute as f64 / 60.0, burst: burst.max(1) as f64, clients: HashMap::new(), capacity: capacity.max(1) }
+    }
+
+    /// From a key lookup, such as a credentials file.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let number = |key: &str, default: u32| -> Result<u32, Error> {
+            match get(key) {
+                Some(v) => v.trim().parse::<u32>().map_err(|_| anyhow!("{} \"{}\" is not a number", key, v)),
+                None => Ok(default),
+            }
+        };
+        Ok(Self::new(number(RATE_KEY, DEFAULT_RATE_PER_MINUTE)?, number(BURST_KEY, DEFAULT_BURST)?, MAX_RATE_LIMIT_CLIENTS))
+    }
+
+    /// May this client make a request now? If so, it's counted.
+    pub fn allow(&mut self, client: &str, now: Instant) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        if !self.clients.contains_key(client) && self.clients.len() >= self.capacity {
+            self.make_room(now);
+        }
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.clients.entry(client.to_string()).or_insert(Bucket { tokens: burst, last: now });
+        bucket.tokens = (bucket.tokens + now.saturating_duration_since(bucket.last).as_secs_f64() * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Seconds until this client can make another request. 0 if it can now.
+    pub fn retry_after(&self, client: &str, now: Instant) -> u64 {
+        match self.clients.get(client) {
+            Some(bucket) if self.rate > 0.0 => {
+                let tokens = bucket.tokens + now.saturating_duration_since(bucket.last).as_secs_f64() * self.rate;
+                ((1.0 - tokens).max(0.0) / self.rate).ceil() as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Clients remembered.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// No clients remembered.
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Forget clients with full buckets. If none, forget the least recently seen.
+    fn make_room(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.clients.retain(|_, bucket| bucket.tokens + now.saturating_duration_since(bucket.last).as_secs_f64() * rate < burst);
+        if self.clients.len() >= self.capacity {
+            let oldest = self.clients.iter().min_by_key(|(_, bucket)| bucket.last).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.clients.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    use std::time::Duration;
+    let start = Instant::now();
+    //  One a second, bursts of 3.
+    let mut limiter = RateLimiter::new(60, 3, 10);
+    assert!((0..3).all(|_| limiter.allow("10.0.0.1", start)));
+    assert!(!limiter.allow("10.0.0.1", start));
+    assert_eq!(limiter.retry_after("10.0.0.1", start), 1);
+    //  Other clients have their own buckets.
+    assert!(limiter.allow("10.0.0.2", start));
+    //  Refills at the rate, up to the burst.
+    assert!(!limiter.allow("10.0.0.1", start + Duration::from_millis(500)));
+    assert!(limiter.allow("10.0.0.1", start + Duration::from_millis(1500)));
+    assert!(!limiter.allow("10.0.0.1", start + Duration::from_millis(1500)));
+    let later = start + Duration::from_secs(3600);
+    assert_eq!(limiter.retry_after("10.0.0.1", later), 0);
+    assert_eq!((0..5).filter(|_| limiter.allow("10.0.0.1", later)).count(), 3);
+    //  Off.
+    let mut off = RateLimiter::new_from_lookup(|k| (k == RATE_KEY).then(|| "0".to_string())).unwrap();
+    assert!((0..1000).all(|_| off.allow("10.0.0.1", start)));
+    assert!(off.is_empty());
+    assert!(RateLimiter::new_from_lookup(|_| Some("fast".to_string())).is_err());
+}
+
+#[test]
+fn test_rate_limit_clients_bounded() {
+    use std::time::Duration;
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(60, 2, 4);
+    //  Many clients, each with a used bucket. Oldest forgotten.
+    for n in 0..10u64 {
+        assert!(limiter.allow(&format!("client{}", n), start + Duration::from_millis(n)));
+    }
+    assert_eq!(limiter.len(), 4);
+    assert!(limiter.clients.contains_key("client9") && !limiter.clients.contains_key("client0"));
+    //  Once their buckets have refilled, forgetting them changes nothing, so they all go.
+    assert!(limiter.allow("late", start + Duration::from_secs(3600)));
+    assert_eq!(limiter.len(), 1);
+}
@@ -0,0 +1,394 @@
+//! rawterrain.rs -- reading and writing rows of raw_terrain_heights.
+//! Part of the Animats impostor system
+//!
+//! Raw terrain comes from the upload responder, and from files
+//! imported by generateterrain. Both write rows the same way.
+//!
+//! Regions which leave the grid are marked deleted, not removed, so a
+//! later upload can bring them back. Replacing a row undeletes it.
+//!
+//! Sample spacing is NULL unless the uploader sent one. Those rows, and
+//! all older ones, have samples spanning the region, last ones on the far edges.
+//!
+//! A region renamed in world keeps its terrain. Only the names change,
+//! here and in its impostors, so nothing needs to be regenerated.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::Error;
+use mysql::{params, Params};
+use crate::db::Db;
+use crate::{GlobalMeters, HeightField, UploadedRegionInfo};
+use crate::grid::canonical;
+
+/// Add a region.
+const SQL_INSERT: &str = r"INSERT INTO raw_terrain_heights (grid, region_loc_x, region_loc_y, samples_x, samples_y, region_size_x, region_size_y, name, scale, offset, elevs,  water_level, sample_spacing_x, sample_spacing_y, creator, last_uploaded, last_confirmed)
+    VALUES
+    (:grid, :region_loc_x, :region_loc_y, :samples_x, :samples_y, :region_size_x, :region_size_y, :name, :scale, :offset, :elevs, :water_level, :sample_spacing_x, :sample_spacing_y, :creator, NOW(), NOW())";
+/// Replace a region's entire record.
+const SQL_FULL_UPDATE: &str = r"UPDATE raw_terrain_heights
+    SET samples_x = :samples_x, samples_y = :samples_y, scale = :scale, offset = :offset, elevs = :elevs, water_level = :water_level, creator = :creator,
+        sample_spacing_x = :sample_spacing_x, sample_spacing_y = :sample_spacing_y,
+        region_size_x = :region_size_x, region_size_y = :region_size_y, name = :name, confirmation_time = NOW(), confirmer = NULL, last_uploaded = NOW(), last_confirmed = NOW(), deleted = FALSE
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+/// Add a region, or replace its entire record, in one statement, so two uploads
+/// of the same region at once can't both try to insert.
+const SQL_UPSERT: &str = r"INSERT INTO raw_terrain_heights (grid, region_loc_x, region_loc_y, samples_x, samples_y, region_size_x, region_size_y, name, scale, offset, elevs,  water_level, sample_spacing_x, sample_spacing_y, creator, last_uploaded, last_confirmed)
+    VALUES
+    (:grid, :region_loc_x, :region_loc_y, :samples_x, :samples_y, :region_size_x, :region_size_y, :name, :scale, :offset, :elevs, :water_level, :sample_spacing_x, :sample_spacing_y, :creator, NOW(), NOW())
+    ON DUPLICATE KEY UPDATE
+        samples_x = :samples_x, samples_y = :samples_y, scale = :scale, offset = :offset, elevs = :elevs, water_level = :water_level, creator = :creator,
+        sample_spacing_x = :sample_spacing_x, sample_spacing_y = :sample_spacing_y,
+        region_size_x = :region_size_x, region_size_y = :region_size_y, name = :name, confirmation_time = NOW(), confirmer = NULL, last_uploaded = NOW(), last_confirmed = NOW(), deleted = FALSE";
+/// One region's row, unless deleted.
+const SQL_SELECT: &str = r"SELECT region_size_x, region_size_y, name, samples_x, samples_y, scale, offset, elevs, water_level, creator,
+        sample_spacing_x, sample_spacing_y
+    FROM raw_terrain_heights
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND NOT deleted";
+/// Is there a row for this region?
+const SQL_EXISTS: &str = r"SELECT COUNT(*) FROM raw_terrain_heights
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+/// Mark a region as gone from the grid.
+const SQL_MARK_DELETED: &str = r"UPDATE raw_terrain_heights
+    SET deleted = TRUE, deletion_time = NOW(), confirmer = :confirmer
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+/// New region name, in raw terrain and in the region's own impostors.
+/// Lower LOD tiles are named for their corner region, and keep that name until regenerated.
+const SQL_RENAMES: [&str; 3] = [
+    r"UPDATE raw_terrain_heights SET name = :name
+        WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y",
+    r"UPDATE initial_impostors SET name = :name
+        WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND impostor_lod = 0",
+    r"UPDATE region_impostors SET name = :name
+        WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND impostor_lod = 0",
+];
+
+/// Is there a row for this region? Deleted or not.
+fn region_exists(conn: &mut dyn Db, grid: &str, region_loc: [GlobalMeters; 2]) -> Result<bool, Error> {
+    let row = conn.exec_first(SQL_EXISTS, params! {
+        "grid" => canonical(grid),
+        "region_loc_x" => region_loc[0].meters(),
+        "region_loc_y" => region_loc[1].meters(),
+    })?;
+    let count: Option<u32> = row.map(|row| row.get(0)).transpose()?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+/// SQL condition on region_impostors leaving out LOD 0 impostors of deleted regions.
+/// The query must bind :grid, in canonical form, so the grid index can be used.
+pub const NOT_DELETED_IMPOSTOR: &str = "NOT (impostor_lod = 0 AND EXISTS (SELECT 1 FROM raw_terrain_heights AS r \
+    WHERE r.deleted AND r.grid = :grid \
+    AND r.region_loc_x = region_impostors.region_loc_x AND r.region_loc_y = region_impostors.region_loc_y))";
+
+/// Mark a region as deleted. Returns false if there's no such region.
+pub fn mark_region_deleted(conn: &mut dyn Db, grid: &str, region_loc: [GlobalMeters; 2], confirmer: &str) -> Result<bool, Error> {
+    if !region_exists(conn, grid, region_loc)? {
+        return Ok(false);
+    }
+    conn.exec_drop(SQL_MARK_DELETED, params! {
+        "grid" => canonical(grid),
+        "region_loc_x" => region_loc[0].meters(),
+        "region_loc_y" => region_loc[1].meters(),
+        "confirmer" => confirmer,
+    })?;
+    log::info!("Region at ({}, {}) on \"{}\" marked deleted by {}.", region_loc[0], region_loc[1], grid, confirmer);
+    Ok(true)
+}
+
+/// Rename a region whose terrain has not changed. Impostor UUIDs and hashes are untouched.
+pub fn rename_region(conn: &mut dyn Db, grid: &str, region_loc: [GlobalMeters; 2], name: &str) -> Result<(), Error> {
+    for sql in SQL_RENAMES {
+        conn.exec_drop(sql, params! {
+            "grid" => canonical(grid),
+            "region_loc_x" => region_loc[0].meters(),
+            "region_loc_y" => region_loc[1].meters(),
+            "name" => name,
+        })?;
+    }
+    log::info!("Region at ({}, {}) on \"{}\" renamed to \"{}\".", region_loc[0], region_loc[1], grid, name);
+    Ok(())
+}
+
+/// One region's raw terrain, as stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawTerrainHeights {
+    /// Grid name
+    pub grid: String,
+    /// Region location, meters
+    pub region_loc: [u32; 2],
+    /// Region size, meters
+    pub region_size: [u32; 2],
+    /// Region name
+    pub name: String,
+    /// Number of samples, X and Y
+    pub samples: [u32; 2],
+    /// Elevation scale, for u8 elevs
+    pub scale: f32,
+    /// Elevation offset, for u8 elevs
+    pub offset: f32,
+    /// Elevations, scaled into u8. Y subscript goes fastest.
+    pub elevs: Vec<u8>,
+    /// Water level, meters
+    pub water_level: f32,
+    /// Who supplied this
+    pub creator: String,
+    /// Distance between samples, meters, X and Y. None if derived from the sample counts.
+    pub sample_spacing: Option<[f32; 2]>,
+}
+
+impl RawTerrainHeights {
+    /// From an upload by the LSL script.
+    /// A sample spacing which doesn't tile the region is rejected here, not stored.
+    pub fn new_from_uploaded(region_info: &UploadedRegionInfo, creator: &str) -> Result<Self, Error> {
+        region_info.to_height_field()?;
+        Ok(Self {
+            grid: region_info.get_grid(),
+            region_loc: region_info.region_coords.map(GlobalMeters::meters),
+            region_size: region_info.get_size(),
+            name: region_info.name.clone(),
+            samples: region_info.get_samples()?,
+            scale: region_info.elev_scale(),
+            offset: region_info.offset,
+            elevs: region_info.get_elevs_as_blob()?,
+            water_level: region_info.water_lev,
+            creator: creator.to_string(),
+            sample_spacing: region_info.sample_spacing,
+        })
+    }
+
+    /// From a height field, quantizing the elevations to u8.
+    pub fn new_from_height_field(grid: &str, region_loc: [u32; 2], name: &str, height_field: &HeightField, creator: &str) -> Result<Self, Error> {
+        let (scale, offset, rows) = height_field.into_sculpt_array()?;
+        let (samples_x, samples_y) = height_field.dims();
+        Ok(Self {
+            grid: canonical(grid),
+            region_loc,
+            region_size: [height_field.size_x, height_field.size_y],
+            name: name.to_string(),
+            samples: [samples_x as u32, samples_y as u32],
+            scale,
+            offset,
+            elevs: rows.into_iter().flatten().collect(),
+            water_level: height_field.water_level,
+            creator: creator.to_string(),
+            sample_spacing: height_field.explicit_spacing(),
+        })
+    }
+
+    /// The stored row for a region. None if there's no such region, or it has been deleted.
+    pub fn get(conn: &mut dyn Db, grid: &str, region_loc: [u32; 2]) -> Result<Option<Self>, Error> {
+        let row = conn.exec_first(SQL_SELECT, params! {
+            "grid" => canonical(grid),
+            "region_loc_x" => region_loc[0],
+            "region_loc_y" => region_loc[1],
+        })?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            grid: canonical(grid),
+            region_loc,
+            region_size: [row.get(0)?, row.get(1)?],
+            name: row.get(2)?,
+            samples: [row.get(3)?, row.get(4)?],
+            scale: row.get(5)?,
+            offset: row.get(6)?,
+            elevs: row.get(7)?,
+            water_level: row.get(8)?,
+            creator: row.get(9)?,
+            sample_spacing: match (row.get::<Option<f32>>(10)?, row.get::<Option<f32>>(11)?) {
+                (Some(x), Some(y)) => Some([x, y]),
+                _ => None,
+            },
+        }))
+    }
+
+    /// Back to a height field.
+    pub fn height_field(&self) -> Result<HeightField, Error> {
+        Ok(HeightField::new_from_elevs_blob(&self.elevs, self.samples[0], self.samples[1],
+            self.region_size[0], self.region_size[1], self.scale, self.offset, self.water_level)?
+            .with_spacing(self.sample_spacing)?)
+    }
+
+    /// SQL parameters, for both insert and update.
+    fn params(&self) -> Params {
+        params! {
+            "grid" => canonical(&self.grid),
+            "region_loc_x" => self.region_loc[0],
+            "region_loc_y" => self.region_loc[1],
+            "region_size_x" => self.region_size[0],
+            "region_size_y" => self.region_size[1],
+            "name" => self.name.clone(),
+            "scale" => self.scale,
+            "offset" => self.offset,
+            "elevs" => self.elevs.clone(),
+            "samples_x" => self.samples[0],
+            "samples_y" => self.samples[1],
+            "water_level" => self.water_level,
+            "creator" => self.creator.clone(),
+            "sample_spacing_x" => self.sample_spacing.map(|spacing| spacing[0]),
+            "sample_spacing_y" => self.sample_spacing.map(|spacing| spacing[1]),
+        }
+    }
+
+    /// Add as a new region.
+    pub fn insert(&self, conn: &mut dyn Db) -> Result<(), Error> {
+        let values = self.params();
+        log::debug!("SQL insert: {:?}", values);
+        conn.exec_drop(SQL_INSERT, values)?;
+        log::debug!("SQL insert succeeded.");
+        Ok(())
+    }
+
+    /// Replace the existing record for the region.
+    pub fn full_update(&self, conn: &mut dyn Db) -> Result<(), Error> {
+        let values = self.params();
+        log::debug!("SQL update: {:?}", values);
+        conn.exec_drop(SQL_FULL_UPDATE, values)?;
+        log::debug!("SQL update succeeded.");
+        Ok(())
+    }
+
+    /// Insert, or replace if the region is already there, atomically.
+    /// Returns true if inserted. MySQL counts an insert as one row affected, a replacement as two.
+    pub fn upsert(&self, conn: &mut dyn Db) -> Result<bool, Error> {
+        let values = self.params();
+        log::debug!("SQL upsert: {:?}", values);
+        let affected = conn.exec_drop(SQL_UPSERT, values)?;
+        log::debug!("SQL upsert succeeded, {} rows affected.", affected);
+        Ok(affected == 1)
+    }
+
+    /// Insert, or replace if the region is already there.
+    /// Returns true if inserted.
+    pub fn insert_or_update(&self, conn: &mut dyn Db) -> Result<bool, Error> {
+        if !region_exists(conn, &self.grid, self.region_loc.map(GlobalMeters))? {
+            self.insert(conn)?;
+            Ok(true)
+        } else {
+            self.full_update(conn)?;
+            Ok(false)
+        }
+    }
+}
+
+#[test]
+fn test_raw_terrain_from_height_field() {
+    //  The blob must read back as the same height field, within half a u8 step.
+    let height_field = HeightField::new_from_fn(5, 3, 256, 128, 20.0, |x, y| 10.0 + x as f32 * 4.0 + y as f32).unwrap();
+    let row = RawTerrainHeights::new_from_height_field("osgrid", [1000, 1000], "Test", &height_field, "tester").unwrap();
+    assert_eq!(row.samples, [5, 3]);
+    assert_eq!(row.region_size, [256, 128]);
+    assert_eq!(row.elevs.len(), 15);
+    assert_eq!((row.scale, row.offset), crate::elev_min_max_to_scale_offset(10.0, 28.0));
+    let stored = row.height_field().unwrap();
+    assert!(stored.diff(&height_field, row.scale / 510.0 + 0.001).unwrap().is_unchanged());
+}
+
+#[test]
+fn test_deletion_sql() {
+    //  Replacing a row undeletes it. Marking deletes it.
+    assert!(SQL_FULL_UPDATE.contains("deleted = FALSE"), "{}", SQL_FULL_UPDATE);
+    assert!(SQL_UPSERT.ends_with("deleted = FALSE"), "{}", SQL_UPSERT);
+    assert!(SQL_MARK_DELETED.contains("SET deleted = TRUE"), "{}", SQL_MARK_DELETED);
+    //  Both find the row the same way.
+    let where_clause = "WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+    for sql in [SQL_FULL_UPDATE, SQL_MARK_DELETED, SQL_EXISTS] {
+        assert!(sql.contains(where_clause), "{}", sql);
+    }
+}
+
+#[test]
+fn test_mixed_case_grid_param() {
+    //  No LOWER() on the column, so the grid index is used. The parameter is lower cased instead.
+    use crate::db::FakeDb;
+    for sql in [SQL_FULL_UPDATE, SQL_SELECT, SQL_EXISTS, SQL_MARK_DELETED].iter().chain(SQL_RENAMES.iter()) {
+        assert!(!sql.contains("LOWER("), "{}", sql);
+    }
+    let mut fake = FakeDb::default();
+    rename_region(&mut fake, " Agni", [GlobalMeters(256), GlobalMeters(512)], "Renamed").unwrap();
+    assert_eq!(fake.statements.len(), SQL_RENAMES.len());
+    for (_, params) in &fake.statements {
+        match params {
+            Params::Named(named) => assert_eq!(named.get("grid".as_bytes()), Some(&mysql::Value::from("agni"))),
+            _ => panic!("Expected named parameters"),
+        }
+    }
+}
+
+#[test]
+fn test_insert_or_update() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let height_field = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |x, y| (x + y) as f32).unwrap();
+    let row = RawTerrainHeights::new_from_height_field("osgrid", [1000, 1000], "Test", &height_field, "tester").unwrap();
+    //  Not there, so inserted.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::Int(0)])]]);
+    assert!(row.insert_or_update(&mut fake).unwrap());
+    assert!(fake.sql()[1].starts_with("INSERT INTO raw_terrain_heights"), "{:?}", fake.sql());
+    //  There, so replaced.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::Int(1)])]]);
+    assert!(!row.insert_or_update(&mut fake).unwrap());
+    assert!(fake.sql()[1].starts_with("UPDATE raw_terrain_heights"), "{:?}", fake.sql());
+    //  Deleting a region which isn't there does nothing.
+    let mut fake = FakeDb::default();
+    assert!(!mark_region_deleted(&mut fake, "osgrid", [GlobalMeters(0), GlobalMeters(0)], "tester").unwrap());
+    assert_eq!(fake.statements.len(), 1);
+}
+
+#[test]
+fn test_upsert() {
+    use crate::db::FakeDb;
+    let height_field = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |x, y| (x + y) as f32).unwrap();
+    let row = RawTerrainHeights::new_from_height_field("osgrid", [1000, 1000], "Test", &height_field, "tester").unwrap();
+    //  One statement, no check first.
+    let mut fake = FakeDb { affected_rows: 1, ..Default::default() };
+    assert!(row.upsert(&mut fake).unwrap());
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 1);
+    assert!(sql[0].starts_with("INSERT INTO raw_terrain_heights"), "{:?}", sql);
+    //  Every column the full update sets, the upsert sets too.
+    let update = &sql[0][sql[0].find("ON DUPLICATE KEY UPDATE").expect("upsert")..];
+    for column in ["samples_x", "sample_spacing_x", "sample_spacing_y", "scale", "offset", "elevs", "water_level", "name", "creator", "last_uploaded", "last_confirmed", "deleted"] {
+        assert!(update.contains(&format!(" {} = ", column)), "{}", column);
+    }
+    //  Replaced.
+    let mut fake = FakeDb { affected_rows: 2, ..Default::default() };
+    assert!(!row.upsert(&mut fake).unwrap());
+}
+
+#[test]
+fn test_get() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let row = DbRow(vec![DbValue::UInt(256), DbValue::UInt(128), DbValue::text("Test"), DbValue::UInt(3), DbValue::UInt(2),
+        DbValue::Float(10.0), DbValue::Float(-5.0), DbValue::Bytes(vec![0, 1, 2, 3, 4, 255]), DbValue::Float(20.0), DbValue::text("tester"),
+        DbValue::Null, DbValue::Null]);
+    let mut fake = FakeDb::new_with_results(vec![vec![row]]);
+    let stored = RawTerrainHeights::get(&mut fake, "OSGrid", [1000, 2000]).unwrap().expect("row");
+    assert_eq!(stored.grid, "osgrid");
+    assert_eq!((stored.region_loc, stored.region_size, stored.samples), ([1000, 2000], [256, 128], [3, 2]));
+    assert_eq!((stored.scale, stored.offset, stored.water_level), (10.0, -5.0, 20.0));
+    assert_eq!(stored.elevs, vec![0, 1, 2, 3, 4, 255]);
+    //  Legacy row, spacing derived.
+    assert_eq!(stored.sample_spacing, None);
+    assert_eq!(stored.height_field().unwrap().spacing, [128.0, 128.0]);
+    assert!(fake.sql()[0].ends_with("AND NOT deleted"), "{:?}", fake.sql());
+    //  Missing or deleted.
+    let mut fake = FakeDb::default();
+    assert!(RawTerrainHeights::get(&mut fake, "osgrid", [0, 0]).unwrap().is_none());
+}
+
+#[test]
+fn test_rename_region() {
+    use crate::db::FakeDb;
+    //  Names only, and only LOD 0 impostors.
+    let mut fake = FakeDb::default();
+    rename_region(&mut fake, "OSGrid", [GlobalMeters(256000), GlobalMeters(256000)], "New Name").unwrap();
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 3);
+    assert!(sql[0].starts_with("UPDATE raw_terrain_heights SET name = :name WHERE"), "{:?}", sql);
+    assert!(sql[1].starts_with("UPDATE initial_impostors SET name = :name WHERE") && sql[1].ends_with("impostor_lod = 0"), "{:?}", sql);
+    assert!(sql[2].starts_with("UPDATE region_impostors SET name = :name WHERE") && sql[2].ends_with("impostor_lod = 0"), "{:?}", sql);
+    assert!(sql.iter().all(|s| !s.contains("uuid") && !s.contains("hash")), "{:?}", sql);
+}
@@ -0,0 +1,168 @@
+//! regiondata.rs -- info about one region or lower LOD tile.
+//! Part of the Animats impostor system
+//!
+//! Used by viz group computation, LOD tile ordering, and the
+//! impostor tables. Serializable, so it can go straight into
+//! manifests and JSON replies.
+//!
+//! Also the bounds and tile size of a group of regions, which
+//! may be of different sizes, as with Open Simulator varregions.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use crate::GlobalMeters;
+use anyhow::{anyhow, Error};
+use num::integer::gcd;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+
+/// RegionData - info about one region relevant to this computation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionData {
+    /// Which grid
+    pub grid: String,
+    /// Which LOD - zero for all data obtained from the world.
+    #[serde(default)]
+    pub lod: u8,
+    /// X, meters
+    pub region_loc_x: u32,
+    /// Y, meters
+    pub region_loc_y: u32,
+    /// X size
+    pub region_size_x: u32,
+    /// Y size
+    pub region_size_y: u32,
+    /// Region name
+    pub name: String,
+    /// Lower LODs only: locations of the LOD N-1 tiles this tile is built from.
+    /// Only tiles actually generated are listed, not water. Empty for LOD 0.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<(u32, u32)>,
+    /// Lower LODs only: an all-water tile, with no land under it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_water: bool,
+    /// LOD 0 only: a region known to exist but not yet surveyed, with no terrain uploaded.
+    /// Gets a flat placeholder impostor until real terrain comes in.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_placeholder: bool,
+}
+
+impl RegionData {
+    /// Location, (X, Y), meters.
+    pub fn loc(&self) -> [GlobalMeters; 2] {
+        [GlobalMeters(self.region_loc_x), GlobalMeters(self.region_loc_y)]
+    }
+}
+
+impl std::fmt::Display for RegionData {
+    /// Just name and location, no size.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "\"{}\" ({}, {})",
+            self.name, self.region_loc_x, self.region_loc_y
+        )
+    }
+}
+
+/// A square LOD 0 region on grid "test", for tests. Location and size in meters.
+/// Not cfg(test), because the binaries' tests use it, and they link the library as built normally.
+pub fn test_region(name: &str, x: u32, y: u32, size: u32) -> RegionData {
+    RegionData {
+        grid: "test".to_string(),
+        lod: 0,
+        region_loc_x: x,
+        region_loc_y: y,
+        region_size_x: size,
+        region_size_y: size,
+        name: name.to_string(),
+        children: Vec::new(),
+        is_water: false,
+        is_placeholder: false,
+    }
+}
+
+/// Tile size for a group which may contain regions of different sizes.
+/// This is the largest size which evenly divides every region's size and location,
+/// so every region covers a whole number of tiles. Same as the region size if the
+/// group is homogeneous, which it always is in SL.
+pub fn group_tile_size<R: Borrow<RegionData>>(group: &[R]) -> Option<(u32, u32)> {
+    if group.is_empty() {
+        return None;
+    }
+    let size_x = group.iter().map(|v| v.borrow()).fold(0, |acc, v| gcd(gcd(acc, v.region_size_x), v.region_loc_x));
+    let size_y = group.iter().map(|v| v.borrow()).fold(0, |acc, v| gcd(gcd(acc, v.region_size_y), v.region_loc_y));
+    if size_x == 0 || size_y == 0 {
+        None
+    } else {
+        Some((size_x, size_y))
+    }
+}
+
+/// Lower left and upper right corners of a group, meters. None if empty.
+pub fn group_bounds<R: Borrow<RegionData>>(group: &[R]) -> Option<((u32, u32), (u32, u32))> {
+    let mut regions = group.iter().map(|v| v.borrow());
+    let first = regions.next()?;
+    let start = ((first.region_loc_x, first.region_loc_y),
+        (first.region_loc_x + first.region_size_x, first.region_loc_y + first.region_size_y));
+    Some(regions.fold(start, |((x0, y0), (x1, y1)), v| (
+        (x0.min(v.region_loc_x), y0.min(v.region_loc_y)),
+        (x1.max(v.region_loc_x + v.region_size_x), y1.max(v.region_loc_y + v.region_size_y)),
+    )))
+}
+
+/// Get dimensions of a group, and the tile size to use for it.
+pub fn get_group_bounds<R: Borrow<RegionData>>(group: &[R]) -> Result<(((u32, u32), (u32, u32)), (u32, u32)), Error> {
+    //  Error if empty group.
+    let tile_size = group_tile_size(group).ok_or_else(|| anyhow!("Empty or zero-sized viz group"))?;
+    let bounds = group_bounds(group).ok_or_else(|| anyhow!("Empty viz group"))?;
+    Ok((bounds, tile_size))
+}
+
+/// Total area of a group, square meters.
+pub fn group_area<R: Borrow<RegionData>>(group: &[R]) -> u64 {
+    group.iter().map(|v| v.borrow()).map(|v| v.region_size_x as u64 * v.region_size_y as u64).sum()
+}
+
+#[test]
+fn test_region_data_serde() {
+    let region = RegionData { grid: "agni".to_string(), lod: 0, region_loc_x: 290304, region_loc_y: 268288,
+        region_size_x: 256, region_size_y: 256, name: "Blake Sea - Kraken".to_string(), children: Vec::new(), is_water: false, is_placeholder: false };
+    let tile = RegionData { grid: "agni".to_string(), lod: 1, region_loc_x: 290304, region_loc_y: 268288,
+        region_size_x: 512, region_size_y: 512, name: "LOD1-290304-268288".to_string(), children: vec![(290304, 268288), (290560, 268288)], is_water: false, is_placeholder: false };
+    let water = RegionData { lod: 1, name: "LOD1-290816-268288 Water".to_string(), region_loc_x: 290816, children: Vec::new(), is_water: true, ..tile.clone() };
+    for item in [&region, &tile, &water] {
+        let json = serde_json::to_string(item).expect("serialize");
+        let back: RegionData = serde_json::from_str(&json).expect("deserialize");
+        assert_eq!(&back, item);
+    }
+    //  Empty and default fields are left out.
+    let json = serde_json::to_string(&region).expect("serialize");
+    assert!(!json.contains("children"));
+    assert!(!json.contains("is_water"));
+    assert!(!json.contains("is_placeholder"));
+    //  LOD defaults to 0, for JSON from before LODs.
+    let old: RegionData = serde_json::from_str(r#"{"grid":"agni","region_loc_x":290304,"region_loc_y":268288,
+        "region_size_x":256,"region_size_y":256,"name":"Blake Sea - Kraken"}"#).expect("deserialize");
+    assert_eq!(old, region);
+}
+
+#[test]
+fn test_group_bounds_and_area() {
+    //  A 512 varregion with 256 regions east and north of it, and a 1024 beyond.
+    let group = vec![test_region("Var512", 0, 0, 512), test_region("East", 512, 0, 256), test_region("NorthEast", 512, 256, 256),
+        test_region("North", 0, 512, 256), test_region("Var1024", 768, 0, 1024)];
+    assert_eq!(group_bounds(&group), Some(((0, 0), (1792, 1024))));
+    assert_eq!(group_area(&group), 512 * 512 + 3 * 256 * 256 + 1024 * 1024);
+    assert_eq!(get_group_bounds(&group).unwrap(), (((0, 0), (1792, 1024)), (256, 256)));
+    //  Bounds don't depend on order.
+    let reversed: Vec<RegionData> = group.iter().rev().cloned().collect();
+    assert_eq!(group_bounds(&reversed), group_bounds(&group));
+    //  Nothing, or nothing of any size.
+    let empty: Vec<RegionData> = Vec::new();
+    assert_eq!((group_bounds(&empty), group_area(&empty)), (None, 0));
+    assert!(get_group_bounds(&empty).is_err());
+    assert!(get_group_bounds(&[test_region("Empty", 0, 0, 0)]).is_err());
+}
@@ -0,0 +1,169 @@
+//! replayguard.rs -- reject signed uploads sent again.
+//! Part of the Animats impostor system
+//!
+//! The signature covers the body, so a captured upload replayed verbatim
+//! still validates, and could put back terrain that has since changed.
+//! So signed uploads carry a "nonce", different for every upload, and
+//! "sent_at", Unix time in seconds, inside the signed JSON. An upload whose
+//! nonce was seen recently from the same token is a replay, error 409.
+//! One sent too long ago, or too far ahead by our clock, is error 400.
+//! That bounds how long a nonce must be remembered.
+//!
+//! Nonces are remembered in memory, most recent REPLAY_CACHE_SIZE of them.
+//! A responder restart forgets them, but sent_at still limits a replay to
+//! the skew window.
+//!
+//! For scripts from before this, enforcement can be turned off with
+//!
+//!     REPLAY_PROTECTION = off
+//!
+//! in the credentials file. Then uploads without a nonce are accepted.
+//! Uploads with one are still checked.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::Error;
+use std::collections::{HashSet, VecDeque};
+
+/// How far sent_at may be from our clock, seconds, either way.
+pub const MAX_CLOCK_SKEW_SECS: i64 = 10 * 60;
+/// Nonces remembered.
+pub const REPLAY_CACHE_SIZE: usize = 4096;
+/// Longest nonce accepted.
+const MAX_NONCE_LEN: usize = 64;
+/// Credentials key to turn enforcement off.
+const REPLAY_PROTECTION_KEY: &str = "REPLAY_PROTECTION";
+
+/// Seconds since the Unix epoch, now. What LSL's llGetUnixTime returns.
+pub fn unix_time_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Recently seen nonces, by token name.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    /// Nonce and sent_at required
+    enforced: bool,
+    /// (token name, nonce), for lookup
+    seen: HashSet<(String, String)>,
+    /// The same, oldest first, for eviction
+    order: VecDeque<(String, String)>,
+    /// Most to remember
+    capacity: usize,
+}
+
+impl ReplayGuard {
+    /// Usual new.
+    pub fn new(enforced: bool, capacity: usize) -> Self {
+        Self { enforced, seen: HashSet::new(), order: VecDeque::new(), capacity: capacity.max(1) }
+    }
+
+    /// From a key lookup, such as a credentials file. Enforced unless REPLAY_PROTECTION is off.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let enforced = match get(REPLAY_PROTECTION_KEY).map(|v| v.trim().to_lowercase()) {
+            None => true,
+            Some(v) if v == "on" => true,
+            Some(v) if v == "off" => false,
+            Some(v) => return Err(Error::BadRequest(format!("{} \"{}\" is not on or off", REPLAY_PROTECTION_KEY, v))),
+        };
+        Ok(Self::new(enforced, REPLAY_CACHE_SIZE))
+    }
+
+    /// Check an upload signed with this token, at time now, and remember its nonce.
+    /// Missing fields, when enforced, and clock skew are bad requests. A nonce seen before is a conflict.
+    pub fn check(&mut self, token_name: &str, nonce: Option<&str>, sent_at: Option<i64>, now: i64) -> Result<(), Error> {
+        let (nonce, sent_at) = match (nonce, sent_at) {
+            (Some(nonce), Some(sent_at)) => (nonce, sent_at),
+            (None, None) if !self.enforced => return Ok(()),
+            _ => return Err(Error::BadRequest("Signed uploads must have \"nonce\" and \"sent_at\"".to_string())),
+        };
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN || !nonce.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(Error::BadRequest(format!("Nonce must be 1 to {} printable ASCII characters", MAX_NONCE_LEN)));
+        }
+        let skew = sent_at.abs_diff(now);
+        if skew > MAX_CLOCK_SKEW_SECS as u64 {
+            return Err(Error::BadRequest(format!("sent_at is {} seconds from server time, more than {} allowed", skew, MAX_CLOCK_SKEW_SECS)));
+        }
+        let key = (token_name.to_uppercase(), nonce.to_string());
+        if self.seen.contains(&key) {
+            return Err(Error::Conflict(format!("Nonce \"{}\" was already used", nonce)));
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        Ok(())
+    }
+
+    /// Forget a nonce, so the upload can be retried with it. For when we failed, not the client.
+    pub fn forget(&mut self, token_name: &str, nonce: &str) {
+        let key = (token_name.to_uppercase(), nonce.to_string());
+        if self.seen.remove(&key) {
+            self.order.retain(|k| k != &key);
+        }
+    }
+}
+
+#[test]
+fn test_replay_duplicate_nonce() {
+    let now = 1_750_000_000;
+    let mut guard = ReplayGuard::new(true, 3);
+    guard.check("UPLOADER_1", Some("a1"), Some(now), now).expect("first");
+    //  Same nonce again is a conflict, whatever the case of the token name.
+    let err = guard.check("uploader_1", Some("a1"), Some(now), now).expect_err("replay accepted");
+    assert_eq!(err.http_status(), 409);
+    //  Another token's nonces are its own.
+    guard.check("UPLOADER_2", Some("a1"), Some(now), now).expect("other token");
+    //  Oldest forgotten when full.
+    guard.check("UPLOADER_1", Some("a2"), Some(now), now).unwrap();
+    guard.check("UPLOADER_1", Some("a3"), Some(now), now).unwrap();
+    guard.check("UPLOADER_1", Some("a1"), Some(now), now).expect("evicted");
+    //  Forgotten on our failure, so a retry works.
+    guard.forget("UPLOADER_1", "a3");
+    guard.check("UPLOADER_1", Some("a3"), Some(now), now).expect("retry");
+    //  Junk nonces.
+    for bad in ["", "has space", &"x".repeat(65)] {
+        assert_eq!(guard.check("UPLOADER_1", Some(bad), Some(now), now).expect_err(bad).http_status(), 400);
+    }
+}
+
+#[test]
+fn test_replay_clock_skew() {
+    let now = 1_750_000_000;
+    let mut guard = ReplayGuard::new(true, REPLAY_CACHE_SIZE);
+    //  Right at the limit, either way, is accepted. One second past is not.
+    guard.check("T", Some("early-ok"), Some(now - MAX_CLOCK_SKEW_SECS), now).expect("early edge");
+    guard.check("T", Some("late-ok"), Some(now + MAX_CLOCK_SKEW_SECS), now).expect("late edge");
+    for (nonce, sent_at) in [("early", now - MAX_CLOCK_SKEW_SECS - 1), ("late", now + MAX_CLOCK_SKEW_SECS + 1), ("zero", 0), ("huge", i64::MAX), ("negative", i64::MIN)] {
+        let err = guard.check("T", Some(nonce), Some(sent_at), now).expect_err(nonce);
+        assert_eq!(err.http_status(), 400, "{}", nonce);
+    }
+    //  Rejected for skew, so not remembered.
+    guard.check("T", Some("early"), Some(now), now).expect("not remembered");
+}
+
+#[test]
+fn test_replay_enforcement() {
+    let now = 1_750_000_000;
+    //  Enforced, both fields required.
+    let mut guard = ReplayGuard::new_from_lookup(|_| None).unwrap();
+    assert_eq!(guard.check("T", None, None, now).expect_err("no nonce").http_status(), 400);
+    assert_eq!(guard.check("T", Some("n"), None, now).expect_err("no sent_at").http_status(), 400);
+    assert_eq!(guard.check("T", None, Some(now), now).expect_err("no nonce").http_status(), 400);
+    //  Off, legacy uploads go through, as often as sent.
+    let mut legacy = ReplayGuard::new_from_lookup(|k| (k == REPLAY_PROTECTION_KEY).then(|| " Off ".to_string())).unwrap();
+    legacy.check("T", None, None, now).expect("legacy");
+    legacy.check("T", None, None, now).expect("legacy again");
+    //  Still checked when a nonce is sent.
+    legacy.check("T", Some("n"), Some(now), now).unwrap();
+    assert_eq!(legacy.check("T", Some("n"), Some(now), now).expect_err("replay").http_status(), 409);
+    assert!(ReplayGuard::new_from_lookup(|_| Some("maybe".to_string())).is_err());
+}
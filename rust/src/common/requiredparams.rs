@@ -0,0 +1,112 @@
+//! requiredparams.rs -- check the FCGI params a handler needs, all at once.
+//! Part of the Animats impostor system
+//!
+//! Which params the web server passes depends on its configuration.
+//! A handler that finds them missing one at a time reports only the first,
+//! and reports it as a server error. This collects every missing or blank
+//! one, so the reply is one 400 naming them all.
+//!
+//! QUERY_STRING is different. Some nginx configurations leave it out for a
+//! URL with no query. That's the same as an empty query, so it's never
+//! missing. Whatever the query itself needs, such as a grid, is checked by
+//! the handler, as before.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::Error;
+use crate::Request;
+use std::collections::HashMap;
+
+/// Collects missing required params.
+#[derive(Debug)]
+pub struct RequiredParams<'a> {
+    /// The request's params, if any arrived.
+    params: Option<&'a HashMap<String, String>>,
+    /// Names of required params absent or blank, in the order asked for.
+    missing: Vec<String>,
+}
+
+impl<'a> RequiredParams<'a> {
+    /// Start checking a request's params.
+    pub fn new(request: &'a Request) -> Self {
+        Self::new_from_params(request.params.as_ref())
+    }
+
+    /// Start checking a set of params.
+    pub fn new_from_params(params: Option<&'a HashMap<String, String>>) -> Self {
+        Self { params, missing: Vec::new() }
+    }
+
+    /// A param which must be present and not blank. If it isn't, it's noted,
+    /// and "" returned so checking can go on. finish reports it.
+    pub fn require(&mut self, name: &str) -> &'a str {
+        match self.params.and_then(|params| params.get(name)).map(|v| v.as_str()) {
+            Some(value) if !value.trim().is_empty() => value,
+            _ => {
+                self.missing.push(name.to_string());
+                ""
+            }
+        }
+    }
+
+    /// The query string. Absent is empty.
+    pub fn query_string(&self) -> &'a str {
+        self.params.map(query_string).unwrap_or("")
+    }
+
+    /// The params, if everything required is there. Otherwise a bad request naming everything missing.
+    pub fn finish(self) -> Result<&'a HashMap<String, String>, Error> {
+        match (self.params, self.missing.is_empty()) {
+            (Some(params), true) => Ok(params),
+            (None, true) => Err(Error::BadRequest("No FCGI parameters".to_string())),
+            (_, false) => Err(Error::BadRequest(format!("Missing FCGI parameters: {}", self.missing.join(", ")))),
+        }
+    }
+}
+
+/// The query string from a set of params. Absent is empty.
+pub fn query_string(params: &HashMap<String, String>) -> &str {
+    params.get("QUERY_STRING").map(|s| s.as_str()).unwrap_or("")
+}
+
+#[test]
+fn test_required_params() {
+    let params = |pairs: &[(&str, &str)]| -> HashMap<String, String> { pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() };
+    //  All there.
+    let all = params(&[("REQUEST_METHOD", "POST"), ("CONTENT_TYPE", "application/json"), ("QUERY_STRING", "grid=agni")]);
+    let mut required = RequiredParams::new_from_params(Some(&all));
+    assert_eq!(required.require("REQUEST_METHOD"), "POST");
+    assert_eq!(required.require("CONTENT_TYPE"), "application/json");
+    assert_eq!(required.query_string(), "grid=agni");
+    assert_eq!(required.finish().expect("all present").len(), 3);
+    //  Several missing or blank, all reported at once, as a client error.
+    let some = params(&[("REQUEST_METHOD", " "), ("HTTP_USER_AGENT", "LSL")]);
+    let mut required = RequiredParams::new_from_params(Some(&some));
+    assert_eq!(required.require("REQUEST_METHOD"), "");
+    assert_eq!(required.require("CONTENT_TYPE"), "");
+    let err = required.finish().expect_err("missing accepted");
+    assert_eq!(err.http_status(), 400);
+    assert_eq!(err.to_string(), "Bad request: Missing FCGI parameters: REQUEST_METHOD, CONTENT_TYPE");
+    //  No params at all.
+    let mut required = RequiredParams::new_from_params(None);
+    required.require("REQUEST_METHOD");
+    assert!(required.finish().expect_err("no params").to_string().contains("REQUEST_METHOD"));
+    assert!(RequiredParams::new_from_params(None).finish().is_err());
+}
+
+#[test]
+fn test_query_string_absent_or_empty() {
+    //  Absent and empty are the same, and neither is missing.
+    let absent: HashMap<String, String> = [("REQUEST_METHOD".to_string(), "GET".to_string())].into_iter().collect();
+    let mut empty = absent.clone();
+    empty.insert("QUERY_STRING".to_string(), String::new());
+    for params in [&absent, &empty] {
+        assert_eq!(query_string(params), "");
+        let mut required = RequiredParams::new_from_params(Some(params));
+        required.require("REQUEST_METHOD");
+        assert_eq!(required.query_string(), "");
+        assert!(required.finish().is_ok());
+    }
+}
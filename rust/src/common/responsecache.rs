@@ -0,0 +1,272 @@
+//! responsecache.rs -- last good replies to impostor queries, reused while still current.
+//! Part of the Animats impostor system
+//!
+//! Every viewer entering a popular region asks downloadimpostor the same
+//! question, such as the impostors for one viz group. Each answer is an SQL
+//! query and a JSON serialization. So replies are kept, by the normalized
+//! query, with their ETag and the grid's generation watermark, the grid's
+//! generation serial when the reply was made.
+//!
+//! Before a cached reply is reused, the watermark is read again, with one
+//! cheap primary key lookup. Every promotion, upload, and admin deletion bumps
+//! it, so a changed watermark means the reply may be out of date, and it's dropped. If the
+//! watermark can't be had, replies are reused for CACHE_TTL only. Either
+//! way, nothing is reused after MAX_CACHE_AGE, since a region deletion
+//! changes replies without changing generations.
+//!
+//! Total body size is capped. When over, the least recently used replies go first.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::{anyhow, Error};
+use crate::db::Db;
+use crate::hashing::hash_bytes;
+use mysql::params;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Reuse time when the watermark isn't available.
+pub const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Longest any reply is reused.
+pub const MAX_CACHE_AGE: Duration = Duration::from_secs(10 * 60);
+/// Cache size if not configured, megabytes.
+pub const DEFAULT_RESPONSE_CACHE_MB: usize = 64;
+/// Credentials key for the cache size, megabytes. 0 turns the cache off.
+pub const RESPONSE_CACHE_MB_KEY: &str = "RESPONSE_CACHE_MB";
+
+/// A grid's generation serial.
+const SQL_GENERATION_WATERMARK: &str = r"SELECT generation FROM grid_generations WHERE grid = :grid";
+
+/// A query, normalized, so the same question asked differently is one entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResponseCacheKey {
+    /// Grid, lower case
+    pub grid: String,
+    /// Region location, meters, if asked for one region. Not with a viz group.
+    pub coords: Option<(u32, u32)>,
+    /// Viz group, if asked for one
+    pub viz_group: Option<u32>,
+    /// Only impostors newer than this generation
+    pub since_generation: Option<u32>,
+    /// Reply format version
+    pub version: u32,
+}
+
+impl ResponseCacheKey {
+    /// Key for a query. A viz group query ignores coordinates, so they're dropped.
+    pub fn new(grid: &str, coords: Option<(u32, u32)>, viz_group: Option<u32>, since_generation: Option<u32>, version: u32) -> Self {
+        Self { grid: grid.to_lowercase(), coords: coords.filter(|_| viz_group.is_none()), viz_group, since_generation, version }
+    }
+}
+
+/// One kept reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    /// Reply body, as sent
+    pub body: String,
+    /// ETag for the body. Quoted, as HTTP wants.
+    pub etag: String,
+    /// Grid's generation watermark when made, if known
+    watermark: Option<u32>,
+    /// When made
+    stored_at: Instant,
+    /// When last used, as a count of cache operations, for eviction order
+    last_used: u64,
+}
+
+impl CachedResponse {
+    /// Can this still be used?
+    fn is_current(&self, watermark: Option<u32>, now: Instant) -> bool {
+        let age = now.saturating_duration_since(self.stored_at);
+        match (self.watermark, watermark) {
+            (Some(then), Some(now_watermark)) => then == now_watermark && age < MAX_CACHE_AGE,
+            _ => age < CACHE_TTL,
+        }
+    }
+}
+
+/// ETag for a reply body.
+pub fn etag_for(body: &[u8]) -> String {
+    format!("\"{}\"", &hash_bytes(body)[..16])
+}
+
+/// Does an If-None-Match header match this ETag?
+pub fn etag_matches(etag: &str, if_none_match: &str) -> bool {
+    if_none_match.split(',').map(|tag| tag.trim().trim_start_matches("W/")).any(|tag| tag == etag || tag == "*")
+}
+
+/// The grid's generation watermark. None if it can't be read, such as before the grid_generations table.
+pub fn generation_watermark(conn: &mut dyn Db, grid: &str) -> Option<u32> {
+    match conn.exec_first(SQL_GENERATION_WATERMARK, params! { "grid" => grid.to_lowercase() }) {
+        Ok(row) => row.and_then(|row| row.get::<Option<u32>>(0).ok().flatten()),
+        Err(e) => {
+            log::warn!("Generation watermark for grid \"{}\" not available, cached replies expire by time: {:?}", grid, e);
+            None
+        }
+    }
+}
+
+/// Replies, by query.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: HashMap<ResponseCacheKey, CachedResponse>,
+    /// Body bytes held
+    bytes: usize,
+    /// Most body bytes to hold
+    cap_bytes: usize,
+    /// Counts cache operations, for least recently used
+    clock: u64,
+}
+
+impl ResponseCache {
+    /// Usual new. Holds at most cap_bytes of reply bodies. 0 holds nothing.
+    pub fn new(cap_bytes: usize) -> Self {
+        Self { entries: HashMap::new(), bytes: 0, cap_bytes, clock: 0 }
+    }
+
+    /// From a key lookup, such as a credentials file.
+    pub fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let mb = match get(RESPONSE_CACHE_MB_KEY) {
+            Some(v) => v.trim().parse::<usize>().map_err(|_| anyhow!("{} \"{}\" is not a number of megabytes", RESPONSE_CACHE_MB_KEY, v))?,
+            None => DEFAULT_RESPONSE_CACHE_MB,
+        };
+        Ok(Self::new(mb.saturating_mul(1024 * 1024)))
+    }
+
+    /// Reply for this query, if there is one still current at this watermark. One that isn't is dropped.
+    pub fn get(&mut self, key: &ResponseCacheKey, watermark: Option<u32>, now: Instant) -> Option<&CachedResponse> {
+        if !self.entries.get(key)?.is_current(watermark, now) {
+            self.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry)
+    }
+
+    /// Keep a reply. Least recently used replies are dropped to make room.
+    /// One too big for the whole cache isn't kept. Returns the ETag.
+    pub fn insert(&mut self, key: ResponseCacheKey, body: String, watermark: Option<u32>, now: Instant) -> String {
+        let etag = etag_for(body.as_bytes());
+        self.remove(&key);
+        if body.len() > self.cap_bytes {
+            return etag;
+        }
+        while self.bytes + body.len() > self.cap_bytes {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone()) else { break };
+            self.remove(&oldest);
+        }
+        self.clock += 1;
+        self.bytes += body.len();
+        self.entries.insert(key, CachedResponse { body, etag: etag.clone(), watermark, stored_at: now, last_used: self.clock });
+        etag
+    }
+
+    /// Forget one reply.
+    fn remove(&mut self, key: &ResponseCacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.body.len();
+        }
+    }
+
+    /// Replies held.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Nothing held?
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Body bytes held.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+fn test_key(viz_group: u32) -> ResponseCacheKey {
+    ResponseCacheKey::new("Agni", None, Some(viz_group), None, 1)
+}
+
+#[test]
+fn test_response_cache_generation() {
+    let start = Instant::now();
+    let mut cache = ResponseCache::new(1024);
+    let etag = cache.insert(test_key(1), "[1]".to_string(), Some(7), start);
+    //  Same watermark, reused, well past the TTL.
+    let later = start + CACHE_TTL * 3;
+    let hit = cache.get(&test_key(1), Some(7), later).expect("hit");
+    assert_eq!((hit.body.as_str(), hit.etag.as_str()), ("[1]", etag.as_str()));
+    //  Normalized. Grid case and coordinates with a viz group don't matter.
+    assert!(cache.get(&ResponseCacheKey::new("AGNI", Some((256, 512)), Some(1), None, 1), Some(7), later).is_some());
+    assert!(cache.get(&ResponseCacheKey::new("agni", None, Some(1), None, 2), Some(7), later).is_none());
+    //  A generation bump drops it.
+    assert!(cache.get(&test_key(1), Some(8), later).is_none());
+    assert!(cache.is_empty());
+    assert_eq!(cache.bytes(), 0);
+    //  Even with an unchanged watermark, nothing lasts past the maximum age.
+    cache.insert(test_key(2), "[2]".to_string(), Some(8), start);
+    assert!(cache.get(&test_key(2), Some(8), start + MAX_CACHE_AGE).is_none());
+}
+
+#[test]
+fn test_response_cache_ttl() {
+    let start = Instant::now();
+    let mut cache = ResponseCache::new(1024);
+    //  No watermark when stored, or none now, falls back to the TTL.
+    cache.insert(test_key(1), "[1]".to_string(), None, start);
+    cache.insert(test_key(2), "[2]".to_string(), Some(3), start);
+    assert!(cache.get(&test_key(1), None, start + CACHE_TTL / 2).is_some());
+    assert!(cache.get(&test_key(2), None, start + CACHE_TTL / 2).is_some());
+    assert!(cache.get(&test_key(1), Some(3), start + CACHE_TTL).is_none());
+    assert!(cache.get(&test_key(2), None, start + CACHE_TTL).is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_response_cache_size_cap() {
+    let start = Instant::now();
+    let body = |n: usize| "x".repeat(n);
+    let mut cache = ResponseCache::new(100);
+    cache.insert(test_key(1), body(40), Some(1), start);
+    cache.insert(test_key(2), body(40), Some(1), start);
+    //  Using 1 makes 2 the least recently used, so 2 goes first.
+    assert!(cache.get(&test_key(1), Some(1), start).is_some());
+    cache.insert(test_key(3), body(40), Some(1), start);
+    assert_eq!((cache.len(), cache.bytes()), (2, 80));
+    assert!(cache.get(&test_key(2), Some(1), start).is_none());
+    //  Then 1, the older of what's left. A replacement takes the old one's place.
+    cache.insert(test_key(3), body(50), Some(1), start);
+    cache.insert(test_key(4), body(50), Some(1), start);
+    assert_eq!((cache.len(), cache.bytes()), (2, 100));
+    assert!(cache.get(&test_key(1), Some(1), start).is_none());
+    assert!(cache.get(&test_key(3), Some(1), start).is_some());
+    //  Too big to keep at all, and nothing else is pushed out for it.
+    let etag = cache.insert(test_key(5), body(101), Some(1), start);
+    assert_eq!(etag, etag_for(body(101).as_bytes()));
+    assert_eq!((cache.len(), cache.bytes()), (2, 100));
+    //  Size zero is off.
+    let mut off = ResponseCache::new_from_lookup(|k| (k == RESPONSE_CACHE_MB_KEY).then(|| "0".to_string())).unwrap();
+    off.insert(test_key(1), body(1), Some(1), start);
+    assert!(off.is_empty());
+    assert!(ResponseCache::new_from_lookup(|_| Some("lots".to_string())).is_err());
+    assert!(etag_matches(&etag, &format!("\"0000\", W/{}", etag)));
+}
+
+#[test]
+fn test_generation_watermark() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::UInt(12)])], vec![DbRow(vec![DbValue::Null])]]);
+    assert_eq!(generation_watermark(&mut fake, "Agni"), Some(12));
+    //  Nothing promoted on the grid yet.
+    assert_eq!(generation_watermark(&mut fake, "agni"), None);
+    assert!(fake.sql()[0].starts_with("SELECT generation FROM grid_generations"));
+    //  Can't be read, so none.
+    fake.fail_on = Some("grid_generations".to_string());
+    assert_eq!(generation_watermark(&mut fake, "agni"), None);
+}
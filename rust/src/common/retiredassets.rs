@@ -0,0 +1,259 @@
+//! retiredassets.rs -- impostor assets superseded by a newer generation.
+//! Part of the Animats impostor system
+//!
+//! Every generation of impostors is uploaded as new sculpt, mesh, and texture
+//! assets, which end up in the uploader avatar's inventory. When promotion
+//! replaces a grid's impostors, the old generation's assets that the new one
+//! doesn't reuse are no longer referenced by anything. They're recorded in
+//! retired_assets, with when they were retired, so the in-world cleanup script
+//! can delete them from inventory. An asset which comes back into use in a later
+//! generation is taken off the list.
+//!
+//! Asset kinds are the asset name prefixes, RS, RM, RTn, and REn. See assetname.
+//!
+//! Rows are purged once they're old enough that the cleanup script must have seen them.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::Error;
+use mysql::params;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use uuid::Uuid;
+use crate::db::Db;
+use crate::grid::canonical;
+use crate::{AssetKind, faces_from_json, string_opt_to_uuid};
+
+/// The assets of a grid's live impostors.
+const SQL_LIVE_ASSETS: &str = r"SELECT sculpt_uuid, mesh_uuid, faces_json FROM region_impostors WHERE grid = :grid";
+/// The assets of a grid's next generation.
+const SQL_NEW_ASSETS: &str = r"SELECT sculpt_uuid, mesh_uuid, faces_json FROM initial_impostors WHERE grid = :grid";
+/// Assets already retired on a grid.
+const SQL_RETIRED_UUIDS: &str = r"SELECT asset_uuid FROM retired_assets WHERE grid = :grid";
+/// Retire one asset. Already retired keeps its original time.
+const SQL_RETIRE: &str = r"INSERT IGNORE INTO retired_assets (grid, asset_uuid, asset_kind, retirement_time)
+    VALUES (:grid, :asset_uuid, :asset_kind, NOW())";
+/// Back in use.
+const SQL_UNRETIRE: &str = r"DELETE FROM retired_assets WHERE grid = :grid AND asset_uuid = :asset_uuid";
+/// Assets retired since a time.
+const SQL_GET_RETIRED: &str = r"SELECT asset_uuid, asset_kind, retirement_time FROM retired_assets
+    WHERE grid = :grid AND retirement_time >= :since
+    ORDER BY retirement_time, asset_uuid";
+/// Forget assets retired long ago, on every grid.
+const SQL_PURGE: &str = r"DELETE FROM retired_assets WHERE retirement_time < NOW() - INTERVAL :days DAY";
+
+/// Since the beginning.
+const SINCE_ALWAYS: &str = "1970-01-01 00:00:00";
+
+/// Every asset one impostor uses.
+/// Unreadable faces JSON is logged, and its textures left out.
+pub fn impostor_assets(sculpt_uuid: Option<String>, mesh_uuid: Option<String>, faces_json: &str) -> Result<Vec<(Uuid, AssetKind)>, Error> {
+    let mut assets = Vec::new();
+    if let Some(uuid) = string_opt_to_uuid(sculpt_uuid)? {
+        assets.push((uuid, AssetKind::Sculpt));
+    }
+    if let Some(uuid) = string_opt_to_uuid(mesh_uuid)? {
+        assets.push((uuid, AssetKind::Mesh));
+    }
+    match faces_from_json(faces_json) {
+        Ok(faces) => {
+            for (n, face) in faces.iter().enumerate() {
+                if !face.base_texture_uuid.is_nil() {
+                    assets.push((face.base_texture_uuid, AssetKind::Texture(n as u8)));
+                }
+                if let Some(uuid) = face.emissive_texture_uuid.filter(|u| !u.is_nil()) {
+                    assets.push((uuid, AssetKind::Emissive(n as u8)));
+                }
+            }
+        }
+        Err(e) => log::warn!("Faces JSON unreadable, its textures can't be retired: {}", e),
+    }
+    Ok(assets)
+}
+
+/// Assets of the old generation not used by the new one. Each once, in UUID order.
+pub fn retired_between(old: &[(Uuid, AssetKind)], new: &[(Uuid, AssetKind)]) -> Vec<(Uuid, AssetKind)> {
+    let in_use: HashSet<Uuid> = new.iter().map(|(uuid, _)| *uuid).collect();
+    let retired: BTreeMap<Uuid, AssetKind> = old.iter()
+        .filter(|(uuid, _)| !in_use.contains(uuid))
+        .map(|(uuid, kind)| (*uuid, *kind))
+        .collect();
+    retired.into_iter().collect()
+}
+
+/// Every asset in one of the impostor tables, for a grid.
+fn grid_assets(conn: &mut dyn Db, sql: &str, grid: &str) -> Result<Vec<(Uuid, AssetKind)>, Error> {
+    let rows = conn.exec_map(sql, params! { "grid" => grid }, |row| Ok((row.get(0)?, row.get(1)?, row.get::<String>(2)?)))?;
+    let mut assets = Vec::new();
+    for (sculpt_uuid, mesh_uuid, faces_json) in rows {
+        assets.extend(impostor_assets(sculpt_uuid, mesh_uuid, &faces_json)?);
+    }
+    Ok(assets)
+}
+
+/// Record the live generation's assets which the next generation drops,
+/// and take any the next generation reuses off the list.
+/// Call inside promotion's transaction, before region_impostors is replaced.
+/// Returns the number of assets retired.
+pub fn retire_superseded(conn: &mut dyn Db, grid: &str) -> Result<usize, Error> {
+    let grid = canonical(grid);
+    let old = grid_assets(conn, SQL_LIVE_ASSETS, &grid)?;
+    let new = grid_assets(conn, SQL_NEW_ASSETS, &grid)?;
+    let retired = retired_between(&old, &new);
+    for (uuid, kind) in &retired {
+        conn.exec_drop(SQL_RETIRE, params! { "grid" => &grid, "asset_uuid" => uuid.to_string(), "asset_kind" => kind.prefix() })?;
+    }
+    let in_use: HashSet<String> = new.iter().map(|(uuid, _)| uuid.to_string()).collect();
+    let previously_retired = conn.exec_map(SQL_RETIRED_UUIDS, params! { "grid" => &grid }, |row| row.get::<String>(0))?;
+    for uuid in previously_retired.iter().filter(|uuid| in_use.contains(uuid.trim())) {
+        log::info!("Asset {} on \"{}\" is back in use.", uuid, grid);
+        conn.exec_drop(SQL_UNRETIRE, params! { "grid" => &grid, "asset_uuid" => uuid })?;
+    }
+    log::info!("Grid \"{}\": {} assets retired.", grid, retired.len());
+    Ok(retired.len())
+}
+
+/// Retire these assets, once no longer used by any impostor of the grid in either table.
+/// For impostors removed outside promotion. Call inside the removal's transaction, after the rows are gone.
+/// Returns the number of assets retired.
+pub fn retire_unused(conn: &mut dyn Db, grid: &str, assets: &[(Uuid, AssetKind)]) -> Result<usize, Error> {
+    let grid = canonical(grid);
+    if assets.is_empty() {
+        return Ok(0);
+    }
+    let mut in_use = grid_assets(conn, SQL_LIVE_ASSETS, &grid)?;
+    in_use.extend(grid_assets(conn, SQL_NEW_ASSETS, &grid)?);
+    let retired = retired_between(assets, &in_use);
+    for (uuid, kind) in &retired {
+        conn.exec_drop(SQL_RETIRE, params! { "grid" => &grid, "asset_uuid" => uuid.to_string(), "asset_kind" => kind.prefix() })?;
+    }
+    Ok(retired.len())
+}
+
+/// One retired asset, as sent to the cleanup script.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetiredAsset {
+    /// Asset UUID
+    pub uuid: String,
+    /// Asset name prefix: RS, RM, RTn, or REn.
+    pub kind: String,
+    /// When retired, "YYYY-MM-DD HH:MM:SS", UTC.
+    pub retired: String,
+}
+
+/// Assets retired on a grid since a time, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetiredAssetsReply {
+    /// Grid name
+    pub grid: String,
+    /// Start time used, "YYYY-MM-DD HH:MM:SS"
+    pub since: String,
+    /// Oldest first
+    pub assets: Vec<RetiredAsset>,
+}
+
+/// Start time from a request, as "YYYY-MM-DD HH:MM:SS", which sorts and compares as MySQL does.
+/// Accepts a date, or a date and time separated by a space or T. None is since the beginning.
+pub fn parse_since(since: Option<&str>) -> Result<String, crate::Error> {
+    let Some(since) = since.map(|s| s.trim()) else {
+        return Ok(SINCE_ALWAYS.to_string());
+    };
+    let bad = || crate::Error::BadRequest(format!("\"since\" is \"{}\", expected YYYY-MM-DD or YYYY-MM-DD HH:MM:SS", since));
+    let (date, time) = match since.split_once([' ', 'T']) {
+        Some((date, time)) => (date, time),
+        None => (since, "00:00:00"),
+    };
+    let numbers = |s: &str, sep: char, limits: &[(u32, u32)]| -> Option<Vec<u32>> {
+        let parts: Vec<&str> = s.split(sep).collect();
+        if parts.len() != limits.len() {
+            return None;
+        }
+        parts.iter().zip(limits).map(|(part, &(min, max))| {
+            part.parse::<u32>().ok().filter(|n| part.len() >= 2 && part.chars().all(|c| c.is_ascii_digit()) && (min..=max).contains(n))
+        }).collect()
+    };
+    let date = numbers(date, '-', &[(1970, 9999), (1, 12), (1, 31)]).ok_or_else(bad)?;
+    let time = numbers(time, ':', &[(0, 23), (0, 59), (0, 59)]).ok_or_else(bad)?;
+    Ok(format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", date[0], date[1], date[2], time[0], time[1], time[2]))
+}
+
+/// Assets retired on a grid at or after since, which must come from parse_since.
+pub fn get_retired_assets(conn: &mut dyn Db, grid: &str, since: &str) -> Result<RetiredAssetsReply, Error> {
+    let grid = canonical(grid);
+    let assets = conn.exec_map(SQL_GET_RETIRED, params! { "grid" => &grid, "since" => since }, |row| Ok(RetiredAsset {
+        uuid: row.get::<String>(0)?.trim().to_string(),
+        kind: row.get(1)?,
+        retired: row.get(2)?,
+    }))?;
+    Ok(RetiredAssetsReply { grid, since: since.to_string(), assets })
+}
+
+/// Forget assets retired more than days ago. Returns the number forgotten.
+pub fn purge_retired_assets(conn: &mut dyn Db, days: u32) -> Result<u64, Error> {
+    let purged = conn.exec_drop(SQL_PURGE, params! { "days" => days })?;
+    log::info!("Purged {} retired assets older than {} days.", purged, days);
+    Ok(purged)
+}
+
+#[cfg(test)]
+fn test_uuid(n: u128) -> Uuid {
+    Uuid::from_u128(n)
+}
+
+#[test]
+fn test_retired_between() {
+    let (a, b, c, d) = (test_uuid(1), test_uuid(2), test_uuid(3), test_uuid(4));
+    let old = vec![(a, AssetKind::Sculpt), (b, AssetKind::Texture(0)), (c, AssetKind::Texture(1)), (b, AssetKind::Texture(0))];
+    //  b reused, c moved to another face, d new.
+    let new = vec![(b, AssetKind::Texture(0)), (c, AssetKind::Texture(3)), (d, AssetKind::Mesh)];
+    assert_eq!(retired_between(&old, &new), vec![(a, AssetKind::Sculpt)]);
+    //  Nothing reused. Duplicates retired once.
+    assert_eq!(retired_between(&old, &[]), vec![(a, AssetKind::Sculpt), (b, AssetKind::Texture(0)), (c, AssetKind::Texture(1))]);
+    //  First generation, nothing to retire.
+    assert!(retired_between(&[], &new).is_empty());
+    assert!(retired_between(&old, &old).is_empty());
+}
+
+#[test]
+fn test_parse_since() {
+    assert_eq!(parse_since(None).unwrap(), SINCE_ALWAYS);
+    assert_eq!(parse_since(Some("2026-03-01")).unwrap(), "2026-03-01 00:00:00");
+    assert_eq!(parse_since(Some(" 2026-03-01 14:05:09 ")).unwrap(), "2026-03-01 14:05:09");
+    assert_eq!(parse_since(Some("2026-03-01T14:05:09")).unwrap(), "2026-03-01 14:05:09");
+    for bad in ["", "yesterday", "2026-13-01", "2026-03-32", "2026-3-1", "2026-03-01 25:00:00", "2026-03-01 12:00", "1969-12-31",
+        "2026-03-01; DROP TABLE retired_assets", "+2026-03-01"] {
+        let e = parse_since(Some(bad)).expect_err(bad);
+        assert_eq!(e.http_status(), 400, "{}", bad);
+    }
+}
+
+#[test]
+fn test_retire_and_list() {
+    use crate::db::{DbRow, DbValue, FakeDb};
+    let row = |sculpt: Option<u128>, mesh: Option<u128>| DbRow(vec![
+        sculpt.map(|n| DbValue::text(&test_uuid(n).to_string())).unwrap_or(DbValue::Null),
+        mesh.map(|n| DbValue::text(&test_uuid(n).to_string())).unwrap_or(DbValue::Null),
+        DbValue::text("[]")]);
+    //  Live has sculpts 1 and 2, new has 2 and mesh 3, and 3 was retired before.
+    let mut fake = FakeDb::new_with_results(vec![vec![row(Some(1), None), row(Some(2), None)], vec![row(Some(2), None), row(None, Some(3))],
+        vec![DbRow(vec![DbValue::text(&test_uuid(3).to_string())])]]);
+    assert_eq!(retire_superseded(&mut fake, "Agni").unwrap(), 1);
+    let sql = fake.sql();
+    assert!(sql[2].starts_with("INSERT IGNORE INTO retired_assets"), "{}", sql[2]);
+    assert!(sql[4].starts_with("DELETE FROM retired_assets WHERE grid = :grid AND asset_uuid"), "{}", sql[4]);
+    assert_eq!(sql.len(), 5);
+    //  Since filter goes to the query as given.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::text(&test_uuid(1).to_string()), DbValue::text("RS"),
+        DbValue::text("2026-03-02 10:00:00")])]]);
+    let since = parse_since(Some("2026-03-01")).unwrap();
+    let reply = get_retired_assets(&mut fake, "AGNI", &since).unwrap();
+    assert_eq!((reply.grid.as_str(), reply.since.as_str(), reply.assets.len()), ("agni", "2026-03-01 00:00:00", 1));
+    assert_eq!(reply.assets[0].kind, "RS");
+    assert!(fake.sql()[0].contains("retirement_time >= :since"));
+    match &fake.statements[0].1 {
+        mysql::Params::Named(named) => assert_eq!(named.len(), 2),
+        _ => panic!("Expected named parameters"),
+    }
+}
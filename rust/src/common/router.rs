@@ -0,0 +1,181 @@
+//! router.rs -- one FCGI responder, several endpoints.
+//! Part of the Animats impostor system
+//!
+//! Each server program used to be its own responder, with its own
+//! process pool, database connection and metrics. A Router holds
+//! several handlers and passes each request to one of them by path,
+//! so one process can serve them all.
+//!
+//! The path is PATH_INFO if the web server set it, else SCRIPT_NAME,
+//! without a trailing ".fcgi" or "/". A route matches if the path ends
+//! with it, so "/uploadterrain" matches both "/uploadterrain.fcgi" and
+//! "/cgi/maptools.fcgi/uploadterrain". The longest match wins. No match
+//! is a 404.
+//!
+//! The route is picked in on_params, so a sub-handler's on_stdin_chunk
+//! sees its own uploads as they arrive.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::Error;
+use std::collections::HashMap;
+use std::io::Write;
+use crate::{Handler, Request, Response};
+
+/// Handlers, by path.
+#[derive(Default)]
+pub struct Router {
+    /// Path suffix and its handler
+    routes: Vec<(String, Box<dyn Handler>)>,
+    /// Route for the request in progress, if any.
+    current: Option<usize>,
+}
+
+impl Router {
+    /// Add a handler for a path, such as "/uploadterrain".
+    pub fn add(mut self, route: &str, handler: Box<dyn Handler>) -> Self {
+        let route = route.trim_end_matches('/');
+        let route = if route.starts_with('/') { route.to_string() } else { format!("/{}", route) };
+        self.routes.push((route, handler));
+        self
+    }
+
+    /// Routes, in the order added.
+    pub fn routes(&self) -> Vec<&str> {
+        self.routes.iter().map(|(route, _)| route.as_str()).collect()
+    }
+
+    /// The path a request is for, from its params.
+    pub fn request_path(params: &HashMap<String, String>) -> String {
+        let path = params.get("PATH_INFO").filter(|p| !p.is_empty()).or_else(|| params.get("SCRIPT_NAME"));
+        let path = path.map(|p| p.as_str()).unwrap_or("").trim_end_matches('/');
+        path.strip_suffix(".fcgi").unwrap_or(path).to_string()
+    }
+
+    /// Which route serves a path. The longest matching suffix.
+    fn find(&self, path: &str) -> Option<usize> {
+        self.routes.iter().enumerate()
+            .filter(|(_, (route, _))| path.ends_with(route.as_str()))
+            .max_by_key(|(_, (route, _))| route.len())
+            .map(|(n, _)| n)
+    }
+
+    /// Route for a request. Uses on_params's choice if made, since the caller may not call that.
+    fn route_for(&self, request: &Request) -> Option<usize> {
+        self.current.or_else(|| request.params.as_ref().and_then(|params| self.find(&Self::request_path(params))))
+    }
+}
+
+impl Handler for Router {
+    /// Dispatch to the route, or reply 404.
+    fn handler(&mut self, out: &mut dyn Write, request: &Request, env: &HashMap<String, String>) -> Result<(), Error> {
+        match self.route_for(request) {
+            Some(n) => {
+                self.current = None;
+                self.routes[n].1.handler(out, request, env)
+            }
+            None => {
+                let path = request.params.as_ref().map(Self::request_path).unwrap_or_default();
+                log::warn!("No route for \"{}\"", path);
+                let msg = format!("Not found: \"{}\"", path);
+                Response::write_response(out, request, Response::http_response("text/plain", 404, &msg).as_slice(), msg.as_bytes())
+            }
+        }
+    }
+
+    /// Pick the route, once params are in.
+    fn on_params(&mut self, request: &Request) {
+        self.current = request.params.as_ref().and_then(|params| self.find(&Self::request_path(params)));
+        if let Some(n) = self.current {
+            self.routes[n].1.on_params(request);
+        }
+    }
+
+    /// Body so far goes to the route's handler. Unrouted bodies are just buffered, for the 404.
+    fn on_stdin_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        match self.current {
+            Some(n) => self.routes[n].1.on_stdin_chunk(chunk),
+            None => Ok(()),
+        }
+    }
+
+    /// The route's handler replies to its own rejections.
+    fn stdin_rejected(&mut self, out: &mut dyn Write, request: &Request, error: &Error) -> Result<(), Error> {
+        match self.route_for(request) {
+            Some(n) => {
+                self.current = None;
+                self.routes[n].1.stdin_rejected(out, request, error)
+            }
+            None => {
+                let status = crate::status_for(error).into();
+                let msg = format!("Request rejected: {}", error);
+                Response::write_response(out, request, Response::http_response("text/plain", status, &msg).as_slice(), msg.as_bytes())
+            }
+        }
+    }
+
+    /// Every handler gets the chance, not just the one which ran.
+    fn between_requests(&mut self) {
+        for (_, handler) in self.routes.iter_mut() {
+            handler.between_requests();
+        }
+    }
+}
+
+/// Records which handler ran, and the body it saw in chunks. For tests.
+#[cfg(test)]
+struct NamedHandler {
+    name: &'static str,
+    log: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl Handler for NamedHandler {
+    fn handler(&mut self, out: &mut dyn Write, request: &Request, _env: &HashMap<String, String>) -> Result<(), Error> {
+        self.log.borrow_mut().push(format!("{} handler", self.name));
+        Response::write_response(out, request, Response::http_response("text/plain", 200, self.name).as_slice(), self.name.as_bytes())
+    }
+    fn on_stdin_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.log.borrow_mut().push(format!("{} chunk {}", self.name, chunk.len()));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_request_path() {
+    let params = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+    assert_eq!(Router::request_path(&params(&[("SCRIPT_NAME", "/cgi/uploadterrain.fcgi")])), "/cgi/uploadterrain");
+    assert_eq!(Router::request_path(&params(&[("SCRIPT_NAME", "/maptools.fcgi"), ("PATH_INFO", "/status/")])), "/status");
+    assert_eq!(Router::request_path(&params(&[("SCRIPT_NAME", "/status.fcgi"), ("PATH_INFO", "")])), "/status");
+    assert_eq!(Router::request_path(&params(&[])), "");
+}
+
+#[test]
+fn test_router_dispatch() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let named = |name| Box::new(NamedHandler { name, log: log.clone() });
+    let mut router = Router::default()
+        .add("/status", named("status"))
+        .add("uploadterrain/", named("uploadterrain"))
+        .add("/upload", named("upload"))
+        .add("/terrain/upload", named("terrain"));
+    assert_eq!(router.routes(), vec!["/status", "/uploadterrain", "/upload", "/terrain/upload"]);
+    let requests = [
+        crate::minifcgi::test_request_bytes(1, &[("SCRIPT_NAME", "/cgi/uploadterrain.fcgi")], b"12345"),
+        crate::minifcgi::test_request_bytes(2, &[("SCRIPT_NAME", "/maptools.fcgi"), ("PATH_INFO", "/status")], b"?"),
+        crate::minifcgi::test_request_bytes(3, &[("SCRIPT_NAME", "/maptools.fcgi"), ("PATH_INFO", "/terrain/upload")], b"xy"),
+        crate::minifcgi::test_request_bytes(4, &[("SCRIPT_NAME", "/cgi/nothere.fcgi")], b"body"),
+    ].concat();
+    let mut out = Vec::new();
+    crate::run(&mut std::io::Cursor::new(requests), &mut out, &mut router).expect("run");
+    //  Longest match wins, so "/terrain/upload" doesn't go to "/upload". Chunks go to the routed handler.
+    assert_eq!(*log.borrow(), vec!["uploadterrain chunk 5", "uploadterrain handler", "status chunk 1", "status handler", "terrain chunk 2", "terrain handler"]);
+    let out = String::from_utf8_lossy(&out);
+    assert!(out.contains("Status: 404"), "{}", out);
+    assert!(out.contains("Not found: \"/cgi/nothere\""), "{}", out);
+    assert_eq!(out.matches("Status: 200").count(), 3, "{}", out);
+}
@@ -0,0 +1,153 @@
+//! staleness.rs -- how old is each region's raw terrain?
+//! Part of the Animats impostor system
+//!
+//! Terrain is surveyed by a bot visiting regions. Every visit either
+//! uploads new terrain or confirms the stored terrain is unchanged, and
+//! sets last_confirmed. Regions not visited in a long time need a
+//! re-survey. The generator's dry run summarizes this, and the download
+//! responder lists such regions so the bot operator can plan routes.
+//! Deleted regions don't need surveying.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::Error;
+use mysql::{params, Params};
+use crate::db::Db;
+use crate::grid::canonical;
+use serde::Serialize;
+
+/// Regions surveyed in the last 30 days are fresh.
+const BUCKET_DAYS: [u32; 3] = [30, 90, 365];
+
+/// Age of each region's terrain. Rows for regions with no elevations always qualify.
+/// Regions surveyed before last_confirmed existed fall back to last_uploaded.
+const SQL_REGION_AGES: &str = r"SELECT name, region_loc_x, region_loc_y,
+        GREATEST(0, DATEDIFF(NOW(), COALESCE(last_confirmed, last_uploaded))), LENGTH(elevs) = 0
+    FROM raw_terrain_heights
+    WHERE grid = :grid AND NOT deleted
+        AND (COALESCE(last_confirmed, last_uploaded) <= NOW() - INTERVAL :stale_days DAY OR LENGTH(elevs) = 0)
+    ORDER BY region_loc_x, region_loc_y";
+
+/// Age of one region's raw terrain.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionAge {
+    /// Region name
+    pub name: String,
+    /// Location in world (meters)
+    pub region_loc: [u32; 2],
+    /// Days since terrain was last uploaded or confirmed.
+    pub age_days: u32,
+    /// True if there is a database row but no elevation data.
+    pub missing_elevs: bool,
+}
+
+/// Counts of regions by terrain age.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct StalenessBuckets {
+    /// Surveyed in the last 30 days
+    pub fresh: usize,
+    /// 30 to 90 days
+    pub over_30_days: usize,
+    /// 90 days to a year
+    pub over_90_days: usize,
+    /// More than a year
+    pub over_1_year: usize,
+    /// No elevations at all
+    pub missing: usize,
+}
+
+impl StalenessBuckets {
+    /// Count these regions.
+    pub fn new_from_ages(ages: &[RegionAge]) -> Self {
+        let mut buckets = Self::default();
+        for age in ages {
+            buckets.add(age);
+        }
+        buckets
+    }
+
+    /// Count one region.
+    pub fn add(&mut self, age: &RegionAge) {
+        let bucket = if age.missing_elevs {
+            &mut self.missing
+        } else if age.age_days >= BUCKET_DAYS[2] {
+            &mut self.over_1_year
+        } else if age.age_days >= BUCKET_DAYS[1] {
+            &mut self.over_90_days
+        } else if age.age_days >= BUCKET_DAYS[0] {
+            &mut self.over_30_days
+        } else {
+            &mut self.fresh
+        };
+        *bucket += 1;
+    }
+}
+
+impl std::fmt::Display for StalenessBuckets {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "fresh {}, over 30 days {}, over 90 days {}, over 1 year {}, missing {}",
+            self.fresh, self.over_30_days, self.over_90_days, self.over_1_year, self.missing)
+    }
+}
+
+/// Regions needing a re-survey, as JSON for the bot operator.
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleRegionsReply {
+    /// Grid name
+    pub grid: String,
+    /// Threshold used
+    pub stale_days: u32,
+    /// Regions older than that, or with no elevations
+    pub regions: Vec<RegionAge>,
+}
+
+/// SQL and parameters for regions older than stale_days, or with no elevations.
+/// 0 days gets every region.
+fn region_ages_query(grid: &str, stale_days: u32) -> (&'static str, Params) {
+    (SQL_REGION_AGES, params! { "grid" => canonical(grid), "stale_days" => stale_days })
+}
+
+/// Regions on this grid older than stale_days, or with no elevations.
+/// 0 days gets every region.
+pub fn get_region_ages(conn: &mut dyn Db, grid: &str, stale_days: u32) -> Result<Vec<RegionAge>, Error> {
+    let (sql, params) = region_ages_query(grid, stale_days);
+    conn.exec_map(sql, params, |row| Ok(RegionAge {
+        name: row.get(0)?,
+        region_loc: [row.get(1)?, row.get(2)?],
+        age_days: row.get(3)?,
+        missing_elevs: row.get(4)?,
+    }))
+}
+
+#[test]
+fn test_region_ages_query() {
+    let (sql, params) = region_ages_query("Agni", 90);
+    //  Age is from the last visit, falling back to the last upload.
+    assert!(sql.contains("COALESCE(last_confirmed, last_uploaded) <= NOW() - INTERVAL :stale_days DAY"), "{}", sql);
+    assert!(sql.contains("DATEDIFF(NOW(), COALESCE(last_confirmed, last_uploaded))"), "{}", sql);
+    assert!(sql.contains("OR LENGTH(elevs) = 0"), "{}", sql);
+    assert!(sql.contains("WHERE grid = :grid AND NOT deleted"), "{}", sql);
+    match params {
+        Params::Named(named) => {
+            let mut names: Vec<String> = named.keys().map(|k| String::from_utf8_lossy(k).to_string()).collect();
+            names.sort();
+            assert_eq!(names, vec!["grid", "stale_days"]);
+            //  Grids are stored lower case, so the column is compared as is.
+            assert_eq!(named.get("grid".as_bytes()), Some(&mysql::Value::from("agni")));
+        }
+        _ => panic!("Expected named parameters"),
+    }
+}
+
+#[test]
+fn test_staleness_buckets() {
+    let age = |age_days: u32, missing_elevs: bool| RegionAge { name: "Test".to_string(), region_loc: [0, 0], age_days, missing_elevs };
+    let ages = vec![age(0, false), age(29, false), age(30, false), age(89, false), age(90, false), age(364, false),
+        age(365, false), age(2000, false), age(5, true), age(500, true)];
+    let buckets = StalenessBuckets::new_from_ages(&ages);
+    assert_eq!(buckets, StalenessBuckets { fresh: 2, over_30_days: 2, over_90_days: 2, over_1_year: 2, missing: 2 });
+    assert_eq!(buckets.to_string(), "fresh 2, over 30 days 2, over 90 days 2, over 1 year 2, missing 2");
+    assert_eq!(StalenessBuckets::new_from_ages(&[]), StalenessBuckets::default());
+}
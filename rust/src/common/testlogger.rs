@@ -1,13 +1,101 @@
-//! Test logger - send everything to console for cargo test
+//! Test logger - capture log output for cargo test
+//!
+//! Log records are kept, so tests can check what was logged,
+//! and printed, so cargo test shows them only for failing tests.
+//! RUST_LOG sets the printed level, default Debug. Everything
+//! through Debug is captured regardless.
+//!
+//! Only one logger can be installed per process, so both
+//! test_logger and test_logger_capture install this one.
+//
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::thread::ThreadId;
 
+/// Captured records
+type Records = Arc<Mutex<Vec<(Level, String)>>>;
+
+/// The logger. Sends each record to the captures for the thread that logged it.
+struct CaptureLogger {
+    /// Level printed
+    print_level: LevelFilter,
+    /// Captures, by thread. Dropped captures are removed.
+    captures: Mutex<Vec<(ThreadId, Weak<Mutex<Vec<(Level, String)>>>)>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = record.args().to_string();
+        if record.level() <= self.print_level {
+            //  println, not the terminal, so the test harness captures it.
+            println!("{} [{}] {}", record.level(), record.target(), msg);
+        }
+        let thread = std::thread::current().id();
+        let mut captures = self.captures.lock().expect("log capture lock");
+        captures.retain(|(_, records)| records.strong_count() > 0);
+        for (_, records) in captures.iter().filter(|(id, _)| *id == thread) {
+            if let Some(records) = records.upgrade() {
+                records.lock().expect("log records lock").push((record.level(), msg.clone()));
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The one logger, installed on first use.
+fn capture_logger() -> &'static CaptureLogger {
+    static LOGGER: OnceLock<&'static CaptureLogger> = OnceLock::new();
+    LOGGER.get_or_init(|| {
+        let print_level = std::env::var("RUST_LOG").ok().and_then(|s| s.trim().parse().ok()).unwrap_or(LevelFilter::Debug);
+        let logger: &'static CaptureLogger = Box::leak(Box::new(CaptureLogger { print_level, captures: Mutex::new(Vec::new()) }));
+        //  Fails if some other logger got there first. Then nothing is captured.
+        if log::set_logger(logger).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+        }
+        logger
+    })
+}
+
+/// Log records captured on the thread which made this.
+/// Clones share the same records.
+#[derive(Clone)]
+pub struct LogCapture {
+    records: Records,
+}
+
+impl LogCapture {
+    /// All records so far, oldest first.
+    pub fn records(&self) -> Vec<(Level, String)> {
+        self.records.lock().expect("log records lock").clone()
+    }
+
+    /// True if a record at this level contains this text.
+    pub fn contains(&self, level: Level, substr: &str) -> bool {
+        self.records.lock().expect("log records lock").iter().any(|(l, msg)| *l == level && msg.contains(substr))
+    }
+
+    /// Forget all records so far.
+    pub fn clear(&self) {
+        self.records.lock().expect("log records lock").clear();
+    }
+}
+
+/// Logging for tests. Output appears only for failing tests.
 pub fn test_logger() {
-    //  All errors to console.
-    //  This needs to be reworked to send to standard output, rather than the
-    //  console, because the logging appears even for successful tests.
-    let _ = simplelog::CombinedLogger::init(vec![simplelog::TermLogger::new(
-        simplelog::LevelFilter::Debug,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Stdout,
-        simplelog::ColorChoice::Auto,
-    )]);
+    capture_logger();
+}
+
+/// Logging for tests, keeping what this thread logs for checking.
+pub fn test_logger_capture() -> LogCapture {
+    let records = Records::default();
+    capture_logger().captures.lock().expect("log capture lock").push((std::thread::current().id(), Arc::downgrade(&records)));
+    LogCapture { records }
 }
@@ -0,0 +1,125 @@
+//! textureatlas.rs -- where a tile's texture sits in a shared atlas.
+//! Part of the Animats impostor system
+//!
+//! Every texture is a separate upload, and on SL each upload costs money.
+//! For small tiles, the texture count dominates. So, optionally, the base
+//! textures of a 4 x 4 block of sibling tiles at the same LOD are packed into
+//! one atlas texture, and each tile's face says which part of the atlas is its own.
+//!
+//! A block is the tile two LODs up, so the tiles in an atlas are neighbors.
+//! Each tile's slot follows from its position in the block, so the generator,
+//! which packs the atlas, and the upload server, which records which tiles
+//! use it, agree without passing a layout around.
+//!
+//! Slot 0 is the southwest corner. Slots run west to east, then south to north,
+//! like faces. The atlas image has north at the top, as map tiles do.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::Error;
+
+/// Atlas image size, texels on a side. The largest texture SL accepts.
+pub const ATLAS_SIZE: u32 = 1024;
+/// Tiles across and down an atlas.
+pub const ATLAS_TILES_ACROSS: u32 = 4;
+/// Most tiles in one atlas.
+pub const MAX_ATLAS_TILES: usize = (ATLAS_TILES_ACROSS * ATLAS_TILES_ACROSS) as usize;
+/// Size of each tile's part of the atlas, texels on a side.
+pub const ATLAS_SLOT_SIZE: u32 = ATLAS_SIZE / ATLAS_TILES_ACROSS;
+
+/// One tile's place in an atlas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AtlasSlot {
+    /// Southwest corner of the block of tiles sharing the atlas, meters.
+    pub block_loc: [u32; 2],
+    /// Slot number, 0..MAX_ATLAS_TILES.
+    pub slot: u8,
+}
+
+impl AtlasSlot {
+    /// Slot for a square tile at this location. Tiles must be on a boundary of their own size.
+    pub fn new(region_loc: [u32; 2], tile_size: u32) -> Result<Self, Error> {
+        if tile_size == 0 || region_loc.iter().any(|&c| c % tile_size != 0) {
+            return Err(Error::OutOfRange(format!("Tile at ({}, {}) is not on a {} m boundary", region_loc[0], region_loc[1], tile_size)));
+        }
+        let block_size = tile_size * ATLAS_TILES_ACROSS;
+        let [col, row] = region_loc.map(|c| (c % block_size) / tile_size);
+        Ok(Self { block_loc: region_loc.map(|c| c - c % block_size), slot: (row * ATLAS_TILES_ACROSS + col) as u8 })
+    }
+
+    /// Column and row in the block, from the southwest corner.
+    pub fn col_row(&self) -> (u32, u32) {
+        (self.slot as u32 % ATLAS_TILES_ACROSS, self.slot as u32 / ATLAS_TILES_ACROSS)
+    }
+
+    /// Location of the tile in this slot, for tiles of this size.
+    pub fn tile_loc(&self, tile_size: u32) -> [u32; 2] {
+        let (col, row) = self.col_row();
+        [self.block_loc[0] + col * tile_size, self.block_loc[1] + row * tile_size]
+    }
+
+    /// Top left corner of the slot in the atlas image, texels. North is up.
+    pub fn image_origin(&self) -> (u32, u32) {
+        let (col, row) = self.col_row();
+        (col * ATLAS_SLOT_SIZE, (ATLAS_TILES_ACROSS - 1 - row) * ATLAS_SLOT_SIZE)
+    }
+
+    /// The slot's part of the atlas as UV coordinates, [u_min, v_min, u_max, v_max].
+    /// Origin at the southwest corner, +V north, like face rectangles.
+    pub fn uv(&self) -> [f32; 4] {
+        let (col, row) = self.col_row();
+        let n = ATLAS_TILES_ACROSS as f32;
+        [col as f32 / n, row as f32 / n, (col + 1) as f32 / n, (row + 1) as f32 / n]
+    }
+}
+
+/// Slots used, as a bit mask, bit n for slot n. Goes in the atlas asset name,
+/// so the upload server knows which tiles the atlas is for.
+pub fn slot_mask(slots: impl IntoIterator<Item = u8>) -> u16 {
+    slots.into_iter().fold(0, |mask, slot| mask | (1 << slot))
+}
+
+/// Slots in a mask, in order.
+pub fn slots_in_mask(mask: u16) -> impl Iterator<Item = u8> {
+    (0..MAX_ATLAS_TILES as u8).filter(move |&slot| mask & (1 << slot) != 0)
+}
+
+#[test]
+fn test_atlas_slots() {
+    //  Southwest corner of a block is slot 0, northeast is slot 15.
+    let slot = AtlasSlot::new([1024 * 300, 1024 * 200], 256).unwrap();
+    assert_eq!(slot, AtlasSlot { block_loc: [1024 * 300, 1024 * 200], slot: 0 });
+    let slot = AtlasSlot::new([1024 * 300 + 768, 1024 * 200 + 768], 256).unwrap();
+    assert_eq!(slot, AtlasSlot { block_loc: [1024 * 300, 1024 * 200], slot: 15 });
+    //  West to east, then south to north.
+    let slot = AtlasSlot::new([1024 * 300 + 256, 1024 * 200 + 512], 256).unwrap();
+    assert_eq!((slot.slot, slot.col_row()), (9, (1, 2)));
+    assert_eq!(slot.tile_loc(256), [1024 * 300 + 256, 1024 * 200 + 512]);
+    //  Bigger tiles, bigger blocks.
+    assert_eq!(AtlasSlot::new([2048 + 512, 0], 512).unwrap(), AtlasSlot { block_loc: [2048, 0], slot: 1 });
+    //  Off the tile grid.
+    assert!(AtlasSlot::new([100, 0], 256).is_err());
+    assert!(AtlasSlot::new([0, 0], 0).is_err());
+}
+
+#[test]
+fn test_atlas_uv() {
+    //  Slot 0 is the bottom left of the UV square, and the bottom left of the image.
+    let slot = AtlasSlot { block_loc: [0, 0], slot: 0 };
+    assert_eq!(slot.uv(), [0.0, 0.0, 0.25, 0.25]);
+    assert_eq!(slot.image_origin(), (0, 768));
+    //  Slot 9 is column 1, row 2.
+    let slot = AtlasSlot { block_loc: [0, 0], slot: 9 };
+    assert_eq!(slot.uv(), [0.25, 0.5, 0.5, 0.75]);
+    assert_eq!(slot.image_origin(), (256, 256));
+    //  Every slot is a quarter of the atlas each way, and they don't overlap.
+    for s in 0..MAX_ATLAS_TILES as u8 {
+        let uv = AtlasSlot { block_loc: [0, 0], slot: s }.uv();
+        assert_eq!((uv[2] - uv[0], uv[3] - uv[1]), (0.25, 0.25));
+    }
+    //  Masks round trip.
+    assert_eq!(slot_mask([0, 9, 15]), 0b1000_0010_0000_0001);
+    assert_eq!(slots_in_mask(0b1000_0010_0000_0001).collect::<Vec<_>>(), vec![0, 9, 15]);
+}
@@ -4,31 +4,50 @@
 //! Animats
 //! August, 2025.
 //
-use anyhow::{anyhow, Error};
+use crate::Error;
 use array2d::Array2D;
-use serde::Deserialize;
+use crate::grid::canonical;
+use crate::coords::{CoordUnits, GlobalMeters, region_coords_to_meters};
+use serde::{Deserialize, Serialize};
 ///  Our data as uploaded from SL/OS in JSON format
+//  Older uploaders send region_coords in region grid units, newer ones in meters. Both are stored as meters. See coords.rs.
 // "{\"region\":\"Vallone\",\"scale\":1.092822,\"offset\":33.500740,\"waterlev\":20.000000,\"regioncoords\":[1807,1199],
 //  \"elevs\":[\"E7CAACA3A5A8ACAEB0B2B5B9BDC0C4C5C5C3C0BDB9B6B3B2B2B3B4B7BBBFC3C7CBCED1D3D5D5D4CFC4B5A4"";
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+//  Serializes back to the same form, so uploads can be replayed and test fixtures built.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct UploadedRegionInfo {
     /// Grid name
     pub grid: String,
-    /// Position of region in world, meters.
-    pub region_coords: [u32; 2],
+    /// Position of region in world, meters. Converted in parse, from the units sent or guessed.
+    pub region_coords: [GlobalMeters; 2],
+    /// Units of region_coords as sent, if the sender said. Always meters once parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region_coords_units: Option<CoordUnits>,
     /// Region size. 256 x 256 if ommitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub size: Option<[u32; 2]>,
     /// Region name
     pub name: String,
     /// Height data, a long set of hex data. Each string is one set of Y values. The outer array is indexed by X.
     pub elevs: Vec<String>,
-    /// Scale factor for elevs
+    /// Scale factor for elevs, in the legacy encoding the uploader uses.
+    /// actual = input/256*scale + offset. See elev_scale.
     pub scale: f32,
     /// Offset factor for elevs
-    /// actual = input*scale + offset
     pub offset: f32,
     //  Water level
     pub water_lev: f32,
+    /// Different for every upload, against replays. See replayguard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// When sent, Unix time in seconds. See replayguard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<i64>,
+    /// Distance between samples, meters, (X, Y). If omitted, the samples span the region,
+    /// with the last ones on the far edges. The survey script samples on a lattice from 0,
+    /// which may stop one spacing short of the far edge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_spacing: Option<[f32; 2]>,
 }
 
 impl UploadedRegionInfo {
@@ -38,8 +57,8 @@ impl UploadedRegionInfo {
     /// Usual new. This takes elevations as hex strings.
     pub fn new(
         grid: String,
-        region_coords_x: u32,
-        region_coords_y: u32,
+        region_coords_x: GlobalMeters,
+        region_coords_y: GlobalMeters,
         size_x: u32,
         size_y: u32,
         name: String,
@@ -51,18 +70,50 @@ impl UploadedRegionInfo {
         Self {
             grid,
             region_coords: [region_coords_x, region_coords_y],
+            region_coords_units: Some(CoordUnits::Meters),
             size: Some([size_x, size_y]),
             name,
             elevs,
             scale,
             offset,
             water_lev,
+            nonce: None,
+            sent_at: None,
+            sample_spacing: None,
         }
     }
 
-    /// Parse from string
+    /// Parse from string. The grid name is made canonical here, on the way in.
     pub fn parse(s: &str) -> Result<Self, Error> {
-        Ok(serde_json::from_str(s)?)
+        let mut region_info: Self = serde_json::from_str(s)?;
+        region_info.grid = canonical(&region_info.grid);
+        region_info.region_coords = region_coords_to_meters(region_info.region_coords.map(GlobalMeters::meters), region_info.region_coords_units)?;
+        region_info.region_coords_units = Some(CoordUnits::Meters);
+        Ok(region_info)
+    }
+
+    /// To JSON, in the form the uploader sends.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Builder, mostly for tests and replaying uploads.
+    pub fn builder() -> UploadedRegionInfoBuilder {
+        UploadedRegionInfoBuilder::default()
+    }
+
+    /// Scale for the elevs in the current encoding, as stored and decoded by u8_to_elev.
+    pub fn elev_scale(&self) -> f32 {
+        scale_from_legacy(self.scale)
+    }
+
+    /// As a height field. A sample spacing which doesn't tile the region is an error.
+    pub fn to_height_field(&self) -> Result<HeightField, Error> {
+        let [samples_x, samples_y] = self.get_samples()?;
+        let [size_x, size_y] = self.get_size();
+        HeightField::new_from_elevs_blob(&self.get_elevs_as_blob()?, samples_x, samples_y, size_x, size_y,
+            self.elev_scale(), self.offset, self.water_lev)?
+            .with_spacing(self.sample_spacing)
     }
 
     /// Get size, applying default region size for non-varregions
@@ -77,24 +128,24 @@ impl UploadedRegionInfo {
     /// Get dimensions of elevation samples array. Result is X,Y
     pub fn get_samples(&self) -> Result<[u32; 2], Error> {
         if self.elevs.is_empty() {
-            return Err(anyhow!("Elevation data is missing"));
+            return Err(Error::ElevationFormat("Elevation data is missing".to_string()));
         }
         //  Validate that all rows are the same length. This is the number of Y entries.
         let rowlen = self.elevs[0].len() / 2; // it's a hex string, we want the byte count
         for row in &self.elevs {
             if row.len() != rowlen * 2 {
-                return Err(anyhow!(
+                return Err(Error::ElevationFormat(format!(
                     "Elevation data has a row of the wrong length. Not {}",
                     rowlen
-                ));
+                )));
             }
         }
         Ok([self.elevs.len().try_into()?, rowlen.try_into()?])
     }
 
-    /// Get grid in canonial lowercase format
+    /// Get grid in canonical form
     pub fn get_grid(&self) -> String {
-        self.grid.to_lowercase()
+        canonical(&self.grid)
     }
 
     /// Get region name in canonical lowercase format
@@ -109,34 +160,50 @@ impl UploadedRegionInfo {
         Ok(elevs_blob)
     }
 
-    /// Convert SQL blob to hex format.
-    /// We have to figure out the length of the strings from the length and aspect ratio.
+    /// Convert SQL blob to hex format, the inverse of get_elevs_as_blob.
+    /// One hex string per X, each samples_y long, since Y goes fastest in the blob.
+    /// samples_x and samples_y are stored with the blob.
     pub fn elevs_blob_to_hex(
-        elevs: Vec<u8>,
+        elevs: &[u8],
+        samples_x: u32,
+        samples_y: u32,
+    ) -> Result<Vec<String>, Error> {
+        if samples_x == 0 || samples_y == 0 || elevs.len() != (samples_x as usize) * (samples_y as usize) {
+            return Err(Error::Dimensions(format!(
+                "Elevation data size incorrect: length {}, samples ({}, {})",
+                elevs.len(),
+                samples_x,
+                samples_y
+            )));
+        }
+        Ok(elevs
+            .chunks_exact(samples_y as usize)
+            .map(hex::encode_upper)
+            .collect())
+    }
+
+    /// Convert SQL blob to hex format, guessing the samples from the region size.
+    /// Only works when the samples have the region's aspect ratio.
+    #[deprecated(note = "Fails for varregions with shared edges, such as 65 x 129. Use elevs_blob_to_hex with the stored samples_x and samples_y.")]
+    pub fn elevs_blob_to_hex_from_size(
+        elevs: &[u8],
         size_x: u32,
         size_y: u32,
     ) -> Result<Vec<String>, Error> {
         let n = elevs.len() as u32;
-        let gcd = num::integer::gcd(size_x, size_y) as u32;
-        let sx = size_x / gcd;
-        let sy = size_y / gcd;
-        if n % (sx * sy) != 0 {
-            return Err(anyhow!(
+        let gcd = num::integer::gcd(size_x, size_y);
+        let (sx, sy) = (size_x / gcd, size_y / gcd);
+        //  n = k * sx * k * sy for some k.
+        let k = if n % (sx * sy) == 0 { ((n / (sx * sy)) as f64).sqrt().round() as u32 } else { 0 };
+        if k == 0 || k * sx * k * sy != n {
+            return Err(Error::Dimensions(format!(
                 "Elevation data size incorrect: length {}, size ({}, {})",
                 n,
                 size_x,
                 size_y
-            ));
+            )));
         }
-        let r = n / (sx * sy);
-        let elevs_x = size_x / r;
-        let elevs_y = size_y / r;
-        assert_eq!(n, elevs_x * elevs_y);
-        //  Now take slices of length elevs_x and make into hex.
-        Ok(elevs
-            .chunks_exact(elevs_x as usize)
-            .map(|c| hex::encode_upper(c))
-            .collect())
+        Self::elevs_blob_to_hex(elevs, k * sx, k * sy)
     }
 
     /// Get elevations as numbers before offsetting.
@@ -148,29 +215,198 @@ impl UploadedRegionInfo {
     }
 }
 
+/// Builds an UploadedRegionInfo. Elevations come from a height field,
+/// quantized to hex the way the uploader does it.
+//  UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(462592), GlobalMeters(306944)).from_height_field(&hf).build()
+#[derive(Debug, Default)]
+pub struct UploadedRegionInfoBuilder {
+    grid: String,
+    region_coords: [GlobalMeters; 2],
+    size: Option<[u32; 2]>,
+    name: String,
+    /// Hex elevs, scale, offset, or the error from converting them.
+    elevs: Option<Result<(Vec<String>, f32, f32), Error>>,
+    water_lev: Option<f32>,
+    sample_spacing: Option<[f32; 2]>,
+}
+
+impl UploadedRegionInfoBuilder {
+    /// Grid name
+    pub fn grid(mut self, grid: &str) -> Self {
+        self.grid = grid.to_string();
+        self
+    }
+
+    /// Position of region in world, meters.
+    pub fn coords(mut self, x: GlobalMeters, y: GlobalMeters) -> Self {
+        self.region_coords = [x, y];
+        self
+    }
+
+    /// Region size. Overrides the height field's.
+    pub fn size(mut self, x: u32, y: u32) -> Self {
+        self.size = Some([x, y]);
+        self
+    }
+
+    /// Region name
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Water level. Overrides the height field's.
+    pub fn water_lev(mut self, water_lev: f32) -> Self {
+        self.water_lev = Some(water_lev);
+        self
+    }
+
+    /// Elevations, size, water level, and any explicit sample spacing from a height field.
+    pub fn from_height_field(mut self, height_field: &HeightField) -> Self {
+        self.elevs = Some(height_field.into_sculpt_array().map(|(scale, offset, elevs)| {
+            (elevs.iter().map(hex::encode_upper).collect(), scale_to_legacy(scale), offset)
+        }));
+        self.size = self.size.or(Some([height_field.size_x, height_field.size_y]));
+        self.water_lev = self.water_lev.or(Some(height_field.water_level));
+        self.sample_spacing = height_field.explicit_spacing();
+        self
+    }
+
+    /// Build. Elevations are required.
+    pub fn build(self) -> Result<UploadedRegionInfo, Error> {
+        let (elevs, scale, offset) = self.elevs
+            .ok_or_else(|| Error::ElevationFormat("No height field given to builder".to_string()))??;
+        let region_info = UploadedRegionInfo {
+            grid: self.grid,
+            region_coords: self.region_coords,
+            region_coords_units: Some(CoordUnits::Meters),
+            size: self.size,
+            name: self.name,
+            elevs,
+            scale,
+            offset,
+            water_lev: self.water_lev.unwrap_or_default(),
+            nonce: None,
+            sent_at: None,
+            sample_spacing: self.sample_spacing,
+        };
+        region_info.get_samples()?;
+        Ok(region_info)
+    }
+}
+
+/// Request to mark a region as gone from the grid.
+/// A later normal upload for the region brings it back.
+//  {"grid":"agni", "region_coords":[1000,1000], "deleted":true}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RegionDeletion {
+    /// Grid name
+    pub grid: String,
+    /// Position of region in world, meters. Converted in parse, from the units sent or guessed.
+    pub region_coords: [GlobalMeters; 2],
+    /// Units of region_coords as sent, if the sender said. Always meters once parsed.
+    #[serde(default)]
+    pub region_coords_units: Option<CoordUnits>,
+    /// Must be true.
+    pub deleted: bool,
+    /// Different for every upload, against replays. See replayguard.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// When sent, Unix time in seconds. See replayguard.
+    #[serde(default)]
+    pub sent_at: Option<i64>,
+}
+
+/// What the terrain uploader can be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TerrainUpload {
+    /// Terrain for a region
+    Region(UploadedRegionInfo),
+    /// Region is gone
+    Deletion(RegionDeletion),
+}
+
+impl TerrainUpload {
+    /// Grid this is for.
+    pub fn grid(&self) -> &str {
+        match self {
+            TerrainUpload::Region(region_info) => &region_info.grid,
+            TerrainUpload::Deletion(deletion) => &deletion.grid,
+        }
+    }
+
+    /// Replay nonce, if sent.
+    pub fn nonce(&self) -> Option<&str> {
+        match self {
+            TerrainUpload::Region(region_info) => region_info.nonce.as_deref(),
+            TerrainUpload::Deletion(deletion) => deletion.nonce.as_deref(),
+        }
+    }
+
+    /// When sent, if given.
+    pub fn sent_at(&self) -> Option<i64> {
+        match self {
+            TerrainUpload::Region(region_info) => region_info.sent_at,
+            TerrainUpload::Deletion(deletion) => deletion.sent_at,
+        }
+    }
+
+    /// Parse from string. Anything with a "deleted" field is a deletion.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let value: serde_json::Value = serde_json::from_str(s)?;
+        if value.get("deleted").is_none() {
+            return Ok(TerrainUpload::Region(UploadedRegionInfo::parse(s)?));
+        }
+        let mut deletion: RegionDeletion = serde_json::from_value(value)?;
+        deletion.grid = canonical(&deletion.grid);
+        deletion.region_coords = region_coords_to_meters(deletion.region_coords.map(GlobalMeters::meters), deletion.region_coords_units)?;
+        deletion.region_coords_units = Some(CoordUnits::Meters);
+        if !deletion.deleted {
+            return Err(Error::BadRequest("\"deleted\" must be true. Upload the region's terrain to undelete it.".to_string()));
+        }
+        Ok(TerrainUpload::Deletion(deletion))
+    }
+}
+
 /// Height field.
-/// Always an odd number of rows and columns, because the right and top edges
-/// are supposed to be the edges adjacent regions.
+/// Usually an odd number of rows and columns, because the right and top edges
+/// are supposed to be the edges adjacent regions. Uploads on a lattice which stops
+/// one spacing short of the far edges are the exception. See to_shared_edges.
+///
+/// Sample indices are always (x, y), with +X east and +Y north, (0, 0) at the lower left.
 #[derive(Debug, Clone, PartialEq)]
 pub struct HeightField {
-    /// The heights
+    /// The heights. Array2D rows are X and columns are Y, so in row major order
+    /// Y goes fastest, as in the elevs blob. Index through sample and set_sample,
+    /// which take (x, y), not through Array2D's (row, column).
     heights: Array2D<f32>,
     /// size of region, X
     pub size_x: u32,
     /// size of region, Y
     pub size_y: u32,
+    /// Distance between samples, meters, (X, Y).
+    /// size / (samples - 1) unless the upload said otherwise.
+    pub spacing: [f32; 2],
     /// Water level for region. Here because of where the data comes from.
+    /// For a combined tile, the lowest water level of the regions in it.
     pub water_level: f32,
+    /// Highest water level of the regions in a combined tile, if they differ by more than WATER_LEVEL_EPSILON.
+    pub water_level_max: Option<f32>,
 }
 
+/// Water levels closer than this, meters, are the same water level.
+pub const WATER_LEVEL_EPSILON: f32 = 0.01;
+/// A sample spacing tiles a region if it misses the region size by no more than this, meters.
+pub const SPACING_EPSILON: f32 = 0.01;
+
 impl std::fmt::Display for HeightField {
     /// Usual display
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
             "HeightField samples ({}, {})  region ({}, {})",
-            self.heights.num_rows(),
-            self.heights.num_columns(),
+            self.dims().0,
+            self.dims().1,
             self.size_x,
             self.size_y
         )
@@ -193,25 +429,21 @@ impl HeightField {
     ) -> Result<Self, Error> {
         log::debug!("New height field, scale {:5}, offset {:5}", scale, offset);
         if elevs.len() != (samples_x as usize) * (samples_y as usize) {
-            return Err(anyhow!(
+            return Err(Error::Dimensions(format!(
                 "Elevations array data length {} does not match dimensions ({}, {})",
                 elevs.len(),
                 samples_x,
                 samples_y
-            ));
+            )));
         }
-        let iterator = (0..).map(|n| { u8_to_elev(elevs[n], scale, offset) });
-        let heights =
-            Array2D::from_iter_row_major(iterator, samples_x as usize, samples_y as usize)?;
-        Ok(Self {
-            heights,
-            size_x,
-            size_y,
-            water_level,
+        let samples_y = samples_y as usize;
+        Self::new_from_fn(samples_x as usize, samples_y, size_x, size_y, water_level, |x, y| {
+            u8_to_elev(elevs[x * samples_y + y], scale, offset)
         })
     }
 
     /// New from the 2D array of elevs we get from JSON - test only
+    /// Indexed elevs[x][y].
     pub fn new_from_unscaled_elevs(
         elevs: &Vec<Vec<u8>>,
         size_x: u32,
@@ -221,59 +453,256 @@ impl HeightField {
         water_level: f32,
     ) -> Result<Self, Error> {
         if elevs.is_empty() {
-            return Err(anyhow!("Elevs array is empty."));
+            return Err(Error::ElevationFormat("Elevs array is empty.".to_string()));
         }
         //  Get Y length
-        let row_length = elevs[0].len();
-        let iterator = (0..).map(|n| {
-            let x = n / row_length;
-            let y = n % row_length;
+        let samples_y = elevs[0].len();
+        if elevs.iter().any(|column| column.len() != samples_y) {
+            return Err(Error::ElevationFormat("Elevs array is ragged.".to_string()));
+        }
+        Self::new_from_fn(elevs.len(), samples_y, size_x, size_y, water_level, |x, y| {
             u8_to_elev(elevs[x][y], scale, offset)
-        });
-        let heights = Array2D::from_iter_row_major(iterator, row_length, elevs.len())?;
+        })
+    }
+
+    /// New from a function giving the height at each sample point, (X, Y).
+    pub fn new_from_fn(
+        samples_x: usize,
+        samples_y: usize,
+        size_x: u32,
+        size_y: u32,
+        water_level: f32,
+        mut f: impl FnMut(usize, usize) -> f32,
+    ) -> Result<Self, Error> {
+        if samples_x == 0 || samples_y == 0 {
+            return Err(Error::Dimensions(format!("Height field dimensions ({}, {}) are empty", samples_x, samples_y)));
+        }
+        let iterator = (0..).map(|n| f(n / samples_y, n % samples_y));
+        let heights = Array2D::from_iter_row_major(iterator, samples_x, samples_y)?;
+        let spacing = |size: u32, n: usize| if n > 1 { size as f32 / (n - 1) as f32 } else { size as f32 };
         Ok(Self {
             heights,
             size_x,
             size_y,
+            spacing: [spacing(size_x, samples_x), spacing(size_y, samples_y)],
             water_level,
+            water_level_max: None,
         })
     }
-    
+
+    /// Approximate memory used, bytes. For cache budgets.
+    pub fn byte_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.heights.num_elements() * std::mem::size_of::<f32>()
+    }
+
+    /// Number of samples, (X, Y).
+    pub fn dims(&self) -> (usize, usize) {
+        (self.heights.num_rows(), self.heights.num_columns())
+    }
+
+    /// Height at one sample point. Panics if out of range.
+    pub fn sample(&self, ix: usize, iy: usize) -> f32 {
+        *self.heights.get(ix, iy).expect("Height field sample out of range")
+    }
+
+    /// Set height at one sample point. Panics if out of range.
+    fn set_sample(&mut self, ix: usize, iy: usize, height: f32) {
+        self.heights.set(ix, iy, height).expect("Height field sample out of range")
+    }
+
+    /// With an explicit sample spacing, meters, (X, Y). None keeps the derived spacing.
+    /// The spacing must tile the region, within SPACING_EPSILON, with the last samples
+    /// on the far edges or one spacing short of them.
+    pub fn with_spacing(mut self, spacing: Option<[f32; 2]>) -> Result<Self, Error> {
+        let Some(spacing) = spacing else {
+            return Ok(self);
+        };
+        let (nx, ny) = self.dims();
+        for (axis, size, n, d) in [("X", self.size_x, nx, spacing[0]), ("Y", self.size_y, ny, spacing[1])] {
+            let steps = (size as f32 / d).round();
+            let tiles = d.is_finite() && d > 0.0 && (size as f32 - steps * d).abs() <= SPACING_EPSILON;
+            if !tiles || (steps as usize != n - 1 && steps as usize != n) {
+                return Err(Error::Dimensions(format!(
+                    "Sample spacing {} m in {} does not tile region size {} m with {} samples", d, axis, size, n)));
+            }
+        }
+        self.spacing = spacing;
+        Ok(self)
+    }
+
+    /// True if the last samples are on the far edges, as combine and the sculpt expect.
+    pub fn reaches_far_edge(&self) -> bool {
+        let (nx, ny) = self.dims();
+        let reaches = |size: u32, n: usize, d: f32| n < 2 || ((n - 1) as f32 * d - size as f32).abs() <= SPACING_EPSILON;
+        reaches(self.size_x, nx, self.spacing[0]) && reaches(self.size_y, ny, self.spacing[1])
+    }
+
+    /// The sample spacing, if it can't be derived from the sample counts. For storing.
+    pub fn explicit_spacing(&self) -> Option<[f32; 2]> {
+        if self.reaches_far_edge() { None } else { Some(self.spacing) }
+    }
+
+    /// This height field with the last samples on the far edges.
+    /// A lattice which stops short of the far edges gets one more row and column,
+    /// which repeat the last samples, since the neighbor's samples aren't available here.
+    pub fn to_shared_edges(&self) -> Self {
+        if self.reaches_far_edge() {
+            return self.clone();
+        }
+        let steps = |size: u32, d: f32| ((size as f32 / d).round() as usize).max(1);
+        let (steps_x, steps_y) = (steps(self.size_x, self.spacing[0]), steps(self.size_y, self.spacing[1]));
+        let (dx, dy) = (self.size_x as f32 / steps_x as f32, self.size_y as f32 / steps_y as f32);
+        let mut shared = Self::new_from_fn(steps_x + 1, steps_y + 1, self.size_x, self.size_y, self.water_level,
+            |x, y| self.elevation_at_meters(x as f32 * dx, y as f32 * dy).expect("Height field is empty"))
+            .expect("Shared edge height field is empty");
+        shared.water_level_max = self.water_level_max;
+        shared
+    }
+
+    /// Distance between samples, meters, (X, Y).
+    fn sample_spacing(&self) -> (f32, f32) {
+        (self.spacing[0], self.spacing[1])
+    }
+
+    /// Height at a point in the region, in meters from the lower left corner.
+    /// Interpolated between the four nearest samples. Points outside the region are clamped to the edge.
+    /// None if there are no samples.
+    pub fn elevation_at_meters(&self, x: f32, y: f32) -> Option<f32> {
+        let (nx, ny) = self.dims();
+        if nx == 0 || ny == 0 {
+            return None;
+        }
+        let (dx, dy) = self.sample_spacing();
+        let fx = (x / dx).clamp(0.0, (nx - 1) as f32);
+        let fy = (y / dy).clamp(0.0, (ny - 1) as f32);
+        let (ix, iy) = ((fx.floor() as usize).min(nx.saturating_sub(2)), (fy.floor() as usize).min(ny.saturating_sub(2)));
+        let (tx, ty) = (fx - ix as f32, fy - iy as f32);
+        let (ix1, iy1) = ((ix + 1).min(nx - 1), (iy + 1).min(ny - 1));
+        let bottom = self.sample(ix, iy) * (1.0 - tx) + self.sample(ix1, iy) * tx;
+        let top = self.sample(ix, iy1) * (1.0 - tx) + self.sample(ix1, iy1) * tx;
+        Some(bottom * (1.0 - ty) + top * ty)
+    }
+
+    /// Lowest and highest heights. (0.0, 0.0) if empty.
+    pub fn min_max(&self) -> (f32, f32) {
+        let mut heights = self.heights.elements_row_major_iter();
+        let Some(first) = heights.next() else {
+            return (0.0, 0.0);
+        };
+        heights.fold((*first, *first), |(min, max), v| (min.min(*v), max.max(*v)))
+    }
+
+    /// True if no point is more than tolerance above the water level, so there's no land to show.
+    /// For combined tiles, the lowest water level is used. An empty height field is not water.
+    pub fn is_effectively_water(&self, tolerance: f32) -> bool {
+        if self.heights.num_elements() == 0 {
+            return false;
+        }
+        self.min_max().1 <= self.water_level + tolerance
+    }
+
+    /// Mean height. 0.0 if empty.
+    pub fn mean(&self) -> f32 {
+        let n = self.heights.num_elements();
+        if n == 0 {
+            return 0.0;
+        }
+        (self.heights.elements_row_major_iter().map(|v| *v as f64).sum::<f64>() / n as f64) as f32
+    }
+
+    /// Slope at a sample point, rise over run in (X, Y).
+    /// Central differences inside, one-sided at the edges.
+    pub fn slope_at(&self, ix: usize, iy: usize) -> (f32, f32) {
+        let (nx, ny) = self.dims();
+        let (dx, dy) = self.sample_spacing();
+        let diff = |i: usize, n: usize, at: &dyn Fn(usize) -> f32, spacing: f32| {
+            if n < 2 {
+                return 0.0;
+            }
+            let (lo, hi) = (i.saturating_sub(1), (i + 1).min(n - 1));
+            (at(hi) - at(lo)) / ((hi - lo) as f32 * spacing)
+        };
+        (
+            diff(ix, nx, &|i| self.sample(i, iy), dx),
+            diff(iy, ny, &|i| self.sample(ix, i), dy),
+        )
+    }
+
+    /// Compare two height fields in elevation, not in encoded values,
+    /// so differing scale and offset don't matter. If sample densities differ,
+    /// the denser one's sample points are compared with the other interpolated.
+    /// Samples which differ by more than tolerance are counted as changed.
+    pub fn diff(&self, other: &HeightField, tolerance: f32) -> Result<HeightFieldDiff, Error> {
+        if (self.size_x, self.size_y) != (other.size_x, other.size_y) {
+            return Err(Error::Dimensions(format!("Height fields cover different areas, ({}, {}) vs ({}, {})", self.size_x, self.size_y, other.size_x, other.size_y)));
+        }
+        let (dense, sparse) = if self.heights.num_elements() >= other.heights.num_elements() { (self, other) } else { (other, self) };
+        let (nx, ny) = dense.dims();
+        if nx == 0 || ny == 0 {
+            return Err(Error::Dimensions("Height field has no entries.".to_string()));
+        }
+        let (dx, dy) = dense.sample_spacing();
+        let mut result = HeightFieldDiff { max_error: 0.0, rms_error: 0.0, over_tolerance: 0, changed_bounds: None };
+        let mut sum_sq = 0.0f64;
+        for ix in 0..nx {
+            for iy in 0..ny {
+                let (x, y) = (ix as f32 * dx, iy as f32 * dy);
+                let other_elev = sparse.elevation_at_meters(x, y)
+                    .ok_or_else(|| Error::Dimensions("Height field has no entries.".to_string()))?;
+                let err = (dense.sample(ix, iy) - other_elev).abs();
+                sum_sq += (err as f64) * (err as f64);
+                result.max_error = result.max_error.max(err);
+                if err > tolerance {
+                    result.over_tolerance += 1;
+                    result.changed_bounds = Some(match result.changed_bounds {
+                        Some([(x0, y0), (x1, y1)]) => [(x0.min(x), y0.min(y)), (x1.max(x), y1.max(y))],
+                        None => [(x, y), (x, y)],
+                    });
+                }
+            }
+        }
+        result.rms_error = (sum_sq / (nx * ny) as f64).sqrt() as f32;
+        Ok(result)
+    }
+
+    /// True if the two height fields are the same terrain, with no elevation
+    /// differing by more than tolerance, in meters. Uses diff, so scale and offset don't matter.
+    /// Height fields which can't be compared are not the same.
+    pub fn same_terrain(&self, other: &HeightField, tolerance: f32) -> bool {
+        match self.diff(other, tolerance) {
+            Ok(diff) => {
+                if let Some([(x0, y0), (x1, y1)]) = diff.changed_bounds {
+                    log::warn!("Elevations differ by up to {:5} m, tolerance {} m, {} samples, from ({}, {}) to ({}, {})",
+                        diff.max_error, tolerance, diff.over_tolerance, x0, y0, x1, y1);
+                }
+                diff.is_unchanged()
+            }
+            Err(e) => {
+                log::warn!("Elevations not comparable: {:?}", e);
+                false
+            }
+        }
+    }
+
     /// Get scale and offset from heights
     pub fn get_scale_offset(&self) -> Result<(f32, f32), Error> {
         //  Calculate max and min.
         if self.heights.column_len() == 0 {
-            return Err(anyhow!("Height field has no entries."));
+            return Err(Error::Dimensions("Height field has no entries.".to_string()));
         }
-        let max = self
-            .heights
-            .elements_row_major_iter()
-            .max_by(|a, b| a.total_cmp(b))
-            .unwrap();
-        let min = self
-            .heights
-            .elements_row_major_iter()
-            .min_by(|a, b| a.total_cmp(b))
-            .unwrap();
+        let (min, max) = self.min_max();
         //  Scale into 0..255
         log::debug!("Height range:  {:5} .. {:5}", min, max);
-        Ok(elev_min_max_to_scale_offset(*min, *max))
+        Ok(elev_min_max_to_scale_offset(min, max))
     }
 
     /// As one big flat u8 array.
-    /// Returns scale, offset, values
+    /// Returns scale, offset, values, indexed values[x][y].
     pub fn into_sculpt_array(&self) -> Result<(f32, f32, Vec<Vec<u8>>), Error> {
         let (scale, offset) = self.get_scale_offset()?;
-        let height_array = self
-            .heights
-            .as_rows()
-            .into_iter()
-            .map(|r| {
-                r.into_iter()
-                    //////.map(|v| ((((v - min) / range) / 256.0).round() as usize).clamp(0, 255) as u8)
-                    .map(|v| elev_to_u8(v, scale, offset))
-                    .collect()
-            })
+        let (nx, ny) = self.dims();
+        let height_array = (0..nx)
+            .map(|x| (0..ny).map(|y| elev_to_u8(self.sample(x, y), scale, offset)).collect())
             .collect();
         Ok((scale, offset, height_array))
     }
@@ -281,26 +710,35 @@ impl HeightField {
     /// Combine four height fields into one, at lower resolution.
     /// Input and output sizes are the same.
     /// Order of input height fields is ll, lr, ul, ur.
-    //  ***WATER LEVEL IS A PROBLEM - What happens when we combine non-uniform water levels?***
-    //  ***PUT IN CODE TO USE LOWEST WATER LEVEL OF SET***
-    //  ***BUT NEED TO THINK MORE ABOUT WATER LEVEL. MAY NEED TO MODIFY HEIGHT FIELD AND TEXTURE***
+    /// Inputs which stop short of their far edges are resampled to reach them first.
+    /// The water level is the lowest of the inputs. If they differ, the highest is kept too,
+    /// so the viewer can draw a water plane per region if it wants to.
+    //  ***MAY NEED TO MODIFY HEIGHT FIELD AND TEXTURE FOR NON-UNIFORM WATER LEVELS***
     //  ***POSSIBLE SOLUTION: WHEN COMBINING, MIN HEIGHT IS WATER LEVEL AND THOSE CELLS BECOME WATER IMAGE IN THE IMAGE TEXTURE***
     pub fn combine(h: [Option<Self>;4]) ->  Result<Self, Error> {
         const INSERT_OFFSETS: [(usize, usize);4] = [(0,0), (1,0), (0,1), (1,1)];
+        let h = h.map(|v| v.map(|v| v.to_shared_edges()));
         if let Some(non_empty) = h.iter().find(|v| v.is_some()) {
             let non_empty = non_empty.as_ref().unwrap();
             //  Output array, which is 2x as big, -1.
-            //  ***CHECK ROWS/COLS***
-            let cnt_x = non_empty.heights.num_columns() * 2 - 1;
-            let cnt_y = non_empty.heights.num_rows() * 2 - 1;           
-            let mut heights = Array2D::filled_with(0.0, cnt_x, cnt_y);
+            let (nx, ny) = non_empty.dims();
+            let (water_min, water_max) = Self::water_level_range(&h);
+            let mut combined = Self::new_from_fn(nx * 2 - 1, ny * 2 - 1, non_empty.size_x * 2, non_empty.size_y * 2,
+                water_min, |_, _| 0.0)?;
+            if water_max - water_min > WATER_LEVEL_EPSILON {
+                combined.water_level_max = Some(water_max);
+            }
             //  Closure to copy an input array into an area of the output array.
-            let mut set_quadrant = |xstart: usize, ystart: usize, v: &Array2D<f32>| {
-                for x in 0..v.num_columns() {
-                    for y in 0..v.num_rows() {
-                        heights.set(x + xstart, y + ystart, *v.get(x, y).unwrap()).unwrap();
+            let mut set_quadrant = |xstart: usize, ystart: usize, v: &Self| -> Result<(), Error> {
+                if v.dims() != (nx, ny) {
+                    return Err(Error::Dimensions(format!("Height field combine - sizes differ, {:?} vs {:?}", v.dims(), (nx, ny))));
+                }
+                for x in 0..nx {
+                    for y in 0..ny {
+                        combined.set_sample(x + xstart, y + ystart, v.sample(x, y));
                     }
                 }
+                Ok(())
             };
             //  Copy all four input arrays into the appropriate quadrant.
             //  Note that there is an overlap of one row. 
@@ -308,23 +746,26 @@ impl HeightField {
             //  So a height field for 0.256 has 257 entries.
             for i in 0..4 {
                 let (xstart, ystart) = INSERT_OFFSETS[i];
-                let xstart = if xstart == 0 {0} else { non_empty.heights.num_columns() - 1 };
-                let ystart = if ystart == 0 {0} else { non_empty.heights.num_rows() - 1 };
+                let xstart = if xstart == 0 {0} else { nx - 1 };
+                let ystart = if ystart == 0 {0} else { ny - 1 };
                 if let Some(from_height_field) = &h[i] {
-                    set_quadrant(xstart, ystart, &from_height_field.heights);
+                    set_quadrant(xstart, ystart, from_height_field)?;
                 }
             }
-            Ok(Self {
-                size_x: non_empty.size_x * 2,
-                size_y: non_empty.size_y * 2,
-                water_level: non_empty.water_level,
-                heights,
-            })
+            Ok(combined)
         } else {
-            Err(anyhow!("Height field combine - all inputs were none."))
+            Err(Error::Dimensions("Height field combine - all inputs were none.".to_string()))
         }
     }
     
+    /// Lowest and highest water levels of the inputs which are present.
+    /// Inputs which are themselves combined tiles contribute their own range.
+    fn water_level_range(h: &[Option<Self>; 4]) -> (f32, f32) {
+        h.iter().flatten().fold((f32::MAX, f32::MIN), |(lo, hi), v| {
+            (lo.min(v.water_level), hi.max(v.water_level_max.unwrap_or(v.water_level)))
+        })
+    }
+
     /// Average height at this point, interpolated.
     /// xloc and yloc are indexes into the height array, but they are
     /// not integers. We pick the appropriate cells and interpolate.
@@ -335,11 +776,13 @@ impl HeightField {
         //  ***MAX, or AVERAGE???***
         //  Trying max for now.
         const INSERT_OFFSETS: [(usize, usize);4] = [(0,0), (1,0), (0,1), (1,1)];
+        let (nx, ny) = self.dims();
         let mut height = f32::MIN;
         //  Ignore out of range entries
         for offsets in INSERT_OFFSETS {
-            if let Some(next_height) = self.heights.get(x + offsets.0, y + offsets.1) {
-                height = height.max(*next_height);
+            let (ix, iy) = (x + offsets.0, y + offsets.1);
+            if ix < nx && iy < ny {
+                height = height.max(self.sample(ix, iy));
             }
         }
         assert!(height > f32::MIN);
@@ -350,13 +793,19 @@ impl HeightField {
     /// Preserve values from all edge pixels 
     /// so that adjacent tiles will match.
     pub fn halve(&self) -> Self {
+        if !self.reaches_far_edge() {
+            return self.to_shared_edges().halve();
+        }
         //  Must be odd sized.
-        assert_eq!(self.heights.num_columns() % 2, 1);
-        assert_eq!(self.heights.num_rows() % 2, 1);
+        let (nx, ny) = self.dims();
+        assert_eq!(nx % 2, 1);
+        assert_eq!(ny % 2, 1);
         //  Output size info.
-        let cnt_x = (self.heights.num_columns() - 1) / 2 + 1;
-        let cnt_y = (self.heights.num_rows() - 1) / 2 + 1;        
-        let mut heights = Array2D::filled_with(0.0, cnt_x, cnt_y);
+        let cnt_x = (nx - 1) / 2 + 1;
+        let cnt_y = (ny - 1) / 2 + 1;
+        let mut halved = Self::new_from_fn(cnt_x, cnt_y, self.size_x, self.size_y, self.water_level, |_, _| 0.0)
+            .expect("Halved height field is empty");
+        halved.water_level_max = self.water_level_max;
         //  This works like downsizing an image, only slightly differently.
         //  Height field values are points, not pixels.
         //  The edge points should not be averaged with interior points.
@@ -366,45 +815,81 @@ impl HeightField {
         //  Do the interior points.
         for x in 0..cnt_x {
             for y in 0..cnt_y {
-                let xloc = (x as f32 * self.heights.num_columns() as f32) / (cnt_x as f32);
-                let yloc = (y as f32 * self.heights.num_rows() as f32) / (cnt_y as f32);
+                let xloc = (x as f32 * nx as f32) / (cnt_x as f32);
+                let yloc = (y as f32 * ny as f32) / (cnt_y as f32);
                 let height = self.average_height(xloc, yloc);
-                heights.set(x, y, height).unwrap();
+                halved.set_sample(x, y, height);
             }
         }
         //  Now do the edge points
         //////todo!();    // ***MORE***
-        Self {
-            size_x: self.size_x,
-            size_y: self.size_y,
-            water_level: self.water_level,
-            heights,
-        }
+        halved
+    }
+}
+
+/// Differences between two height fields. Errors are in meters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightFieldDiff {
+    /// Largest difference
+    pub max_error: f32,
+    /// Root mean square difference
+    pub rms_error: f32,
+    /// Samples which differ by more than the tolerance
+    pub over_tolerance: usize,
+    /// Bounding box of those samples, lower left and upper right, meters. None if no changes.
+    pub changed_bounds: Option<[(f32, f32); 2]>,
+}
+
+impl HeightFieldDiff {
+    /// True if nothing differs by more than the tolerance.
+    pub fn is_unchanged(&self) -> bool {
+        self.over_tolerance == 0
     }
 }
 
 /// Conversions -- elevation min and max to scale and offset.
+/// A flat region gets this scale, not zero. Every sample is at the offset, which
+/// encodes as 0 and decodes back to the offset exactly, whatever the scale.
+pub const MIN_ELEV_SCALE: f32 = 0.001;
+
+/// Scale and offset for elevations from zmin to zmax.
+/// zmin encodes as 0, zmax as 255.
 pub fn elev_min_max_to_scale_offset(zmin: f32, zmax: f32) -> (f32, f32) {
     let zoffset = zmin;
-    let zscale = zmax - zmin;
+    let zscale = (zmax - zmin).max(MIN_ELEV_SCALE);
     (zscale, zoffset)
 }             
 
+/// Steps in the legacy elevation encoding, which uploaders still send.
+/// There, byte b is offset + scale * b / 256, so 255 falls just short of offset + scale.
+/// Rows stored before schema version 17 were rescaled to the current encoding by that migration.
+pub const LEGACY_ELEV_STEPS: f32 = 256.0;
+
+/// Scale in the current encoding, for a scale in the legacy one. Same bytes, same elevations.
+pub fn scale_from_legacy(legacy_scale: f32) -> f32 {
+    legacy_scale * 255.0 / LEGACY_ELEV_STEPS
+}
+
+/// Scale in the legacy encoding, for a scale in the current one. Inverse of above.
+pub fn scale_to_legacy(scale: f32) -> f32 {
+    scale * LEGACY_ELEV_STEPS / 255.0
+}
+
 /// Conversions -- z as f32 to scaled elevation as u8.
+/// Rounds to nearest, so error is at most half a step, scale / 510.
 pub fn elev_to_u8(z: f32, scale: f32, offset: f32) -> u8 {
-    let z = if scale > 0.001 {
+    let z = if scale >= MIN_ELEV_SCALE {
         (z-offset)/scale
     } else {
         0.0
     };
-    let zint = ((z*256.0).floor() as usize).clamp(0, 255);
-    zint as u8
+    (z*255.0).round().clamp(0.0, 255.0) as u8
 }
 
 /// Conversions -- scaled elevation as u8 to z as f32.
-/// Inverse of above.
+/// Inverse of above. 0 is the offset, 255 is offset + scale.
 pub fn u8_to_elev(z: u8, scale: f32, offset: f32) -> f32 {
-    let z = (z as f32) / 256.0; // into 0..1
+    let z = (z as f32) / 255.0; // into 0..1
     z * scale + offset
 }
 
@@ -429,7 +914,8 @@ fn test_height_field() {
 
 #[test]
 /// Create four height maps and merge them.
-/// ARE ROWS AND COLUMNS REVERSED IN ARRAY2D? CODE ASSUMES THAT SUBSCRIPTS x, y are row, column. 
+/// Each inner vec below is one value of Y, running along X. from_columns makes
+/// it an Array2D column, and Array2D columns are Y.
 fn test_combine() {
     //  Construct rows
     let ll = vec![
@@ -461,7 +947,9 @@ fn test_combine() {
         Some(HeightField {
             size_x: 5,
             size_y: 5,
+            spacing: [1.25, 1.25],
             water_level: 20.0,
+            water_level_max: None,
             heights: a
             }
         )
@@ -473,20 +961,43 @@ fn test_combine() {
     let quadrants: [Option<HeightField>;4] = [lla, lra, ula, ura];
     let combined = HeightField::combine(quadrants).expect("HeightField combine failed");
     //  Check result
-    for x in 0..combined.heights.num_columns() {
-        for y in 0..combined.heights.num_rows() {
+    let (nx, ny) = combined.dims();
+    for x in 0..nx {
+        for y in 0..ny {
             let expected = x as f32 + 1.0 + (y as f32 + 1.0) * 100.0;
-            let actual = combined.heights.get(x,y).unwrap();
-            if expected != *actual {
+            let actual = combined.sample(x, y);
+            if expected != actual {
                 panic!("Test combine failed at ({}, {}): expected {}, actual {}", x, y, expected, actual);
             }
         }
     }
+    assert_eq!((combined.water_level, combined.water_level_max), (20.0, None));
     //  Now halve this
     let half_combined = HeightField::halve(&combined);
     println!("Halved combined: {:?}", half_combined);
 }
 
+#[test]
+fn test_combine_water_levels() {
+    let flat = |water_level: f32| Some(HeightField::new_from_fn(3, 3, 256, 256, water_level, |_, _| 30.0).expect("flat"));
+    //  One region has higher water. Lowest is the water level, and the highest is kept.
+    let combined = HeightField::combine([flat(20.0), flat(20.0), flat(20.0), flat(25.0)]).expect("combine");
+    assert_eq!((combined.water_level, combined.water_level_max), (20.0, Some(25.0)));
+    assert_eq!(combined.halve().water_level_max, Some(25.0));
+    //  Uniform, or nearly so, has no highest.
+    let combined = HeightField::combine([flat(20.0), flat(20.0), flat(20.0), flat(20.0)]).expect("combine");
+    assert_eq!((combined.water_level, combined.water_level_max), (20.0, None));
+    let combined = HeightField::combine([flat(20.0), None, flat(20.005), None]).expect("combine");
+    assert_eq!((combined.water_level, combined.water_level_max), (20.0, None));
+    //  SL water at 0 is a water level like any other.
+    let combined = HeightField::combine([flat(0.0), flat(20.0), None, None]).expect("combine");
+    assert_eq!((combined.water_level, combined.water_level_max), (0.0, Some(20.0)));
+    //  The next LOD up sees the range of the tile below.
+    let lower = HeightField::combine([flat(20.0), flat(25.0), None, None]).expect("combine").halve();
+    let combined = HeightField::combine([Some(lower), flat(22.0), None, None]).expect("combine");
+    assert_eq!((combined.water_level, combined.water_level_max), (20.0, Some(25.0)));
+}
+
 #[test]
 fn test_conversions() {
     let min = 100.0;
@@ -497,9 +1008,364 @@ fn test_conversions() {
         let z = zindex as f32 + min;
         let zu8 = elev_to_u8(z, scale, offset);
         let znew = u8_to_elev(zu8, scale, offset);
-        //  There's some error in reducing to u8, but not too much.
-        if (z-znew).abs() > 0.75 {
+        //  Error is at most half a step.
+        if (z-znew).abs() > scale / 510.0 + 0.001 {
             panic!("Conversions failed: {:.5} -> {} -> {:.5}",  z, zu8, znew);
         }
     }    
 }
+
+#[test]
+fn test_conversion_endpoints() {
+    //  Min and max are exact, at 0 and 255.
+    let (scale, offset) = elev_min_max_to_scale_offset(-12.5, 87.5);
+    assert_eq!((elev_to_u8(-12.5, scale, offset), elev_to_u8(87.5, scale, offset)), (0, 255));
+    assert_eq!((u8_to_elev(0, scale, offset), u8_to_elev(255, scale, offset)), (-12.5, 87.5));
+    //  Just under max rounds up to 255. Just over min rounds down to 0.
+    assert_eq!(elev_to_u8(87.4, scale, offset), 255);
+    assert_eq!(elev_to_u8(-12.4, scale, offset), 0);
+    //  Out of range is clamped.
+    assert_eq!((elev_to_u8(-100.0, scale, offset), elev_to_u8(100.0, scale, offset)), (0, 255));
+}
+
+#[test]
+fn test_legacy_elev_encoding() {
+    //  Uploader's encoding: byte / 256 * scale + offset. Decoded the same way as before.
+    let (legacy_scale, offset) = (51.2, 20.0);
+    for b in [0u8, 1, 128, 200, 255] {
+        let legacy = b as f32 / LEGACY_ELEV_STEPS * legacy_scale + offset;
+        assert!((u8_to_elev(b, scale_from_legacy(legacy_scale), offset) - legacy).abs() < 0.0001, "{}", b);
+    }
+    //  An upload as the LSL script sends it. 0x80 is half way up the legacy scale.
+    let upload = r#"{"grid":"agni","region_coords":[1000,1000],"name":"Test","elevs":["0080","FF00"],"scale":256.0,"offset":10.0,"water_lev":20.0}"#;
+    let height_field = UploadedRegionInfo::parse(upload).expect("parse").to_height_field().expect("height field");
+    for ((x, y), expected) in [((0, 0), 10.0), ((0, 1), 138.0), ((1, 0), 265.0)] {
+        assert!((height_field.sample(x, y) - expected).abs() < 0.001, "({}, {}): {}", x, y, height_field.sample(x, y));
+    }
+    //  A height field sent as an upload comes back within half a step.
+    let (scale, _) = elev_min_max_to_scale_offset(10.0, 265.0);
+    let rebuilt = UploadedRegionInfo::builder().grid("agni").from_height_field(&height_field).build().expect("build");
+    assert!((rebuilt.elev_scale() - scale).abs() < 0.0001);
+    let diff = rebuilt.to_height_field().expect("rebuilt").diff(&height_field, scale / 510.0 + 0.001).expect("diff");
+    assert!(diff.is_unchanged(), "{:?}", diff);
+    assert!((scale_to_legacy(scale_from_legacy(legacy_scale)) - legacy_scale).abs() < 0.0001);
+}
+
+#[test]
+fn test_flat_conversions() {
+    //  Flat terrain has a small scale, not zero, and comes back exactly.
+    let (scale, offset) = elev_min_max_to_scale_offset(21.25, 21.25);
+    assert_eq!((scale, offset), (MIN_ELEV_SCALE, 21.25));
+    assert_eq!(elev_to_u8(21.25, scale, offset), 0);
+    assert_eq!(u8_to_elev(0, scale, offset), 21.25);
+    let flat = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |_, _| 21.25).unwrap();
+    let (scale, offset, elevs) = flat.into_sculpt_array().unwrap();
+    assert!(elevs.iter().flatten().all(|&z| z == 0));
+    let stored = HeightField::new_from_unscaled_elevs(&elevs, 256, 256, scale, offset, 20.0).unwrap();
+    assert_eq!(stored, flat);
+    //  Zero scale, as in older rows, still decodes to the offset.
+    assert_eq!(elev_to_u8(30.0, 0.0, 21.25), 0);
+    assert_eq!(u8_to_elev(0, 0.0, 21.25), 21.25);
+}
+
+#[test]
+fn test_height_field_accessors() {
+    //  Ramp, 5 x 5 samples over 256 meters, so samples are 64 meters apart.
+    //  Height is 4 per sample in X, 1 per sample in Y. Scale 255 makes the u8 values the heights.
+    let (nx, ny) = (5usize, 5usize);
+    let elevs: Vec<u8> = (0..nx).flat_map(|ix| (0..ny).map(move |iy| (ix * 4 + iy) as u8)).collect();
+    let height_field = HeightField::new_from_elevs_blob(&elevs, nx as u32, ny as u32, 256, 256, 255.0, 0.0, 20.0).expect("ramp");
+    assert_eq!(height_field.dims(), (5, 5));
+    assert_eq!(height_field.sample(0, 0), 0.0);
+    assert_eq!(height_field.sample(2, 3), 11.0);
+    assert_eq!(height_field.sample(4, 4), 20.0);
+    //  Sample points, and half way between them.
+    assert_eq!(height_field.elevation_at_meters(64.0, 128.0), Some(6.0));
+    assert_eq!(height_field.elevation_at_meters(32.0, 0.0), Some(2.0));
+    assert_eq!(height_field.elevation_at_meters(0.0, 32.0), Some(0.5));
+    assert_eq!(height_field.elevation_at_meters(32.0, 32.0), Some(2.5));
+    assert_eq!(height_field.elevation_at_meters(256.0, 256.0), Some(20.0));
+    //  Outside is clamped to the edge.
+    assert_eq!(height_field.elevation_at_meters(-10.0, 300.0), Some(4.0));
+    //  No samples, no elevation.
+    let empty = HeightField { heights: Array2D::filled_with(0.0, 0, 0), ..height_field.clone() };
+    assert_eq!(empty.elevation_at_meters(128.0, 128.0), None);
+    assert!(height_field.diff(&empty, 0.0).is_err());
+    assert_eq!(empty.to_shared_edges(), empty);
+    //  Stats
+    assert_eq!(height_field.min_max(), (0.0, 20.0));
+    assert_eq!(height_field.mean(), 10.0);
+    assert_eq!(height_field.get_scale_offset().expect("scale offset"), (20.0, 0.0));
+    //  Slope is the same everywhere on a ramp, inside and at the edges.
+    for (ix, iy) in [(2, 2), (0, 0), (4, 4), (0, 3)] {
+        assert_eq!(height_field.slope_at(ix, iy), (4.0 / 64.0, 1.0 / 64.0), "({}, {})", ix, iy);
+    }
+}
+
+#[test]
+fn test_is_effectively_water() {
+    //  Sea floor, all under water. Also flat at exactly water level, which has a scale of about 0.
+    let under = HeightField::new_from_fn(5, 5, 256, 256, 20.0, |x, y| 2.0 + (x + y) as f32).unwrap();
+    assert!(under.is_effectively_water(0.0));
+    let flat = HeightField::new_from_fn(5, 5, 256, 256, 20.0, |_, _| 20.0).unwrap();
+    assert!(flat.is_effectively_water(0.0));
+    //  A sandbar just breaking the surface is land, unless within tolerance.
+    let sandbar = HeightField::new_from_fn(5, 5, 256, 256, 20.0, |x, y| if (x, y) == (2, 2) { 20.05 } else { 19.0 }).unwrap();
+    assert!(!sandbar.is_effectively_water(0.0));
+    assert!(!sandbar.is_effectively_water(0.01));
+    assert!(sandbar.is_effectively_water(0.1));
+}
+
+#[test]
+fn test_height_field_diff() {
+    //  Same terrain, 0..50 meters, encoded with two different scales and offsets.
+    let heights = |ix: usize, iy: usize| 10.0 + ix as f32 * 5.0 + iy as f32 * 2.0;
+    let encode = |n: usize, scale: f32, offset: f32, bump: Option<(usize, usize)>| {
+        let elevs: Vec<u8> = (0..n).flat_map(|ix| (0..n).map(move |iy| (ix, iy)))
+            .map(|(ix, iy)| elev_to_u8(heights(ix, iy) + if bump == Some((ix, iy)) { 8.0 } else { 0.0 }, scale, offset))
+            .collect();
+        HeightField::new_from_elevs_blob(&elevs, n as u32, n as u32, 256, 256, scale, offset, 20.0).expect("height field")
+    };
+    let a = encode(5, 64.0, 0.0, None);
+    let b = encode(5, 48.0, 8.0, None);
+    let diff = a.diff(&b, 0.5).expect("diff");
+    assert!(diff.is_unchanged(), "{:?}", diff);
+    assert!(diff.max_error < 0.5, "{:?}", diff);
+    assert_eq!(diff.changed_bounds, None);
+    //  One sample changed.
+    let bumped = encode(5, 64.0, 0.0, Some((3, 1)));
+    let diff = a.diff(&bumped, 0.5).expect("diff");
+    assert_eq!(diff.over_tolerance, 1);
+    assert!((diff.max_error - 8.0).abs() < 0.5, "{:?}", diff);
+    assert!(diff.rms_error > 0.0 && diff.rms_error < diff.max_error);
+    assert_eq!(diff.changed_bounds, Some([(192.0, 64.0), (192.0, 64.0)]));
+    //  Density mismatch. A plane interpolates exactly, so 9 x 9 samples match 5 x 5.
+    let plane = |n: usize, scale: f32| {
+        let step = 4.0 / (n - 1) as f32;
+        let elevs: Vec<u8> = (0..n).flat_map(|ix| (0..n).map(move |iy| (ix, iy)))
+            .map(|(ix, iy)| elev_to_u8(heights(0, 0) + ix as f32 * step * 5.0 + iy as f32 * step * 2.0, scale, 0.0))
+            .collect();
+        HeightField::new_from_elevs_blob(&elevs, n as u32, n as u32, 256, 256, scale, 0.0, 20.0).expect("height field")
+    };
+    let coarse = plane(5, 64.0);
+    let fine = plane(9, 64.0);
+    assert!(coarse.diff(&fine, 0.5).expect("diff").is_unchanged());
+    assert!(fine.diff(&coarse, 0.5).expect("diff").is_unchanged());
+    //  Different areas can't be compared.
+    let big = HeightField::new_from_elevs_blob(&vec![0; 25], 5, 5, 512, 512, 64.0, 0.0, 20.0).expect("height field");
+    assert!(a.diff(&big, 0.5).is_err());
+}
+
+#[test]
+fn test_terrain_upload_parse() {
+    let deletion = TerrainUpload::parse(r#"{"grid":"agni", "region_coords":[1000,1000], "deleted":true}"#).expect("deletion");
+    assert_eq!(deletion, TerrainUpload::Deletion(RegionDeletion { grid: "agni".to_string(), region_coords: [GlobalMeters(256000), GlobalMeters(256000)], region_coords_units: Some(CoordUnits::Meters), deleted: true, nonce: None, sent_at: None }));
+    //  Undeletion is by upload, not by this.
+    assert!(matches!(TerrainUpload::parse(r#"{"grid":"agni", "region_coords":[1000,1000], "deleted":false}"#), Err(Error::BadRequest(_))));
+    assert!(matches!(TerrainUpload::parse(r#"{"grid":"agni", "deleted":true}"#), Err(Error::JsonParse(_))));
+    let upload = TerrainUpload::parse(r#"{"grid":"agni", "region_coords":[1000,1000], "name":"Test", "elevs":["0102","0304"], "scale":1.0, "offset":0.0, "water_lev":20.0}"#)
+        .expect("upload");
+    match upload {
+        TerrainUpload::Region(region_info) => assert_eq!(region_info.name, "Test"),
+        _ => panic!("Upload parsed as deletion"),
+    }
+    assert!(matches!(TerrainUpload::parse("not json"), Err(Error::JsonParse(_))));
+    //  Replay fields, on either kind. Absent on older uploads.
+    assert_eq!((deletion.nonce(), deletion.sent_at()), (None, None));
+    let deletion = TerrainUpload::parse(r#"{"grid":"agni", "region_coords":[1000,1000], "deleted":true, "nonce":"d1", "sent_at":1750000000}"#).expect("deletion");
+    assert_eq!((deletion.nonce(), deletion.sent_at()), (Some("d1"), Some(1750000000)));
+    let upload = TerrainUpload::parse(r#"{"grid":"agni", "region_coords":[1000,1000], "name":"Test", "elevs":["0102"], "scale":1.0, "offset":0.0, "water_lev":20.0, "nonce":"u1", "sent_at":1750000001}"#)
+        .expect("upload");
+    assert_eq!((upload.nonce(), upload.sent_at()), (Some("u1"), Some(1750000001)));
+}
+
+#[test]
+fn test_elevation_errors() {
+    let region_info = |elevs: &[&str]| UploadedRegionInfo::new("agni".to_string(), GlobalMeters(256000), GlobalMeters(256000), 256, 256, "Test".to_string(),
+        elevs.iter().map(|s| s.to_string()).collect(), 1.0, 0.0, 20.0);
+    //  Bad hex.
+    assert!(matches!(region_info(&["00ZZ", "0102"]).get_elevs_as_blob(), Err(Error::HexDecode(_))));
+    assert!(matches!(region_info(&["001", "012"]).get_unscaled_elevs(), Err(Error::HexDecode(_))));
+    //  Empty and ragged elevs.
+    assert!(matches!(region_info(&[]).get_samples(), Err(Error::ElevationFormat(_))));
+    assert!(matches!(region_info(&["0001", "01"]).get_samples(), Err(Error::ElevationFormat(_))));
+    assert!(matches!(HeightField::new_from_unscaled_elevs(&Vec::new(), 256, 256, 1.0, 0.0, 20.0), Err(Error::ElevationFormat(_))));
+    //  Bad dimensions.
+    assert!(matches!(HeightField::new_from_elevs_blob(&vec![0; 10], 3, 3, 256, 256, 1.0, 0.0, 20.0), Err(Error::Dimensions(_))));
+    assert!(matches!(HeightField::new_from_fn(0, 3, 256, 256, 20.0, |_, _| 0.0), Err(Error::Dimensions(_))));
+    assert!(matches!(UploadedRegionInfo::elevs_blob_to_hex(&[0; 9], 4, 2), Err(Error::Dimensions(_))));
+    let a = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |_, _| 0.0).unwrap();
+    let b = HeightField::new_from_fn(3, 3, 512, 256, 20.0, |_, _| 0.0).unwrap();
+    assert!(matches!(a.diff(&b, 0.5), Err(Error::Dimensions(_))));
+    assert!(matches!(HeightField::combine([None, None, None, None]), Err(Error::Dimensions(_))));
+    //  All of these are the client's fault.
+    assert_eq!(region_info(&[]).get_samples().unwrap_err().http_status(), 400);
+}
+
+#[test]
+fn test_elevs_blob_to_hex() {
+    //  Blob to hex and back, through get_elevs_as_blob.
+    let round_trip = |samples_x: u32, samples_y: u32, size: [u32; 2]| {
+        let blob: Vec<u8> = (0..samples_x * samples_y).map(|n| (n * 7 % 256) as u8).collect();
+        let hex = UploadedRegionInfo::elevs_blob_to_hex(&blob, samples_x, samples_y).expect("to hex");
+        assert_eq!(hex.len(), samples_x as usize);
+        assert!(hex.iter().all(|s| s.len() == samples_y as usize * 2));
+        let region_info = UploadedRegionInfo::new("agni".to_string(), GlobalMeters(256000), GlobalMeters(256000), size[0], size[1], "Test".to_string(), hex, 1.0, 0.0, 20.0);
+        assert_eq!(region_info.get_samples().unwrap(), [samples_x, samples_y]);
+        assert_eq!(region_info.get_elevs_as_blob().unwrap(), blob);
+    };
+    round_trip(3, 3, [256, 256]);
+    //  2:1 varregion, sampled every 4 meters.
+    round_trip(64, 128, [256, 512]);
+    //  Shared edges make odd sample counts, which don't divide the region size.
+    round_trip(257, 257, [256, 256]);
+    round_trip(65, 129, [256, 512]);
+    //  Length must match.
+    assert!(matches!(UploadedRegionInfo::elevs_blob_to_hex(&[0; 10], 3, 3), Err(Error::Dimensions(_))));
+    assert!(matches!(UploadedRegionInfo::elevs_blob_to_hex(&[], 0, 0), Err(Error::Dimensions(_))));
+    //  The old guess works only when the samples have the region's shape, and now fails without panicking otherwise.
+    #[allow(deprecated)]
+    {
+        assert_eq!(UploadedRegionInfo::elevs_blob_to_hex_from_size(&[0; 64 * 128], 256, 512).unwrap().len(), 64);
+        assert_eq!(UploadedRegionInfo::elevs_blob_to_hex_from_size(&[0; 257 * 257], 256, 256).unwrap().len(), 257);
+        assert!(matches!(UploadedRegionInfo::elevs_blob_to_hex_from_size(&[0; 65 * 129], 256, 512), Err(Error::Dimensions(_))));
+        assert!(matches!(UploadedRegionInfo::elevs_blob_to_hex_from_size(&[0; 10], 256, 256), Err(Error::Dimensions(_))));
+    }
+}
+
+#[test]
+fn test_height_field_orientation() {
+    //  3 samples in X, 5 in Y, so a transpose can't go unnoticed.
+    //  The marker is at the lower right corner, (2, 0), X east, Y north.
+    let marker = |x: usize, y: usize| if (x, y) == (2, 0) { 200u8 } else { (x * 10 + y) as u8 };
+    let elevs: Vec<Vec<u8>> = (0..3).map(|x| (0..5).map(|y| marker(x, y)).collect()).collect();
+    let blob: Vec<u8> = elevs.iter().flatten().cloned().collect();
+    let from_blob = HeightField::new_from_elevs_blob(&blob, 3, 5, 256, 512, 255.0, 0.0, 20.0).expect("from blob");
+    let from_elevs = HeightField::new_from_unscaled_elevs(&elevs, 256, 512, 255.0, 0.0, 20.0).expect("from elevs");
+    let from_fn = HeightField::new_from_fn(3, 5, 256, 512, 20.0, |x, y| marker(x, y) as f32).expect("from fn");
+    assert_eq!(from_blob.dims(), (3, 5));
+    assert_eq!(from_blob, from_elevs);
+    assert_eq!(from_blob, from_fn);
+    assert_eq!(from_blob.sample(2, 0), 200.0);
+    assert_eq!(from_blob.sample(1, 4), 14.0);
+    //  Lower right corner, in meters.
+    assert_eq!(from_blob.elevation_at_meters(256.0, 0.0), Some(200.0));
+    //  Sculpt array comes back out [x][y].
+    let (_, _, sculpt) = from_blob.into_sculpt_array().expect("sculpt array");
+    assert_eq!((sculpt.len(), sculpt[0].len()), (3, 5));
+    assert_eq!(sculpt[2][0], sculpt.iter().flatten().cloned().max().unwrap());
+    //  Ragged columns are rejected, not transposed.
+    assert!(matches!(HeightField::new_from_unscaled_elevs(&vec![vec![0; 5], vec![0; 4]], 256, 512, 255.0, 0.0, 20.0), Err(Error::ElevationFormat(_))));
+    //  Combined, the lr quadrant's marker lands at the lower right of the whole.
+    let combined = HeightField::combine([None, Some(from_blob.clone()), None, None]).expect("combine");
+    assert_eq!(combined.dims(), (5, 9));
+    assert_eq!((combined.size_x, combined.size_y), (512, 1024));
+    assert_eq!(combined.sample(4, 0), 200.0);
+    assert_eq!(combined.sample(0, 8), 0.0);
+    let halved = combined.halve();
+    assert_eq!(halved.dims(), (3, 5));
+    //  Mismatched quadrants are an error.
+    let other = HeightField::new_from_fn(5, 3, 256, 512, 20.0, |_, _| 0.0).expect("other");
+    assert!(matches!(HeightField::combine([Some(from_blob), Some(other), None, None]), Err(Error::Dimensions(_))));
+}
+
+#[test]
+fn test_uploaded_region_info_round_trip() {
+    //  As the uploader sends it, 3 x 5 samples.
+    const UPLOAD: &str = r#"{"grid":"agni","region_coords":[1807,1199],"size":[256,512],"name":"Vallone","elevs":["00102030FF","40506070E0","8090A0B0C0"],"scale":100.0,"offset":20.0,"water_lev":20.0}"#;
+    let region_info = UploadedRegionInfo::parse(UPLOAD).expect("parse");
+    let height_field = region_info.to_height_field().expect("height field");
+    assert_eq!(height_field.dims(), (3, 5));
+    let rebuilt = UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(462592), GlobalMeters(306944)).name("Vallone")
+        .from_height_field(&height_field).build().expect("build");
+    assert_eq!((rebuilt.region_coords, rebuilt.size, rebuilt.water_lev), (region_info.region_coords, Some([256, 512]), 20.0));
+    //  Requantized, so scale and offset can differ. Elevations match within one step of either.
+    let rebuilt_height_field = rebuilt.to_height_field().expect("rebuilt height field");
+    let tolerance = region_info.scale.max(rebuilt.scale) / 256.0;
+    let diff = height_field.diff(&rebuilt_height_field, tolerance).expect("diff");
+    assert_eq!(diff.over_tolerance, 0, "{:?}", diff);
+    //  Through JSON and back.
+    let json = rebuilt.to_json().expect("to json");
+    assert!(json.contains(r#""water_lev":20.0"#));
+    assert_eq!(UploadedRegionInfo::parse(&json).expect("reparse"), rebuilt);
+    //  Absent size stays absent.
+    let no_size = UploadedRegionInfo { size: None, ..rebuilt };
+    assert!(!no_size.to_json().expect("to json").contains("size"));
+    //  Elevations are required.
+    assert!(matches!(UploadedRegionInfo::builder().grid("agni").build(), Err(Error::ElevationFormat(_))));
+}
+
+#[test]
+fn test_region_coords_units() {
+    //  The same region, as sent by an uploader using region grid units and by one using meters.
+    const GRID_UNITS: &str = r#"{"grid":"agni","region_coords":[1807,1199],"name":"Vallone","elevs":["0102","0304"],"scale":1.0,"offset":0.0,"water_lev":20.0}"#;
+    const METERS: &str = r#"{"grid":"agni","region_coords":[462592,306944],"name":"Vallone","elevs":["0102","0304"],"scale":1.0,"offset":0.0,"water_lev":20.0}"#;
+    let from_grid_units = UploadedRegionInfo::parse(GRID_UNITS).expect("grid units");
+    let from_meters = UploadedRegionInfo::parse(METERS).expect("meters");
+    assert_eq!(from_grid_units.region_coords, [GlobalMeters(462592), GlobalMeters(306944)]);
+    assert_eq!(from_grid_units, from_meters);
+    //  Written back out in meters, saying so.
+    let json = from_grid_units.to_json().unwrap();
+    assert!(json.contains(r#""region_coords":[462592,306944],"region_coords_units":"meters""#), "{}", json);
+    assert_eq!(UploadedRegionInfo::parse(&json).unwrap(), from_meters);
+    //  Units given override the guess. Small numbers said to be meters stay meters.
+    let regions = GRID_UNITS.replace(r#""name""#, r#""region_coords_units":"regions","name""#);
+    assert_eq!(UploadedRegionInfo::parse(&regions).unwrap(), from_meters);
+    let near_origin = UploadedRegionInfo::parse(&GRID_UNITS.replace("[1807,1199]", r#"[512,1024],"region_coords_units":"meters""#)).expect("near origin");
+    assert_eq!(near_origin.region_coords, [GlobalMeters(512), GlobalMeters(1024)]);
+    //  Deletions too, in either units.
+    for deletion in [r#"{"grid":"agni","region_coords":[1807,1199],"deleted":true}"#, r#"{"grid":"agni","region_coords":[462592,306944],"deleted":true}"#] {
+        let deletion = TerrainUpload::parse(deletion).expect("deletion");
+        assert!(matches!(deletion, TerrainUpload::Deletion(RegionDeletion { region_coords: [GlobalMeters(462592), GlobalMeters(306944)], .. })));
+    }
+    //  Meters not on a region boundary are rejected, as are units that aren't.
+    assert!(matches!(UploadedRegionInfo::parse(&METERS.replace("462592", "462593")), Err(Error::BadRequest(_))));
+    assert!(matches!(UploadedRegionInfo::parse(&METERS.replace("[462592,306944]", r#"[462592,306944],"region_coords_units":"feet""#)), Err(Error::JsonParse(_))));
+}
+
+#[test]
+fn test_sample_spacing() {
+    //  The survey script's 4 m lattice on a 256 m region. Linear terrain, so interpolation is exact.
+    let terrain = |x: f32, y: f32| x * 0.25 + y * 0.5;
+    let lattice = |n: usize| HeightField::new_from_fn(n, n, 256, 256, 20.0, |x, y| terrain(x as f32 * 4.0, y as f32 * 4.0)).expect("lattice");
+    //  Legacy, 65 samples reaching the far edge. Explicit 4 m is the same thing.
+    let legacy = lattice(65);
+    assert_eq!(legacy.spacing, [4.0, 4.0]);
+    assert!(legacy.reaches_far_edge());
+    assert_eq!(legacy.explicit_spacing(), None);
+    assert_eq!(legacy.clone().with_spacing(Some([4.0, 4.0])).expect("explicit"), legacy);
+    assert_eq!(legacy.clone().with_spacing(None).expect("none"), legacy);
+    //  64 samples from 0 to 252 m. Inferred spacing puts the samples in the wrong places.
+    let inferred = lattice(64);
+    assert!((inferred.elevation_at_meters(128.0, 128.0).unwrap() - terrain(128.0, 128.0)).abs() > 1.0);
+    let short = inferred.with_spacing(Some([4.0, 4.0])).expect("4 m spacing");
+    assert!(!short.reaches_far_edge());
+    assert_eq!(short.explicit_spacing(), Some([4.0, 4.0]));
+    for (x, y) in [(0.0, 0.0), (128.0, 128.0), (130.0, 2.0), (252.0, 252.0)] {
+        assert!((short.elevation_at_meters(x, y).unwrap() - terrain(x, y)).abs() < 0.001, "({}, {})", x, y);
+    }
+    //  Resampled to reach the far edges, for combine and the sculpt.
+    let shared = short.to_shared_edges();
+    assert_eq!((shared.dims(), shared.spacing), ((65, 65), [4.0, 4.0]));
+    assert!((shared.sample(40, 10) - terrain(160.0, 40.0)).abs() < 0.001);
+    assert_eq!(shared.sample(64, 0), short.sample(63, 0));
+    assert_eq!(HeightField::combine([Some(short.clone()), None, None, None]).expect("combine").dims(), (129, 129));
+    assert_eq!(short.halve().dims(), (33, 33));
+    //  Spacings which don't tile the region, or don't fit the samples, are rejected.
+    for bad in [[3.0, 4.0], [4.0, 8.0], [0.0, 4.0], [-4.0, 4.0], [f32::NAN, 4.0], [4.0, f32::INFINITY]] {
+        assert!(matches!(lattice(64).with_spacing(Some(bad)), Err(Error::Dimensions(_))), "{:?}", bad);
+    }
+    //  Uploads carry the spacing, and legacy uploads don't.
+    let region_info = UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(256000), GlobalMeters(256000)).name("Test")
+        .from_height_field(&short).build().expect("build");
+    assert_eq!(region_info.sample_spacing, Some([4.0, 4.0]));
+    let json = region_info.to_json().expect("to json");
+    assert!(json.contains(r#""sample_spacing":[4.0,4.0]"#), "{}", json);
+    let height_field = UploadedRegionInfo::parse(&json).expect("parse").to_height_field().expect("height field");
+    assert_eq!((height_field.dims(), height_field.spacing), ((64, 64), [4.0, 4.0]));
+    let region_info = UploadedRegionInfo::builder().grid("agni").from_height_field(&legacy).build().expect("build");
+    assert_eq!(region_info.sample_spacing, None);
+    assert!(!region_info.to_json().expect("to json").contains("sample_spacing"));
+    let bad = UploadedRegionInfo { sample_spacing: Some([3.0, 3.0]), ..region_info };
+    assert!(matches!(bad.to_height_field(), Err(Error::Dimensions(_))));
+}
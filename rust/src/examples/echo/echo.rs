@@ -1,12 +1,22 @@
 //! FCGI echo server.
 //! For test use.
-use std::collections::HashMap;
-use std::io::Write;
-//////use std::io::BufReader;
+//!
+//! Run by the web server, it talks FCGI over the socket the web server passes it.
+//! Run as
+//!
+//!     echo --listen 127.0.0.1:9000
+//!
+//! it listens on that TCP address instead, one connection at a time, so
+//! fcgi_client, or a web server configured for a remote responder, can talk to it.
+//! See echohandler for what it replies.
+use std::io::BufReader;
+use std::net::TcpListener;
 use anyhow::Error;
 use log::LevelFilter;
 use common::init_fcgi;
-use common::{Handler, Request, Response};
+
+mod echohandler;
+use echohandler::EchoHandler;
 
 /// Debug logging
 fn logger() {
@@ -21,45 +31,37 @@ fn logger() {
     log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
 }
 
-//  Our data
-struct EchoHandler {
-    cnt: usize,
-}
-impl EchoHandler {
-    pub fn new() -> Self {
-        Self { cnt: 0 }
-    }
-}
-//  Our "handler"
-impl Handler for EchoHandler {
-    fn handler(
-        &mut self,
-        out: &mut dyn Write,
-        request: &Request,
-        env: &HashMap<String, String>,
-    ) -> Result<(), Error> {
-        // Dummy up a response
-        self.cnt += 1;
-        let http_response = Response::http_response("text/plain", 200, "OK");
-        //  Return something useful.
-        let b = format!(
-            "Env: {:?}\nParams: {:?}\ntally: {}",
-            env, request.params, self.cnt
-        )
-        .into_bytes();
-        Response::write_response(out, request, http_response.as_slice(), &b)?;
-        Ok(())
+/// Serve FCGI on a TCP address, one connection at a time, until killed.
+fn run_listener(addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("Listening on {:?}", listener.local_addr()?);
+    //  Tally runs across connections.
+    let mut echo_handler = EchoHandler::new();
+    for socket in listener.incoming() {
+        let socket = socket?;
+        let outsocket = socket.try_clone()?;
+        let mut instream = BufReader::new(socket);
+        let mut outio = std::io::BufWriter::new(outsocket);
+        if let Err(e) = common::run(&mut instream, &mut outio, &mut echo_handler) {
+            log::error!("Connection failed: {:?}", e);
+        }
     }
+    Ok(())
 }
 
 /// Main program
 pub fn main() {
     logger(); // start logging
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--listen" {
+        run_listener(&args[2]).expect("Listener failed");
+        return;
+    }
     log::info!(
         "stdin points to {}",
         std::fs::read_link("/proc/self/fd/0").unwrap().display()
     );
-    log::info!("Environment: {:?}", std::env::vars());
+    log::info!("Environment: {:?}", common::environment_names());
     //  Set up in and out sockets.
     //  Communication with the parent process is via a UNIX socket.
     //  This is a pain to set up, because UNIX sockets are badly mis-matched
@@ -0,0 +1,127 @@
+//! echohandler.rs -- the echo server's handler.
+//! For test use.
+//!
+//! Replies are controlled by the query string:
+//!
+//!     ?status=NNN   reply with this HTTP status
+//!     ?sleep=MS     wait this long before replying, for timeout tests
+//!     ?size=N       reply with N bytes of generated data, for chunking tests
+//!
+//! Otherwise, a request with a body gets the body back, byte for byte,
+//! and one without gets a dump of its params and environment.
+//!
+//! Separate from echo.rs so the integration tests can run it.
+//
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::Duration;
+use anyhow::{anyhow, Error};
+use common::{Handler, Request, Response};
+
+/// Longest sleep allowed, milliseconds.
+const MAX_SLEEP_MS: u64 = 60_000;
+/// Largest generated reply, bytes.
+const MAX_GENERATED_SIZE: usize = 64 * 1024 * 1024;
+
+/// N bytes of generated data. Lower case letters, repeating, so truncation and reordering show.
+pub fn generated_bytes(n: usize) -> Vec<u8> {
+    (0..n).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+/// What the query string asked for.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EchoRequest {
+    /// HTTP status to reply with
+    pub status: Option<u16>,
+    /// Delay before replying
+    pub sleep: Option<Duration>,
+    /// Generated reply size
+    pub size: Option<usize>,
+}
+
+impl EchoRequest {
+    /// Parse the query string. Unknown keys are ignored.
+    pub fn new_from_query(query_string: &str) -> Result<Self, Error> {
+        let mut echo_request = Self::default();
+        for (k, v) in querystring::querify(query_string) {
+            let number = || v.trim().parse::<u64>().map_err(|_| anyhow!("\"{}\" is \"{}\", not a number", k, v));
+            match k {
+                "status" => {
+                    let status = number()?;
+                    if !(100..=599).contains(&status) {
+                        return Err(anyhow!("Status {} is not 100..599", status));
+                    }
+                    echo_request.status = Some(status as u16);
+                }
+                "sleep" => echo_request.sleep = Some(Duration::from_millis(number()?.min(MAX_SLEEP_MS))),
+                "size" => {
+                    let size = number()? as usize;
+                    if size > MAX_GENERATED_SIZE {
+                        return Err(anyhow!("Size {} is over {}", size, MAX_GENERATED_SIZE));
+                    }
+                    echo_request.size = Some(size);
+                }
+                _ => {}
+            }
+        }
+        Ok(echo_request)
+    }
+}
+
+//  Our data
+pub struct EchoHandler {
+    pub cnt: usize,
+}
+impl EchoHandler {
+    pub fn new() -> Self {
+        Self { cnt: 0 }
+    }
+}
+//  Our "handler"
+impl Handler for EchoHandler {
+    fn handler(
+        &mut self,
+        out: &mut dyn Write,
+        request: &Request,
+        env: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        self.cnt += 1;
+        let query_string = request.params.as_ref().and_then(|p| p.get("QUERY_STRING")).map(|s| s.as_str()).unwrap_or("");
+        let echo_request = match EchoRequest::new_from_query(query_string) {
+            Ok(echo_request) => echo_request,
+            Err(e) => {
+                let msg = format!("Bad echo request: {}", e);
+                return Response::write_response(out, request, Response::http_response("text/plain", 400, &msg).as_slice(), msg.as_bytes());
+            }
+        };
+        if let Some(sleep) = echo_request.sleep {
+            std::thread::sleep(sleep);
+        }
+        let status = echo_request.status.unwrap_or(200) as usize;
+        let (content_type, b) = if let Some(size) = echo_request.size {
+            ("text/plain", generated_bytes(size))
+        } else if !request.standard_input.is_empty() {
+            ("application/octet-stream", request.standard_input.clone())
+        } else {
+            //  Sorted, so replies can be compared.
+            let mut params: Vec<_> = request.params.iter().flatten().collect();
+            params.sort();
+            ("text/plain", format!("Env: {:?}\nParams: {:?}\ntally: {}", env, params, self.cnt).into_bytes())
+        };
+        let mut http_response = Response::http_response(content_type, status, "Echo");
+        http_response.push(format!("X-Echo-Tally: {}", self.cnt));
+        Response::write_response(out, request, http_response.as_slice(), &b)
+    }
+}
+
+#[test]
+fn test_echo_request() {
+    assert_eq!(EchoRequest::new_from_query("").unwrap(), EchoRequest::default());
+    let echo_request = EchoRequest::new_from_query("status=418&sleep=20&size=100000&other=x").unwrap();
+    assert_eq!(echo_request, EchoRequest { status: Some(418), sleep: Some(Duration::from_millis(20)), size: Some(100_000) });
+    assert_eq!(EchoRequest::new_from_query("sleep=999999999").unwrap().sleep, Some(Duration::from_millis(MAX_SLEEP_MS)));
+    for bad in ["status=99", "status=600", "status=ok", "size=-1", "size=99999999999", "sleep=soon"] {
+        assert!(EchoRequest::new_from_query(bad).is_err(), "{}", bad);
+    }
+    assert_eq!(generated_bytes(28), b"abcdefghijklmnopqrstuvwxyzab");
+}
@@ -0,0 +1,90 @@
+//! FCGI test client.
+//! For test use.
+//!
+//! Sends one transaction to a responder listening on TCP, such as
+//! "echo --listen", the way a web server would, and prints the reply.
+//!
+//!     fcgi_client 127.0.0.1:9000 --query "status=418&size=100000"
+//!     fcgi_client 127.0.0.1:9000 --method POST --body upload.json -H X-Authtoken-Name=UPLOADER_1
+//!
+//! Headers are sent as HTTP_ params, as a web server would. The reply's
+//! status and headers go to stderr, and the body to stdout, unchanged.
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Error};
+use common::{fcgi_transaction, header_param, read_reply};
+
+/// Request ID. Anything but 0, which is for management records.
+const REQUEST_ID: u16 = 1;
+
+/// Params for a request, as a web server builds them.
+fn build_params(method: &str, query: &str, body: &[u8], headers: &[String]) -> Result<Vec<(String, String)>, Error> {
+    let mut params = vec![
+        ("REQUEST_METHOD".to_string(), method.to_uppercase()),
+        ("QUERY_STRING".to_string(), query.to_string()),
+        ("CONTENT_LENGTH".to_string(), body.len().to_string()),
+        ("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string()),
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+    ];
+    for header in headers {
+        let (name, value) = header.split_once('=').ok_or_else(|| anyhow!("Header \"{}\" is not NAME=VALUE", header))?;
+        params.push((header_param(name), value.to_string()));
+    }
+    Ok(params)
+}
+
+/// Send one transaction and print the reply.
+fn run(args: &[String]) -> Result<(), Error> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("m", "method", "HTTP method, default GET, or POST if there's a body.", "METHOD");
+    opts.optopt("q", "query", "Query string, without the \"?\".", "QUERY");
+    opts.optopt("b", "body", "File to send as the body. - for standard input.", "FILE");
+    opts.optmulti("H", "header", "HTTP header to send.", "NAME=VALUE");
+    opts.optopt("t", "timeout", "Give up waiting for the reply after this many seconds.", "SECS");
+    let matches = opts.parse(&args[1..])?;
+    let [addr] = matches.free.as_slice() else {
+        return Err(anyhow!("{}", opts.usage(&format!("Usage: {} HOST:PORT [options]", args[0]))));
+    };
+    let body = match matches.opt_str("body").as_deref() {
+        Some("-") => {
+            let mut b = Vec::new();
+            std::io::stdin().read_to_end(&mut b)?;
+            b
+        }
+        Some(path) => std::fs::read(path)?,
+        None => Vec::new(),
+    };
+    let method = matches.opt_str("method").unwrap_or_else(|| if body.is_empty() { "GET" } else { "POST" }.to_string());
+    let params = build_params(&method, &matches.opt_str("query").unwrap_or_default(), &body, &matches.opt_strs("header"))?;
+    let params: Vec<(&str, &str)> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let mut socket = TcpStream::connect(addr)?;
+    if let Some(timeout) = matches.opt_str("timeout") {
+        let secs: f64 = timeout.parse().map_err(|_| anyhow!("Timeout \"{}\" is not a number of seconds", timeout))?;
+        socket.set_read_timeout(Some(Duration::from_secs_f64(secs)))?;
+    }
+    let start = Instant::now();
+    socket.write_all(&fcgi_transaction(REQUEST_ID, &params, &body))?;
+    //  One transaction per connection. The responder sees EOF after it.
+    socket.shutdown(Shutdown::Write)?;
+    let reply = read_reply(&mut BufReader::new(socket), REQUEST_ID)?;
+    eprintln!("Status {}, {} body bytes in {} Stdout records, {:.3} secs", reply.status, reply.body.len(), reply.stdout_records,
+        start.elapsed().as_secs_f64());
+    for header in &reply.headers {
+        eprintln!("{}", header);
+    }
+    if !reply.stderr.is_empty() {
+        eprintln!("Stderr: {}", String::from_utf8_lossy(&reply.stderr));
+    }
+    std::io::stdout().write_all(&reply.body)?;
+    Ok(())
+}
+
+/// Main program
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
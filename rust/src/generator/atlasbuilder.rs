@@ -0,0 +1,193 @@
+//! atlasbuilder.rs -- pack the textures of sibling tiles into shared atlases.
+//! Part of the Animats impostor system
+//!
+//! In atlas mode, a tile whose texture is one small face doesn't get a texture
+//! of its own. Its face image waits here, with the others from the same block
+//! of 4 x 4 tiles, until the viz group is done. Then each block's images are
+//! packed into one atlas, and the tiles' faces are pointed at their part of it.
+//! See common::textureatlas for the layout.
+//!
+//! Only tiles built on this run are packed. A tile left unchanged keeps the
+//! atlas it already has, so its slot in the new atlas is left black.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use crate::faceplan::FacePlan;
+use common::{ATLAS_SIZE, ATLAS_SLOT_SIZE, AtlasSlot, RegionData, RegionImpostorData, slot_mask};
+use image::{RgbImage, imageops};
+use std::collections::BTreeMap;
+
+/// Which atlas a tile goes in. Tiles of different LODs never share one.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct BlockKey {
+    /// Grid, lower case
+    grid: String,
+    /// LOD of the tiles
+    lod: u8,
+    /// Size of the tiles, meters
+    tile_size: u32,
+    /// Southwest corner of the block, meters
+    block_loc: [u32; 2],
+}
+
+/// An atlas ready to write.
+#[derive(Debug)]
+pub struct PackedAtlas {
+    /// Grid, lower case
+    pub grid: String,
+    /// LOD of the tiles
+    pub lod: u8,
+    /// Size of the tiles, meters
+    pub tile_size: u32,
+    /// Southwest corner of the block, meters
+    pub block_loc: [u32; 2],
+    /// Slots used
+    pub mask: u16,
+    /// The atlas
+    pub image: RgbImage,
+}
+
+/// Face images waiting for their block to be packed.
+#[derive(Debug, Default)]
+pub struct PendingAtlases {
+    /// Face image for each slot, by block.
+    blocks: BTreeMap<BlockKey, BTreeMap<u8, RgbImage>>,
+}
+
+/// The atlas slot for a tile, if its texture can go in an atlas.
+/// It must be square, one face, and no more texels than a slot.
+pub fn atlas_slot_for(region: &RegionData, face_plan: &FacePlan) -> Option<AtlasSlot> {
+    if region.region_size_x != region.region_size_y || face_plan.face_count() != 1 || face_plan.texels_per_face > ATLAS_SLOT_SIZE {
+        return None;
+    }
+    AtlasSlot::new([region.region_loc_x, region.region_loc_y], region.region_size_x).ok()
+}
+
+impl PendingAtlases {
+    /// Hold a tile's face image until its block is packed.
+    pub fn add(&mut self, region: &RegionData, slot: AtlasSlot, image: RgbImage) {
+        let key = BlockKey { grid: region.grid.to_lowercase(), lod: region.lod, tile_size: region.region_size_x, block_loc: slot.block_loc };
+        self.blocks.entry(key).or_default().insert(slot.slot, image);
+    }
+
+    /// Pack every waiting block, in block order, and start over.
+    pub fn take(&mut self) -> Vec<PackedAtlas> {
+        std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(|(key, images)| PackedAtlas {
+                mask: slot_mask(images.keys().copied()),
+                image: pack_atlas(key.block_loc, &images),
+                grid: key.grid,
+                lod: key.lod,
+                tile_size: key.tile_size,
+                block_loc: key.block_loc,
+            })
+            .collect()
+    }
+
+    /// Forget anything waiting.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+/// Pack face images into one atlas. Unused slots are black.
+fn pack_atlas(block_loc: [u32; 2], images: &BTreeMap<u8, RgbImage>) -> RgbImage {
+    let mut atlas = RgbImage::new(ATLAS_SIZE, ATLAS_SIZE);
+    for (&slot, image) in images {
+        let (x, y) = AtlasSlot { block_loc, slot }.image_origin();
+        if image.dimensions() == (ATLAS_SLOT_SIZE, ATLAS_SLOT_SIZE) {
+            imageops::replace(&mut atlas, image, x as i64, y as i64);
+        } else {
+            let resized = imageops::resize(image, ATLAS_SLOT_SIZE, ATLAS_SLOT_SIZE, imageops::FilterType::Triangle);
+            imageops::replace(&mut atlas, &resized, x as i64, y as i64);
+        }
+    }
+    atlas
+}
+
+impl PackedAtlas {
+    /// Point the face of each tile in this atlas at it. Returns the number of impostors changed.
+    pub fn apply(&self, texture_hash: u32, impostors: &mut [RegionImpostorData]) -> usize {
+        let mut changed = 0;
+        for impostor in impostors.iter_mut() {
+            if impostor.grid != self.grid || impostor.impostor_lod != self.lod
+                || impostor.region_size != [self.tile_size, self.tile_size] || impostor.faces.len() != 1 {
+                continue;
+            }
+            let region_loc = impostor.region_loc.map(|c| c.meters());
+            let Ok(slot) = AtlasSlot::new(region_loc, self.tile_size) else { continue };
+            if slot.block_loc != self.block_loc || self.mask & (1 << slot.slot) == 0 {
+                continue;
+            }
+            impostor.faces[0].base_texture_hash = format!("{:08x}", texture_hash);
+            impostor.faces[0].atlas_uv = Some(slot.uv());
+            changed += 1;
+        }
+        changed
+    }
+}
+
+#[test]
+fn test_atlas_slot_for() {
+    use crate::faceplan::{plan_faces, MAX_TEXELS_PER_FACE};
+    use common::test_region;
+    //  A 256m region is one 256 texel face, so it fits.
+    let region = test_region("R1280-2048", 1024 + 256, 2048, 256);
+    let slot = atlas_slot_for(&region, &plan_faces([256, 256], 0, MAX_TEXELS_PER_FACE)).expect("fits");
+    assert_eq!(slot, AtlasSlot { block_loc: [1024, 2048], slot: 1 });
+    //  Bigger faces, or more than one, keep their own textures.
+    let region = RegionData { lod: 1, ..test_region("LOD1", 0, 0, 512) };
+    assert!(atlas_slot_for(&region, &plan_faces([512, 512], 1, MAX_TEXELS_PER_FACE)).is_none());
+    let region = RegionData { lod: 3, ..test_region("LOD3", 0, 0, 2048) };
+    assert!(atlas_slot_for(&region, &plan_faces([2048, 2048], 3, 256)).is_none());
+    //  Not square.
+    let region = RegionData { region_size_y: 512, ..test_region("R0-0", 0, 0, 256) };
+    assert!(atlas_slot_for(&region, &plan_faces([256, 256], 0, MAX_TEXELS_PER_FACE)).is_none());
+}
+
+#[test]
+fn test_pack_atlases() {
+    use common::{test_region, GlobalMeters, RegionImpostorFaceData};
+    //  Three tiles from one block, one from the next block east, one at another LOD.
+    let mut pending = PendingAtlases::default();
+    let color = |n: u8| RgbImage::from_pixel(ATLAS_SLOT_SIZE, ATLAS_SLOT_SIZE, image::Rgb([n, n, n]));
+    for (x, y, n) in [(0, 0, 10), (768, 0, 20), (256, 512, 30), (1024, 0, 40)] {
+        let region = test_region(&format!("R{}-{}", x, y), x, y, 256);
+        pending.add(&region, AtlasSlot::new([x, y], 256).unwrap(), color(n));
+    }
+    //  A small image is scaled up to fill its slot.
+    let small = RgbImage::from_pixel(64, 64, image::Rgb([50, 50, 50]));
+    pending.add(&RegionData { lod: 1, ..test_region("LOD1", 0, 0, 256) }, AtlasSlot::new([0, 0], 256).unwrap(), small);
+    let atlases = pending.take();
+    assert!(pending.take().is_empty());
+    assert_eq!(atlases.len(), 3);
+    let atlas = &atlases[0];
+    assert_eq!((atlas.lod, atlas.block_loc, atlas.mask), (0, [0, 0], 0b0000_0010_0000_1001));
+    assert_eq!(atlas.image.dimensions(), (ATLAS_SIZE, ATLAS_SIZE));
+    //  Slot 0 is the bottom left of the image, slot 3 the bottom right, slot 9 column 1, row 2.
+    assert_eq!(*atlas.image.get_pixel(0, ATLAS_SIZE - 1), image::Rgb([10, 10, 10]));
+    assert_eq!(*atlas.image.get_pixel(ATLAS_SIZE - 1, ATLAS_SIZE - 1), image::Rgb([20, 20, 20]));
+    assert_eq!(*atlas.image.get_pixel(300, 300), image::Rgb([30, 30, 30]));
+    //  Unused slots are black.
+    assert_eq!(*atlas.image.get_pixel(0, 0), image::Rgb([0, 0, 0]));
+    assert_eq!((atlases[1].block_loc, atlases[1].mask), ([1024, 0], 1));
+    assert_eq!(atlases[2].lod, 1);
+    assert_eq!(*atlases[2].image.get_pixel(10, ATLAS_SIZE - 10), image::Rgb([50, 50, 50]));
+    //  Faces of the packed tiles point at their part of the atlas. Others are left alone.
+    let impostor = |x: u32, y: u32| RegionImpostorData {
+        region_loc: [GlobalMeters(x), GlobalMeters(y)], region_size: [256, 256], scale: [256.0, 256.0, 10.0], impostor_lod: 0, lod_distance: 1064.0, viz_group: 1,
+        sculpt_uuid: None, sculpt_hash: None, mesh_uuid: None, mesh_hash: None, elevation_offset: 0.0, water_height: Some(20.0),
+        water_height_max: None, name: None, grid: "agni".to_string(),
+        faces: vec![RegionImpostorFaceData { base_texture_uuid: uuid::Uuid::nil(), emissive_texture_uuid: None,
+            base_texture_hash: "00000001".to_string(), emissive_texture_hash: None, atlas_uv: None }],
+        generation: 0, placeholder: false, water_only: false, provenance_json: None, terrain_hash: None };
+    let mut impostors = vec![impostor(256, 512), impostor(512, 0), impostor(1024, 0)];
+    assert_eq!(atlas.apply(0xabcd1234, &mut impostors), 1);
+    assert_eq!(impostors[0].faces[0].base_texture_hash, "abcd1234");
+    assert_eq!(impostors[0].faces[0].atlas_uv, Some([0.25, 0.5, 0.5, 0.75]));
+    assert_eq!(impostors[1].faces[0].atlas_uv, None);
+    assert_eq!(impostors[2].faces[0].base_texture_hash, "00000001");
+}
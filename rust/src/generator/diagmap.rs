@@ -0,0 +1,195 @@
+//! diagmap.rs -- diagnostic maps of viz groups and LOD tiles.
+//! Part of the Animats impostor system
+//!
+//! Reading debug logs to find out why regions ended up in the wrong
+//! viz group, or why a lower LOD tile is missing, is painful. These
+//! maps show the same information as PNG images, one pixel per tile.
+//!
+//! The group map colors each region by viz group. Each LOD map
+//! colors each tile as land, water, or skipped. Skipped tiles are
+//! all-water tiles which get no impostor because water tiles are off.
+//! Black is nothing at all.
+//!
+//! North is up.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use anyhow::{anyhow, Error};
+use image::{Rgb, RgbImage};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use common::{RegionData, group_tile_size, save_png_atomic};
+use crate::vizgroup::CompletedGroups;
+
+/// Largest map we will make, pixels on a side.
+const MAX_MAP_DIM: u32 = 16384;
+/// Land tile.
+const LAND_COLOR: Rgb<u8> = Rgb([60, 160, 60]);
+/// Water tile, generated.
+const WATER_COLOR: Rgb<u8> = Rgb([30, 72, 98]);
+/// Water tile, not generated.
+const SKIPPED_COLOR: Rgb<u8> = Rgb([128, 128, 128]);
+
+/// Where tiles go in a map image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MapLayout {
+    /// Lower left corner of the map, meters.
+    origin: (u32, u32),
+    /// Size of one pixel, meters.
+    cell: (u32, u32),
+    /// Size of the map, pixels.
+    dim: (u32, u32),
+}
+
+impl MapLayout {
+    /// Layout big enough for all these regions, at the resolution of the smallest.
+    fn new<'a>(regions: impl Iterator<Item = &'a RegionData>) -> Result<Self, Error> {
+        let all: Vec<&RegionData> = regions.collect();
+        let cell = group_tile_size(&all).ok_or_else(|| anyhow!("Nothing to map"))?;
+        let origin = (
+            all.iter().map(|r| r.region_loc_x).min().unwrap() / cell.0 * cell.0,
+            all.iter().map(|r| r.region_loc_y).min().unwrap() / cell.1 * cell.1,
+        );
+        let upper_right = (
+            all.iter().map(|r| r.region_loc_x + r.region_size_x).max().unwrap(),
+            all.iter().map(|r| r.region_loc_y + r.region_size_y).max().unwrap(),
+        );
+        let dim = ((upper_right.0 - origin.0).div_ceil(cell.0), (upper_right.1 - origin.1).div_ceil(cell.1));
+        if dim.0 > MAX_MAP_DIM || dim.1 > MAX_MAP_DIM {
+            return Err(anyhow!("Diagnostic map would be {} x {} pixels, limit is {}", dim.0, dim.1, MAX_MAP_DIM));
+        }
+        Ok(Self { origin, cell, dim })
+    }
+
+    /// Pixel for a location in meters. Y is flipped, so north is up.
+    pub fn pixel(&self, loc: (u32, u32)) -> (u32, u32) {
+        let x = (loc.0 - self.origin.0) / self.cell.0;
+        let y = (loc.1 - self.origin.1) / self.cell.1;
+        (x, self.dim.1 - 1 - y)
+    }
+
+    /// Fill all the pixels covered by a region.
+    fn paint(&self, image: &mut RgbImage, region: &RegionData, color: Rgb<u8>) {
+        for x in (region.region_loc_x..region.region_loc_x + region.region_size_x).step_by(self.cell.0 as usize) {
+            for y in (region.region_loc_y..region.region_loc_y + region.region_size_y).step_by(self.cell.1 as usize) {
+                let (px, py) = self.pixel((x, y));
+                image.put_pixel(px, py, color);
+            }
+        }
+    }
+}
+
+/// A color for each viz group. Never black, so empty space shows.
+fn viz_group_color(viz_group: usize) -> Rgb<u8> {
+    //  Spread consecutive numbers out, so neighboring groups look different.
+    let h = (viz_group as u32).wrapping_add(1).wrapping_mul(0x9e37_79b1);
+    let channel = |shift: u32| 64 + ((h >> shift) & 0xff) as u8 % 192;
+    Rgb([channel(24), channel(16), channel(8)])
+}
+
+/// Map of regions colored by viz group, with its layout.
+/// Viz group IDs are positions in the list, as with a dry run.
+pub fn group_map_image(groups: &CompletedGroups) -> Result<(RgbImage, MapLayout), Error> {
+    let layout = MapLayout::new(groups.iter().flat_map(|group| group.regions()))?;
+    let mut image = RgbImage::new(layout.dim.0, layout.dim.1);
+    for (viz_group, group) in groups.iter().enumerate() {
+        for region in group.regions() {
+            layout.paint(&mut image, region, viz_group_color(viz_group));
+        }
+    }
+    Ok((image, layout))
+}
+
+/// Map of tiles at one LOD, colored by kind, with its layout.
+/// None if there are no tiles at this LOD.
+pub fn lod_map_image(tiles: &[Rc<RegionData>], lod: u8, water_tiles: bool) -> Result<Option<(RgbImage, MapLayout)>, Error> {
+    let at_lod = tiles.iter().map(|t| &**t).filter(|t| t.lod == lod);
+    if at_lod.clone().next().is_none() {
+        return Ok(None);
+    }
+    let layout = MapLayout::new(at_lod.clone())?;
+    let mut image = RgbImage::new(layout.dim.0, layout.dim.1);
+    for tile in at_lod {
+        let color = match (tile.is_water, water_tiles) {
+            (false, _) => LAND_COLOR,
+            (true, true) => WATER_COLOR,
+            (true, false) => SKIPPED_COLOR,
+        };
+        layout.paint(&mut image, tile, color);
+    }
+    Ok(Some((image, layout)))
+}
+
+/// Write the viz group map.
+pub fn render_group_map(groups: &CompletedGroups, path: &Path) -> Result<(), Error> {
+    let (image, _) = group_map_image(groups)?;
+    save_png_atomic(path, &image)?;
+    log::info!("Viz group map saved: \"{}\"", path.display());
+    Ok(())
+}
+
+/// Write one map per LOD, named prefix-lodN.png. Returns the files written.
+/// Tiles should include water tiles, so skipped ones can be shown.
+pub fn render_lod_maps(tiles: &[Rc<RegionData>], water_tiles: bool, prefix: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    let max_lod = tiles.iter().map(|t| t.lod).max().unwrap_or(0);
+    for lod in 0..=max_lod {
+        if let Some((image, _)) = lod_map_image(tiles, lod, water_tiles)? {
+            let path = PathBuf::from(format!("{}-lod{}.png", prefix.display(), lod));
+            save_png_atomic(&path, &image)?;
+            log::info!("LOD {} map saved: \"{}\"", lod, path.display());
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[test]
+fn test_group_map() {
+    use crate::vizgroup::{VizGroups, vizgroup_test_patterns};
+    let mut viz_groups = VizGroups::new(false);
+    for item in vizgroup_test_patterns()[0].clone() {
+        viz_groups.add_region_data(item);
+    }
+    let groups = viz_groups.end_grid();
+    assert_eq!(groups.len(), 3);
+    let (image, layout) = group_map_image(&groups).expect("group map");
+    assert_eq!((image.width(), image.height()), (10, 5));
+    let color = |loc: (u32, u32)| {
+        let (x, y) = layout.pixel(loc);
+        *image.get_pixel(x, y)
+    };
+    //  Three groups, three colors.
+    let outer = color((0, 0));
+    let tiny = color((200, 300));
+    let tall = color((700, 0));
+    assert_ne!(outer, tiny);
+    assert_ne!(outer, tall);
+    assert_ne!(tiny, tall);
+    //  Same group, same color.
+    assert_eq!(color((900, 400)), outer);
+    assert_eq!(color((300, 300)), tiny);
+    //  Both cells of a tall region.
+    assert_eq!(color((700, 100)), tall);
+    //  Nothing there.
+    assert_eq!(color((100, 100)), Rgb([0, 0, 0]));
+}
+
+#[test]
+fn test_lod_map() {
+    use crate::regionorder::TileLods;
+    use crate::vizgroup::vizgroup_test_patterns;
+    let tiles: Vec<Rc<RegionData>> = TileLods::new_with_max_lod(vizgroup_test_patterns()[1].clone(), true, None).collect();
+    let water = tiles.iter().find(|t| t.lod == 1 && t.is_water).expect("water tile at LOD 1");
+    let land = tiles.iter().find(|t| t.lod == 1 && !t.is_water).expect("land tile at LOD 1");
+    for (water_tiles, water_color) in [(true, WATER_COLOR), (false, SKIPPED_COLOR)] {
+        let (image, layout) = lod_map_image(&tiles, 1, water_tiles).expect("LOD map").expect("LOD 1 tiles");
+        let (x, y) = layout.pixel((water.region_loc_x, water.region_loc_y));
+        assert_eq!(*image.get_pixel(x, y), water_color);
+        let (x, y) = layout.pixel((land.region_loc_x, land.region_loc_y));
+        assert_eq!(*image.get_pixel(x, y), LAND_COLOR);
+    }
+    assert!(lod_map_image(&tiles, 20, true).expect("LOD map").is_none());
+}
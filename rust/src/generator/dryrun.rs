@@ -0,0 +1,336 @@
+//! dryrun.rs -- statistics for a terrain generation run, without generating anything.
+//! Part of the Animats impostor system
+//!
+//! A full run of generateterrain over Second Life takes hours. Before starting
+//! one, operators want to know how many viz groups there are, how many tiles
+//! will be generated at each LOD, roughly how many assets will have to be
+//! uploaded, and which regions have raw terrain data too old to trust.
+//!
+//! This runs the same transitive closure and LOD ordering as a real run,
+//! but counts tiles instead of building impostors.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use anyhow::Error;
+use serde::Serialize;
+use common::{RegionAge, RegionData, StalenessBuckets};
+use crate::vizgroup::{CompletedGroup, CompletedGroups, OverlapReport, canonicalize_groups};
+use crate::regionorder::{GroupLimits, TileLods, lod_tile_size};
+use crate::faceplan::{plan_faces, MAX_TEXELS_PER_FACE};
+
+/// Rough size of a sculpt image file, bytes. 64x64 PNG.
+const EST_SCULPT_FILE_SIZE: usize = 12 * 1024;
+/// Rough size of a terrain texture image file, bytes. 256x256 PNG, one per face.
+const EST_TEXTURE_FILE_SIZE: usize = 150 * 1024;
+
+/// Dry run options, from the command line.
+#[derive(Debug, Clone)]
+pub struct DryRunOptions {
+    /// Write summary to stdout as JSON instead of text.
+    pub json: bool,
+    /// Regions whose raw terrain is older than this are reported as stale.
+    pub stale_days: u32,
+}
+
+/// Counts for one viz group.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupSummary {
+    /// Viz group number, as a real run would assign it.
+    pub viz_group_id: usize,
+    /// Regions in the group
+    pub regions: usize,
+    /// Tiles which would be generated, indexed by LOD.
+    pub tiles_per_lod: Vec<usize>,
+    /// Texture images which would be generated, one per face of each tile.
+    pub textures: usize,
+    /// Lower LOD tiles skipped because they would be all water.
+    pub water_tiles_skipped: usize,
+    /// Group is smaller than the minimum group size, and would not be generated.
+    pub skipped: bool,
+}
+
+impl GroupSummary {
+    /// Count the tiles for one group.
+    /// This must follow the same rules as TerrainGenerator::process_group.
+    pub fn new(group: CompletedGroup, viz_group_id: usize, varregion_lods: bool, limits: &GroupLimits) -> Self {
+        let CompletedGroup { regions: group, bounds, .. } = group;
+        let regions = group.len();
+        if limits.skip_group(regions) {
+            return Self { viz_group_id, regions, tiles_per_lod: Vec::new(), textures: 0, water_tiles_skipped: 0, skipped: true };
+        }
+        let mut tiles_per_lod = Vec::new();
+        let mut textures = 0;
+        let mut count_tile = |region: &RegionData| {
+            let lod = region.lod as usize;
+            if tiles_per_lod.len() <= lod {
+                tiles_per_lod.resize(lod + 1, 0);
+            }
+            tiles_per_lod[lod] += 1;
+            textures += plan_faces([region.region_size_x, region.region_size_y], region.lod, MAX_TEXELS_PER_FACE).face_count();
+        };
+        let region_size_opt = lod_tile_size(&group, varregion_lods);
+        let water_tiles_skipped = if region_size_opt.is_some() && group.len() > 1 && limits.lower_lods() {
+            //  Do the LOD thing.
+            let mut tile_lods = TileLods::new_with_bounds(group, bounds, false, limits.max_lod);
+            for region in tile_lods.by_ref() {
+                count_tile(&region);
+            }
+            tile_lods.water_tiles_skipped()
+        } else {
+            //  LOD 0 only.
+            for region in &group {
+                count_tile(region);
+            }
+            0
+        };
+        Self {
+            viz_group_id,
+            regions,
+            tiles_per_lod,
+            textures,
+            water_tiles_skipped,
+            skipped: false,
+        }
+    }
+
+    /// Total tiles, all LODs.
+    pub fn tiles(&self) -> usize {
+        self.tiles_per_lod.iter().sum()
+    }
+}
+
+/// Totals over all groups of a grid.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SummaryTotals {
+    /// Regions in all groups
+    pub regions: usize,
+    /// Groups too small to generate
+    pub groups_skipped: usize,
+    /// Regions in groups too small to generate
+    pub regions_skipped: usize,
+    /// Tiles which would be generated, indexed by LOD.
+    pub tiles_per_lod: Vec<usize>,
+    /// Lower LOD tiles skipped because they would be all water.
+    pub water_tiles_skipped: usize,
+    /// Texture images, one per face of each tile.
+    pub textures: usize,
+    /// Assets which would be generated, if none are reused.
+    pub estimated_assets: usize,
+    /// Output directory size, bytes, if none are reused.
+    pub estimated_output_bytes: usize,
+}
+
+/// Dry run summary for one grid.
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunSummary {
+    /// Grid name
+    pub grid: String,
+    /// Groups, biggest first.
+    pub groups: Vec<GroupSummary>,
+    /// Totals
+    pub totals: SummaryTotals,
+    /// Smallest group generated, regions.
+    pub min_group_size: usize,
+    /// Highest LOD generated, if limited.
+    pub max_lod: Option<u8>,
+    /// Staleness threshold used.
+    pub stale_days: u32,
+    /// All regions with raw terrain, by age.
+    pub staleness: StalenessBuckets,
+    /// Regions with raw terrain older than stale_days, or no elevations.
+    pub stale_regions: Vec<RegionAge>,
+    /// Regions which overlap other regions.
+    pub overlaps: Vec<OverlapReport>,
+}
+
+impl DryRunSummary {
+    /// Count everything for one grid.
+    /// Groups are numbered in canonical order, so the summary is the same from run to run.
+    /// Region ages are for every region with raw terrain.
+    pub fn new(grid: &str, mut completed_groups: CompletedGroups, stale_days: u32, region_ages: Vec<RegionAge>, overlaps: Vec<OverlapReport>, varregion_lods: bool, limits: &GroupLimits) -> Self {
+        canonicalize_groups(&mut completed_groups);
+        let groups: Vec<GroupSummary> = completed_groups
+            .into_iter()
+            .enumerate()
+            .map(|(viz_group_id, group)| GroupSummary::new(group, viz_group_id, varregion_lods, limits))
+            .collect();
+        let mut totals = SummaryTotals::default();
+        for group in &groups {
+            totals.regions += group.regions;
+            if group.skipped {
+                totals.groups_skipped += 1;
+                totals.regions_skipped += group.regions;
+            }
+            totals.water_tiles_skipped += group.water_tiles_skipped;
+            totals.textures += group.textures;
+            if totals.tiles_per_lod.len() < group.tiles_per_lod.len() {
+                totals.tiles_per_lod.resize(group.tiles_per_lod.len(), 0);
+            }
+            for (lod, count) in group.tiles_per_lod.iter().enumerate() {
+                totals.tiles_per_lod[lod] += count;
+            }
+        }
+        let tiles: usize = totals.tiles_per_lod.iter().sum();
+        //  One sculpt per tile, plus the textures.
+        totals.estimated_assets = tiles + totals.textures;
+        totals.estimated_output_bytes = tiles * EST_SCULPT_FILE_SIZE + totals.textures * EST_TEXTURE_FILE_SIZE;
+        let staleness = StalenessBuckets::new_from_ages(&region_ages);
+        let stale_regions = region_ages.into_iter().filter(|age| age.missing_elevs || age.age_days >= stale_days).collect();
+        Self {
+            grid: grid.to_string(),
+            groups,
+            totals,
+            min_group_size: limits.min_group_size,
+            max_lod: limits.max_lod,
+            stale_days,
+            staleness,
+            stale_regions,
+            overlaps,
+        }
+    }
+
+    /// As JSON, for scripting.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl std::fmt::Display for DryRunSummary {
+    /// Human-readable summary.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        //  Tiles per LOD as "LOD0 LOD1 ..."
+        fn lod_counts(v: &[usize]) -> String {
+            v.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(" ")
+        }
+        writeln!(f, "Grid \"{}\": {} viz groups, {} regions.", self.grid, self.groups.len(), self.totals.regions)?;
+        writeln!(f, "{:>8} {:>8} {:>8} {:>8}  Tiles by LOD", "Group", "Regions", "Tiles", "Water")?;
+        for group in &self.groups {
+            if group.skipped {
+                writeln!(f, "{:>8} {:>8}  skipped", group.viz_group_id, group.regions)?;
+                continue;
+            }
+            writeln!(f, "{:>8} {:>8} {:>8} {:>8}  {}",
+                group.viz_group_id, group.regions, group.tiles(), group.water_tiles_skipped, lod_counts(&group.tiles_per_lod))?;
+        }
+        if self.totals.groups_skipped > 0 {
+            writeln!(f, "Groups skipped, smaller than {} regions: {} ({} regions)",
+                self.min_group_size, self.totals.groups_skipped, self.totals.regions_skipped)?;
+        }
+        if let Some(max_lod) = self.max_lod {
+            writeln!(f, "LODs limited to:     {}", max_lod)?;
+        }
+        writeln!(f, "Tiles by LOD:        {}", lod_counts(&self.totals.tiles_per_lod))?;
+        writeln!(f, "Water tiles skipped: {}", self.totals.water_tiles_skipped)?;
+        writeln!(f, "Estimated assets:    {}", self.totals.estimated_assets)?;
+        writeln!(f, "Estimated output:    {:.1} MB", self.totals.estimated_output_bytes as f64 / (1024.0 * 1024.0))?;
+        writeln!(f, "Raw terrain age:     {}", self.staleness)?;
+        writeln!(f, "Regions with raw terrain older than {} days or missing: {}", self.stale_days, self.stale_regions.len())?;
+        for stale in &self.stale_regions {
+            if stale.missing_elevs {
+                writeln!(f, "    \"{}\" ({}, {}): no elevations", stale.name, stale.region_loc[0], stale.region_loc[1])?;
+            } else {
+                writeln!(f, "    \"{}\" ({}, {}): {} days", stale.name, stale.region_loc[0], stale.region_loc[1], stale.age_days)?;
+            }
+        }
+        writeln!(f, "Overlapping region pairs: {}", self.overlaps.len())?;
+        for overlap in &self.overlaps {
+            writeln!(f, "    {}", overlap)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_dry_run_counts() {
+    use common::test_logger;
+    use crate::vizgroup::{VizGroups, vizgroup_test_patterns};
+    test_logger();
+    for test_data in vizgroup_test_patterns() {
+        let region_count = test_data.len();
+        let mut viz_groups = VizGroups::new(false);
+        for item in test_data {
+            assert_eq!(viz_groups.add_region_data(item), None);
+        }
+        let summary = DryRunSummary::new("Test", viz_groups.end_grid(), 30, vec![], viz_groups.take_overlaps(), false, &GroupLimits::default());
+        log::info!("Dry run summary:\n{}", summary);
+        //  Every region appears exactly once, at LOD 0.
+        assert_eq!(summary.totals.regions, region_count);
+        assert_eq!(summary.totals.tiles_per_lod[0], region_count);
+        //  Biggest groups first.
+        assert!(summary.groups.windows(2).all(|w| w[0].regions >= w[1].regions));
+        //  Each multi-LOD group ends with exactly one tile covering the whole group.
+        for group in &summary.groups {
+            if group.tiles_per_lod.len() > 1 {
+                assert_eq!(*group.tiles_per_lod.last().unwrap(), 1);
+            }
+        }
+        let tiles: usize = summary.totals.tiles_per_lod.iter().sum();
+        //  At least one texture per tile, plus a sculpt.
+        assert!(summary.totals.textures >= tiles);
+        assert_eq!(summary.totals.estimated_assets, tiles + summary.totals.textures);
+    }
+}
+
+#[test]
+fn test_dry_run_water_tiles() {
+    use crate::vizgroup::{VizGroups, vizgroup_test_patterns};
+    //  Pattern 1 is homogeneous, so it gets lower LODs, and its ring shape has water in the middle.
+    let mut viz_groups = VizGroups::new(false);
+    for item in vizgroup_test_patterns()[1].clone() {
+        viz_groups.add_region_data(item);
+    }
+    let summary = DryRunSummary::new("Test", viz_groups.end_grid(), 30, vec![], viz_groups.take_overlaps(), false, &GroupLimits::default());
+    assert!(summary.totals.tiles_per_lod.len() > 1);
+    assert!(summary.totals.water_tiles_skipped > 0);
+}
+
+#[test]
+/// Small groups are listed but skipped, and lower LODs stop at the cap.
+fn test_dry_run_limits() {
+    use crate::vizgroup::{VizGroups, vizgroup_test_patterns};
+    let groups = |pattern: usize| {
+        let mut viz_groups = VizGroups::new(false);
+        for item in vizgroup_test_patterns()[pattern].clone() {
+            viz_groups.add_region_data(item);
+        }
+        viz_groups.end_grid()
+    };
+    for pattern in 0..vizgroup_test_patterns().len() {
+        let all = DryRunSummary::new("Test", groups(pattern), 30, vec![], vec![], false, &GroupLimits::default());
+        let min_group_size = all.groups[0].regions;
+        let limits = GroupLimits { min_group_size, max_lod: Some(1) };
+        let limited = DryRunSummary::new("Test", groups(pattern), 30, vec![], vec![], false, &limits);
+        assert_eq!(limited.groups.len(), all.groups.len());
+        for (group, unlimited) in limited.groups.iter().zip(&all.groups) {
+            assert_eq!(group.skipped, group.regions < min_group_size);
+            if group.skipped {
+                assert_eq!(group.tiles(), 0);
+            } else {
+                assert!(group.tiles_per_lod.len() <= 2);
+                assert_eq!(group.tiles_per_lod[..], unlimited.tiles_per_lod[..group.tiles_per_lod.len()]);
+            }
+        }
+        let skipped: Vec<&GroupSummary> = limited.groups.iter().filter(|g| g.skipped).collect();
+        assert_eq!(limited.totals.groups_skipped, skipped.len());
+        assert_eq!(limited.totals.regions_skipped, skipped.iter().map(|g| g.regions).sum::<usize>());
+        assert_eq!(limited.totals.regions, all.totals.regions);
+        if !skipped.is_empty() {
+            assert!(limited.to_string().contains("skipped"), "{}", limited);
+        }
+    }
+}
+
+#[test]
+fn test_dry_run_staleness() {
+    let age = |name: &str, age_days: u32, missing_elevs: bool| RegionAge { name: name.to_string(), region_loc: [0, 0], age_days, missing_elevs };
+    let ages = vec![age("Fresh", 3, false), age("Month", 45, false), age("Quarter", 120, false), age("Ancient", 800, false), age("Empty", 0, true)];
+    let summary = DryRunSummary::new("Test", Vec::new(), 90, ages, Vec::new(), false, &GroupLimits::default());
+    assert_eq!(summary.staleness, StalenessBuckets { fresh: 1, over_30_days: 1, over_90_days: 1, over_1_year: 1, missing: 1 });
+    let stale: Vec<&str> = summary.stale_regions.iter().map(|r| r.name.as_str()).collect();
+    assert_eq!(stale, vec!["Quarter", "Ancient", "Empty"]);
+    let text = summary.to_string();
+    assert!(text.contains("fresh 1, over 30 days 1, over 90 days 1, over 1 year 1, missing 1"), "{}", text);
+    assert!(text.contains("older than 90 days or missing: 3"), "{}", text);
+}
@@ -0,0 +1,154 @@
+//! faceplan.rs -- how many texture faces an impostor has, and which part of it each covers.
+//! Part of the Animats impostor system
+//!
+//! SL textures are limited in size. A big tile at full texture density
+//! needs more texels than one texture can hold, so its texture is split
+//! into a grid of faces, each a texture of its own. The viewer's impostor
+//! mesh has one face per texture, so the faces sent for an impostor must
+//! follow this plan.
+//!
+//! Faces split the tile in powers of two, so face edges fall on region
+//! edges, and never more finely than one face per LOD 0 region.
+//! There are never more than RegionImpostorFaceData::MAX_TEXTURES faces.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use common::RegionImpostorFaceData;
+use image::{imageops, RgbImage};
+
+/// Largest texture SL will accept, texels on a side.
+pub const MAX_TEXELS_PER_FACE: u32 = 1024;
+/// Texture density wanted, texels per meter.
+const TEXELS_PER_METER: u32 = 1;
+
+/// The part of the impostor one face covers. UV coordinates, 0..1,
+/// origin at the southwest corner, +V north.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceRect {
+    /// Southwest corner
+    pub uv_min: [f32; 2],
+    /// Northeast corner
+    pub uv_max: [f32; 2],
+}
+
+/// Texture faces for one impostor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FacePlan {
+    /// Faces across, west to east.
+    pub faces_x: u32,
+    /// Faces down, south to north.
+    pub faces_y: u32,
+    /// Size of each face's texture, texels on a side.
+    pub texels_per_face: u32,
+    /// The faces, in face order. West to east, then south to north.
+    pub faces: Vec<FaceRect>,
+}
+
+/// Plan the faces for a tile of this size, in meters, at this LOD.
+pub fn plan_faces(region_size: [u32; 2], lod: u8, max_texels_per_face: u32) -> FacePlan {
+    //  Enough faces to stay under the texture size limit, in powers of two,
+    //  but no more than one per LOD 0 region.
+    let max_split = 1u32 << lod.min(16);
+    let split = |size: u32| (size * TEXELS_PER_METER).div_ceil(max_texels_per_face).next_power_of_two().min(max_split);
+    let (mut faces_x, mut faces_y) = (split(region_size[0]), split(region_size[1]));
+    //  Over the face limit, so give up resolution along the longer split.
+    while (faces_x * faces_y) as usize > RegionImpostorFaceData::MAX_TEXTURES {
+        if faces_x > faces_y {
+            faces_x /= 2;
+        } else {
+            faces_y /= 2;
+        }
+    }
+    let texels_per_face = (region_size[0] * TEXELS_PER_METER / faces_x)
+        .max(region_size[1] * TEXELS_PER_METER / faces_y)
+        .min(max_texels_per_face);
+    let faces = (0..faces_y)
+        .flat_map(|iy| (0..faces_x).map(move |ix| FaceRect {
+            uv_min: [ix as f32 / faces_x as f32, iy as f32 / faces_y as f32],
+            uv_max: [(ix + 1) as f32 / faces_x as f32, (iy + 1) as f32 / faces_y as f32],
+        }))
+        .collect();
+    FacePlan { faces_x, faces_y, texels_per_face, faces }
+}
+
+impl FacePlan {
+    /// Number of faces.
+    pub fn face_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    /// Cut a texture for the whole tile into one image per face, in face order.
+    /// The image has north at the top, as map tiles do.
+    /// A face which is already the planned size is used as is, so one face textures are unchanged.
+    pub fn face_images(&self, image: &RgbImage) -> Vec<RgbImage> {
+        let crop_width = image.width() / self.faces_x;
+        let crop_height = image.height() / self.faces_y;
+        self.faces
+            .iter()
+            .map(|face| {
+                let x0 = (face.uv_min[0] * image.width() as f32).round() as u32;
+                //  +V is north, which is the top of the image.
+                let y0 = ((1.0 - face.uv_max[1]) * image.height() as f32).round() as u32;
+                let crop = RgbImage::from_fn(crop_width, crop_height, |x, y| *image.get_pixel(x0 + x, y0 + y));
+                if crop.dimensions() == (self.texels_per_face, self.texels_per_face) {
+                    crop
+                } else {
+                    imageops::resize(&crop, self.texels_per_face, self.texels_per_face, imageops::FilterType::Triangle)
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_plan_faces() {
+    //  One region, one face. Up to the texture size limit, still one face.
+    let plan = plan_faces([256, 256], 0, MAX_TEXELS_PER_FACE);
+    assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (1, 1, 256));
+    assert_eq!(plan.faces, vec![FaceRect { uv_min: [0.0, 0.0], uv_max: [1.0, 1.0] }]);
+    assert_eq!(plan.face_count(), 1);
+    let plan = plan_faces([1024, 1024], 2, MAX_TEXELS_PER_FACE);
+    assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (1, 1, 1024));
+    //  LOD 3, 2048 meters, split in four.
+    let plan = plan_faces([2048, 2048], 3, MAX_TEXELS_PER_FACE);
+    assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (2, 2, 1024));
+    assert_eq!(plan.faces[1], FaceRect { uv_min: [0.5, 0.0], uv_max: [1.0, 0.5] });
+    assert_eq!(plan.faces[2], FaceRect { uv_min: [0.0, 0.5], uv_max: [0.5, 1.0] });
+    //  Each LOD, with small faces. Never finer than a region, never more than 8 faces.
+    for (lod, faces_x, faces_y) in [(0, 1, 1), (1, 2, 2), (2, 4, 2), (3, 4, 2), (4, 4, 2), (5, 4, 2)] {
+        let size = 256 << lod;
+        let plan = plan_faces([size, size], lod, 256);
+        assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (faces_x, faces_y, 256), "LOD {}", lod);
+        assert_eq!(plan.face_count(), (faces_x * faces_y) as usize);
+        assert!(plan.face_count() <= RegionImpostorFaceData::MAX_TEXTURES);
+    }
+    //  A big LOD 0 var region is still one face, capped in size.
+    let plan = plan_faces([2048, 2048], 0, MAX_TEXELS_PER_FACE);
+    assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (1, 1, 1024));
+    //  Long and thin splits only the long way.
+    let plan = plan_faces([2048, 512], 3, 512);
+    assert_eq!((plan.faces_x, plan.faces_y, plan.texels_per_face), (4, 1, 512));
+}
+
+#[test]
+fn test_face_images() {
+    //  One face, already the right size, is the image unchanged.
+    let plan = plan_faces([256, 256], 0, MAX_TEXELS_PER_FACE);
+    let image = RgbImage::from_fn(256, 256, |x, y| image::Rgb([x as u8, y as u8, 7]));
+    assert_eq!(plan.face_images(&image), vec![image]);
+    //  Each quarter of the image a different color. North is the top,
+    //  so face 0, the southwest one, is the bottom left of the image.
+    let plan = FacePlan { texels_per_face: 4, ..plan_faces([2048, 2048], 3, MAX_TEXELS_PER_FACE) };
+    let image = RgbImage::from_fn(8, 8, |x, y| image::Rgb([(x / 4) as u8, (y / 4) as u8, 0]));
+    let faces = plan.face_images(&image);
+    assert_eq!(faces.len(), 4);
+    assert_eq!(*faces[0].get_pixel(0, 0), image::Rgb([0, 1, 0]));
+    assert_eq!(*faces[1].get_pixel(0, 0), image::Rgb([1, 1, 0]));
+    assert_eq!(*faces[2].get_pixel(0, 0), image::Rgb([0, 0, 0]));
+    assert_eq!(*faces[3].get_pixel(0, 0), image::Rgb([1, 0, 0]));
+    //  Resized to the planned size.
+    let plan = FacePlan { texels_per_face: 2, ..plan };
+    assert!(plan.face_images(&image).iter().all(|f| f.dimensions() == (2, 2)));
+}
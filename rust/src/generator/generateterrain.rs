@@ -18,19 +18,54 @@
 mod sculptmaker;
 mod regionorder;
 mod vizgroup;
+mod dryrun;
+mod neededregions;
+mod persistnumbers;
+mod tilecache;
+mod watertiles;
+mod faceplan;
+mod atlasbuilder;
+mod diagmap;
+mod overviewmap;
+mod initialimpostors;
+mod importterrain;
+mod knownregions;
+mod surveyroute;
+mod manifest;
+mod provenance;
+mod regionpages;
 use anyhow::{anyhow, Error};
-use common::{HeightField, RegionImpostorFaceData};
-use envie::Envie;
+use common::{CoordUnits, DEFAULT_LOD_QUALITY, ExportFormat, ExportTable, export_impostors, unix_time_now, write_atomic};
+use common::{AssetKind, AssetName, HeightField, remove_stale_tmp_files, save_png_atomic, write_bytes_atomic, check_raw_terrain, purge_retired_assets, RegionAge, RegionData, RegionImpostorData, RegionImpostorFaceData, faces_from_json, get_region_ages};
+use common::db::{Db, WatchedPool, migrations, refresh_conn};
+use common::hashing::{GenParams, hash_height_field};
+use common::grid::canonical;
 use getopts::Options;
 use log::LevelFilter;
 use mysql::prelude::{Queryable};
 use mysql::{params, PooledConn};
-use mysql::{Pool};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use vizgroup::{CompletedGroups, RegionData, VizGroups};
-use sculptmaker::{TerrainSculpt, TerrainSculptTexture};
-use regionorder::{TileLods, homogeneous_group_size};
+use std::rc::Rc;
+use vizgroup::{CompletedGroup, CompletedGroups, OverlapReport, VizGroups, canonicalize_groups};
+use sculptmaker::{TerrainSculpt, TerrainSculptTexture, SCULPTDIM, calc_rgbimage_hash};
+use faceplan::{plan_faces, MAX_TEXELS_PER_FACE};
+use atlasbuilder::{PendingAtlases, atlas_slot_for};
+use regionorder::{GroupLimits, TileLods, lod_tile_size};
+use dryrun::{DryRunOptions, DryRunSummary};
+use neededregions::{ExistingImpostor, TileKey, TileWork, WantedTile, WorkList, classify_tiles, next_generation};
+use persistnumbers::{VizGroupNumbering};
+use tilecache::{TileCache, TileCacheKey, compose_height_field};
+use watertiles::{DEFAULT_WATER_LEVEL, WaterTileAssets, water_height_field};
+use diagmap::{render_group_map, render_lod_maps};
+use overviewmap::{OverviewMap, render_overview_map};
+use initialimpostors::{CarriedTile, InitialImpostors, assemble_region_impostor_data, assemble_water_only_impostor_data};
+use importterrain::{ImportOptions, ImportSource, import_terrain};
+use knownregions::{import_known_regions, read_placeholders};
+use regionpages::{GridRegions, PageReader, REGION_PAGE_SIZE, page_params};
+use surveyroute::SurveyRoute;
+use manifest::{GeneratorManifest, GeneratorSettings, MANIFEST_FILE_NAME};
+use provenance::{ImpostorProvenance, TerrainSource};
 use ureq::{Agent};
 
 /// MySQL Credentials for uploading.
@@ -46,24 +81,62 @@ use ureq::{Agent};
 ///
 /// The table name is hard-coded.
 ///
-/// Environment variables for obtaining owner info.
-/// ***ADD VALUES FOR OPEN SIMULATOR***
-const _OWNER_NAME: &str = "HTTP_X_SECONDLIFE_OWNER_NAME";
 /// Size of output terrain sculpt textures, pixels.
 const TERRAIN_SCULPT_TEXTURE_SIZE: u32 = 256;
 /// User agent for talking to asset server
 const TERRAIN_GENERATOR_USER_AGENT: &str = "animats.info impostor asset system";
+/// Default age at which raw terrain data is reported as stale in a dry run.
+const DEFAULT_STALE_DAYS: u32 = 365;
+/// Default memory budget for height fields kept for building lower LODs, megabytes.
+const DEFAULT_TILE_CACHE_MB: usize = 256;
+/// Terrain no more than this far above water, meters, is all water as far as impostors go.
+/// It gets no assets, and the viewer's water plane covers it.
+const WATER_ONLY_TOLERANCE: f32 = 0.1;
+
+/// One page of a grid's regions, for transitive_closure. Deleted regions are left out,
+/// so their impostors become stale and are removed. See regionpages.
+const SQL_SELECT_REGIONS: &str = r"SELECT grid, region_loc_x, region_loc_y, region_size_x, region_size_y, name FROM raw_terrain_heights
+    WHERE grid = :grid AND NOT deleted
+        AND (:first OR region_loc_x > :after_x OR (region_loc_x = :after_x AND region_loc_y > :after_y))
+    ORDER BY region_loc_x, region_loc_y LIMIT :page_size";
+
+/// Grids with terrain, for --all-grids.
+const SQL_SELECT_GRIDS: &str = r"SELECT DISTINCT LOWER(grid) FROM raw_terrain_heights WHERE NOT deleted";
+
+/// One row of raw_terrain_heights, as selected by get_height_field_one_region.
+/// Types are explicit so that scale and offset come back as f32.
+/// (region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level, sample_spacing_x, sample_spacing_y,
+/// last_uploaded)
+type RawTerrainRow = (u32, u32, u32, u32, f32, f32, Vec<u8>, String, f32, Option<f32>, Option<f32>, i64);
+
+/// Build a height field from a raw terrain row. Returns region name, height field, and the row as provenance.
+/// Sample spacing is derived from the sample counts unless the row has one.
+fn height_field_from_row(row: RawTerrainRow) -> Result<(String, HeightField, TerrainSource), Error> {
+    let (region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level, spacing_x, spacing_y, last_uploaded) = row;
+    let sample_spacing = match (spacing_x, spacing_y) {
+        (Some(x), Some(y)) => Some([x, y]),
+        _ => None,
+    };
+    let height_field = HeightField::new_from_elevs_blob(
+        &elevs, samples_x, samples_y, region_size_x, region_size_y, scale, offset, water_level,
+    )?.with_spacing(sample_spacing)?;
+    Ok((name, height_field, TerrainSource::new(last_uploaded, &elevs)))
+}
+
+/// Default local log file.
+const DEFAULT_LOG_FILE_NAME: &str = "logs/generatelog.txt";
 
 /// Debug logging
-fn logger() {
-    //  Local log file.
-    const LOG_FILE_NAME: &str = "logs/generatelog.txt";
+fn logger(log_file_name: &str, log_level: LevelFilter) -> Result<(), Error> {
+    let log_file = std::fs::File::create(log_file_name)
+        .map_err(|e| anyhow!("Unable to create log file \"{}\": {:?}", log_file_name, e))?;
     let _ = simplelog::CombinedLogger::init(vec![simplelog::WriteLogger::new(
-        LevelFilter::Debug,
+        log_level,
         simplelog::Config::default(),
-        std::fs::File::create(LOG_FILE_NAME).expect("Unable to create log file"),
+        log_file,
     )]);
-    log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
+    log::warn!("Logging to {:?}", log_file_name); // where the log is going
+    Ok(())
 }
 
 /// Type of UUID
@@ -160,54 +233,159 @@ impl TileHashes {
     }
 }
 
-/// Key for cache of region info for all LODs.
-/// All cache items must be from the same grid.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct RegionLodKey {
-    /// Location in world of region (meters)
-    region_loc_x: u32,
-    /// Location in world of region (meters)
-    region_loc_y: u32, 
-    /// Level of detail.
-    lod: u8,
+/// Read elevation data for one region from the database.
+/// Returns region name, as stored with the elevations, the height field, and where it came from,
+/// or None if there is no such region.
+fn read_height_field(conn: &mut PooledConn, grid: &str, region_loc_x: u32, region_loc_y: u32) -> Result<Option<(String, HeightField, TerrainSource)>, Error> {
+    const SQL_SELECT: &str = r"SELECT region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level,
+                sample_spacing_x, sample_spacing_y, UNIX_TIMESTAMP(last_uploaded)
+            FROM raw_terrain_heights
+            WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
+    let mut height_fields = conn.exec_map(
+        SQL_SELECT,
+        params! { "grid" => grid.to_lowercase(), region_loc_x, region_loc_y },
+        |row: RawTerrainRow| height_field_from_row(row),
+    )?;
+    if height_fields.len() > 1 {
+        //  Duplicate data - warning
+        //  SQL indices should make this impossible.
+        log::error!(
+            "More than one region data set for region at ({},{}) on \"{}\"",
+            region_loc_x,
+            region_loc_y,
+            grid
+        );
+    }
+    height_fields.pop().transpose()
 }
 
-/// Height field cache.
-/// Height fields for LOD 0 come from the database.
-/// Height fields for lower LODs are computed by
-/// combining the height fields of four tiles.
-///
-/// Because of the order in which regionorder
-/// returns the desired regions and LODs, each
-/// heigh field is only needed once. So 
-/// obtaining a height field consumes it.
-/// This bounds the memory required.
-#[derive(Debug)]
-struct HeightFieldCache {
-    /// The cache
-    cache: HashMap<RegionLodKey, HeightField>,
-}
-
-impl HeightFieldCache {
-    /// Usual new
-    fn new() -> Self {
+/// All regions of a grid, in SQL_SELECT_REGIONS order, as LOD 0 regions.
+fn read_grid_regions(db: &mut dyn Db, grid: &str, after: Option<(u32, u32)>, page_size: usize) -> Result<Vec<RegionData>, Error> {
+    db.exec_map(SQL_SELECT_REGIONS, page_params(grid, after, page_size), |row| {
+        Ok(RegionData {
+            grid: row.get(0)?,
+            region_loc_x: row.get(1)?,
+            region_loc_y: row.get(2)?,
+            region_size_x: row.get(3)?,
+            region_size_y: row.get(4)?,
+            name: row.get(5)?,
+            lod: 0,
+            children: Vec::new(),
+            is_water: false,
+            is_placeholder: false,
+        })
+    })
+}
+
+/// Survey route options, from the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouteOptions {
+    /// If set, only regions with raw terrain older than this, or none at all.
+    pub stale_days: Option<u32>,
+}
+
+/// Impostor export options, from the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportOptions {
+    /// Output file
+    pub path: PathBuf,
+    /// CSV or GeoJSON
+    pub format: ExportFormat,
+    /// Live impostors, or the next generation
+    pub table: ExportTable,
+}
+
+/// Database check options, from the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CheckDbOptions {
+    /// Fix what can be fixed.
+    pub repair: bool,
+}
+
+/// Options which control generation.
+/// These are collected in one struct so they can't be mixed up as positional arguments.
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    /// Are regions with only corners touching adjacent?
+    /// Set to true for Open Simulator grids
+    pub corners_touch_connects: bool,
+    /// Generate glTF mesh if on.
+    pub generate_mesh: bool,
+    /// Sculpt image size, pixels on a side.
+    pub sculpt_dim: usize,
+    /// Number of parallel jobs requested.
+    pub jobs: usize,
+    /// If present, count only, generate nothing.
+    pub dry_run: Option<DryRunOptions>,
+    /// Generate lower LODs for groups with regions of different sizes.
+    /// For Open Simulator varregions. Otherwise such groups get LOD 0 only.
+    pub varregion_lods: bool,
+    /// Memory budget for height fields kept for building lower LODs, megabytes.
+    pub tile_cache_mb: usize,
+    /// Generate flat water impostors for lower LOD tiles with no land.
+    /// Otherwise those are left as holes.
+    pub water_tiles: bool,
+    /// Write diagnostic maps of viz groups and LOD tiles at the end of the grid.
+    pub diag_maps: bool,
+    /// Write an overview image of the grid, by elevation and water, at the end of the grid.
+    pub overview_map: bool,
+    /// Pack the textures of small sibling tiles into shared atlases, for fewer uploads.
+    pub atlas: bool,
+    /// Smallest group to generate, and highest LOD.
+    pub group_limits: GroupLimits,
+    /// Quality for each tile's recommended LOD distance. Higher switches to coarser tiles farther out.
+    pub lod_quality: f32,
+    /// Promote this grid's initial impostors to live, generate nothing.
+    pub promote: bool,
+    /// Create or update the database tables, generate nothing.
+    pub migrate: bool,
+    /// If present, check raw_terrain_heights for consistency, generate nothing.
+    pub check_db: Option<CheckDbOptions>,
+    /// If present, forget assets retired more than this many days ago, generate nothing.
+    pub purge_retired: Option<u32>,
+    /// If present, write a survey route for the bot, generate nothing.
+    pub plan_route: Option<RouteOptions>,
+    /// If present, import this terrain into raw_terrain_heights, generate nothing.
+    pub import: Option<ImportOptions>,
+    /// Include known regions with no terrain yet, as flat placeholders.
+    pub known_regions: bool,
+    /// If present, import this region list into known_regions, generate nothing.
+    pub import_known: Option<PathBuf>,
+    /// Units of locations in the import_known list.
+    pub import_known_units: CoordUnits,
+    /// If present, write the grid's impostors to a file, generate nothing.
+    pub export: Option<ExportOptions>,
+}
+
+impl Default for GeneratorOptions {
+    /// Defaults are for Second Life.
+    fn default() -> Self {
         Self {
-            cache: HashMap::new(),
-        }
-    }
-    
-    /// Insert.
-    /// Panics on duplicate insert
-    fn insert(&mut self, key: RegionLodKey, height_map: HeightField) {
-        if self.cache.insert(key.clone(), height_map).is_some() {
-            panic!("Duplicate insert into height field cache: {:?}", key);
+            corners_touch_connects: false,
+            generate_mesh: false,
+            sculpt_dim: SCULPTDIM,
+            jobs: 1,
+            dry_run: None,
+            varregion_lods: false,
+            tile_cache_mb: DEFAULT_TILE_CACHE_MB,
+            water_tiles: false,
+            diag_maps: false,
+            overview_map: false,
+            atlas: false,
+            group_limits: GroupLimits::default(),
+            lod_quality: DEFAULT_LOD_QUALITY,
+            promote: false,
+            migrate: false,
+            check_db: None,
+            purge_retired: None,
+            plan_route: None,
+            import: None,
+            known_regions: false,
+            import_known: None,
+            import_known_units: CoordUnits::Meters,
+            export: None,
         }
     }
-    
-    /// Destructive remove 
-    fn take(&mut self, key: &RegionLodKey) -> Option<HeightField> {
-        self.cache.remove(key)
-    }
 }
 
 /// Statistics for terrain generator
@@ -216,6 +394,30 @@ struct TerrainGeneratorStats {
     assets_generated: usize,
     /// Reused, nothing to upload to SL/OS
     assets_reused: usize,
+    /// Tiles unchanged since last run, not built.
+    tiles_unchanged: usize,
+    /// Tiles which only need a viz group update, not built.
+    viz_group_updates: usize,
+    /// Existing impostors with no raw terrain, to be deleted.
+    stale_impostors: usize,
+    /// Height fields for lower LODs found in the tile cache.
+    tile_cache_hits: usize,
+    /// Height fields for lower LODs which had to be rebuilt.
+    tile_cache_misses: usize,
+    /// Regions skipped as duplicate, out of order, or overlapping.
+    region_order_errors: usize,
+    /// Groups skipped as smaller than the minimum group size.
+    groups_skipped: usize,
+    /// Regions in those groups.
+    regions_in_skipped_groups: usize,
+    /// Impostors added to initial_impostors.
+    impostors_recorded: usize,
+    /// Live impostors of tiles not rebuilt, carried forward into initial_impostors.
+    impostors_carried: usize,
+    /// Groups whose impostors could not be added to initial_impostors.
+    groups_not_recorded: usize,
+    /// Tiles with no land above water, recorded with no assets.
+    water_only_tiles: usize,
 }
 
 impl TerrainGeneratorStats {
@@ -224,6 +426,18 @@ impl TerrainGeneratorStats {
         Self {
             assets_generated: 0,
             assets_reused: 0,
+            tiles_unchanged: 0,
+            viz_group_updates: 0,
+            stale_impostors: 0,
+            tile_cache_hits: 0,
+            tile_cache_misses: 0,
+            region_order_errors: 0,
+            groups_skipped: 0,
+            regions_in_skipped_groups: 0,
+            impostors_recorded: 0,
+            impostors_carried: 0,
+            groups_not_recorded: 0,
+            water_only_tiles: 0,
         }
     }
 }
@@ -231,12 +445,38 @@ impl TerrainGeneratorStats {
 impl std::fmt::Display for TerrainGeneratorStats {
     // Implement `fmt::Display` for the struct
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "Assets generated: {}\nAssets reused:   {}", self.assets_generated, self.assets_reused)
+        writeln!(f, "Assets generated: {}\nAssets reused:   {}", self.assets_generated, self.assets_reused)?;
+        writeln!(f, "Tiles unchanged:  {}\nViz group updates: {}\nStale impostors:  {}",
+            self.tiles_unchanged, self.viz_group_updates, self.stale_impostors)?;
+        writeln!(f, "Tile cache hits:  {}\nTile cache misses: {}", self.tile_cache_hits, self.tile_cache_misses)?;
+        writeln!(f, "Regions skipped:  {}", self.region_order_errors)?;
+        writeln!(f, "Small groups skipped: {} ({} regions)", self.groups_skipped, self.regions_in_skipped_groups)?;
+        writeln!(f, "Water-only tiles: {}", self.water_only_tiles)?;
+        writeln!(f, "Impostors recorded: {}\nImpostors carried forward: {}\nGroups not recorded: {}",
+            self.impostors_recorded, self.impostors_carried, self.groups_not_recorded)
     }
 }
 
+/// State for one grid, while its viz groups arrive from the transitive closure.
+struct GridState {
+    /// Grid name
+    grid: String,
+    /// Impostors from previous runs, by tile.
+    existing: HashMap<TileKey, Vec<ExistingImpostor>>,
+    /// Viz group numbers, kept from previous runs where possible.
+    numbering: VizGroupNumbering,
+    /// Tiles wanted by this run, so far.
+    seen: HashSet<TileKey>,
+    /// All groups, kept only for diagnostic maps.
+    diag_groups: CompletedGroups,
+    /// Region summaries for the overview map, if wanted.
+    overview: Option<OverviewMap>,
+}
+
 /// The terrain object generator
 struct TerrainGenerator {
+    /// SQL connection pool, for reconnecting
+    pool: WatchedPool,
     /// SQL connection
     conn: PooledConn,
     /// Network connection pool
@@ -245,13 +485,20 @@ struct TerrainGenerator {
     outdir: PathBuf,
     /// Asset server URL prefix
     url_prefix_opt: Option<String>,
-    /// Are regions with only corners touching adjacent?
-    /// Set to true for Open Simulator grids
-    corners_touch_connects: bool,
-    /// Generate glTF mesh if on.
-    generate_mesh: bool,
-    /// The height field cache
-    height_field_cache: HeightFieldCache,
+    /// Generation options
+    options: GeneratorOptions,
+    /// Recently built height fields, for building lower LODs.
+    tile_cache: TileCache,
+    /// Shared assets for water tiles made so far.
+    water_tile_assets: WaterTileAssets,
+    /// Water level of the last LOD 0 region, for water tiles.
+    water_level: f32,
+    /// Raw terrain row of the last LOD 0 region read, for its provenance.
+    terrain_source: Option<TerrainSource>,
+    /// Textures waiting to be packed into atlases, in atlas mode.
+    pending_atlases: PendingAtlases,
+    /// State for the grid being processed
+    grid_state: Option<GridState>,
     /// Statistics
     stats: TerrainGeneratorStats,
 }
@@ -259,11 +506,11 @@ struct TerrainGenerator {
 impl TerrainGenerator {
     /// Usual new.
     pub fn new(
+        pool: WatchedPool,
         conn: PooledConn,
         outdir: PathBuf,
         url_prefix_opt: Option<String>,
-        corners_touch_connects: bool,
-        generate_mesh: bool,
+        options: GeneratorOptions,
     ) -> Self {
         //  HTTP connection pool, used to validate UUIDs against asset server.
         let config = Agent::config_builder()
@@ -271,153 +518,129 @@ impl TerrainGenerator {
             .build();
         let agent: Agent = config.into();
         Self {
+            pool,
             conn,
             agent,
             outdir,
             url_prefix_opt,
-            corners_touch_connects,
-            generate_mesh,
-            height_field_cache: HeightFieldCache::new(),
+            tile_cache: TileCache::new_megabytes(options.tile_cache_mb),
+            water_tile_assets: WaterTileAssets::new(options.sculpt_dim, TERRAIN_SCULPT_TEXTURE_SIZE),
+            water_level: DEFAULT_WATER_LEVEL,
+            terrain_source: None,
+            pending_atlases: PendingAtlases::default(),
+            options,
+            grid_state: None,
             stats: TerrainGeneratorStats::new(),
         }
     }
 
-    /// Build visibility group info from database
-    pub fn transitive_closure(&mut self, grid: &str) -> Result<Vec<CompletedGroups>, Error> {
-        let mut vizgroups = VizGroups::new(self.corners_touch_connects);
-        let mut grids = Vec::new();
-        log::info!("Build start"); // ***TEMP***
-                                   //  The loop here is sequential data processing with control breaks when an index field changes.
-        const SQL_SELECT: &str = r"SELECT grid, region_loc_x, region_loc_y, region_size_x, region_size_y, name FROM raw_terrain_heights WHERE LOWER(grid) = :grid ORDER BY grid, region_loc_x, region_loc_y ";
-        let _all_regions = self.conn.exec_map(
-            SQL_SELECT,
-            params! { grid },
-            |(grid, region_loc_x, region_loc_y, region_size_x, region_size_y, name)| {
-                let region_data = RegionData {
-                    grid,
-                    region_loc_x,
-                    region_loc_y,
-                    region_size_x,
-                    region_size_y,
-                    name,
-                    lod: 0,
-                };
-                if let Some(completed_groups) = vizgroups.add_region_data(region_data) {
-                    grids.push(completed_groups);
-                }
-            },
-        )?;
-        grids.push(vizgroups.end_grid());
-        Ok(grids)
+    /// Build visibility group info from database.
+    /// Regions are read a page at a time, and each group is passed to process
+    /// as soon as it is complete, so neither the whole grid's regions nor its
+    /// groups are held at once.
+    /// Returns the number of regions found, and any overlapping regions.
+    pub fn transitive_closure(&mut self, grid: &str, mut process: impl FnMut(&mut Self, CompletedGroup) -> Result<(), Error>) -> Result<(usize, Vec<OverlapReport>), Error> {
+        let placeholders: Option<PageReader> = if self.options.known_regions { Some(read_placeholders) } else { None };
+        let mut all_regions = GridRegions::new(grid, read_grid_regions, placeholders, REGION_PAGE_SIZE);
+        //  Completed groups come back through a channel, so they can be processed
+        //  while the sweep continues.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut vizgroups = VizGroups::new_with_sink(self.options.corners_touch_connects, move |group| {
+            let _ = sender.send(group);
+        });
+        //  The loop here is sequential data processing with control breaks when an index field changes.
+        while let Some(region_data) = all_regions.next(&mut self.conn)? {
+            vizgroups.add_region_data(region_data);
+            for group in receiver.try_iter() {
+                process(self, group)?;
+            }
+        }
+        vizgroups.end_grid();
+        for group in receiver.try_iter() {
+            process(self, group)?;
+        }
+        let overlaps = vizgroups.take_overlaps();
+        if !overlaps.is_empty() {
+            log::warn!("Grid \"{}\": {} overlapping region pairs.", grid, overlaps.len());
+        }
+        if self.options.known_regions {
+            log::info!("Grid \"{}\": {} known regions with no terrain yet, as placeholders.", grid, all_regions.placeholder_count());
+        }
+        Ok((all_regions.count(), overlaps))
     }
 
-    /// Get elevation data for one region.
+    /// Age of every region's raw terrain on this grid.
+    pub fn get_region_ages(&mut self, grid: &str) -> Result<Vec<RegionAge>, Error> {
+        get_region_ages(&mut self.conn, grid, 0)
+    }
+
+    /// Get elevation data for one region, and cache it for building lower LODs.
+    /// Returns region name, as stored with the elevations, and the height field.
+    /// The row it came from is kept for the impostor's provenance.
     pub fn get_height_field_one_region(
         &mut self,
         grid: String,
         region_loc_x: u32,
         region_loc_y: u32,
-    ) -> Result<HeightField, Error> {
-        const SQL_SELECT: &str = r"SELECT region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level
-                FROM raw_terrain_heights
-                WHERE LOWER(grid) = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
-        let grid_for_msg = grid.clone();
-        let mut height_fields = self.conn.exec_map(
-            SQL_SELECT,
-            params! { grid, region_loc_x, region_loc_y },
-            |(region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level)| {
-                let _name_v: String = name;
-                let _water_level_v: f32 = water_level;
-                let height_field = HeightField::new_from_elevs_blob(
-                    &elevs, samples_x, samples_y, region_size_x, region_size_y, scale, offset, water_level,
-                );
-                height_field
-            },
-        )?;
-        if height_fields.is_empty() {
-            return Err(anyhow!(
-                "No raw terrain data for region at ({},{}) on \"{}\"",
-                region_loc_x,
-                region_loc_y,
-                grid_for_msg
-            ));
-        }
-
-        if height_fields.len() > 1 {
-            //  Duplicate data - warning
-            //  SQL indices should make this impossible.
-            log::error!(
-                "More than one region data set for region at ({},{}) on \"{}\"",
-                region_loc_x,
-                region_loc_y,
-                grid_for_msg
-            );
-        }
-        let height_field = height_fields.pop().unwrap()?;
+    ) -> Result<(String, HeightField), Error> {
+        let (name, height_field, source) = self.read_height_field_one_region(grid.clone(), region_loc_x, region_loc_y)?;
+        self.terrain_source = Some(source);
         //  Cache for later generation of lower LODs
-        let key = RegionLodKey { lod: 0, region_loc_x, region_loc_y };
-        self.height_field_cache.insert(key, height_field.clone());
-        Ok(height_field)
+        let key = TileCacheKey { grid, region_loc_x, region_loc_y, lod: 0 };
+        self.tile_cache.insert(key, height_field.clone());
+        Ok((name, height_field))
     }
-    
-    /// Get height field for multiple regions.
-    /// We fetch four regions and merge them.
-    pub fn get_height_field_multi_region(
+
+    /// Read elevation data for one region from the database. No caching.
+    fn read_height_field_one_region(
         &mut self,
-        _grid: String,
+        grid: String,
         region_loc_x: u32,
         region_loc_y: u32,
-        region_size: (u32, u32),
-        lod: u8) -> Result<HeightField, Error> {
-        //  Not for LOD 0. We can't build that from other LODs.
-        assert!(lod > 0);
-        //  Get a relevant region, or None if it's all water.
-        //  May need more checking for missing regions.
-        let mut take = |lod, dx, dy| {
-            let key = RegionLodKey { lod, region_loc_x: region_loc_x + dx, region_loc_y: region_loc_y + dy };
-            log::debug!("Multi region height field needed for LOD {}: {:?}", key.lod, (key.region_loc_x, key.region_loc_y));  // ***TEMP***
-            self.height_field_cache.take(&key)
-        };
-        //  Get the four height fields.
-        //  Region size here is the full sized impostor, so we have to divide by 2 to get the size of the 4 squares that make it up.
-        let height_fields = [
-            take(lod - 1, 0, 0),            
-            take(lod - 1, region_size.0 / 2, 0),
-            take(lod - 1, 0, region_size.1 / 2),
-            take(lod - 1, region_size.0 / 2, region_size.1 / 2)
-        ];
-        //  Generate combined height field;
-        let height_field = HeightField::halve(&HeightField::combine(height_fields)?);
-        let key = RegionLodKey { lod , region_loc_x, region_loc_y };
-        self.height_field_cache.insert(key, height_field.clone());
-        Ok(height_field)
+    ) -> Result<(String, HeightField, TerrainSource), Error> {
+        read_height_field(&mut self.conn, &grid, region_loc_x, region_loc_y)?.ok_or_else(|| anyhow!(
+            "No raw terrain data for region at ({},{}) on \"{}\"",
+            region_loc_x,
+            region_loc_y,
+            grid
+        ))
+    }
+    
+    /// Get height field for a lower LOD tile.
+    /// We take the height fields of its child tiles, built at the LOD above, and merge them.
+    /// Children missing from the tile cache are rebuilt from raw terrain.
+    pub fn get_height_field_multi_region(&mut self, region: &RegionData) -> Result<HeightField, Error> {
+        let conn = &mut self.conn;
+        let mut fetch_lod_0 = |x, y| Ok(read_height_field(conn, &region.grid, x, y)?.map(|(_, height_field, _)| height_field));
+        compose_height_field(&mut self.tile_cache, region, &mut fetch_lod_0)
     }
     
     /// Encoded name for impostor asset file.
     /// The name contains all the info we need to generate the impostor.
-    /// Format: RS_x_y_sx_sy_sz_offset_lod_waterlevel_vizgroup_hash_
+    /// See AssetName for the format.
     fn impostor_name(
-        prefix: &str,
+        kind: AssetKind,
         region: &RegionData,
         height_field: &HeightField,
         lod: u8,
         viz_group_id: usize,
         hash: u32,
     ) -> Result<String, Error> {
-        let x = region.region_loc_x;
-        let y = region.region_loc_y;
         let (scale, offset) = height_field.get_scale_offset()?;
-        let sx = region.region_size_x;
-        let sy = region.region_size_y;
-        let sz = scale;
-        let water_level = height_field.water_level;
-        //////Ok(format!("{}_{}_{}_{}_{}_{:.2}_{:.2}_{}_{}_{:.2}_0x{:016x}", prefix, x, y, sx, sy, sz, offset, lod, viz_group_id, water_level, hash))
-        let s = format!("{}_{}_{}_{}_{}_{:.2}_{:.2}_{}_{}_{:.2}_{:08x}", prefix, x, y, sx, sy, sz, offset, lod, viz_group_id, water_level, hash);
-        if s.len() > 63 {
-            Err(anyhow!("Generated filename is too long: {}", s))
-        } else {
-            Ok(s)
-        }
+        let name = AssetName {
+            kind,
+            grid_tag: AssetName::grid_tag(&region.grid),
+            region_loc: [region.region_loc_x, region.region_loc_y],
+            region_size: [region.region_size_x, region.region_size_y],
+            scale_z: scale,
+            offset,
+            lod,
+            viz_group: viz_group_id.try_into()?,
+            water_level: height_field.water_level,
+            hash,
+            slot_mask: 0,
+        };
+        Ok(name.fit()?.to_string())
     }
     
     /// Get all the hash values for one tile.
@@ -425,13 +648,13 @@ impl TerrainGenerator {
     fn get_hashes_one_tile(&mut self, grid: &str, region_loc_x: u32, region_loc_y: u32, impostor_lod: u8) -> Result<Option<TileHashes>, Error> {
         const SQL_SELECT: &str = r"SELECT sculpt_uuid, sculpt_hash, mesh_uuid, mesh_hash, faces_json
             FROM region_impostors
-            WHERE LOWER(grid) = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND impostor_lod = :impostor_lod";
+            WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y AND impostor_lod = :impostor_lod";
         let tile_hashes = self.conn.exec_map(
             SQL_SELECT,
-            params! { grid, region_loc_x, region_loc_y, impostor_lod },
+            params! { "grid" => grid.to_lowercase(), region_loc_x, region_loc_y, impostor_lod },
             |(sculpt_uuid, sculpt_hash, mesh_uuid, mesh_hash, faces_json)| {
                 let faces_json: String = faces_json;    // type inference needs a hint here
-                let face_data: Vec<RegionImpostorFaceData> = match serde_json::from_str(&faces_json) {
+                let face_data: Vec<RegionImpostorFaceData> = match faces_from_json(&faces_json) {
                     Ok(v) => v,
                     Err(e) => {
                         log::error!("Invalid stored JSON for tile at {} ({}, {}) lod {}: {:?}",
@@ -459,16 +682,16 @@ impl TerrainGenerator {
         }
     }
     
-    /// Build the impostor
+    /// Build the impostor. Returns its initial_impostors data.
     pub fn build_impostor(
         &mut self,
         region: &RegionData,
         height_field: &HeightField,
         viz_group_id: usize,
-    ) -> Result<(), Error> {
+    ) -> Result<RegionImpostorData, Error> {
         let hash_info_opt = self. get_hashes_one_tile(&region.grid, region.region_loc_x, region.region_loc_y, region.lod)?;
         log::debug!("Hash info: {:?}", hash_info_opt);
-        if self.generate_mesh {
+        if self.options.generate_mesh {
             self.build_impostor_mesh(
                 region,
                 height_field,
@@ -511,26 +734,36 @@ impl TerrainGenerator {
         }
     }
 
+    /// Generation parameters which affect the assets made.
+    fn gen_params(&self) -> GenParams {
+        GenParams { sculpt_dim: self.options.sculpt_dim, texture_size: TERRAIN_SCULPT_TEXTURE_SIZE }
+    }
+
+    /// Make the sculpt image for a tile.
+    /// The sculpt's edges are the region's edges, so the samples must reach them.
+    fn make_sculpt(&self, region: &RegionData, height_field: &HeightField) -> Result<TerrainSculpt, Error> {
+        let mut terrain_sculpt = TerrainSculpt::new(&region.name, self.options.sculpt_dim);
+        let (scale, offset, elevs) = height_field.to_shared_edges().into_sculpt_array()?;
+        terrain_sculpt.setelevs(elevs, scale as f64, offset as f64);
+        terrain_sculpt.makeimage();
+        Ok(terrain_sculpt)
+    }
+
     /// Build the impostor as a sculpt.
     pub fn build_impostor_sculpt(
         &mut self,
         region: &RegionData,
         height_field: &HeightField,
         viz_group_id: usize,
-    ) -> Result<(), Error> {
-        const IMPOSTOR_SCULPT_PREFIX: &str = "RS";
-        const IMPOSTOR_TERRAIN_PREFIX: &str = "RT0";
+    ) -> Result<RegionImpostorData, Error> {
         let lod = region.lod;
         let grid = &region.grid;
         log::info!("Generating sculpt for \"{}\": {}", region.name, height_field);
         // TerrainSculpt was translated from Python with an LLM. NEEDS WORK
         //  Do sculpt
-        let mut terrain_sculpt = TerrainSculpt::new(&region.name);
-        let (scale, offset, elevs) = height_field.into_sculpt_array()?;
-        terrain_sculpt.setelevs(elevs, scale as f64, offset as f64);
-        terrain_sculpt.makeimage();
-        let hash = terrain_sculpt.get_hash()?;
-        let sculpt_name = Self::impostor_name(IMPOSTOR_SCULPT_PREFIX, region, height_field, lod, viz_group_id, hash)?;
+        let terrain_sculpt = self.make_sculpt(region, height_field)?;
+        let sculpt_hash = terrain_sculpt.get_hash()?;
+        let sculpt_name = Self::impostor_name(AssetKind::Sculpt, region, height_field, lod, viz_group_id, sculpt_hash)?;
         if self.asset_already_exists(grid, &sculpt_name)? {
             log::info!("Sculpt image asset already exists: {}", sculpt_name);
             self.stats.assets_reused += 1;
@@ -538,28 +771,97 @@ impl TerrainGenerator {
             let sculpt_image = terrain_sculpt.image.unwrap();
             let mut sculpt_image_path = self.outdir.clone();
             sculpt_image_path.push(sculpt_name.to_owned() + ".png");
-            sculpt_image.save(&sculpt_image_path)?;
-            log::info!("Sculpt image file saved: \"{}\"", sculpt_image_path.display());  
+            let written = save_png_atomic(&sculpt_image_path, &sculpt_image)?;
+            log::info!("Sculpt image file saved: \"{}\", SHA-256 {}", written.path.display(), written.hash);
             self.stats.assets_generated += 1;  
         }
-        //  Do texture
+        //  Do texture, one image per planned face
         log::info!("Generating texture image for  \"{}\"", &region.name);
+        let face_plan = plan_faces([region.region_size_x, region.region_size_y], lod, MAX_TEXELS_PER_FACE);
         let mut terrain_image = TerrainSculptTexture::new(region.region_loc_x, region.region_loc_y, lod, &region.name);
         terrain_image.makeimage(TERRAIN_SCULPT_TEXTURE_SIZE)?;
-        let hash = terrain_image.get_hash()?;
-        let terrain_image_name = Self::impostor_name(IMPOSTOR_TERRAIN_PREFIX, region, height_field, lod, viz_group_id, hash)?;
-        if self.asset_already_exists(grid, &terrain_image_name)? {
-            log::info!("Terrain image asset already exists: {}", terrain_image_name);
-            self.stats.assets_reused += 1;
+        let face_images = face_plan.face_images(terrain_image.image.as_ref().expect("Texture image was just made"));
+        let atlas_slot = if self.options.atlas { atlas_slot_for(region, &face_plan) } else { None };
+        let mut faces = Vec::with_capacity(face_images.len());
+        for (face_ix, face_image) in face_images.into_iter().enumerate() {
+            let texture_hash = calc_rgbimage_hash(&face_image);
+            if let Some(slot) = atlas_slot {
+                //  Written with the rest of its block at the end of the group. The face is updated then.
+                self.pending_atlases.add(region, slot, face_image);
+                faces.push(Self::new_face(texture_hash));
+                continue;
+            }
+            let terrain_image_name = Self::impostor_name(AssetKind::Texture(face_ix as u8), region, height_field, lod, viz_group_id, texture_hash)?;
+            if self.asset_already_exists(grid, &terrain_image_name)? {
+                log::info!("Terrain image asset already exists: {}", terrain_image_name);
+                self.stats.assets_reused += 1;
+            } else {
+                let mut terrain_image_path = self.outdir.clone();
+                terrain_image_path.push(terrain_image_name.to_owned() + ".png");
+                let written = save_png_atomic(&terrain_image_path, &face_image)?;
+                log::info!("Terrain image file saved: \"{}\", SHA-256 {}", written.path.display(), written.hash);
+                self.stats.assets_generated += 1;
+            }
+            faces.push(Self::new_face(texture_hash));
+        }
+        assemble_region_impostor_data(region, height_field, viz_group_id as u32, Some(format!("{:08x}", sculpt_hash)), None, faces,
+            self.options.lod_quality)
+    }
+
+    /// Face with a texture not uploaded yet.
+    fn new_face(texture_hash: u32) -> RegionImpostorFaceData {
+        RegionImpostorFaceData {
+            base_texture_uuid: uuid::Uuid::nil(),
+            emissive_texture_uuid: None,
+            base_texture_hash: format!("{:08x}", texture_hash),
+            emissive_texture_hash: None,
+            atlas_uv: None,
+        }
+    }
+
+    /// Build the impostor for a tile with no land.
+    /// No terrain is fetched. All water tiles of the same kind share one sculpt and one texture,
+    /// so their asset names have no location or viz group.
+    fn build_water_impostor(&mut self, region: &RegionData, viz_group_id: usize) -> Result<RegionImpostorData, Error> {
+        self.build_flat_impostor(region, self.water_level, viz_group_id)
+    }
+
+    /// Build a flat impostor at this water level, with the shared water tile assets.
+    /// For water tiles, and for placeholders, which have no terrain yet.
+    fn build_flat_impostor(&mut self, region: &RegionData, water_level: f32, viz_group_id: usize) -> Result<RegionImpostorData, Error> {
+        let height_field = water_height_field(region, water_level)?;
+        if self.options.generate_mesh {
+            return self.build_impostor_mesh(region, &height_field, viz_group_id);
+        }
+        log::info!("Generating water tile for \"{}\", water level {:.2}", region.name, water_level);
+        let (water_tile, images_opt) = self.water_tile_assets.get(region, water_level)?;
+        if let Some(images) = images_opt {
+            for (asset_name, image) in [(&water_tile.sculpt_name, images.sculpt), (&water_tile.texture_name, images.texture)] {
+                if self.asset_already_exists(&region.grid, asset_name)? {
+                    log::info!("Water tile asset already exists: {}", asset_name);
+                    self.stats.assets_reused += 1;
+                } else {
+                    let mut image_path = self.outdir.clone();
+                    image_path.push(asset_name.to_owned() + ".png");
+                    let written = save_png_atomic(&image_path, &image)?;
+                    log::info!("Water tile image file saved: \"{}\", SHA-256 {}", written.path.display(), written.hash);
+                    self.stats.assets_generated += 1;
+                }
+            }
         } else {
-            let mut terrain_image_path = self.outdir.clone();
-            terrain_image_path.push(terrain_image_name.to_owned() + ".png");
-            let terrain_image = terrain_image.image.unwrap();
-            terrain_image.save(&terrain_image_path)?;
-            log::info!("Terrain image file saved: \"{}\"", terrain_image_path.display());
-            self.stats.assets_generated += 1;      
+            //  Sculpt and texture both already handled on this run.
+            self.stats.assets_reused += 2;
         }
-        Ok(())
+        //  Water looks the same everywhere, so every face gets the one water texture.
+        let face_count = plan_faces([region.region_size_x, region.region_size_y], region.lod, MAX_TEXELS_PER_FACE).face_count();
+        assemble_region_impostor_data(region, &height_field, viz_group_id as u32, Some(format!("{:08x}", water_tile.sculpt_hash)), None,
+            vec![Self::new_face(water_tile.texture_hash); face_count], self.options.lod_quality)
+    }
+
+    /// Record a tile with no land above water. No assets are generated.
+    fn build_water_only_impostor(&mut self, region: &RegionData, height_field: &HeightField, viz_group_id: usize) -> Result<RegionImpostorData, Error> {
+        self.stats.water_only_tiles += 1;
+        assemble_water_only_impostor_data(region, height_field, viz_group_id as u32, self.options.lod_quality)
     }
 
     /// Build the impostor as a glTF mesh.
@@ -568,184 +870,937 @@ impl TerrainGenerator {
         _region: &RegionData,
         _height_field: &HeightField,
         _viz_group_id: usize,
-    ) -> Result<(), Error> {
+    ) -> Result<RegionImpostorData, Error> {
         todo!("glTF mesh generation is not implemented yet");
     }
     
     /// Build an impostor for LOD N.
-    fn build_impostor_for_lod(&mut self, region: &RegionData, _region_region_size_opt: Option<(u32, u32)>, viz_group_id: usize) -> Result<(), Error> {
+    /// Tiles which don't need building still have their height field
+    /// computed if lower LODs will need it.
+    /// Returns the initial_impostors data for tiles built.
+    fn build_impostor_for_lod(&mut self, region: &RegionData, region_size_opt: Option<(u32, u32)>, viz_group_id: usize, work: TileWork) -> Result<Option<RegionImpostorData>, Error> {
+        match work {
+            TileWork::Unchanged => self.stats.tiles_unchanged += 1,
+            TileWork::VizGroupChanged { old_viz_group } => {
+                log::info!("Region \"{}\", LOD {}: viz group {} -> {}, metadata update only.", region.name, region.lod, old_viz_group, viz_group_id);
+                self.stats.viz_group_updates += 1;
+            }
+            TileWork::NewRegion | TileWork::ChangedTerrain => {}
+        }
+        if region.is_water {
+            //  No terrain, and never a child of a lower LOD tile.
+            if work.must_build() {
+                let impostor = self.build_water_impostor(region, viz_group_id)?;
+                log::info!("Water tile \"{}\", LOD {} built.", region.name, region.lod);
+                return Ok(Some(impostor));
+            }
+            return Ok(None);
+        }
+        if region.is_placeholder {
+            //  Known region, no terrain yet. Flat, at the default water level.
+            //  Cached like any LOD 0 height field, because lower LOD tiles are built from it.
+            let height_field = water_height_field(region, DEFAULT_WATER_LEVEL)?;
+            let key = TileCacheKey { grid: region.grid.clone(), region_loc_x: region.region_loc_x, region_loc_y: region.region_loc_y, lod: 0 };
+            self.tile_cache.insert(key, height_field);
+            if work.must_build() {
+                let impostor = self.build_flat_impostor(region, DEFAULT_WATER_LEVEL, viz_group_id)?;
+                log::info!("Placeholder \"{}\" built.", region.name);
+                return Ok(Some(impostor));
+            }
+            return Ok(None);
+        }
+        if !work.must_build() && region_size_opt.is_none() {
+            //  LOD 0 only, so no lower LODs need this height field.
+            return Ok(None);
+        }
         log::info!("Region \"{}\", LOD {} starting.", region.name, region.lod);
         let height_field = if region.lod == 0 {
-            self.get_height_field_one_region(
+            let (name, height_field) = self.get_height_field_one_region(
                 region.grid.clone(),
                 region.region_loc_x,
                 region.region_loc_y,
-            )?
+            )?;
+            if name != region.name {
+                //  Region renamed between the viz group scan and now. Not fatal.
+                log::warn!("Region at ({}, {}) is \"{}\" in raw terrain, \"{}\" in region data.",
+                    region.region_loc_x, region.region_loc_y, name, region.name);
+            }
+            self.water_level = height_field.water_level;
+            height_field
         } else {
-            self.get_height_field_multi_region(
-                region.grid.clone(),
-                region.region_loc_x,
-                region.region_loc_y,               
-                (region.region_size_x, region.region_size_y),
-                region.lod,
-            )?
+            self.get_height_field_multi_region(region)?
         };
-        self.build_impostor(
+        if !work.must_build() {
+            return Ok(None);
+        }
+        if height_field.is_effectively_water(WATER_ONLY_TOLERANCE) {
+            //  A sculpt of this would be degenerate, with a scale of about 0.
+            let impostor = self.build_water_only_impostor(region, &height_field, viz_group_id)?;
+            log::info!("Region \"{}\", LOD {} is all water, no assets.", region.name, region.lod);
+            return Ok(Some(impostor));
+        }
+        let impostor = self.build_impostor(
             region,
             &height_field,
             viz_group_id,
         )?;
         log::info!("Region \"{}\", LOD {} built.", region.name, region.lod);
-        Ok(())
+        Ok(Some(impostor))
     }
     
+    /// All the tiles for one group, all LODs, in build order.
+    /// This must follow the same rules as process_group.
+    fn group_tiles(group: &[Rc<RegionData>], bounds: ((u32, u32), (u32, u32)), options: &GeneratorOptions, water_tiles: bool) -> Vec<Rc<RegionData>> {
+        if lod_tile_size(group, options.varregion_lods).is_some() && group.len() > 1 && options.group_limits.lower_lods() {
+            TileLods::new_with_bounds(group.to_vec(), bounds, water_tiles, options.group_limits.max_lod).collect()
+        } else {
+            group.to_vec()
+        }
+    }
+
+    /// Impostors already in the database for this grid.
+    fn get_existing_impostors(&mut self, grid: &str) -> Result<Vec<ExistingImpostor>, Error> {
+        const SQL_SELECT: &str = r"SELECT name, region_loc_x, region_loc_y, impostor_lod, viz_group, sculpt_hash, terrain_hash, faces_json, generation
+            FROM region_impostors
+            WHERE grid = :grid";
+        let existing = self.conn.exec_map(
+            SQL_SELECT,
+            params! { "grid" => grid.to_lowercase() },
+            |(name, region_loc_x, region_loc_y, lod, viz_group, sculpt_hash, terrain_hash, faces_json, generation): (String, u32, u32, u8, usize, Option<String>, Option<String>, String, u32)| {
+                //  Bad face JSON just means the textures count as changed.
+                let texture_hashes = faces_from_json(&faces_json)
+                    .map(|faces| faces.into_iter().map(|face| face.base_texture_hash).collect())
+                    .unwrap_or_default();
+                ExistingImpostor {
+                    key: TileKey { region_loc_x, region_loc_y, lod },
+                    name,
+                    viz_group,
+                    sculpt_hash,
+                    terrain_hash,
+                    texture_hashes,
+                    generation,
+                }
+            },
+        )?;
+        Ok(existing)
+    }
+
+    /// Which tiles of this group need to be built?
+    /// Compares what this run would generate with the impostors from previous runs.
+    /// This reads and hashes every LOD 0 height field. Nothing is built to decide.
+    /// Stale impostors are found at the end of the grid, by finish_grid.
+    pub fn needed_regions(&mut self, group: &[Rc<RegionData>], bounds: ((u32, u32), (u32, u32)), viz_group: usize) -> Result<WorkList, Error> {
+        let mut wanted = Vec::new();
+        for region in Self::group_tiles(group, bounds, &self.options, self.options.water_tiles) {
+            let terrain_hash = if region.lod == 0 {
+                //  A placeholder's terrain is flat. When real terrain arrives, the hash changes and it's rebuilt.
+                let height_field = if region.is_placeholder {
+                    water_height_field(&region, DEFAULT_WATER_LEVEL)?
+                } else {
+                    self.read_height_field_one_region(region.grid.clone(), region.region_loc_x, region.region_loc_y)?.1
+                };
+                self.add_to_overview(&region, Some(&height_field))?;
+                Some(hash_height_field(&height_field, &self.gen_params())?)
+            } else {
+                None
+            };
+            wanted.push(WantedTile { region, viz_group, terrain_hash });
+        }
+        let grid_state = self.grid_state.as_mut().ok_or_else(|| anyhow!("needed_regions called outside a grid"))?;
+        //  Existing impostors for these tiles, plus LOD 0 impostors inside lower LOD tiles,
+        //  so that vanished regions force a rebuild of the tiles containing them.
+        let mut relevant = Vec::new();
+        for tile in &wanted {
+            let r = &tile.region;
+            let key = TileKey::new(r);
+            if let Some(olds) = grid_state.existing.get(&key) {
+                relevant.extend(olds.iter().cloned());
+            }
+            if r.lod > 0 {
+                let step_x = (r.region_size_x >> r.lod).max(1);
+                let step_y = (r.region_size_y >> r.lod).max(1);
+                for x in (r.region_loc_x..r.region_loc_x + r.region_size_x).step_by(step_x as usize) {
+                    for y in (r.region_loc_y..r.region_loc_y + r.region_size_y).step_by(step_y as usize) {
+                        let inside = TileKey { region_loc_x: x, region_loc_y: y, lod: 0 };
+                        if let Some(olds) = grid_state.existing.get(&inside) {
+                            relevant.extend(olds.iter().cloned());
+                        }
+                    }
+                }
+            }
+        }
+        grid_state.seen.extend(wanted.iter().map(|t| TileKey::new(&t.region)));
+        let mut work_list = classify_tiles(relevant, &wanted);
+        //  Only the tiles of this group were considered, so stale here is not stale for the grid.
+        work_list.stale.clear();
+        log::info!("Work list for viz group {}: {} tiles, {} to build.",
+            viz_group, work_list.tiles.len(), work_list.count(TileWork::must_build));
+        Ok(work_list)
+    }
+
+    /// Summarize a LOD 0 region for the overview map, if wanted.
+    /// The height field is read if not given.
+    fn add_to_overview(&mut self, region: &RegionData, height_field: Option<&HeightField>) -> Result<(), Error> {
+        if !self.options.overview_map {
+            return Ok(());
+        }
+        let read;
+        let height_field = match height_field {
+            _ if region.is_placeholder => None,
+            Some(height_field) => Some(height_field),
+            None => {
+                read = self.read_height_field_one_region(region.grid.clone(), region.region_loc_x, region.region_loc_y)?.1;
+                Some(&read)
+            }
+        };
+        let overview = self.grid_state.as_mut().and_then(|grid_state| grid_state.overview.as_mut())
+            .ok_or_else(|| anyhow!("add_to_overview called outside a grid"))?;
+        match height_field {
+            Some(height_field) => overview.add_region(region, height_field),
+            None => overview.add_placeholder(region),
+        }
+        Ok(())
+    }
+
     /// Process group, multi-LOD version
-    fn process_group(&mut self, group: Vec<RegionData>, initial_viz_group_id: usize) -> Result<(), Error> {
-        log::info!("Group #{}: {} entries.", initial_viz_group_id, group.len());
-        //  ***NEED TO ASSIGN PERSISTENT GROUP NUMBER***
-        let viz_group_id = initial_viz_group_id;    // ***TEMP*** Need real assignment algorithm.
-        let region_size_opt = homogeneous_group_size(&group);
-        if region_size_opt.is_some() && group.len() > 1 {
+    fn process_group(&mut self, group: Vec<Rc<RegionData>>, bounds: ((u32, u32), (u32, u32)), viz_group_id: usize, work_list: &WorkList) -> Result<(), Error> {
+        log::info!("Group #{}: {} entries.", viz_group_id, group.len());
+        self.pending_atlases.clear();
+        let region_size_opt = lod_tile_size(&group, self.options.varregion_lods);
+        let mut impostors = Vec::new();
+        //  Tiles not rebuilt keep their live impostors.
+        let mut carried = Vec::new();
+        if region_size_opt.is_some() && group.len() > 1 && self.options.group_limits.lower_lods() {
             //  Do the LOD thing.
-            for region in TileLods::new(group) {
-                self.build_impostor_for_lod(&region, region_size_opt, viz_group_id)?;
+            let mut tile_lods = TileLods::new_with_bounds(group, bounds, self.options.water_tiles, self.options.group_limits.max_lod);
+            for region in tile_lods.by_ref() {
+                let work = work_list.work(&region);
+                carried.extend(CarriedTile::new(&region, &work, viz_group_id));
+                //  The terrain hash is stored, so the next run can tell the tile is unchanged.
+                let built = self.build_impostor_for_lod(&region, region_size_opt, viz_group_id, work)?
+                    .map(|impostor| RegionImpostorData { terrain_hash: work_list.terrain_hash(&region), ..impostor });
+                impostors.extend(built.map(|impostor| self.add_provenance(&region, impostor)).transpose()?);
+            }
+            //  Bad regions were skipped. Already logged by TileLods.
+            let errors = tile_lods.take_errors();
+            if !errors.is_empty() {
+                log::error!("Group #{}: {} regions skipped because of errors.", viz_group_id, errors.len());
+                self.stats.region_order_errors += errors.len();
             }
         } else {
             //  LOD 0 only.
             for region in group {
-                self.build_impostor_for_lod(&region, None, viz_group_id)?;
+                let work = work_list.work(&region);
+                carried.extend(CarriedTile::new(&region, &work, viz_group_id));
+                let built = self.build_impostor_for_lod(&region, None, viz_group_id, work)?
+                    .map(|impostor| RegionImpostorData { terrain_hash: work_list.terrain_hash(&region), ..impostor });
+                impostors.extend(built.map(|impostor| self.add_provenance(&region, impostor)).transpose()?);
             }
         }
+        self.write_atlases(viz_group_id, &mut impostors)?;
+        self.assign_generations(&mut impostors);
+        self.record_group(viz_group_id, &impostors, &carried);
         Ok(())
     }
 
-    /// Process one grid, with multiple visibilty groups
-    pub fn process_grid(&mut self, mut completed_groups: CompletedGroups) -> Result<(), Error> {
-        //  Sort by length, biggest groups first.
-        completed_groups.sort_by(|a, b| b.len().partial_cmp(&a.len()).unwrap());
-        for (viz_group_id, group) in completed_groups.into_iter().enumerate() {
-            self.process_group(group, viz_group_id)?;
+    /// Record which generator, settings, and raw terrain built an impostor.
+    /// Only a LOD 0 tile of real terrain has a raw terrain row of its own. It was read to build the tile.
+    fn add_provenance(&mut self, region: &RegionData, impostor: RegionImpostorData) -> Result<RegionImpostorData, Error> {
+        let source = self.terrain_source.take();
+        let terrain = if region.lod == 0 && !region.is_water && !region.is_placeholder { source } else { None };
+        let settings = GeneratorSettings::new(&self.options, TERRAIN_SCULPT_TEXTURE_SIZE);
+        let provenance = ImpostorProvenance::new(settings, terrain, unix_time_now());
+        Ok(RegionImpostorData { provenance_json: Some(provenance.to_json()?), ..impostor })
+    }
+
+    /// Pack and write this group's atlases, and point the faces of their tiles at them.
+    fn write_atlases(&mut self, viz_group_id: usize, impostors: &mut [RegionImpostorData]) -> Result<(), Error> {
+        for atlas in self.pending_atlases.take() {
+            let texture_hash = calc_rgbimage_hash(&atlas.image);
+            let name = AssetName {
+                kind: AssetKind::Atlas,
+                grid_tag: AssetName::grid_tag(&atlas.grid),
+                region_loc: atlas.block_loc,
+                region_size: [atlas.tile_size, atlas.tile_size],
+                scale_z: 0.0,
+                offset: 0.0,
+                lod: atlas.lod,
+                viz_group: viz_group_id.try_into()?,
+                water_level: 0.0,
+                hash: texture_hash,
+                slot_mask: atlas.mask,
+            }.fit()?.to_string();
+            if self.asset_already_exists(&atlas.grid, &name)? {
+                log::info!("Atlas asset already exists: {}", name);
+                self.stats.assets_reused += 1;
+            } else {
+                let mut atlas_path = self.outdir.clone();
+                atlas_path.push(name.to_owned() + ".png");
+                let written = save_png_atomic(&atlas_path, &atlas.image)?;
+                log::info!("Atlas image file saved: \"{}\", SHA-256 {}", written.path.display(), written.hash);
+                self.stats.assets_generated += 1;
+            }
+            let tiles = atlas.apply(texture_hash, impostors);
+            log::info!("Atlas {}: {} tiles.", name, tiles);
         }
         Ok(())
     }
+
+    /// Generation for each new impostor, kept from the live one unless its assets changed.
+    fn assign_generations(&self, impostors: &mut [RegionImpostorData]) {
+        let Some(grid_state) = self.grid_state.as_ref() else { return };
+        for impostor in impostors {
+            let key = TileKey { region_loc_x: impostor.region_loc[0].meters(), region_loc_y: impostor.region_loc[1].meters(), lod: impostor.impostor_lod };
+            let texture_hashes: Vec<String> = impostor.faces.iter().map(|face| face.base_texture_hash.clone()).collect();
+            let olds = grid_state.existing.get(&key).map(|olds| olds.as_slice()).unwrap_or_default();
+            impostor.generation = next_generation(olds, &impostor.sculpt_hash, &texture_hashes);
+        }
+    }
+
+    /// Add a group's new impostors to initial_impostors, with the live ones of its tiles not rebuilt, all or none.
+    /// A failure loses only this group, so it is logged, not returned.
+    fn record_group(&mut self, viz_group_id: usize, impostors: &[RegionImpostorData], carried: &[CarriedTile]) {
+        if impostors.is_empty() && carried.is_empty() {
+            return;
+        }
+        match InitialImpostors::add_group(&mut self.conn, impostors, carried) {
+            Ok(()) => {
+                self.stats.impostors_recorded += impostors.len();
+                self.stats.impostors_carried += carried.len();
+            }
+            Err(e) => {
+                log::error!("Group #{}: {} impostors not added to initial_impostors: {}", viz_group_id, impostors.len(), e);
+                self.stats.groups_not_recorded += 1;
+            }
+        }
+    }
+
+    /// Start of one grid. Loads what previous runs generated.
+    /// New impostors from any earlier run for this grid are removed from initial_impostors.
+    pub fn begin_grid(&mut self, grid: &str) -> Result<(), Error> {
+        InitialImpostors::clear_grid(&mut self.conn, grid)?;
+        let existing_list = self.get_existing_impostors(grid)?;
+        log::info!("{} existing impostors for grid \"{}\".", existing_list.len(), grid);
+        //  Keep last run's viz group numbers where possible, so assets named with them stay valid.
+        let old_viz_groups: HashMap<(u32, u32), u32> = existing_list
+            .iter()
+            .filter(|e| e.key.lod == 0)
+            .map(|e| ((e.key.region_loc_x, e.key.region_loc_y), e.viz_group as u32))
+            .collect();
+        let mut existing: HashMap<TileKey, Vec<ExistingImpostor>> = HashMap::new();
+        for item in existing_list {
+            existing.entry(item.key).or_default().push(item);
+        }
+        self.grid_state = Some(GridState {
+            grid: grid.to_string(),
+            existing,
+            numbering: VizGroupNumbering::new(old_viz_groups),
+            seen: HashSet::new(),
+            diag_groups: Vec::new(),
+            overview: self.options.overview_map.then(OverviewMap::default),
+        });
+        Ok(())
+    }
+
+    /// Process one completed viz group, as it comes from the transitive closure.
+    pub fn process_completed_group(&mut self, group: CompletedGroup) -> Result<(), Error> {
+        //  A big group can take long enough that MySQL drops an idle connection.
+        refresh_conn(&self.pool, &mut self.conn)?;
+        let grid_state = self.grid_state.as_mut().ok_or_else(|| anyhow!("process_completed_group called outside a grid"))?;
+        let viz_group_id = grid_state.numbering.assign(group.regions()) as usize;
+        if self.options.diag_maps {
+            grid_state.diag_groups.push(group.clone());
+        }
+        if let Some(overview) = &mut grid_state.overview {
+            overview.add_group(&group)?;
+        }
+        log::debug!("Group #{}: bounds {:?}, {} square meters.", viz_group_id, group.bounds, group.total_area_m2);
+        //  Shared from here on, so tiles can be passed around without copying.
+        let bounds = group.bounds;
+        let group: Vec<Rc<RegionData>> = group.regions.into_iter().map(Rc::new).collect();
+        if self.options.group_limits.skip_group(group.len()) {
+            //  Impostors from earlier runs are left alone, not reported as stale.
+            log::info!("Group #{}: {} regions, fewer than {}, skipped.", viz_group_id, group.len(), self.options.group_limits.min_group_size);
+            let tiles = Self::group_tiles(&group, bounds, &self.options, true);
+            grid_state.seen.extend(tiles.iter().map(|t| TileKey::new(t)));
+            //  Small groups are still on the overview. Their height fields are read only for that.
+            for region in &group {
+                self.add_to_overview(region, None)?;
+            }
+            self.stats.groups_skipped += 1;
+            self.stats.regions_in_skipped_groups += group.len();
+            return Ok(());
+        }
+        let work_list = self.needed_regions(&group, bounds, viz_group_id)?;
+        self.process_group(group, bounds, viz_group_id, &work_list)
+    }
+
+    /// End of one grid. Reports viz group changes and stale impostors, and writes the manifest.
+    pub fn finish_grid(&mut self) -> Result<(), Error> {
+        let grid_state = self.grid_state.take().ok_or_else(|| anyhow!("finish_grid called outside a grid"))?;
+        let assignment = grid_state.numbering.finish();
+        for (new_id, old_ids) in &assignment.merges {
+            log::warn!("Viz groups {:?} merged into viz group {}.", old_ids, new_id);
+        }
+        for (old_id, new_ids) in &assignment.splits {
+            log::warn!("Viz group {} split into viz groups {:?}.", old_id, new_ids);
+        }
+        log::info!("Grid \"{}\": {} viz groups, {} new, {} merges, {} splits, {} viz groups may need re-upload.",
+            grid_state.grid, assignment.ids.len(), assignment.minted.len(), assignment.merges.len(), assignment.splits.len(),
+            assignment.changed_groups().len());
+        //  Anything which exists but was not wanted by any group is stale.
+        let mut stale: Vec<&ExistingImpostor> = grid_state.existing
+            .iter()
+            .filter(|(key, _)| !grid_state.seen.contains(key))
+            .flat_map(|(_, olds)| olds.iter())
+            .collect();
+        stale.sort_by_key(|s| (s.key.lod, s.key.region_loc_x, s.key.region_loc_y));
+        for item in &stale {
+            log::warn!("Impostor \"{}\" at ({}, {}) LOD {} has no raw terrain. Should be deleted.",
+                item.name, item.key.region_loc_x, item.key.region_loc_y, item.key.lod);
+        }
+        self.stats.stale_impostors = stale.len();
+        self.stats.tile_cache_hits = self.tile_cache.hits();
+        self.stats.tile_cache_misses = self.tile_cache.misses();
+        //  The overview is a picture of the grid, not part of it. A failed write is only a warning.
+        let overview_result = grid_state.overview.as_ref().map(|overview| render_overview_map(overview, &grid_state.grid, &self.outdir));
+        if let Some(Err(e)) = overview_result {
+            log::warn!("Grid \"{}\": overview map not written: {:?}", grid_state.grid, e);
+        }
+        let manifest = GeneratorManifest::new(&grid_state.grid, &self.options, TERRAIN_SCULPT_TEXTURE_SIZE, unix_time_now());
+        write_bytes_atomic(&self.outdir.join(MANIFEST_FILE_NAME), manifest.to_json()?.as_bytes())?;
+        //  Diagnostic maps are for looking at. Failing to write them doesn't fail the grid.
+        let diag_result = if self.options.diag_maps { self.write_diag_maps(&grid_state.grid, grid_state.diag_groups) } else { Ok(()) };
+        if let Err(e) = diag_result {
+            log::warn!("Grid \"{}\": diagnostic maps not written: {:?}", grid_state.grid, e);
+        }
+        Ok(())
+    }
+
+    /// Diagnostic maps of viz groups and of tiles at each LOD.
+    /// Tiles are generated again, with water tiles, so skipped water shows up.
+    fn write_diag_maps(&self, grid: &str, mut groups: CompletedGroups) -> Result<(), Error> {
+        if groups.is_empty() {
+            return Ok(());
+        }
+        canonicalize_groups(&mut groups);
+        let mut group_map_path = self.outdir.clone();
+        group_map_path.push(format!("diag-{}-groups.png", grid));
+        render_group_map(&groups, &group_map_path)?;
+        let tiles: Vec<Rc<RegionData>> = groups
+            .into_iter()
+            .flat_map(|group| {
+                let bounds = group.bounds;
+                let group: Vec<Rc<RegionData>> = group.regions.into_iter().map(Rc::new).collect();
+                Self::group_tiles(&group, bounds, &self.options, true)
+            })
+            .collect();
+        let mut lod_map_prefix = self.outdir.clone();
+        lod_map_prefix.push(format!("diag-{}", grid));
+        render_lod_maps(&tiles, self.options.water_tiles, &lod_map_prefix)?;
+        Ok(())
+    }
+}
+
+/// Which grids to run.
+#[derive(Debug, Clone, PartialEq)]
+enum GridSelection {
+    /// These, lower case, no duplicates.
+    Named(Vec<String>),
+    /// Every grid with terrain in the database.
+    All,
+}
+
+impl GridSelection {
+    /// The grid names. For All, ask the database.
+    fn resolve(&self, query_grids: impl FnOnce() -> Result<Vec<String>, Error>) -> Result<Vec<String>, Error> {
+        let mut grids = match self {
+            GridSelection::Named(grids) => return Ok(grids.clone()),
+            GridSelection::All => query_grids()?,
+        };
+        grids.iter_mut().for_each(|grid| *grid = canonical(grid));
+        grids.sort();
+        grids.dedup();
+        if grids.is_empty() {
+            return Err(anyhow!("No grids have terrain in the database."));
+        }
+        Ok(grids)
+    }
 }
 
-/// Actually do the work
-fn run(pool: Pool, outdir: PathBuf, grid: String, url_prefix_opt: Option<String>, generate_mesh: bool) -> Result<(), Error> {
-    let corners_touch_connects = false; // for now, SL only.
-    let conn = pool.get_conn()?;
+/// Options for one grid. Open Simulator grids count regions touching only at corners as adjacent.
+fn options_for_grid(options: &GeneratorOptions, grid: &str, os_grids: &[String]) -> GeneratorOptions {
+    GeneratorOptions { corners_touch_connects: os_grids.iter().any(|g| g == grid), ..options.clone() }
+}
+
+/// Actually do the work.
+/// Each grid is run separately, into its own subdirectory of outdir.
+/// A grid which fails is reported, and the others still run.
+fn run(pool: WatchedPool, cli: CliOptions) -> Result<(), Error> {
+    let CliOptions { outdir, grids, os_grids, url_prefix_opt, generator_options: options, .. } = cli;
+    if options.jobs > 1 {
+        log::warn!("{} jobs requested, but generation is single-threaded for now.", options.jobs);
+    }
+    let mut conn = pool.get_conn()?;
+    if options.migrate {
+        let report = migrations::migrate(&mut conn)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(check_db) = options.check_db {
+        let report = check_raw_terrain(&mut conn, check_db.repair)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(days) = options.purge_retired {
+        let purged = purge_retired_assets(&mut conn, days)?;
+        println!("Purged {} retired assets older than {} days.", purged, days);
+        return Ok(());
+    }
+    let grids = grids.resolve(|| Ok(conn.query(SQL_SELECT_GRIDS)?))?;
+    if let Some(import) = &options.import {
+        //  Command line parsing allows only one grid here.
+        let report = import_terrain(&mut conn, &grids[0], import)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(path) = &options.import_known {
+        //  Likewise.
+        let report = import_known_regions(&mut conn, &grids[0], path, options.import_known_units)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(export) = &options.export {
+        //  Likewise. Written into place only when complete.
+        let mut count = 0;
+        write_atomic(&export.path, |out| {
+            count = export_impostors(&mut conn, &grids[0], export.table, export.format, out).map_err(std::io::Error::other)?;
+            Ok(())
+        })?;
+        println!("Exported {} impostors for grid \"{}\" to {}.", count, grids[0], export.path.display());
+        return Ok(());
+    }
+    drop(conn);
+    let mut failed = Vec::new();
+    for grid in &grids {
+        let grid_options = options_for_grid(&options, grid, &os_grids);
+        if let Err(e) = run_grid(pool.clone(), outdir.join(grid), grid, url_prefix_opt.clone(), grid_options) {
+            log::error!("Grid \"{}\" failed: {:?}", grid, e);
+            failed.push(grid.as_str());
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} of {} grids failed: {}", failed.len(), grids.len(), failed.join(", ")))
+    }
+}
+
+/// Promote, dry run, or generate, for one grid.
+fn run_grid(pool: WatchedPool, outdir: PathBuf, grid: &str, url_prefix_opt: Option<String>, options: GeneratorOptions) -> Result<(), Error> {
+    let varregion_lods = options.varregion_lods;
+    let group_limits = options.group_limits;
+    let dry_run_opt = options.dry_run.clone();
+    let mut conn = pool.get_conn()?;
+    if options.promote {
+        //  Uploads done, make the new impostors live.
+        let report = InitialImpostors::promote(&mut conn, grid)?;
+        println!("{}", report);
+        return Ok(());
+    }
+    if let Some(route_options) = options.plan_route {
+        return write_survey_route(pool, conn, outdir, grid, url_prefix_opt, options, route_options);
+    }
+    //  Create the output directory. Not needed for a dry run.
+    //  Partly written files from a run that crashed are removed.
+    if dry_run_opt.is_none() {
+        std::fs::create_dir_all(&outdir)?;
+        remove_stale_tmp_files(&outdir)?;
+    }
     let mut terrain_generator =
-        TerrainGenerator::new(conn, outdir, url_prefix_opt, generate_mesh, corners_touch_connects);
-    let mut grids = terrain_generator.transitive_closure(&grid)?;
-    if grids.is_empty() {
+        TerrainGenerator::new(pool, conn, outdir, url_prefix_opt, options);
+    if let Some(dry_run) = dry_run_opt {
+        //  Count, don't build.
+        let mut completed_groups = Vec::new();
+        let (region_count, overlaps) = terrain_generator.transitive_closure(grid, |_, group| {
+            completed_groups.push(group);
+            Ok(())
+        })?;
+        if region_count == 0 {
+            return Err(anyhow!("Grid \"{}\" not found.", grid));
+        }
+        let region_ages = terrain_generator.get_region_ages(grid)?;
+        let summary = DryRunSummary::new(grid, completed_groups, dry_run.stale_days, region_ages, overlaps, varregion_lods, &group_limits);
+        if dry_run.json {
+            println!("{}", summary.to_json()?);
+        } else {
+            print!("{}", summary);
+        }
+        log::info!("Dry run summary:\n{}", summary);
+        return Ok(());
+    }
+    terrain_generator.begin_grid(grid)?;
+    //  Overlaps were logged by transitive_closure.
+    let (region_count, _overlaps) = terrain_generator.transitive_closure(grid, |generator, group| generator.process_completed_group(group))?;
+    if region_count == 0 {
         return Err(anyhow!("Grid \"{}\" not found.", grid));
     }
+    terrain_generator.finish_grid()?;
+    println!("Statistics for grid \"{}\":\n{}", grid, terrain_generator.stats);
+    log::info!("Statistics for grid \"{}\":\n{}", grid, terrain_generator.stats);
+    Ok(())
+}
 
-    if grids.len() != 1 {
-        return Err(anyhow!(
-            "More than one grid found but SQL should return only one grid."
-        ));
+/// Plan the survey bot's route through the grid, and write it as route.json and route.csv.
+fn write_survey_route(pool: WatchedPool, mut conn: PooledConn, outdir: PathBuf, grid: &str, url_prefix_opt: Option<String>,
+    options: GeneratorOptions, route_options: RouteOptions) -> Result<(), Error> {
+    let wanted: Option<HashSet<(u32, u32)>> = match route_options.stale_days {
+        Some(stale_days) => Some(get_region_ages(&mut conn, grid, stale_days)?.iter().map(|age| (age.region_loc[0], age.region_loc[1])).collect()),
+        None => None,
+    };
+    std::fs::create_dir_all(&outdir)?;
+    let mut terrain_generator = TerrainGenerator::new(pool, conn, outdir.clone(), url_prefix_opt, options);
+    let mut completed_groups = Vec::new();
+    let (region_count, _overlaps) = terrain_generator.transitive_closure(grid, |_, group| {
+        completed_groups.push(group);
+        Ok(())
+    })?;
+    if region_count == 0 {
+        return Err(anyhow!("Grid \"{}\" not found.", grid));
     }
-    let grid_entry = grids.pop().unwrap(); // get the one grid
-    terrain_generator.process_grid(grid_entry)?;
-    println!("Statistics:\n{}", terrain_generator.stats);
-    log::info!("Statistics:\n{}", terrain_generator.stats);
+    canonicalize_groups(&mut completed_groups);
+    let route = SurveyRoute::new(grid, &completed_groups, wanted.as_ref(), route_options.stale_days);
+    write_bytes_atomic(&outdir.join("route.json"), route.to_json()?.as_bytes())?;
+    write_bytes_atomic(&outdir.join("route.csv"), route.to_csv().as_bytes())?;
+    println!("{}", route);
+    log::info!("{}", route);
     Ok(())
 }
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
-    print!("{}", opts.usage(&brief));
+/// Command line options, as parsed.
+/// Parsing has no side effects, so this can be tested.
+#[derive(Debug, Clone)]
+struct CliOptions {
+    /// Output directory. Each grid's output goes in a subdirectory named for the grid.
+    outdir: PathBuf,
+    /// Credentials file
+    credsfile: String,
+    /// Grids to run
+    grids: GridSelection,
+    /// Grids which are Open Simulator grids, lower case
+    os_grids: Vec<String>,
+    /// Asset server URL prefix
+    url_prefix_opt: Option<String>,
+    /// Verbose mode
+    verbose: bool,
+    /// Log file name
+    log_file: String,
+    /// Log level
+    log_level: LevelFilter,
+    /// Options passed through to the generator
+    generator_options: GeneratorOptions,
 }
 
-/// Set up options, credentials, and database connection.
-fn setup() -> Result<(Pool, PathBuf, String, Option<String>, bool), Error> {
-    //  Usual options processing
-    let args: Vec<String> = std::env::args().collect();
-    let program = args[0].clone();
-    //  The options
+/// The option table. One entry per option.
+fn option_table() -> Options {
     let mut opts = Options::new();
     opts.optopt("o", "outdir", "Set output directory name.", "NAME");
-    opts.optopt(
-        "c",
-        "credentials",
-        "Get database credentials from this file.",
-        "NAME",
-    );
-    opts.optflag("m", "mesh", "Generate glTF mesh, not sculpt image");
-    opts.optopt("g", "grid", "Only output for this grid", "NAME");
+    opts.optopt("c", "credentials", "Get database credentials from this file.", "NAME");
+    opts.optmulti("g", "grid", "Output for this grid. May be given more than once.", "NAME");
+    opts.optflag("", "all-grids", "Output for every grid with terrain in the database.");
+    opts.optopt("", "os-grids", "These grids are Open Simulator grids, where regions touching at corners are adjacent.", "NAME,NAME");
     opts.optopt("p", "prefix", "Asset server URL prefix for validating assets", "NAME");
-    opts.optflag("h", "help", "Print this help menu.");
+    opts.optflag("m", "mesh", "Generate glTF mesh, not sculpt image");
+    opts.optopt("d", "sculpt-dim", "Sculpt image size, pixels on a side.", "PIXELS");
+    opts.optopt("j", "jobs", "Number of parallel jobs.", "COUNT");
+    opts.optopt("", "cache-mb", "Memory for height fields kept for building lower LODs, megabytes.", "MB");
+    opts.optflag("", "varregion-lods", "Generate lower LODs for groups with Open Simulator varregions.");
+    opts.optflag("", "water-tiles", "Generate flat water impostors for lower LOD tiles with no land.");
+    opts.optopt("", "min-group-size", "Skip viz groups with fewer regions than this.", "COUNT");
+    opts.optopt("", "max-lod", "Generate no tiles beyond this LOD.", "LOD");
+    opts.optopt("", "lod-quality", &format!("Recommended LOD distance, in tile sizes plus elevation range. Default {}.", DEFAULT_LOD_QUALITY), "N");
+    opts.optflag("", "diag-maps", "Write PNG maps of viz groups and LOD tiles to the output directory.");
+    opts.optflag("", "overview-map", "Write overview-GRID.png, the grid colored by elevation and water, and its pixel to meter transform as overview-GRID.json.");
+    opts.optflag("", "atlas", "Pack the textures of up to 16 neighboring small tiles into one atlas texture.");
+    opts.optflag("n", "dry-run", "Count groups, tiles, and assets, but generate nothing.");
+    opts.optflag("", "plan-route", "Write a survey route for the terrain bot, as JSON and CSV, to the output directory, generate nothing.");
+    opts.optflag("", "promote", "Replace the grid's live impostors with the uploaded new ones, generate nothing.");
+    opts.optflag("", "migrate", "Create or update the database tables, generate nothing. Needs only --credentials.");
+    opts.optflag("", "check-db", "Report raw terrain rows with inconsistent sizes, sample counts, or grid names, generate nothing. Needs only --credentials.");
+    opts.optflag("", "repair", "With --check-db, infer square sample counts and fix grid name case where possible.");
+    opts.optopt("", "purge-retired", "Forget impostor assets retired more than this many days ago, generate nothing. Needs only --credentials.", "DAYS");
+    opts.optopt("", "import", "Put terrain from a PNG-16 height field file into the database, generate nothing. Needs a grid and location.", "FILE");
+    opts.optopt("", "import-raw", "Put terrain from a simulator .raw or .r32 file into the database, generate nothing. Needs a grid, location, and size.", "FILE");
+    opts.optopt("", "loc", "Region location in meters, for importing.", "X,Y");
+    opts.optopt("", "name", "Region name, for importing. Default is the file name.", "NAME");
+    opts.optopt("", "size", "Region size in meters, for .raw and .r32 imports.", "N|X,Y");
+    opts.optopt("", "water", "Water level in meters, for .raw and .r32 imports. Default 20.", "METERS");
+    opts.optopt("", "import-known", "Put a CSV region list, X,Y,SIZE,NAME per line, into known_regions, generate nothing. Needs a grid.", "FILE");
+    opts.optopt("", "region-units", "With --import-known, units of the list's X,Y, meters or regions. Default meters.", "UNITS");
+    opts.optflag("", "known-regions", "Generate flat placeholder impostors for known regions with no terrain yet.");
+    opts.optopt("", "export-impostors", "Write the grid's impostors to this file, generate nothing. Needs a grid.", "FILE");
+    opts.optopt("", "format", "With --export-impostors, csv or geojson. Default csv.", "FORMAT");
+    opts.optflag("", "initial", "With --export-impostors, export the next generation, not yet promoted, instead of the live impostors.");
+    opts.optflag("", "json", "With --dry-run, write the summary to stdout as JSON.");
+    opts.optopt("s", "stale-days", "With --dry-run, report regions with raw terrain older than this. With --plan-route, visit only those.", "DAYS");
+    opts.optopt("l", "log-level", "Log level: off, error, warn, info, debug, trace.", "LEVEL");
+    opts.optopt("f", "log-file", "Log to this file.", "NAME");
     opts.optflag("v", "verbose", "Verbose mode.");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => {
-            panic!("{}", f.to_string());
+    opts.optflag("h", "help", "Print this help menu.");
+    opts
+}
+
+/// Usage message.
+fn usage_text(program: &str) -> String {
+    let brief = format!("Usage: {} [options]", program);
+    option_table().usage(&brief)
+}
+
+fn print_usage(program: &str) {
+    print!("{}", usage_text(program));
+}
+
+/// Parse a numeric option, with a useful message on failure.
+fn parse_number_opt<T: std::str::FromStr>(matches: &getopts::Matches, name: &str) -> Result<Option<T>, Error> {
+    match matches.opt_str(name) {
+        Some(s) => match s.trim().parse::<T>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(anyhow!("Option --{}: \"{}\" is not a valid number", name, s)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse command line. Args[0] is the program name.
+fn parse_args(args: &[String]) -> Result<CliOptions, Error> {
+    let matches = option_table().parse(args.iter().skip(1))?;
+    if matches.opt_present("help") {
+        return Err(anyhow!("Help requested, will not run."));
+    }
+    let migrate = matches.opt_present("migrate");
+    if migrate && (matches.opt_present("promote") || matches.opt_present("dry-run")) {
+        return Err(anyhow!("Option --migrate can't be used with --promote or --dry-run."));
+    }
+    let check_db = matches.opt_present("check-db").then(|| CheckDbOptions { repair: matches.opt_present("repair") });
+    if check_db.is_none() && matches.opt_present("repair") {
+        return Err(anyhow!("Option --repair is only for --check-db."));
+    }
+    if check_db.is_some() && (migrate || matches.opt_present("promote") || matches.opt_present("dry-run")) {
+        return Err(anyhow!("Option --check-db can't be used with --migrate, --promote, or --dry-run."));
+    }
+    let purge_retired = match matches.opt_str("purge-retired") {
+        Some(days) => Some(days.trim().parse::<u32>().map_err(|_| anyhow!("Option --purge-retired: \"{}\" is not a number of days", days))?),
+        None => None,
+    };
+    if purge_retired.is_some() && (migrate || check_db.is_some() || ["promote", "dry-run", "plan-route", "import", "import-raw"].iter().any(|o| matches.opt_present(o))) {
+        return Err(anyhow!("Option --purge-retired can't be used with --migrate, --check-db, --promote, --dry-run, --plan-route, or --import."));
+    }
+    if matches.opt_present("import") && matches.opt_present("import-raw") {
+        return Err(anyhow!("Options --import and --import-raw can't be used together."));
+    }
+    let import_path = matches.opt_str("import").or(matches.opt_str("import-raw"));
+    if import_path.is_some() && (migrate || check_db.is_some() || matches.opt_present("promote") || matches.opt_present("dry-run")) {
+        return Err(anyhow!("Option --import can't be used with --migrate, --check-db, --promote, or --dry-run."));
+    }
+    if import_path.is_none() && (matches.opt_present("loc") || matches.opt_present("name")) {
+        return Err(anyhow!("Options --loc and --name are only for --import and --import-raw."));
+    }
+    if !matches.opt_present("import-raw") && (matches.opt_present("size") || matches.opt_present("water")) {
+        return Err(anyhow!("Options --size and --water are only for --import-raw."));
+    }
+    let import_known = matches.opt_str("import-known").map(PathBuf::from);
+    if import_known.is_some() && (import_path.is_some() || migrate || check_db.is_some() || purge_retired.is_some()
+        || ["promote", "dry-run", "plan-route", "known-regions"].iter().any(|o| matches.opt_present(o))) {
+        return Err(anyhow!("Option --import-known can't be used with other import, database, or generation options."));
+    }
+    let import_known_units = match matches.opt_str("region-units") {
+        Some(_) if import_known.is_none() => return Err(anyhow!("Option --region-units is only for --import-known.")),
+        Some(units) => CoordUnits::new_from_param(&units)?,
+        None => CoordUnits::Meters,
+    };
+    let export = match matches.opt_str("export-impostors") {
+        Some(path) => {
+            let format = match matches.opt_str("format") {
+                Some(format) => format.parse::<ExportFormat>().map_err(|e| anyhow!("Option --format: {}", e))?,
+                None => ExportFormat::Csv,
+            };
+            let table = if matches.opt_present("initial") { ExportTable::Initial } else { ExportTable::Live };
+            Some(ExportOptions { path: PathBuf::from(path), format, table })
         }
+        None => None,
     };
-    if matches.opt_present("h") {
-        print_usage(&program, opts);
-        panic!("Help requested, will not run.");
-    }
-    let outdir = matches.opt_str("o");
-    let credsfile = matches.opt_str("c");
-    let verbose = matches.opt_present("v");
-    let grid = matches.opt_str("g");
-    let url_prefix_opt = matches.opt_str("p");
-    let generate_mesh = matches.opt_present("m");
-    if outdir.is_none() || credsfile.is_none() || grid.is_none() {
-        print_usage(&program, opts);
-        return Err(anyhow!("Required command line options missing"));
-    }
-    let credsfile = credsfile.unwrap();
-    let outdir = PathBuf::from(&outdir.unwrap());
-    let grid = grid.unwrap().trim().to_lowercase();
-    // Create the output directory, empty.
-    std::fs::create_dir_all(&outdir)?;
-    // Connect to the database
-    let creds = match Envie::load_with_path(&credsfile) {
-        Ok(creds) => creds,
-        Err(e) => {
-            //  Envie returns a string and we need an Error
-            return Err(anyhow!(
-                "Unable to open credentials file \"{}\": {:?}",
-                credsfile,
-                e
-            ));
+    if export.is_none() && (matches.opt_present("format") || matches.opt_present("initial")) {
+        return Err(anyhow!("Options --format and --initial are only for --export-impostors."));
+    }
+    if export.is_some() && (import_path.is_some() || import_known.is_some() || migrate || check_db.is_some() || purge_retired.is_some()
+        || ["promote", "dry-run", "plan-route"].iter().any(|o| matches.opt_present(o))) {
+        return Err(anyhow!("Option --export-impostors can't be used with import, database, or generation options."));
+    }
+    let mut named_grids = Vec::new();
+    for grid in matches.opt_strs("grid") {
+        let grid = canonical(&grid);
+        if !named_grids.contains(&grid) {
+            named_grids.push(grid);
         }
+    }
+    let grids = match (named_grids.is_empty(), matches.opt_present("all-grids")) {
+        (false, true) => return Err(anyhow!("Options --grid and --all-grids can't be used together.")),
+        (true, true) => Some(GridSelection::All),
+        (false, false) => Some(GridSelection::Named(named_grids)),
+        (true, false) => None,
     };
-    //  Optional MySQL port number
-    let portnum = if let Some(port) = creds.get("DB_PORT") {
-        port.parse::<u16>()?
+    if import_path.is_some() && !matches!(&grids, Some(GridSelection::Named(g)) if g.len() == 1) {
+        return Err(anyhow!("Option --import needs exactly one --grid."));
+    }
+    if import_known.is_some() && !matches!(&grids, Some(GridSelection::Named(g)) if g.len() == 1) {
+        return Err(anyhow!("Option --import-known needs exactly one --grid."));
+    }
+    if export.is_some() && !matches!(&grids, Some(GridSelection::Named(g)) if g.len() == 1) {
+        return Err(anyhow!("Option --export-impostors needs exactly one --grid."));
+    }
+    let os_grids: Vec<String> = matches.opt_str("os-grids").unwrap_or_default()
+        .split(',').map(|g| g.trim().to_lowercase()).filter(|g| !g.is_empty()).collect();
+    //  Migration, checking, and purging are for the whole database, and write no files. Import writes no files.
+    //  Export writes only the file named.
+    let (outdir, grids) = if migrate || check_db.is_some() || purge_retired.is_some() {
+        (Some(matches.opt_str("outdir").unwrap_or_default()), Some(grids.unwrap_or(GridSelection::Named(Vec::new()))))
+    } else if import_path.is_some() || import_known.is_some() || export.is_some() {
+        (Some(matches.opt_str("outdir").unwrap_or_default()), grids)
     } else {
-        //  Use MySQL default
-        3306
+        (matches.opt_str("outdir"), grids)
     };
-    let opts = mysql::OptsBuilder::new()
-        //  Dreamhost is still using old authentication
-        .secure_auth(false)
-        .ip_or_hostname(creds.get("DB_HOST"))
-        .tcp_port(portnum)
-        .user(creds.get("DB_USER"))
-        .pass(creds.get("DB_PASS"))
-        .db_name(creds.get("DB_NAME"));
-    drop(creds);
-    log::info!("Opts: {:?}", opts);
-    let pool = Pool::new(opts)?;
-    if verbose {
+    let import = match import_path {
+        Some(path) => {
+            let loc = matches.opt_str("loc").ok_or_else(|| anyhow!("Option --import needs --loc."))?;
+            let region_loc = ImportOptions::parse_loc(&loc).map_err(|e| anyhow!("Option --loc: {}", e))?;
+            let path = PathBuf::from(path);
+            let name = match matches.opt_str("name") {
+                Some(name) => name,
+                None => path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+            };
+            let source = if matches.opt_present("import-raw") {
+                let size = matches.opt_str("size").ok_or_else(|| anyhow!("Option --import-raw needs --size."))?;
+                let region_size = ImportOptions::parse_size(&size).map_err(|e| anyhow!("Option --size: {}", e))?;
+                let water_level = parse_number_opt::<f32>(&matches, "water")?.unwrap_or(DEFAULT_WATER_LEVEL);
+                ImportSource::SimulatorRaw { region_size, water_level }
+            } else {
+                ImportSource::Png16
+            };
+            Some(ImportOptions { path, source, region_loc, name })
+        }
+        None => None,
+    };
+    let credsfile = matches.opt_str("credentials");
+    if outdir.is_none() || credsfile.is_none() || grids.is_none() {
+        return Err(anyhow!("Required command line options missing: --outdir, --credentials, and --grid or --all-grids are required."));
+    }
+    let sculpt_dim = parse_number_opt::<usize>(&matches, "sculpt-dim")?.unwrap_or(SCULPTDIM);
+    if sculpt_dim < 2 {
+        return Err(anyhow!("Option --sculpt-dim: {} is too small", sculpt_dim));
+    }
+    let jobs = parse_number_opt::<usize>(&matches, "jobs")?.unwrap_or(1);
+    if jobs == 0 {
+        return Err(anyhow!("Option --jobs: must be at least 1"));
+    }
+    let tile_cache_mb = parse_number_opt::<usize>(&matches, "cache-mb")?.unwrap_or(DEFAULT_TILE_CACHE_MB);
+    let group_limits = GroupLimits {
+        min_group_size: parse_number_opt::<usize>(&matches, "min-group-size")?.unwrap_or(1),
+        max_lod: parse_number_opt::<u8>(&matches, "max-lod")?,
+    };
+    let lod_quality = parse_number_opt::<f32>(&matches, "lod-quality")?.unwrap_or(DEFAULT_LOD_QUALITY);
+    if !(lod_quality.is_finite() && lod_quality > 0.0) {
+        return Err(anyhow!("Option --lod-quality: {} must be more than zero", lod_quality));
+    }
+    let promote = matches.opt_present("promote");
+    if promote && matches.opt_present("dry-run") {
+        return Err(anyhow!("Options --promote and --dry-run can't be used together."));
+    }
+    let plan_route = if matches.opt_present("plan-route") {
+        if promote || migrate || check_db.is_some() || import.is_some() || matches.opt_present("dry-run") {
+            return Err(anyhow!("Option --plan-route can't be used with --promote, --migrate, --check-db, --import, or --dry-run."));
+        }
+        Some(RouteOptions { stale_days: parse_number_opt::<u32>(&matches, "stale-days")? })
+    } else {
+        None
+    };
+    let dry_run = if matches.opt_present("dry-run") {
+        let stale_days = parse_number_opt::<u32>(&matches, "stale-days")?.unwrap_or(DEFAULT_STALE_DAYS);
+        Some(DryRunOptions { json: matches.opt_present("json"), stale_days })
+    } else {
+        None
+    };
+    let log_level = if let Some(level) = matches.opt_str("log-level") {
+        level.trim().parse::<LevelFilter>()
+            .map_err(|_| anyhow!("Option --log-level: \"{}\" is not a log level", level))?
+    } else {
+        LevelFilter::Debug
+    };
+    Ok(CliOptions {
+        outdir: PathBuf::from(outdir.unwrap()),
+        credsfile: credsfile.unwrap(),
+        grids: grids.unwrap(),
+        os_grids,
+        url_prefix_opt: matches.opt_str("prefix"),
+        verbose: matches.opt_present("verbose"),
+        log_file: matches.opt_str("log-file").unwrap_or(DEFAULT_LOG_FILE_NAME.to_string()),
+        log_level,
+        generator_options: GeneratorOptions {
+            corners_touch_connects: false, // set per grid, from os_grids
+            generate_mesh: matches.opt_present("mesh"),
+            sculpt_dim,
+            jobs,
+            dry_run,
+            varregion_lods: matches.opt_present("varregion-lods"),
+            tile_cache_mb,
+            water_tiles: matches.opt_present("water-tiles"),
+            diag_maps: matches.opt_present("diag-maps"),
+            overview_map: matches.opt_present("overview-map"),
+            atlas: matches.opt_present("atlas"),
+            group_limits,
+            lod_quality,
+            promote,
+            migrate,
+            check_db,
+            purge_retired,
+            plan_route,
+            import,
+            known_regions: matches.opt_present("known-regions"),
+            import_known,
+            import_known_units,
+            export,
+        },
+    })
+}
+
+/// Set up options, logging, credentials, and database connection.
+fn setup() -> Result<(WatchedPool, CliOptions), Error> {
+    //  Usual options processing
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            print_usage(&program);
+            return Err(e);
+        }
+    };
+    logger(&cli.log_file, cli.log_level)?;
+    //  Output directories are created per grid, by run_grid.
+    // Connect to the database. A rotated password is picked up when reconnecting.
+    let creds = common::Credentials::watch_with_env(&cli.credsfile, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&creds)?;
+    if cli.verbose {
         println!("Connected to database.");
     }
-    log::info!("Connected to database.");
     //  Setup complete. Return what's needed to run.
-    Ok((pool, outdir, grid, url_prefix_opt, generate_mesh))
+    Ok((pool, cli))
 }
 
 /// Main program.
 /// Setup, then run.
 fn main() {
-    logger();
     match setup() {
-        Ok((pool, outdir, grid, url_prefix_opt, mesh)) => match run(pool, outdir, grid, url_prefix_opt, mesh) {
+        Ok((pool, cli)) => match run(pool, cli) {
             Ok(_) => {}
             Err(e) => {
                 panic!("Failed: {:?}", e);
@@ -757,3 +1812,290 @@ fn main() {
     };
 }
 
+#[test]
+fn test_generator_options() {
+    //  A TerrainGenerator can't be built without a database connection,
+    //  so check the options themselves, and that sculpt size is honored.
+    let defaults = GeneratorOptions::default();
+    assert!(!defaults.corners_touch_connects);
+    assert!(!defaults.generate_mesh);
+    assert_eq!(defaults.sculpt_dim, SCULPTDIM);
+    assert_eq!(defaults.jobs, 1);
+    assert!(defaults.dry_run.is_none());
+    let options = GeneratorOptions { generate_mesh: true, sculpt_dim: 32, ..Default::default() };
+    assert!(options.generate_mesh);
+    assert!(!options.corners_touch_connects);
+    let mut terrain_sculpt = TerrainSculpt::new("Test", options.sculpt_dim);
+    terrain_sculpt.setelevs(vec![vec![0; 16]; 16], 1.0, 0.0);
+    terrain_sculpt.makeimage();
+    let image = terrain_sculpt.image.unwrap();
+    assert_eq!((image.width(), image.height()), (32, 32));
+}
+
+#[test]
+fn test_parse_args() {
+    fn argv(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+    //  Minimal
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g Agni")).expect("minimal args");
+    assert_eq!(cli.outdir, PathBuf::from("/tmp/out"));
+    assert_eq!(cli.credsfile, "creds.txt");
+    assert_eq!(cli.grids, GridSelection::Named(vec!["agni".to_string()]));
+    assert!(cli.os_grids.is_empty());
+    assert_eq!(cli.log_file, DEFAULT_LOG_FILE_NAME);
+    assert_eq!(cli.log_level, LevelFilter::Debug);
+    assert!(!cli.generator_options.generate_mesh);
+    assert_eq!(cli.generator_options.sculpt_dim, SCULPTDIM);
+    assert!(cli.generator_options.dry_run.is_none());
+    assert!(!cli.generator_options.varregion_lods);
+    assert_eq!(cli.generator_options.tile_cache_mb, DEFAULT_TILE_CACHE_MB);
+    assert!(!cli.generator_options.water_tiles);
+    assert!(!cli.generator_options.diag_maps);
+    assert_eq!(cli.generator_options.group_limits, GroupLimits::default());
+    assert!(!cli.generator_options.promote);
+    assert!(!cli.generator_options.migrate);
+    assert!(cli.generator_options.import.is_none());
+    assert!(!cli.generator_options.known_regions);
+    assert!(cli.generator_options.import_known.is_none());
+    assert!(!cli.generator_options.atlas);
+    assert_eq!(cli.generator_options.lod_quality, DEFAULT_LOD_QUALITY);
+    //  Everything
+    let cli = parse_args(&argv("generateterrain --outdir /tmp/out --credentials creds.txt --grid agni --mesh --sculpt-dim 32 --jobs 4 --varregion-lods --cache-mb 64 --water-tiles --diag-maps --overview-map --known-regions --atlas \
+        --min-group-size 3 --max-lod 4 --lod-quality 6 \
+        --dry-run --json --stale-days 90 --log-level warn --log-file /tmp/gen.log -v -p http://example.com/"))
+        .expect("full args");
+    assert!(cli.generator_options.generate_mesh);
+    assert_eq!(cli.generator_options.sculpt_dim, 32);
+    assert_eq!(cli.generator_options.jobs, 4);
+    assert!(cli.generator_options.varregion_lods);
+    assert_eq!(cli.generator_options.tile_cache_mb, 64);
+    assert!(cli.generator_options.water_tiles);
+    assert!(cli.generator_options.diag_maps);
+    assert!(cli.generator_options.overview_map);
+    assert!(cli.generator_options.known_regions);
+    assert!(cli.generator_options.atlas);
+    assert_eq!(cli.generator_options.group_limits, GroupLimits { min_group_size: 3, max_lod: Some(4) });
+    assert_eq!(cli.generator_options.lod_quality, 6.0);
+    let dry_run = cli.generator_options.dry_run.expect("dry run");
+    assert!(dry_run.json);
+    assert_eq!(dry_run.stale_days, 90);
+    assert_eq!(cli.log_level, LevelFilter::Warn);
+    assert_eq!(cli.log_file, "/tmp/gen.log");
+    assert!(cli.verbose);
+    assert_eq!(cli.url_prefix_opt, Some("http://example.com/".to_string()));
+    //  Errors
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --jobs many")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --jobs 0")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --cache-mb lots")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --max-lod 300")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --sculpt-dim -5")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --lod-quality 0")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni -n --stale-days x")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --log-level loud")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --bogus")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --promote --dry-run")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --promote")).expect("promote").generator_options.promote);
+    //  Migration needs only credentials.
+    let cli = parse_args(&argv("generateterrain -c creds.txt --migrate")).expect("migrate");
+    assert!(cli.generator_options.migrate);
+    assert_eq!(cli.credsfile, "creds.txt");
+    assert!(parse_args(&argv("generateterrain --migrate")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt --migrate --promote")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt --migrate -n")).is_err());
+    //  So does checking the database.
+    let cli = parse_args(&argv("generateterrain -c creds.txt --check-db")).expect("check-db");
+    assert_eq!(cli.generator_options.check_db, Some(CheckDbOptions { repair: false }));
+    let cli = parse_args(&argv("generateterrain -c creds.txt --check-db --repair")).expect("repair");
+    assert_eq!(cli.generator_options.check_db, Some(CheckDbOptions { repair: true }));
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --repair")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt --check-db --migrate")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni --check-db --import x.png --loc 0,0")).is_err());
+    //  And purging retired assets.
+    let cli = parse_args(&argv("generateterrain -c creds.txt --purge-retired 90")).expect("purge-retired");
+    assert_eq!(cli.generator_options.purge_retired, Some(90));
+    assert!(parse_args(&argv("generateterrain -c creds.txt --purge-retired soon")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt --purge-retired 90 --check-db")).is_err());
+    //  Route planning, everywhere or only stale regions.
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --plan-route")).expect("plan-route");
+    assert_eq!(cli.generator_options.plan_route, Some(RouteOptions { stale_days: None }));
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --plan-route -s 180")).expect("plan-route stale");
+    assert_eq!(cli.generator_options.plan_route, Some(RouteOptions { stale_days: Some(180) }));
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --plan-route -n")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --plan-route --promote")).is_err());
+    //  Import needs grid and location, not an output directory.
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g OSgrid --import /tmp/Terrain_Test.png --loc 1000,1000")).expect("import");
+    let import = cli.generator_options.import.expect("import options");
+    assert_eq!(import.path, PathBuf::from("/tmp/Terrain_Test.png"));
+    assert_eq!(import.region_loc, [1000, 1000]);
+    assert_eq!(import.name, "Terrain_Test");
+    assert_eq!(cli.grids, GridSelection::Named(vec!["osgrid".to_string()]));
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png --loc 256,512 --name Sandbox")).expect("import with name");
+    assert_eq!(cli.generator_options.import.expect("import options").name, "Sandbox");
+    assert!(parse_args(&argv("generateterrain -c creds.txt --import t.png --loc 256,512")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png --loc 256")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png --loc 256,512 --promote")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --loc 256,512")).is_err());
+    //  Simulator files need a size.
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g osgrid --import-raw /tmp/sandbox.r32 --loc 1000,1000 --size 256 --water 21.5")).expect("import raw");
+    let import = cli.generator_options.import.expect("import options");
+    assert_eq!(import.source, ImportSource::SimulatorRaw { region_size: [256, 256], water_level: 21.5 });
+    assert_eq!(import.name, "sandbox");
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g osgrid --import-raw v.raw --loc 1000,1000 --size 512,256")).expect("import raw default water");
+    assert_eq!(cli.generator_options.import.expect("import options").source, ImportSource::SimulatorRaw { region_size: [512, 256], water_level: DEFAULT_WATER_LEVEL });
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import-raw v.raw --loc 1000,1000")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import-raw v.raw --loc 1000,1000 --size 256 --water deep")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png --loc 1000,1000 --size 256")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid --import t.png --import-raw v.raw --loc 1000,1000 --size 256")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g osgrid -g agni --import t.png --loc 1000,1000")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt --all-grids --import t.png --loc 1000,1000")).is_err());
+    //  Region lists need one grid, and nothing else.
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g Agni --import-known /tmp/agni.csv")).expect("import known");
+    assert_eq!(cli.generator_options.import_known, Some(PathBuf::from("/tmp/agni.csv")));
+    assert_eq!(cli.generator_options.import_known_units, CoordUnits::Meters);
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g agni --import-known agni.csv --region-units regions")).expect("region units");
+    assert_eq!(cli.generator_options.import_known_units, CoordUnits::Regions);
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni --import-known agni.csv --region-units feet")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --region-units regions")).is_err());
+    assert_eq!(cli.grids, GridSelection::Named(vec!["agni".to_string()]));
+    assert!(parse_args(&argv("generateterrain -c creds.txt --import-known agni.csv")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni -g osgrid --import-known agni.csv")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni --import-known agni.csv --import t.png --loc 0,0")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --import-known agni.csv --known-regions")).is_err());
+    //  Export needs one grid, and no output directory.
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g Agni --export-impostors /tmp/agni.csv")).expect("export");
+    assert_eq!(cli.generator_options.export, Some(ExportOptions { path: PathBuf::from("/tmp/agni.csv"), format: ExportFormat::Csv, table: ExportTable::Live }));
+    let cli = parse_args(&argv("generateterrain -c creds.txt -g agni --export-impostors agni.geojson --format GeoJSON --initial")).expect("export geojson");
+    let export = cli.generator_options.export.expect("export options");
+    assert_eq!((export.format, export.table), (ExportFormat::GeoJson, ExportTable::Initial));
+    assert!(parse_args(&argv("generateterrain -c creds.txt --export-impostors agni.csv")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni -g osgrid --export-impostors agni.csv")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni --export-impostors agni.kml --format kml")).is_err());
+    assert!(parse_args(&argv("generateterrain -c creds.txt -g agni --export-impostors agni.csv --promote")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --initial")).is_err());
+    assert!(parse_args(&argv("generateterrain -h")).is_err());
+}
+
+#[test]
+fn test_grid_selection() {
+    fn argv(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+    let no_query = || -> Result<Vec<String>, Error> { panic!("Named grids don't need the database") };
+    //  Several grids, in order given, duplicates dropped.
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g Agni --grid OSgrid -g agni --os-grids osgrid,Kitely")).expect("several grids");
+    assert_eq!(cli.grids.resolve(no_query).unwrap(), vec!["agni", "osgrid"]);
+    assert_eq!(cli.os_grids, vec!["osgrid", "kitely"]);
+    //  All grids, from the database.
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt --all-grids")).expect("all grids");
+    assert_eq!(cli.grids, GridSelection::All);
+    let grids = cli.grids.resolve(|| Ok(vec!["osgrid".to_string(), "Agni".to_string(), "agni".to_string()])).unwrap();
+    assert_eq!(grids, vec!["agni", "osgrid"]);
+    assert!(GridSelection::All.resolve(|| Ok(Vec::new())).is_err());
+    assert!(GridSelection::All.resolve(|| Err(anyhow!("Database down"))).is_err());
+    //  Not both, and not neither.
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --all-grids")).is_err());
+    assert!(parse_args(&argv("generateterrain -o /tmp/out -c creds.txt")).is_err());
+    //  Migration needs no grid.
+    let cli = parse_args(&argv("generateterrain -c creds.txt --migrate")).expect("migrate");
+    assert_eq!(cli.grids, GridSelection::Named(Vec::new()));
+}
+
+#[test]
+fn test_options_for_grid() {
+    let options = GeneratorOptions { water_tiles: true, ..Default::default() };
+    let os_grids = vec!["osgrid".to_string(), "kitely".to_string()];
+    let sl = options_for_grid(&options, "agni", &os_grids);
+    assert!(!sl.corners_touch_connects);
+    assert!(sl.water_tiles);
+    let os = options_for_grid(&options, "osgrid", &os_grids);
+    assert!(os.corners_touch_connects);
+    assert!(os.water_tiles);
+    assert!(!options_for_grid(&options, "osgrid", &[]).corners_touch_connects);
+}
+
+#[test]
+fn test_mesh_and_corners_independent() {
+    //  --mesh once landed in corners_touch_connects, and the Open Simulator setting in generate_mesh.
+    //  Each must change only its own behavior.
+    fn argv(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+    //  Two regions which touch only at a corner.
+    let groups = |options: &GeneratorOptions| {
+        let mut viz_groups = VizGroups::new(options.corners_touch_connects);
+        viz_groups.add_region_data(common::test_region("A", 0, 256, 256));
+        viz_groups.add_region_data(common::test_region("B", 256, 0, 256));
+        viz_groups.end_grid().len()
+    };
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g agni --mesh")).expect("mesh");
+    let mesh = options_for_grid(&cli.generator_options, "agni", &cli.os_grids);
+    assert!(mesh.generate_mesh && !mesh.corners_touch_connects);
+    assert_eq!(groups(&mesh), 2);
+    let cli = parse_args(&argv("generateterrain -o /tmp/out -c creds.txt -g osgrid --os-grids osgrid")).expect("os grid");
+    let os = options_for_grid(&cli.generator_options, "osgrid", &cli.os_grids);
+    assert!(!os.generate_mesh && os.corners_touch_connects);
+    assert_eq!(groups(&os), 1);
+}
+
+#[test]
+fn test_usage_lists_options_once() {
+    let usage = usage_text("generateterrain");
+    for option in ["outdir", "credentials", "grid", "all-grids", "os-grids", "prefix", "mesh", "sculpt-dim", "jobs", "cache-mb", "varregion-lods", "water-tiles", "min-group-size", "max-lod", "diag-maps", "overview-map", "atlas", "dry-run", "plan-route", "promote", "migrate", "check-db", "repair", "purge-retired", "import", "import-raw", "loc", "name", "size", "water", "import-known", "region-units", "known-regions", "json",
+        "stale-days", "log-level", "log-file", "verbose", "help"] {
+        let long = format!("--{} ", option);
+        let long_arg = format!("--{}\n", option);
+        assert_eq!(usage.matches(&long).count() + usage.matches(&long_arg).count(), 1, "Option --{} in usage:\n{}", option, usage);
+    }
+}
+
+#[test]
+fn test_height_field_from_row() {
+    //  3x3 samples of a 256m region, all at the same level.
+    let row: RawTerrainRow = (256, 256, 3, 3, 100.0, 20.0, vec![128; 9], "Test Region".to_string(), 22.5, None, None, 1_774_900_000);
+    let (name, height_field, source) = height_field_from_row(row).expect("valid row");
+    assert_eq!(name, "Test Region");
+    //  Provenance is from the bytes read, not another query.
+    assert_eq!(source, TerrainSource::new(1_774_900_000, &[128; 9]));
+    assert_eq!(height_field.water_level, 22.5);
+    assert_eq!((height_field.size_x, height_field.size_y), (256, 256));
+    let (_scale, _offset, elevs) = height_field.into_sculpt_array().expect("sculpt array");
+    assert_eq!(elevs.len(), 3);
+    assert!(elevs.iter().all(|row| row.len() == 3));
+    assert_eq!(height_field.spacing, [128.0, 128.0]);
+    //  Blob length doesn't match samples.
+    let bad_row: RawTerrainRow = (256, 256, 3, 3, 100.0, 20.0, vec![128; 8], "Test Region".to_string(), 22.5, None, None, 0);
+    assert!(height_field_from_row(bad_row).is_err());
+    //  The survey script's 4 m lattice, stopping short of the far edges.
+    let row: RawTerrainRow = (256, 256, 64, 64, 100.0, 20.0, vec![128; 64 * 64], "Test Region".to_string(), 22.5, Some(4.0), Some(4.0), 0);
+    let (_, height_field, _) = height_field_from_row(row).expect("4 m row");
+    assert_eq!((height_field.dims(), height_field.spacing), ((64, 64), [4.0, 4.0]));
+    assert_eq!(height_field.to_shared_edges().dims(), (65, 65));
+    //  A spacing which doesn't fit the samples.
+    let bad_row: RawTerrainRow = (256, 256, 3, 3, 100.0, 20.0, vec![128; 9], "Test Region".to_string(), 22.5, Some(4.0), Some(4.0), 0);
+    assert!(height_field_from_row(bad_row).is_err());
+}
+
+#[test]
+fn test_select_regions_excludes_deleted() {
+    assert!(SQL_SELECT_REGIONS.contains("WHERE grid = :grid AND NOT deleted"), "{}", SQL_SELECT_REGIONS);
+    //  Pages are in location order, so each can start where the last ended.
+    assert!(SQL_SELECT_REGIONS.contains("ORDER BY region_loc_x, region_loc_y LIMIT :page_size"), "{}", SQL_SELECT_REGIONS);
+}
+
+#[test]
+fn test_read_grid_regions() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    let row = |x: u64, name: &str| DbRow(vec![DbValue::text("Agni"), DbValue::UInt(x), DbValue::UInt(512), DbValue::UInt(256), DbValue::UInt(256), DbValue::text(name)]);
+    let mut fake = FakeDb::new_with_results(vec![vec![row(256, "Ahern"), row(512, "Morris")]]);
+    let regions = read_grid_regions(&mut fake, "agni", None, REGION_PAGE_SIZE).unwrap();
+    assert_eq!(fake.sql(), vec![SQL_SELECT_REGIONS.split_whitespace().collect::<Vec<_>>().join(" ")]);
+    assert_eq!(regions.len(), 2);
+    assert_eq!((regions[1].region_loc_x, regions[1].region_loc_y, regions[1].name.as_str()), (512, 512, "Morris"));
+    assert!(regions.iter().all(|r| r.lod == 0 && r.children.is_empty() && !r.is_water && r.region_size_x == 256));
+    //  A bad row fails the whole read.
+    let mut fake = FakeDb::new_with_results(vec![vec![DbRow(vec![DbValue::Null; 6])]]);
+    assert!(read_grid_regions(&mut fake, "agni", Some((256, 512)), REGION_PAGE_SIZE).is_err());
+}
@@ -0,0 +1,230 @@
+//! importterrain.rs -- put terrain from a file into raw_terrain_heights.
+//! Part of the Animats impostor system
+//!
+//! Normally raw terrain comes from an LSL script visiting regions.
+//! This is for terrain from elsewhere, such as a PNG-16 written by
+//! HeightField::save_png16 and edited in a terrain editor, or a
+//! terrain file exported from an Open Simulator region. It replaces
+//! any existing row for the region.
+//!
+//! Simulator terrain files come in two formats, both one sample per meter,
+//! with no header.
+//!
+//! - .raw, the classic Linden Lab format. 13 bytes per sample. Height is
+//!   channel 0 times channel 1 / 128. Rows run north to south.
+//! - .r32, little-endian f32 meters. Rows run south to north.
+//!
+//! A height field has one more row and column than that, because its edges
+//! are shared with the regions to the north and east. Those are copied from
+//! the last row and column.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use common::{HeightField, RawTerrainHeights};
+use mysql::PooledConn;
+use std::path::{Path, PathBuf};
+
+/// Creator recorded for imported terrain.
+const IMPORT_CREATOR: &str = "generateterrain --import";
+/// Bytes per sample in a Linden Lab .raw file.
+const LLRAW_CHANNELS: usize = 13;
+
+/// Where the terrain comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportSource {
+    /// PNG-16 from HeightField::save_png16. Knows its own size and water level.
+    Png16,
+    /// Simulator .raw or .r32 file, by extension. Size and water level must be given.
+    SimulatorRaw {
+        /// Region size, meters
+        region_size: [u32; 2],
+        /// Water level, meters
+        water_level: f32,
+    },
+}
+
+/// Import options, from the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportOptions {
+    /// Terrain file
+    pub path: PathBuf,
+    /// Its format
+    pub source: ImportSource,
+    /// Region location, meters
+    pub region_loc: [u32; 2],
+    /// Region name
+    pub name: String,
+}
+
+impl ImportOptions {
+    /// Parse a region location, "X,Y" in meters.
+    pub fn parse_loc(s: &str) -> Result<[u32; 2], Error> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+        match parts.as_slice() {
+            [x, y] => match (x.parse::<u32>(), y.parse::<u32>()) {
+                (Ok(x), Ok(y)) => Ok([x, y]),
+                _ => Err(anyhow!("Region location \"{}\" is not two numbers", s)),
+            },
+            _ => Err(anyhow!("Region location \"{}\" is not X,Y", s)),
+        }
+    }
+
+    /// Parse a region size, "N" for square or "X,Y", in meters.
+    pub fn parse_size(s: &str) -> Result<[u32; 2], Error> {
+        let size = if s.contains(',') {
+            Self::parse_loc(s).map_err(|_| anyhow!("Region size \"{}\" is not N or X,Y", s))?
+        } else {
+            let n = s.trim().parse::<u32>().map_err(|_| anyhow!("Region size \"{}\" is not a number", s))?;
+            [n, n]
+        };
+        if size[0] == 0 || size[1] == 0 {
+            return Err(anyhow!("Region size \"{}\" is empty", s));
+        }
+        Ok(size)
+    }
+}
+
+/// What an import did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportReport {
+    pub grid: String,
+    pub name: String,
+    pub region_loc: [u32; 2],
+    /// Samples, (X, Y)
+    pub samples: (usize, usize),
+    /// Lowest and highest elevation, meters
+    pub min_max: (f32, f32),
+    /// New region, not a replacement
+    pub inserted: bool,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} \"{}\" at ({}, {}) on grid \"{}\", {} x {} samples, elevations {:.2} .. {:.2}.",
+            if self.inserted { "Imported" } else { "Replaced" }, self.name, self.region_loc[0], self.region_loc[1], self.grid, self.samples.0, self.samples.1, self.min_max.0, self.min_max.1)
+    }
+}
+
+/// Expected file size, bytes, with a clear error if it's wrong.
+fn check_file_size(bytes: &[u8], region_size: [u32; 2], bytes_per_sample: usize, format: &str) -> Result<(), Error> {
+    let expected = region_size[0] as usize * region_size[1] as usize * bytes_per_sample;
+    if bytes.len() != expected {
+        return Err(anyhow!("File is {} bytes, but a {} x {} {} file is {} bytes. Wrong --size, or not a {} file?",
+            bytes.len(), region_size[0], region_size[1], format, expected, format));
+    }
+    Ok(())
+}
+
+/// Height field from one sample per meter, indexed (x, y).
+/// The north and east edges are copied to make the extra row and column.
+fn height_field_from_samples(region_size: [u32; 2], water_level: f32, sample: impl Fn(usize, usize) -> f32) -> Result<HeightField, Error> {
+    let (nx, ny) = (region_size[0] as usize, region_size[1] as usize);
+    Ok(HeightField::new_from_fn(nx + 1, ny + 1, region_size[0], region_size[1], water_level, |x, y| sample(x.min(nx - 1), y.min(ny - 1)))?)
+}
+
+/// Height field from a Linden Lab .raw file.
+fn parse_llraw(bytes: &[u8], region_size: [u32; 2], water_level: f32) -> Result<HeightField, Error> {
+    check_file_size(bytes, region_size, LLRAW_CHANNELS, "13-channel .raw")?;
+    let (nx, ny) = (region_size[0] as usize, region_size[1] as usize);
+    height_field_from_samples(region_size, water_level, |x, y| {
+        //  First row is the north edge.
+        let pos = ((ny - 1 - y) * nx + x) * LLRAW_CHANNELS;
+        bytes[pos] as f32 * bytes[pos + 1] as f32 / 128.0
+    })
+}
+
+/// Height field from an .r32 file.
+fn parse_r32(bytes: &[u8], region_size: [u32; 2], water_level: f32) -> Result<HeightField, Error> {
+    check_file_size(bytes, region_size, 4, ".r32")?;
+    let nx = region_size[0] as usize;
+    height_field_from_samples(region_size, water_level, |x, y| {
+        let pos = (y * nx + x) * 4;
+        f32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    })
+}
+
+/// Read a simulator terrain file. Format is by extension.
+fn read_simulator_raw(path: &Path, region_size: [u32; 2], water_level: f32) -> Result<HeightField, Error> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let parse = match extension.as_str() {
+        "raw" => parse_llraw,
+        "r32" => parse_r32,
+        _ => return Err(anyhow!("\"{}\": terrain file must be .raw or .r32", path.display())),
+    };
+    let bytes = std::fs::read(path).map_err(|e| anyhow!("Unable to read \"{}\": {:?}", path.display(), e))?;
+    parse(&bytes, region_size, water_level).map_err(|e| anyhow!("\"{}\": {}", path.display(), e))
+}
+
+/// Read the file and insert or replace the region's raw terrain.
+pub fn import_terrain(conn: &mut PooledConn, grid: &str, options: &ImportOptions) -> Result<ImportReport, Error> {
+    let height_field = match &options.source {
+        ImportSource::Png16 => HeightField::load_png16(&options.path)?,
+        ImportSource::SimulatorRaw { region_size, water_level } => read_simulator_raw(&options.path, *region_size, *water_level)?,
+    };
+    let row = RawTerrainHeights::new_from_height_field(grid, options.region_loc, &options.name, &height_field, IMPORT_CREATOR)?;
+    let inserted = row.insert_or_update(conn)?;
+    let report = ImportReport { grid: grid.to_string(), name: options.name.clone(), region_loc: options.region_loc, samples: height_field.dims(), min_max: height_field.min_max(), inserted };
+    log::info!("{}", report);
+    Ok(report)
+}
+
+#[test]
+fn test_parse_loc() {
+    assert_eq!(ImportOptions::parse_loc("1000,1000").unwrap(), [1000, 1000]);
+    assert_eq!(ImportOptions::parse_loc(" 256 , 512 ").unwrap(), [256, 512]);
+    assert!(ImportOptions::parse_loc("1000").is_err());
+    assert!(ImportOptions::parse_loc("1000,1000,0").is_err());
+    assert!(ImportOptions::parse_loc("x,1000").is_err());
+    assert!(ImportOptions::parse_loc("-1,1000").is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(ImportOptions::parse_size("256").unwrap(), [256, 256]);
+    assert_eq!(ImportOptions::parse_size("512,256").unwrap(), [512, 256]);
+    assert!(ImportOptions::parse_size("0").is_err());
+    assert!(ImportOptions::parse_size("big").is_err());
+    assert!(ImportOptions::parse_size("512,").is_err());
+}
+
+#[test]
+fn test_parse_simulator_raw() {
+    //  8 x 4 region, height 2x + 10y + 1, with no two samples alike.
+    let size = [8u32, 4u32];
+    let height = |x: usize, y: usize| (2 * x + 10 * y + 1) as f32;
+    let check = |height_field: &HeightField| {
+        assert_eq!(height_field.dims(), (9, 5));
+        assert_eq!((height_field.size_x, height_field.size_y, height_field.water_level), (8, 4, 20.0));
+        for x in 0..9 {
+            for y in 0..5 {
+                //  Extra row and column copy the edges.
+                assert_eq!(height_field.sample(x, y), height(x.min(7), y.min(3)), "({}, {})", x, y);
+            }
+        }
+    };
+    //  LL .raw, north row first. Height is channel 0 * channel 1 / 128.
+    let mut llraw = Vec::new();
+    for y in (0..4).rev() {
+        for x in 0..8 {
+            let mut sample = [0u8; LLRAW_CHANNELS];
+            sample[0] = (height(x, y) * 2.0) as u8;
+            sample[1] = 64;
+            sample[2] = 20;     // water, ignored
+            llraw.extend_from_slice(&sample);
+        }
+    }
+    check(&parse_llraw(&llraw, size, 20.0).expect("llraw"));
+    //  .r32, south row first.
+    let r32: Vec<u8> = (0..4).flat_map(|y| (0..8).map(move |x| height(x, y))).flat_map(|z| z.to_le_bytes()).collect();
+    check(&parse_r32(&r32, size, 20.0).expect("r32"));
+    //  Wrong sizes say what was expected.
+    let msg = parse_llraw(&llraw, [8, 8], 20.0).err().expect("wrong size accepted").to_string();
+    assert!(msg.contains("416 bytes") && msg.contains("832 bytes"), "{}", msg);
+    let msg = parse_r32(&llraw, size, 20.0).err().expect("llraw taken as r32").to_string();
+    assert!(msg.contains(".r32"), "{}", msg);
+    //  Unknown extension.
+    assert!(read_simulator_raw(Path::new("/tmp/terrain.ter"), size, 20.0).is_err());
+}
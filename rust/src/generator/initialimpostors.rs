@@ -0,0 +1,626 @@
+//! initialimpostors.rs -- the next version of the region_impostors table.
+//! Part of the Animats impostor system
+//!
+//! A generator run produces files which must be uploaded to the asset
+//! servers before the viewer can use them. Until that's done, the new
+//! impostors can't go into region_impostors, which is what viewers read.
+//! So they go into initial_impostors first, which has the same columns.
+//! UUIDs are filled in as uploads complete.
+//!
+//! When every UUID in initial_impostors for a grid is filled in, that
+//! grid's rows are copied over region_impostors, as an atomic operation.
+//! That's promotion.
+//!
+//! Promotion replaces all of a grid's live impostors, so initial_impostors
+//! must hold every tile of the next version, not just those rebuilt. Tiles
+//! which didn't need rebuilding are carried forward from region_impostors,
+//! assets and generation unchanged, so they are neither retired nor reported
+//! to viewers as changed.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     February, 2026.
+//
+use anyhow::{anyhow, Error};
+use mysql::params;
+use common::db::Db;
+use crate::neededregions::TileWork;
+use common::{HeightField, RegionData, RegionImpostorData, RegionImpostorFaceData, faces_from_json, faces_to_json, lod_distance, record_impostor_changes, retire_superseded,
+    uuid_opt_to_string};
+
+/// Most missing impostors listed in an error message.
+const MAX_MISSING_LISTED: usize = 10;
+
+/// Delete a grid's live impostors.
+const SQL_DELETE_LIVE: &str = r"DELETE FROM region_impostors WHERE grid = :grid";
+/// Copy a grid's new impostors to live. The tables have the same columns, provenance included.
+const SQL_COPY_TO_LIVE: &str = r"INSERT INTO region_impostors SELECT * FROM initial_impostors WHERE grid = :grid";
+/// Everything needed to tell which of a grid's assets are missing.
+/// Faces are checked in Rust, because they're JSON. Water-only impostors have no assets to miss.
+const SQL_FIND_MISSING: &str = r"SELECT grid, name, region_loc_x, region_loc_y, region_size_x, region_size_y, impostor_lod,
+        sculpt_uuid, sculpt_hash, mesh_uuid, faces_json
+    FROM initial_impostors
+    WHERE grid = :grid AND NOT water_only
+    ORDER BY impostor_lod, region_loc_x, region_loc_y";
+
+/// Delete a grid's new impostors, before generating them again.
+const SQL_CLEAR_GRID: &str = r"DELETE FROM initial_impostors WHERE grid = :grid";
+/// Add one new impostor. UUIDs are filled in later, as uploads complete.
+const SQL_ADD_IMPOSTOR: &str = r"INSERT INTO initial_impostors
+        (grid, name, region_loc_x, region_loc_y, region_size_x, region_size_y, uniqueness_viz_group,
+        scale_x, scale_y, scale_z,
+        elevation_offset, impostor_lod, viz_group,
+        mesh_uuid, mesh_hash, sculpt_uuid, sculpt_hash,
+        water_height, water_height_max, creator, creation_time, faces_json, generation, placeholder, lod_distance, water_only, provenance_json, terrain_hash)
+    VALUES
+        (:grid, :name, :region_loc_x, :region_loc_y, :region_size_x, :region_size_y, :uniqueness_viz_group,
+        :scale_x, :scale_y, :scale_z,
+        :elevation_offset, :impostor_lod, :viz_group,
+        :mesh_uuid, :mesh_hash, :sculpt_uuid, :sculpt_hash,
+        :water_height, :water_height_max, :creator, NOW(), :faces_json, :generation, :placeholder, :lod_distance, :water_only, :provenance_json, :terrain_hash)";
+/// Copy a live impostor, unchanged, into the next generation.
+/// More than one row per tile is possible, one per viz group, so the old viz group picks one.
+const SQL_CARRY_FORWARD: &str = r"INSERT INTO initial_impostors SELECT * FROM region_impostors
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y
+        AND impostor_lod = :impostor_lod AND viz_group = :old_viz_group LIMIT 1";
+/// Move a carried impostor to its new viz group.
+const SQL_SET_VIZ_GROUP: &str = r"UPDATE initial_impostors SET viz_group = :viz_group, uniqueness_viz_group = :viz_group
+    WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y
+        AND impostor_lod = :impostor_lod AND viz_group = :old_viz_group";
+/// Creator recorded for generated impostors.
+const CREATOR: &str = "generateterrain";
+
+/// What is missing from an impostor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MissingKind {
+    /// Sculpt image not uploaded yet.
+    Sculpt,
+    /// Mesh not uploaded yet.
+    Mesh,
+    /// Base texture for this face not uploaded yet.
+    Texture { face: usize },
+    /// Faces JSON is unreadable, so which textures are missing is unknown.
+    CorruptFaces(String),
+}
+
+impl std::fmt::Display for MissingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MissingKind::Sculpt => write!(f, "no sculpt UUID"),
+            MissingKind::Mesh => write!(f, "no mesh UUID"),
+            MissingKind::Texture { face } => write!(f, "no texture UUID for face {}", face),
+            MissingKind::CorruptFaces(msg) => write!(f, "corrupt faces JSON: {}", msg),
+        }
+    }
+}
+
+/// An impostor waiting for its assets to be uploaded. One per missing asset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingAsset {
+    /// The impostor's tile
+    pub region: RegionData,
+    /// What's missing
+    pub kind: MissingKind,
+}
+
+/// A tile not rebuilt this run. Its live impostor goes into the next generation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarriedTile {
+    /// Grid, lower case
+    pub grid: String,
+    /// Location in world of tile (meters)
+    pub region_loc_x: u32,
+    /// Location in world of tile (meters)
+    pub region_loc_y: u32,
+    /// Level of detail
+    pub lod: u8,
+    /// Viz group of the live impostor
+    pub old_viz_group: usize,
+    /// Viz group for this run
+    pub viz_group: usize,
+}
+
+impl CarriedTile {
+    /// The carried tile, if this tile isn't being rebuilt.
+    pub fn new(region: &RegionData, work: &TileWork, viz_group: usize) -> Option<Self> {
+        let old_viz_group = match work {
+            TileWork::Unchanged => viz_group,
+            TileWork::VizGroupChanged { old_viz_group } => *old_viz_group,
+            TileWork::NewRegion | TileWork::ChangedTerrain => return None,
+        };
+        Some(Self { grid: region.grid.to_lowercase(), region_loc_x: region.region_loc_x, region_loc_y: region.region_loc_y, lod: region.lod,
+            old_viz_group, viz_group })
+    }
+}
+
+/// What promotion did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromotionReport {
+    /// Grid promoted
+    pub grid: String,
+    /// Old impostors removed from region_impostors
+    pub deleted: u64,
+    /// New impostors copied into region_impostors
+    pub inserted: u64,
+    /// Old assets no longer used
+    pub retired: usize,
+    /// Impostors added, replaced, or removed, as recorded for viewers
+    pub changes: usize,
+}
+
+impl std::fmt::Display for PromotionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Grid \"{}\": {} impostors replaced by {} new ones, {} changed, {} assets retired.",
+            self.grid, self.deleted, self.inserted, self.changes, self.retired)
+    }
+}
+
+/// The data for an initial_impostors row, for a tile just built.
+/// Location, size, and LOD come from the tile. Scale, offset, and water level come from the height field.
+/// The recommended LOD distance comes from the scale, at this quality. There are no UUIDs yet.
+pub fn assemble_region_impostor_data(
+    region: &RegionData,
+    height_field: &HeightField,
+    viz_group: u32,
+    sculpt_hash: Option<String>,
+    mesh_hash: Option<String>,
+    faces: Vec<RegionImpostorFaceData>,
+    lod_quality: f32,
+) -> Result<RegionImpostorData, Error> {
+    let (scale_z, elevation_offset) = height_field.get_scale_offset()?;
+    let scale = [height_field.size_x as f32, height_field.size_y as f32, scale_z];
+    Ok(RegionImpostorData {
+        region_loc: region.loc(),
+        region_size: [region.region_size_x, region.region_size_y],
+        scale,
+        impostor_lod: region.lod,
+        lod_distance: lod_distance(scale, lod_quality),
+        viz_group,
+        sculpt_uuid: None,
+        sculpt_hash,
+        mesh_uuid: None,
+        mesh_hash,
+        elevation_offset,
+        //  A placeholder is drawn flat at a default level, but the region's real water level is unknown.
+        water_height: if region.is_placeholder { None } else { Some(height_field.water_level) },
+        water_height_max: height_field.water_level_max,
+        name: Some(region.name.clone()),
+        grid: region.grid.to_lowercase(),
+        faces,
+        generation: 0,
+        placeholder: region.is_placeholder,
+        water_only: false,
+        provenance_json: None,
+        terrain_hash: None,
+    })
+}
+
+/// The data for an initial_impostors row, for a tile with no land above water.
+/// There are no assets and no faces.
+pub fn assemble_water_only_impostor_data(
+    region: &RegionData,
+    height_field: &HeightField,
+    viz_group: u32,
+    lod_quality: f32,
+) -> Result<RegionImpostorData, Error> {
+    let data = assemble_region_impostor_data(region, height_field, viz_group, None, None, Vec::new(), lod_quality)?;
+    Ok(RegionImpostorData { water_only: true, ..data })
+}
+
+/// Access to the initial_impostors table.
+pub struct InitialImpostors {}
+
+impl InitialImpostors {
+    /// Assets for this grid's impostors which don't have UUIDs yet.
+    pub fn find_missing_uuids(conn: &mut dyn Db, grid: &str) -> Result<Vec<MissingAsset>, Error> {
+        //  Column order must match SQL_FIND_MISSING.
+        let rows = conn.exec_map(SQL_FIND_MISSING, params! { "grid" => grid.to_lowercase() }, |row| {
+            let region = RegionData { grid: row.get(0)?, name: row.get(1)?, region_loc_x: row.get(2)?, region_loc_y: row.get(3)?,
+                region_size_x: row.get(4)?, region_size_y: row.get(5)?, lod: row.get(6)?, children: Vec::new(), is_water: false, is_placeholder: false };
+            let sculpt_uuid: Option<String> = row.get(7)?;
+            let sculpt_hash: Option<String> = row.get(8)?;
+            let mesh_uuid: Option<String> = row.get(9)?;
+            let faces_json: String = row.get(10)?;
+            Ok((region, classify_missing(&sculpt_uuid, &sculpt_hash, &mesh_uuid, &faces_json)))
+        })?;
+        let mut missing = Vec::new();
+        for (region, kinds) in rows {
+            missing.extend(kinds.into_iter().map(|kind| MissingAsset { region: region.clone(), kind }));
+        }
+        Ok(missing)
+    }
+
+    /// Remove a grid's new impostors. Done once per grid, before generating.
+    pub fn clear_grid(conn: &mut dyn Db, grid: &str) -> Result<(), Error> {
+        conn.exec_drop(SQL_CLEAR_GRID, params! { "grid" => grid.to_lowercase() })?;
+        Ok(())
+    }
+
+    /// Add one new impostor.
+    pub fn add_impostor(conn: &mut dyn Db, impostor: &RegionImpostorData) -> Result<(), Error> {
+        let insert_params = params! {
+            "grid" => impostor.grid.to_lowercase(),
+            "name" => impostor.name.clone().unwrap_or_default(),
+            "region_loc_x" => impostor.region_loc[0].meters(),
+            "region_loc_y" => impostor.region_loc[1].meters(),
+            "region_size_x" => impostor.region_size[0],
+            "region_size_y" => impostor.region_size[1],
+            "uniqueness_viz_group" => impostor.viz_group,
+            "scale_x" => impostor.scale[0].round() as u32,
+            "scale_y" => impostor.scale[1].round() as u32,
+            "scale_z" => impostor.scale[2],
+            "elevation_offset" => impostor.elevation_offset,
+            "impostor_lod" => impostor.impostor_lod,
+            "viz_group" => impostor.viz_group,
+            "mesh_uuid" => uuid_opt_to_string(impostor.mesh_uuid),
+            "mesh_hash" => impostor.mesh_hash.clone(),
+            "sculpt_uuid" => uuid_opt_to_string(impostor.sculpt_uuid),
+            "sculpt_hash" => impostor.sculpt_hash.clone(),
+            "water_height" => impostor.water_height,
+            "water_height_max" => impostor.water_height_max,
+            "creator" => CREATOR,
+            "faces_json" => faces_to_json(&impostor.faces)?,
+            "generation" => impostor.generation,
+            "placeholder" => impostor.placeholder,
+            "lod_distance" => impostor.lod_distance,
+            "water_only" => impostor.water_only,
+            "provenance_json" => impostor.provenance_json.clone(),
+            "terrain_hash" => impostor.terrain_hash.clone(),
+        };
+        conn.exec_drop(SQL_ADD_IMPOSTOR, insert_params)?;
+        Ok(())
+    }
+
+    /// Carry one live impostor forward, into its new viz group if that changed.
+    /// Returns rows copied, 0 if the live impostor has gone.
+    pub fn carry_forward(conn: &mut dyn Db, tile: &CarriedTile) -> Result<u64, Error> {
+        let copied = conn.exec_drop(SQL_CARRY_FORWARD, params! {
+            "grid" => &tile.grid,
+            "region_loc_x" => tile.region_loc_x,
+            "region_loc_y" => tile.region_loc_y,
+            "impostor_lod" => tile.lod,
+            "old_viz_group" => tile.old_viz_group as u64,
+        })?;
+        if tile.viz_group != tile.old_viz_group {
+            conn.exec_drop(SQL_SET_VIZ_GROUP, params! {
+                "grid" => &tile.grid,
+                "region_loc_x" => tile.region_loc_x,
+                "region_loc_y" => tile.region_loc_y,
+                "impostor_lod" => tile.lod,
+                "old_viz_group" => tile.old_viz_group as u64,
+                "viz_group" => tile.viz_group as u64,
+            })?;
+        }
+        Ok(copied)
+    }
+
+    /// Add the new impostors for one viz group, and carry forward its tiles not rebuilt, all or none.
+    /// On failure, the group is rolled back and the error returned. Other groups are not affected.
+    pub fn add_group(conn: &mut dyn Db, impostors: &[RegionImpostorData], carried: &[CarriedTile]) -> Result<(), Error> {
+        conn.start_transaction()?;
+        let added = impostors.iter().try_for_each(|impostor| Self::add_impostor(conn, impostor))
+            .and_then(|()| carried.iter().try_for_each(|tile| Self::carry_forward(conn, tile).map(|_| ())));
+        if let Err(e) = added {
+            conn.rollback()?;
+            return Err(e);
+        }
+        conn.commit()
+    }
+
+    /// Can this grid be promoted now? For polling.
+    #[allow(dead_code)] // for upload tools to poll, not used by the generator
+    pub fn promotion_ready(conn: &mut dyn Db, grid: &str) -> Result<bool, Error> {
+        Ok(Self::find_missing_uuids(conn, grid)?.is_empty())
+    }
+
+    /// Replace the grid's live impostors with the new ones.
+    /// Refuses if any UUIDs are missing. Either all of it happens or none of it does.
+    pub fn promote(conn: &mut dyn Db, grid: &str) -> Result<PromotionReport, Error> {
+        let missing = Self::find_missing_uuids(conn, grid)?;
+        check_promotion_ready(grid, &missing)?;
+        let grid_key = grid.to_lowercase();
+        conn.start_transaction()?;
+        //  The live generation's unused assets are retired, and the changes recorded, before it's deleted.
+        //  Viewers see the changes only when the new impostors are there too.
+        let replaced = retire_superseded(conn, &grid_key)
+            .and_then(|retired| Ok((retired, record_impostor_changes(conn, &grid_key)?)))
+            .and_then(|(retired, changes)| {
+                let deleted = conn.exec_drop(SQL_DELETE_LIVE, params! { "grid" => &grid_key })?;
+                Ok((retired, changes, deleted, conn.exec_drop(SQL_COPY_TO_LIVE, params! { "grid" => &grid_key })?))
+            });
+        let (retired, changes, deleted, inserted) = match replaced {
+            Ok(counts) => counts,
+            Err(e) => {
+                conn.rollback()?;
+                return Err(e);
+            }
+        };
+        conn.commit()?;
+        let report = PromotionReport { grid: grid.to_string(), deleted, inserted, retired, changes };
+        log::info!("Promoted: {}", report);
+        Ok(report)
+    }
+}
+
+/// What's missing from one impostor row.
+/// Impostors are sculpts unless they have a mesh hash and no sculpt hash.
+fn classify_missing(sculpt_uuid: &Option<String>, sculpt_hash: &Option<String>, mesh_uuid: &Option<String>, faces_json: &str) -> Vec<MissingKind> {
+    let mut missing = Vec::new();
+    let is_sculpt = sculpt_hash.is_some() || mesh_uuid.is_none();
+    if is_sculpt && sculpt_uuid.is_none() {
+        missing.push(MissingKind::Sculpt);
+    } else if !is_sculpt && mesh_uuid.is_none() {
+        missing.push(MissingKind::Mesh);
+    }
+    missing.extend(classify_faces(faces_json));
+    missing
+}
+
+/// Which faces have no base texture UUID.
+/// A face not uploaded yet has a nil base texture UUID.
+fn classify_faces(faces_json: &str) -> Vec<MissingKind> {
+    let faces = match faces_from_json(faces_json) {
+        Ok(faces) => faces,
+        Err(e) => return vec![MissingKind::CorruptFaces(format!("{}", e))],
+    };
+    if faces.is_empty() {
+        //  Every impostor has at least one face.
+        return vec![MissingKind::Texture { face: 0 }];
+    }
+    faces
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.base_texture_uuid.is_nil())
+        .map(|(face, _)| MissingKind::Texture { face })
+        .collect()
+}
+
+/// Promotion is allowed only when nothing is missing.
+/// The error lists what is.
+fn check_promotion_ready(grid: &str, missing: &[MissingAsset]) -> Result<(), Error> {
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut listed: Vec<String> = missing
+        .iter()
+        .take(MAX_MISSING_LISTED)
+        .map(|m| format!("\"{}\" at ({}, {}) LOD {}: {}", m.region.name, m.region.region_loc_x, m.region.region_loc_y, m.region.lod, m.kind))
+        .collect();
+    if missing.len() > MAX_MISSING_LISTED {
+        listed.push(format!("and {} more", missing.len() - MAX_MISSING_LISTED));
+    }
+    Err(anyhow!("Grid \"{}\" is not ready for promotion, {} assets have no UUID: {}", grid, missing.len(), listed.join(", ")))
+}
+
+#[test]
+fn test_promotion_gate() {
+    let missing = |n: usize| -> Vec<MissingAsset> {
+        (0..n).map(|i| MissingAsset {
+            region: RegionData { grid: "agni".to_string(), name: format!("R{}", i), region_loc_x: i as u32 * 256, region_loc_y: 0,
+                region_size_x: 256, region_size_y: 256, lod: 0, children: Vec::new(), is_water: false, is_placeholder: false },
+            kind: MissingKind::Sculpt,
+        }).collect()
+    };
+    assert!(check_promotion_ready("agni", &missing(0)).is_ok());
+    let msg = check_promotion_ready("agni", &missing(2)).unwrap_err().to_string();
+    assert!(msg.contains("2 assets"), "{}", msg);
+    assert!(msg.contains("\"R1\" at (256, 0) LOD 0: no sculpt UUID"), "{}", msg);
+    //  Long lists are cut off.
+    let msg = check_promotion_ready("agni", &missing(MAX_MISSING_LISTED + 5)).unwrap_err().to_string();
+    assert!(msg.contains("and 5 more"), "{}", msg);
+    assert!(!msg.contains(&format!("\"R{}\"", MAX_MISSING_LISTED)), "{}", msg);
+    //  Both halves of the promotion work on the same grid, and only that grid.
+    for sql in [SQL_DELETE_LIVE, SQL_COPY_TO_LIVE, SQL_FIND_MISSING] {
+        assert!(sql.contains("grid = :grid"), "{}", sql);
+    }
+    assert!(SQL_DELETE_LIVE.starts_with("DELETE FROM region_impostors "));
+    assert!(SQL_COPY_TO_LIVE.starts_with("INSERT INTO region_impostors SELECT * FROM initial_impostors "));
+}
+
+#[test]
+fn test_classify_missing() {
+    const UUID_A: &str = "4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01";
+    const UUID_B: &str = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+    let some = |s: &str| Some(s.to_string());
+    let faces = format!(r#"[{{"base_texture_uuid": "{}"}}, {{"base_texture_uuid": "{}"}}]"#, UUID_A, UUID_B);
+    //  Complete sculpt.
+    assert!(classify_missing(&some(UUID_A), &some("0123abcd"), &None, &faces).is_empty());
+    //  Sculpt not uploaded. Also what an impostor with no hashes at all is.
+    assert_eq!(classify_missing(&None, &some("0123abcd"), &None, &faces), vec![MissingKind::Sculpt]);
+    assert_eq!(classify_missing(&None, &None, &None, &faces), vec![MissingKind::Sculpt]);
+    //  Complete mesh.
+    assert!(classify_missing(&None, &None, &some(UUID_B), &faces).is_empty());
+    //  Second face has no texture yet, in each of the ways that can look, in both formats.
+    for second in [r#"{}"#, r#"{"base_texture_uuid": "00000000-0000-0000-0000-000000000000"}"#] {
+        for faces in [format!(r#"[{{"base_texture_uuid": "{}"}}, {}]"#, UUID_A, second),
+                format!(r#"{{"v": 1, "faces": [{{"base_texture_uuid": "{}"}}, {}]}}"#, UUID_A, second)] {
+            assert_eq!(classify_faces(&faces), vec![MissingKind::Texture { face: 1 }], "{}", faces);
+        }
+    }
+    assert_eq!(classify_faces("[]"), vec![MissingKind::Texture { face: 0 }]);
+    //  Corrupt, each with its own reason.
+    for corrupt in ["[{", r#"{"base_texture_uuid": "x"}"#, r#"[{"base_texture_uuid": "not-a-uuid"}]"#, r#"[{"base_texture_uuid": 42}]"#,
+            r#"[{"base_texture_uuid": null}]"#, r#"{"v": 2, "faces": []}"#] {
+        let kinds = classify_faces(corrupt);
+        assert_eq!(kinds.len(), 1, "{}", corrupt);
+        assert!(matches!(&kinds[0], MissingKind::CorruptFaces(msg) if !msg.is_empty()), "{}: {:?}", corrupt, kinds);
+    }
+    //  Everything at once.
+    let faces = r#"[{}, {}]"#;
+    assert_eq!(classify_missing(&None, &None, &None, faces),
+        vec![MissingKind::Sculpt, MissingKind::Texture { face: 0 }, MissingKind::Texture { face: 1 }]);
+}
+
+#[test]
+fn test_assemble_region_impostor_data() {
+    //  LOD 1 tile, 512m, from a 512m height field made of four regions.
+    let region = RegionData { grid: "Agni".to_string(), name: "LOD1-512-768 Ahern".to_string(), region_loc_x: 512, region_loc_y: 768,
+        region_size_x: 512, region_size_y: 512, lod: 1, children: vec![(512, 768)], is_water: false, is_placeholder: false };
+    let height_field = HeightField::new_from_fn(8, 8, 512, 512, 20.0, |x, y| 15.0 + (x + y) as f32).unwrap();
+    let (scale_z, offset) = height_field.get_scale_offset().unwrap();
+    let face = RegionImpostorFaceData { base_texture_uuid: uuid::Uuid::nil(), emissive_texture_uuid: None,
+        base_texture_hash: "0badf00d".to_string(), emissive_texture_hash: None, atlas_uv: None };
+    let data = assemble_region_impostor_data(&region, &height_field, 7, Some("12345678".to_string()), None, vec![face.clone()], 2.0).unwrap();
+    assert_eq!(data.region_loc, [common::GlobalMeters(512), common::GlobalMeters(768)]);
+    assert_eq!(data.region_size, [512, 512]);
+    assert_eq!(data.impostor_lod, 1);
+    assert_eq!(data.scale, [512.0, 512.0, scale_z]);
+    assert_eq!(data.lod_distance, 2.0 * (512.0 + scale_z));
+    assert_eq!(data.elevation_offset, offset);
+    assert_eq!(offset, 15.0);
+    assert_eq!(data.water_height, Some(20.0));
+    assert_eq!(data.water_height_max, None);
+    assert_eq!(data.viz_group, 7);
+    assert_eq!(data.grid, "agni");
+    assert_eq!(data.name.as_deref(), Some("LOD1-512-768 Ahern"));
+    assert!(data.sculpt_uuid.is_none() && data.mesh_uuid.is_none());
+    assert_eq!(data.sculpt_hash.as_deref(), Some("12345678"));
+    assert_eq!(data.faces, vec![face]);
+    assert!(!data.placeholder);
+    //  Placeholder regions make placeholder impostors.
+    let placeholder = RegionData { lod: 0, is_placeholder: true, children: Vec::new(), ..region };
+    let placeholder_data = assemble_region_impostor_data(&placeholder, &height_field, 7, None, None, Vec::new(), 2.0).unwrap();
+    assert!(placeholder_data.placeholder);
+    assert_eq!(placeholder_data.water_height, None);
+    assert!(!data.water_only);
+}
+
+#[test]
+fn test_assemble_water_only_impostor_data() {
+    //  Sea floor, nothing above water.
+    let region = RegionData { grid: "Agni".to_string(), name: "Blake Sea - Kraken".to_string(), region_loc_x: 290304, region_loc_y: 268288,
+        region_size_x: 256, region_size_y: 256, lod: 0, children: Vec::new(), is_water: false, is_placeholder: false };
+    let height_field = HeightField::new_from_fn(5, 5, 256, 256, 20.0, |x, _| 10.0 + x as f32).unwrap();
+    assert!(height_field.is_effectively_water(0.0));
+    let data = assemble_water_only_impostor_data(&region, &height_field, 3, 2.0).unwrap();
+    assert!(data.water_only && !data.placeholder);
+    assert!(data.faces.is_empty());
+    assert!(data.sculpt_uuid.is_none() && data.mesh_uuid.is_none() && data.mesh_hash.is_none() && data.sculpt_hash.is_none());
+    assert_eq!((data.scale, data.elevation_offset), ([256.0, 256.0, 4.0], 10.0));
+    assert_eq!(data.water_height, Some(20.0));
+    assert_eq!(data.lod_distance, 2.0 * (256.0 + 4.0));
+    assert_eq!((data.viz_group, data.grid.as_str()), (3, "agni"));
+    //  The row says so, and promotion doesn't wait for assets it will never have.
+    assert!(SQL_ADD_IMPOSTOR.contains(":water_only"));
+    assert!(SQL_FIND_MISSING.contains("AND NOT water_only"));
+}
+
+#[test]
+fn test_add_group_transactions() {
+    use common::db::FakeDb;
+    let region = |x: u32| RegionData { grid: "agni".to_string(), name: format!("R{}", x), region_loc_x: x * 256, region_loc_y: 0,
+        region_size_x: 256, region_size_y: 256, lod: 0, children: Vec::new(), is_water: false, is_placeholder: false };
+    let height_field = HeightField::new_from_fn(4, 4, 256, 256, 20.0, |x, _| x as f32).unwrap();
+    let group: Vec<RegionImpostorData> = (0..3)
+        .map(|x| assemble_region_impostor_data(&region(x), &height_field, 1, Some(format!("{:08x}", x)), None, Vec::new(), common::DEFAULT_LOD_QUALITY).unwrap())
+        .collect();
+    const INSERT: &str = "INSERT INTO initial_impostors";
+    //  First three words of each statement.
+    let statements = |fake: &FakeDb| -> Vec<String> { fake.sql().iter().map(|sql| sql.split(' ').take(3).collect::<Vec<_>>().join(" ")).collect() };
+    //  Clear, then one group, committed.
+    let mut fake = FakeDb::default();
+    InitialImpostors::clear_grid(&mut fake, "Agni").unwrap();
+    InitialImpostors::add_group(&mut fake, &group, &[]).unwrap();
+    assert_eq!(statements(&fake), vec!["DELETE FROM initial_impostors", "START TRANSACTION", INSERT, INSERT, INSERT, "COMMIT"]);
+    //  An insert fails. That group is rolled back, and the next group still goes in.
+    let mut fake = FakeDb { fail_on: Some(INSERT.to_string()), ..Default::default() };
+    assert!(InitialImpostors::add_group(&mut fake, &group, &[]).is_err());
+    fake.fail_on = None;
+    InitialImpostors::add_group(&mut fake, &group[..1], &[]).unwrap();
+    assert_eq!(statements(&fake), vec!["START TRANSACTION", INSERT, "ROLLBACK", "START TRANSACTION", INSERT, "COMMIT"]);
+    //  Every column of the table is set, except the UUIDs, which are NULL until uploads.
+    for column in ["grid", "creator", "sculpt_hash", "mesh_hash", "faces_json", "uniqueness_viz_group", "generation", "water_height_max", "placeholder", "lod_distance",
+            "water_only", "provenance_json", "terrain_hash"] {
+        assert!(SQL_ADD_IMPOSTOR.contains(&format!(":{}", column)), "{}", column);
+    }
+    assert!(SQL_CLEAR_GRID.contains("grid = :grid"));
+}
+
+#[test]
+fn test_one_tile_rebuilt() {
+    use common::db::FakeDb;
+    //  Three regions. Only the first has new terrain. The third moved to another viz group.
+    let region = |x: u32| RegionData { grid: "Agni".to_string(), name: format!("R{}", x), region_loc_x: x * 256, region_loc_y: 0,
+        region_size_x: 256, region_size_y: 256, lod: 0, children: Vec::new(), is_water: false, is_placeholder: false };
+    let works = [TileWork::ChangedTerrain, TileWork::Unchanged, TileWork::VizGroupChanged { old_viz_group: 2 }];
+    let carried: Vec<CarriedTile> = works.iter().enumerate().filter_map(|(x, work)| CarriedTile::new(&region(x as u32), work, 1)).collect();
+    assert_eq!(carried.len(), 2);
+    assert_eq!((carried[0].grid.as_str(), carried[0].region_loc_x, carried[0].old_viz_group, carried[0].viz_group), ("agni", 256, 1, 1));
+    assert_eq!((carried[1].region_loc_x, carried[1].old_viz_group, carried[1].viz_group), (512, 2, 1));
+    let height_field = HeightField::new_from_fn(4, 4, 256, 256, 20.0, |x, _| x as f32).unwrap();
+    let rebuilt = assemble_region_impostor_data(&region(0), &height_field, 1, Some("0000cafe".to_string()), None, Vec::new(), common::DEFAULT_LOD_QUALITY).unwrap();
+    //  The rebuilt tile is inserted, and the other two copied from the live impostors, in one transaction.
+    //  So promotion, which replaces the whole grid, keeps them.
+    let mut fake = FakeDb::default();
+    InitialImpostors::add_group(&mut fake, &[rebuilt], &carried).unwrap();
+    let expected: Vec<String> = ["START TRANSACTION", SQL_ADD_IMPOSTOR, SQL_CARRY_FORWARD, SQL_CARRY_FORWARD, SQL_SET_VIZ_GROUP, "COMMIT"].iter()
+        .map(|sql| sql.split_whitespace().collect::<Vec<_>>().join(" ")).collect();
+    assert_eq!(fake.sql(), expected);
+    assert!(SQL_CARRY_FORWARD.starts_with("INSERT INTO initial_impostors SELECT * FROM region_impostors"));
+    //  A failed copy loses the whole group, not just the carried tiles.
+    let mut fake = FakeDb { fail_on: Some("UPDATE initial_impostors".to_string()), ..Default::default() };
+    assert!(InitialImpostors::add_group(&mut fake, &[], &carried).is_err());
+    assert_eq!(fake.sql().last(), Some(&"ROLLBACK"));
+}
+
+#[test]
+fn test_promote_transaction() {
+    use common::db::FakeDb;
+    //  Nothing missing, so the live impostors are replaced in one transaction.
+    use common::db::{DbRow, DbValue};
+    //  Missing UUIDs, old and new assets, retired assets, then the grid's new serial.
+    let results = vec![vec![], vec![], vec![], vec![], vec![DbRow(vec![DbValue::UInt(5)])]];
+    let mut fake = FakeDb { affected_rows: 4, ..FakeDb::new_with_results(results) };
+    let report = InitialImpostors::promote(&mut fake, "Agni").unwrap();
+    assert_eq!((report.deleted, report.inserted), (4, 4));
+    let sql = fake.sql();
+    assert!(sql[0].starts_with("SELECT grid, name"));
+    assert_eq!(sql[1], "START TRANSACTION");
+    //  Old and new assets read, inside the transaction, then replaced.
+    assert!(sql[2].starts_with("SELECT sculpt_uuid, mesh_uuid, faces_json FROM region_impostors"));
+    assert!(sql[3].starts_with("SELECT sculpt_uuid, mesh_uuid, faces_json FROM initial_impostors"));
+    assert!(sql[4].starts_with("SELECT asset_uuid FROM retired_assets"));
+    //  The grid's serial bumped and given to changed tiles, changes recorded, and old ones pruned, in the same transaction.
+    assert!(sql[5].starts_with("INSERT INTO grid_generations"));
+    assert!(sql[6].starts_with("SELECT generation FROM grid_generations"));
+    assert!(sql[7].starts_with("UPDATE initial_impostors AS n"));
+    assert!(sql[8].starts_with("SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation FROM region_impostors"));
+    assert!(sql[9].starts_with("SELECT viz_group, region_loc_x, region_loc_y, impostor_lod, generation FROM initial_impostors"));
+    assert!(sql[10].starts_with("DELETE FROM impostor_changes"));
+    assert_eq!(&sql[11..], &[SQL_DELETE_LIVE, SQL_COPY_TO_LIVE, "COMMIT"]);
+    assert_eq!((report.retired, report.changes), (0, 0));
+    //  The copy fails, so the delete is rolled back.
+    let mut fake = FakeDb { fail_on: Some("INSERT INTO region_impostors".to_string()), ..Default::default() };
+    assert!(InitialImpostors::promote(&mut fake, "Agni").is_err());
+    assert_eq!(fake.sql().last(), Some(&"ROLLBACK"));
+    assert!(!fake.sql().contains(&"COMMIT"));
+}
+
+#[test]
+fn test_viz_group_changed_moves_row() {
+    use crate::neededregions::{ExistingImpostor, TileKey, WantedTile, classify_tiles};
+    use common::db::FakeDb;
+    use std::rc::Rc;
+    //  Same terrain, but the region is now reached from viz group 5, not 2.
+    let region = RegionData { grid: "Agni".to_string(), name: "Moved".to_string(), region_loc_x: 512, region_loc_y: 256,
+        region_size_x: 256, region_size_y: 256, lod: 0, children: Vec::new(), is_water: false, is_placeholder: false };
+    let live = ExistingImpostor { key: TileKey::new(&region), name: "Moved".to_string(), viz_group: 2, sculpt_hash: Some("0000cafe".to_string()),
+        terrain_hash: Some("ab".repeat(32)), texture_hashes: Vec::new(), generation: 4 };
+    let wanted = WantedTile { region: Rc::new(region.clone()), viz_group: 5, terrain_hash: Some("ab".repeat(32)) };
+    let work = classify_tiles(vec![live], &[wanted]).work(&region);
+    assert_eq!(work, TileWork::VizGroupChanged { old_viz_group: 2 });
+    assert!(!work.must_build());
+    //  Not rebuilt. The live row is copied, then moved to the new group.
+    let carried = CarriedTile::new(&region, &work, 5).expect("carried");
+    let mut fake = FakeDb::default();
+    InitialImpostors::carry_forward(&mut fake, &carried).unwrap();
+    let collapsed = |sql: &str| sql.split_whitespace().collect::<Vec<_>>().join(" ");
+    assert_eq!(fake.sql(), vec![collapsed(SQL_CARRY_FORWARD), collapsed(SQL_SET_VIZ_GROUP)]);
+    match &fake.statements[1].1 {
+        mysql::Params::Named(named) => {
+            assert_eq!(named.get("old_viz_group".as_bytes()), Some(&mysql::Value::from(2u64)));
+            assert_eq!(named.get("viz_group".as_bytes()), Some(&mysql::Value::from(5u64)));
+        }
+        _ => panic!("Expected named parameters"),
+    }
+}
@@ -0,0 +1,272 @@
+//! knownregions.rs -- regions known to exist, but not surveyed yet.
+//! Part of the Animats impostor system
+//!
+//! Raw terrain only exists for regions the bot has visited. Until it
+//! gets to one, there's a hole in the impostors. Worse, a missing region
+//! can split what should be one viz group into two.
+//!
+//! So a region list from elsewhere, such as one exported from the grid's
+//! map, goes into the known_regions table. Known regions with no raw terrain
+//! become placeholders. They join viz groups like any other region, and get
+//! flat impostors, marked so viewers can show them dimmed. Their water level
+//! isn't known, so the impostor rows have none. Once terrain for one is uploaded, it's not a placeholder
+//! any more, and gets a real impostor on the next run.
+//!
+//! Region lists are CSV, one region per line:
+//!
+//!     X,Y,SIZE,NAME
+//!
+//! X and Y are in the units given on import, meters unless told otherwise.
+//! Lists from some map exports are in region grid units. SIZE is meters,
+//! blank for 256. NAME is the rest of the line, and may contain commas.
+//! Blank lines and lines starting with # are skipped.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::{anyhow, Error};
+use common::db::Db;
+use common::{CoordUnits, GlobalMeters, RegionData, region_coords_to_meters};
+use mysql::params;
+use crate::regionpages::page_params;
+use std::path::Path;
+
+/// Region size when the list doesn't say. Second Life standard.
+const DEFAULT_REGION_SIZE: u32 = 256;
+
+/// One page of the known regions of a grid with no raw terrain. Deleted regions have raw terrain rows,
+/// so they don't come back as placeholders. See regionpages.
+const SQL_SELECT_PLACEHOLDERS: &str = r"SELECT k.grid, k.region_loc_x, k.region_loc_y, k.region_size_x, k.region_size_y, k.name
+    FROM known_regions AS k
+    WHERE k.grid = :grid AND NOT EXISTS (SELECT 1 FROM raw_terrain_heights AS r
+        WHERE r.grid = k.grid AND r.region_loc_x = k.region_loc_x AND r.region_loc_y = k.region_loc_y)
+        AND (:first OR k.region_loc_x > :after_x OR (k.region_loc_x = :after_x AND k.region_loc_y > :after_y))
+    ORDER BY k.region_loc_x, k.region_loc_y LIMIT :page_size";
+
+/// Add one known region, or update it if it's already there.
+const SQL_UPSERT_KNOWN_REGION: &str = r"INSERT INTO known_regions
+        (grid, region_loc_x, region_loc_y, region_size_x, region_size_y, name, source)
+    VALUES
+        (:grid, :region_loc_x, :region_loc_y, :region_size_x, :region_size_y, :name, :source)
+    ON DUPLICATE KEY UPDATE region_size_x = VALUES(region_size_x), region_size_y = VALUES(region_size_y),
+        name = VALUES(name), source = VALUES(source)";
+
+/// One region from a region list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownRegion {
+    /// Location, meters
+    pub region_loc: [GlobalMeters; 2],
+    /// Size, meters. Regions in lists are square.
+    pub region_size: u32,
+    /// Region name
+    pub name: String,
+}
+
+impl KnownRegion {
+    /// Parse one line of a region list, with X and Y in the given units. None for blank lines and comments.
+    fn parse_line(line: &str, units: CoordUnits) -> Result<Option<Self>, Error> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let fields: Vec<&str> = line.splitn(4, ',').map(|f| f.trim()).collect();
+        let [x, y, size, name] = fields.as_slice() else {
+            return Err(anyhow!("\"{}\" is not X,Y,SIZE,NAME", line));
+        };
+        let (x, y) = match (x.parse::<u32>(), y.parse::<u32>()) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => return Err(anyhow!("Location \"{},{}\" is not two numbers", x, y)),
+        };
+        let region_loc = region_coords_to_meters([x, y], Some(units))?;
+        let region_size = if size.is_empty() {
+            DEFAULT_REGION_SIZE
+        } else {
+            match size.parse::<u32>() {
+                Ok(n) if n > 0 => n,
+                _ => return Err(anyhow!("Size \"{}\" is not a number of meters", size)),
+            }
+        };
+        if name.is_empty() {
+            return Err(anyhow!("Region at ({}, {}) has no name", region_loc[0], region_loc[1]));
+        }
+        Ok(Some(Self { region_loc, region_size, name: name.to_string() }))
+    }
+}
+
+/// Parse a region list. Any bad line fails the whole list, with its line number.
+pub fn parse_region_list(text: &str, units: CoordUnits) -> Result<Vec<KnownRegion>, Error> {
+    let mut regions = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        match KnownRegion::parse_line(line, units) {
+            Ok(Some(region)) => regions.push(region),
+            Ok(None) => {}
+            Err(e) => return Err(anyhow!("Line {}: {}", n + 1, e)),
+        }
+    }
+    Ok(regions)
+}
+
+/// What a region list import did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownRegionsReport {
+    /// Grid
+    pub grid: String,
+    /// Region list file name
+    pub source: String,
+    /// Regions in the list
+    pub regions: usize,
+    /// Rows added or changed
+    pub changed: u64,
+}
+
+impl std::fmt::Display for KnownRegionsReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Grid \"{}\": {} known regions from \"{}\", {} added or changed.", self.grid, self.regions, self.source, self.changed)
+    }
+}
+
+/// Put a region list file into known_regions, all or none.
+pub fn import_known_regions(conn: &mut dyn Db, grid: &str, path: &Path, units: CoordUnits) -> Result<KnownRegionsReport, Error> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Unable to read region list \"{}\": {}", path.display(), e))?;
+    let regions = parse_region_list(&text, units).map_err(|e| anyhow!("Region list \"{}\": {}", path.display(), e))?;
+    let source = path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let grid = grid.to_lowercase();
+    conn.start_transaction()?;
+    let mut changed = 0;
+    for region in &regions {
+        let upsert_params = params! {
+            "grid" => &grid,
+            "region_loc_x" => region.region_loc[0].meters(),
+            "region_loc_y" => region.region_loc[1].meters(),
+            "region_size_x" => region.region_size,
+            "region_size_y" => region.region_size,
+            "name" => &region.name,
+            "source" => &source,
+        };
+        match conn.exec_drop(SQL_UPSERT_KNOWN_REGION, upsert_params) {
+            Ok(n) => changed += n,
+            Err(e) => {
+                conn.rollback()?;
+                return Err(e);
+            }
+        }
+    }
+    conn.commit()?;
+    let report = KnownRegionsReport { grid, source, regions: regions.len(), changed };
+    log::info!("{}", report);
+    Ok(report)
+}
+
+/// One page of the known regions of a grid with no raw terrain, as placeholders, in location order.
+/// GridRegions merges them with the regions with terrain.
+pub fn read_placeholders(db: &mut dyn Db, grid: &str, after: Option<(u32, u32)>, page_size: usize) -> Result<Vec<RegionData>, Error> {
+    db.exec_map(SQL_SELECT_PLACEHOLDERS, page_params(grid, after, page_size), |row| {
+        Ok(RegionData {
+            grid: row.get(0)?,
+            region_loc_x: row.get(1)?,
+            region_loc_y: row.get(2)?,
+            region_size_x: row.get(3)?,
+            region_size_y: row.get(4)?,
+            name: row.get(5)?,
+            lod: 0,
+            children: Vec::new(),
+            is_water: false,
+            is_placeholder: true,
+        })
+    })
+}
+
+#[test]
+fn test_parse_region_list() {
+    let text = "# Agni, from the map\n\
+        290304,268288,,Blake Sea - Kraken\n\
+        \n\
+        290560, 268288, 256, Smith, Jones\n\
+        256000,256000,512,Big Var\n";
+    let regions = parse_region_list(text, CoordUnits::Meters).expect("region list");
+    assert_eq!(regions.len(), 3);
+    assert_eq!(regions[0], KnownRegion { region_loc: [GlobalMeters(290304), GlobalMeters(268288)], region_size: 256, name: "Blake Sea - Kraken".to_string() });
+    assert_eq!(regions[1].name, "Smith, Jones");
+    assert_eq!(regions[2].region_size, 512);
+    //  A list in region grid units, when it's said to be.
+    let regions = parse_region_list("1134,1048,,Blake Sea - Kraken\n", CoordUnits::Regions).expect("grid units");
+    assert_eq!(regions[0].region_loc, [GlobalMeters(290304), GlobalMeters(268288)]);
+    //  Small meters are meters, not guessed to be region grid units.
+    let regions = parse_region_list("512,1024,,Near Origin\n", CoordUnits::Meters).expect("small meters");
+    assert_eq!(regions[0].region_loc, [GlobalMeters(512), GlobalMeters(1024)]);
+    //  Bad lines say where they are.
+    for (bad, line) in [("1,2\n", 1), ("# ok\n256,512,256,A\nx,2,256,B\n", 3), ("1,2,big,A\n", 1), ("1,2,0,A\n", 1), ("1,2,256,\n", 1), ("290305,268288,256,Off\n", 1), ("1134,1048,,Grid Units\n", 1)] {
+        let err = parse_region_list(bad, CoordUnits::Meters).expect_err(bad).to_string();
+        assert!(err.starts_with(&format!("Line {}:", line)), "{}: {}", bad, err);
+    }
+}
+
+#[test]
+fn test_placeholder_connects_groups() {
+    use crate::regionpages::{GridRegions, PageReader, REGION_PAGE_SIZE};
+    use crate::vizgroup::VizGroups;
+    use common::db::{DbRow, DbValue, FakeDb};
+    let row = |x: u64| DbRow(vec![DbValue::text("test"), DbValue::UInt(x * 256), DbValue::UInt(0), DbValue::UInt(256), DbValue::UInt(256), DbValue::text(&format!("R{}", x))]);
+    fn groups_of(mut fake: FakeDb, placeholders: Option<PageReader>) -> crate::vizgroup::CompletedGroups {
+        let mut regions = GridRegions::new("test", crate::read_grid_regions, placeholders, REGION_PAGE_SIZE);
+        let mut viz_groups = VizGroups::new(false);
+        while let Some(region) = regions.next(&mut fake).expect("regions") {
+            viz_groups.add_region_data(region);
+        }
+        viz_groups.end_grid()
+    }
+    //  Two surveyed regions with an unsurveyed one between them.
+    let surveyed = || vec![row(0), row(2)];
+    assert_eq!(groups_of(FakeDb::new_with_results(vec![surveyed()]), None).len(), 2);
+    //  With the placeholder, they're one group, and the placeholder is in it, marked.
+    let groups = groups_of(FakeDb::new_with_results(vec![surveyed(), vec![row(1)]]), Some(read_placeholders));
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].regions.len(), 3);
+    assert_eq!(groups[0].regions.iter().filter(|r| r.is_placeholder).map(|r| r.region_loc_x).collect::<Vec<_>>(), vec![256]);
+}
+
+#[test]
+fn test_placeholder_replaced_by_terrain() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    let row = |x: u64, name: &str| DbRow(vec![DbValue::text("test"), DbValue::UInt(x), DbValue::UInt(0), DbValue::UInt(256), DbValue::UInt(256), DbValue::text(name)]);
+    use crate::regionpages::{GridRegions, REGION_PAGE_SIZE};
+    let mut fake = FakeDb::new_with_results(vec![vec![row(256, "Middle"), row(768, "Far")]]);
+    let placeholders = read_placeholders(&mut fake, "Test", None, REGION_PAGE_SIZE).expect("placeholders");
+    assert!(SQL_SELECT_PLACEHOLDERS.contains("NOT EXISTS"));
+    //  Grids are stored lower case, and the parameter is lowercased, so the grid index is usable.
+    assert!(!SQL_SELECT_PLACEHOLDERS.contains("LOWER("));
+    match &fake.statements[0].1 {
+        mysql::Params::Named(named) => assert_eq!(named.get("grid".as_bytes()), Some(&mysql::Value::from("test"))),
+        _ => panic!("Expected named parameters"),
+    }
+    assert!(SQL_SELECT_PLACEHOLDERS.contains("ORDER BY k.region_loc_x, k.region_loc_y LIMIT :page_size"));
+    assert!(placeholders.iter().all(|p| p.is_placeholder && p.lod == 0 && p.grid == "test"));
+    //  Terrain for "Middle" arrives. It's a real region now, and "Far" is still a placeholder.
+    //  The query leaves out known regions with terrain, but one which gets terrain during a run is dropped too.
+    let mut fake = FakeDb::new_with_results(vec![vec![row(256, "Middle")], vec![row(256, "Middle"), row(768, "Far")]]);
+    let mut regions = GridRegions::new("test", crate::read_grid_regions, Some(read_placeholders), REGION_PAGE_SIZE);
+    let middle = regions.next(&mut fake).unwrap().expect("middle");
+    assert!(!middle.is_placeholder && middle.region_loc_x == 256);
+    let far = regions.next(&mut fake).unwrap().expect("far");
+    assert!(far.is_placeholder && far.region_loc_x == 768);
+    assert!(regions.next(&mut fake).unwrap().is_none());
+}
+
+#[test]
+fn test_import_known_regions() {
+    use common::db::FakeDb;
+    let path = std::env::temp_dir().join(format!("known_regions_test_{}.csv", std::process::id()));
+    std::fs::write(&path, "1000,1000,,Alpha\n1001,1000,,Beta\n").unwrap();
+    let mut fake = FakeDb::default();
+    let report = import_known_regions(&mut fake, "OSgrid", &path, CoordUnits::Regions).expect("import");
+    assert_eq!((report.grid.as_str(), report.regions), ("osgrid", 2));
+    let statements: Vec<String> = fake.sql().iter().map(|sql| sql.split(' ').take(3).collect::<Vec<_>>().join(" ")).collect();
+    assert_eq!(statements, vec!["START TRANSACTION", "INSERT INTO known_regions", "INSERT INTO known_regions", "COMMIT"]);
+    //  A bad list changes nothing.
+    std::fs::write(&path, "1000,1000,,Alpha\nnonsense\n").unwrap();
+    let mut fake = FakeDb::default();
+    assert!(import_known_regions(&mut fake, "osgrid", &path, CoordUnits::Regions).is_err());
+    assert!(fake.sql().is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
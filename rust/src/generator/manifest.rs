@@ -0,0 +1,109 @@
+//! manifest.rs -- the settings a grid was generated with.
+//! Part of the Animats impostor system
+//!
+//! Each grid's output directory gets manifest.json at the end of a run,
+//! listing the settings which affect what was generated, such as the
+//! sculpt size and the LOD distance quality. Impostors regenerated with
+//! different settings can then be traced to the run that made them.
+//! A later run replaces it.
+//!
+//! The same settings go into each impostor's provenance. See provenance.rs.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use crate::GeneratorOptions;
+
+/// Name of the manifest, in the grid's output directory.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// The generator options which affect what is generated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratorSettings {
+    /// glTF mesh, not sculpts
+    pub mesh: bool,
+    /// Sculpt image size, pixels on a side
+    pub sculpt_dim: usize,
+    /// Terrain texture size, pixels on a side
+    pub texture_size: u32,
+    /// Quality for recommended LOD distances
+    pub lod_quality: f32,
+    /// Highest LOD generated, if limited
+    pub max_lod: Option<u8>,
+    /// Smallest group generated
+    pub min_group_size: usize,
+    /// Lower LODs for groups with varregions
+    pub varregion_lods: bool,
+    /// Flat water tiles where there's no land
+    pub water_tiles: bool,
+    /// Shared texture atlases
+    pub atlas: bool,
+    /// Placeholders for known regions with no terrain
+    pub known_regions: bool,
+}
+
+impl GeneratorSettings {
+    /// Settings from the options.
+    pub fn new(options: &GeneratorOptions, texture_size: u32) -> Self {
+        Self {
+            mesh: options.generate_mesh,
+            sculpt_dim: options.sculpt_dim,
+            texture_size,
+            lod_quality: options.lod_quality,
+            max_lod: options.group_limits.max_lod,
+            min_group_size: options.group_limits.min_group_size,
+            varregion_lods: options.varregion_lods,
+            water_tiles: options.water_tiles,
+            atlas: options.atlas,
+            known_regions: options.known_regions,
+        }
+    }
+}
+
+/// Settings for one grid's run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratorManifest {
+    /// Grid name
+    pub grid: String,
+    /// When the run ended, Unix seconds
+    pub generated_at: i64,
+    /// Version of generateterrain
+    pub generator_version: String,
+    /// The settings, at the top level of the JSON
+    #[serde(flatten)]
+    pub settings: GeneratorSettings,
+}
+
+impl GeneratorManifest {
+    /// Manifest for a grid generated with these options.
+    pub fn new(grid: &str, options: &GeneratorOptions, texture_size: u32, generated_at: i64) -> Self {
+        Self {
+            grid: grid.to_string(),
+            generated_at,
+            generator_version: env!("CARGO_PKG_VERSION").to_string(),
+            settings: GeneratorSettings::new(options, texture_size),
+        }
+    }
+
+    /// As JSON, for the file.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[test]
+fn test_manifest() {
+    let options = GeneratorOptions { lod_quality: 6.5, water_tiles: true, ..Default::default() };
+    let manifest = GeneratorManifest::new("agni", &options, 256, 1_775_000_000);
+    let json = manifest.to_json().unwrap();
+    assert!(json.contains(r#""lod_quality": 6.5"#), "{}", json);
+    assert!(json.contains(r#""max_lod": null"#), "{}", json);
+    //  Reads back, so runs can be compared.
+    let read: GeneratorManifest = serde_json::from_str(&json).unwrap();
+    assert_eq!(read, manifest);
+    assert!(read.settings.water_tiles && !read.settings.atlas);
+    assert_eq!((read.settings.sculpt_dim, read.settings.texture_size, read.generated_at), (options.sculpt_dim, 256, 1_775_000_000));
+}
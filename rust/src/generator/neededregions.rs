@@ -0,0 +1,304 @@
+//! neededregions.rs -- decide which tiles actually need to be regenerated.
+//! Part of the Animats impostor system
+//!
+//! A full run over Second Life generates tens of thousands of tiles, but
+//! between runs only a few regions change. After the viz groups are computed,
+//! each tile we would generate is matched against the impostors already in
+//! the region_impostors table, by location and LOD.
+//!
+//! - No existing impostor: NewRegion. Build it.
+//! - Existing impostor, but the terrain hash differs: ChangedTerrain. Build it.
+//! - Same hash, different viz group: VizGroupChanged. The asset can be reused,
+//!   only the metadata row needs updating. The live row is carried forward into
+//!   the new viz group, by initialimpostors::CarriedTile, and promotion gives it
+//!   a new generation there, so viewers in both groups hear of the move.
+//! - Same hash, same viz group: Unchanged. Nothing to do.
+//! - Existing impostor with no tile in this run: Stale. The region is gone
+//!   from raw_terrain_heights, so the impostor should be deleted.
+//!
+//! The terrain hash is of the height field and the generation parameters, from
+//! hashing::hash_height_field, so deciding needs no sculpt or texture built.
+//! Impostors from before terrain hashes were stored have none, and are rebuilt
+//! once. Their assets come out the same, so they're reused, not uploaded again.
+//!
+//! Lower LOD tiles have no hash until they are built, because they are
+//! made from the tiles above them. So a lower LOD tile is ChangedTerrain if
+//! any LOD 0 tile within its bounds is new, changed, or stale.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use std::collections::HashMap;
+use std::rc::Rc;
+use common::RegionData;
+
+/// Location and LOD of one tile. Unique within a grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    /// Location in world of tile (meters)
+    pub region_loc_x: u32,
+    /// Location in world of tile (meters)
+    pub region_loc_y: u32,
+    /// Level of detail.
+    pub lod: u8,
+}
+
+impl TileKey {
+    /// Key for a region or tile.
+    pub fn new(region: &RegionData) -> Self {
+        Self { region_loc_x: region.region_loc_x, region_loc_y: region.region_loc_y, lod: region.lod }
+    }
+}
+
+/// An impostor already in the database, from a previous run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingImpostor {
+    /// Where
+    pub key: TileKey,
+    /// Region name
+    pub name: String,
+    /// Viz group when generated.
+    pub viz_group: usize,
+    /// Sculpt hash, 8 hex chars, if a sculpt.
+    pub sculpt_hash: Option<String>,
+    /// Terrain hash, if stored. LOD 0 only.
+    pub terrain_hash: Option<String>,
+    /// Base texture hashes, in face order.
+    pub texture_hashes: Vec<String>,
+    /// Generation, bumped each time the assets change.
+    pub generation: u32,
+}
+
+/// A tile this run wants to exist.
+#[derive(Debug, Clone)]
+pub struct WantedTile {
+    /// The tile
+    pub region: Rc<RegionData>,
+    /// Viz group for this run.
+    pub viz_group: usize,
+    /// Hash of the height field and generation parameters. Known only for LOD 0.
+    pub terrain_hash: Option<String>,
+}
+
+/// What has to be done for one tile.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TileWork {
+    /// Never generated before.
+    NewRegion,
+    /// Terrain has changed since the last run.
+    ChangedTerrain,
+    /// Asset can be reused, metadata row needs an update.
+    VizGroupChanged { old_viz_group: usize },
+    /// Nothing to do.
+    Unchanged,
+}
+
+impl TileWork {
+    /// Does this tile have to be built?
+    pub fn must_build(&self) -> bool {
+        matches!(self, TileWork::NewRegion | TileWork::ChangedTerrain)
+    }
+}
+
+/// The work list for one grid.
+#[derive(Debug, Default)]
+pub struct WorkList {
+    /// Work for each tile of this run.
+    pub tiles: HashMap<TileKey, TileWork>,
+    /// Impostors with no tile in this run, to be deleted.
+    pub stale: Vec<ExistingImpostor>,
+    /// Terrain hashes of this run's LOD 0 tiles, to be stored with them.
+    pub terrain_hashes: HashMap<TileKey, String>,
+}
+
+impl WorkList {
+    /// Work for one tile. Tiles not in the list are built.
+    pub fn work(&self, region: &RegionData) -> TileWork {
+        self.tiles.get(&TileKey::new(region)).cloned().unwrap_or(TileWork::NewRegion)
+    }
+
+    /// Terrain hash for a tile, if it has one.
+    pub fn terrain_hash(&self, region: &RegionData) -> Option<String> {
+        self.terrain_hashes.get(&TileKey::new(region)).cloned()
+    }
+
+    /// Count of tiles matching a test.
+    pub fn count(&self, test: impl Fn(&TileWork) -> bool) -> usize {
+        self.tiles.values().filter(|w| test(w)).count()
+    }
+}
+
+/// Classify all the tiles of this run against what already exists.
+pub fn classify_tiles(existing: Vec<ExistingImpostor>, wanted: &[WantedTile]) -> WorkList {
+    //  More than one existing row per key is possible, one per uniqueness viz group.
+    let mut existing_by_key: HashMap<TileKey, Vec<ExistingImpostor>> = HashMap::new();
+    for item in existing {
+        existing_by_key.entry(item.key).or_default().push(item);
+    }
+    let mut work_list = WorkList::default();
+    //  LOD 0 first, because lower LODs depend on it.
+    for tile in wanted.iter().filter(|t| t.region.lod == 0) {
+        let key = TileKey::new(&tile.region);
+        let work = match existing_by_key.get(&key) {
+            None => TileWork::NewRegion,
+            Some(olds) => {
+                if tile.terrain_hash.is_some() && olds.iter().all(|old| old.terrain_hash != tile.terrain_hash) {
+                    TileWork::ChangedTerrain
+                } else {
+                    viz_group_work(olds, tile.viz_group)
+                }
+            }
+        };
+        work_list.tiles.insert(key, work);
+        if let Some(hash) = &tile.terrain_hash {
+            work_list.terrain_hashes.insert(key, hash.clone());
+        }
+    }
+    //  Anything existing but not wanted is stale.
+    let wanted_keys: std::collections::HashSet<TileKey> = wanted.iter().map(|t| TileKey::new(&t.region)).collect();
+    for (key, olds) in &existing_by_key {
+        if !wanted_keys.contains(key) {
+            work_list.stale.extend(olds.iter().cloned());
+        }
+    }
+    //  LOD 0 tiles whose change affects lower LODs.
+    let changed_lod_0: Vec<TileKey> = work_list.tiles.iter()
+        .filter(|(_, work)| work.must_build())
+        .map(|(key, _)| *key)
+        .chain(work_list.stale.iter().filter(|s| s.key.lod == 0).map(|s| s.key))
+        .collect();
+    for tile in wanted.iter().filter(|t| t.region.lod > 0) {
+        let key = TileKey::new(&tile.region);
+        let r = &tile.region;
+        let work = match existing_by_key.get(&key) {
+            None => TileWork::NewRegion,
+            Some(olds) => {
+                let inside = |k: &TileKey| k.region_loc_x >= r.region_loc_x && k.region_loc_x < r.region_loc_x + r.region_size_x
+                    && k.region_loc_y >= r.region_loc_y && k.region_loc_y < r.region_loc_y + r.region_size_y;
+                if changed_lod_0.iter().any(inside) {
+                    TileWork::ChangedTerrain
+                } else {
+                    viz_group_work(olds, tile.viz_group)
+                }
+            }
+        };
+        work_list.tiles.insert(key, work);
+    }
+    //  Stable output order for logging.
+    work_list.stale.sort_by_key(|s| (s.key.lod, s.key.region_loc_x, s.key.region_loc_y));
+    work_list
+}
+
+/// Same terrain. Has the viz group changed?
+fn viz_group_work(olds: &[ExistingImpostor], viz_group: usize) -> TileWork {
+    if olds.iter().any(|old| old.viz_group == viz_group) {
+        TileWork::Unchanged
+    } else {
+        TileWork::VizGroupChanged { old_viz_group: olds[0].viz_group }
+    }
+}
+
+/// Generation for a tile just built.
+/// If an earlier impostor for the tile had the same assets, its generation is kept, so viewers
+/// need not fetch anything. Otherwise it's one more than any earlier generation, which only
+/// marks it as changed. Promotion replaces that with the grid's serial.
+pub fn next_generation(olds: &[ExistingImpostor], sculpt_hash: &Option<String>, texture_hashes: &[String]) -> u32 {
+    if let Some(old) = olds.iter().find(|old| old.sculpt_hash == *sculpt_hash && old.texture_hashes == texture_hashes) {
+        return old.generation;
+    }
+    olds.iter().map(|old| old.generation).max().unwrap_or(0) + 1
+}
+
+#[test]
+fn test_classify_tiles() {
+    //  Four LOD 0 regions in a square, plus the LOD 1 tile covering them.
+    fn region(x: u32, y: u32, lod: u8) -> RegionData {
+        RegionData { lod, ..common::test_region(&format!("R{}-{}", x, y), x, y, 256 << lod) }
+    }
+    fn existing(x: u32, y: u32, lod: u8, viz_group: usize, hash: &str) -> ExistingImpostor {
+        ExistingImpostor { key: TileKey { region_loc_x: x, region_loc_y: y, lod }, name: format!("R{}-{}", x, y), viz_group, sculpt_hash: None, terrain_hash: Some(hash.to_string()), texture_hashes: Vec::new(), generation: 1 }
+    }
+    fn wanted(x: u32, y: u32, lod: u8, viz_group: usize, hash: Option<&str>) -> WantedTile {
+        WantedTile { region: Rc::new(region(x, y, lod)), viz_group, terrain_hash: hash.map(|h| h.to_string()) }
+    }
+    let old = vec![
+        existing(0, 0, 0, 1, "00000001"),
+        existing(256, 0, 0, 1, "00000002"),
+        existing(0, 256, 0, 1, "00000003"),
+        existing(1024, 1024, 0, 1, "00000009"),  // region gone
+        existing(0, 0, 1, 1, "00000010"),
+    ];
+    //  Clean rerun, except for the vanished region.
+    let new = vec![
+        wanted(0, 0, 0, 1, Some("00000001")),
+        wanted(256, 0, 0, 1, Some("00000002")),
+        wanted(0, 256, 0, 1, Some("00000003")),
+        wanted(0, 0, 1, 1, None),
+    ];
+    let work_list = classify_tiles(old.clone(), &new);
+    assert!(new.iter().all(|t| work_list.work(&t.region) == TileWork::Unchanged));
+    assert_eq!(work_list.stale, vec![old[3].clone()]);
+    //  One new region, one changed region, one viz group change.
+    let new = vec![
+        wanted(0, 0, 0, 2, Some("00000001")),
+        wanted(256, 0, 0, 1, Some("000000ff")),
+        wanted(0, 256, 0, 1, Some("00000003")),
+        wanted(256, 256, 0, 1, Some("00000004")),
+        wanted(0, 0, 1, 1, None),
+        wanted(1024, 1024, 0, 1, Some("00000009")),
+    ];
+    let work_list = classify_tiles(old.clone(), &new);
+    assert_eq!(work_list.work(&region(0, 0, 0)), TileWork::VizGroupChanged { old_viz_group: 1 });
+    assert_eq!(work_list.work(&region(256, 0, 0)), TileWork::ChangedTerrain);
+    assert_eq!(work_list.work(&region(0, 256, 0)), TileWork::Unchanged);
+    assert_eq!(work_list.work(&region(256, 256, 0)), TileWork::NewRegion);
+    assert_eq!(work_list.work(&region(1024, 1024, 0)), TileWork::Unchanged);
+    //  LOD 1 tile contains changed regions, so it must be rebuilt.
+    assert_eq!(work_list.work(&region(0, 0, 1)), TileWork::ChangedTerrain);
+    assert!(work_list.stale.is_empty());
+    assert_eq!(work_list.count(TileWork::must_build), 3);
+    //  LOD 0 hashes are kept, to be stored with the tiles.
+    assert_eq!(work_list.terrain_hash(&region(256, 0, 0)).as_deref(), Some("000000ff"));
+    assert_eq!(work_list.terrain_hash(&region(0, 0, 1)), None);
+    //  An impostor from before terrain hashes were stored is rebuilt once.
+    let unhashed = vec![ExistingImpostor { terrain_hash: None, ..existing(0, 0, 0, 1, "") }];
+    let work_list = classify_tiles(unhashed, &[wanted(0, 0, 0, 1, Some("00000001"))]);
+    assert_eq!(work_list.work(&region(0, 0, 0)), TileWork::ChangedTerrain);
+}
+
+#[test]
+fn test_classify_deleted_region() {
+    //  A region deleted from raw_terrain_heights is no longer wanted, so its impostor is stale,
+    //  and the LOD 1 tile which included it must be rebuilt.
+    fn region(x: u32, y: u32, lod: u8) -> RegionData {
+        RegionData { lod, ..common::test_region(&format!("R{}-{}", x, y), x, y, 256 << lod) }
+    }
+    let existing = |x: u32, y: u32, lod: u8, hash: &str| ExistingImpostor {
+        key: TileKey { region_loc_x: x, region_loc_y: y, lod }, name: format!("R{}-{}", x, y), viz_group: 1, sculpt_hash: None, terrain_hash: Some(hash.to_string()), texture_hashes: Vec::new(), generation: 1 };
+    let wanted = |x: u32, y: u32, lod: u8, hash: Option<&str>| WantedTile { region: Rc::new(region(x, y, lod)), viz_group: 1, terrain_hash: hash.map(|h| h.to_string()) };
+    let old = vec![existing(0, 0, 0, "00000001"), existing(256, 0, 0, "00000002"), existing(0, 0, 1, "00000010")];
+    let new = vec![wanted(0, 0, 0, Some("00000001")), wanted(0, 0, 1, None)];
+    let work_list = classify_tiles(old.clone(), &new);
+    assert_eq!(work_list.stale, vec![old[1].clone()]);
+    assert_eq!(work_list.work(&region(0, 0, 0)), TileWork::Unchanged);
+    assert_eq!(work_list.work(&region(0, 0, 1)), TileWork::ChangedTerrain);
+}
+
+#[test]
+fn test_next_generation() {
+    let old = |hash: &str, texture: &str, generation: u32| ExistingImpostor {
+        key: TileKey { region_loc_x: 0, region_loc_y: 0, lod: 0 }, name: "R0-0".to_string(), viz_group: 1,
+        sculpt_hash: Some(hash.to_string()), terrain_hash: None, texture_hashes: vec![texture.to_string()], generation };
+    let hash = Some("00000001".to_string());
+    let textures = vec!["aaaa".to_string()];
+    //  First build of a tile.
+    assert_eq!(next_generation(&[], &hash, &textures), 1);
+    //  Nothing changed, so same generation.
+    assert_eq!(next_generation(&[old("00000001", "aaaa", 3)], &hash, &textures), 3);
+    //  Terrain or texture changed.
+    assert_eq!(next_generation(&[old("00000002", "aaaa", 3)], &hash, &textures), 4);
+    assert_eq!(next_generation(&[old("00000001", "bbbb", 3)], &hash, &textures), 4);
+    //  Past the highest of several old versions.
+    assert_eq!(next_generation(&[old("00000002", "aaaa", 3), old("00000003", "aaaa", 5)], &hash, &textures), 6);
+}
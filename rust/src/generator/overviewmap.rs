@@ -0,0 +1,298 @@
+//! overviewmap.rs -- one overview image of a whole grid.
+//! Part of the Animats impostor system
+//!
+//! A look at a whole grid at once shows whether the pipeline did something
+//! sensible, and the image doubles as a web asset. There is one pixel per
+//! region, or per grid cell for grids with mixed region sizes, colored by
+//! the region's mean elevation and how much of it is under water.
+//!
+//! Colors:
+//!
+//! - Land ramps from LOW_LAND_COLOR at the water level to HIGH_LAND_COLOR at
+//!   RELIEF_METERS or more above it, by the region's mean elevation.
+//! - That is blended toward WATER_COLOR by the fraction of samples below the water level.
+//! - Known regions with no terrain yet are PLACEHOLDER_COLOR.
+//! - Black is no region.
+//!
+//! North is up. overview-GRID.png comes with overview-GRID.json, which gives
+//! the transform from pixels to meters. The southwest corner of pixel (px, py) is
+//!
+//!     x = origin[0] + px * cell[0]
+//!     y = origin[1] + (height - 1 - py) * cell[1]
+//!
+//! A grid too big for that is downsampled. The cell is doubled until the image
+//! is at most MAX_OVERVIEW_DIM on a side, and a pixel covering several regions
+//! shows whichever was drawn last. The transform gives the cell actually used.
+//!
+//! Each region is summarized as its height field is read for the work list,
+//! so nothing is read twice. Only the summaries are kept, a few bytes per region,
+//! so memory stays small even for a grid of 28,000 regions. The image is
+//! allocated once, from the bounds of all the groups, at the end of the grid.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::{anyhow, Error};
+use image::{Rgb, RgbImage};
+use num::integer::gcd;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use crate::vizgroup::CompletedGroup;
+use common::{HeightField, RegionData, group_tile_size, save_png_atomic, write_bytes_atomic};
+
+/// Largest overview we will make, pixels on a side. 48 MB as RGB.
+const MAX_OVERVIEW_DIM: u32 = 4096;
+/// Mean elevation above water, meters, at which land is HIGH_LAND_COLOR.
+const RELIEF_METERS: f32 = 100.0;
+/// Land at the water level.
+const LOW_LAND_COLOR: Rgb<u8> = Rgb([60, 160, 60]);
+/// Land RELIEF_METERS above the water level.
+const HIGH_LAND_COLOR: Rgb<u8> = Rgb([220, 210, 180]);
+/// Entirely under water.
+const WATER_COLOR: Rgb<u8> = Rgb([30, 72, 98]);
+/// Known region, no terrain yet.
+const PLACEHOLDER_COLOR: Rgb<u8> = Rgb([128, 128, 128]);
+
+/// One region, as the overview needs it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RegionSummary {
+    /// Southwest corner, meters
+    loc: (u32, u32),
+    /// Size, meters
+    size: (u32, u32),
+    /// Mean elevation above the water level, meters. None for a placeholder.
+    relief: Option<f32>,
+    /// Fraction of samples below the water level.
+    water_fraction: f32,
+}
+
+impl RegionSummary {
+    /// Color, as described at the top of the file.
+    fn color(&self) -> Rgb<u8> {
+        let Some(relief) = self.relief else {
+            return PLACEHOLDER_COLOR;
+        };
+        let land = blend(LOW_LAND_COLOR, HIGH_LAND_COLOR, relief / RELIEF_METERS);
+        blend(land, WATER_COLOR, self.water_fraction)
+    }
+}
+
+/// From a toward b by t, 0 to 1.
+fn blend(a: Rgb<u8>, b: Rgb<u8>, t: f32) -> Rgb<u8> {
+    let t = t.clamp(0.0, 1.0);
+    Rgb(std::array::from_fn(|i| (a.0[i] as f32 + (b.0[i] as f32 - a.0[i] as f32) * t).round() as u8))
+}
+
+/// Pixel to meter transform of an overview, written beside it as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverviewTransform {
+    /// Grid name
+    pub grid: String,
+    /// Image width, pixels
+    pub width: u32,
+    /// Image height, pixels
+    pub height: u32,
+    /// Southwest corner of the image, meters
+    pub origin: [u32; 2],
+    /// Size of one pixel, meters
+    pub cell: [u32; 2],
+}
+
+impl OverviewTransform {
+    /// Southwest corner of a pixel, meters.
+    pub fn pixel_to_meters(&self, px: u32, py: u32) -> (u32, u32) {
+        (self.origin[0] + px * self.cell[0], self.origin[1] + (self.height - 1 - py) * self.cell[1])
+    }
+
+    /// Pixel containing a point, meters. None if off the image.
+    pub fn meters_to_pixel(&self, x: u32, y: u32) -> Option<(u32, u32)> {
+        let px = x.checked_sub(self.origin[0])? / self.cell[0];
+        let py = y.checked_sub(self.origin[1])? / self.cell[1];
+        if px < self.width && py < self.height { Some((px, self.height - 1 - py)) } else { None }
+    }
+}
+
+/// A grid's regions, collected as its groups go by, for the overview.
+#[derive(Debug, Default)]
+pub struct OverviewMap {
+    /// Southwest and northeast corners of all groups so far, meters.
+    bounds: Option<((u32, u32), (u32, u32))>,
+    /// Pixel size, meters. Divides every region's location and size. (0, 0) until a group is added.
+    cell: (u32, u32),
+    /// One per region
+    regions: Vec<RegionSummary>,
+}
+
+impl OverviewMap {
+    /// Widen the bounds to take in a group.
+    pub fn add_group(&mut self, group: &CompletedGroup) -> Result<(), Error> {
+        let ((x0, y0), (x1, y1)) = group.bounds;
+        let tile_size = group_tile_size(group.regions()).ok_or_else(|| anyhow!("Empty or zero-sized viz group"))?;
+        self.bounds = Some(match self.bounds {
+            Some(((bx0, by0), (bx1, by1))) => ((bx0.min(x0), by0.min(y0)), (bx1.max(x1), by1.max(y1))),
+            None => ((x0, y0), (x1, y1)),
+        });
+        self.cell = (gcd(self.cell.0, tile_size.0), gcd(self.cell.1, tile_size.1));
+        Ok(())
+    }
+
+    /// Summarize a region from its height field.
+    pub fn add_region(&mut self, region: &RegionData, height_field: &HeightField) {
+        let (nx, ny) = height_field.dims();
+        let under_water = (0..nx)
+            .flat_map(|x| (0..ny).map(move |y| (x, y)))
+            .filter(|&(x, y)| height_field.sample(x, y) < height_field.water_level)
+            .count();
+        self.regions.push(RegionSummary {
+            loc: (region.region_loc_x, region.region_loc_y),
+            size: (region.region_size_x, region.region_size_y),
+            relief: Some(height_field.mean() - height_field.water_level),
+            water_fraction: under_water as f32 / (nx * ny) as f32,
+        });
+    }
+
+    /// A known region with no terrain yet.
+    pub fn add_placeholder(&mut self, region: &RegionData) {
+        self.regions.push(RegionSummary {
+            loc: (region.region_loc_x, region.region_loc_y),
+            size: (region.region_size_x, region.region_size_y),
+            relief: None,
+            water_fraction: 0.0,
+        });
+    }
+
+    /// The overview image, and its transform. None if no groups were added.
+    pub fn image(&self, grid: &str) -> Result<Option<(RgbImage, OverviewTransform)>, Error> {
+        self.image_with_limit(grid, MAX_OVERVIEW_DIM)
+    }
+
+    /// The overview image, downsampled if needed to at most max_dim pixels on a side.
+    fn image_with_limit(&self, grid: &str, max_dim: u32) -> Result<Option<(RgbImage, OverviewTransform)>, Error> {
+        let Some(((x0, y0), (x1, y1))) = self.bounds else {
+            return Ok(None);
+        };
+        let (mut cell_x, mut cell_y) = self.cell;
+        let (origin, width, height) = loop {
+            let origin = [x0 / cell_x * cell_x, y0 / cell_y * cell_y];
+            let (width, height) = ((x1 - origin[0]).div_ceil(cell_x), (y1 - origin[1]).div_ceil(cell_y));
+            if width <= max_dim.max(1) && height <= max_dim.max(1) {
+                break (origin, width, height);
+            }
+            (cell_x, cell_y) = (cell_x * 2, cell_y * 2);
+        };
+        if (cell_x, cell_y) != self.cell {
+            log::info!("Overview of \"{}\" downsampled to {} x {} meters per pixel, {} x {} pixels.", grid, cell_x, cell_y, width, height);
+        }
+        let transform = OverviewTransform { grid: grid.to_string(), width, height, origin, cell: [cell_x, cell_y] };
+        let mut image = RgbImage::new(width, height);
+        for region in &self.regions {
+            let color = region.color();
+            for x in (region.loc.0..region.loc.0 + region.size.0).step_by(cell_x as usize) {
+                for y in (region.loc.1..region.loc.1 + region.size.1).step_by(cell_y as usize) {
+                    if let Some((px, py)) = transform.meters_to_pixel(x, y) {
+                        image.put_pixel(px, py, color);
+                    }
+                }
+            }
+        }
+        Ok(Some((image, transform)))
+    }
+}
+
+/// Write overview-GRID.png and overview-GRID.json. Returns the image file, or None if the grid had no groups.
+pub fn render_overview_map(overview: &OverviewMap, grid: &str, outdir: &Path) -> Result<Option<PathBuf>, Error> {
+    let Some((image, transform)) = overview.image(grid)? else {
+        return Ok(None);
+    };
+    let path = outdir.join(format!("overview-{}.png", grid));
+    save_png_atomic(&path, &image)?;
+    write_bytes_atomic(&outdir.join(format!("overview-{}.json", grid)), serde_json::to_string(&transform)?.as_bytes())?;
+    log::info!("Overview map saved: \"{}\", {} x {} pixels, {} regions", path.display(), transform.width, transform.height, overview.regions.len());
+    Ok(Some(path))
+}
+
+#[test]
+fn test_overview_colors() {
+    use common::test_region;
+    //  A 3 x 2 grid: high land, deep water, half and half, a placeholder, and nothing.
+    let high = test_region("High", 0, 0, 256);
+    let sea = test_region("Sea", 256, 0, 256);
+    let shore = test_region("Shore", 512, 0, 256);
+    let placeholder = RegionData { is_placeholder: true, ..test_region("Placeholder", 0, 256, 256) };
+    let mut overview = OverviewMap::default();
+    overview.add_group(&CompletedGroup::new(vec![high.clone(), sea.clone(), shore.clone()])).unwrap();
+    overview.add_group(&CompletedGroup::new(vec![placeholder.clone()])).unwrap();
+    let flat = |height: f32| HeightField::new_from_fn(3, 3, 256, 256, 20.0, |_, _| height).unwrap();
+    overview.add_region(&high, &flat(20.0 + RELIEF_METERS * 2.0));
+    overview.add_region(&sea, &flat(5.0));
+    //  West half 10 m under water, east half 10 m above. Mean is the water level.
+    overview.add_region(&shore, &HeightField::new_from_fn(2, 2, 256, 256, 20.0, |x, _| if x == 0 { 10.0 } else { 30.0 }).unwrap());
+    overview.add_placeholder(&placeholder);
+    let (image, transform) = overview.image("agni").unwrap().expect("overview");
+    assert_eq!((image.width(), image.height()), (3, 2));
+    let color = |region: &RegionData| {
+        let (px, py) = transform.meters_to_pixel(region.region_loc_x, region.region_loc_y).expect("on map");
+        *image.get_pixel(px, py)
+    };
+    assert_eq!(color(&high), HIGH_LAND_COLOR);
+    assert_eq!(color(&sea), WATER_COLOR);
+    assert_eq!(color(&shore), Rgb([45, 116, 79]));
+    assert_eq!(color(&placeholder), PLACEHOLDER_COLOR);
+    //  North is up, so the placeholder is in the top row.
+    assert_eq!(*image.get_pixel(0, 0), PLACEHOLDER_COLOR);
+    assert_eq!(*image.get_pixel(2, 0), Rgb([0, 0, 0]));
+    //  Nothing added, nothing drawn.
+    assert!(OverviewMap::default().image("agni").unwrap().is_none());
+}
+
+#[test]
+fn test_overview_transform() {
+    //  A varregion covers four pixels of 256 m regions. The map starts at the lowest corner.
+    let mut overview = OverviewMap::default();
+    let big = common::test_region("Big", 256000, 256000, 512);
+    let small = common::test_region("Small", 256512, 256768, 256);
+    overview.add_group(&CompletedGroup::new(vec![big.clone()])).unwrap();
+    overview.add_group(&CompletedGroup::new(vec![small.clone()])).unwrap();
+    overview.add_region(&big, &HeightField::new_from_fn(3, 3, 512, 512, 20.0, |_, _| 30.0).unwrap());
+    let (image, transform) = overview.image("osgrid").unwrap().expect("overview");
+    assert_eq!(transform, OverviewTransform { grid: "osgrid".to_string(), width: 3, height: 4, origin: [256000, 256000], cell: [256, 256] });
+    assert_eq!(serde_json::to_string(&transform).unwrap(),
+        r#"{"grid":"osgrid","width":3,"height":4,"origin":[256000,256000],"cell":[256,256]}"#);
+    //  Southwest corners of the corner pixels.
+    assert_eq!(transform.pixel_to_meters(0, 3), (256000, 256000));
+    assert_eq!(transform.pixel_to_meters(2, 0), (256512, 256768));
+    //  Round trip, and points inside a pixel land in it.
+    for (px, py) in [(0, 0), (1, 2), (2, 3)] {
+        let (x, y) = transform.pixel_to_meters(px, py);
+        assert_eq!(transform.meters_to_pixel(x, y), Some((px, py)));
+        assert_eq!(transform.meters_to_pixel(x + 255, y + 255), Some((px, py)));
+    }
+    assert_eq!(transform.meters_to_pixel(255999, 256000), None);
+    assert_eq!(transform.meters_to_pixel(256000 + 3 * 256, 256000), None);
+    //  All four pixels of the varregion are painted. The small region had no terrain summary.
+    let land = *image.get_pixel(0, 3);
+    for (px, py) in [(0, 2), (1, 2), (1, 3)] {
+        assert_eq!(*image.get_pixel(px, py), land);
+    }
+    assert_eq!(*image.get_pixel(2, 0), Rgb([0, 0, 0]));
+}
+
+#[test]
+fn test_overview_downsampled() {
+    //  A 40 x 10 row of regions, in a map at most 16 pixels on a side. Cells double to 1024 m, 10 x 3 pixels.
+    let mut overview = OverviewMap::default();
+    let regions: Vec<RegionData> = (0..40).flat_map(|x| (0..10).map(move |y| common::test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    overview.add_group(&CompletedGroup::new(regions.clone())).unwrap();
+    let flat = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |_, _| 20.0 + RELIEF_METERS * 2.0).unwrap();
+    for region in &regions {
+        overview.add_region(region, &flat);
+    }
+    let (image, transform) = overview.image_with_limit("agni", 16).unwrap().expect("overview");
+    assert_eq!((transform.width, transform.height, transform.cell), (10, 3, [1024, 1024]));
+    assert_eq!((image.width(), image.height()), (10, 3));
+    //  Every pixel with a region under it is painted, and the transform still finds them.
+    assert!(regions.iter().all(|r| transform.meters_to_pixel(r.region_loc_x, r.region_loc_y).is_some_and(|(px, py)| *image.get_pixel(px, py) == HIGH_LAND_COLOR)));
+    //  Small enough maps are left alone.
+    assert_eq!(overview.image("agni").unwrap().expect("overview").1.cell, [256, 256]);
+}
@@ -15,12 +15,210 @@
 //! On each run of generateterrain, we generate all new viz_group numbers.
 //! (Viz_group numbers are ordered by viz_group member count, so they don't change much.)z_
 //!
-//! When new viz_group numbers are assigned to old assets, ...??? ***MORE***
+//! When new viz_group numbers are assigned, each new group gets the old number
+//! of the old group it overlaps most, counting regions in common. Each old number
+//! is used at most once. Groups with no overlap, and the smaller parts of a split,
+//! get newly minted numbers above any old number. Merges and splits are reported,
+//! because assets of the groups involved may have to be re-uploaded.
 //!
 //!     License: LGPL.
 //!     Animats
 //!     February, 2025.
 //
-use anyhow::{anyhow, Error};
-use std::collections::VecDeque;
-use crate::vizgroup::{RegionData};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use common::RegionData;
+use crate::vizgroup::{CompletedGroups, group_order_key};
+
+/// Result of viz group number assignment.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VizGroupAssignment {
+    /// Viz group number for each new group, in CompletedGroups order.
+    pub ids: Vec<u32>,
+    /// Newly minted numbers. No overlap with any old group.
+    pub minted: Vec<u32>,
+    /// Merges. New number, and all the old numbers whose regions it now contains.
+    pub merges: Vec<(u32, Vec<u32>)>,
+    /// Splits. Old number, and all the new numbers its regions went to.
+    pub splits: Vec<(u32, Vec<u32>)>,
+}
+
+impl VizGroupAssignment {
+    /// New viz group numbers involved in a merge or split.
+    /// Their assets may have to be re-uploaded.
+    pub fn changed_groups(&self) -> BTreeSet<u32> {
+        self.merges.iter().map(|(new_id, _)| *new_id)
+            .chain(self.splits.iter().flat_map(|(_, new_ids)| new_ids.iter().cloned()))
+            .collect()
+    }
+}
+
+/// Regions in common with each old group. old id -> count.
+fn overlap_counts(old: &HashMap<(u32, u32), u32>, group: &[RegionData]) -> BTreeMap<u32, usize> {
+    let mut counts = BTreeMap::new();
+    for region in group {
+        if let Some(old_id) = old.get(&(region.region_loc_x, region.region_loc_y)) {
+            *counts.entry(*old_id).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// Incremental viz group numbering, for groups which arrive one at a time.
+/// Each group gets the unused old number it overlaps most. Unlike
+/// persist_viz_group_numbers, this can't wait to see all the groups, so after
+/// a split the first part to arrive keeps the old number, not the biggest.
+pub struct VizGroupNumbering {
+    /// Last run's numbers, by region location.
+    old: HashMap<(u32, u32), u32>,
+    /// Old numbers already given out.
+    used: BTreeSet<u32>,
+    /// Next number to mint.
+    next_id: u32,
+    /// Overlap counts for each group so far.
+    overlaps: Vec<BTreeMap<u32, usize>>,
+    /// Numbers assigned so far.
+    ids: Vec<u32>,
+    /// Numbers minted so far.
+    minted: Vec<u32>,
+}
+
+impl VizGroupNumbering {
+    /// Usual new. old maps region location (meters) to last run's viz group number.
+    pub fn new(old: HashMap<(u32, u32), u32>) -> Self {
+        Self {
+            next_id: old.values().max().map(|n| n + 1).unwrap_or(0),
+            old,
+            used: BTreeSet::new(),
+            overlaps: Vec::new(),
+            ids: Vec::new(),
+            minted: Vec::new(),
+        }
+    }
+
+    /// Number for the next group.
+    pub fn assign(&mut self, group: &[RegionData]) -> u32 {
+        let counts = overlap_counts(&self.old, group);
+        //  Biggest overlap, ties to the lowest number.
+        let best = counts
+            .iter()
+            .filter(|(old_id, _)| !self.used.contains(old_id))
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(old_id, _)| *old_id);
+        let id = match best {
+            Some(old_id) => {
+                self.used.insert(old_id);
+                old_id
+            }
+            None => self.mint(),
+        };
+        self.overlaps.push(counts);
+        self.ids.push(id);
+        id
+    }
+
+    /// A number never used before.
+    fn mint(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.minted.push(id);
+        id
+    }
+
+    /// Done. Report merges and splits.
+    pub fn finish(self) -> VizGroupAssignment {
+        //  Merges: new group containing regions of more than one old group.
+        let merges: Vec<(u32, Vec<u32>)> = self.overlaps
+            .iter()
+            .zip(self.ids.iter())
+            .filter(|(counts, _)| counts.len() > 1)
+            .map(|(counts, new_id)| (*new_id, counts.keys().cloned().collect()))
+            .collect();
+        //  Splits: old group whose regions went to more than one new group.
+        let mut old_to_new: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+        for (counts, new_id) in self.overlaps.iter().zip(self.ids.iter()) {
+            for old_id in counts.keys() {
+                old_to_new.entry(*old_id).or_default().insert(*new_id);
+            }
+        }
+        let splits: Vec<(u32, Vec<u32>)> = old_to_new
+            .into_iter()
+            .filter(|(_, new_ids)| new_ids.len() > 1)
+            .map(|(old_id, new_ids)| (old_id, new_ids.into_iter().collect()))
+            .collect();
+        VizGroupAssignment { ids: self.ids, minted: self.minted, merges, splits }
+    }
+}
+
+/// Assign viz group numbers, keeping last run's numbers where possible.
+/// old maps region location (meters) to last run's viz group number.
+/// All the groups are known, so the biggest overlaps are matched first.
+#[allow(dead_code)] // batch form. The generator gets groups one at a time and uses VizGroupNumbering.
+pub fn persist_viz_group_numbers(old: &HashMap<(u32, u32), u32>, new: &CompletedGroups) -> VizGroupAssignment {
+    let mut numbering = VizGroupNumbering::new(old.clone());
+    numbering.overlaps = new.iter().map(|group| overlap_counts(old, group.regions())).collect();
+    //  Greedy matching, biggest overlap first. Ties go to the new group first in canonical order,
+    //  then the lower old id, so the result doesn't depend on the order the groups are in.
+    let keys: Vec<_> = new.iter().map(|group| group_order_key(group.regions())).collect();
+    let mut candidates: Vec<(usize, usize, u32)> = numbering.overlaps
+        .iter()
+        .enumerate()
+        .flat_map(|(ix, counts)| counts.iter().map(move |(old_id, count)| (*count, ix, *old_id)))
+        .collect();
+    candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| keys[a.1].cmp(&keys[b.1])).then(a.2.cmp(&b.2)));
+    let mut ids: Vec<Option<u32>> = vec![None; new.len()];
+    for (_, ix, old_id) in candidates {
+        if ids[ix].is_none() && !numbering.used.contains(&old_id) {
+            ids[ix] = Some(old_id);
+            numbering.used.insert(old_id);
+        }
+    }
+    //  Mint new numbers above anything old.
+    let ids: Vec<u32> = ids.into_iter().map(|id_opt| id_opt.unwrap_or_else(|| numbering.mint())).collect();
+    numbering.ids = ids;
+    numbering.finish()
+}
+
+#[test]
+fn test_persist_viz_group_numbers() {
+    fn group(locs: &[(u32, u32)]) -> crate::vizgroup::CompletedGroup {
+        crate::vizgroup::CompletedGroup::new(locs.iter().map(|&(x, y)| common::test_region(&format!("R{}-{}", x, y), x, y, 256)).collect())
+    }
+    let a = [(0, 0), (256, 0), (512, 0)];
+    let b = [(0, 1024), (256, 1024)];
+    let c = [(2048, 2048)];
+    let old: HashMap<(u32, u32), u32> = a.iter().map(|loc| (*loc, 7)).chain(b.iter().map(|loc| (*loc, 3))).collect();
+    //  Clean rerun, order changed. Same ids.
+    let result = persist_viz_group_numbers(&old, &vec![group(&b), group(&a)]);
+    assert_eq!(result.ids, vec![3, 7]);
+    assert!(result.minted.is_empty() && result.merges.is_empty() && result.splits.is_empty());
+    //  Two-way merge. Bigger old group's id wins.
+    let merged: Vec<(u32, u32)> = a.iter().chain(b.iter()).cloned().collect();
+    let result = persist_viz_group_numbers(&old, &vec![group(&merged)]);
+    assert_eq!(result.ids, vec![7]);
+    assert_eq!(result.merges, vec![(7, vec![3, 7])]);
+    assert!(result.splits.is_empty());
+    assert_eq!(result.changed_groups().into_iter().collect::<Vec<_>>(), vec![7]);
+    //  Split. Bigger part keeps the id, smaller part gets a new one.
+    let result = persist_viz_group_numbers(&old, &vec![group(&a[0..2]), group(&a[2..3]), group(&b)]);
+    assert_eq!(result.ids, vec![7, 8, 3]);
+    assert_eq!(result.minted, vec![8]);
+    assert_eq!(result.splits, vec![(7, vec![7, 8])]);
+    //  Brand-new island.
+    let result = persist_viz_group_numbers(&old, &vec![group(&a), group(&b), group(&c)]);
+    assert_eq!(result.ids, vec![7, 3, 8]);
+    assert_eq!(result.minted, vec![8]);
+    assert!(result.merges.is_empty() && result.splits.is_empty());
+    //  Even split. The part first in canonical order keeps the number, whatever order the parts come in.
+    let halves = [(0, 0), (256, 0), (1024, 0), (1280, 0)];
+    let even_old: HashMap<(u32, u32), u32> = halves.iter().map(|loc| (*loc, 5)).collect();
+    let result = persist_viz_group_numbers(&even_old, &vec![group(&halves[2..4]), group(&halves[0..2])]);
+    assert_eq!(result.ids, vec![6, 5]);
+    let result = persist_viz_group_numbers(&even_old, &vec![group(&halves[0..2]), group(&halves[2..4])]);
+    assert_eq!(result.ids, vec![5, 6]);
+    //  Incremental split. First part to arrive keeps the old number.
+    let mut numbering = VizGroupNumbering::new(old);
+    assert_eq!(numbering.assign(group(&a[2..3]).regions()), 7);
+    assert_eq!(numbering.assign(group(&a[0..2]).regions()), 8);
+    assert_eq!(numbering.assign(group(&b).regions()), 3);
+    assert_eq!(numbering.finish().splits, vec![(7, vec![7, 8])]);
+}
@@ -0,0 +1,101 @@
+//! provenance.rs -- where each impostor came from.
+//! Part of the Animats impostor system
+//!
+//! When an impostor looks wrong in-world, the operator needs to know
+//! which generator, with which settings, built it from which terrain.
+//! So each impostor row gets provenance_json, written by the generator:
+//!
+//!     {"generator_version":"0.1.0","git_hash":"3f2a9c1","built_at":1775000000,
+//!      "settings":{"mesh":false,"sculpt_dim":64,...},
+//!      "terrain":{"last_uploaded":1774900000,"elevs_hash":"9b1d..."}}
+//!
+//! The git hash is there if MAPTOOLS_GIT_HASH was set when the generator
+//! was built. Terrain is the raw terrain row of a LOD 0 tile, hashed as it
+//! was read to build the tile, so it can't be from a later upload. Lower LOD
+//! tiles are built from many rows, and have none. Their LOD 0 tiles say
+//! which rows were used.
+//!
+//! Provenance is for operators. It is never in viewer replies. It's in the
+//! CSV export, and the signed debug download.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::Error;
+use common::hashing::hash_bytes;
+use serde::{Deserialize, Serialize};
+use crate::manifest::GeneratorSettings;
+
+/// Source revision of the generator, if the build supplied one.
+const GIT_HASH: Option<&str> = option_env!("MAPTOOLS_GIT_HASH");
+
+/// A raw terrain row, as an impostor was built from it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TerrainSource {
+    /// When the terrain was last uploaded, Unix seconds
+    pub last_uploaded: i64,
+    /// SHA-256 of the elevations, as stored
+    pub elevs_hash: String,
+}
+
+/// Where one impostor came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpostorProvenance {
+    /// Version of generateterrain
+    pub generator_version: String,
+    /// Source revision, if known
+    pub git_hash: Option<String>,
+    /// When the impostor was built, Unix seconds
+    pub built_at: i64,
+    /// Generator settings
+    pub settings: GeneratorSettings,
+    /// Raw terrain, for LOD 0 tiles with terrain
+    pub terrain: Option<TerrainSource>,
+}
+
+impl TerrainSource {
+    /// From a raw terrain row's upload time and elevations.
+    pub fn new(last_uploaded: i64, elevs: &[u8]) -> Self {
+        Self { last_uploaded, elevs_hash: hash_bytes(elevs) }
+    }
+}
+
+impl ImpostorProvenance {
+    /// Provenance for an impostor built now by this generator.
+    pub fn new(settings: GeneratorSettings, terrain: Option<TerrainSource>, built_at: i64) -> Self {
+        Self {
+            generator_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: GIT_HASH.map(|h| h.trim().to_string()).filter(|h| !h.is_empty()),
+            built_at,
+            settings,
+            terrain,
+        }
+    }
+
+    /// As JSON, for the provenance_json column.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+#[test]
+fn test_provenance_json() {
+    let options = crate::GeneratorOptions { lod_quality: 6.5, atlas: true, ..Default::default() };
+    let settings = GeneratorSettings::new(&options, 256);
+    //  LOD 0, from one raw terrain row.
+    let terrain = Some(TerrainSource::new(1_774_900_000, b"abc"));
+    let provenance = ImpostorProvenance::new(settings.clone(), terrain, 1_775_000_000);
+    let json = provenance.to_json().unwrap();
+    assert!(json.starts_with(&format!(r#"{{"generator_version":"{}","git_hash":"#, env!("CARGO_PKG_VERSION"))), "{}", json);
+    assert!(json.contains(r#""built_at":1775000000"#), "{}", json);
+    assert!(json.contains(r#""lod_quality":6.5"#) && json.contains(r#""atlas":true"#), "{}", json);
+    assert!(json.contains(&format!(r#""terrain":{{"last_uploaded":1774900000,"elevs_hash":"{}"}}"#, hash_bytes(b"abc"))), "{}", json);
+    //  Reads back.
+    let read: ImpostorProvenance = serde_json::from_str(&json).unwrap();
+    assert_eq!(read, provenance);
+    assert_eq!(read.git_hash.as_deref(), GIT_HASH.map(|h| h.trim()).filter(|h| !h.is_empty()));
+    //  Lower LODs have no terrain.
+    let json = ImpostorProvenance::new(settings, None, 1_775_000_000).to_json().unwrap();
+    assert!(json.contains(r#""terrain":null"#), "{}", json);
+}
@@ -21,47 +21,145 @@
 //!     December, 2025.
 //
 use anyhow::{anyhow, Error};
-use std::collections::VecDeque;
-use crate::vizgroup::{RegionData};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+use common::{GlobalMeters, RegionData, group_bounds, group_tile_size};
 
 /// Maximum LOD. It never gets this big, because there would have to be a viz group 2^LOD across for that to happen.
 const MAX_LOD: u8 = 16;
 
+/// Problems with the regions given to TileLods.
+/// These are not fatal. The offending region is skipped and reported,
+/// and the rest of the group is processed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionOrderError {
+    /// Same location as a region already seen.
+    Duplicate(RegionData),
+    /// Overlaps a region already seen, such as a misconfigured varregion.
+    Overlap(RegionData),
+    /// Outside the bounds of the group.
+    OutOfBounds(RegionData),
+}
+
+impl std::fmt::Display for RegionOrderError {
+    /// Usual display
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegionOrderError::Duplicate(region) => write!(f, "Duplicate region {}, skipped", region),
+            RegionOrderError::Overlap(region) => write!(f, "Region {} overlaps another region, skipped", region),
+            RegionOrderError::OutOfBounds(region) => write!(f, "Region {} is outside the group bounds, skipped", region),
+        }
+    }
+}
+
+impl std::error::Error for RegionOrderError {}
+
+/// Limits on which viz groups get impostors, and to what LOD.
+/// Tiny island groups far out in the ocean can cost more to upload than they are worth.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupLimits {
+    /// Groups with fewer regions than this are skipped.
+    pub min_group_size: usize,
+    /// No tiles beyond this LOD, even if no tile at this LOD covers the whole group.
+    pub max_lod: Option<u8>,
+}
+
+impl Default for GroupLimits {
+    /// No limits.
+    fn default() -> Self {
+        Self { min_group_size: 1, max_lod: None }
+    }
+}
+
+impl GroupLimits {
+    /// Is this group too small to generate?
+    pub fn skip_group(&self, regions: usize) -> bool {
+        regions < self.min_group_size
+    }
+
+    /// Lower LOD tiles allowed at all?
+    pub fn lower_lods(&self) -> bool {
+        self.max_lod != Some(0)
+    }
+}
+
+/// Check that LOD 0 regions are in strictly increasing X, Y order.
+/// This module assumes everything is in strictly increasing sequence.
+/// Regions are sorted on the way in, so only a duplicate location can fail.
+fn check_loc_sequence(prev: (u32, u32), region: &RegionData) -> Result<(), RegionOrderError> {
+    let loc = (region.region_loc_x, region.region_loc_y);
+    debug_assert!(prev <= loc, "Region {} not sorted", region);
+    if prev == loc {
+        Err(RegionOrderError::Duplicate(region.clone()))
+    } else {
+        Ok(())
+    }
+}
+
 /// All the column cursors for all the LODs.
 ///
 /// The goal here is to return all the regions that
 /// need to be impostored in the order that will allow
 /// the lower LOD impostors to be constructed from recently
 /// constructes higher LOD impostors.
-
+///
+/// Regions are returned as Rc<RegionData>, so the LOD 0 regions
+/// come out without being copied.
 pub struct TileLods {
     /// Cursors for each LOD
     cursors: Vec<ColumnCursor>,
     /// The regions in
-    regions: VecDeque<RegionData>,
+    regions: VecDeque<Rc<RegionData>>,
     /// Available results
-    regions_to_output: VecDeque<RegionData>,
+    regions_to_output: VecDeque<Rc<RegionData>>,
+    /// Location of the last LOD 0 region accepted.
+    prev_loc: Option<(u32, u32)>,
+    /// Regions skipped, and why.
+    errors: Vec<RegionOrderError>,
+    /// End of input has been handled. Only queued tiles are left.
+    finished: bool,
 }
 
 impl TileLods {
     /// The cursors for the levels of detail of regions.
-    pub fn new(mut regions: Vec<RegionData>) -> Self {
-        let bounds = get_group_bounds(&regions).expect("Invalid group bounds");
-        log::debug!("Group bounds: {:?}", bounds);
-        assert!(!regions.is_empty()); // This is checked in get_group_bounds
+    /// Regions may be of different sizes, as with Open Simulator varregions.
+    /// Everything is then tiled on the largest size which divides all region sizes and locations.
+    /// No water tiles, no LOD limit. The generator always passes its options.
+    #[cfg(test)]
+    pub fn new<R: Into<Rc<RegionData>>>(regions: Vec<R>) -> Self {
+        Self::new_with_max_lod(regions, false, None)
+    }
+
+    /// As above, with the option of emitting all-water lower LOD tiles, marked is_water,
+    /// instead of skipping them. Those fill in oceans and lakes inside the group's bounds.
+    /// With max_lod, there are no tiles beyond that LOD, even if no tile at that LOD covers the whole group.
+    /// There is always at least LOD 1. Callers wanting only LOD 0 don't need this.
+    pub fn new_with_max_lod<R: Into<Rc<RegionData>>>(regions: Vec<R>, emit_water: bool, max_lod: Option<u8>) -> Self {
+        let regions: Vec<Rc<RegionData>> = regions.into_iter().map(Into::into).collect();
+        let bounds = group_bounds(&regions).expect("Empty viz group");
+        Self::new_with_bounds(regions, bounds, emit_water, max_lod)
+    }
+
+    /// As above, for a group whose bounds are already known, as for a CompletedGroup.
+    pub fn new_with_bounds<R: Into<Rc<RegionData>>>(regions: Vec<R>, bounds: ((u32, u32), (u32, u32)), emit_water: bool, max_lod: Option<u8>) -> Self {
+        let mut regions: Vec<Rc<RegionData>> = regions.into_iter().map(Into::into).collect();
+        let base_region_size = group_tile_size(&regions).expect("Empty or zero-sized viz group");
+        log::debug!("Group bounds: {:?}, tile size {:?}", bounds, base_region_size);
+        assert!(!regions.is_empty()); // This is checked in group_tile_size
         //  Sort by X, Y. The input is usually almost in order, but not quite.
-        regions.sort_by_key(|v: &RegionData| (v.region_loc_x, v.region_loc_y));
+        regions.sort_by_key(|v| (v.region_loc_x, v.region_loc_y));
         //  Immutable after this point
         let regions = regions;
-        let base_region_size = (regions[0].region_size_x, regions[0].region_size_y);
-        let (max_lod, ll,ur) = get_group_scan_bounds(bounds, base_region_size).expect("Group scan bounds calc failed");
+        let (full_coverage_lod, ll,ur) = get_group_scan_bounds(bounds, base_region_size).expect("Group scan bounds calc failed");
+        let max_lod = max_lod.map_or(full_coverage_lod, |cap| full_coverage_lod.min(cap.max(1)));
         //  ***CHECK FOR AT LEAST 2X2***
         //  ***MUST HAVE AS MANY COLUMNS AS ROWS*** add columns if necessary
         let grid = &regions[0].grid;
         //  Generate LODs unti one LOD covers the entire bounds.
         let mut cursors = Vec::new();
         for lod in 0..(max_lod+1) {
-            let new_cursor = ColumnCursor::new((ll, ur), base_region_size, lod, grid.clone());
+            let new_cursor = ColumnCursor::new((ll, ur), base_region_size, lod, grid.clone(), emit_water);
             let done = new_cursor.recent_column_info.is_full_coverage();
             cursors.push(new_cursor);
             if done {
@@ -75,8 +173,33 @@ impl TileLods {
             regions: regions.into(),
             cursors,
             regions_to_output: VecDeque::new(),
+            prev_loc: None,
+            errors: Vec::new(),
+            finished: false,
         }
     }
+
+    /// Regions skipped so far, and why. Call after iteration is done.
+    pub fn take_errors(&mut self) -> Vec<RegionOrderError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Mark a LOD 0 region, shifting columns as needed.
+    /// Lower LOD tiles finished by the shift are queued for output.
+    fn add_lod_0(&mut self, region: &RegionData) -> Result<(), RegionOrderError> {
+        if let Some(prev_loc) = self.prev_loc {
+            check_loc_sequence(prev_loc, region)?;
+        }
+        let loc = (region.region_loc_x, region.region_loc_y);
+        //  Column has changed. Finish up columns until aligned.
+        while loc.0 > self.cursors[0].recent_column_info.start.0 {
+            self.scan_and_shift();
+        }
+        assert_eq!(loc.0, self.cursors[0].recent_column_info.start.0);
+        self.cursors[0].mark_lod_0(region)?;
+        self.prev_loc = Some(loc);
+        Ok(())
+    }
     
     /// Scan for newly finished blocks. Then shift down by one column of LOD 0.
     fn scan_and_shift(&mut self) {
@@ -115,10 +238,41 @@ impl TileLods {
             }
         }
     }
+    
+    /// End of input. The current LOD 0 column can still have Unknown cells past the last region,
+    /// and lower LOD tiles over it and the columns after it are not done yet.
+    /// Fill out with water, scanning and shifting until the lowest LOD has shifted past the bounds.
+    /// Then anything still Unknown, at any LOD, is water. Runs once.
+    fn finish(&mut self) {
+        let lowest = self.cursors.len() - 1;
+        //  Runout can't take more steps than there are LOD 0 columns.
+        let max_runout = self.cursors[0].recent_column_info.x_steps();
+        log::debug!("Runout start: lowest LOD is LOD {}", lowest);
+        self.scan_and_shift();
+        //  The lowest LOD is one column wide, so it is done when it shifts off the upper bound.
+        //  Going further would scan columns outside the bounds.
+        let mut runout = 0;
+        while self.cursors[lowest].recent_column_info.start.0 < self.cursors[lowest].recent_column_info.lod_bounds.1.0 {
+            log::debug!("Runout at EOF: at {:?}", self.cursors[0].recent_column_info.start);
+            self.scan_and_shift();
+            runout += 1;
+            assert!(runout <= max_runout, "EOF runout went past {} columns", max_runout);
+        }
+        for cursor in &mut self.cursors {
+            cursor.column_finished();
+        }
+        self.finished = true;
+        log::debug!("Runout done");
+    }
+
+    /// Number of lower LOD tiles skipped so far because all four cells beneath them were water.
+    pub fn water_tiles_skipped(&self) -> usize {
+        self.cursors.iter().map(|c| c.water_tiles).sum()
+    }
 }
 
 impl Iterator for TileLods {
-    type Item = RegionData;
+    type Item = Rc<RegionData>;
     /// Next, new version
     /// This is an iterator, which turns the loops inside out and means we have
     /// to maintain too much state.
@@ -129,55 +283,31 @@ impl Iterator for TileLods {
             return region
         }
         //  No region was queued to be returned.
-        //  So get a new input region.
-        if let Some(region) = self.regions.pop_front() {
+        //  So get a new input region. Regions with errors are skipped.
+        while let Some(region) = self.regions.pop_front() {
             //  We have a new region to handle.
-            //  Mark it in the current row.
-            let loc = (region.region_loc_x, region.region_loc_y);
-            if loc.0 == self.cursors[0].recent_column_info.start.0 {
-                //  Column has not changed.
-                self.cursors[0].mark_lod_0(loc);
-                assert!(self.regions_to_output.is_empty());
-                return Some(region);
-            } else {
-                //  Queue this region for output
-                self.regions_to_output.push_back(region.clone());
-                //  Column has changed.
-                assert!(loc.0 > self.cursors[0].recent_column_info.start.0);
-                while loc.0 > self.cursors[0].recent_column_info.start.0 {
-                    self.scan_and_shift();
+            //  Mark it in the current row. This may finish lower LOD tiles,
+            //  which come out after this region.
+            match self.add_lod_0(&region) {
+                Ok(()) => {
+                    self.regions_to_output.push_front(region);
+                    return self.regions_to_output.pop_front();
+                }
+                Err(err) => {
+                    log::warn!("{}", err);
+                    self.errors.push(err);
+                    if let Some(tile) = self.regions_to_output.pop_front() {
+                        return Some(tile);
+                    }
                 }
-                //  Aligned now, push new item.
-                assert_eq!(loc.0, self.cursors[0].recent_column_info.start.0);
-                self.cursors[0].mark_lod_0(loc);
-            }
-            //  There must always be a region queued at this point, because we pushed one at start.
-            let opt_region = self.regions_to_output.pop_front();
-            assert!(opt_region.is_some());
-            opt_region
-        } else {
-            //  End of input.
-            //  Lower LODs must be flushed.
-            //  Mark entire column as water, then call scan and shift, until lowest LOD is completed.
-            //  Done when the lowest LOD is completed.
-            //  ***EOF TEST CAN RUN AWAY***
-            let mut runaway: usize = 0; // ***TEMP***
-            log::debug!("Runout start: lowest LOD is LOD {}", self.cursors.len()-1); 
-            //  ***TERMINATION CONDITION MAY BE TOTALLY BOGUS TESTING AGAINST ROW 1***    
-            self.scan_and_shift();      
-            //  ***PROBABLY SHOULD BE STRICTLY LESS FOR LOOP TERMINATION TEST***
-            while self.cursors[self.cursors.len()-1].recent_column_info.start.0 <= self.cursors[self.cursors.len()-1].recent_column_info.lod_bounds.1.0 {
-                log::debug!("Runout at EOF: at {:?}", self.cursors[0].recent_column_info.start);
-                log::debug!("Runout: next y index: {} for length {}", self.cursors[0].next_y_index, self.cursors[0].recent_column_info.region_type_info[0].len());
-                log::debug!("Runout: Col finished LOD 0: {:?}", self.cursors[0].recent_column_info.region_type_info[0]);  // ***TEMP***
-                //  This fills all with water.
-                self.scan_and_shift();
-                if runaway > 100 { panic!("EOF runaway"); } else { runaway += 1; } // ***TEMP***
             }
-            log::debug!("Runout done"); 
-            //  Return a region, or None if we're all done.
-            self.regions_to_output. pop_front()
         }
+        //  End of input. Lower LODs must be flushed, once.
+        if !self.finished {
+            self.finish();
+        }
+        //  Return a region, or None if we're all done.
+        self.regions_to_output.pop_front()
     }
 }
 
@@ -192,6 +322,32 @@ enum RecentRegionType {
     Land,
 }
 
+/// What lower LODs need to know about a tile which was generated.
+#[derive(Debug, Clone)]
+struct TileInfo {
+    /// Name of the largest LOD 0 region under this tile.
+    dominant_name: String,
+    /// Area of all the LOD 0 regions under this tile. Square meters.
+    area: u64,
+}
+
+impl TileInfo {
+    /// Info for a tile built from these children.
+    /// The dominant child is the one with the most area, the first one on ties.
+    fn combine(children: &[((u32, u32), &TileInfo)]) -> Option<TileInfo> {
+        let mut dominant: Option<&TileInfo> = None;
+        for (_, child) in children {
+            if dominant.is_none_or(|d| child.area > d.area) {
+                dominant = Some(child);
+            }
+        }
+        Some(TileInfo {
+            dominant_name: dominant?.dominant_name.clone(),
+            area: children.iter().map(|(_, child)| child.area).sum(),
+        })
+    }
+}
+
 /// The most recent two columns.
 /// This is how we decide which lower LODs get impostered,
 /// and when the info for them is emitted.
@@ -205,6 +361,9 @@ pub struct RecentColumnInfo {
     lod_bounds: ((u32, u32), (u32, u32)),
     /// Region type info
     region_type_info: [Vec<RecentRegionType>; 2],
+    /// Info for tiles actually generated, parallel to region_type_info.
+    /// Land cells covered by a bigger region, but not its origin, have none.
+    tile_info: [Vec<Option<TileInfo>>; 2],
     /// True if this LOD needs only one tile to cover the entire area
     full_coverage: bool,
 }
@@ -222,28 +381,38 @@ impl RecentColumnInfo {
             base_region_size.0 * scale,
             base_region_size.1 * scale,
         );
+        //  A column runs north-south, so it is indexed by Y.
+        let x_steps = (ur.0 - ll.0) / tile_size.0;
         let y_steps = (ur.1 - ll.1) / tile_size.1;
         //  The off the edge row, row 1, starts as all water.
         let region_type_info = [
             vec![RecentRegionType::Unknown; y_steps as usize],
             vec![RecentRegionType::Water; y_steps as usize],
         ];        
+        let tile_info = [vec![None; y_steps as usize], vec![None; y_steps as usize]];
         let lod_bounds = (ll, ur);
-        let full_coverage = y_steps == 1;
-        log::debug!("LOD {}, bounds {:?}, {} y_steps, full coverage: {}", lod, bounds, y_steps, full_coverage);
+        let full_coverage = x_steps == 1 && y_steps == 1;
+        log::debug!("LOD {}, bounds {:?}, {} x_steps, {} y_steps, full coverage: {}", lod, bounds, x_steps, y_steps, full_coverage);
 
         Self {
             size: tile_size,	
             region_type_info,
+            tile_info,
             full_coverage,
             lod_bounds,
             start: lod_bounds.0,
         }
     }
     
-    /// Calculate array index for a Y value.
+    /// Number of columns at this LOD.
+    fn x_steps(&self) -> usize {
+        ((self.lod_bounds.1.0 - self.lod_bounds.0.0) / self.size.0) as usize
+    }
+
+    /// Calculate array index for a Y value. Meters, not region grid units.
     /// Non-fatal bounds check
-    pub fn try_calc_y_index(&self, y: u32) -> Option<usize> {
+    fn try_calc_y_index(&self, y: GlobalMeters) -> Option<usize> {
+        let y = y.meters();
         let ll_y = self.lod_bounds.0.1;
         if y >= ll_y {
             let yix = ((y - ll_y) / self.size.1) as usize;
@@ -257,16 +426,6 @@ impl RecentColumnInfo {
         }
     }
     
-    /// As above, but panic on bounds check
-    pub fn calc_y_index(&self, y: u32) -> usize {
-        if let Some(yix) = self.try_calc_y_index(y) {
-            yix 
-        } else {
-            panic!("RegionOrder::RecentColumnInfo bounds check fail: y: {}, size: {:?}, start: {:?}, len: {}",
-                y, self.size, self.lod_bounds, self.region_type_info[0].len());
-        }
-    }
-
     /// Shift recent column info from current to previous column.
     /// Current column is 0, previous column is 1.
     fn shift_inner(&mut self) {
@@ -275,6 +434,7 @@ impl RecentColumnInfo {
         assert!(self.region_type_info[0].iter().find(|&&v| v == RecentRegionType::Unknown).is_none());
         self.region_type_info[1] = self.region_type_info[0].clone();
         self.region_type_info[0] = vec![RecentRegionType::Unknown; self.region_type_info[0].len()];
+        self.tile_info[1] = std::mem::replace(&mut self.tile_info[0], vec![None; self.region_type_info[0].len()]);
         //  Advance position. Position is of the current column, not the previous one.
         self.start.0 += self.size.0;
         log::debug!("Column shift. Next start: {:?}", self.start);
@@ -285,32 +445,53 @@ impl RecentColumnInfo {
         self.full_coverage
     }   
 
-    /// Test one cell for status
-    fn test_cell(&self, loc: (u32, u32)) -> RecentRegionType {
+    /// Column and Y index of a cell, if it is in one of the two recent columns.
+    fn cell_index(&self, loc: (u32, u32)) -> Option<(usize, usize)> {
         let (x, y) = loc;
         //  Check that X is within bounds.
         //  Low limit is previous column.
         //  High limit is current column.
-        let column = if x == self.start.0 {
-            &self.region_type_info[0]
+        let col = if x == self.start.0 {
+            0
         } else if x + self.size.0 == self.start.0 {
-            &self.region_type_info[1]
+            1
         } else {
             log::trace!("Tested cell of invalid column: x: {}, column 0: {}, column 1: {}", x, self.start.0, self.start.0 as i32 - self.size.0 as i32);
-            return RecentRegionType::Water;
+            return None;
         };
-        //  Return element.
         assert_eq!(y % self.size.1, 0);
-        //  If out of range, treat as water.
-        let result = if let Some(v) = column.get(((y - self.start.1) / self.size.1) as usize) {
-            *v
+        let yix = ((y - self.start.1) / self.size.1) as usize;
+        if yix < self.region_type_info[col].len() {
+            Some((col, yix))
         } else {
-            RecentRegionType::Water
+            None
+        }
+    }
+
+    /// Test one cell for status
+    fn test_cell(&self, loc: (u32, u32)) -> RecentRegionType {
+        //  If out of range, treat as water.
+        let result = match self.cell_index(loc) {
+            Some((col, yix)) => self.region_type_info[col][yix],
+            None => RecentRegionType::Water,
         };
-        log::trace!("Test cell: loc {:?}, yix: {}, result: {:?}", loc, y/self.size.1, result);
+        log::trace!("Test cell: loc {:?}, yix: {}, result: {:?}", loc, loc.1/self.size.1, result);
         result
     }
 
+    /// The generated tiles in a 4-cell quadrant, with their locations.
+    /// These are the children of the next lowest LOD tile at loc.
+    fn four_cell_tiles(&self, loc: (u32, u32)) -> Vec<((u32, u32), &TileInfo)> {
+        let (x, y) = loc;
+        [(x, y), (x + self.size.0, y), (x, y + self.size.1), (x + self.size.0, y + self.size.1)]
+            .into_iter()
+            .filter_map(|cell| {
+                let (col, yix) = self.cell_index(cell)?;
+                self.tile_info[col][yix].as_ref().map(|info| (cell, info))
+            })
+            .collect()
+    }
+
     /// Test a 4-cell quadrant for status.
     /// This is used by the next lowest LOD to decide what to do.
     fn test_four_cells(&self, loc: (u32, u32)) -> RecentRegionType {
@@ -352,6 +533,13 @@ struct ColumnCursor {
     lod: u8,
     /// Grid, for output
     grid: String,
+    /// Count of all-water tiles not generated at this LOD.
+    water_tiles: usize,
+    /// Generate all-water tiles instead of skipping them.
+    emit_water: bool,
+    /// Land cells in columns not reached yet, from regions wider than one tile.
+    /// Column X (meters) -> Y indices. LOD 0 only.
+    pending_land: BTreeMap<u32, Vec<usize>>,
 }
 
 impl ColumnCursor {
@@ -361,6 +549,7 @@ impl ColumnCursor {
         base_region_size: (u32, u32),
         lod: u8,
         grid: String,
+        emit_water: bool,
     ) -> ColumnCursor {
         //  Calculate tile size at this LOD.
         let recent_column_info = RecentColumnInfo::new(bounds, base_region_size, lod);
@@ -369,6 +558,9 @@ impl ColumnCursor {
             next_y_index: 0,
             lod,
             grid,
+            water_tiles: 0,
+            emit_water,
+            pending_land: BTreeMap::new(),
         }
     }
 
@@ -392,12 +584,21 @@ impl ColumnCursor {
         log::debug!("Shift LOD {} column finished: {:?}", self.lod, self.recent_column_info.region_type_info[0]); // ***TEMP***
         self.recent_column_info.shift_inner();
         self.next_y_index = 0;
+        //  Land from wide regions to the left.
+        //  Wide regions which overlap each other can mark the same cell twice.
+        if let Some(yixs) = self.pending_land.remove(&self.recent_column_info.start.0) {
+            for yix in yixs {
+                if self.recent_column_info.region_type_info[0][yix] == RecentRegionType::Unknown {
+                    self.mark_region_type(yix, RecentRegionType::Land);
+                }
+            }
+        }
     }
     
     /// Build a new tile for a LOD > 0.
-    fn build_new_tile(&self, loc: (u32, u32), size: (u32, u32)) -> RegionData {
-        //  Dummy name for higher LODs.
-        let name = format!("LOD{} {:?}", self.lod, loc);
+    /// The name is the LOD and location, plus the name of the biggest region in the tile.
+    fn build_new_tile(&self, loc: (u32, u32), size: (u32, u32), children: &[((u32, u32), &TileInfo)], info: &TileInfo) -> RegionData {
+        let name = format!("LOD{}-{}-{} {}", self.lod, loc.0, loc.1, info.dominant_name);
         //  Build a new tile.
         RegionData {
             grid: self.grid.clone(),
@@ -407,58 +608,92 @@ impl ColumnCursor {
             region_size_y: size.1,
             name,
             lod: self.lod,
+            children: children.iter().map(|(child_loc, _)| *child_loc).collect(),
+            is_water: false,
+            is_placeholder: false,
+        }
+    }
+    
+    /// Build a new all-water tile for a LOD > 0.
+    fn build_water_tile(&self, loc: (u32, u32), size: (u32, u32)) -> RegionData {
+        RegionData {
+            grid: self.grid.clone(),
+            region_loc_x: loc.0,
+            region_loc_y: loc.1,
+            region_size_x: size.0,
+            region_size_y: size.1,
+            name: format!("LOD{}-{}-{} Water", self.lod, loc.0, loc.1),
+            lod: self.lod,
+            children: Vec::new(),
+            is_water: true,
+            is_placeholder: false,
         }
     }
     
-    /// Mark cell in use on LOD 0.
-    fn mark_lod_0(&mut self, loc: (u32, u32)) {
+    /// Mark cells in use on LOD 0.
+    /// A region bigger than the tile size, such as an Open Simulator varregion,
+    /// covers several cells in this column and maybe in following columns.
+    /// A region overlapping one already marked is not marked, and is an error.
+    fn mark_lod_0(&mut self, region: &RegionData) -> Result<(), RegionOrderError> {
         assert_eq!(self.lod, 0);    // LOD 0 only.
+        let loc = (region.region_loc_x, region.region_loc_y);
         assert_eq!(self.recent_column_info.start.0, loc.0); // on correct column
-        let yix = self.recent_column_info.calc_y_index(loc.1);
-        log::debug!("Marking cell {} of column {:?}",  yix, self.recent_column_info.region_type_info[0]);
-        assert_eq!(loc.1 % self.recent_column_info.size.1, 0);
-        //  Duplicates not allowed.
-        assert_eq!(
-            self.recent_column_info.region_type_info[0][yix],
-            RecentRegionType::Unknown
-        );
-        //  Mark this as a land cell.
-        //  Fill as water up to new land cell.
+        let size = self.recent_column_info.size;
+        assert_eq!(loc.1 % size.1, 0);
+        let yix = self.recent_column_info.try_calc_y_index(region.loc()[1]).ok_or_else(|| RegionOrderError::OutOfBounds(region.clone()))?;
+        let y_cells = (region.region_size_y / size.1).max(1) as usize;
+        let x_cells = (region.region_size_x / size.0).max(1);
+        let y_end = (yix + y_cells).min(self.recent_column_info.region_type_info[0].len());
+        if self.recent_column_info.region_type_info[0][yix .. y_end].iter().any(|&v| v != RecentRegionType::Unknown) {
+            return Err(RegionOrderError::Overlap(region.clone()));
+        }
         log::debug!(
-            "Mark {:?}, index {} as land. Size {:?}",
+            "Mark {:?}, index {} as land, {} x {} cells. Size {:?}",
             loc,
             yix,
-            self.recent_column_info.size
+            x_cells,
+            y_cells,
+            size
         );
         //  Fill as water up to, but not including, yix.
+        //  Cells already marked as land by a wide region to the left are left alone.
         for n in self.next_y_index .. yix {
-            self.mark_region_type(n, RecentRegionType::Water);
+            if self.recent_column_info.region_type_info[0][n] == RecentRegionType::Unknown {
+                self.mark_region_type(n, RecentRegionType::Water);
+            }
+        }
+        for n in yix .. y_end {
+            self.mark_region_type(n, RecentRegionType::Land);
         }
-        self.mark_region_type(yix, RecentRegionType::Land);
-        self.next_y_index = yix + 1;
+        //  Origin of the region. Only this cell has terrain for lower LODs.
+        let area = region.region_size_x as u64 * region.region_size_y as u64;
+        self.recent_column_info.tile_info[0][yix] = Some(TileInfo { dominant_name: region.name.clone(), area });
+        //  Remember the cells in later columns.
+        for col in 1..x_cells {
+            let x = loc.0 + col * size.0;
+            self.pending_land.entry(x).or_default().extend(yix .. y_end);
+        }
+        self.next_y_index = self.next_y_index.max(y_end);
+        Ok(())
     }
     
     /// Finished with this LOD 0 column. Fill out to end.
     fn column_finished(&mut self) {
         assert!(self.recent_column_info.region_type_info[0].len() > 0);
-        let fill_last = self.recent_column_info.region_type_info[0].len() -1;
         log::debug!("Col finished LOD {} start, yix = {}: {:?}", self.lod, self.next_y_index, self.recent_column_info.region_type_info[0]);  // ***TEMP***
-        if self.recent_column_info.region_type_info[0][fill_last] == RecentRegionType::Unknown {
-            //  This column is not full yet, so we have to fill it out to the end.
-            for n in self.next_y_index as usize .. fill_last + 1 {
-                assert_eq!(self.recent_column_info.region_type_info[0][n], RecentRegionType::Unknown);
-                self.recent_column_info.region_type_info[0][n] = RecentRegionType::Water;
+        //  This column may not be full yet, so we have to fill it out to the end.
+        //  Cells already marked as land by wide regions stay land.
+        for v in self.recent_column_info.region_type_info[0].iter_mut() {
+            if *v == RecentRegionType::Unknown {
+                *v = RecentRegionType::Water;
             }
-            //  At this point, all entries in the column should be known.
-            log::debug!("Col finished LOD {} done, yix = {}: {:?}", self.lod, self.next_y_index, self.recent_column_info.region_type_info[0]);  // ***TEMP***
-            
         }
         //  Column complete. All cells are land or water.
         assert!(self.recent_column_info.region_type_info[0].iter().find(|&&v| v == RecentRegionType::Unknown).is_none());
     }
     
     /// Scan all of a column of LOD n, returning any new tiles.
-    fn scan_lod_n(&mut self, previous_lod_column_info: &RecentColumnInfo) -> VecDeque<RegionData> {
+    fn scan_lod_n(&mut self, previous_lod_column_info: &RecentColumnInfo) -> VecDeque<Rc<RegionData>> {
         assert!(self.is_aligned(previous_lod_column_info));
         let mut new_tiles = VecDeque::new();
         for n in 0..self.recent_column_info.region_type_info[0].len() {
@@ -470,14 +705,28 @@ impl ColumnCursor {
                         self.lod, n, previous_lod_column_info.region_type_info);
                 }
                 RecentRegionType::Land => {
-                    //  Generate and return a land tile.
-                    let new_tile = self.build_new_tile(loc, self.recent_column_info.size);
-                    log::debug!("New tile: {:?}", new_tile);
-                    self.mark_region_type(n, RecentRegionType::Land);
-                    new_tiles.push_back(new_tile);
+                    let children = previous_lod_column_info.four_cell_tiles(loc);
+                    if let Some(info) = TileInfo::combine(&children) {
+                        //  Generate and return a land tile.
+                        let new_tile = self.build_new_tile(loc, self.recent_column_info.size, &children, &info);
+                        log::debug!("New tile: {:?}", new_tile);
+                        self.mark_region_type(n, RecentRegionType::Land);
+                        self.recent_column_info.tile_info[0][n] = Some(info);
+                        new_tiles.push_back(Rc::new(new_tile));
+                    } else {
+                        //  Land, but all covered by bigger regions from elsewhere. No terrain to build from.
+                        log::debug!("LOD {} tile at {:?} has land but no child tiles, skipped.", self.lod, loc);
+                        self.mark_region_type(n, RecentRegionType::Water);
+                        self.water_tiles += 1;
+                    }
                 }
                 RecentRegionType::Water => {  
                     self.mark_region_type(n, RecentRegionType::Water);   
+                    if self.emit_water {
+                        new_tiles.push_back(Rc::new(self.build_water_tile(loc, self.recent_column_info.size)));
+                    } else {
+                        self.water_tiles += 1;
+                    }
                 }    
             }
         }
@@ -512,51 +761,30 @@ impl ColumnCursor {
 
 /// Is this group suitable for multiple-LOD processing?
 /// ***NEED CHECK THAT GROUP IS AT LEAST 2x2***
-pub fn homogeneous_group_size(group: &Vec<RegionData>) -> Option<(u32, u32)> {
+pub fn homogeneous_group_size<R: Borrow<RegionData>>(group: &[R]) -> Option<(u32, u32)> {
     //  Return size of region if group is homogeneous. It always is in SL. For OS, we don't try to do multi-region impostors.
-    if !group.is_empty() && group
+    let first = group.first()?.borrow();
+    if group
         .iter()
-        .find(|v| v.region_size_x != group[0].region_size_x || v.region_size_y != group[0].region_size_y)
+        .map(|v| v.borrow())
+        .find(|v| v.region_size_x != first.region_size_x || v.region_size_y != first.region_size_y)
         .is_none() {
-            Some((group[0].region_size_x, group[0].region_size_y))
+            Some((first.region_size_x, first.region_size_y))
     } else {
         None
     }
 }
 
 
-/// Get dimensions of a group.
-pub fn get_group_bounds(group: &Vec<RegionData>) -> Result<((u32, u32), (u32, u32)), Error> {
-    //  Error if empty group.
-    if group.is_empty() {
-        return Err(anyhow!("Empty viz group"));
+/// Tile size for LOD processing, if this group can have lower LODs.
+/// With mixed_sizes, groups with regions of different sizes are tiled on group_tile_size.
+/// Otherwise, only homogeneous groups get lower LODs.
+pub fn lod_tile_size<R: Borrow<RegionData>>(group: &[R], mixed_sizes: bool) -> Option<(u32, u32)> {
+    if mixed_sizes {
+        group_tile_size(group)
+    } else {
+        homogeneous_group_size(group)
     }
-    //  Error if group is not homogeneous. It always is in SL. For OS, we don't try to do multi-region impostors.
-    if group
-        .iter()
-        .find(|v| v.region_size_x != group[0].region_size_x || v.region_size_y != group[0].region_size_y)
-        .is_some()
-    {
-        return Err(anyhow!("Regions in a viz group are not all the same size"));
-    }
-    Ok((
-        (
-            group
-                .iter()
-                .fold(u32::MAX, |acc, v| acc.min(v.region_loc_x)),
-            group
-                .iter()
-                .fold(u32::MAX, |acc, v| acc.min(v.region_loc_y)),
-        ),
-        (
-            group
-                .iter()
-                .fold(u32::MIN, |acc, v| acc.max(v.region_loc_x + v.region_size_x)),
-            group
-                .iter()
-                .fold(u32::MIN, |acc, v| acc.max(v.region_loc_y + v.region_size_y)),
-        ),
-    ))
 }
 
 /// Get the bounds of the area of interest.
@@ -638,14 +866,6 @@ pub fn get_enclosing_square(
 fn test_region_order() {
     //  Set up logging
     use common::test_logger;
-    // Check loc order. Panic if error.
-    // This module assumes everything is in strictly increasing sequence. So we check.
-    fn check_loc_sequence(a: (u32, u32), b: (u32, u32)) {
-        if a.0 > b.0 || (a.0 == b.0 && a.1 >= b.1) {
-            panic!("Locations out of sequence: a {:?} >= b {:?}", a, b);
-        }
-    }
-
     test_logger();
     //  Build test data
     use super::vizgroup::{VizGroups, vizgroup_test_patterns};
@@ -659,17 +879,16 @@ fn test_region_order() {
     let results = viz_groups.end_grid();
     //  Validate data is in increasing order.
     for group in results {
-        log::debug!("Next group, {} items", group.len());
+        log::debug!("Next group, {} items", group.regions.len());
         let mut prev_loc_opt = None;
-        for item in &group {
-            let loc = (item.region_loc_x, item.region_loc_y);
+        for item in &group.regions {
             if let Some(prev_loc) = prev_loc_opt {
-                check_loc_sequence(prev_loc, loc);
+                check_loc_sequence(prev_loc, item).expect("Locations out of sequence");
             }
-            prev_loc_opt = Some(loc);
+            prev_loc_opt = Some((item.region_loc_x, item.region_loc_y));
         }
-        //  Do test for one group
-        let tile_lods = TileLods::new(group);
+        //  Do test for one group. Bounds come with the group.
+        let tile_lods = TileLods::new_with_bounds(group.regions, group.bounds, false, None);
         log::debug!("Generating lower LODs");
         for item in tile_lods {
             log::debug!(" Output item: {:?}", item);
@@ -677,3 +896,267 @@ fn test_region_order() {
         // ***MORE***
     }
 }
+
+#[test]
+/// Mixed 256 and 512 meter regions, as on Open Simulator grids with varregions.
+fn test_region_order_varregion() {
+    use common::test_region;
+    //  512 varregion at the origin, three 256 regions around it.
+    let group = vec![
+        test_region("Var", 0, 0, 512),
+        test_region("Top", 0, 512, 256),
+        test_region("Right 0", 512, 0, 256),
+        test_region("Right 256", 512, 256, 256),
+    ];
+    assert_eq!(homogeneous_group_size(&group), None);
+    assert_eq!(group_tile_size(&group), Some((256, 256)));
+    assert_eq!(lod_tile_size(&group, false), None);
+    let output: Vec<Rc<RegionData>> = TileLods::new(group.clone()).collect();
+    //  Each LOD 0 region emitted once, with its size preserved.
+    let lod_0: Vec<&RegionData> = output.iter().filter(|r| r.lod == 0).map(|r| &**r).collect();
+    assert_eq!(lod_0.len(), group.len());
+    assert!(group.iter().all(|g| lod_0.iter().filter(|r| **r == g).count() == 1));
+    //  LOD 1 tiles cover the three quadrants with land. The upper right quadrant is all water.
+    let mut lod_1: Vec<(u32, u32)> = output.iter().filter(|r| r.lod == 1).map(|r| (r.region_loc_x, r.region_loc_y)).collect();
+    lod_1.sort();
+    assert_eq!(lod_1, vec![(0, 0), (0, 512), (512, 0)]);
+    assert!(output.iter().filter(|r| r.lod == 1).all(|r| r.region_size_x == 512 && r.region_size_y == 512));
+    assert_eq!(output.iter().filter(|r| r.lod == 2).count(), 1);
+    //  Each lower LOD tile comes after all the LOD 0 regions it covers.
+    for (n, tile) in output.iter().enumerate().filter(|(_, r)| r.lod > 0) {
+        for r in output.iter().filter(|r| r.lod == 0) {
+            let inside = r.region_loc_x >= tile.region_loc_x && r.region_loc_x < tile.region_loc_x + tile.region_size_x
+                && r.region_loc_y >= tile.region_loc_y && r.region_loc_y < tile.region_loc_y + tile.region_size_y;
+            if inside {
+                assert!(output.iter().position(|o| o == r).unwrap() < n, "{} emitted after LOD {} tile {}", r, tile.lod, tile);
+            }
+        }
+    }
+}
+
+#[test]
+/// Long thin groups, one region wide. Columns are indexed by Y, so these used to
+/// overrun or waste the column array.
+fn test_region_order_strips() {
+    use common::{test_logger, test_region};
+    test_logger();
+    let tall: Vec<RegionData> = (0..10).map(|y| test_region(&format!("R3-{}", y), 768, y * 256, 256)).collect();
+    let wide: Vec<RegionData> = (0..10).map(|x| test_region(&format!("R{}-3", x), x * 256, 768, 256)).collect();
+    for group in [tall, wide] {
+        let output: Vec<Rc<RegionData>> = TileLods::new(group.clone()).collect();
+        //  Every region exactly once at LOD 0.
+        let lod_0: Vec<&RegionData> = output.iter().filter(|r| r.lod == 0).map(|r| &**r).collect();
+        assert_eq!(lod_0.len(), group.len());
+        assert!(group.iter().all(|g| lod_0.iter().filter(|r| **r == g).count() == 1));
+        //  Each LOD halves the tile count, rounding up, until one tile covers everything.
+        let counts: Vec<usize> = (0..5).map(|lod| output.iter().filter(|r| r.lod == lod).count()).collect();
+        assert_eq!(counts, vec![10, 5, 3, 2, 1]);
+    }
+}
+
+#[test]
+/// A LOD cap stops the cursors early, before one tile covers the group.
+fn test_region_order_max_lod() {
+    use common::test_region;
+    let group: Vec<RegionData> = (0..10).flat_map(|x| (0..3).map(move |y| test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    let counts = |max_lod: Option<u8>| {
+        let output: Vec<Rc<RegionData>> = TileLods::new_with_max_lod(group.clone(), false, max_lod).collect();
+        (0..6).map(|lod| output.iter().filter(|r| r.lod == lod).count()).collect::<Vec<usize>>()
+    };
+    assert_eq!(counts(None), vec![30, 10, 3, 2, 1, 0]);
+    assert_eq!(counts(Some(2)), vec![30, 10, 3, 0, 0, 0]);
+    //  A cap past full coverage changes nothing. There is always LOD 1.
+    assert_eq!(counts(Some(9)), counts(None));
+    assert_eq!(counts(Some(0)), counts(Some(1)));
+}
+
+#[test]
+/// Lower LOD tiles list the tiles they are built from.
+fn test_region_order_children() {
+    use crate::vizgroup::vizgroup_test_patterns;
+    //  Pattern 1 is homogeneous, so it gets lower LODs.
+    let group = vizgroup_test_patterns()[1].clone();
+    let output: Vec<Rc<RegionData>> = TileLods::new(group.clone()).collect();
+    let inside = |tile: &RegionData, r: &RegionData| r.region_loc_x >= tile.region_loc_x && r.region_loc_x < tile.region_loc_x + tile.region_size_x
+        && r.region_loc_y >= tile.region_loc_y && r.region_loc_y < tile.region_loc_y + tile.region_size_y;
+    assert!(output.iter().any(|r| r.lod == 1));
+    for tile in output.iter().filter(|r| r.lod > 0) {
+        //  Children are exactly the tiles of the LOD above which are inside this one.
+        let mut expected: Vec<(u32, u32)> = output.iter()
+            .filter(|r| r.lod + 1 == tile.lod && inside(tile, r))
+            .map(|r| (r.region_loc_x, r.region_loc_y))
+            .collect();
+        let mut children = tile.children.clone();
+        expected.sort();
+        children.sort();
+        assert_eq!(children, expected, "Children of {}", tile);
+        //  Name is deterministic, and ends with the name of some region inside.
+        let prefix = format!("LOD{}-{}-{} ", tile.lod, tile.region_loc_x, tile.region_loc_y);
+        assert!(tile.name.starts_with(&prefix), "Tile name {}", tile.name);
+        let dominant = &tile.name[prefix.len()..];
+        assert!(group.iter().any(|r| r.name == dominant && inside(tile, r)), "Tile name {}", tile.name);
+    }
+    //  LOD 0 regions have no children.
+    assert!(output.iter().filter(|r| r.lod == 0).all(|r| r.children.is_empty()));
+}
+
+#[test]
+/// LOD 0 regions pass through without being copied.
+fn test_region_order_no_copy() {
+    let group: Vec<Rc<RegionData>> = (0..4)
+        .flat_map(|x| (0..4).map(move |y| Rc::new(common::test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))))
+        .collect();
+    let output: Vec<Rc<RegionData>> = TileLods::new(group.clone()).collect();
+    for region in &group {
+        //  One reference here, one in the output, none left inside TileLods.
+        assert_eq!(Rc::strong_count(region), 2, "Region {}", region);
+        assert_eq!(output.iter().filter(|r| Rc::ptr_eq(r, region)).count(), 1);
+    }
+}
+
+#[test]
+/// Bad input regions are skipped and reported, and the rest of the group still comes out.
+fn test_region_order_errors() {
+    use common::test_region;
+    let good = vec![
+        test_region("A", 0, 0, 256),
+        test_region("B", 0, 256, 256),
+        test_region("Big", 0, 512, 512),
+        test_region("C", 256, 0, 256),
+        test_region("D", 256, 256, 256),
+    ];
+    //  Out of order pair, which is sorted, not an error, plus a duplicate, plus two regions overlapping the varregion,
+    //  in its column and the next.
+    let input = vec![good[1].clone(), good[0].clone(), good[2].clone(), test_region("A again", 0, 0, 256), good[3].clone(), good[4].clone(),
+        test_region("Inside big", 0, 768, 256), test_region("Under big", 256, 768, 256)];
+    let mut tile_lods = TileLods::new(input);
+    let output: Vec<Rc<RegionData>> = tile_lods.by_ref().collect();
+    let errors = tile_lods.take_errors();
+    assert_eq!(errors.len(), 3, "Errors: {:?}", errors);
+    assert!(errors.iter().any(|e| matches!(e, RegionOrderError::Duplicate(r) if r.name == "A again")));
+    assert!(errors.iter().any(|e| matches!(e, RegionOrderError::Overlap(r) if r.name == "Inside big")));
+    assert!(errors.iter().any(|e| matches!(e, RegionOrderError::Overlap(r) if r.name == "Under big")));
+    //  Each good region exactly once.
+    let lod_0: Vec<&RegionData> = output.iter().filter(|r| r.lod == 0).map(|r| &**r).collect();
+    assert_eq!(lod_0.len(), good.len());
+    assert!(good.iter().all(|g| lod_0.iter().filter(|r| **r == g).count() == 1));
+    //  Lower LODs as if the bad regions were never there.
+    let clean: Vec<Rc<RegionData>> = TileLods::new(good.clone()).collect();
+    assert_eq!(output, clean);
+    //  Direct sequence checks.
+    assert_eq!(check_loc_sequence((0, 0), &good[1]), Ok(()));
+    assert_eq!(check_loc_sequence((0, 0), &good[0]), Err(RegionOrderError::Duplicate(good[0].clone())));
+}
+
+#[test]
+/// A ring of land around an interior lake. With water tiles on, the lake gets tiles too.
+fn test_region_order_water_tiles() {
+    //  8x8 regions, with the middle 4x4 missing.
+    let group: Vec<RegionData> = (0..8u32)
+        .flat_map(|x| (0..8u32).map(move |y| (x, y)))
+        .filter(|(x, y)| !((2..6).contains(x) && (2..6).contains(y)))
+        .map(|(x, y)| common::test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))
+        .collect();
+    //  Default is to skip water.
+    let mut tile_lods = TileLods::new(group.clone());
+    let skipped: Vec<Rc<RegionData>> = tile_lods.by_ref().collect();
+    assert!(skipped.iter().all(|r| !r.is_water));
+    assert_eq!(tile_lods.water_tiles_skipped(), 4);
+    //  The lake is four LOD 1 tiles.
+    let mut tile_lods = TileLods::new_with_max_lod(group.clone(), true, None);
+    let output: Vec<Rc<RegionData>> = tile_lods.by_ref().collect();
+    assert_eq!(tile_lods.water_tiles_skipped(), 0);
+    let mut water: Vec<(u8, u32, u32)> = output.iter().filter(|r| r.is_water).map(|r| (r.lod, r.region_loc_x, r.region_loc_y)).collect();
+    water.sort();
+    assert_eq!(water, vec![(1, 512, 512), (1, 512, 1024), (1, 1024, 512), (1, 1024, 1024)]);
+    assert!(output.iter().filter(|r| r.is_water).all(|r| r.children.is_empty() && r.region_size_x == 512));
+    //  Land tiles are the same either way.
+    let land: Vec<&Rc<RegionData>> = output.iter().filter(|r| !r.is_water).collect();
+    assert_eq!(land, skipped.iter().collect::<Vec<_>>());
+}
+
+#[test]
+/// Columns with nothing in them, and columns ending with land.
+/// Every lower LOD tile with land under it must come out once, whatever the column pattern.
+fn test_region_order_column_fill() {
+    use common::test_region;
+    //  Two land columns with an empty column between them.
+    let gap: Vec<RegionData> = [0, 2].into_iter().flat_map(|x| (0..4).map(move |y| test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    //  Columns ending in land, with water below, and an empty column before a full one.
+    let last_land = vec![test_region("R0-0", 0, 0, 256), test_region("R0-3", 0, 768, 256), test_region("R1-3", 256, 768, 256),
+        test_region("R3-0", 768, 0, 256), test_region("R3-1", 768, 256, 256), test_region("R3-2", 768, 512, 256), test_region("R3-3", 768, 768, 256)];
+    //  Land only at the top of each column.
+    let tops: Vec<RegionData> = (0..4).map(|x| test_region(&format!("R{}-3", x), x * 256, 768, 256)).collect();
+    for group in [gap, last_land, tops] {
+        let output: Vec<Rc<RegionData>> = TileLods::new(group.clone()).collect();
+        let max_lod = output.iter().map(|r| r.lod).max().expect("no output");
+        assert_eq!(max_lod, 2);
+        //  Expected tiles: every aligned square with some region inside.
+        let mut expected = Vec::new();
+        for lod in 0..=max_lod {
+            let size = 256u32 << lod;
+            for x in (0..1024).step_by(size as usize) {
+                for y in (0..1024).step_by(size as usize) {
+                    if group.iter().any(|r| r.region_loc_x / size == x / size && r.region_loc_y / size == y / size) {
+                        expected.push((lod, x, y));
+                    }
+                }
+            }
+        }
+        let mut got: Vec<(u8, u32, u32)> = output.iter().map(|r| (r.lod, r.region_loc_x, r.region_loc_y)).collect();
+        got.sort();
+        expected.sort();
+        assert_eq!(got, expected);
+    }
+}
+
+#[test]
+/// Bounds which are already aligned to the LOD grid must not get an extra column.
+/// Every tile comes out exactly once, and nothing outside the bounds, even as water.
+fn test_region_order_aligned_edges() {
+    use common::test_region;
+    //  4x4, not at the origin. 8x2, exactly a power of two wide.
+    let square: Vec<RegionData> = (4..8).flat_map(|x| (4..8).map(move |y| test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    let wide: Vec<RegionData> = (0..8).flat_map(|x| (0..2).map(move |y| test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    for (group, expected_counts) in [(square, vec![16, 4, 1]), (wide, vec![16, 4, 2, 1])] {
+        let output: Vec<Rc<RegionData>> = TileLods::new_with_max_lod(group.clone(), true, None).collect();
+        let mut tiles: Vec<(u8, u32, u32)> = output.iter().map(|r| (r.lod, r.region_loc_x, r.region_loc_y)).collect();
+        tiles.sort();
+        let count = tiles.len();
+        tiles.dedup();
+        assert_eq!(tiles.len(), count, "Duplicate tiles: {:?}", output);
+        let counts: Vec<usize> = (0..expected_counts.len() as u8).map(|lod| output.iter().filter(|r| r.lod == lod && !r.is_water).count()).collect();
+        assert_eq!(counts, expected_counts);
+        //  Water tiles are only inside the enclosing square of the group, which is the lowest LOD tile.
+        let square = output.iter().find(|r| r.lod as usize == expected_counts.len() - 1).expect("lowest LOD tile");
+        assert!(output.iter().filter(|r| r.is_water).all(|r| r.region_loc_x < square.region_loc_x + square.region_size_x
+            && r.region_loc_y < square.region_loc_y + square.region_size_y), "{:?}", output);
+    }
+    //  A long strip has a huge enclosing square, almost all of it after the last region.
+    let strip: Vec<RegionData> = (0..130).map(|y| test_region(&format!("R0-{}", y), 0, y * 256, 256)).collect();
+    let output: Vec<Rc<RegionData>> = TileLods::new(strip.clone()).collect();
+    assert_eq!(output.iter().filter(|r| r.lod == 0).count(), strip.len());
+    assert_eq!(output.iter().filter(|r| r.lod == 8).count(), 1);
+}
+
+#[test]
+/// Land in the right-most column of a group, with water above it.
+/// Input runs out with that column only partly filled in, so the LOD 1 tile over it
+/// only comes out of the end of input flush.
+fn test_region_order_last_column_land() {
+    use common::test_region;
+    //  3 x 3 bounds, so the enclosing square has an empty fourth row and column.
+    let group = vec![test_region("R0-0", 0, 0, 256), test_region("R0-1", 0, 256, 256), test_region("R1-0", 256, 0, 256), test_region("R2-2", 512, 512, 256)];
+    for emit_water in [false, true] {
+        let mut tile_lods = TileLods::new_with_max_lod(group.clone(), emit_water, None);
+        let output: Vec<Rc<RegionData>> = tile_lods.by_ref().collect();
+        let tile = output.iter().find(|r| r.lod == 1 && (r.region_loc_x, r.region_loc_y) == (512, 512)).expect("LOD 1 tile over the last column");
+        assert!(!tile.is_water);
+        assert_eq!(tile.children, vec![(512, 512)]);
+        assert_eq!(output.iter().filter(|r| r.lod == 2).count(), 1);
+        //  Done means done. Nothing more comes out, and nothing past the bounds.
+        assert!(tile_lods.next().is_none());
+        assert!(tile_lods.next().is_none());
+        assert!(output.iter().all(|r| r.region_loc_x < 1024 && r.region_loc_y < 1024));
+    }
+}
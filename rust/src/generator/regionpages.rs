@@ -0,0 +1,177 @@
+//! regionpages.rs -- a grid's regions in location order, read a page at a time.
+//! Part of the Animats impostor system
+//!
+//! The viz group sweep wants every region of a grid, in X then Y order.
+//! Second Life has about 30,000, and each group is processed as soon as it
+//! closes, using the same database connection. So the regions are read in
+//! pages, each query starting after the last location of the one before.
+//! Between pages the connection is free, and only one page is in memory.
+//!
+//! Regions with terrain and placeholders for known regions come from
+//! different queries. Both are in location order, so they are merged
+//! a region at a time.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+use anyhow::Error;
+use common::db::Db;
+use common::RegionData;
+use mysql::{params, Params};
+use std::collections::VecDeque;
+
+/// Regions read per query.
+pub const REGION_PAGE_SIZE: usize = 2000;
+
+/// Reads one page of a grid's regions, in location order, after a location if given.
+pub type PageReader = fn(&mut dyn Db, &str, Option<(u32, u32)>, usize) -> Result<Vec<RegionData>, Error>;
+
+/// Parameters for a page query. The query must select rows after (:after_x, :after_y)
+/// in location order, unless :first, and at most :page_size of them.
+pub fn page_params(grid: &str, after: Option<(u32, u32)>, page_size: usize) -> Params {
+    let (after_x, after_y) = after.unwrap_or_default();
+    params! { "grid" => grid.to_lowercase(), "first" => after.is_none(), "after_x" => after_x, "after_y" => after_y, "page_size" => page_size as u64 }
+}
+
+/// One query's regions, a page at a time.
+struct RegionPages {
+    /// Grid
+    grid: String,
+    /// Reads a page
+    read: PageReader,
+    /// Rows per page
+    page_size: usize,
+    /// Read but not yet taken
+    page: VecDeque<RegionData>,
+    /// Location of the last region read
+    after: Option<(u32, u32)>,
+    /// A short page was read, so there are no more.
+    done: bool,
+}
+
+impl RegionPages {
+    /// Usual new. Nothing is read until asked for.
+    fn new(grid: &str, read: PageReader, page_size: usize) -> Self {
+        Self { grid: grid.to_string(), read, page_size: page_size.max(1), page: VecDeque::new(), after: None, done: false }
+    }
+
+    /// Location of the next region, reading a page if needed.
+    fn peek(&mut self, db: &mut dyn Db) -> Result<Option<(u32, u32)>, Error> {
+        if self.page.is_empty() && !self.done {
+            let page = (self.read)(db, &self.grid, self.after, self.page_size)?;
+            self.done = page.len() < self.page_size;
+            self.after = page.last().map(|r| (r.region_loc_x, r.region_loc_y)).or(self.after);
+            self.page = page.into();
+        }
+        Ok(self.page.front().map(|r| (r.region_loc_x, r.region_loc_y)))
+    }
+
+    /// Take the next region.
+    fn next(&mut self, db: &mut dyn Db) -> Result<Option<RegionData>, Error> {
+        self.peek(db)?;
+        Ok(self.page.pop_front())
+    }
+}
+
+/// A grid's regions with terrain, with placeholders merged in if wanted, in location order.
+pub struct GridRegions {
+    /// Regions with terrain
+    regions: RegionPages,
+    /// Known regions with no terrain
+    placeholders: Option<RegionPages>,
+    /// Regions returned so far
+    count: usize,
+    /// Placeholders among them
+    placeholder_count: usize,
+}
+
+impl GridRegions {
+    /// Usual new. Placeholders are read only if a reader for them is given.
+    pub fn new(grid: &str, regions: PageReader, placeholders: Option<PageReader>, page_size: usize) -> Self {
+        Self {
+            regions: RegionPages::new(grid, regions, page_size),
+            placeholders: placeholders.map(|read| RegionPages::new(grid, read, page_size)),
+            count: 0,
+            placeholder_count: 0,
+        }
+    }
+
+    /// Next region in location order, or None at the end of the grid.
+    /// A placeholder where there's a region with terrain is dropped. The terrain replaces it.
+    pub fn next(&mut self, db: &mut dyn Db) -> Result<Option<RegionData>, Error> {
+        let Some(placeholders) = self.placeholders.as_mut() else {
+            let region = self.regions.next(db)?;
+            self.count += region.is_some() as usize;
+            return Ok(region);
+        };
+        let region = match (self.regions.peek(db)?, placeholders.peek(db)?) {
+            (Some(loc), Some(placeholder_loc)) if placeholder_loc < loc => placeholders.next(db)?,
+            (Some(loc), Some(placeholder_loc)) => {
+                if placeholder_loc == loc {
+                    placeholders.next(db)?;
+                }
+                self.regions.next(db)?
+            }
+            (Some(_), None) => self.regions.next(db)?,
+            (None, _) => placeholders.next(db)?,
+        };
+        self.count += region.is_some() as usize;
+        self.placeholder_count += region.as_ref().is_some_and(|r| r.is_placeholder) as usize;
+        Ok(region)
+    }
+
+    /// Regions returned so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Placeholders returned so far.
+    pub fn placeholder_count(&self) -> usize {
+        self.placeholder_count
+    }
+}
+
+#[test]
+fn test_grid_regions_paged() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    //  Rows as both queries select them, location order.
+    fn rows(locs: &[u32]) -> Vec<DbRow> {
+        locs.iter().map(|&x| DbRow(vec![DbValue::text("agni"), DbValue::UInt(x as u64), DbValue::UInt(0), DbValue::UInt(256), DbValue::UInt(256),
+            DbValue::text(&format!("R{}", x))])).collect()
+    }
+    fn read(db: &mut dyn Db, grid: &str, after: Option<(u32, u32)>, page_size: usize, is_placeholder: bool) -> Result<Vec<RegionData>, Error> {
+        db.exec_map("SELECT page", page_params(grid, after, page_size), |row| Ok(RegionData { grid: row.get(0)?, region_loc_x: row.get(1)?, region_loc_y: row.get(2)?,
+            region_size_x: row.get(3)?, region_size_y: row.get(4)?, name: row.get(5)?, lod: 0, children: Vec::new(), is_water: false, is_placeholder }))
+    }
+    let terrain: PageReader = |db, grid, after, page_size| read(db, grid, after, page_size, false);
+    let known: PageReader = |db, grid, after, page_size| read(db, grid, after, page_size, true);
+    //  Five regions, pages of two. The third page is short, so it's the last.
+    let mut fake = FakeDb::new_with_results(vec![rows(&[0, 256]), rows(&[512, 768]), rows(&[1024])]);
+    let mut regions = GridRegions::new("Agni", terrain, None, 2);
+    let mut locs = Vec::new();
+    while let Some(region) = regions.next(&mut fake).unwrap() {
+        locs.push(region.region_loc_x);
+    }
+    assert_eq!(locs, vec![0, 256, 512, 768, 1024]);
+    assert_eq!((regions.count(), fake.sql().len()), (5, 3));
+    //  Each page starts after the last one ended.
+    let after: Vec<(mysql::Value, mysql::Value)> = fake.statements.iter().map(|(_, params)| match params {
+        Params::Named(named) => (named[b"first".as_slice()].clone(), named[b"after_x".as_slice()].clone()),
+        _ => panic!("Page query without named parameters"),
+    }).collect();
+    assert_eq!(after, vec![(true.into(), 0u32.into()), (false.into(), 256u32.into()), (false.into(), 768u32.into())]);
+    //  Placeholders merged in, in order. One where terrain now is gets dropped.
+    //  Each reader reads its first page when first looked at, then when it runs out.
+    let mut fake = FakeDb::new_with_results(vec![rows(&[0, 768]), rows(&[256, 768]), rows(&[]), rows(&[1024])]);
+    let mut regions = GridRegions::new("agni", terrain, Some(known), 2);
+    let mut merged = Vec::new();
+    while let Some(region) = regions.next(&mut fake).unwrap() {
+        merged.push((region.region_loc_x, region.is_placeholder));
+    }
+    assert_eq!(merged, vec![(0, false), (256, true), (768, false), (1024, true)]);
+    assert_eq!((regions.count(), regions.placeholder_count()), (4, 2));
+    //  A failed page fails the sweep.
+    let mut fake = FakeDb { fail_on: Some("SELECT".to_string()), ..Default::default() };
+    assert!(GridRegions::new("agni", terrain, None, 2).next(&mut fake).is_err());
+}
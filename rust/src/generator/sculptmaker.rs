@@ -6,34 +6,39 @@
 
 use image::{Rgb, RgbImage, ImageReader, DynamicImage};
 use std::cmp::{max, min};
-use std::hash::{Hash, Hasher, DefaultHasher};
+use common::hashing::short_hash;
 use std::f64;
 use anyhow::{anyhow, Error};
 use std::io::{Cursor};
 
 /// Calculate hash for duplicate check.
-fn calc_rgbimage_hash(img: &RgbImage) -> u32 {
-    let mut hasher = DefaultHasher::new();
-    img.hash(&mut hasher);
-    let hash: u64 = hasher.finish();
+/// Stored hashes from earlier runs are compared with this, so it must not change between builds.
+pub fn calc_rgbimage_hash(img: &RgbImage) -> u32 {
+    let mut bytes = Vec::with_capacity(8 + img.pixels().len() * 3);
+    bytes.extend_from_slice(&img.width().to_le_bytes());
+    bytes.extend_from_slice(&img.height().to_le_bytes());
+    bytes.extend(img.pixels().flat_map(|p| p.0));
     //  We only want a 32-bit hash, because we have a length problem.
-    (((hash >> 32) & 0xffffffff) ^ (hash & 0xffffffff)) as u32
+    short_hash(&bytes)
 }
 
-const SCULPTDIM: usize = 64; // Sculpt textures are always 64x64
+pub const SCULPTDIM: usize = 64; // Sculpt textures are usually 64x64
 
 #[derive(Debug)]
 pub struct TerrainSculpt {
     pub image: Option<RgbImage>,
+    /// Sculpt image size, pixels on a side
+    sculpt_dim: usize,
     elevs: Option<Vec<Vec<f64>>>,
     zheight: Option<f64>,
     zoffset: Option<f64>,
 }
 
 impl TerrainSculpt {
-    pub fn new(_region: &str) -> Self {
+    pub fn new(_region: &str, sculpt_dim: usize) -> Self {
         TerrainSculpt {
             image: None,
+            sculpt_dim,
             elevs: None,
             zheight: None,
             zoffset: None,
@@ -75,8 +80,10 @@ impl TerrainSculpt {
         Ok(calc_rgbimage_hash(&self.image.as_ref().unwrap()))
     }
 
+    /// Set elevations, indexed elevs[x][y], +Y north, as from HeightField::into_sculpt_array.
     pub fn setelevs(&mut self, elevs: Vec<Vec<u8>>, inputscale: f64, inputoffset: f64) {
-        if elevs.len() == SCULPTDIM && elevs[0].len() == SCULPTDIM {
+        let sculpt_dim = self.sculpt_dim;
+        if elevs.len() == sculpt_dim && elevs[0].len() == sculpt_dim {
             // Directly convert to f64
             let elevs_f64: Vec<Vec<f64>> = elevs
                 .into_iter()
@@ -85,15 +92,16 @@ impl TerrainSculpt {
             self.elevs = Some(elevs_f64);
             return;
         }
-        // Interpolate to SCULPTDIM x SCULPTDIM
-        let mut newelevs: Vec<Vec<f64>> = vec![vec![0.0; SCULPTDIM]; SCULPTDIM];
+        // Interpolate to sculpt_dim x sculpt_dim
+        let mut newelevs: Vec<Vec<f64>> = vec![vec![0.0; sculpt_dim]; sculpt_dim];
         let orig_x = elevs.len();
         let orig_y = elevs[0].len();
+        let elev = |x: usize, y: usize| elevs[x][y];
 
-        for x in 0..SCULPTDIM {
-            for y in 0..SCULPTDIM {
-                let xfract = ((x as f64) / SCULPTDIM as f64) * orig_x as f64;
-                let yfract = ((y as f64) / SCULPTDIM as f64) * orig_y as f64;
+        for x in 0..sculpt_dim {
+            for y in 0..sculpt_dim {
+                let xfract = ((x as f64) / sculpt_dim as f64) * orig_x as f64;
+                let yfract = ((y as f64) / sculpt_dim as f64) * orig_y as f64;
                 let xfract = xfract.min((orig_x - 1) as f64);
                 let yfract = yfract.min((orig_y - 1) as f64);
 
@@ -102,10 +110,10 @@ impl TerrainSculpt {
                 let y0 = yfract.floor() as usize;
                 let y1 = yfract.ceil() as usize;
 
-                let z0 = elevs[x0][y0];
-                let z1 = elevs[x0][y1];
-                let z2 = elevs[x1][y0];
-                let z3 = elevs[x1][y1];
+                let z0 = elev(x0, y0);
+                let z1 = elev(x0, y1);
+                let z2 = elev(x1, y0);
+                let z3 = elev(x1, y1);
 
                 let z = max(z0, max(z1, max(z2, z3))) as f64;
                 newelevs[x][y] = z * (inputscale / 256.0) + inputoffset;
@@ -164,6 +172,14 @@ impl TerrainSculptTexture {
         Ok(())
     }
     
+    /// Makes a plain water image instead, for tiles with no land.
+    /// No map server fetch.
+    pub fn make_water_image(&mut self, resolution: u32) {
+        //  Approximately the color of water on the SL map.
+        const WATER_COLOR: Rgb<u8> = Rgb([30, 72, 98]);
+        self.image = Some(RgbImage::from_pixel(resolution, resolution, WATER_COLOR));
+    }
+    
     /// Get uniqueness hash
     pub fn get_hash(&self) -> Result<u32, Error> {
         Ok(calc_rgbimage_hash(&self.image.as_ref().unwrap()))
@@ -224,3 +240,22 @@ fn read_terrain_texture() {
     let img = TerrainSculptTexture::fetch_terrain_image(URL_PREFIX, 1024*256, 1024*256, 0).expect("Terrain fetch failed");
     img.save("/tmp/testimg.jpg").expect("test image write failed");
 }
+
+#[test]
+fn sculpt_orientation() {
+    //  3 samples in X, 5 in Y, with a high marker at the lower right corner, (2, 0).
+    //  +Y is north, so that's the bottom right pixel of the sculpt image, which is flipped in Y.
+    let height_field = common::HeightField::new_from_fn(3, 5, 256, 256, 20.0, |x, y| if (x, y) == (2, 0) { 100.0 } else { 0.0 })
+        .expect("Height field");
+    let (scale, offset, elevs) = height_field.into_sculpt_array().expect("Sculpt array");
+    let mut sculpt = TerrainSculpt::new("Test", 3);
+    sculpt.setelevs(elevs, scale as f64, offset as f64);
+    sculpt.makeimage();
+    let img = sculpt.image.as_ref().expect("Sculpt image");
+    assert_eq!(img.dimensions(), (3, 3));
+    let (_, (px, py)) = img.enumerate_pixels().map(|(x, y, p)| (p.0[2], (x, y))).max().unwrap();
+    assert_eq!((px, py), (2, 2));
+    //  X and Y channels of the marker pixel are its position in the region, unflipped.
+    assert_eq!(img.get_pixel(2, 2).0[0], 171);
+    assert_eq!(img.get_pixel(2, 2).0[1], 0);
+}
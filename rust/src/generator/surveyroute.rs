@@ -0,0 +1,181 @@
+//! surveyroute.rs -- visiting order for the terrain survey bot.
+//! Part of the Animats impostor system
+//!
+//! The bot that surveys regions visits them one at a time. Within a viz group
+//! the regions are connected, so the bot can fly from one to the next.
+//! Going from one group to another takes a teleport.
+//!
+//! Within a group, regions are swept a column at a time, west to east,
+//! in the (x, y) order the transitive closure already uses. Alternate
+//! columns run north to south, so each column starts next to where the
+//! last one ended. Small groups come first, so a partial run finishes
+//! whole groups.
+//!
+//! The route is written as JSON, for people, and as CSV lines of
+//! "grid,x,y,name", for the LSL survey script to read in chunks.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::Error;
+use serde::Serialize;
+use std::collections::HashSet;
+use common::RegionData;
+use crate::vizgroup::CompletedGroups;
+
+/// One region to visit.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurveyStop {
+    /// Location in world (meters)
+    pub x: u32,
+    /// Location in world (meters)
+    pub y: u32,
+    /// Region name
+    pub name: String,
+}
+
+/// The regions of one viz group, in visiting order.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurveyLeg {
+    /// Regions, in visiting order.
+    pub stops: Vec<SurveyStop>,
+}
+
+/// The whole route for a grid.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurveyRoute {
+    /// Grid name
+    pub grid: String,
+    /// If set, only regions with raw terrain at least this old are visited.
+    pub stale_days: Option<u32>,
+    /// Regions on the route
+    pub regions: usize,
+    /// Estimated teleports. One to reach each group.
+    pub teleports: usize,
+    /// One leg per viz group, smallest first.
+    pub legs: Vec<SurveyLeg>,
+}
+
+/// A group's regions in serpentine column order.
+/// Columns run west to east. Even numbered columns go south to north, odd ones north to south.
+pub fn serpentine_order(group: &[RegionData]) -> Vec<RegionData> {
+    let mut regions = group.to_vec();
+    regions.sort_by_key(|r| (r.region_loc_x, r.region_loc_y));
+    let mut ordered = Vec::with_capacity(regions.len());
+    for (n, column) in regions.chunk_by(|a, b| a.region_loc_x == b.region_loc_x).enumerate() {
+        if n % 2 == 0 {
+            ordered.extend(column.iter().cloned());
+        } else {
+            ordered.extend(column.iter().rev().cloned());
+        }
+    }
+    ordered
+}
+
+impl SurveyRoute {
+    /// Plan a route through these viz groups.
+    /// If wanted is given, only regions at those locations are visited, and groups with none are skipped.
+    pub fn new(grid: &str, groups: &CompletedGroups, wanted: Option<&HashSet<(u32, u32)>>, stale_days: Option<u32>) -> Self {
+        let mut legs: Vec<SurveyLeg> = groups.iter()
+            .map(|group| {
+                let stops = serpentine_order(group.regions()).into_iter()
+                    .filter(|r| wanted.is_none_or(|w| w.contains(&(r.region_loc_x, r.region_loc_y))))
+                    .map(|r| SurveyStop { x: r.region_loc_x, y: r.region_loc_y, name: r.name })
+                    .collect();
+                SurveyLeg { stops }
+            })
+            .filter(|leg| !leg.stops.is_empty())
+            .collect();
+        //  Smallest first. Ties by location, so the route is the same every time.
+        legs.sort_by_key(|leg| (leg.stops.len(), leg.stops.iter().map(|s| (s.x, s.y)).min()));
+        let regions = legs.iter().map(|leg| leg.stops.len()).sum();
+        Self { grid: grid.to_string(), stale_days, regions, teleports: legs.len(), legs }
+    }
+
+    /// As JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// As CSV, one "grid,x,y,name" line per region, in visiting order.
+    /// LSL's llCSV2List has no quoting, so commas in names become spaces.
+    pub fn to_csv(&self) -> String {
+        self.legs.iter()
+            .flat_map(|leg| leg.stops.iter())
+            .map(|stop| format!("{},{},{},{}\n", self.grid, stop.x, stop.y, stop.name.replace(',', " ")))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for SurveyRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Survey route for grid \"{}\": {} regions in {} groups, about {} teleports",
+            self.grid, self.regions, self.legs.len(), self.teleports)?;
+        if let Some(stale_days) = self.stale_days {
+            write!(f, ", regions older than {} days", stale_days)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_route_groups() -> CompletedGroups {
+    use crate::vizgroup::{VizGroups, canonicalize_groups};
+    let mut viz_groups = VizGroups::new(false);
+    for region in crate::vizgroup::vizgroup_test_patterns()[0].clone() {
+        viz_groups.add_region_data(region);
+    }
+    let mut groups = viz_groups.end_grid();
+    canonicalize_groups(&mut groups);
+    groups
+}
+
+#[test]
+fn test_serpentine_order() {
+    //  The big ring of the test pattern.
+    let groups = test_route_groups();
+    let names: Vec<String> = serpentine_order(groups[0].regions()).into_iter().map(|r| r.name).collect();
+    //  Up the left column, then along the bottom, one region per column, then down column 5.
+    assert_eq!(&names[..9], &["Bottom left", "Left 100", "Left 200", "Left 300", "Left 400", "Bottom 100", "Bottom 200", "Bottom 300", "Bottom 400"]);
+    assert_eq!(&names[9..14], &["Column 5-4", "Column 5-3", "Column 5-2", "Column 5-1", "Bottom 500"]);
+    //  Each region once.
+    assert_eq!(names.len(), groups[0].regions.len());
+    //  Columns alternate direction.
+    let column = |x: u32| -> Vec<u32> { serpentine_order(groups[0].regions()).iter().filter(|r| r.region_loc_x == x).map(|r| r.region_loc_y).collect() };
+    assert_eq!(column(0), vec![0, 100, 200, 300, 400]);
+    assert_eq!(column(500), vec![400, 300, 200, 100, 0]);
+    assert!(serpentine_order(&[]).is_empty());
+}
+
+#[test]
+fn test_survey_route() {
+    let groups = test_route_groups();
+    assert_eq!(groups.len(), 3);
+    let route = SurveyRoute::new("test", &groups, None, None);
+    //  Smallest group first, one teleport per group.
+    assert_eq!(route.legs.iter().map(|leg| leg.stops.len()).collect::<Vec<_>>(), vec![2, 2, 21]);
+    assert_eq!(route.teleports, 3);
+    assert_eq!(route.regions, 25);
+    assert_eq!(route.legs[0].stops[0].name, "Tiny West");
+    assert_eq!(route.legs[1].stops[0].name, "Tall skinny region");
+    //  Only stale regions. A group with none isn't visited, so no teleport to it.
+    let wanted: HashSet<(u32, u32)> = [(200, 300), (300, 300), (0, 0), (500, 400)].into_iter().collect();
+    let route = SurveyRoute::new("test", &groups, Some(&wanted), Some(90));
+    assert_eq!(route.teleports, 2);
+    assert_eq!(route.regions, 4);
+    assert_eq!(route.to_string(), "Survey route for grid \"test\": 4 regions in 2 groups, about 2 teleports, regions older than 90 days");
+    //  CSV in visiting order. Legs the same size go west to east.
+    assert_eq!(route.to_csv(), "test,0,0,Bottom left\ntest,500,400,Column 5-4\ntest,200,300,Tiny West\ntest,300,300,Tiny East\n");
+    //  Nothing wanted, nothing to do.
+    let route = SurveyRoute::new("test", &groups, Some(&HashSet::new()), Some(90));
+    assert_eq!((route.teleports, route.regions, route.to_csv()), (0, 0, String::new()));
+}
+
+#[test]
+fn test_survey_route_csv_names() {
+    let region = RegionData { grid: "test".to_string(), lod: 0, region_loc_x: 256, region_loc_y: 512, region_size_x: 256, region_size_y: 256,
+        name: "Smith, Jones".to_string(), children: Vec::new(), is_water: false, is_placeholder: false };
+    let route = SurveyRoute::new("test", &vec![vec![region]], None, None);
+    assert_eq!(route.to_csv(), "test,256,512,Smith  Jones\n");
+}
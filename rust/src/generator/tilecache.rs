@@ -0,0 +1,279 @@
+//! tilecache.rs -- recently built height fields, for building lower LODs.
+//! Part of the Animats impostor system
+//!
+//! regionorder emits tiles column by column, so a LOD N tile comes out
+//! shortly after the four LOD N-1 tiles it is built from. Keeping those
+//! height fields here means they don't have to be read from SQL and
+//! decoded again.
+//!
+//! Each height field is normally needed only once, so using one
+//! consumes it. Anything left over is evicted, least recently used
+//! first, when the cache goes over its memory budget. A miss is not
+//! an error. The missing tile is rebuilt from LOD 0 terrain, which
+//! is slower but gives the same result.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use anyhow::{anyhow, Error};
+use common::HeightField;
+use std::collections::{BTreeMap, HashMap};
+use common::RegionData;
+
+/// Key for cache of height fields for all LODs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TileCacheKey {
+    /// Which grid
+    pub grid: String,
+    /// Location in world of tile (meters)
+    pub region_loc_x: u32,
+    /// Location in world of tile (meters)
+    pub region_loc_y: u32,
+    /// Level of detail.
+    pub lod: u8,
+}
+
+/// One cached height field.
+#[derive(Debug)]
+struct TileCacheEntry {
+    /// The height field
+    height_field: HeightField,
+    /// Memory used, bytes.
+    bytes: usize,
+    /// When last used, as a use count.
+    last_use: u64,
+}
+
+/// Height field cache, with a memory budget.
+#[derive(Debug)]
+pub struct TileCache {
+    /// The cache
+    entries: HashMap<TileCacheKey, TileCacheEntry>,
+    /// Keys in order of last use, oldest first.
+    lru: BTreeMap<u64, TileCacheKey>,
+    /// Use counter, for LRU order.
+    use_count: u64,
+    /// Memory used by all entries, bytes.
+    bytes: usize,
+    /// Memory budget, bytes.
+    budget: usize,
+    /// Lookups which found something.
+    hits: usize,
+    /// Lookups which found nothing.
+    misses: usize,
+}
+
+impl TileCache {
+    /// New, with a memory budget in bytes.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            use_count: 0,
+            bytes: 0,
+            budget,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// New, with a memory budget in megabytes.
+    pub fn new_megabytes(budget_mb: usize) -> Self {
+        Self::new(budget_mb * 1024 * 1024)
+    }
+
+    /// Insert, replacing any previous entry for the same tile.
+    /// Evicts least recently used entries until within budget.
+    /// The new entry is never evicted by its own insert.
+    pub fn insert(&mut self, key: TileCacheKey, height_field: HeightField) {
+        self.remove(&key);
+        self.use_count += 1;
+        let bytes = height_field.byte_size();
+        self.bytes += bytes;
+        self.lru.insert(self.use_count, key.clone());
+        self.entries.insert(key, TileCacheEntry { height_field, bytes, last_use: self.use_count });
+        while self.bytes > self.budget && self.entries.len() > 1 {
+            let (_, oldest) = self.lru.pop_first().expect("LRU list out of sync");
+            log::debug!("Tile cache full, evicting {:?}", oldest);
+            let entry = self.entries.remove(&oldest).expect("Tile cache out of sync");
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    /// Destructive remove. Counts as a hit or a miss.
+    pub fn take(&mut self, key: &TileCacheKey) -> Option<HeightField> {
+        let result = self.remove(key);
+        if result.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        result
+    }
+
+    /// Remove, without counting.
+    fn remove(&mut self, key: &TileCacheKey) -> Option<HeightField> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.last_use);
+        self.bytes -= entry.bytes;
+        Some(entry.height_field)
+    }
+
+    /// Lookups which found something.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Lookups which found nothing.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+}
+
+/// Height field for a tile of LOD > 0, built from its children.
+/// Children come from the cache if possible. A child missing from the cache is
+/// rebuilt from its own children, recursively, down to LOD 0 terrain from fetch_lod_0.
+/// fetch_lod_0 returns None for a location with no region.
+/// The result is cached for the next LOD down.
+pub fn compose_height_field(
+    cache: &mut TileCache,
+    tile: &RegionData,
+    fetch_lod_0: &mut impl FnMut(u32, u32) -> Result<Option<HeightField>, Error>,
+) -> Result<HeightField, Error> {
+    //  Not for LOD 0. We can't build that from other LODs.
+    assert!(tile.lod > 0);
+    let tile_size = (tile.region_size_x, tile.region_size_y);
+    //  Get the four height fields, in quadrant order.
+    //  Tile size here is the full sized impostor, so we have to divide by 2 to get the size of the 4 squares that make it up.
+    let mut height_fields = [None, None, None, None];
+    for &(child_x, child_y) in &tile.children {
+        let quadrant_x = (child_x - tile.region_loc_x) / (tile_size.0 / 2);
+        let quadrant_y = (child_y - tile.region_loc_y) / (tile_size.1 / 2);
+        if quadrant_x > 1 || quadrant_y > 1 {
+            return Err(anyhow!("Tile {} child ({}, {}) is outside the tile", tile, child_x, child_y));
+        }
+        let key = TileCacheKey { grid: tile.grid.clone(), region_loc_x: child_x, region_loc_y: child_y, lod: tile.lod - 1 };
+        let height_field = match cache.take(&key) {
+            Some(height_field) => Some(height_field),
+            None => {
+                log::info!("Tile {}, LOD {}: child at ({}, {}) not in cache, rebuilding.", tile, tile.lod, child_x, child_y);
+                rebuild_height_field(cache, &key, (tile_size.0 / 2, tile_size.1 / 2), fetch_lod_0)?
+            }
+        };
+        if height_field.is_none() {
+            log::warn!("Tile {}, LOD {}: no height field for child at ({}, {}), using water.", tile, tile.lod, child_x, child_y);
+        }
+        height_fields[(quadrant_x + 2 * quadrant_y) as usize] = height_field;
+    }
+    let height_field = combine_quadrants(height_fields, tile_size)?;
+    let key = TileCacheKey { grid: tile.grid.clone(), region_loc_x: tile.region_loc_x, region_loc_y: tile.region_loc_y, lod: tile.lod };
+    cache.insert(key, height_field.clone());
+    Ok(height_field)
+}
+
+/// Rebuild a height field which is not in the cache.
+/// Lower LODs are rebuilt from the four quadrants at the LOD above.
+/// Returns None if it's all water. Not cached, because whoever asked is about to consume it.
+fn rebuild_height_field(
+    cache: &mut TileCache,
+    key: &TileCacheKey,
+    size: (u32, u32),
+    fetch_lod_0: &mut impl FnMut(u32, u32) -> Result<Option<HeightField>, Error>,
+) -> Result<Option<HeightField>, Error> {
+    if key.lod == 0 {
+        return fetch_lod_0(key.region_loc_x, key.region_loc_y);
+    }
+    let half = (size.0 / 2, size.1 / 2);
+    let mut height_fields = [None, None, None, None];
+    for (n, (dx, dy)) in [(0, 0), (half.0, 0), (0, half.1), (half.0, half.1)].into_iter().enumerate() {
+        let child_key = TileCacheKey { grid: key.grid.clone(), region_loc_x: key.region_loc_x + dx, region_loc_y: key.region_loc_y + dy, lod: key.lod - 1 };
+        height_fields[n] = match cache.take(&child_key) {
+            Some(height_field) => Some(height_field),
+            None => rebuild_height_field(cache, &child_key, half, fetch_lod_0)?,
+        };
+    }
+    if height_fields.iter().all(|h| h.is_none()) {
+        Ok(None)
+    } else {
+        Ok(Some(combine_quadrants(height_fields, size)?))
+    }
+}
+
+/// Combine four quadrants, in order ll, lr, ul, ur, into one height field at half resolution.
+/// A varregion exactly the size of this tile is already complete, and just needs halving.
+/// A varregion bigger than this tile, such as a 1024 among 256 regions at LOD 1, is an error.
+/// Only the tile at its origin lists it as a child, and that tile covers only part of it,
+/// so combining would make a tile of the wrong size.
+fn combine_quadrants(height_fields: [Option<HeightField>; 4], tile_size: (u32, u32)) -> Result<HeightField, Error> {
+    if let Some(h) = height_fields.iter().flatten().find(|h| h.size_x > tile_size.0 || h.size_y > tile_size.1) {
+        return Err(anyhow!("Child of {} x {} tile is {} x {}. Varregions bigger than the tile size are not supported at lower LODs.",
+            tile_size.0, tile_size.1, h.size_x, h.size_y));
+    }
+    Ok(match height_fields {
+        [Some(h), None, None, None] if (h.size_x, h.size_y) == tile_size => h.halve(),
+        height_fields => HeightField::halve(&HeightField::combine(height_fields)?),
+    })
+}
+
+#[test]
+fn test_tile_cache() {
+    use crate::regionorder::TileLods;
+    use std::rc::Rc;
+    //  4x4 group, each region with its own distinctive terrain.
+    fn terrain(x: u32, y: u32) -> HeightField {
+        let elevs: Vec<u8> = (0..9).map(|n| (n * 10 + x * 3 + y * 50) as u8).collect();
+        HeightField::new_from_elevs_blob(&elevs, 3, 3, 256, 256, 100.0, 0.0, 20.0).expect("test height field")
+    }
+    let group: Vec<RegionData> = (0..4).flat_map(|x| (0..4).map(move |y| common::test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256))).collect();
+    //  Build all tiles of the group, as process_group does. Returns lower LOD height fields and count of LOD 0 fetches.
+    let build = |cache: &mut TileCache| -> (Vec<(Rc<RegionData>, HeightField)>, usize) {
+        let mut fetches = 0;
+        let mut fetch_lod_0 = |x: u32, y: u32| -> Result<Option<HeightField>, Error> {
+            fetches += 1;
+            Ok(Some(terrain(x / 256, y / 256)))
+        };
+        let mut results = Vec::new();
+        for tile in TileLods::new(group.clone()) {
+            if tile.lod == 0 {
+                let height_field = fetch_lod_0(tile.region_loc_x, tile.region_loc_y).unwrap().unwrap();
+                cache.insert(TileCacheKey { grid: tile.grid.clone(), region_loc_x: tile.region_loc_x, region_loc_y: tile.region_loc_y, lod: 0 }, height_field);
+            } else {
+                let height_field = compose_height_field(cache, &tile, &mut fetch_lod_0).expect("compose");
+                results.push((tile, height_field));
+            }
+        }
+        (results, fetches)
+    };
+    let entry_bytes = terrain(0, 0).byte_size();
+    //  Room for two columns of LOD 0 and the column of LOD 1 built from them. Nothing has to be fetched twice.
+    let mut cache = TileCache::new(entry_bytes * 10);
+    let (results, fetches) = build(&mut cache);
+    assert_eq!(results.iter().filter(|(tile, _)| tile.lod == 1).count(), 4);
+    assert_eq!(results.iter().filter(|(tile, _)| tile.lod == 2).count(), 1);
+    assert_eq!(fetches, group.len());
+    assert_eq!(cache.misses(), 0);
+    assert_eq!(cache.hits(), 16 + 4);
+    //  Too small. LOD 0 has to be fetched again, but the results are the same.
+    let mut small_cache = TileCache::new(entry_bytes * 4);
+    let (small_results, small_fetches) = build(&mut small_cache);
+    assert!(small_fetches > group.len());
+    assert!(small_cache.misses() > 0);
+    assert_eq!(small_results, results);
+}
+
+#[test]
+fn test_combine_quadrants_varregions() {
+    let flat = |size: u32| HeightField::new_from_fn(5, 5, size, size, 20.0, |_, _| 30.0).unwrap();
+    //  Four 256 regions make a 512 tile.
+    let combined = combine_quadrants([Some(flat(256)), Some(flat(256)), Some(flat(256)), Some(flat(256))], (512, 512)).unwrap();
+    assert_eq!((combined.size_x, combined.size_y), (512, 512));
+    //  A 512 varregion is the whole 512 tile.
+    let halved = combine_quadrants([Some(flat(512)), None, None, None], (512, 512)).unwrap();
+    assert_eq!((halved.size_x, halved.size_y), (512, 512));
+    //  A 1024 varregion at the origin of a 512 tile, alone or with a neighbor, is rejected.
+    assert!(combine_quadrants([Some(flat(1024)), None, None, None], (512, 512)).is_err());
+    assert!(combine_quadrants([Some(flat(1024)), Some(flat(256)), None, None], (512, 512)).is_err());
+    //  The same varregion is fine in a tile its own size.
+    assert!(combine_quadrants([Some(flat(1024)), None, None, None], (1024, 1024)).is_ok());
+}
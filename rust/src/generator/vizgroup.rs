@@ -15,150 +15,88 @@
 //! License: LGPL.
 //!
 #![forbid(unsafe_code)]
-use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::rc::{Rc, Weak};
+use serde::Serialize;
+use common::{RegionData, group_area, group_bounds};
 
-/// RegionData - info about one region relevant to this computation.
-#[derive(Debug, Clone, PartialEq)]
-pub struct RegionData {
-    /// Which grid
-    pub grid: String,
-    /// Which LOD - zero for all data obtained from the world.
-    pub lod: u8,
-    /// X
-    pub region_loc_x: u32,
-    /// Y
-    pub region_loc_y: u32,
-    /// X size
-    pub region_size_x: u32,
-    /// Y size
-    pub region_size_y: u32,
-    /// Region name
-    pub name: String,
-}   
-
-impl std::fmt::Display for RegionData {
-    /// Just name and location, no size.
+/// Two regions which overlap, rather than just touching.
+/// Not correct, but happens when the region database is temporarily
+/// inconsistent, or with misconfigured Open Simulator varregions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OverlapReport {
+    /// Earlier region
+    pub a: RegionData,
+    /// Later region
+    pub b: RegionData,
+    /// Area of overlap, square meters.
+    pub area: u64,
+}
+
+impl std::fmt::Display for OverlapReport {
+    /// Both regions and the area
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "\"{}\" ({}, {})",
-            self.name, self.region_loc_x, self.region_loc_y
-        )
+        write!(f, "{} overlaps {} by {} square meters", self.a, self.b, self.area)
     }
 }
 
 //  General concept of transitive closure algorithm.
 //
-//  LiveBlocks have an Rc link to a VizGroup.
-//  When we detect that a region in a new column touches
-//  a LiveBlock or another region in the new column,
-//  VizGroups are merged.
+//  Regions come in sorted by X, then Y, so they arrive a column at a time.
+//  Each region is added to a disjoint-set forest as a set of one.
+//  LiveBlocks are the regions which might touch a region in an incoming
+//  column. When we detect that a region in a new column touches
+//  a LiveBlock or another region in the new column, their sets are merged.
 //
-//  When a LiveBlock is deleted, it drops its reference to the VizGroup.
-//  When a VizGroup is deleted because no LiveBlock is referencing it,
-//  that means a VizGroup is complete, so
-//  ownership of the VizGroup's data is transferred to a vector of
-//  completed VizGroup items in VizGroups.
+//  Each set counts the LiveBlocks, including those in the current column,
+//  which belong to it. When a LiveBlock is purged and its set's count
+//  drops to zero, nothing else can touch that set, so the group is
+//  complete. It is then delivered to the completed groups of VizGroups,
+//  or handed to the sink if there is one.
 
 /// A rectangle of interest which might touch a object in an incoming column.
-#[derive(Debug)]
-pub struct LiveBlock {
-    /// This block
-    region_data: RegionData,
-    /// Link to VizGroup
-    viz_group: Rc<RefCell<VizGroup>>,
-    /// Weak link to self
-    weak_link_to_self: WeakLiveBlockLink,
+#[derive(Debug, Clone)]
+struct LiveBlock {
+    /// Location in world (meters)
+    region_loc_x: u32,
+    /// Location in world (meters)
+    region_loc_y: u32,
+    /// Size (meters)
+    region_size_x: u32,
+    /// Size (meters)
+    region_size_y: u32,
+    /// Index of region in DisjointSets
+    ix: usize,
 }
 
-/// So we can have backpointers.
-type LiveBlockLink = Rc<RefCell<LiveBlock>>;
-/// Backpointers for LiveBlocks, so we can update them after merges.
-type WeakLiveBlockLink = Weak<RefCell<LiveBlock>>;
-
 impl LiveBlock {
     /// Usual new
-    pub fn new(
-        region_data: &RegionData,
-        completed_groups_weak: &Weak<RefCell<CompletedGroups>>,
-    ) -> Rc<RefCell<LiveBlock>> {
-        Rc::new_cyclic(|weak_self| {
-            RefCell::new(LiveBlock {
-                region_data: region_data.clone(),
-                viz_group: VizGroup::new(
-                    region_data.clone(),
-                    weak_self.clone(),
-                    completed_groups_weak,
-                ),
-                weak_link_to_self: weak_self.clone(),
-            })
-        })
-    }
-
-    /// Two live blocks touch.
-    /// Their VizGroup must be merged.
-    /// And all live blocks that use the VizGroup that was merged out must be redirected to the new combined group.
-    pub fn blocks_touch(&mut self, other: &LiveBlockLink) {
-        //  Merge VizGroup data of the two LiveBlock items.
-        //  At end, both share the same combined VizGroup, and the "other" VizGroup is dead, never to be used again.
-        if !Rc::ptr_eq(&self.viz_group, &other.borrow().viz_group) {
-            log::debug!(
-                "Blocks with different viz groups touch: {} and {}",
-                self.region_data,
-                other.borrow().region_data
-            );
-            //  Merge the VizGroup sets.
-            self.viz_group
-                .borrow_mut()
-                .merge(&mut other.borrow().viz_group.borrow_mut());
-
-            //  Tell all other involved LiveBlock items about this merge.
-            //  Cloning here clones a vector, but we have to get out from under those borrows.
-            let self_shared_groups = self.viz_group.borrow().live_blocks_weak.clone();
-            let other_shared_groups = other.borrow().viz_group.borrow().live_blocks_weak.clone();
-            //  Now here we have to avoid a double mutable borrow.
-            //  So there's a test to check that we're not trying to borrow the LiveBlock we are working on.
-            for weak_block in &self_shared_groups {
-                if !Weak::ptr_eq(&self.weak_link_to_self, weak_block) {
-                    if let Some(block) = weak_block.upgrade() {
-                        block.borrow_mut().viz_group = self.viz_group.clone();
-                    }
-                }
-            }
-            for weak_block in &other_shared_groups {
-                if !Weak::ptr_eq(&self.weak_link_to_self, weak_block) {
-                    if let Some(block) = weak_block.upgrade() {
-                        block.borrow_mut().viz_group = self.viz_group.clone();
-                    }
-                }
-            }
+    fn new(region_data: &RegionData, ix: usize) -> Self {
+        Self {
+            region_loc_x: region_data.region_loc_x,
+            region_loc_y: region_data.region_loc_y,
+            region_size_x: region_data.region_size_x,
+            region_size_y: region_data.region_size_y,
+            ix,
         }
     }
 
     /// y-adjacent - true if adjacent in y.
     /// Called while iterating over a single column.
-    fn y_adjacent(&self, bref: &LiveBlockLink, tolerance: u32) -> bool {
-        let b = bref.borrow();
-        assert!(self.region_data.region_loc_y <= b.region_data.region_loc_y); // ordered properly, a < b in Y
-        self.region_data.region_loc_y + self.region_data.region_size_y + tolerance
-            >= b.region_data.region_loc_y
+    fn y_adjacent(&self, b: &LiveBlock, tolerance: u32) -> bool {
+        assert!(self.region_loc_y <= b.region_loc_y); // ordered properly, a < b in Y
+        self.region_loc_y + self.region_size_y + tolerance >= b.region_loc_y
     }
 
     /// xy-adjacent - true if adjacent in x and y, on different columns.
     /// Called when iterating over two columns in sync.
-    fn xy_adjacent(&self, bref: &LiveBlockLink, tolerance: u32) -> bool {
-        let b = bref.borrow();
-        assert!(
-            self.region_data.region_loc_x + self.region_data.region_size_x
-                <= b.region_data.region_loc_x
-        ); // columns must be adjacent in X.
-           //  True if overlaps in Y.
-        let a0 = self.region_data.region_loc_y;
-        let a1 = a0 + self.region_data.region_size_y + tolerance;
-        let b0 = b.region_data.region_loc_y;
-        let b1 = b0 + b.region_data.region_size_y + tolerance;
+    /// A wide live block may reach into the new column, so they may also overlap in X.
+    fn xy_adjacent(&self, b: &LiveBlock, tolerance: u32) -> bool {
+        assert!(self.region_loc_x < b.region_loc_x); // live blocks are from earlier columns.
+        //  True if overlaps in Y.
+        let a0 = self.region_loc_y;
+        let a1 = a0 + self.region_size_y + tolerance;
+        let b0 = b.region_loc_y;
+        let b1 = b0 + b.region_size_y + tolerance;
         let overlap = a0 < b1 && a1 >= b0;
         log::trace!(
             "XY-adjacent test: overlap: ({}, {}) vs ({}, {}) overlap: {}",
@@ -170,126 +108,201 @@ impl LiveBlock {
         );
         overlap
     }
+
+    /// Area where two blocks overlap, not just touch. Zero if none.
+    fn intersection_area(&self, b: &LiveBlock) -> u64 {
+        let span = |a0: u32, asize: u32, b0: u32, bsize: u32| -> u64 {
+            let lo = a0.max(b0);
+            let hi = (a0 + asize).min(b0 + bsize);
+            hi.saturating_sub(lo) as u64
+        };
+        span(self.region_loc_x, self.region_size_x, b.region_loc_x, b.region_size_x)
+            * span(self.region_loc_y, self.region_size_y, b.region_loc_y, b.region_size_y)
+    }
 }
 
-/// An ordered sequence of LiveBlock items which might touch an object in an incoming column.
-/// When a new column comes in, any LiveBlock which doesn't reach that far is purged.
-//  Needs an ordered representation.
-struct LiveBlocks {
-    /// The blocks
-    live_blocks: BTreeMap<u32, LiveBlockLink>,
+/// Disjoint sets of regions. Union by size, with path compression.
+/// Indices are into a flat vector of regions, in arrival order.
+/// Size, live count, and members are valid only at the root of each set.
+#[derive(Debug, Default)]
+struct DisjointSets {
+    /// Parent of each element. Roots are their own parent.
+    parent: Vec<usize>,
+    /// Number of regions in the set.
+    size: Vec<usize>,
+    /// Number of LiveBlocks, including the current column, in the set.
+    live: Vec<usize>,
+    /// Regions in the set.
+    members: Vec<Vec<usize>>,
+    /// The regions. Taken when their group is delivered.
+    regions: Vec<Option<RegionData>>,
 }
 
-impl LiveBlocks {
-    /// Usual new
-    pub fn new() -> Self {
-        Self {
-            live_blocks: BTreeMap::new(),
+impl DisjointSets {
+    /// Add a region as a set of one, with one live block. Returns its index.
+    fn add(&mut self, region_data: RegionData) -> usize {
+        let ix = self.parent.len();
+        self.parent.push(ix);
+        self.size.push(1);
+        self.live.push(1);
+        self.members.push(vec![ix]);
+        self.regions.push(Some(region_data));
+        ix
+    }
+
+    /// Root of the set containing ix.
+    fn find(&mut self, ix: usize) -> usize {
+        let mut root = ix;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        //  Path compression
+        let mut n = ix;
+        while self.parent[n] != root {
+            let next = self.parent[n];
+            self.parent[n] = root;
+            n = next;
         }
+        root
     }
 
-    /// Purge all blocks whose X edge is below or equal to the limit.
-    /// This is all of them on SL, but larger regions on OS might be kept.
-    fn purge_below_x_limit(&mut self, x_limit: u32) {
-        self.live_blocks.retain(|_, v| {
-            let bk = v.borrow();
-            bk.region_data.region_loc_x + bk.region_data.region_size_x > x_limit
-        });
+    /// Merge the sets containing a and b.
+    fn union(&mut self, a: usize, b: usize) {
+        let (mut ra, mut rb) = (self.find(a), self.find(b));
+        if ra == rb {
+            return;
+        }
+        log::debug!("Groups touch: {} regions and {} regions", self.size[ra], self.size[rb]);
+        //  Smaller set goes into larger set.
+        if self.size[ra] < self.size[rb] {
+            std::mem::swap(&mut ra, &mut rb);
+        }
+        self.parent[rb] = ra;
+        self.size[ra] += self.size[rb];
+        self.live[ra] += self.live[rb];
+        let moved = std::mem::take(&mut self.members[rb]);
+        self.members[ra].extend(moved);
+    }
+
+    /// A live block of this set is gone.
+    /// If it was the last one, the set is complete, and its regions are returned.
+    fn release(&mut self, ix: usize) -> Option<Vec<RegionData>> {
+        let root = self.find(ix);
+        assert!(self.live[root] > 0, "Live block count underflow");
+        self.live[root] -= 1;
+        if self.live[root] > 0 {
+            return None;
+        }
+        let members = std::mem::take(&mut self.members[root]);
+        Some(members.into_iter().filter_map(|n| self.regions[n].take()).collect())
     }
 }
 
-/// A set of regions which all have the same viz group
-#[derive(Debug)]
-pub struct VizGroup {
-    /// All in this viz group are in this string.
-    pub grid: String,
-    /// Regions
-    /// Will probably change to a different data structure
+/// One completed viz group, with its extent, computed once when it completes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletedGroup {
+    /// The regions
     pub regions: Vec<RegionData>,
-    /// Backlink to LiveBlocks that use this VizGroup.
-    /// Used to tell the LiveBlock about a merge.
-    pub live_blocks_weak: Vec<Weak<RefCell<LiveBlock>>>,
-    /// Backlink to completed groups so they can be updated from drop.
-    completed_groups_weak: Weak<RefCell<CompletedGroups>>,
+    /// Lower left and upper right corners, meters.
+    pub bounds: ((u32, u32), (u32, u32)),
+    /// Sum of region areas, square meters. Less than the bounds' area unless the group is a rectangle.
+    pub total_area_m2: u64,
+    /// Which grid
+    pub grid: String,
 }
 
-impl Drop for VizGroup {
-    /// Drop happens when no live block is using this VizGroup.
-    /// Thus, that VizGroup is complete.
-    /// The group is delivered to VizGroups as done.
-    fn drop(&mut self) {
-        let completed_groups = self
-            .completed_groups_weak
-            .upgrade()
-            .expect("Unable to upgrade vizgroups");
-        log::debug!("Drop of VizGroup: {} regions", self.regions.len());
-        if !self.regions.is_empty() {
-            completed_groups.borrow_mut().push(self.regions.clone());
-        }
+impl CompletedGroup {
+    /// Usual new. An empty group has zero bounds.
+    pub fn new(regions: Vec<RegionData>) -> Self {
+        let bounds = group_bounds(&regions).unwrap_or_default();
+        let total_area_m2 = group_area(&regions);
+        let grid = regions.first().map(|r| r.grid.clone()).unwrap_or_default();
+        Self { regions, bounds, total_area_m2, grid }
     }
-}
 
-impl VizGroup {
-    /// New, with the first region, and a back link to the VizGroups
-    pub fn new(
-        region: RegionData,
-        live_block_weak: WeakLiveBlockLink,
-        completed_groups_weak: &Weak<RefCell<CompletedGroups>>,
-    ) -> Rc<RefCell<Self>> {
-        let new_item = Self {
-            grid: region.grid.clone(),
-            regions: vec![region],
-            live_blocks_weak: vec![live_block_weak],
-            completed_groups_weak: completed_groups_weak.clone(),
-        };
-        Rc::new(RefCell::new(new_item))
-    }
-    /// Merge another VizGroup into this one. The other group is drained and cannot be used again.
-    pub fn merge(&mut self, other: &mut VizGroup) {
-        assert_eq!(self.grid, other.grid);
-        //  Drop all dead blocks before merging.
-        other.live_blocks_weak.retain(|v| v.upgrade().is_some());
-        //  Do the merge,
-        self.live_blocks_weak.append(&mut other.live_blocks_weak);
-        <Vec<RegionData> as AsMut<Vec<RegionData>>>::as_mut(&mut self.regions)
-            .append(&mut other.regions);
-        log::debug!(
-            "Merged: {} live blocks weak, {} regions",
-            self.live_blocks_weak.len(),
-            self.regions.len()
-        );
+    /// The regions
+    pub fn regions(&self) -> &[RegionData] {
+        &self.regions
     }
 }
 
 /// Array of completed groups for one grid.
-pub type CompletedGroups = Vec<Vec<RegionData>>;
+pub type CompletedGroups = Vec<CompletedGroup>;
+
+/// Sort key for the canonical group order: biggest first, then by the lowest (x, y) in the group.
+/// Groups are disjoint, so no two have the same key, and the order is total.
+/// The group need not be sorted.
+pub fn group_order_key(group: &[RegionData]) -> (std::cmp::Reverse<usize>, Option<(u32, u32)>) {
+    (std::cmp::Reverse(group.len()), group.iter().map(|r| (r.region_loc_x, r.region_loc_y)).min())
+}
+
+/// Put completed groups into canonical order.
+/// Groups come out of the transitive closure in an order which depends on merge history,
+/// so identical input can produce differently ordered output.
+/// Regions within a group are sorted by (x, y).
+/// Groups are sorted by group_order_key.
+pub fn canonicalize_groups(groups: &mut CompletedGroups) {
+    for group in groups.iter_mut() {
+        group.regions.sort_by_key(|r| (r.region_loc_x, r.region_loc_y));
+    }
+    groups.sort_by_key(|g| group_order_key(g.regions()));
+}
 
 /// Vizgroups - find all the visibility groups
 pub struct VizGroups {
     /// The active column
-    column: Vec<Rc<RefCell<LiveBlock>>>,
+    column: Vec<LiveBlock>,
     /// Previous region data while inputting a column
     prev_region_data: Option<RegionData>,
     /// Live blocks. The blocks that touch or pass the current column.
     /// Ordered by Y.
-    live_blocks: LiveBlocks,
-    /// Completed groups. This is the output from transitive closure.
+    live_blocks: BTreeMap<u32, LiveBlock>,
+    /// All the regions of this grid, as disjoint sets.
+    sets: DisjointSets,
+    /// Completed groups. This is the output from transitive closure, if there is no sink.
     /// No ordering
-    completed_groups: Rc<RefCell<CompletedGroups>>,
+    completed_groups: CompletedGroups,
+    /// Sink for completed groups.
+    sink: Option<Box<dyn FnMut(CompletedGroup)>>,
+    /// Overlapping regions found so far.
+    overlaps: Vec<OverlapReport>,
+    /// Biggest region size in Y so far. Bounds the search for live blocks which reach a new block.
+    max_size_y: u32,
     /// Tolerance. 0 or 1. 1 expands regions 1 unit for the overlap test.
     /// This makes corner adjacency work for Open Simulator
     tolerance: u32,
+    /// Regions from add_unsorted, waiting for finish_unsorted.
+    unsorted: Vec<RegionData>,
 }
 
 impl VizGroups {
-    /// Usual new
+    /// Usual new. Completed groups are returned by end_grid.
+    #[allow(dead_code)] // the generator uses new_with_sink, tests use this.
     pub fn new(detect_corners_touching: bool) -> Self {
+        Self::new_with_output(detect_corners_touching, None)
+    }
+
+    /// New, with a sink which gets each group as soon as it is complete.
+    /// A group is complete when the input column has passed all its regions.
+    /// With a sink, end_grid returns nothing; it just flushes the remaining groups to the sink.
+    /// This avoids holding all the completed groups of a large grid at once.
+    pub fn new_with_sink(detect_corners_touching: bool, sink: impl FnMut(CompletedGroup) + 'static) -> Self {
+        Self::new_with_output(detect_corners_touching, Some(Box::new(sink)))
+    }
+
+    /// Common part of new.
+    fn new_with_output(detect_corners_touching: bool, sink: Option<Box<dyn FnMut(CompletedGroup)>>) -> Self {
         Self {
             column: Vec::new(),
             prev_region_data: None,
-            completed_groups: Rc::new(RefCell::new(Vec::new())),
-            live_blocks: LiveBlocks::new(),
+            live_blocks: BTreeMap::new(),
+            sets: DisjointSets::default(),
+            completed_groups: Vec::new(),
+            sink,
+            overlaps: Vec::new(),
+            max_size_y: 0,
             tolerance: if detect_corners_touching { 1 } else { 0 },
+            unsorted: Vec::new(),
         }
     }
 
@@ -298,40 +311,75 @@ impl VizGroups {
     pub fn clear(&mut self) {
         self.column = Vec::new();
         self.prev_region_data = None;
-        self.completed_groups = Rc::new(RefCell::new(Vec::new()));
-        self.live_blocks = LiveBlocks::new();
+        self.live_blocks = BTreeMap::new();
+        self.sets = DisjointSets::default();
+        self.completed_groups.clear();
+        self.max_size_y = 0;
+    }
+
+    /// Overlapping regions found so far. Not cleared by end_grid, so call this after it.
+    pub fn take_overlaps(&mut self) -> Vec<OverlapReport> {
+        std::mem::take(&mut self.overlaps)
+    }
+
+    /// Check two blocks for overlap, and report if they do.
+    fn check_overlap(&mut self, a: &LiveBlock, b: &LiveBlock) {
+        let area = a.intersection_area(b);
+        if area > 0 {
+            if let (Some(a_data), Some(b_data)) = (&self.sets.regions[a.ix], &self.sets.regions[b.ix]) {
+                let report = OverlapReport { a: a_data.clone(), b: b_data.clone(), area };
+                log::warn!("Overlapping regions: {}", report);
+                self.overlaps.push(report);
+            }
+        }
+    }
+
+    /// A live block is gone. Deliver its group if that was the last one.
+    fn release(&mut self, block: &LiveBlock) {
+        if let Some(mut group) = self.sets.release(block.ix) {
+            log::debug!("Completed viz group: {} regions", group.len());
+            group.sort_by_key(|r| (r.region_loc_x, r.region_loc_y));
+            let group = CompletedGroup::new(group);
+            if let Some(sink) = &mut self.sink {
+                sink(group);
+            } else {
+                self.completed_groups.push(group);
+            }
+        }
+    }
+
+    /// Purge all blocks whose X edge is below or equal to the limit.
+    /// This is all of them on SL, but larger regions on OS might be kept.
+    fn purge_below_x_limit(&mut self, x_limit: u32) {
+        let (keep, purge): (BTreeMap<u32, LiveBlock>, BTreeMap<u32, LiveBlock>) = std::mem::take(&mut self.live_blocks)
+            .into_iter()
+            .partition(|(_, b)| b.region_loc_x + b.region_size_x > x_limit);
+        self.live_blocks = keep;
+        for block in purge.values() {
+            self.release(block);
+        }
     }
 
     /// Check the current and previous live block lists.
-    /// They're both sequential in y.
+    /// Each new block is checked against every live block which might reach it in Y.
+    /// A tall live block, such as an Open Simulator varregion, can touch several new blocks.
     fn check_overlap_live_block_columns(&mut self) {
-        //  Create iterators for existing live blocks and new column.
-        let mut prev_iter = self.live_blocks.live_blocks.iter_mut();
-        let mut prev_opt = prev_iter.next();
-        let mut curr_iter = self.column.iter_mut();
-        let mut curr_opt = curr_iter.next();
-        loop {
-            if let Some(ref mut prev) = prev_opt {
-                if let Some(ref mut curr) = curr_opt {
-                    //  Test if we want to merge viz groups
-                    if prev.1.borrow().xy_adjacent(curr, self.tolerance) {
-                        prev.1.borrow_mut().blocks_touch(curr)
-                    }
-
-                    if curr.borrow().region_data.region_loc_y
-                        < prev.1.borrow().region_data.region_loc_y
-                    {
-                        curr_opt = curr_iter.next();
-                    } else {
-                        prev_opt = prev_iter.next();
-                    }
-                } else {
-                    break;
+        //  Collect the touching pairs first, then merge.
+        let mut touching = Vec::new();
+        for curr in &self.column {
+            let y_low = curr.region_loc_y.saturating_sub(self.max_size_y + self.tolerance);
+            let y_high = curr.region_loc_y.saturating_add(curr.region_size_y + self.tolerance);
+            for prev in self.live_blocks.range(y_low..=y_high).map(|(_, b)| b) {
+                //  Test if we want to merge viz groups
+                if prev.xy_adjacent(curr, self.tolerance) {
+                    touching.push((prev.clone(), curr.clone()));
                 }
-            } else {
-                break;
             }
         }
+        for (a, b) in touching {
+            self.check_overlap(&a, &b);
+            self.sets.union(a.ix, b.ix);
+        }
     }
 
     /// End of a column.
@@ -339,24 +387,22 @@ impl VizGroups {
     /// Each entry in the new column has to be compared with the
     /// live blocks to check for overlap/touching, and with adjacent
     /// entries in the column to check for overlap/touching.
-    /// Eacn new column entry creates a new VizGroup.
-    /// Overlapped/touching groups get their VizGroups merged.
+    /// Each new column entry starts as a set of one.
+    /// Overlapped/touching sets are merged.
     fn end_column(&mut self) {
-        //  If two live blocks in this list overlap, merge their viz groups.
+        //  If two live blocks in this list overlap, merge their sets.
         //  This is the check for overlap in Y.
-        let mut prev_opt: Option<Rc<RefCell<LiveBlock>>> = None;
-        for item in &mut self.column {
-            if let Some(prev) = prev_opt {
-                assert!(
-                    prev.borrow().region_data.region_loc_y
-                        <= item.borrow().region_data.region_loc_y,
-                    "VizGroup data not sorted into increasing order in Y"
-                );
-                if prev.borrow().y_adjacent(item, self.tolerance) {
-                    prev.borrow_mut().blocks_touch(item)
-                }
+        for n in 1..self.column.len() {
+            let (prev, item) = (&self.column[n - 1], &self.column[n]);
+            assert!(
+                prev.region_loc_y <= item.region_loc_y,
+                "VizGroup data not sorted into increasing order in Y"
+            );
+            if prev.y_adjacent(item, self.tolerance) {
+                let (a, b) = (prev.clone(), item.clone());
+                self.check_overlap(&a, &b);
+                self.sets.union(a.ix, b.ix);
             }
-            prev_opt = Some(item.clone());
         }
         //  Next, need the check for overlap in X, between existing live blocks
         //  and new live blocks
@@ -368,29 +414,31 @@ impl VizGroups {
         log::debug!("End column. {} regions.", self.column.len());
         if !self.column.is_empty() {
             //  Purge now-dead live blocks. This will be all of them on SL, but wide regions on OS may not be ready to die yet.
-            let x_limit = self.column[0].borrow().region_data.region_loc_x;
-            self.live_blocks.purge_below_x_limit(x_limit);
+            let x_limit = self.column[0].region_loc_x;
+            self.purge_below_x_limit(x_limit);
             //  Add new live blocks.
             //  Put all the blocks in the column into the B-tree of live blocks.
-            while let Some(b) = self.column.pop() {
-                let y = b.borrow().region_data.region_loc_y;
-                self.live_blocks.live_blocks.insert(y, b);
+            //  A block displaced from the same Y position is dead.
+            for b in std::mem::take(&mut self.column) {
+                if let Some(displaced) = self.live_blocks.insert(b.region_loc_y, b) {
+                    self.release(&displaced);
+                }
             }
-            log::debug!("{} live blocks", self.live_blocks.live_blocks.len());
-            assert!(self.column.is_empty());
+            log::debug!("{} live blocks", self.live_blocks.len());
         }
         self.column.clear();
     }
 
-    /// End of input for one grid. Returns completed groups.
+    /// End of input for one grid. Returns completed groups, in canonical order.
     pub fn end_grid(&mut self) -> CompletedGroups {
         //  Finish last column
         self.end_column();
         //  Flush all waiting live blocks.
-        self.live_blocks.purge_below_x_limit(u32::MAX);
+        self.purge_below_x_limit(u32::MAX);
         log::info!("End grid.");
-        let result = self.completed_groups.take();
+        let mut result = std::mem::take(&mut self.completed_groups);
         self.clear();
+        canonicalize_groups(&mut result);
         result
     }
 
@@ -413,13 +461,51 @@ impl VizGroups {
             }
         };
         //  Add to column, or start new column.
-        self.column.push(LiveBlock::new(
-            &region_data,
-            &Rc::<RefCell<Vec<Vec<RegionData>>>>::downgrade(&self.completed_groups),
-        ));
+        self.max_size_y = self.max_size_y.max(region_data.region_size_y);
+        let ix = self.sets.add(region_data.clone());
+        self.column.push(LiveBlock::new(&region_data, ix));
         self.prev_region_data = Some(region_data);
         result
     }
+
+    /// Add one item of region data, in any order.
+    /// Held until finish_unsorted. Don't mix with add_region_data.
+    #[allow(dead_code)] // for region sources with no ORDER BY
+    pub fn add_unsorted(&mut self, region_data: RegionData) {
+        self.unsorted.push(region_data);
+    }
+
+    /// End of unsorted input. Sorts what add_unsorted got and runs it through add_region_data.
+    /// Returns the groups of each grid, in grid order, the same as for sorted input.
+    #[allow(dead_code)] // for region sources with no ORDER BY
+    pub fn finish_unsorted(&mut self) -> Vec<CompletedGroups> {
+        let mut results = Vec::new();
+        for region_data in sort_region_data(std::mem::take(&mut self.unsorted)) {
+            results.extend(self.add_region_data(region_data));
+        }
+        if self.prev_region_data.is_some() {
+            results.push(self.end_grid());
+        }
+        results
+    }
+}
+
+/// Sort regions into the order add_region_data needs, by grid, X, Y.
+/// Exact duplicates are dropped, with a warning.
+pub fn sort_region_data(mut regions: Vec<RegionData>) -> Vec<RegionData> {
+    //  All fields in the key, so exact duplicates end up next to each other.
+    regions.sort_by(|a, b| {
+        (&a.grid, a.region_loc_x, a.region_loc_y, a.region_size_x, a.region_size_y, &a.name, a.lod, &a.children, a.is_water, a.is_placeholder)
+            .cmp(&(&b.grid, b.region_loc_x, b.region_loc_y, b.region_size_x, b.region_size_y, &b.name, b.lod, &b.children, b.is_water, b.is_placeholder))
+    });
+    regions.dedup_by(|region, prev| {
+        let duplicate = region == prev;
+        if duplicate {
+            log::warn!("Duplicate region {} in grid \"{}\" dropped.", region, region.grid);
+        }
+        duplicate
+    });
+    regions
 }
 
 /// Unit test data. Available for tests in other modules.
@@ -512,6 +598,9 @@ pub fn vizgroup_test_patterns() -> Vec<Vec<RegionData>> {
                         region_size_y: *region_size_y,
                         lod: 0,
                         name: name.to_string(),
+                        children: Vec::new(),
+                        is_water: false,
+                        is_placeholder: false,
                     },
                 )
                 .collect()
@@ -540,3 +629,234 @@ fn test_vizgroup() {
     }
     assert_eq!(results.len(), 3); // 3 groups in this test case.
 }
+
+#[test]
+fn test_vizgroup_canonical() {
+    //  Same input, fed twice, fed after another grid, and fed in pages of any size, must produce identical output.
+    use crate::regionpages::GridRegions;
+    use common::db::{DbRow, DbValue, FakeDb};
+    fn run(viz_groups: &mut VizGroups, data: &[RegionData]) -> Option<CompletedGroups> {
+        let mut grid_break = None;
+        for item in data {
+            if let Some(groups) = viz_groups.add_region_data(item.clone()) {
+                grid_break = Some(groups);
+            }
+        }
+        grid_break
+    }
+    fn viz_groups_from(data: &[RegionData]) -> CompletedGroups {
+        let mut viz_groups = VizGroups::new(false);
+        run(&mut viz_groups, data);
+        viz_groups.end_grid()
+    }
+    for test_data in vizgroup_test_patterns() {
+        let mut viz_groups = VizGroups::new(false);
+        run(&mut viz_groups, &test_data);
+        let first = format!("{:?}", viz_groups.end_grid());
+        run(&mut viz_groups, &test_data);
+        let second = format!("{:?}", viz_groups.end_grid());
+        assert_eq!(first, second);
+        //  Preceded by another grid, so this grid starts at a control break.
+        let other_grid: Vec<RegionData> = test_data.iter().map(|r| RegionData { grid: "Other".to_string(), ..r.clone() }).collect();
+        let mut viz_groups = VizGroups::new(false);
+        run(&mut viz_groups, &other_grid);
+        let other = run(&mut viz_groups, &test_data).expect("grid break");
+        assert_eq!(other.len(), 3);
+        let third = format!("{:?}", viz_groups.end_grid());
+        assert_eq!(first, third);
+        //  Read a page at a time, as the generator reads the grid. One query result per page,
+        //  and an empty one after a full last page.
+        let row = |r: &RegionData| DbRow(vec![DbValue::text(&r.grid), DbValue::UInt(r.region_loc_x as u64), DbValue::UInt(r.region_loc_y as u64),
+            DbValue::UInt(r.region_size_x as u64), DbValue::UInt(r.region_size_y as u64), DbValue::text(&r.name)]);
+        for page_size in [1, 4, 7, test_data.len(), test_data.len() + 1] {
+            let mut pages: Vec<Vec<DbRow>> = test_data.chunks(page_size).map(|page| page.iter().map(row).collect()).collect();
+            if test_data.len() % page_size == 0 {
+                pages.push(Vec::new());
+            }
+            let mut fake = FakeDb::new_with_results(pages);
+            let mut regions = GridRegions::new("test", crate::read_grid_regions, None, page_size);
+            let mut viz_groups = VizGroups::new(false);
+            while let Some(region) = regions.next(&mut fake).expect("regions") {
+                viz_groups.add_region_data(region);
+            }
+            assert_eq!(format!("{:?}", viz_groups.end_grid()), first, "page size {}", page_size);
+        }
+        //  Canonical order
+        let groups = viz_groups_from(&test_data);
+        assert!(groups.windows(2).all(|w| w[0].regions.len() >= w[1].regions.len()));
+        assert!(groups.iter().all(|g| g.regions.windows(2).all(|w| (w[0].region_loc_x, w[0].region_loc_y) < (w[1].region_loc_x, w[1].region_loc_y))));
+    }
+}
+
+#[test]
+fn test_vizgroup_unsorted() {
+    //  Any arrival order, with duplicates, gives the same groups as sorted input.
+    let capture = common::test_logger_capture();
+    for test_data in vizgroup_test_patterns() {
+        let mut viz_groups = VizGroups::new(false);
+        for item in test_data.clone() {
+            viz_groups.add_region_data(item);
+        }
+        let sorted = format!("{:?}", viz_groups.end_grid());
+        let n = test_data.len();
+        let reversed: Vec<RegionData> = test_data.iter().rev().cloned().collect();
+        //  7 is prime to 25, so this visits each region once.
+        let strided: Vec<RegionData> = (0..n).map(|i| test_data[(i * 7) % n].clone()).collect();
+        let duplicated: Vec<RegionData> = test_data.iter().chain(test_data[..5].iter()).rev().cloned().collect();
+        for shuffled in [reversed, strided, duplicated] {
+            for item in shuffled {
+                viz_groups.add_unsorted(item);
+            }
+            let results = viz_groups.finish_unsorted();
+            assert_eq!(results.len(), 1);
+            assert_eq!(format!("{:?}", results[0]), sorted);
+        }
+    }
+    assert!(capture.contains(log::Level::Warn, "Duplicate region"));
+    //  Two grids, interleaved, come out one per grid, in grid order.
+    let test_data = vizgroup_test_patterns()[0].clone();
+    let mut viz_groups = VizGroups::new(false);
+    for item in test_data.iter().rev() {
+        viz_groups.add_unsorted(RegionData { grid: "Other".to_string(), ..item.clone() });
+        viz_groups.add_unsorted(item.clone());
+    }
+    let results = viz_groups.finish_unsorted();
+    assert_eq!(results.iter().map(|groups| (groups[0].grid.as_str(), groups.len())).collect::<Vec<_>>(), vec![("Other", 3), ("Test", 3)]);
+    assert!(viz_groups.finish_unsorted().is_empty());
+}
+
+#[test]
+fn test_group_order() {
+    fn group(locs: &[(u32, u32)]) -> Vec<RegionData> {
+        locs.iter().map(|&(x, y)| common::test_region(&format!("R{}-{}", x, y), x, y, 256)).collect()
+    }
+    //  Equal sized groups are ordered by their lowest (x, y), sorted or not.
+    let a = group(&[(512, 0), (0, 256)]);
+    let b = group(&[(0, 512), (256, 512)]);
+    let c = group(&[(1024, 0), (1024, 256), (1024, 512)]);
+    assert!(group_order_key(&a) < group_order_key(&b));
+    assert!(group_order_key(&c) < group_order_key(&a));
+    //  Every arrival order comes out the same.
+    let mut expected = None;
+    for order in [[0, 1, 2], [2, 1, 0], [1, 0, 2], [1, 2, 0]] {
+        let mut groups: CompletedGroups = order.iter().map(|&n| CompletedGroup::new([&a, &b, &c][n].clone())).collect();
+        canonicalize_groups(&mut groups);
+        let names: Vec<Vec<String>> = groups.iter().map(|g| g.regions.iter().map(|r| r.name.clone()).collect()).collect();
+        assert_eq!(names[0], vec!["R1024-0", "R1024-256", "R1024-512"]);
+        assert_eq!(names[1], vec!["R0-256", "R512-0"]);
+        assert_eq!(*expected.get_or_insert(names.clone()), names);
+    }
+}
+
+#[test]
+fn test_vizgroup_sink() {
+    //  Groups must arrive through the sink as soon as the column has passed them.
+    use std::sync::mpsc::channel;
+    let (sender, receiver) = channel();
+    let mut viz_groups = VizGroups::new_with_sink(false, move |group| sender.send(group).expect("send"));
+    let test_data = vizgroup_test_patterns()[0].clone();
+    let mut arrived_early = 0;
+    for item in test_data {
+        //  Past x = 400, the "Tiny" group at x = 200..400 is complete.
+        //  Past x = 800, so is the "Tall skinny" group.
+        let past_tiny = item.region_loc_x >= 500;
+        assert_eq!(viz_groups.add_region_data(item), None);
+        let arrived: Vec<_> = receiver.try_iter().collect();
+        if past_tiny {
+            arrived_early += arrived.len();
+        } else {
+            assert!(arrived.is_empty());
+        }
+    }
+    assert_eq!(arrived_early, 2);
+    assert!(viz_groups.end_grid().is_empty());
+    let remaining: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(remaining.len(), 1);
+}
+
+#[test]
+fn test_completed_group_stats() {
+    use common::test_region;
+    //  Open Simulator varregions of mixed sizes, and one 256 island off by itself.
+    let mut viz_groups = VizGroups::new(false);
+    for item in [test_region("Var512", 0, 0, 512), test_region("East", 512, 0, 256), test_region("NorthEast", 512, 256, 256),
+            test_region("Var1024", 768, 0, 1024), test_region("Island", 4096, 4096, 256)] {
+        viz_groups.add_region_data(item);
+    }
+    let groups = viz_groups.end_grid();
+    assert_eq!(groups.len(), 2);
+    assert_eq!(groups[0].regions.len(), 4);
+    assert_eq!(groups[0].bounds, ((0, 0), (1792, 1024)));
+    assert_eq!(groups[0].total_area_m2, 512 * 512 + 2 * 256 * 256 + 1024 * 1024);
+    assert_eq!(groups[0].grid, "test");
+    assert_eq!((groups[1].bounds, groups[1].total_area_m2), (((4096, 4096), (4352, 4352)), 256 * 256));
+    //  The same through the sink.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut viz_groups = VizGroups::new_with_sink(false, move |group| sender.send(group).expect("send"));
+    for item in groups.iter().flat_map(|group| group.regions().to_vec()) {
+        viz_groups.add_region_data(item);
+    }
+    viz_groups.end_grid();
+    let mut sunk: CompletedGroups = receiver.try_iter().collect();
+    canonicalize_groups(&mut sunk);
+    assert_eq!(sunk, groups);
+    //  Empty, which the closure never makes.
+    let empty = CompletedGroup::new(Vec::new());
+    assert_eq!((empty.bounds, empty.total_area_m2, empty.grid.as_str()), (((0, 0), (0, 0)), 0, ""));
+}
+
+#[test]
+fn test_vizgroup_large_grid() {
+    //  200 x 200 contiguous regions. One big group.
+    //  Merging must not cost O(group size) per merge, or this takes forever.
+    const SIDE: u32 = 200;
+    let start = std::time::Instant::now();
+    let mut viz_groups = VizGroups::new(false);
+    for x in 0..SIDE {
+        for y in 0..SIDE {
+            viz_groups.add_region_data(common::test_region(&format!("R{}-{}", x, y), x * 256, y * 256, 256));
+        }
+    }
+    let results = viz_groups.end_grid();
+    let elapsed = start.elapsed();
+    log::debug!("{} regions in {:?}", SIDE * SIDE, elapsed);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].regions.len(), (SIDE * SIDE) as usize);
+    assert_eq!(results[0].bounds, ((0, 0), (SIDE * 256, SIDE * 256)));
+    assert_eq!(results[0].total_area_m2, (SIDE * SIDE) as u64 * 256 * 256);
+    assert!(elapsed.as_secs() < 30);
+}
+
+#[test]
+fn test_vizgroup_overlaps() {
+    use common::test_region;
+    fn overlaps_of(data: Vec<RegionData>) -> (CompletedGroups, Vec<OverlapReport>) {
+        let mut viz_groups = VizGroups::new(false);
+        for item in data {
+            viz_groups.add_region_data(item);
+        }
+        let groups = viz_groups.end_grid();
+        (groups, viz_groups.take_overlaps())
+    }
+    let capture = common::test_logger_capture();
+    //  Touching only. No overlaps.
+    let (_, overlaps) = overlaps_of(vizgroup_test_patterns()[0].clone());
+    assert!(overlaps.is_empty());
+    assert!(!capture.contains(log::Level::Warn, "Overlapping regions"));
+    //  Same column, second region starts inside the first.
+    let (groups, overlaps) = overlaps_of(vec![test_region("A", 0, 0, 256), test_region("B", 0, 128, 256)]);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!((overlaps[0].a.name.as_str(), overlaps[0].b.name.as_str(), overlaps[0].area), ("A", "B", 128 * 256));
+    assert!(capture.contains(log::Level::Warn, "Overlapping regions: "), "{:?}", capture.records());
+    //  Cross column. Bigger region on the left reaches into the next column.
+    let (groups, overlaps) = overlaps_of(vec![test_region("Wide", 0, 0, 384), test_region("C", 256, 256, 256)]);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].area, 128 * 128);
+    //  Varregion over small regions. 512 varregion covers three of the 256 regions.
+    let (groups, overlaps) = overlaps_of(vec![test_region("Var", 0, 0, 512), test_region("S1", 0, 256, 256), test_region("S2", 256, 0, 256), test_region("S3", 256, 256, 256)]);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(overlaps.len(), 3);
+    assert!(overlaps.iter().all(|o| o.a.name == "Var" && o.area == 256 * 256));
+}
@@ -0,0 +1,147 @@
+//! watertiles.rs -- impostors for lower LOD tiles with no land.
+//! Part of the Animats impostor system
+//!
+//! Oceans and lakes inside a viz group's bounds have no regions, so
+//! without these there is a hole in the impostors at every LOD.
+//! Water tiles are flat, at water level, with a plain water texture.
+//! They need no terrain from SQL.
+//!
+//! All water tiles of the same size, LOD, and water level look the same,
+//! so they share one sculpt and one texture. The asset names don't
+//! contain a location or viz group, so the assets are only generated
+//! and uploaded once.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     December, 2025.
+//
+use anyhow::Error;
+use common::HeightField;
+use image::RgbImage;
+use std::collections::HashMap;
+use crate::sculptmaker::{TerrainSculpt, TerrainSculptTexture};
+use common::RegionData;
+
+/// Water level when there's no LOD 0 terrain to get it from. Second Life standard.
+pub const DEFAULT_WATER_LEVEL: f32 = 20.0;
+
+/// Flat height field at water level, for a water tile.
+pub fn water_height_field(region: &RegionData, water_level: f32) -> Result<HeightField, Error> {
+    //  Smallest useful height field. It's flat, so more samples add nothing.
+    const SAMPLES: u32 = 3;
+    Ok(HeightField::new_from_elevs_blob(
+        &vec![0; (SAMPLES * SAMPLES) as usize],
+        SAMPLES,
+        SAMPLES,
+        region.region_size_x,
+        region.region_size_y,
+        0.0,
+        water_level,
+        water_level,
+    )?)
+}
+
+/// The shared assets for one kind of water tile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaterTile {
+    /// Sculpt asset name
+    pub sculpt_name: String,
+    /// Sculpt image hash
+    pub sculpt_hash: u32,
+    /// Texture asset name
+    pub texture_name: String,
+    /// Texture image hash
+    pub texture_hash: u32,
+}
+
+/// Images for a water tile, to be saved for upload.
+#[derive(Debug)]
+pub struct WaterTileImages {
+    /// Sculpt image
+    pub sculpt: RgbImage,
+    /// Texture image
+    pub texture: RgbImage,
+}
+
+/// Water tiles made so far, so each kind is made only once.
+#[derive(Debug)]
+pub struct WaterTileAssets {
+    /// Sculpt image size, pixels on a side.
+    sculpt_dim: usize,
+    /// Texture image size, pixels on a side.
+    texture_size: u32,
+    /// Water tiles made so far, by size, LOD, and water level.
+    tiles: HashMap<String, WaterTile>,
+}
+
+impl WaterTileAssets {
+    /// Usual new
+    pub fn new(sculpt_dim: usize, texture_size: u32) -> Self {
+        Self {
+            sculpt_dim,
+            texture_size,
+            tiles: HashMap::new(),
+        }
+    }
+
+    /// Assets for this water tile.
+    /// Images are returned only the first time a kind of water tile is seen.
+    /// After that, the caller already has them.
+    pub fn get(&mut self, region: &RegionData, water_level: f32) -> Result<(WaterTile, Option<WaterTileImages>), Error> {
+        const WATER_SCULPT_PREFIX: &str = "RSW";
+        const WATER_TEXTURE_PREFIX: &str = "RTW";
+        let kind = format!("{}_{}_{}_{:.2}", region.region_size_x, region.region_size_y, region.lod, water_level);
+        if let Some(water_tile) = self.tiles.get(&kind) {
+            return Ok((water_tile.clone(), None));
+        }
+        //  New kind of water tile. Make the images.
+        let height_field = water_height_field(region, water_level)?;
+        let mut terrain_sculpt = TerrainSculpt::new(&region.name, self.sculpt_dim);
+        let (scale, offset, elevs) = height_field.into_sculpt_array()?;
+        terrain_sculpt.setelevs(elevs, scale as f64, offset as f64);
+        terrain_sculpt.makeimage();
+        let sculpt_hash = terrain_sculpt.get_hash()?;
+        let mut texture = TerrainSculptTexture::new(region.region_loc_x, region.region_loc_y, region.lod, &region.name);
+        texture.make_water_image(self.texture_size);
+        let texture_hash = texture.get_hash()?;
+        let water_tile = WaterTile {
+            sculpt_name: format!("{}_{}_{:08x}", WATER_SCULPT_PREFIX, kind, sculpt_hash),
+            sculpt_hash,
+            texture_name: format!("{}_{}_{:08x}", WATER_TEXTURE_PREFIX, kind, texture_hash),
+            texture_hash,
+        };
+        self.tiles.insert(kind, water_tile.clone());
+        let images = WaterTileImages {
+            sculpt: terrain_sculpt.image.expect("Sculpt image was just made"),
+            texture: texture.image.expect("Texture image was just made"),
+        };
+        Ok((water_tile, Some(images)))
+    }
+}
+
+#[test]
+fn test_water_tile_dedup() {
+    fn water(x: u32, y: u32, lod: u8) -> RegionData {
+        RegionData { lod, is_water: true, ..common::test_region(&format!("LOD{}-{}-{} Water", lod, x, y), x, y, 256 << lod) }
+    }
+    let mut assets = WaterTileAssets::new(8, 16);
+    //  First one makes the images.
+    let (first, images) = assets.get(&water(512, 512, 1), DEFAULT_WATER_LEVEL).expect("water tile");
+    assert!(images.is_some());
+    //  The same kind of tile somewhere else reuses them.
+    for (x, y) in [(512, 1024), (1024, 512), (1024, 1024)] {
+        let (other, images) = assets.get(&water(x, y, 1), DEFAULT_WATER_LEVEL).expect("water tile");
+        assert!(images.is_none());
+        assert_eq!(other, first);
+    }
+    //  A different LOD is a different asset, but the images are the same.
+    let (lod_2, images) = assets.get(&water(0, 0, 2), DEFAULT_WATER_LEVEL).expect("water tile");
+    assert!(images.is_some());
+    assert_ne!(lod_2.sculpt_name, first.sculpt_name);
+    assert_eq!(lod_2.sculpt_hash, first.sculpt_hash);
+    assert_eq!(lod_2.texture_hash, first.texture_hash);
+    //  Water height field is flat, at water level.
+    let height_field = water_height_field(&water(0, 0, 1), DEFAULT_WATER_LEVEL).expect("water height field");
+    assert_eq!(height_field.get_scale_offset().expect("scale offset"), (common::MIN_ELEV_SCALE, DEFAULT_WATER_LEVEL));
+    assert_eq!((height_field.size_x, height_field.size_y), (512, 512));
+}
@@ -17,8 +17,64 @@
 //!
 //! Returns info for an entire grid. Mostly for test purposes.
 //!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&stale_days=NNN
+//!
+//! Returns the regions whose raw terrain hasn't been uploaded or confirmed
+//! in that many days, or has no elevations, so the survey bot's operator
+//! can plan routes.
+//!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&mode=coverage
+//!
+//! Returns, for each LOD, the bounding box of the tiles with impostors and
+//! a run-length encoded bitmap of which tiles are populated. See common::coverage.
+//! Cached for a minute, so polling it is cheap.
+//!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&mode=retired&since=YYYY-MM-DD
+//!
+//! Returns the impostor assets retired on a grid at or after since, a date or
+//! "YYYY-MM-DD HH:MM:SS", UTC, for the in-world cleanup script to delete.
+//! Without since, everything retired. See common::retiredassets.
+//! Since this list drives deletion, the request must be signed, as uploads are,
+//! but over the query string, with a nonce and sent_at, as debug requests are. 401 if not.
+//!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&mode=debug&x=NNN&y=NNN
+//!
+//! Returns the impostors at one region location, all LODs, with the provenance
+//! recorded by the generator: its version, settings, and the raw terrain used.
+//! Provenance is for operators, and is never in the viewer replies. This request
+//! must be signed over the query string by an admin token, and the query must
+//! include nonce=NONCE&sent_at=UNIXTIME, as signed uploads do, so a captured
+//! request can't be sent again. See common::replayguard. 401 if not.
+//!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&viz_group=NNN&changes_since=SINCE
+//!
+//! Returns the impostors in a visibility group added, replaced, or removed since
+//! SINCE, which is a generation number, or a date or "YYYY-MM-DD HH:MM:SS", UTC.
+//! At most the latest 1000. See common::impostorchanges.
+//!
+//! Replies to the plain impostor requests, by region, viz group, or grid, are
+//! cached, and have an ETag, so an unchanged reply gets a 304. A cached reply is
+//! reused until the grid gets new impostors. See common::responsecache.
+//!
+//! Any of the impostor requests can add version=N, to get that version of the reply format.
+//! The default is version 1. Unsupported versions get a 400 error.
+//!
+//!     https://animats.info/actions/downloadimpostor.fcgi?grid=NAME&x=NNN&y=NNN&format=heights
+//!
+//! Returns one region's raw terrain, for viewers which build their own terrain meshes, as
+//!
+//!     {"grid":"agni","region_coords":[1000,1000],"size":[256,256],"samples":[65,65],
+//!      "scale":25.5,"offset":20.0,"water_level":20.0,"elevs":["0A0B...",...]}
+//!
+//! elevs is one hex string per X, as uploaded. Byte b is offset + scale * b / 255,
+//! so scale is not the uploader's scale. format=png16 returns the same terrain
+//! as a 16-bit grayscale PNG instead. Either way, 404 if there's no such region.
+//! Terrain replies have an ETag, so an unchanged region gets a 304.
+//! Each client may only make so many terrain requests. Past that is a 429,
+//! with Retry-After. See common::ratelimit.
+//!
 //! Data is returned as JSON. Format is currently on animats.com.
-//! There is no authentication. Anyone can read this data.
+//! Except for the retired asset list and debug requests, there is no authentication. Anyone can read this data.
 //!
 //!     License: LGPL.
 //!     Animats
@@ -27,16 +83,25 @@
 #![forbid(unsafe_code)]
 use anyhow::{Error, anyhow};
 use log::LevelFilter;
-use uuid::Uuid;
-use common::Credentials;
 use common::init_fcgi;
-use common::{Handler, Request, Response};
-use common::{RegionImpostorReply, RegionImpostorData};
-use mysql::prelude::{Queryable};
-use mysql::{Pool};
-use mysql::{PooledConn, params};
+use common::{Handler, Request, RequiredParams, Response, query_string, status_for};
+use common::{RegionImpostorReply, RegionImpostorData, ReplyFormatter, PROVENANCE_COLUMN, REGION_IMPOSTOR_COLUMNS};
+use common::{StaleRegionsReply, get_region_ages};
+use common::{CoverageReply, get_coverage};
+use common::{Authorizer, AuthorizeType, Credentials, WatchedCredentials, get_retired_assets, parse_since};
+use common::{ReplayGuard, REPLAY_CACHE_SIZE, unix_time_now};
+use common::RateLimiter;
+use common::{ChangesSince, get_impostor_changes};
+use common::{ResponseCache, ResponseCacheKey, etag_for, etag_matches, generation_watermark};
+use common::{RawTerrainHeights, UploadedRegionInfo, NOT_DELETED_IMPOSTOR};
+use common::grid::canonical;
+use mysql::params;
+use serde::Serialize;
+use common::db::{Db, WatchedPool, with_conn};
+use common::metrics::{ConnectionLimits, Metrics, METRICS_LOG_INTERVAL};
 use std::collections::HashMap;
 use std::io::Write;
+use std::time::{Duration, Instant};
 
 /// MySQL Credentials for uploading.
 /// This filename will be searched for in parent directories,
@@ -49,8 +114,30 @@ use std::io::Write;
 ///     DB_PORT = portnumber (optional, defaults to 3306)
 ///     DB_NAME = databasename
 ///
+/// and, for the retired asset list, a secret for each token name allowed to fetch it
+///
+///     AUTH_CLEANUP_1 = secret
+///
+/// and, for debug requests, an admin token
+///
+///     ADMIN_AUTH_OPERATOR = secret
+///
+/// Optionally, the size of the reply cache, in megabytes. 0 turns it off. Default 64.
+///
+///     RESPONSE_CACHE_MB = 64
+///
+/// Optionally, the raw terrain rate limit per client. A rate of 0 turns it off.
+///
+///     TERRAIN_RATE_PER_MINUTE = 120
+///     TERRAIN_BURST = 30
+///
 const DOWNLOAD_CREDS_FILE: &str = "download_credentials.txt";
 
+/// How long a grid's coverage map is reused before re-reading it.
+const COVERAGE_CACHE_TIME: Duration = Duration::from_secs(60);
+/// Most grids with a cached coverage map. Grid names come from requests.
+const MAX_COVERAGE_GRIDS: usize = 64;
+
 /// Debug logging
 fn logger() {
     //  Log file is openly visible as a web page.
@@ -64,20 +151,138 @@ fn logger() {
     log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
 }
 
+/// Form of a raw terrain reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TerrainFormat {
+    /// JSON, with hex elevations
+    Heights,
+    /// 16-bit grayscale PNG
+    Png16,
+}
+
+/// A request for one region's raw terrain.
+#[derive(Debug, Clone, PartialEq)]
+struct TerrainRequest {
+    grid: String,
+    /// Region location, meters
+    region_loc: [u32; 2],
+    format: TerrainFormat,
+}
+
+/// One region's raw terrain, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct TerrainHeightsReply {
+    grid: String,
+    /// Region location, meters
+    region_coords: [u32; 2],
+    /// Region size, meters
+    size: [u32; 2],
+    /// Samples, X and Y
+    samples: [u32; 2],
+    scale: f32,
+    offset: f32,
+    water_level: f32,
+    /// Elevations, one hex string per X, one byte per sample.
+    elevs: Vec<String>,
+    /// Distance between samples, meters, if not size / (samples - 1).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_spacing: Option<[f32; 2]>,
+}
+
+/// One impostor, with its provenance, for the debug reply.
+#[derive(Debug, Clone, Serialize)]
+struct DebugImpostor {
+    #[serde(flatten)]
+    impostor: RegionImpostorData,
+    /// As recorded by the generator. A string if it isn't valid JSON. None for older impostors.
+    provenance: Option<serde_json::Value>,
+}
+
+impl DebugImpostor {
+    /// From an impostor read with its provenance.
+    fn new(impostor: RegionImpostorData) -> Self {
+        let provenance = impostor.provenance_json.as_ref()
+            .map(|s| serde_json::from_str(s).unwrap_or_else(|_| serde_json::Value::String(s.clone())));
+        Self { impostor, provenance }
+    }
+}
+
+/// A raw terrain reply, ready to send.
+#[derive(Debug, Clone, PartialEq)]
+struct TerrainReply {
+    status: usize,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl TerrainReply {
+    /// ETag for this reply. Quoted, as HTTP wants.
+    fn etag(&self) -> String {
+        etag_for(&self.body)
+    }
+
+    /// Does an If-None-Match header match this reply?
+    fn matches_etag(&self, if_none_match: &str) -> bool {
+        etag_matches(&self.etag(), if_none_match)
+    }
+}
+
 ///  Our handler
-struct TerrainDownloadHandler {
-    /// MySQL onnection pool. We only use one.
-    #[allow(dead_code)] // needed to keep the pool alive, but never referenced.
-    pool: Pool,
-    /// Active MySQL connection.
-    conn: PooledConn,
+pub(crate) struct TerrainDownloadHandler {
+    /// MySQL connection pool. Each request gets a connection from it.
+    pool: WatchedPool,
+    /// Request counters, shared with the FCGI loop
+    metrics: Metrics,
+    /// Token secrets, for the retired asset list and debug requests
+    secrets: WatchedCredentials,
+    /// Coverage maps, and when they were read, by grid
+    coverage_cache: CoverageCache,
+    /// Replies to impostor queries, by query
+    response_cache: ResponseCache,
+    /// Nonces of signed queries recently used, against replays
+    replay_guard: ReplayGuard,
+    /// Raw terrain requests per client
+    terrain_rate_limiter: RateLimiter,
+}
+
+/// Recent coverage maps, by lower case grid name.
+/// Bounded, since any grid name can be asked for.
+#[derive(Default)]
+struct CoverageCache {
+    grids: HashMap<String, (Instant, CoverageReply)>,
+}
+
+impl CoverageCache {
+    /// Coverage for a grid, from the cache if recent enough.
+    fn get(&mut self, conn: &mut dyn Db, grid: &str, now: Instant) -> Result<CoverageReply, Error> {
+        let key = grid.to_lowercase();
+        if let Some((fetched, reply)) = self.grids.get(&key) {
+            if now.duration_since(*fetched) < COVERAGE_CACHE_TIME {
+                return Ok(reply.clone());
+            }
+        }
+        let reply = get_coverage(conn, &key)?;
+        self.grids.retain(|_, (fetched, _)| now.duration_since(*fetched) < COVERAGE_CACHE_TIME);
+        if self.grids.len() >= MAX_COVERAGE_GRIDS {
+            let oldest = self.grids.iter().min_by_key(|(_, (fetched, _))| *fetched).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.grids.remove(&oldest);
+            }
+        }
+        self.grids.insert(key, (now, reply.clone()));
+        Ok(reply)
+    }
 }
 impl TerrainDownloadHandler {
 
-    /// Usual new. Saves connection pool for use.
-    pub fn new(pool: Pool) -> Result<Self, Error> {
-        let conn = pool.get_conn()?;
-        Ok(Self { pool, conn })
+    /// Usual new. Saves connection pool, token secrets, and metrics for use.
+    /// The reply cache size comes from the credentials file.
+    pub fn new(pool: WatchedPool, secrets: WatchedCredentials, metrics: Metrics) -> Result<Self, Error> {
+        let response_cache = ResponseCache::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", DOWNLOAD_CREDS_FILE, e))?;
+        let terrain_rate_limiter = RateLimiter::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", DOWNLOAD_CREDS_FILE, e))?;
+        //  Signed queries are new, so there are no old clients without a nonce. Always enforced.
+        let replay_guard = ReplayGuard::new(true, REPLAY_CACHE_SIZE);
+        Ok(Self { pool, metrics, secrets, coverage_cache: CoverageCache::default(), response_cache, replay_guard, terrain_rate_limiter })
     }
 
     /// Parse a request.
@@ -96,19 +301,184 @@ impl TerrainDownloadHandler {
         Ok(())
     }
     
+    /// Parse the query string into lower case keys and values.
+    /// The grid name is made canonical here, for every kind of request.
+    fn query_params(params: &HashMap<String, String>) -> Result<HashMap<String, String>, Error> {
+        let query_vec = querystring::querify(query_string(params));
+        Ok(query_vec.iter().map(|(k, v)| {
+            let k = k.to_lowercase().trim().to_string();
+            let v = if k == "grid" { canonical(v) } else { v.to_string() };
+            (k, v)
+        }).collect())
+    }
+    
+    /// Reply formatter for the requested version.
+    fn reply_formatter(params: &HashMap<String, String>) -> Result<ReplyFormatter, Error> {
+        let query_params = Self::query_params(params)?;
+        ReplyFormatter::new_from_param(query_params.get("version").map(|v| v.as_str()))
+    }
+    
+    /// Grid and staleness threshold, if this is a request for regions needing a re-survey.
+    fn stale_request(params: &HashMap<String, String>) -> Result<Option<(String, u32)>, Error> {
+        let query_params = Self::query_params(params)?;
+        let Some(stale_days) = query_params.get("stale_days") else {
+            return Ok(None);
+        };
+        let stale_days = stale_days.trim().parse::<u32>().map_err(|_| anyhow!("\"stale_days\" parameter \"{}\" is not a number of days", stale_days))?;
+        let grid = query_params.get("grid").ok_or_else(|| anyhow!("No \"grid\" parameter in HTTP request"))?;
+        Ok(Some((grid.clone(), stale_days)))
+    }
+
+    /// Mode and grid, if there's a mode parameter.
+    fn mode_request(params: &HashMap<String, String>) -> Result<Option<(String, String)>, Error> {
+        let query_params = Self::query_params(params)?;
+        let Some(mode) = query_params.get("mode") else {
+            return Ok(None);
+        };
+        let mode = mode.trim().to_lowercase();
+        if mode != "coverage" && mode != "retired" && mode != "debug" {
+            return Err(anyhow!("\"mode\" parameter \"{}\" is not \"coverage\", \"retired\", or \"debug\"", mode));
+        }
+        let grid = query_params.get("grid").ok_or_else(|| anyhow!("No \"grid\" parameter in HTTP request"))?;
+        Ok(Some((mode, grid.clone())))
+    }
+
+    /// Grid, if this is a request for the coverage map.
+    fn coverage_request(params: &HashMap<String, String>) -> Result<Option<String>, Error> {
+        Ok(Self::mode_request(params)?.filter(|(mode, _)| mode == "coverage").map(|(_, grid)| grid))
+    }
+
+    /// Grid and start time, if this is a request for retired assets.
+    fn retired_request(params: &HashMap<String, String>) -> Result<Option<(String, String)>, Error> {
+        let Some((_, grid)) = Self::mode_request(params)?.filter(|(mode, _)| mode == "retired") else {
+            return Ok(None);
+        };
+        let since = parse_since(Self::query_params(params)?.get("since").map(|s| s.as_str()))?;
+        Ok(Some((grid, since)))
+    }
+
+    /// Check a signed query's signature, then its nonce and sent_at, so it can't be replayed.
+    /// Why a query was refused is logged, never sent back.
+    fn authorize_query(replay_guard: &mut ReplayGuard, auth_type: AuthorizeType, request: &Request, now: i64,
+            secrets: impl Fn(&str) -> Option<String>) -> Result<(), Error> {
+        Authorizer::authorize_signed_query(auth_type, request, secrets)?;
+        let query_params = request.params.as_ref().map(Self::query_params).transpose()?.unwrap_or_default();
+        let sent_at = query_params.get("sent_at").and_then(|s| s.trim().parse::<i64>().ok());
+        let token_name = Authorizer::token_name(request).unwrap_or_default();
+        replay_guard.check(&token_name, query_params.get("nonce").map(|s| s.as_str()), sent_at, now).map_err(|e| {
+            log::warn!("Signed query by token \"{}\" rejected: {}", token_name, e);
+            anyhow!("{}", e)
+        })
+    }
+
+    /// Grid and region location, if this is a debug request.
+    fn debug_request(params: &HashMap<String, String>) -> Result<Option<(String, u32, u32)>, Error> {
+        let Some((_, grid)) = Self::mode_request(params)?.filter(|(mode, _)| mode == "debug") else {
+            return Ok(None);
+        };
+        let query_params = Self::query_params(params)?;
+        let coord = |name: &str| -> Result<u32, Error> {
+            let v = query_params.get(name).ok_or_else(|| anyhow!("No \"{}\" parameter in debug request", name))?;
+            v.trim().parse().map_err(|_| anyhow!("\"{}\" parameter \"{}\" is not a location", name, v))
+        };
+        Ok(Some((grid, coord("x")?, coord("y")?)))
+    }
+
+    /// Grid, viz group, and where the viewer is up to, if this is a request for changes.
+    fn changes_request(params: &HashMap<String, String>) -> Result<Option<(String, u32, ChangesSince)>, Error> {
+        let query_params = Self::query_params(params)?;
+        let Some(since) = query_params.get("changes_since") else {
+            return Ok(None);
+        };
+        let since = ChangesSince::new_from_param(since)?;
+        let grid = query_params.get("grid").ok_or_else(|| anyhow!("No \"grid\" parameter in HTTP request"))?;
+        let viz_group = query_params.get("viz_group").ok_or_else(|| anyhow!("\"changes_since\" needs a \"viz_group\" parameter"))?;
+        let viz_group = viz_group.trim().parse::<u32>().map_err(|_| anyhow!("\"viz_group\" parameter \"{}\" is not a number", viz_group))?;
+        Ok(Some((grid.clone(), viz_group, since)))
+    }
+
+    /// Region and format, if this is a request for raw terrain.
+    fn terrain_request(params: &HashMap<String, String>) -> Result<Option<TerrainRequest>, Error> {
+        let query_params = Self::query_params(params)?;
+        let Some(format) = query_params.get("format") else {
+            return Ok(None);
+        };
+        let format = match format.trim().to_lowercase().as_str() {
+            "heights" => TerrainFormat::Heights,
+            "png16" => TerrainFormat::Png16,
+            _ => return Err(anyhow!("\"format\" parameter \"{}\" is not \"heights\" or \"png16\"", format)),
+        };
+        let grid = query_params.get("grid").ok_or_else(|| anyhow!("No \"grid\" parameter in HTTP request"))?;
+        let coord = |name: &str| -> Result<u32, Error> {
+            let v = query_params.get(name).ok_or_else(|| anyhow!("No \"{}\" parameter in terrain request", name))?;
+            v.trim().parse().map_err(|_| anyhow!("\"{}\" parameter \"{}\" is not a location", name, v))
+        };
+        Ok(Some(TerrainRequest { grid: grid.clone(), region_loc: [coord("x")?, coord("y")?], format }))
+    }
+
+    /// One region's raw terrain, straight from raw_terrain_heights.
+    fn process_terrain_request(conn: &mut dyn Db, terrain_request: &TerrainRequest) -> Result<TerrainReply, Error> {
+        let Some(row) = RawTerrainHeights::get(conn, &terrain_request.grid, terrain_request.region_loc)? else {
+            log::info!("No terrain for region at {:?} on \"{}\".", terrain_request.region_loc, terrain_request.grid);
+            return Ok(TerrainReply { status: 404, content_type: "text/plain", body: b"No terrain for that region".to_vec() });
+        };
+        let body = match terrain_request.format {
+            TerrainFormat::Heights => {
+                let reply = TerrainHeightsReply {
+                    grid: row.grid.clone(),
+                    region_coords: row.region_loc,
+                    size: row.region_size,
+                    samples: row.samples,
+                    scale: row.scale,
+                    offset: row.offset,
+                    water_level: row.water_level,
+                    elevs: UploadedRegionInfo::elevs_blob_to_hex(&row.elevs, row.samples[0], row.samples[1])?,
+                    sample_spacing: row.sample_spacing,
+                };
+                serde_json::to_string(&reply)?.into_bytes()
+            }
+            TerrainFormat::Png16 => row.height_field()?.to_png16()?,
+        };
+        let content_type = match terrain_request.format {
+            TerrainFormat::Heights => "application/json",
+            TerrainFormat::Png16 => "image/png",
+        };
+        Ok(TerrainReply { status: 200, content_type, body })
+    }
+
+    /// The impostors at one region location, all LODs, with provenance, as JSON.
+    /// Deleted regions are included. The operator may be asking why.
+    fn process_debug_request(conn: &mut dyn Db, grid: &str, region_loc_x: u32, region_loc_y: u32) -> Result<String, Error> {
+        let stmt = format!("SELECT {}, {} FROM region_impostors \
+            WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y ORDER BY impostor_lod",
+            REGION_IMPOSTOR_COLUMNS, PROVENANCE_COLUMN);
+        let impostors = conn.exec_map(&stmt, params! { "grid" => grid, "region_loc_x" => region_loc_x, "region_loc_y" => region_loc_y },
+            |row| Ok(DebugImpostor::new(RegionImpostorData::from_db_row_with_provenance(&row)?)))?;
+        log::info!("Debug request: {} impostors at ({}, {}) on \"{}\".", impostors.len(), region_loc_x, region_loc_y, grid);
+        Ok(serde_json::to_string(&impostors)?)
+    }
+
+    /// Regions needing a re-survey, as JSON.
+    fn process_stale_request(conn: &mut dyn Db, grid: &str, stale_days: u32) -> Result<(usize, String), Error> {
+        let regions = get_region_ages(conn, grid, stale_days)?;
+        log::info!("{} regions on \"{}\" older than {} days or missing.", regions.len(), grid, stale_days);
+        let reply = StaleRegionsReply { grid: grid.to_string(), stale_days, regions };
+        Ok((200, serde_json::to_string(&reply)?))
+    }
+
     /// Build the SQL query statement.
-    fn build_sql_query(params: &HashMap<String, String>) -> Result<(String, String, Option<(u32, u32)>, Option<u32>), Error> {
+    fn build_sql_query(params: &HashMap<String, String>) -> Result<(String, String, Option<(u32, u32)>, Option<u32>, Option<u32>), Error> {
         //  Parse URL parameters.  Build WHILE part.
-        let query_string = params.get("QUERY_STRING").ok_or_else(|| anyhow!("No QUERY_STRING from FCGI"))?;
-        let query_vec = querystring::querify(query_string);
-        let query_params: HashMap<String, String> = query_vec.iter().map(|(k, v)| (k.to_lowercase().trim().to_string(), v.to_string())).collect();
+        let query_params = Self::query_params(params)?;
         //  Parameters are
         //      grid
         //      x
         //      y
         //      viz_group
+        //      since_generation (only impostors changed since the viewer's cached copy)
+        //      version (handled by reply_formatter)
         //  Grid is mandatory, others are optional.
-        let grid = query_params.get("grid").ok_or_else(|| anyhow!("No \"grid\" parameter in HTTP request"))?;
+        let grid = query_params.get("grid").ok_or_else(|| common::Error::BadRequest("No \"grid\" parameter in HTTP request".to_string()))?;
         let coords_opt: Option<(u32, u32)> = {
             if let Some(x) = query_params.get("x") {            
                 if let Some(y) = query_params.get("y") {
@@ -125,6 +495,11 @@ impl TerrainDownloadHandler {
         } else {
             None
         };
+        let since_generation_opt: Option<u32> = if let Some(generation) = query_params.get("since_generation") {
+            Some(generation.parse()?)
+        } else {
+            None
+        };
         
         //  There are three cases.
         let where_clause = if viz_group_opt.is_some() {
@@ -135,69 +510,32 @@ impl TerrainDownloadHandler {
         else {
             "grid = :grid"  
         };
+        let where_clause = if since_generation_opt.is_some() {
+            format!("{} AND generation > :since_generation", where_clause)
+        } else {
+            where_clause.to_string()
+        };
         log::info!("Query: grid: {} coords {:?}  viz_group: {:?}, WHERE clause: {}", grid, coords_opt, viz_group_opt, where_clause);
-        const SELECT_PART: &str = "grid, region_loc_x, region_loc_y, name, region_size_x, region_size_y, scale_x, scale_y, scale_z, \
-        elevation_offset, impostor_lod, viz_group, mesh_uuid, sculpt_uuid, water_height, creator, creation_time, faces_json FROM region_impostors ";
+        //  Never return a region which has been deleted, even before the next promote removes its impostor.
         let priority = if where_clause.is_empty() { " LOW PRIORITY ". to_string() } else { "".to_string() };
-        let stmt = format!("SELECT {}{} WHERE {} ORDER BY grid, region_loc_x, region_loc_y", SELECT_PART, priority, where_clause);
-        Ok((stmt, grid.clone(), coords_opt, viz_group_opt))
+        let stmt = format!("SELECT {} FROM region_impostors {}WHERE {} AND {} ORDER BY grid, region_loc_x, region_loc_y", REGION_IMPOSTOR_COLUMNS, priority, where_clause, NOT_DELETED_IMPOSTOR);
+        Ok((stmt, grid.clone(), coords_opt, viz_group_opt, since_generation_opt))
     }
     
     /// Select the desired items and generate JSON.
-    fn do_select(&mut self, params: &HashMap<String, String>) -> Result<Vec<Result<RegionImpostorData, Error>>, Error> {
-        //  Convert UUIDs, return None if fail.
-        fn convert_uuid(s_opt: Option<String>) -> Option<Uuid> {
-            if let Some(s) = s_opt {
-                match Uuid::try_parse(&s) {
-                    Ok(u) => Some(u),
-                    Err(_) => None
-                }
-            } else {
-                None
-            }
-        }
+    fn do_select(conn: &mut dyn Db, params: &HashMap<String, String>) -> Result<Vec<Result<RegionImpostorData, Error>>, Error> {
         // Build SELECT statement and get params
-        let (stmt, grid, coords_opt, viz_group_opt) = Self::build_sql_query(params)?;
+        let (stmt, grid, coords_opt, viz_group_opt, since_generation_opt) = Self::build_sql_query(params)?;
         let viz_group = if let Some(viz_group) = viz_group_opt { viz_group } else { 0 };
+        let since_generation = since_generation_opt.unwrap_or(0);
         let (region_loc_x, region_loc_y) = if let Some(coords) = coords_opt { (coords.0, coords.1) } else { (0, 0) };
         //  Perform the SELECT
         log::info!("Query: {}", stmt);
-        let mut query_result: mysql::QueryResult<_> = self.conn.exec_iter(
-            stmt,
-            params! { grid, region_loc_x, region_loc_y, viz_group })?;
-        //  Process the results.
-        //  There should be only one query result set since we only made one query.
-        //  So this is iteration over rows.
-        let first_result_set: mysql::ResultSet<_> = query_result.iter().expect("No result set from SELECT");
-        let impostor_results: Vec<Result<RegionImpostorData, Error>> = first_result_set.map(|rs: Result<mysql::Row, mysql::Error> | {          
-            log::trace!("SELECT result: {:?}", rs);
-            let row = rs?;
-            //  We have to do this the hard way because there are more than 12 columns being read.
-            //  Faces is JSON as a string and must be parsed.
-            let faces_json: String = row.get_opt(17).ok_or_else(|| anyhow!("faces_json is null"))??;
-            let faces = serde_json::from_str(&faces_json)?;
-            let rd = RegionImpostorData {
-                //  None of these null checks should fail, because those fields are non-null in the SQL table definition.
-                grid: row.get_opt(0).ok_or_else(|| anyhow!("grid is null"))??,
-                region_loc: [row.get_opt(1).ok_or_else(|| anyhow!("loc_x is null"))??, row.get_opt(2).ok_or_else(|| anyhow!("loc_y is null"))??],
-                name: row.get_opt(3).ok_or_else(|| anyhow!("name is null"))??,
-                region_size: [row.get_opt(4).ok_or_else(|| anyhow!("size_x is null"))??, row.get_opt(5).ok_or_else(|| anyhow!("size_y is null"))??],
-                scale: [
-                    row.get_opt::<u32, _>(6).ok_or_else(|| anyhow!("scale_x is null"))?? as f32, 
-                    row.get_opt::<u32, _>(7).ok_or_else(|| anyhow!("scale_y is null"))?? as f32, 
-                    row.get_opt(8).ok_or_else(|| anyhow!("scale_z is null"))??],
-                elevation_offset: row.get_opt(9).ok_or_else(|| anyhow!("elevation_offset is null"))??,
-                impostor_lod: row.get_opt(10).ok_or_else(|| anyhow!("impostor_lod is null"))??,
-                viz_group: row.get_opt(11).ok_or_else(|| anyhow!("Viz_group is null"))??,
-                mesh_uuid: convert_uuid(row.get_opt(12).ok_or_else(|| anyhow!("mesh_uuid is invalid"))??,),
-                sculpt_uuid: convert_uuid(row.get_opt(13).ok_or_else(|| anyhow!("mesh_uuid is invalid"))??,),
-                water_height: row.get_opt(14).ok_or_else(|| anyhow!("water_height is null"))??,
-                //  Fields not used by the viewer
-                mesh_hash: None,
-                sculpt_hash: None,
-                faces,
-            };
-            log::debug!("{:?}",rd);
+        let rows = conn.exec_rows(&stmt, params! { grid, region_loc_x, region_loc_y, viz_group, since_generation })?;
+        let impostor_results: Vec<Result<RegionImpostorData, Error>> = rows.iter().map(|row| {
+            log::trace!("SELECT result: {:?}", row);
+            let rd = RegionImpostorData::from_db_row(row)?;
+            log::debug!("{:?}", rd);
             Ok(rd)
         }).collect();
         //  We have a vector of results. Some may have errors.
@@ -205,13 +543,51 @@ impl TerrainDownloadHandler {
         Ok(impostor_results)
     }
 
+    /// Cache key for an impostor query. None for queries which aren't cached.
+    fn cache_key(params: &HashMap<String, String>, formatter: &ReplyFormatter) -> Result<Option<ResponseCacheKey>, Error> {
+        if Self::stale_request(params)?.is_some() {
+            return Ok(None);
+        }
+        let (_, grid, coords_opt, viz_group_opt, since_generation_opt) = Self::build_sql_query(params)?;
+        Ok(Some(ResponseCacheKey::new(&grid, coords_opt, viz_group_opt, since_generation_opt, formatter.version())))
+    }
+
+    /// Handle request, from the cache if the grid's impostors haven't changed.
+    /// Returns status, JSON, and the ETag if the reply is cacheable.
+    fn process_request_cached(
+        conn: &mut dyn Db,
+        params: &HashMap<String, String>,
+        formatter: &ReplyFormatter,
+        cache: &mut ResponseCache,
+        metrics: &Metrics,
+        now: Instant,
+    ) -> Result<(usize, String, Option<String>), Error> {
+        let Some(key) = Self::cache_key(params, formatter)? else {
+            let (status, json) = Self::process_request(conn, params, formatter)?;
+            return Ok((status, json, None));
+        };
+        let watermark = generation_watermark(conn, &key.grid);
+        if let Some(hit) = cache.get(&key, watermark, now) {
+            metrics.observe_cache(true);
+            return Ok((200, hit.body.clone(), Some(hit.etag.clone())));
+        }
+        metrics.observe_cache(false);
+        let (status, json) = Self::process_request(conn, params, formatter)?;
+        let etag = cache.insert(key, json.clone(), watermark, now);
+        Ok((status, json, Some(etag)))
+    }
+
     /// Handle request.
     /// Return requsted data as JSON.
     fn process_request(
-        &mut self,
+        conn: &mut dyn Db,
         params: &HashMap<String, String>,
+        formatter: &ReplyFormatter,
     ) -> Result<(usize, String), Error> {
-        let impostor_results = self.do_select(params)?;
+        if let Some((grid, stale_days)) = Self::stale_request(params)? {
+            return Self::process_stale_request(conn, &grid, stale_days);
+        }
+        let impostor_results = Self::do_select(conn, params)?;
         //  Now separate the good results from the errors.
         let (impostors, errors) : (Vec<_>, Vec<_>) = impostor_results
             .into_iter()
@@ -223,14 +599,134 @@ impl TerrainDownloadHandler {
         }
         //  Construct reply for REST query
         let full_reply = RegionImpostorReply {
-            version: RegionImpostorReply::REGION_IMPOSTOR_INFO_VERSION,
+            version: formatter.version(),
             impostors,
             errors,            
         };
-        let json = serde_json::to_string(&full_reply)?;
+        let json = formatter.format(&full_reply)?;
         Ok((200, json))
     }
 }
+//  Reply writing
+impl TerrainDownloadHandler {
+    /// Send a grid's coverage map, as JSON.
+    fn write_coverage_reply(&mut self, out: &mut dyn Write, request: &Request, grid: &str) -> Result<(), Error> {
+        let cache = &mut self.coverage_cache;
+        let result = with_conn(&self.pool, |conn| cache.get(conn, grid, Instant::now()))
+            .and_then(|reply| Ok(serde_json::to_string(&reply)?));
+        match result {
+            Ok(json) => {
+                let http_response = Response::http_response("application/json", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), json.as_bytes())
+            }
+            Err(e) => {
+                self.metrics.observe_error(&e);
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+
+    /// Send a viz group's changes, as JSON.
+    fn write_changes_reply(&mut self, out: &mut dyn Write, request: &Request, grid: &str, viz_group: u32, since: &ChangesSince) -> Result<(), Error> {
+        let result = with_conn(&self.pool, |conn| get_impostor_changes(conn, grid, viz_group, since))
+            .and_then(|reply| Ok(serde_json::to_string(&reply)?));
+        match result {
+            Ok(json) => {
+                let http_response = Response::http_response("application/json", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), json.as_bytes())
+            }
+            Err(e) => {
+                self.metrics.observe_error(&e);
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+
+    /// Send the assets retired on a grid, as JSON. The request must be signed.
+    fn write_retired_reply(&mut self, out: &mut dyn Write, request: &Request, grid: &str, since: &str) -> Result<(), Error> {
+        if Self::authorize_query(&mut self.replay_guard, AuthorizeType::RetiredAssets, request, unix_time_now(), |k| self.secrets.get_fresh(k)).is_err() {
+            self.metrics.observe_auth_failure();
+            let http_response = Response::http_response("text/plain", 401, "Not authorized");
+            return Response::write_response(out, request, http_response.as_slice(), b"Not authorized");
+        }
+        let result = with_conn(&self.pool, |conn| get_retired_assets(conn, grid, since))
+            .and_then(|reply| Ok(serde_json::to_string(&reply)?));
+        match result {
+            Ok(json) => {
+                let http_response = Response::http_response("application/json", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), json.as_bytes())
+            }
+            Err(e) => {
+                self.metrics.observe_error(&e);
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+
+    /// Send the impostors at one region location, with provenance, as JSON.
+    /// The request must be signed by an admin token.
+    fn write_debug_reply(&mut self, out: &mut dyn Write, request: &Request, grid: &str, region_loc_x: u32, region_loc_y: u32) -> Result<(), Error> {
+        if Self::authorize_query(&mut self.replay_guard, AuthorizeType::Debug, request, unix_time_now(), |k| self.secrets.get_fresh(k)).is_err() {
+            self.metrics.observe_auth_failure();
+            let http_response = Response::http_response("text/plain", 401, "Not authorized");
+            return Response::write_response(out, request, http_response.as_slice(), b"Not authorized");
+        }
+        match with_conn(&self.pool, |conn| Self::process_debug_request(conn, grid, region_loc_x, region_loc_y)) {
+            Ok(json) => {
+                let http_response = Response::http_response("application/json", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), json.as_bytes())
+            }
+            Err(e) => {
+                self.metrics.observe_error(&e);
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+
+    /// The 429 reply for a client over its rate limit, if it is. Otherwise the request is counted.
+    fn rate_limited(limiter: &mut RateLimiter, request: &Request, now: Instant) -> Option<Vec<String>> {
+        let client = request.params.as_ref().and_then(|p| p.get("REMOTE_ADDR")).map(|s| s.as_str()).unwrap_or("");
+        if limiter.allow(client, now) {
+            return None;
+        }
+        log::warn!("Terrain requests from {:?} over the rate limit.", client);
+        Some(vec!["Status: 429 Too Many Requests".to_string(), "Content-Type: text/plain; charset=utf-8".to_string(),
+            format!("Retry-After: {}", limiter.retry_after(client, now).max(1))])
+    }
+
+    /// Fetch and send raw terrain. Unchanged since the viewer last fetched it is a 304, with no body.
+    /// Too many requests from one client is a 429.
+    fn write_terrain_reply(&mut self, out: &mut dyn Write, request: &Request, terrain_request: &TerrainRequest) -> Result<(), Error> {
+        if let Some(http_response) = Self::rate_limited(&mut self.terrain_rate_limiter, request, Instant::now()) {
+            return Response::write_response(out, request, http_response.as_slice(), b"Too many requests");
+        }
+        match with_conn(&self.pool, |conn| Self::process_terrain_request(conn, terrain_request)) {
+            Ok(reply) if reply.status == 200 => {
+                let etag = reply.etag();
+                if request.header("If-None-Match").is_some_and(|tags| reply.matches_etag(tags)) {
+                    let http_response = vec!["Status: 304 Not Modified".to_string(), format!("ETag: {}", etag)];
+                    return Response::write_response(out, request, http_response.as_slice(), &[]);
+                }
+                let http_response = vec!["Status: 200 OK".to_string(), format!("Content-Type: {}", reply.content_type), format!("ETag: {}", etag)];
+                Response::write_response(out, request, http_response.as_slice(), &reply.body)
+            }
+            Ok(reply) => {
+                let http_response = Response::http_response(reply.content_type, reply.status, "Not Found");
+                Response::write_response(out, request, http_response.as_slice(), &reply.body)
+            }
+            Err(e) => {
+                self.metrics.observe_error(&e);
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+}
+
 //  Our "handler"
 impl Handler for TerrainDownloadHandler {
     fn handler(
@@ -244,31 +740,108 @@ impl Handler for TerrainDownloadHandler {
         match Self::parse_request(&request.standard_input, env) {
             Ok(_) => {
                 log::info!("Request made: env {:?}", env);
-                let params = request
-                    .params
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("No HTTP parameters found"))?;
+                //  Error 400, naming all of them, if params needed here are missing.
+                let mut required = RequiredParams::new(request);
+                let request_method = required.require("REQUEST_METHOD");
+                let params = match required.finish() {
+                    Ok(params) => params,
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Incorrect request");
+                        Response::write_response(out, request, http_response.as_slice(), e.to_string().as_bytes())?;
+                        return Ok(());
+                    }
+                };
                 //  This must be a GET
-                if let Some(request_method) = params.get("REQUEST_METHOD") {   
-                    if request_method.to_uppercase().trim() != "GET" {             
-                        return Err(anyhow!("Request method \"{}\" was not GET.", request_method));
-                    }            
-                } else {
-                    return Err(anyhow!("No HTTP request method."));
+                if request_method.to_uppercase().trim() != "GET" {
+                    return Err(anyhow!("Request method \"{}\" was not GET.", request_method));
+                }
+                //  Raw terrain requests are handled separately. Error 400 if the request is bad.
+                match Self::terrain_request(params) {
+                    Ok(Some(terrain_request)) => return self.write_terrain_reply(out, request, &terrain_request),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Bad terrain request");
+                        Response::write_response(out, request, http_response.as_slice(), format!("{}", e).as_bytes())?;
+                        return Ok(());
+                    }
+                }
+                //  So are coverage maps.
+                match Self::coverage_request(params) {
+                    Ok(Some(grid)) => return self.write_coverage_reply(out, request, &grid),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Bad coverage request");
+                        Response::write_response(out, request, http_response.as_slice(), format!("{}", e).as_bytes())?;
+                        return Ok(());
+                    }
+                }
+                //  And change feeds.
+                match Self::changes_request(params) {
+                    Ok(Some((grid, viz_group, since))) => return self.write_changes_reply(out, request, &grid, viz_group, &since),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Bad changes request");
+                        Response::write_response(out, request, http_response.as_slice(), format!("{}", e).as_bytes())?;
+                        return Ok(());
+                    }
+                }
+                //  And retired asset lists.
+                match Self::retired_request(params) {
+                    Ok(Some((grid, since))) => return self.write_retired_reply(out, request, &grid, &since),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Bad retired asset request");
+                        Response::write_response(out, request, http_response.as_slice(), format!("{}", e).as_bytes())?;
+                        return Ok(());
+                    }
                 }
+                //  And operator debug requests.
+                match Self::debug_request(params) {
+                    Ok(Some((grid, region_loc_x, region_loc_y))) => return self.write_debug_reply(out, request, &grid, region_loc_x, region_loc_y),
+                    Ok(None) => {}
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Bad debug request");
+                        Response::write_response(out, request, http_response.as_slice(), format!("{}", e).as_bytes())?;
+                        return Ok(());
+                    }
+                }
+                //  Requested reply version. Error 400, with the supported versions, if fail.
+                let formatter = match Self::reply_formatter(params) {
+                    Ok(formatter) => formatter,
+                    Err(e) => {
+                        let http_response = Response::http_response("text/plain", 400, "Unsupported version");
+                        let b = format!("{}", e).into_bytes();
+                        Response::write_response(out, request, http_response.as_slice(), &b)?;
+                        return Ok(());
+                    }
+                };
                 //  Process. Error 500 if fail.
-                match self.process_request(&params) {
-                    Ok((status, msg)) => {
+                //  Retried once if the database connection was lost.
+                let cache = &mut self.response_cache;
+                let metrics = &self.metrics;
+                let now = Instant::now();
+                match with_conn(&self.pool, |conn| Self::process_request_cached(conn, params, &formatter, cache, metrics, now)) {
+                    Ok((status, msg, etag_opt)) => {
+                        //  The viewer has this already.
+                        if let Some(etag) = etag_opt.as_ref().filter(|etag| request.header("If-None-Match").is_some_and(|tags| etag_matches(etag, tags))) {
+                            let http_response = vec!["Status: 304 Not Modified".to_string(), format!("ETag: {}", etag)];
+                            return Response::write_response(out, request, http_response.as_slice(), &[]);
+                        }
                         //  Success. Send a plain "OK"
-                        let http_response = Response::http_response("application/json", status, "OK");
+                        let mut http_response = Response::http_response("application/json", status, "OK");
+                        if let Some(etag) = &etag_opt {
+                            http_response.push(format!("ETag: {}", etag));
+                        }
                         //  Return something useful.
                         let b = msg.into_bytes();
                         Response::write_response(out, request, http_response.as_slice(), &b)?;
                     }
                     Err(e) => {
+                        //  A bad query, such as one with no grid, is the client's problem. Error 400 for those.
+                        self.metrics.observe_error(&e);
                         let http_response = Response::http_response(
                             "text/plain",
-                            500,
+                            status_for(&e).into(),
                             format!("Problem processing request: {:?}", e).as_str(),
                         );
                         Response::write_response(out, request, http_response.as_slice(), &[])?;
@@ -289,47 +862,35 @@ impl Handler for TerrainDownloadHandler {
         }
         Ok(())
     }
+
+    /// Pick up rotated token secrets.
+    fn between_requests(&mut self) {
+        if let Err(e) = self.secrets.reload() {
+            log::warn!("Keeping previous credentials: {:?}", e);
+        }
+    }
 }
 
 /// Run the responder.
 pub fn run_responder() -> Result<(), Error> {
-    log::info!("Environment: {:?}", std::env::vars());
+    log::info!("Environment: {:?}", common::environment_names());
     //  Set up in and out sockets.
     //  Communication with the parent process is via a UNIX socket.
     //  This is a pain to set up, because UNIX sockets are badly mis-matched
     //  to parent/child process communication.
     //  See init_fcgi for how it is done.
     let listener = init_fcgi()?;
-    //  Accept a connection on the listener socket. This hooks up
-    //  input and output to the parent process.
-    let (socket, _addr) = listener.accept()?;
-    let outsocket = socket.try_clone()?;
-    let mut instream = std::io::BufReader::new(socket);
-    let mut outio = std::io::BufWriter::new(outsocket);
-    //  Connect to the database
-    let creds = Credentials::new(DOWNLOAD_CREDS_FILE)?;
-    //  Optional MySQL port number
-    let portnum = if let Some(port) = creds.get("DB_PORT") {
-        port.parse::<u16>()?
-    } else {
-        //  Use MySQL default
-        3306
-    };
-    let opts = mysql::OptsBuilder::new()
-        //  Dreamhost is still using old authentication
-        .secure_auth(false)
-        .ip_or_hostname(creds.get("DB_HOST"))
-        .tcp_port(portnum)
-        .user(creds.get("DB_USER"))
-        .pass(creds.get("DB_PASS"))
-        .db_name(creds.get("DB_NAME"));
-    drop(creds);
-    //////log::info!("Opts: {:?}", opts);
-    let pool = Pool::new(opts)?;
-    log::info!("Connected to database.");
-    let mut terrain_upload_handler = TerrainDownloadHandler::new(pool)?;
-    //  Run the FCGI server.
-    common::run(&mut instream, &mut outio, &mut terrain_upload_handler)
+    //  Connect to the database. Secrets and connections follow changes to the credentials file,
+    //  so secrets can be rotated without a restart.
+    let secrets = Credentials::watch_with_env(DOWNLOAD_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&secrets)?;
+    common::db::migrations::check_schema_if_enabled(&mut pool.get_conn()?);
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", DOWNLOAD_CREDS_FILE, e))?;
+    let metrics = Metrics::new_with_log_interval(Some(METRICS_LOG_INTERVAL));
+    let mut terrain_upload_handler = TerrainDownloadHandler::new(pool, secrets, metrics.clone())?;
+    //  Run the FCGI server, one connection from the parent process at a time.
+    //  Replies can be big, so reading and writing are on separate threads.
+    common::serve(&listener, &mut terrain_upload_handler, &metrics, &connection_limits)
 }
 
 /// Main program
@@ -344,3 +905,322 @@ pub fn main() {
     }
 }
 
+
+#[test]
+fn test_sql_excludes_deleted() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    for q in ["grid=agni", "grid=agni&x=256&y=512", "grid=agni&viz_group=3"] {
+        let (stmt, grid, _, _, _) = TerrainDownloadHandler::build_sql_query(&query(q)).expect("query");
+        assert_eq!(grid, "agni");
+        assert!(stmt.contains(&format!("AND {} ORDER BY", NOT_DELETED_IMPOSTOR)), "{}", stmt);
+    }
+    //  Deleted regions are found by index, with the same canonical grid parameter.
+    assert!(NOT_DELETED_IMPOSTOR.contains("r.grid = :grid") && !NOT_DELETED_IMPOSTOR.contains("LOWER("));
+    //  Staleness requests go elsewhere.
+    assert_eq!(TerrainDownloadHandler::stale_request(&query("grid=agni&stale_days=90")).unwrap(), Some(("agni".to_string(), 90)));
+    assert_eq!(TerrainDownloadHandler::stale_request(&query("grid=agni")).unwrap(), None);
+    assert!(TerrainDownloadHandler::stale_request(&query("grid=agni&stale_days=old")).is_err());
+}
+
+#[test]
+fn test_sql_since_generation() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    let (stmt, _, _, _, since) = TerrainDownloadHandler::build_sql_query(&query("grid=agni&since_generation=12")).expect("query");
+    assert_eq!(since, Some(12));
+    assert!(stmt.contains("WHERE grid = :grid AND generation > :since_generation AND "), "{}", stmt);
+    let (stmt, _, _, _, since) = TerrainDownloadHandler::build_sql_query(&query("grid=agni&viz_group=3&since_generation=0")).expect("query");
+    assert_eq!(since, Some(0));
+    assert!(stmt.contains("viz_group = :viz_group AND generation > :since_generation"), "{}", stmt);
+    //  No filter unless asked for.
+    let (stmt, _, _, _, since) = TerrainDownloadHandler::build_sql_query(&query("grid=agni")).expect("query");
+    assert_eq!(since, None);
+    assert!(!stmt.contains("generation >"), "{}", stmt);
+    assert!(TerrainDownloadHandler::build_sql_query(&query("grid=agni&since_generation=new")).is_err());
+}
+
+#[test]
+fn test_cache_key() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    let key = |q: &str| {
+        let params = query(q);
+        let formatter = TerrainDownloadHandler::reply_formatter(&params).expect("formatter");
+        TerrainDownloadHandler::cache_key(&params, &formatter)
+    };
+    //  The same viz group, asked with or without a location, is one entry.
+    assert_eq!(key("grid=agni&viz_group=3").unwrap(), key("grid=agni&viz_group=3&x=256&y=512").unwrap());
+    assert_ne!(key("grid=agni&viz_group=3").unwrap(), key("grid=agni&viz_group=3&since_generation=4").unwrap());
+    assert_eq!(key("grid=agni&x=256&y=512").unwrap().expect("key").coords, Some((256, 512)));
+    //  Staleness reports aren't cached.
+    assert_eq!(key("grid=agni&stale_days=90").unwrap(), None);
+    assert!(key("viz_group=3").is_err());
+}
+
+#[test]
+fn test_absent_query_string() {
+    //  No QUERY_STRING at all, as from some nginx configurations, is an empty query, not a missing param.
+    let absent: HashMap<String, String> = [("REQUEST_METHOD".to_string(), "GET".to_string())].into_iter().collect();
+    let empty: HashMap<String, String> = [("QUERY_STRING".to_string(), String::new())].into_iter().collect();
+    for params in [&absent, &empty] {
+        assert!(TerrainDownloadHandler::query_params(params).expect("query params").is_empty());
+        assert_eq!(TerrainDownloadHandler::stale_request(params).unwrap(), None);
+        assert_eq!(TerrainDownloadHandler::reply_formatter(params).expect("default version").version(), 1);
+        //  But an impostor request still needs a grid. That's a bad request, not a server failure.
+        let err = TerrainDownloadHandler::build_sql_query(params).expect_err("no grid accepted");
+        assert_eq!(status_for(&err), 400);
+        assert!(err.to_string().contains("grid"), "{}", err);
+    }
+}
+
+#[test]
+fn test_select_row_mapping() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    let row = |grid: DbValue, faces_json: &str, water_only: u64| DbRow(vec![grid, DbValue::UInt(256000), DbValue::UInt(256512), DbValue::text("Ahern"),
+        DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::Float(40.5), DbValue::Float(12.25),
+        DbValue::UInt(0), DbValue::UInt(0), DbValue::Null, DbValue::text("4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01"), DbValue::Float(20.0),
+        DbValue::text("creator"), DbValue::text("2026-03-01 00:00:00"), DbValue::text(faces_json), DbValue::UInt(4), DbValue::Null, DbValue::UInt(0), DbValue::Float(1744.0),
+        DbValue::UInt(water_only)]);
+    let mut fake = FakeDb::new_with_results(vec![vec![
+        row(DbValue::text("agni"), "[]", 0),
+        row(DbValue::text("agni"), "not json", 0),
+        row(DbValue::Null, "[]", 0),
+        row(DbValue::text("agni"), "[]", 1)]]);
+    let query: HashMap<String, String> = [("QUERY_STRING".to_string(), "grid=agni&x=256000&y=256512".to_string())].into_iter().collect();
+    let results = TerrainDownloadHandler::do_select(&mut fake, &query).expect("select");
+    assert_eq!(fake.sql().len(), 1);
+    assert!(fake.sql()[0].starts_with("SELECT grid, region_loc_x"));
+    //  One good row. Bad rows are errors, but do not fail the query.
+    assert_eq!(results.len(), 4);
+    let rd = results[0].as_ref().expect("good row");
+    assert_eq!(rd.grid, "agni");
+    assert_eq!(rd.region_loc, [common::GlobalMeters(256000), common::GlobalMeters(256512)]);
+    assert_eq!(rd.name, Some("Ahern".to_string()));
+    assert_eq!(rd.scale, [256.0, 256.0, 40.5]);
+    assert_eq!(rd.elevation_offset, 12.25);
+    assert!(rd.mesh_uuid.is_none());
+    assert!(rd.sculpt_uuid.is_some());
+    assert_eq!(rd.water_height, Some(20.0));
+    assert_eq!(rd.water_height_max, None);
+    assert!(rd.faces.is_empty());
+    assert_eq!(rd.generation, 4);
+    assert!(!rd.placeholder);
+    assert_eq!(rd.lod_distance, 1744.0);
+    assert!(!rd.water_only);
+    assert!(results[1].is_err());
+    assert!(results[2].is_err());
+    //  A water-only tile says so in the reply, so the viewer fetches nothing for it.
+    let water = results[3].as_ref().expect("water-only row");
+    assert!(water.water_only);
+    let reply = RegionImpostorReply { version: 0, impostors: vec![water.clone()], errors: Vec::new() };
+    let json = ReplyFormatter::new_from_param(Some("2")).unwrap().format(&reply).unwrap();
+    assert!(json.contains(r#""water_only":true"#), "{}", json);
+}
+
+#[test]
+fn test_terrain_request() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    assert_eq!(TerrainDownloadHandler::terrain_request(&query("grid=agni&x=256&y=512&format=heights")).unwrap(),
+        Some(TerrainRequest { grid: "agni".to_string(), region_loc: [256, 512], format: TerrainFormat::Heights }));
+    assert_eq!(TerrainDownloadHandler::terrain_request(&query("grid=agni&x=256&y=512&format=PNG16")).unwrap().unwrap().format, TerrainFormat::Png16);
+    //  Grid names are canonical, however they're sent.
+    for grid in ["Agni", "AGNI", "%41gni", "%20agni"] {
+        assert_eq!(TerrainDownloadHandler::terrain_request(&query(&format!("grid={}&x=256&y=512&format=heights", grid))).unwrap().unwrap().grid, "agni");
+        assert_eq!(TerrainDownloadHandler::stale_request(&query(&format!("grid={}&stale_days=30", grid))).unwrap(), Some(("agni".to_string(), 30)));
+    }
+    //  Impostor requests are not terrain requests.
+    assert_eq!(TerrainDownloadHandler::terrain_request(&query("grid=agni&x=256&y=512")).unwrap(), None);
+    for bad in ["grid=agni&x=256&y=512&format=obj", "grid=agni&x=256&format=heights", "x=256&y=512&format=heights", "grid=agni&x=west&y=512&format=png16"] {
+        assert!(TerrainDownloadHandler::terrain_request(&query(bad)).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_terrain_replies() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    //  3 samples in X, 2 in Y.
+    let row = || DbRow(vec![DbValue::UInt(256), DbValue::UInt(256), DbValue::text("Ahern"), DbValue::UInt(3), DbValue::UInt(2),
+        DbValue::Float(25.5), DbValue::Float(20.0), DbValue::Bytes(vec![0x00, 0x0a, 0x10, 0x1b, 0x80, 0xff]), DbValue::Float(20.0), DbValue::text("bot"),
+        DbValue::Null, DbValue::Null]);
+    let request = |format| TerrainRequest { grid: "Agni".to_string(), region_loc: [256000, 256512], format };
+    let mut fake = FakeDb::new_with_results(vec![vec![row()]]);
+    let reply = TerrainDownloadHandler::process_terrain_request(&mut fake, &request(TerrainFormat::Heights)).unwrap();
+    assert_eq!((reply.status, reply.content_type), (200, "application/json"));
+    assert_eq!(String::from_utf8(reply.body.clone()).unwrap(), concat!(r#"{"grid":"agni","region_coords":[256000,256512],"size":[256,256],"samples":[3,2],"#,
+        r#""scale":25.5,"offset":20.0,"water_level":20.0,"elevs":["000A","101B","80FF"]}"#));
+    //  Same terrain, same ETag.
+    assert!(reply.matches_etag(&reply.etag()));
+    assert!(reply.matches_etag(&format!("\"0000\", W/{}", reply.etag())));
+    assert!(!reply.matches_etag("\"0000\""));
+    //  PNG.
+    let mut fake = FakeDb::new_with_results(vec![vec![row()]]);
+    let reply = TerrainDownloadHandler::process_terrain_request(&mut fake, &request(TerrainFormat::Png16)).unwrap();
+    assert_eq!((reply.status, reply.content_type), (200, "image/png"));
+    assert!(reply.body.starts_with(&[0x89, b'P', b'N', b'G']));
+    //  No such region.
+    let mut fake = FakeDb::default();
+    let reply = TerrainDownloadHandler::process_terrain_request(&mut fake, &request(TerrainFormat::Heights)).unwrap();
+    assert_eq!(reply.status, 404);
+    assert_eq!(fake.sql().len(), 1);
+}
+
+#[test]
+fn test_terrain_rate_limit() {
+    let start = Instant::now();
+    let from = |addr: &str| {
+        let mut request = Request::new();
+        request.params = Some([("REMOTE_ADDR".to_string(), addr.to_string())].into_iter().collect());
+        request
+    };
+    let mut limiter = RateLimiter::new(60, 2, 100);
+    let (viewer, other) = (from("192.0.2.1"), from("192.0.2.2"));
+    assert!(TerrainDownloadHandler::rate_limited(&mut limiter, &viewer, start).is_none());
+    assert!(TerrainDownloadHandler::rate_limited(&mut limiter, &viewer, start).is_none());
+    let limited = TerrainDownloadHandler::rate_limited(&mut limiter, &viewer, start).expect("over the limit");
+    assert_eq!(limited[0], "Status: 429 Too Many Requests");
+    assert_eq!(limited[2], "Retry-After: 1");
+    //  Another client isn't held up, and the first gets going again after a while.
+    assert!(TerrainDownloadHandler::rate_limited(&mut limiter, &other, start).is_none());
+    assert!(TerrainDownloadHandler::rate_limited(&mut limiter, &viewer, start + Duration::from_secs(1)).is_none());
+}
+
+#[test]
+fn test_coverage_map() {
+    use common::{LodCoverage, rle_decode};
+    use common::db::{DbRow, DbValue, FakeDb};
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    assert_eq!(TerrainDownloadHandler::coverage_request(&query("grid=Agni&mode=coverage")).unwrap(), Some("agni".to_string()));
+    assert_eq!(TerrainDownloadHandler::coverage_request(&query("grid=agni")).unwrap(), None);
+    assert!(TerrainDownloadHandler::coverage_request(&query("grid=agni&mode=bogus")).is_err());
+    assert!(TerrainDownloadHandler::coverage_request(&query("mode=coverage")).is_err());
+    assert_eq!(TerrainDownloadHandler::coverage_request(&query("grid=agni&mode=retired")).unwrap(), None);
+    //  A 4x4 block of regions with a varregion-sized 2x2 hole in the middle of the east side,
+    //  plus one LOD 1 tile.
+    let row = |x: u32, y: u32, size: u32, lod: u8| DbRow(vec![DbValue::UInt(x as u64), DbValue::UInt(y as u64),
+        DbValue::UInt(size as u64), DbValue::UInt(size as u64), DbValue::UInt(lod as u64)]);
+    let mut rows: Vec<DbRow> = (0..4).flat_map(|y| (0..4).map(move |x| (x, y)))
+        .filter(|&(x, y)| !(x >= 2 && (1..3).contains(&y)))
+        .map(|(x, y)| row(1000 * 256 + x * 256, 1000 * 256 + y * 256, 256, 0))
+        .collect();
+    rows.push(row(1000 * 256, 1000 * 256, 512, 1));
+    let mut fake = FakeDb::new_with_results(vec![rows.clone(), rows]);
+    let mut cache = CoverageCache::default();
+    let start = Instant::now();
+    let reply = cache.get(&mut fake, "Agni", start).unwrap();
+    assert_eq!(reply.grid, "agni");
+    assert_eq!(reply.lods.len(), 2);
+    let LodCoverage { lod, tile_size, origin, tiles, runs } = reply.lods[0].clone();
+    assert_eq!((lod, tile_size, origin, tiles), (0, 256, [256000, 256000], [4, 4]));
+    assert_eq!(runs, vec![0, 6, 2, 2, 2, 4]);
+    let expected: Vec<bool> = [1, 1, 1, 1, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1, 1, 1].iter().map(|&b| b == 1).collect();
+    assert_eq!(rle_decode(&runs), expected);
+    assert!(!reply.lods[0].is_covered(256000 + 600, 256000 + 300));
+    assert_eq!((reply.lods[1].lod, reply.lods[1].tiles, reply.lods[1].runs.clone()), (1, [1, 1], vec![0, 1]));
+    //  Cached for a minute, then read again.
+    assert_eq!(cache.get(&mut fake, "agni", start + Duration::from_secs(30)).unwrap(), reply);
+    assert_eq!(fake.sql().len(), 1);
+    assert_eq!(cache.get(&mut fake, "agni", start + COVERAGE_CACHE_TIME).unwrap(), reply);
+    assert_eq!(fake.sql().len(), 2);
+    assert!(fake.sql()[0].contains("FROM region_impostors WHERE grid = :grid"));
+    assert!(fake.sql()[0].contains(NOT_DELETED_IMPOSTOR));
+    //  Asking for many grids doesn't grow the cache without limit. The oldest goes first.
+    let mut fake = FakeDb::default();
+    for n in 0..MAX_COVERAGE_GRIDS + 10 {
+        cache.get(&mut fake, &format!("grid{}", n), start + Duration::from_millis(n as u64)).unwrap();
+    }
+    assert_eq!(cache.grids.len(), MAX_COVERAGE_GRIDS);
+    assert!(!cache.grids.contains_key("grid0") && cache.grids.contains_key(&format!("grid{}", MAX_COVERAGE_GRIDS + 9)));
+}
+
+#[test]
+fn test_retired_request() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    assert_eq!(TerrainDownloadHandler::retired_request(&query("grid=Agni&mode=retired&since=2026-03-01")).unwrap(),
+        Some(("agni".to_string(), "2026-03-01 00:00:00".to_string())));
+    assert_eq!(TerrainDownloadHandler::retired_request(&query("grid=agni&mode=Retired")).unwrap(),
+        Some(("agni".to_string(), "1970-01-01 00:00:00".to_string())));
+    assert_eq!(TerrainDownloadHandler::retired_request(&query("grid=agni&mode=coverage")).unwrap(), None);
+    assert_eq!(TerrainDownloadHandler::retired_request(&query("grid=agni")).unwrap(), None);
+    assert!(TerrainDownloadHandler::retired_request(&query("grid=agni&mode=retired&since=last%20week")).is_err());
+    assert!(TerrainDownloadHandler::retired_request(&query("mode=retired")).is_err());
+}
+
+#[test]
+fn test_changes_request() {
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    assert_eq!(TerrainDownloadHandler::changes_request(&query("grid=Agni&viz_group=3&changes_since=12")).unwrap(),
+        Some(("agni".to_string(), 3, ChangesSince::Generation(12))));
+    assert_eq!(TerrainDownloadHandler::changes_request(&query("grid=agni&viz_group=3&changes_since=2026-03-01T10:00:00")).unwrap(),
+        Some(("agni".to_string(), 3, ChangesSince::Time("2026-03-01 10:00:00".to_string()))));
+    assert_eq!(TerrainDownloadHandler::changes_request(&query("grid=agni&viz_group=3")).unwrap(), None);
+    for bad in ["grid=agni&changes_since=12", "grid=agni&viz_group=x&changes_since=12", "viz_group=3&changes_since=12", "grid=agni&viz_group=3&changes_since=later"] {
+        assert!(TerrainDownloadHandler::changes_request(&query(bad)).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_debug_authorization() {
+    let now = 1_775_000_000;
+    let secrets = |k: &str| (k == "ADMIN_AUTH_ADMIN_1").then(|| "sekrit".to_string());
+    let signed = |q: &str, secret: &str| {
+        let mut request = Request::new();
+        request.params = Some([("QUERY_STRING", q.to_string()), ("HTTP_X_AUTHTOKEN_NAME", "ADMIN_1".to_string()),
+            ("HTTP_X_AUTHTOKEN_HASH", Authorizer::sign(secret, q.as_bytes()))].into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+        request
+    };
+    let authorize = |guard: &mut ReplayGuard, q: &str, secret: &str| TerrainDownloadHandler::authorize_query(guard, AuthorizeType::Debug, &signed(q, secret), now, secrets);
+    let mut guard = ReplayGuard::new(true, REPLAY_CACHE_SIZE);
+    let query = format!("grid=agni&mode=debug&x=256000&y=256512&nonce=n1&sent_at={}", now);
+    authorize(&mut guard, &query, "sekrit").expect("fresh");
+    //  The same request again is a replay.
+    assert!(authorize(&mut guard, &query, "sekrit").is_err());
+    //  Signed long ago, or without a nonce and time.
+    assert!(authorize(&mut guard, &format!("grid=agni&mode=debug&x=256000&y=256512&nonce=n2&sent_at={}", now - 3600), "sekrit").is_err());
+    assert!(authorize(&mut guard, "grid=agni&mode=debug&x=256000&y=256512", "sekrit").is_err());
+    //  Bad signature. Its nonce isn't used up.
+    let query = format!("grid=agni&mode=debug&x=256000&y=256512&nonce=n3&sent_at={}", now);
+    assert!(authorize(&mut guard, &query, "guess").is_err());
+    authorize(&mut guard, &query, "sekrit").expect("nonce not used up");
+    //  The retired asset list is checked the same way, with its own tokens.
+    let secrets = |k: &str| (k == "AUTH_CLEANUP_1").then(|| "sekrit".to_string());
+    let mut request = signed(&format!("grid=agni&mode=retired&nonce=r1&sent_at={}", now), "sekrit");
+    request.params.as_mut().unwrap().insert("HTTP_X_AUTHTOKEN_NAME".to_string(), "CLEANUP_1".to_string());
+    TerrainDownloadHandler::authorize_query(&mut guard, AuthorizeType::RetiredAssets, &request, now, secrets).expect("retired");
+    assert!(TerrainDownloadHandler::authorize_query(&mut guard, AuthorizeType::RetiredAssets, &request, now, secrets).is_err());
+}
+
+#[test]
+fn test_debug_request() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    let query = |q: &str| -> HashMap<String, String> { [("QUERY_STRING".to_string(), q.to_string())].into_iter().collect() };
+    assert_eq!(TerrainDownloadHandler::debug_request(&query("grid=Agni&mode=debug&x=256000&y=256512")).unwrap(), Some(("agni".to_string(), 256000, 256512)));
+    assert_eq!(TerrainDownloadHandler::debug_request(&query("grid=agni&x=256000&y=256512")).unwrap(), None);
+    assert_eq!(TerrainDownloadHandler::debug_request(&query("grid=agni&mode=coverage")).unwrap(), None);
+    for bad in ["grid=agni&mode=debug", "grid=agni&mode=debug&x=256000&y=north", "mode=debug&x=256000&y=256512"] {
+        assert!(TerrainDownloadHandler::debug_request(&query(bad)).is_err(), "{}", bad);
+    }
+    //  Viewer requests never read provenance.
+    for q in ["grid=agni", "grid=agni&x=256000&y=256512", "grid=agni&viz_group=3"] {
+        let (stmt, _, _, _, _) = TerrainDownloadHandler::build_sql_query(&query(q)).expect("query");
+        assert!(!stmt.contains(PROVENANCE_COLUMN), "{}", stmt);
+    }
+    //  Debug requests do.
+    let provenance = r#"{"built_at":1775000000,"generator_version":"0.1.0","git_hash":null}"#;
+    let row = |provenance: DbValue| DbRow(vec![DbValue::text("agni"), DbValue::UInt(256000), DbValue::UInt(256512), DbValue::text("Ahern"),
+        DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(256), DbValue::Float(40.5), DbValue::Float(12.25),
+        DbValue::UInt(0), DbValue::UInt(0), DbValue::Null, DbValue::text("4f0c7b9e-0c6e-4d5a-9d3e-2b7f6a1c8e01"), DbValue::Float(20.0),
+        DbValue::text("creator"), DbValue::text("2026-03-01 00:00:00"), DbValue::text("[]"), DbValue::UInt(4), DbValue::Null, DbValue::UInt(0), DbValue::Float(1744.0),
+        DbValue::UInt(0), provenance]);
+    let mut fake = FakeDb::new_with_results(vec![vec![row(DbValue::text(provenance)), row(DbValue::Null)]]);
+    let json = TerrainDownloadHandler::process_debug_request(&mut fake, "agni", 256000, 256512).unwrap();
+    assert!(fake.sql()[0].contains(&format!(", {} FROM region_impostors", PROVENANCE_COLUMN)), "{}", fake.sql()[0]);
+    assert!(json.contains(&format!(r#""provenance":{}"#, provenance)), "{}", json);
+    assert!(json.contains(r#""provenance":null"#), "{}", json);
+    assert!(json.contains(r#""name":"Ahern""#), "{}", json);
+    //  The same row, in a viewer reply, has none.
+    let impostor = RegionImpostorData::from_db_row_with_provenance(&row(DbValue::text(provenance))).unwrap();
+    assert!(impostor.provenance_json.is_some());
+    let reply = RegionImpostorReply { version: 0, impostors: vec![impostor], errors: Vec::new() };
+    for version in ["1", "2"] {
+        let json = ReplyFormatter::new_from_param(Some(version)).unwrap().format(&reply).unwrap();
+        assert!(!json.contains("provenance") && !json.contains("generator_version"), "{}", json);
+    }
+}
@@ -0,0 +1,414 @@
+//! Log vehicle events from Second Life to a database
+//! Part of the Animats impostor system
+//!
+//! Vehicle scripts in Second Life report events, such as region crossing
+//! trouble, to this FCGI responder, which adds them to the events table
+//! and queues the trip for later analysis.
+//!
+//! Requests are signed. The script sends X-Authtoken-Name and X-Authtoken-Hash
+//! headers, where the hash is hex SHA-1 of (secret || body). SHA-1 is what
+//! the existing vehicle scripts send.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+#![forbid(unsafe_code)]
+use anyhow::anyhow;
+use common::init_fcgi;
+use common::{Credentials, Handler, Request, Response, constant_time_eq};
+use common::db::with_conn;
+use common::metrics::{ConnectionLimits, Metrics};
+use log::LevelFilter;
+use mysql::{Pool, PooledConn, TxOpts};
+use mysql::prelude::Queryable;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::error::Error;
+use std::io::Write;
+use sha1::{Sha1, Digest};
+
+/// MySQL credentials and authorization tokens for event logging.
+/// This filename will be searched for in parent directories,
+/// so it can be placed above the web root, where the web server can't see it.
+/// The credentials file must contain
+///
+///     DB_USER = username
+///     DB_PASS = databasepassword
+///     DB_HOST = hostname
+///     DB_PORT = portnumber (optional, defaults to 3306)
+///     DB_NAME = databasename
+///     LOG_AUTH_TOKENS = name1, name2
+///
+/// and a secret for each token name.
+///
+///     AUTH_NAME1 = secret
+///
+const EVENTLOG_CREDS_FILE: &str = "eventlog_credentials.txt";
+/// Key with the list of token names.
+const LOG_AUTH_TOKENS_KEY: &str = "LOG_AUTH_TOKENS";
+/// Credentials key prefix for token secrets.
+const AUTH_SECRET_PREFIX: &str = "AUTH_";
+
+/// Debug logging
+fn logger() {
+    //  Log file is openly visible as a web page.
+    //  Only for debug tests.
+    const LOG_FILE_NAME: &str = "logs/eventlog.txt";
+    let _ = simplelog::CombinedLogger::init(vec![simplelog::WriteLogger::new(
+        LevelFilter::Debug,
+        simplelog::Config::default(),
+        std::fs::File::create(LOG_FILE_NAME).expect("Unable to create log file"),
+    )]);
+    log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SlVector {
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl fmt::Display for SlVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SlRegion {
+    name: String,
+    x: i32,
+    y: i32,
+}
+
+impl fmt::Display for SlRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({},{})", self.name, self.x, self.y)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SlHeader {
+    owner_name: String,
+    shard: String,
+    object_name: String,
+    region: SlRegion,
+    local_position: SlVector,
+}
+
+impl fmt::Display for SlHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "owner_name: \"{}\"  object_name: \"{}\"  region: {}  local_position: {}",
+            self.owner_name, self.object_name, self.region, self.local_position
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VehLogEvent {
+    timestamp: i64,
+    serial: i32,
+    tripid: String,
+    severity: i8,
+    eventtype: String,
+    msg: String,
+    auxval: f32,
+    debug: i8,
+}
+
+impl fmt::Display for VehLogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timestamp: {}  tripid: \"{}\"  severity: {}  eventtype: {}  msg: {}  auxval: {}",
+            self.timestamp, self.tripid, self.severity, self.eventtype, self.msg, self.auxval
+        )
+    }
+}
+
+/// Authorization tokens, name to secret.
+#[derive(Clone)]
+struct VdbConfig {
+    authkey: HashMap<String, String>,
+}
+
+impl VdbConfig {
+    /// Token names and secrets from the credentials file.
+    fn new_from_credentials(creds: &Credentials) -> Result<Self, anyhow::Error> {
+        let names = creds.get(LOG_AUTH_TOKENS_KEY).ok_or_else(|| anyhow!("Credentials are missing {}", LOG_AUTH_TOKENS_KEY))?;
+        let mut authkey = HashMap::new();
+        for name in names.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let key = format!("{}{}", AUTH_SECRET_PREFIX, name.to_uppercase());
+            let secret = creds.get(&key).ok_or_else(|| anyhow!("Credentials are missing {} for token \"{}\"", key, name))?;
+            authkey.insert(name.to_string(), secret);
+        }
+        Ok(Self { authkey })
+    }
+}
+
+impl fmt::Display for VdbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        //  Names only, never secrets.
+        let keys = self.authkey.keys().cloned().collect::<Vec<_>>().join(" ");
+        write!(f, "authkeys: {}", keys)
+    }
+}
+
+// Parses "Vallone (462592, 306944)"
+fn parse_slregion(s: &str) -> Result<SlRegion, Box<dyn Error>> {
+    let ix = s.rfind('(').ok_or("SL region location not in expected format")?;
+    let name = s[..ix].trim().to_string();
+    let coords = s[ix..].trim_matches(|c| c == '(' || c == ')');
+    let parts: Vec<&str> = coords.split(',').map(|x| x.trim()).collect();
+    if parts.len() != 2 {
+        return Err("Failed to parse SL region coordinates".into());
+    }
+    Ok(SlRegion {
+        name,
+        x: parts[0].parse()?,
+        y: parts[1].parse()?,
+    })
+}
+
+// Parses "(204.783539, 26.682831, 35.563702)"
+fn parse_slvector(s: &str) -> Result<SlVector, Box<dyn Error>> {
+    let s = s.trim_matches(|c| c == '(' || c == ')');
+    let parts: Vec<&str> = s.split(',').map(|x| x.trim()).collect();
+    if parts.len() != 3 {
+        return Err("Failed to parse SL vector".into());
+    }
+    Ok(SlVector {
+        x: parts[0].parse()?,
+        y: parts[1].parse()?,
+        z: parts[2].parse()?,
+    })
+}
+
+fn get_header_field(headers: &HashMap<String, String>, key: &str) -> Result<String, Box<dyn Error>> {
+    let v = headers.get(key)
+        .ok_or(format!("HTTP header from Second Life was missing field \"{}\"", key))?
+        .trim().to_string();
+    if v.is_empty() {
+        return Err(format!("HTTP header from Second Life was missing field \"{}\"", key).into());
+    }
+    Ok(v)
+}
+
+fn parse_header(headers: &HashMap<String, String>) -> Result<SlHeader, Box<dyn Error>> {
+    Ok(SlHeader {
+        owner_name: get_header_field(headers, "X-Secondlife-Owner-Name")?,
+        object_name: get_header_field(headers, "X-Secondlife-Object-Name")?,
+        shard: get_header_field(headers, "X-Secondlife-Shard")?,
+        region: parse_slregion(&get_header_field(headers, "X-Secondlife-Region")?)?,
+        local_position: parse_slvector(&get_header_field(headers, "X-Secondlife-Local-Position")?)?,
+    })
+}
+
+fn parse_veh_event(s: &[u8]) -> Result<VehLogEvent, Box<dyn Error>> {
+    let event: VehLogEvent = serde_json::from_slice(s)?;
+    if event.tripid.len() != 40 {
+        return Err(format!("Trip ID \"{}\" from Second Life was not 40 bytes long", event.tripid).into());
+    }
+    Ok(event)
+}
+
+fn hash_with_token(token: &[u8], s: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(token);
+    hasher.update(s);
+    hex::encode(hasher.finalize())
+}
+
+fn validate_auth_token(s: &[u8], name: &str, value: &str, config: &VdbConfig) -> Result<(), Box<dyn Error>> {
+    let token = config.authkey.get(name).ok_or(format!("Logging authorization token \"{}\" not recognized.", name))?;
+    let hash = hash_with_token(token.as_bytes(), s);
+    //  The log is readable on the web. Never put the expected hash or the body in it.
+    if !constant_time_eq(hash.as_bytes(), value.as_bytes()) {
+        return Err(format!("Logging authorization token \"{}\" failed to validate.", name).into());
+    }
+    Ok(())
+}
+
+fn insert_event(conn: &mut impl Queryable, hdr: &SlHeader, ev: &VehLogEvent) -> Result<(), Box<dyn Error>> {
+    conn.exec_drop(r"INSERT INTO events 
+        (time, shard, owner_name, object_name, region_name, region_corner_x, region_corner_y, local_position_x, local_position_y, local_position_z, tripid, severity, eventtype, msg, auxval, serial)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        (
+            ev.timestamp,
+            &hdr.shard,
+            &hdr.owner_name,
+            &hdr.object_name,
+            &hdr.region.name,
+            hdr.region.x,
+            hdr.region.y,
+            hdr.local_position.x,
+            hdr.local_position.y,
+            hdr.local_position.z,
+            &ev.tripid,
+            ev.severity,
+            &ev.eventtype,
+            &ev.msg,
+            ev.auxval,
+            ev.serial,
+        ))?;
+    Ok(())
+}
+
+fn insert_todo(conn: &mut impl Queryable, tripid: &str) -> Result<(), Box<dyn Error>> {
+    conn.exec_drop(
+        r"INSERT INTO tripstodo (tripid) VALUES (?) ON DUPLICATE KEY UPDATE stamp=NOW()",
+        (tripid,)
+    )?;
+    Ok(())
+}
+
+fn db_update(conn: &mut PooledConn, hdr: &SlHeader, ev: &VehLogEvent) -> Result<(), Box<dyn Error>> {
+    let mut tx = conn.start_transaction(TxOpts::default())?;
+    insert_event(&mut tx, hdr, ev)?;
+    insert_todo(&mut tx, &ev.tripid)?;
+    tx.commit()?;
+    Ok(())
+}
+
+///  Our handler
+struct EventLogHandler {
+    /// MySQL connection pool. Each request gets a connection from it.
+    pool: Pool,
+    /// Authorization tokens
+    config: VdbConfig,
+}
+
+impl EventLogHandler {
+    /// Usual new.
+    pub fn new(pool: Pool, config: VdbConfig) -> Self {
+        Self { pool, config }
+    }
+
+    /// Check the signature. Errors here are 403.
+    fn authorize(&self, bodycontent: &[u8], headers: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+        validate_auth_token(
+            bodycontent,
+            headers.get("X-Authtoken-Name").map(|s| s.trim()).unwrap_or(""),
+            headers.get("X-Authtoken-Hash").map(|s| s.trim()).unwrap_or(""),
+            &self.config,
+        )
+    }
+
+    /// The main logic to add an event. Errors here are 500.
+    fn add_event(&self, bodycontent: &[u8], headers: &HashMap<String, String>) -> Result<(), anyhow::Error> {
+        let hdr = parse_header(headers).map_err(|e| anyhow!("{}", e))?;
+        let ev = parse_veh_event(bodycontent).map_err(|e| anyhow!("{}", e))?;
+        log::info!("Event: {}  {}", hdr, ev);
+        with_conn(&self.pool, |conn| db_update(conn, &hdr, &ev).map_err(|e| anyhow!("{}", e)))
+    }
+}
+
+//  Our "handler"
+impl Handler for EventLogHandler {
+    fn handler(
+        &mut self,
+        out: &mut dyn Write,
+        request: &Request,
+        _env: &HashMap<String, String>,
+    ) -> Result<(), anyhow::Error> {
+        if request.params.is_none() {
+            return Err(anyhow!("No HTTP parameters found"));
+        }
+        let headers = request.headers();
+        let body = &request.standard_input;
+        if let Err(e) = self.authorize(body, headers) {
+            //  Details go to the log, not to the caller.
+            log::warn!("Event rejected: {}", e);
+            let http_response = Response::http_response("text/plain", 403, "Not authorized");
+            Response::write_response(out, request, http_response.as_slice(), &[])?;
+            return Ok(());
+        }
+        match self.add_event(body, headers) {
+            Ok(()) => {
+                let http_response = Response::http_response("text/plain", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), b"Event added")?;
+            }
+            Err(e) => {
+                log::error!("Event not added: {:?}", e);
+                let http_response = Response::http_response(
+                    "text/plain",
+                    500,
+                    format!("Internal server error: {}", e).as_str(),
+                );
+                Response::write_response(out, request, http_response.as_slice(), &[])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the responder.
+pub fn run_responder() -> Result<(), anyhow::Error> {
+    //  Set up in and out sockets.
+    //  See init_fcgi for how it is done.
+    let listener = init_fcgi()?;
+    //  Connect to the database and load tokens.
+    let pool = common::db::connect(EVENTLOG_CREDS_FILE)?;
+    let creds = Credentials::new_with_env(EVENTLOG_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let config = VdbConfig::new_from_credentials(&creds)?;
+    log::info!("Event logger config: {}", config);
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| creds.get(k)).map_err(|e| anyhow!("{}: {}", EVENTLOG_CREDS_FILE, e))?;
+    let mut event_log_handler = EventLogHandler::new(pool, config);
+    //  Run the FCGI server, one connection from the parent process at a time.
+    common::serve(&listener, &mut event_log_handler, &Metrics::new(), &connection_limits)
+}
+
+/// Main program
+pub fn main() {
+    logger();
+    match run_responder() {
+        Ok(()) => {}
+        Err(e) => {
+            log::error!("Event logger failed: {:?}", e);
+            panic!("Event logger failed: {:?}", e);
+        }
+    }
+}
+
+#[test]
+fn test_parse_slregion() {
+    let region = parse_slregion("Vallone (462592, 306944)").expect("region");
+    assert_eq!(region.name, "Vallone");
+    assert_eq!((region.x, region.y), (462592, 306944));
+    let region = parse_slregion("Sim With Spaces(256,512)").expect("region, no spaces");
+    assert_eq!(region.name, "Sim With Spaces");
+    assert_eq!((region.x, region.y), (256, 512));
+    assert!(parse_slregion("Vallone").is_err());
+    assert!(parse_slregion("Vallone (1, 2, 3)").is_err());
+    assert!(parse_slregion("Vallone (x, 2)").is_err());
+}
+
+#[test]
+fn test_parse_slvector() {
+    let v = parse_slvector("(204.783539, 26.682831, 35.563702)").expect("vector");
+    assert_eq!((v.x, v.y, v.z), (204.783539, 26.682831, 35.563702));
+    assert!(parse_slvector("(1.0, 2.0)").is_err());
+    assert!(parse_slvector("(1.0, 2.0, zed)").is_err());
+}
+
+#[test]
+fn test_auth_hash() {
+    //  Known SHA-1 values. "abc", and the empty string.
+    assert_eq!(hash_with_token(b"ab", b"c"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hash_with_token(b"", b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    let config = VdbConfig { authkey: [("logger1".to_string(), "sekrit".to_string())].into_iter().collect() };
+    let body = b"{\"tripid\":\"x\"}";
+    let hash = hash_with_token(b"sekrit", body);
+    assert!(validate_auth_token(body, "logger1", &hash, &config).is_ok());
+    assert!(validate_auth_token(body, "logger1", &hash_with_token(b"guess", body), &config).is_err());
+    assert!(validate_auth_token(body, "logger2", &hash, &config).is_err());
+    assert!(validate_auth_token(b"{}", "logger1", &hash, &config).is_err());
+    //  A failure doesn't reveal the right signature, or echo the body.
+    let err = validate_auth_token(body, "logger1", "0000", &config).unwrap_err().to_string();
+    assert!(!err.contains(&hash) && !err.contains("tripid"), "{}", err);
+}
@@ -0,0 +1,103 @@
+//! All the responders, as one FCGI program.
+//! Part of the Animats impostor system
+//!
+//! Each responder can run as its own program, with its own web server
+//! config and database pool. This one runs them all in one process,
+//! picking the handler by path with a Router:
+//!
+//!     https://animats.info/actions/maptools_fcgi.fcgi/uploadterrain
+//!     https://animats.info/actions/maptools_fcgi.fcgi/downloadimpostor
+//!     https://animats.info/actions/maptools_fcgi.fcgi/uploadimpostor
+//!     https://animats.info/actions/maptools_fcgi.fcgi/status
+//!
+//! A copy or link of this program named after one of them, such as
+//! uploadterrain.fcgi, also works, since the route is matched on the end
+//! of the path. Anything else gets a 404.
+//!
+//! One database pool, one set of request metrics, and one credentials file,
+//! maptools_credentials.txt, are shared by all. It needs the database
+//! access and tokens of all of them. Changes to the file are picked up
+//! without a restart.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+#![forbid(unsafe_code)]
+use anyhow::{Error, anyhow};
+use log::LevelFilter;
+use common::{init_fcgi, Credentials, Router, WatchedCredentials};
+use common::metrics::{ConnectionLimits, Metrics, RequestLimits, METRICS_LOG_INTERVAL};
+
+//  The responders, built in here too. Their own main programs go unused.
+#[path = "uploadterrain.rs"]
+#[allow(dead_code)]
+mod uploadterrain;
+#[path = "downloadimpostor.rs"]
+#[allow(dead_code)]
+mod downloadimpostor;
+#[path = "uploadimpostor.rs"]
+#[allow(dead_code)]
+mod uploadimpostor;
+#[path = "status.rs"]
+#[allow(dead_code)]
+mod status;
+
+/// MySQL Credentials and tokens for all the responders.
+/// This filename will be searched for in parent directories,
+/// so it can be placed above the web root, where the web server can't see it.
+const MAPTOOLS_CREDS_FILE: &str = "maptools_credentials.txt";
+
+/// Debug logging
+fn logger() {
+    //  Log file is openly visible as a web page.
+    //  Only for debug tests.
+    const LOG_FILE_NAME: &str = "logs/maptoolslog.txt";
+    let _ = simplelog::CombinedLogger::init(vec![simplelog::WriteLogger::new(
+        LevelFilter::Debug,
+        simplelog::Config::default(),
+        std::fs::File::create(LOG_FILE_NAME).expect("Unable to create log file"),
+    )]);
+    log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
+}
+
+/// The router, with every responder on its path.
+/// The handlers share the watched credentials, so one reload is seen by all.
+fn new_router(pool: common::db::WatchedPool, secrets: &WatchedCredentials, metrics: &Metrics) -> Result<Router, Error> {
+    Ok(Router::default()
+        .add("/uploadterrain", Box::new(uploadterrain::TerrainUploadHandler::new(pool.clone(), secrets.clone(), metrics.clone())?))
+        .add("/downloadimpostor", Box::new(downloadimpostor::TerrainDownloadHandler::new(pool.clone(), secrets.clone(), metrics.clone())?))
+        .add("/uploadimpostor", Box::new(uploadimpostor::AssetUploadHandler::new(pool.clone(), secrets.clone())?))
+        .add("/status", Box::new(status::StatusHandler::new(status::DbStatusSource { pool }))))
+}
+
+/// The actual responder.
+pub fn run_responder() -> Result<(), Error> {
+    log::info!("Environment: {:?}", common::environment_names());
+    //  Set up the listener socket. See init_fcgi for how it is done.
+    let listener = init_fcgi()?;
+    //  Connect to the database, once for everybody. Credentials, with environment overrides.
+    let secrets = Credentials::watch_with_env(MAPTOOLS_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&secrets)?;
+    common::db::migrations::check_schema_if_enabled(&mut pool.get_conn()?);
+    let limits = RequestLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", MAPTOOLS_CREDS_FILE, e))?;
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", MAPTOOLS_CREDS_FILE, e))?;
+    let metrics = Metrics::new_with_log_interval(Some(METRICS_LOG_INTERVAL)).with_limits(limits);
+    let mut router = new_router(pool, &secrets, &metrics)?;
+    log::info!("Routes: {:?}", router.routes());
+    //  Run the FCGI server, one connection from the web server at a time.
+    //  Download replies can be big, so reading and writing are on separate threads.
+    common::serve(&listener, &mut router, &metrics, &connection_limits)
+}
+
+/// Main program
+pub fn main() {
+    logger();
+    match run_responder() {
+        Ok(()) => {}
+        Err(e) => {
+            log::error!("Maptools server failed: {:?}", e);
+            panic!("Maptools server failed: {:?}", e);
+        }
+    }
+}
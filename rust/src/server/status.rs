@@ -0,0 +1,307 @@
+//! Status of the responders and their database, for monitoring.
+//! Part of the Animats impostor system
+//!
+//! Monitoring calls this to check that FCGI responders can run and reach
+//! the database, without doing a real upload.
+//!
+//!     https://animats.info/actions/status.fcgi
+//!
+//! Returns 200 and
+//!
+//!     {"status":"ok","version":"0.1.0","uptime_secs":3600,
+//!      "grids":{"agni":{"raw_terrain_heights":31000,"region_impostors":40000}}}
+//!
+//! or 503, naming the check which failed,
+//!
+//!     {"status":"failed","check":"database","reason":"..."}
+//!
+//! Row counts are cached for a minute, so monitoring doesn't load the database.
+//! There is no authentication. Anyone can read this data.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+#![forbid(unsafe_code)]
+use anyhow::{Error, anyhow};
+use log::LevelFilter;
+use common::init_fcgi;
+use common::{Credentials, Handler, Request, RequiredParams, Response};
+use common::db::WatchedPool;
+use common::metrics::{ConnectionLimits, Metrics};
+use mysql::prelude::Queryable;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// MySQL Credentials. The read-only download credentials are enough.
+/// This filename will be searched for in parent directories,
+/// so it can be placed above the web root, where the web server can't see it.
+const STATUS_CREDS_FILE: &str = "download_credentials.txt";
+/// Database must answer within this time.
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Row counts are reused for this long.
+const COUNTS_CACHE_TIME: Duration = Duration::from_secs(60);
+
+/// Rows per grid, for each table.
+const SQL_RAW_TERRAIN_COUNTS: &str = "SELECT LOWER(grid), COUNT(*) FROM raw_terrain_heights GROUP BY LOWER(grid)";
+const SQL_IMPOSTOR_COUNTS: &str = "SELECT LOWER(grid), COUNT(*) FROM region_impostors GROUP BY LOWER(grid)";
+
+/// Debug logging
+fn logger() {
+    //  Log file is openly visible as a web page.
+    //  Only for debug tests.
+    const LOG_FILE_NAME: &str = "logs/statuslog.txt";
+    let _ = simplelog::CombinedLogger::init(vec![simplelog::WriteLogger::new(
+        LevelFilter::Debug,
+        simplelog::Config::default(),
+        std::fs::File::create(LOG_FILE_NAME).expect("Unable to create log file"),
+    )]);
+    log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
+}
+
+/// Row counts for one grid.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub(crate) struct GridCounts {
+    raw_terrain_heights: u64,
+    region_impostors: u64,
+}
+
+/// What the checks look at. The database, except in tests.
+pub(crate) trait StatusSource {
+    /// Is the database answering?
+    fn check_db(&mut self) -> Result<(), Error>;
+    /// Row counts, by grid.
+    fn row_counts(&mut self) -> Result<BTreeMap<String, GridCounts>, Error>;
+}
+
+/// The real database.
+pub(crate) struct DbStatusSource {
+    pub(crate) pool: WatchedPool,
+}
+
+impl StatusSource for DbStatusSource {
+    /// SELECT 1, with a time limit. A hung connection leaves its thread behind,
+    /// but the responder still answers.
+    fn check_db(&mut self) -> Result<(), Error> {
+        let pool = self.pool.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let result = pool.get_conn().and_then(|mut conn| Ok(conn.query_drop("SELECT 1")?));
+            let _ = sender.send(result);
+        });
+        receiver.recv_timeout(DB_CHECK_TIMEOUT)
+            .map_err(|_| anyhow!("No answer from database in {:?}", DB_CHECK_TIMEOUT))?
+    }
+
+    fn row_counts(&mut self) -> Result<BTreeMap<String, GridCounts>, Error> {
+        let mut conn = self.pool.get_conn()?;
+        let mut counts: BTreeMap<String, GridCounts> = BTreeMap::new();
+        for (grid, n) in conn.query::<(String, u64), _>(SQL_RAW_TERRAIN_COUNTS)? {
+            counts.entry(grid).or_default().raw_terrain_heights = n;
+        }
+        for (grid, n) in conn.query::<(String, u64), _>(SQL_IMPOSTOR_COUNTS)? {
+            counts.entry(grid).or_default().region_impostors = n;
+        }
+        Ok(counts)
+    }
+}
+
+/// Reply body.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum StatusReply {
+    Ok {
+        version: String,
+        uptime_secs: u64,
+        grids: BTreeMap<String, GridCounts>,
+    },
+    Failed {
+        /// Name of the check which failed
+        check: String,
+        reason: String,
+    },
+}
+
+///  Our handler
+pub(crate) struct StatusHandler<S: StatusSource> {
+    /// Database, or test stand-in
+    source: S,
+    /// When this process started
+    start_time: Instant,
+    /// Last row counts, and when they were read
+    counts_cache: Option<(Instant, BTreeMap<String, GridCounts>)>,
+}
+
+impl<S: StatusSource> StatusHandler<S> {
+    /// Usual new.
+    pub fn new(source: S) -> Self {
+        Self { source, start_time: Instant::now(), counts_cache: None }
+    }
+
+    /// Row counts, from the cache if recent enough.
+    fn row_counts(&mut self, now: Instant) -> Result<BTreeMap<String, GridCounts>, Error> {
+        if let Some((fetched, counts)) = &self.counts_cache {
+            if now.duration_since(*fetched) < COUNTS_CACHE_TIME {
+                return Ok(counts.clone());
+            }
+        }
+        let counts = self.source.row_counts()?;
+        self.counts_cache = Some((now, counts.clone()));
+        Ok(counts)
+    }
+
+    /// Run the checks. Returns HTTP status and reply.
+    fn check(&mut self, now: Instant) -> (usize, StatusReply) {
+        let failed = |check: &str, e: Error| {
+            log::error!("Status check \"{}\" failed: {:?}", check, e);
+            (503, StatusReply::Failed { check: check.to_string(), reason: e.to_string() })
+        };
+        if let Err(e) = self.source.check_db() {
+            return failed("database", e);
+        }
+        match self.row_counts(now) {
+            Ok(grids) => (200, StatusReply::Ok {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_secs: now.duration_since(self.start_time).as_secs(),
+                grids,
+            }),
+            Err(e) => failed("row_counts", e),
+        }
+    }
+}
+
+//  Our "handler"
+impl<S: StatusSource> Handler for StatusHandler<S> {
+    fn handler(
+        &mut self,
+        out: &mut dyn Write,
+        request: &Request,
+        _env: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        //  Error 400, naming it, if the request method is missing.
+        let mut required = RequiredParams::new(request);
+        let request_method = required.require("REQUEST_METHOD");
+        if let Err(e) = required.finish() {
+            let http_response = Response::http_response("text/plain", 400, "Incorrect request");
+            Response::write_response(out, request, http_response.as_slice(), e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        //  This must be a GET
+        if request_method.to_uppercase().trim() != "GET" {
+            let http_response = Response::http_response("text/plain", 405, "Method not allowed");
+            Response::write_response(out, request, http_response.as_slice(), &[])?;
+            return Ok(());
+        }
+        let (status, reply) = self.check(Instant::now());
+        let http_response = Response::http_response("application/json", status, if status == 200 { "OK" } else { "Service unavailable" });
+        Response::write_response(out, request, http_response.as_slice(), serde_json::to_string(&reply)?.as_bytes())
+    }
+}
+
+/// Run the responder.
+pub fn run_responder() -> Result<(), Error> {
+    log::info!("Environment: {:?}", common::environment_names());
+    //  Set up in and out sockets. See init_fcgi for how it is done.
+    let listener = init_fcgi()?;
+    //  Connect to the database
+    let secrets = Credentials::watch_with_env(STATUS_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&secrets)?;
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", STATUS_CREDS_FILE, e))?;
+    let mut status_handler = StatusHandler::new(DbStatusSource { pool });
+    //  Run the FCGI server, one connection from the parent process at a time.
+    common::serve(&listener, &mut status_handler, &Metrics::new(), &connection_limits)
+}
+
+/// Main program
+pub fn main() {
+    logger();
+    match run_responder() {
+        Ok(()) => {}
+        Err(e) => {
+            log::error!("Status server failed: {:?}", e);
+            panic!("Status server failed: {:?}", e);
+        }
+    }
+}
+
+/// Stand-in for the database, for tests.
+#[cfg(test)]
+struct TestStatusSource {
+    db_error: Option<&'static str>,
+    counts_error: Option<&'static str>,
+    count_calls: usize,
+}
+
+#[cfg(test)]
+impl StatusSource for TestStatusSource {
+    fn check_db(&mut self) -> Result<(), Error> {
+        match self.db_error {
+            Some(msg) => Err(anyhow!(msg)),
+            None => Ok(()),
+        }
+    }
+
+    fn row_counts(&mut self) -> Result<BTreeMap<String, GridCounts>, Error> {
+        self.count_calls += 1;
+        match self.counts_error {
+            Some(msg) => Err(anyhow!(msg)),
+            None => Ok([("agni".to_string(), GridCounts { raw_terrain_heights: 30, region_impostors: 40 })].into_iter().collect()),
+        }
+    }
+}
+
+/// Run one request through the handler. Returns the FCGI output as text.
+#[cfg(test)]
+fn run_test_request(handler: &mut StatusHandler<TestStatusSource>, method: &str) -> String {
+    let request = Request::new_with_params(1, [("REQUEST_METHOD".to_string(), method.to_string())].into_iter().collect());
+    let mut out = Vec::new();
+    handler.handler(&mut out, &request, &HashMap::new()).expect("handler failed");
+    String::from_utf8_lossy(&out).to_string()
+}
+
+#[test]
+fn test_status_healthy() {
+    let mut handler = StatusHandler::new(TestStatusSource { db_error: None, counts_error: None, count_calls: 0 });
+    let out = run_test_request(&mut handler, "GET");
+    assert!(out.contains("Status: 200 OK"), "{}", out);
+    assert!(out.contains(r#"{"status":"ok","version":""#), "{}", out);
+    assert!(out.contains(r#""grids":{"agni":{"raw_terrain_heights":30,"region_impostors":40}}"#), "{}", out);
+    //  Counts are cached for a minute.
+    let now = Instant::now();
+    handler.check(now);
+    handler.check(now + Duration::from_secs(30));
+    assert_eq!(handler.source.count_calls, 1);
+    handler.check(now + COUNTS_CACHE_TIME + Duration::from_secs(1));
+    assert_eq!(handler.source.count_calls, 2);
+    //  GET only.
+    assert!(run_test_request(&mut handler, "POST").contains("Status: 405"));
+}
+
+#[test]
+fn test_status_failing() {
+    //  Database down. Counts not even tried.
+    let mut handler = StatusHandler::new(TestStatusSource { db_error: Some("Connection refused"), counts_error: None, count_calls: 0 });
+    assert_eq!(handler.check(Instant::now()), (503, StatusReply::Failed { check: "database".to_string(), reason: "Connection refused".to_string() }));
+    assert_eq!(handler.source.count_calls, 0);
+    let out = run_test_request(&mut handler, "GET");
+    assert!(out.contains("Status: 503 Service unavailable"), "{}", out);
+    assert!(out.contains(r#"{"status":"failed","check":"database","reason":"Connection refused"}"#), "{}", out);
+    //  Database up, but counting fails.
+    let mut handler = StatusHandler::new(TestStatusSource { db_error: None, counts_error: Some("Table doesn't exist"), count_calls: 0 });
+    assert_eq!(handler.check(Instant::now()), (503, StatusReply::Failed { check: "row_counts".to_string(), reason: "Table doesn't exist".to_string() }));
+}
+
+#[test]
+fn test_status_missing_method() {
+    //  No request method is the caller's mistake, not ours.
+    let mut handler = StatusHandler::new(TestStatusSource { db_error: None, counts_error: None, count_calls: 0 });
+    let request = Request::new_with_params(1, [("QUERY_STRING".to_string(), String::new())].into_iter().collect());
+    let mut out = Vec::new();
+    handler.handler(&mut out, &request, &HashMap::new()).expect("handler failed");
+    let out = String::from_utf8_lossy(&out).to_string();
+    assert!(out.contains("Status: 400"), "{}", out);
+    assert!(out.contains("REQUEST_METHOD"), "{}", out);
+    assert_eq!(handler.source.count_calls, 0);
+}
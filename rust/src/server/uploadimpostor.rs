@@ -4,6 +4,11 @@
 //! At this point, the asset exists on the SL/OS asset store.
 //! A script running in an SL/OS viewer calls this service to tell it about new assets.
 //!
+//! A JSON object, instead of the usual array, is an admin request, signed with
+//! an admin token, and with a nonce, so it can't be replayed. The one admin
+//! request is deleting a bad impostor.
+//! See common::impostordelete.
+//!
 //!     License: LGPL.
 //!     Animats
 //!     August, 2025.
@@ -11,18 +16,22 @@
 #![forbid(unsafe_code)]
 use anyhow::{Error, anyhow};
 use log::LevelFilter;
-use common::Credentials;
 use common::init_fcgi;
-use common::{Handler, Request, Response};
-use common::{RegionImpostorFaceData};
+use common::{Handler, Request, RequiredParams, Response};
+use common::{RegionImpostorFaceData, uuid_opt_to_string, string_opt_to_uuid, faces_to_json, next_grid_generation};
 use mysql::prelude::{Queryable};
-use mysql::{Pool};
 use mysql::{PooledConn, params};
 use std::collections::HashMap;
 use std::io::Write;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use common::{Authorizer, AuthorizeType};
+use common::{Authorizer, AuthorizeType, Credentials, WatchedCredentials, RequestOrigin};
+use common::{ReplayGuard, REPLAY_CACHE_SIZE, unix_time_now};
+use common::{ImpostorDeleteRequest, delete_impostor};
+use common::{AssetKind, AssetName, AtlasSlot, slots_in_mask};
+use common::db::{WatchedPool, with_conn};
+use common::grid::canonical;
+use common::metrics::{ConnectionLimits, Metrics};
 
 /// MySQL Credentials for uploading.
 /// This filename will be searched for in parent directories,
@@ -35,6 +44,14 @@ use common::{Authorizer, AuthorizeType};
 ///     DB_PORT = portnumber (optional, defaults to 3306)
 ///     DB_NAME = databasename
 ///
+/// and a secret for each upload token name, used to check signed uploads.
+///
+///     AUTH_UPLOADER_1 = secret
+///
+/// Admin tokens, for deleting impostors, have secrets of their own. Upload tokens can't delete.
+///
+///     ADMIN_AUTH_ADMIN_1 = secret
+///
 const UPLOAD_CREDS_FILE: &str = "upload_credentials.txt";
 
 /// Debug logging
@@ -61,31 +78,20 @@ pub enum TileAssetType {
     /// Geometry as a sculpt texture
     SculptTexture,
     /// Mesh (future)
-    Mesh
+    Mesh,
+    /// Base color shared by a block of tiles
+    Atlas,
 }
 
-impl TileAssetType {
-    /// From filename prefix string. Valid prefix values are RS, RM, RTn, and REn.
-    pub fn new_from_prefix(prefix: &str) -> Result<Self, Error> {
-        if prefix.len() < 2 {
-            Err(anyhow!("Too short tile asset name prefix: {}", prefix))
-        } else {
-            match &prefix[0..2] {
-                "RS" => Ok(Self::SculptTexture),
-                "RM" => Ok(Self::Mesh),
-                "RT" => Ok(Self::BaseTexture(Self::get_texture_index(prefix)?)),
-                "RE" => Ok(Self::EmissiveTexture(Self::get_texture_index(prefix)?)),
-                _ => Err(anyhow!("Invalid tile asset name prefix: {}", prefix))
-            }
-        }
-    }
-    
-    /// Get one digit, with checking
-    fn get_texture_index(prefix: &str) -> Result<u8, Error> {
-        if prefix.len() < 3 {
-            Err(anyhow!("Too short tile asset name prefix: {}", prefix))
-        } else {
-            Ok(prefix[2..3].parse()?)
+impl From<AssetKind> for TileAssetType {
+    /// From the kind encoded in the asset name.
+    fn from(kind: AssetKind) -> Self {
+        match kind {
+            AssetKind::Sculpt => Self::SculptTexture,
+            AssetKind::Mesh => Self::Mesh,
+            AssetKind::Texture(ix) => Self::BaseTexture(ix),
+            AssetKind::Emissive(ix) => Self::EmissiveTexture(ix),
+            AssetKind::Atlas => Self::Atlas,
         }
     }
 }
@@ -119,29 +125,33 @@ pub struct AssetUpload {
     viz_group: u32,
     /// Tile assset type - derived from prefix
     tile_asset_type: TileAssetType,
+    /// Slots used, if an atlas
+    #[serde(default)]
+    slot_mask: u16,
 }
 
 impl AssetUpload {
     pub fn new_from_asset_name(asset_name: &str, grid: &str, asset_uuid: &str) -> Result<Self, Error> {
-        //  Extract 11 fields from asset name
-        const FIELD_COUNT: usize = 11;
-        let fields: Vec<&str> = asset_name.split('_').collect();
-        if fields.len() != FIELD_COUNT {
-            return Err(anyhow!("Asset name did not contain {} fields: {}", FIELD_COUNT, asset_name));
+        let grid = &canonical(grid);
+        let name: AssetName = asset_name.parse()?;
+        //  The tag may have been shortened to fit, so it need only be the start of this grid's tag.
+        if !AssetName::grid_tag(grid).starts_with(&name.grid_tag) {
+            return Err(anyhow!("Asset name {} is not for grid \"{}\"", asset_name, grid));
         }
         Ok(Self {
             grid: grid.to_string(),
             asset_name: asset_name.to_string(),
-            region_loc: [fields[1].parse()?, fields[2].parse()?],
-            region_size: [fields[3].parse()?, fields[4].parse()?],
-            scale: [fields[3].parse()?, fields[4].parse()?, fields[5].parse()?],
-            elevation_offset: fields[6].parse()?,
-            impostor_lod: fields[7].parse()?,
-            viz_group: fields[8].parse()?,
-            water_height: fields[9].parse()?,
-            asset_hash: fields[10].to_string(),
+            region_loc: name.region_loc,
+            region_size: name.region_size,
+            scale: [name.region_size[0] as f32, name.region_size[1] as f32, name.scale_z],
+            elevation_offset: name.offset,
+            impostor_lod: name.lod,
+            viz_group: name.viz_group,
+            water_height: name.water_level,
+            asset_hash: format!("{:08x}", name.hash),
             asset_uuid: Self::fix_uuid_string(asset_uuid)?,
-            tile_asset_type: TileAssetType::new_from_prefix(fields[0])?,
+            tile_asset_type: name.kind.into(),
+            slot_mask: name.slot_mask,
         })
     }
     
@@ -150,6 +160,26 @@ impl AssetUpload {
         Self::new_from_asset_name(&upload_short.asset_name, &upload_short.grid, &upload_short.asset_uuid)
     }
     
+    /// For an atlas, the same upload as base texture 0 of each tile using it.
+    fn atlas_tiles(&self) -> Result<Vec<Self>, Error> {
+        if self.slot_mask == 0 {
+            return Err(anyhow!("Atlas {} has an invalid slot mask", self.asset_name));
+        }
+        if self.region_size[0] != self.region_size[1] {
+            return Err(anyhow!("Atlas {} is for tiles which aren't square", self.asset_name));
+        }
+        let tile_size = self.region_size[0];
+        Ok(slots_in_mask(self.slot_mask)
+            .map(|slot| Self {
+                region_loc: AtlasSlot { block_loc: self.region_loc, slot }.tile_loc(tile_size),
+                scale: [tile_size as f32, tile_size as f32, 0.0],
+                tile_asset_type: TileAssetType::BaseTexture(0),
+                slot_mask: 0,
+                ..self.clone()
+            })
+            .collect())
+    }
+
     ///  Parse and check UUID
     fn fix_uuid_string(uuid_str: &str) -> Result<String, Error> {
         let uuid = Uuid::parse_str(uuid_str)?;
@@ -177,25 +207,26 @@ pub type AssetUploadArrayShort = Vec<AssetUploadShort>;
 
 ///  Our handler
 
-struct AssetUploadHandler {
-    /// MySQL onnection pool. We only use one.
-    #[allow(dead_code)] // needed to keep the pool alive, but never referenced.
-    pool: Pool,
-    /// Active MySQL connection.
-    conn: PooledConn,
+pub(crate) struct AssetUploadHandler {
+    /// MySQL connection pool. Each request gets a connection from it.
+    pool: WatchedPool,
+    /// Upload token secrets
+    secrets: WatchedCredentials,
     /// Owner of object at other end
     owner_name: Option<String>,
+    /// Nonces of admin requests recently used, against replays
+    replay_guard: ReplayGuard,
 }
 impl AssetUploadHandler {
 
-    /// Usual new. Saves connection pool for use.
-    pub fn new(pool: Pool) -> Result<Self, Error> {
-        let conn = pool.get_conn()?;
-        Ok(Self { pool, conn, owner_name: None  })
+    /// Usual new. Saves connection pool and token secrets for use.
+    pub fn new(pool: WatchedPool, secrets: WatchedCredentials) -> Result<Self, Error> {
+        //  Admin requests always need a nonce. There are no old clients to allow for.
+        Ok(Self { pool, secrets, owner_name: None, replay_guard: ReplayGuard::new(true, REPLAY_CACHE_SIZE) })
     }
 
     /// Update terrain tile. A new terrain tile has been added, and needs to be added to the database.
-    fn update_tile(&mut self, asset_upload: &AssetUpload, texture_index: Option<u8>, asset_type: &str) -> Result<(), Error> {
+    fn update_tile(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload, texture_index: Option<u8>, asset_type: &str) -> Result<(), Error> {
         //  Allowed types. Must match exactly.
         assert!(asset_type == "BaseTexture" || asset_type == "EmissiveTexture" || asset_type == "SculptTexture" || asset_type == "Mesh");
         assert!(if asset_type == "BaseTexture" || asset_type == "EmissiveTexture" { texture_index.is_some() } else { true });
@@ -228,20 +259,20 @@ impl AssetUploadHandler {
             "asset_hash" => asset_upload.asset_hash.clone(),
         };
         log::debug!("SQL terrain tile update: {:?}", params);
-        self.conn.exec_drop(SQL_UPDATE_TILE, params)?;
+        conn.exec_drop(SQL_UPDATE_TILE, params)?;
         log::debug!("SQL terrain tile update succeeded.");
         Ok(())
     }
     
     /// Update a tile. A new tile has been added, and needs to be added to the database.
-    fn update_texture_tile(&mut self, asset_upload: &AssetUpload, texture_index: u8, asset_type: &str) -> Result<(), Error> {
-        self.update_tile(asset_upload, Some(texture_index), asset_type)
+    fn update_texture_tile(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload, texture_index: u8, asset_type: &str) -> Result<(), Error> {
+        self.update_tile(conn, asset_upload, Some(texture_index), asset_type)
     }
     
     //  Look up region name.
     //  Returns name of region if exact match. Otherwise searches for
     //  some name in a larger area containing the region of interest.
-    fn look_up_region_name(&mut self, grid: &str, loc: [u32;2], size: [u32;2]) -> Result<Option<String>, Error> {
+    fn look_up_region_name(&mut self, conn: &mut PooledConn, grid: &str, loc: [u32;2], size: [u32;2]) -> Result<Option<String>, Error> {
         //  Look up some name in the rectangle of interest.
         //  For LOD 0, this gets the region of interest.
         //  For lower LODs, the corner might be a nameless water region, so we pick some region in the rectangle.
@@ -250,6 +281,7 @@ impl AssetUploadHandler {
             WHERE region_loc_x >= :region_loc_x AND region_loc_y >= :region_loc_y
             AND region_loc_x <= :region_loc_x + :region_size_x
             AND region_loc_y <= :region_loc_y + :region_size_y
+            AND NOT deleted
             ORDER BY region_loc_x, region_loc_y LIMIT 1";
         let params = params! {
             "grid" => grid.to_lowercase().clone(), 
@@ -258,7 +290,7 @@ impl AssetUploadHandler {
             "region_size_x" => size[0],
             "region_size_y" => size[1],
             };
-        let names = self.conn.exec_map(
+        let names = conn.exec_map(
             SQL_GET_NAME,
             params,
             |(name, _region_loc_x, _region_loc_y) : (String, u32, u32)| {
@@ -272,9 +304,9 @@ impl AssetUploadHandler {
     }
     
     //  Get face information, which is texture UUIDs.
-    fn get_faces_json(&mut self, asset_upload: &AssetUpload) -> Result<serde_json::Value, Error> {
+    fn get_faces(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload) -> Result<Vec<RegionImpostorFaceData>, Error> {
         //  Get face texture data. One row for each face.
-        const SQL_GET_TEXTURES: &str = r#"SELECT texture_index, asset_uuid, asset_hash, asset_type
+        const SQL_GET_TEXTURES: &str = r#"SELECT texture_index, asset_uuid, asset_hash, asset_type, asset_name
             FROM tile_assets
             WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y
                 AND region_size_x = :region_size_x AND region_size_y = :region_size_y
@@ -292,20 +324,30 @@ impl AssetUploadHandler {
                 "viz_group" => asset_upload.viz_group,
             };
         log::debug!("Textures for sculpt/mesh {:?}, query params: {:?}", asset_upload.asset_name, texture_query_params);
-        let texture_tuples = self.conn.exec_map(
+        let rows = conn.exec_map(
             SQL_GET_TEXTURES,
             texture_query_params,
-            |(texture_index, texture_uuid,texture_hash, asset_type) : (usize, String, String, String)| {
-           (texture_index, texture_uuid, texture_hash, asset_type)
+            |(texture_index, texture_uuid,texture_hash, asset_type, asset_name) : (usize, String, String, String, String)| {
+           ((texture_index, texture_uuid, texture_hash, asset_type), asset_name)
             },
         )?;        
-        //  Build the textures as  JSON. Format is an array of JSON structs.        
+        let (texture_tuples, asset_names): (Vec<_>, Vec<String>) = rows.into_iter().unzip();
+        //  Build the face data, one per texture index.
         log::debug!("Textures for sculpt/mesh {:?}  {:?}", asset_upload.asset_name, texture_tuples);
-        RegionImpostorFaceData::json_from_tuples(&texture_tuples)
+        let mut faces = RegionImpostorFaceData::from_texture_tuples(&texture_tuples)?;
+        //  A base texture from an atlas gets this tile's part of it.
+        for ((texture_index, _, _, asset_type), asset_name) in texture_tuples.iter().zip(&asset_names) {
+            let is_atlas = asset_name.parse::<AssetName>().is_ok_and(|name| name.kind == AssetKind::Atlas);
+            if is_atlas && asset_type == "BaseTexture" {
+                let slot = AtlasSlot::new(asset_upload.region_loc, asset_upload.region_size[0])?;
+                faces[*texture_index].atlas_uv = Some(slot.uv());
+            }
+        }
+        Ok(faces)
     }
     
     /// Update impostor info in region_impostors table.
-    fn update_impostor_info(&mut self, asset_upload: &AssetUpload, name: &str, mesh_uuid: Option<String>, sculpt_uuid: Option<String>, faces_json: serde_json::Value) -> Result<(), Error> {
+    fn update_impostor_info(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload, name: &str, mesh_uuid: Option<Uuid>, sculpt_uuid: Option<Uuid>, faces: &[RegionImpostorFaceData]) -> Result<(), Error> {
 
         log::debug!("Inserting {} into region_impostors.", name);
         //  We have all the info now. Update the region_impostor table.
@@ -315,25 +357,27 @@ impl AssetUploadHandler {
                 scale_x, scale_y, scale_z, 
                 elevation_offset, impostor_lod, viz_group, 
                 mesh_uuid, sculpt_uuid,
-                water_height, creation_time, faces_json) 
+                water_height, creation_time, faces_json, generation) 
             VALUES 
                 (:grid, :name, :region_loc_x, :region_loc_y, :region_size_x, :region_size_y, :uniqueness_viz_group,
                 :scale_x, :scale_y, :scale_z,
                 :elevation_offset, :impostor_lod, :viz_group, 
                 :mesh_uuid, :sculpt_uuid, 
-                :water_height, NOW(), :faces_json)
+                :water_height, NOW(), :faces_json, :generation)
             ON DUPLICATE KEY UPDATE
                 scale_x = :scale_x, scale_y = :scale_y, scale_z = :scale_z,
                 elevation_offset = :elevation_offset, impostor_lod := impostor_lod, viz_group = :viz_group,
                 mesh_uuid = :mesh_uuid,
                 sculpt_uuid = :sculpt_uuid,
-                water_height = :water_height, creation_time = NOW(), faces_json = :faces_json";
-               
+                water_height = :water_height, creation_time = NOW(), faces_json = :faces_json,
+                generation = :generation";
+        //  Viewers asking by generation must see this, so it gets the grid's next serial.
+        let generation = next_grid_generation(conn, &asset_upload.grid)?;
         let insert_params = params! {
                 "grid" => asset_upload.grid.to_lowercase().clone(),
                 "name" => name,
-                "mesh_uuid" => mesh_uuid,
-                "sculpt_uuid" => sculpt_uuid,
+                "mesh_uuid" => uuid_opt_to_string(mesh_uuid),
+                "sculpt_uuid" => uuid_opt_to_string(sculpt_uuid),
                 "region_loc_x" => asset_upload.region_loc[0],
                 "region_loc_y" => asset_upload.region_loc[1],
                 "region_size_x" => asset_upload.region_size[0],
@@ -346,45 +390,46 @@ impl AssetUploadHandler {
                 "viz_group" => asset_upload.viz_group,
                 "elevation_offset" => asset_upload.elevation_offset,
                 "water_height" => asset_upload.water_height,
-                "faces_json" => faces_json.to_string(),
+                "faces_json" => faces_to_json(faces)?,
+                "generation" => generation,
             };
         //  Finally insert into the impostor table
         log::debug!("Inserting impostor into region_impostors, params: {:?}", insert_params);
-        Ok(self.conn.exec_drop(SQL_IMPOSTOR, insert_params)?)
+        Ok(conn.exec_drop(SQL_IMPOSTOR, insert_params)?)
     }
     
     /// Update terrain tile. A new terrain tile has been added, and needs to be added to the database.
-    fn update_mesh_tile(&mut self, asset_upload: &AssetUpload) -> Result<(), Error> {
+    fn update_mesh_tile(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload) -> Result<(), Error> {
         //  Most of the info we need is in asset_upload, but we also need:
         //  - name
         //  - face texture data.
         log::debug!("Update mesh tile: {:?}", asset_upload);
-        let faces_json = self.get_faces_json(asset_upload)?;
-        let name_opt = self.look_up_region_name(&asset_upload.grid.to_lowercase(), asset_upload.region_loc, asset_upload.region_size, )?;
+        let faces = self.get_faces(conn, asset_upload)?;
+        let name_opt = self.look_up_region_name(conn, &asset_upload.grid.to_lowercase(), asset_upload.region_loc, asset_upload.region_size, )?;
         //  Name is only for debug and documentation
         let name = if let Some(name) = name_opt { name } else { "(UNKNOWN)".to_string() };
         //  Valid sculpt tile.  Update tile assets.
-        self.update_tile(asset_upload, None, "SculptTexture")?;        
-        let mesh_uuid = Some(asset_upload.asset_uuid.clone());
+        self.update_tile(conn, asset_upload, None, "SculptTexture")?;        
+        let mesh_uuid = string_opt_to_uuid(Some(asset_upload.asset_uuid.clone()))?;
         let sculpt_uuid = None;
-        self.update_impostor_info(asset_upload, &name, mesh_uuid, sculpt_uuid, faces_json)
+        self.update_impostor_info(conn, asset_upload, &name, mesh_uuid, sculpt_uuid, &faces)
     }
 
     /// Update a sculpt tile.
-    fn update_sculpt_tile(&mut self, asset_upload: &AssetUpload) -> Result<(), Error> {
+    fn update_sculpt_tile(&mut self, conn: &mut PooledConn, asset_upload: &AssetUpload) -> Result<(), Error> {
         //  Most of the info we need is in asset_upload, but we also need:
         //  - name
         //  - face texture data.
         log::debug!("Update sculpt tile: {:?}", asset_upload);
-        let faces_json = self.get_faces_json(asset_upload)?;
-        let name_opt = self.look_up_region_name(&asset_upload.grid.to_lowercase(), asset_upload.region_loc, asset_upload.region_size, )?;
+        let faces = self.get_faces(conn, asset_upload)?;
+        let name_opt = self.look_up_region_name(conn, &asset_upload.grid.to_lowercase(), asset_upload.region_loc, asset_upload.region_size, )?;
         //  Name is only for debug and documentation
         let name = if let Some(name) = name_opt { name } else { "(UNKNOWN)".to_string() };
         //  Valid sculpt tile.  Update tile assets.
-        self.update_tile(asset_upload, None, "SculptTexture")?;       
-        let sculpt_uuid = Some(asset_upload.asset_uuid.clone());
+        self.update_tile(conn, asset_upload, None, "SculptTexture")?;       
+        let sculpt_uuid = string_opt_to_uuid(Some(asset_upload.asset_uuid.clone()))?;
         let mesh_uuid = None;
-        self.update_impostor_info(asset_upload, &name, mesh_uuid, sculpt_uuid, faces_json)
+        self.update_impostor_info(conn, asset_upload, &name, mesh_uuid, sculpt_uuid, &faces)
     }
     
     /// Parse a request
@@ -403,6 +448,59 @@ impl AssetUploadHandler {
         Ok(parsed)
     }
 
+    /// Is this an admin request? Those are a JSON object. Uploads are an array.
+    fn is_admin_request(b: &[u8]) -> bool {
+        b.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'{')
+    }
+
+    /// Handle an admin request. Replies with what was deleted, as JSON, or 404 if nothing was there.
+    fn admin_request(&mut self, out: &mut dyn Write, request: &Request, request_method: &str) -> Result<(), Error> {
+        //  This must be a POST
+        if request_method.to_uppercase().trim() != "POST" {
+            return Err(anyhow!("Request method \"{}\" was not POST.", request_method));
+        }
+        //  Admin token only. Error 401 if not. Nothing is touched in the database before this.
+        //  Why is logged, not sent.
+        if Authorizer::authorize_signed(AuthorizeType::AdminDelete, request, |k| self.secrets.get_fresh(k)).is_err() {
+            let http_response = Response::http_response("text/plain", 401, "Not authorized");
+            return Response::write_response(out, request, http_response.as_slice(), b"Not authorized");
+        }
+        let delete_request = match core::str::from_utf8(&request.standard_input).map_err(|e| anyhow!(e))
+            .and_then(|s| ImpostorDeleteRequest::new_from_json(s).map_err(|e| anyhow!(e))) {
+            Ok(delete_request) => delete_request,
+            Err(e) => {
+                let http_response = Response::http_response("text/plain", 400, format!("Incorrect request: {}", e).as_str());
+                return Response::write_response(out, request, http_response.as_slice(), &[]);
+            }
+        };
+        //  A signed request sent again, or long after it was signed, is refused like a bad signature.
+        let token_name = Authorizer::token_name(request).unwrap_or_default();
+        if let Err(e) = self.replay_guard.check(&token_name, delete_request.nonce.as_deref(), delete_request.sent_at, unix_time_now()) {
+            log::warn!("Admin request by token \"{}\" rejected: {}", token_name, e);
+            let http_response = Response::http_response("text/plain", 401, "Not authorized");
+            return Response::write_response(out, request, http_response.as_slice(), b"Not authorized");
+        }
+        //  Grid must be the one the request came from. Error 403 if not.
+        if let Err(e) = RequestOrigin::new_from_request(request).check_grid(&delete_request.grid) {
+            let http_response = Response::http_response("text/plain", 403, format!("Wrong grid: {}", e).as_str());
+            return Response::write_response(out, request, http_response.as_slice(), &[]);
+        }
+        match with_conn(&self.pool, |conn| delete_impostor(conn, &delete_request)) {
+            Ok(Some(reply)) => {
+                let http_response = Response::http_response("application/json", 200, "OK");
+                Response::write_response(out, request, http_response.as_slice(), serde_json::to_string(&reply)?.as_bytes())
+            }
+            Ok(None) => {
+                let http_response = Response::http_response("text/plain", 404, "No such impostor");
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+            Err(e) => {
+                let http_response = Response::http_response("text/plain", 500, format!("Problem processing request: {:?}", e).as_str());
+                Response::write_response(out, request, http_response.as_slice(), &[])
+            }
+        }
+    }
+
     /// Handle request.
     ///
     /// Start a database transaction.
@@ -411,29 +509,36 @@ impl AssetUploadHandler {
     /// If no, replace old data entirely.
     fn process_request(
         &mut self,
-        asset_info_short: AssetUploadArrayShort,
+        conn: &mut PooledConn,
+        asset_info_short: &AssetUploadArrayShort,
         _params: &HashMap<String, String>,
     ) -> Result<(usize, String), Error> {
         //  We have an array of assets.
         log::info!("Processing {} assets.", asset_info_short.len());
-        for asset_upload_short in &asset_info_short {
+        for asset_upload_short in asset_info_short {
             let asset_upload = AssetUpload::new_from_asset_upload_short(asset_upload_short)?;
             match &asset_upload.tile_asset_type {
                 TileAssetType::SculptTexture => {
                     //  Sculpt
-                    self.update_sculpt_tile(&asset_upload)?;
+                    self.update_sculpt_tile(conn, &asset_upload)?;
                 }
                 TileAssetType::Mesh => {
                     //  Texture
-                    self.update_mesh_tile(&asset_upload)?;
+                    self.update_mesh_tile(conn, &asset_upload)?;
                 }
                 TileAssetType::BaseTexture(ix) => {
                     //  Texture
-                    self.update_texture_tile(&asset_upload, *ix, "BaseTexture")?;
+                    self.update_texture_tile(conn, &asset_upload, *ix, "BaseTexture")?;
                 }
                 TileAssetType::EmissiveTexture(ix) => {
                     //  Texture
-                    self.update_texture_tile(&asset_upload, *ix, "EmissiveTexture")?;
+                    self.update_texture_tile(conn, &asset_upload, *ix, "EmissiveTexture")?;
+                }
+                TileAssetType::Atlas => {
+                    //  Base texture for each tile in the atlas
+                    for tile_upload in asset_upload.atlas_tiles()? {
+                        self.update_texture_tile(conn, &tile_upload, 0, "BaseTexture")?;
+                    }
                 }
             }
         }
@@ -448,27 +553,58 @@ impl Handler for AssetUploadHandler {
         request: &Request,
         env: &HashMap<String, String>,
     ) -> Result<(), Error> {
+        //  Error 400, naming all of them, if params needed here are missing.
+        let mut required = RequiredParams::new(request);
+        let request_method = required.require("REQUEST_METHOD");
+        required.require("CONTENT_TYPE");
+        let params = match required.finish() {
+            Ok(params) => params,
+            Err(e) => {
+                let http_response = Response::http_response("text/plain", 400, "Incorrect request");
+                Response::write_response(out, request, http_response.as_slice(), e.to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
         //  We have a request. It's supposed to be in JSON.
+        //  Error 415 if it says it isn't.
+        if let Err(e) = request.check_json_content() {
+            let http_response = Response::http_response("text/plain", e.http_status().into(), "Unsupported Media Type");
+            Response::write_response(out, request, http_response.as_slice(), e.to_string().as_bytes())?;
+            return Ok(());
+        }
+        //  Admin requests go elsewhere.
+        if Self::is_admin_request(&request.standard_input) {
+            return self.admin_request(out, request, request_method);
+        }
         //  Parse. Error 400 with message if fail.
         match Self::parse_request(&request.standard_input, env) {
             Ok(req) => {
                 log::info!("Request made: {:?} env {:?}", req, env);
-                let params = request
-                    .params
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("No HTTP parameters found"))?;
                 //  This must be a POST
-                if let Some(request_method) = params.get("REQUEST_METHOD") {  
-                    if request_method.to_uppercase().trim() != "POST" {             
-                        return Err(anyhow!("Request method \"{}\" was not POST.", request_method));
+                if request_method.to_uppercase().trim() != "POST" {
+                    return Err(anyhow!("Request method \"{}\" was not POST.", request_method));
+                }
+                //  Authorize. Error 401 if fail. Nothing is touched in the database before this.
+                match Authorizer::authorize_signed(AuthorizeType::UploadImpostors, request, |k| self.secrets.get_fresh(k)) {
+                    Ok(owner_name) => self.owner_name = Some(owner_name),
+                    Err(e) => {
+                        log::warn!("Impostor upload rejected: {}", e);
+                        let http_response = Response::http_response("text/plain", 401, "Not authorized");
+                        Response::write_response(out, request, http_response.as_slice(), b"Not authorized")?;
+                        return Ok(());
                     }
-                } else {
-                    return Err(anyhow!("No HTTP request method."));
                 }
-                //  Authorize
-                self.owner_name = Some(Authorizer::authorize(AuthorizeType::UploadImpostors, env, params)?);
+                //  Grid must be the one the request came from. Error 403 if not.
+                let origin = RequestOrigin::new_from_request(request);
+                if let Err(e) = req.iter().try_for_each(|upload| origin.check_grid(&upload.grid)) {
+                    let http_response = Response::http_response("text/plain", 403, format!("Wrong grid: {}", e).as_str());
+                    Response::write_response(out, request, http_response.as_slice(), &[])?;
+                    return Ok(());
+                }
                 //  Process. Error 500 if fail.
-                match self.process_request(req, &params) {
+                //  Retried once if the database connection was lost.
+                let pool = self.pool.clone();
+                match with_conn(&pool, |conn| self.process_request(conn, &req, params)) {
                     Ok((status, msg)) => {
                         //  Success. Send a plain "OK"
                         let http_response = Response::http_response("text/plain", status, "OK");
@@ -500,47 +636,33 @@ impl Handler for AssetUploadHandler {
         }
         Ok(())
     }
+
+    /// Pick up rotated token secrets.
+    fn between_requests(&mut self) {
+        if let Err(e) = self.secrets.reload() {
+            log::warn!("Keeping previous credentials: {:?}", e);
+        }
+    }
 }
 
 /// Run the responder.
 pub fn run_responder() -> Result<(), Error> {
-    log::info!("Environment: {:?}", std::env::vars());
+    log::info!("Environment: {:?}", common::environment_names());
     //  Set up in and out sockets.
     //  Communication with the parent process is via a UNIX socket.
     //  This is a pain to set up, because UNIX sockets are badly mis-matched
     //  to parent/child process communication.
     //  See init_fcgi for how it is done.
     let listener = init_fcgi()?;
-    //  Accept a connection on the listener socket. This hooks up
-    //  input and output to the parent process.
-    let (socket, _addr) = listener.accept()?;
-    let outsocket = socket.try_clone()?;
-    let mut instream = std::io::BufReader::new(socket);
-    let mut outio = std::io::BufWriter::new(outsocket);
-    //  Connect to the database
-    let creds = Credentials::new(UPLOAD_CREDS_FILE)?;
-    //  Optional MySQL port number
-    let portnum = if let Some(port) = creds.get("DB_PORT") {
-        port.parse::<u16>()?
-    } else {
-        //  Use MySQL default
-        3306
-    };
-    let opts = mysql::OptsBuilder::new()
-        //  Dreamhost is still using old authentication
-        .secure_auth(false)
-        .ip_or_hostname(creds.get("DB_HOST"))
-        .tcp_port(portnum)
-        .user(creds.get("DB_USER"))
-        .pass(creds.get("DB_PASS"))
-        .db_name(creds.get("DB_NAME"));
-    drop(creds);
-    //////log::info!("Opts: {:?}", opts);
-    let pool = Pool::new(opts)?;
-    log::info!("Connected to database.");
-    let mut asset_upload_handler = AssetUploadHandler::new(pool)?;
-    //  Run the FCGI server.
-    common::run(&mut instream, &mut outio, &mut asset_upload_handler)
+    //  Connect to the database. Secrets and connections follow changes to the credentials file,
+    //  so secrets can be rotated without a restart.
+    let secrets = Credentials::watch_with_env(UPLOAD_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&secrets)?;
+    common::db::migrations::check_schema_if_enabled(&mut pool.get_conn()?);
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", UPLOAD_CREDS_FILE, e))?;
+    let mut asset_upload_handler = AssetUploadHandler::new(pool, secrets)?;
+    //  Run the FCGI server, one connection from the parent process at a time.
+    common::serve(&listener, &mut asset_upload_handler, &Metrics::new(), &connection_limits)
 }
 
 /// Main program
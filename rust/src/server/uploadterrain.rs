@@ -6,6 +6,36 @@
 //! Later processing turns that into objects viewable in world via the
 //! region impostor system.
 //!
+//! A region which has left the grid can be marked deleted, with
+//!
+//!     {"grid":"agni", "region_coords":[1000,1000], "deleted":true}
+//!
+//! A later terrain upload for the region undeletes it.
+//!
+//! The body is checked as it arrives. One that isn't a UTF-8 JSON object is
+//! rejected with a 400 on its first bad chunk, without waiting for the rest.
+//!
+//! Every reply body is one short JSON object, so the script needs only one parser.
+//!
+//!     {"status":"inserted"|"updated"|"unchanged"|"renamed","grid":"agni","region":[1000,1000],"samples":[65,65],"row_age_days":12,"elev_tolerance":0.5}
+//!     {"status":"deleted","grid":"agni","region":[1000,1000]}
+//!     {"status":"error","reason":"..."}
+//!
+//! row_age_days is the time since the stored row was last uploaded or confirmed,
+//! before this upload. It is 0 for a new region. elev_tolerance is how far, in meters,
+//! an elevation could differ from the stored one and still count as unchanged.
+//!
+//! A signed upload also carries "nonce", different for every upload, and
+//! "sent_at", Unix time in seconds, so a captured upload can't be replayed.
+//! A nonce already used gets error 409, and a sent_at more than 10 minutes
+//! from server time gets error 400. The reply echoes the nonce, so the script
+//! can match it to the upload. See common::replayguard.
+//!
+//!     {"status":"inserted",...,"nonce":"9f3c1a"}
+//!
+//! "renamed" is same terrain under a new name. Only the name is updated, so
+//! impostors need not be regenerated.
+//!
 //!     License: LGPL.
 //!     Animats
 //!     August, 2025.
@@ -13,17 +43,17 @@
 #![forbid(unsafe_code)]
 use anyhow::{Error, anyhow};
 use log::LevelFilter;
-use common::Credentials;
 use common::init_fcgi;
-use common::{Handler, Request, Response};
-use common::{UploadedRegionInfo};
-use common::u8_to_elev;
-use mysql::prelude::{Queryable};
-use mysql::{Pool};
-use mysql::{PooledConn, params};
+use common::{Handler, Request, RequiredParams, Response};
+use common::{UploadedRegionInfo, HeightField, RawTerrainHeights, RegionDeletion, TerrainUpload, GlobalMeters, mark_region_deleted, rename_region};
+use mysql::params;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::io::Write;
-use common::{Authorizer, AuthorizeType};
+use common::{Authorizer, AuthorizeType, Credentials, WatchedCredentials, ReplayGuard, RequestOrigin, status_for, unix_time_now};
+use common::db::{ConnSource, Db, WatchedPool, is_lock_conflict, with_conn};
+use common::grid::canonical;
+use common::metrics::{ConnectionLimits, Metrics, RequestLimits, METRICS_LOG_INTERVAL};
 /// MySQL Credentials for uploading.
 /// This filename will be searched for in parent directories,
 /// so it can be placed above the web root, where the web server can't see it.
@@ -35,6 +65,26 @@ use common::{Authorizer, AuthorizeType};
 ///     DB_PORT = portnumber (optional, defaults to 3306)
 ///     DB_NAME = databasename
 ///
+/// and a secret for each upload token name, used to check signed uploads.
+///
+///     AUTH_UPLOADER_1 = secret
+///
+/// Optionally, requests bigger or slower than these are logged, with time by phase.
+///
+///     LARGE_REQUEST_BYTES = bytes
+///     SLOW_REQUEST_MS = milliseconds
+///
+/// Optionally, the elevation tolerance for deciding terrain is unchanged, in meters,
+/// and overrides for particular grids.
+///
+///     ELEV_TOLERANCE = 0.5
+///     ELEV_TOLERANCE_GRIDS = osgrid=0.1, agni=1.0
+///
+/// Optionally, replay protection can be turned off, for upload scripts
+/// which don't yet send a nonce and sent_at. It's on by default.
+///
+///     REPLAY_PROTECTION = off
+///
 const UPLOAD_CREDS_FILE: &str = "upload_credentials.txt";
 
 /// Debug logging
@@ -50,343 +100,599 @@ fn logger() {
     log::warn!("Logging to {:?}", LOG_FILE_NAME); // where the log is going
 }
 
-/// Change status for region data
+/// Params this handler uses, by prefix. Owner, shard, and region headers, and the signature.
+const NEEDED_PARAM_PREFIXES: [&str; 3] = ["HTTP_X_SECONDLIFE_", "HTTP_X_OPENSIM_", "HTTP_X_AUTHTOKEN_"];
+
+/// Elevation tolerance key, meters.
+const ELEV_TOLERANCE_KEY: &str = "ELEV_TOLERANCE";
+/// Per-grid elevation tolerance key, as grid=meters, comma separated.
+const ELEV_TOLERANCE_GRIDS_KEY: &str = "ELEV_TOLERANCE_GRIDS";
+/// Most tries for an upload transaction which keeps losing lock conflicts.
+const MAX_UPLOAD_ATTEMPTS: usize = 3;
+
+/// How far elevations can differ and still be the same terrain, in meters.
+/// LSL llGround is slightly noisy, more so on some grids than others.
+#[derive(Debug, Clone, PartialEq)]
+struct ElevTolerance {
+    /// For grids with no override
+    default: f32,
+    /// Overrides, by canonical grid name
+    grids: HashMap<String, f32>,
+}
+
+impl Default for ElevTolerance {
+    fn default() -> Self {
+        Self { default: Self::DEFAULT_TOLERANCE, grids: HashMap::new() }
+    }
+}
+
+impl ElevTolerance {
+    /// Tolerance if not configured.
+    const DEFAULT_TOLERANCE: f32 = 0.5;
+
+    /// From a key lookup, such as a credentials file. Missing keys mean the default.
+    fn new_from_lookup(get: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let meters = |key: &str, v: &str| -> Result<f32, Error> {
+            match v.trim().parse::<f32>() {
+                Ok(t) if t.is_finite() && t >= 0.0 => Ok(t),
+                _ => Err(anyhow!("{} \"{}\" is not a tolerance in meters", key, v)),
+            }
+        };
+        let default = match get(ELEV_TOLERANCE_KEY) {
+            Some(v) => meters(ELEV_TOLERANCE_KEY, &v)?,
+            None => Self::DEFAULT_TOLERANCE,
+        };
+        let mut grids = HashMap::new();
+        for entry in get(ELEV_TOLERANCE_GRIDS_KEY).iter().flat_map(|v| v.split(',')).filter(|e| !e.trim().is_empty()) {
+            let (grid, v) = entry.split_once('=').ok_or_else(|| anyhow!("{} entry \"{}\" is not grid=meters", ELEV_TOLERANCE_GRIDS_KEY, entry))?;
+            grids.insert(canonical(grid), meters(ELEV_TOLERANCE_GRIDS_KEY, v)?);
+        }
+        Ok(Self { default, grids })
+    }
+
+    /// Tolerance for this grid.
+    fn for_grid(&self, grid: &str) -> f32 {
+        self.grids.get(&canonical(grid)).copied().unwrap_or(self.default)
+    }
+}
+
+/// Change status for region data.
+/// Stored rows come with their age in days.
 #[derive(Debug)]
 enum ChangeStatus {
     None, 
-    NoChange,
-    Changed 
+    NoChange(u32),
+    Changed(u32),
+    /// Same terrain, new name.
+    Renamed(u32),
 }
 
-///  Our handler
-struct TerrainUploadHandler {
-    /// MySQL onnection pool. We only use one.
-    #[allow(dead_code)] // needed to keep the pool alive, but never referenced.
-    pool: Pool,
-    /// Active MySQL connection.
-    conn: PooledConn,
-    /// Owner of object at other end
-    owner_name: Option<String>,
+impl ChangeStatus {
+    /// Status for an existing row. Terrain decides whether it changed.
+    /// A new name alone is just a rename.
+    fn for_stored(terrain_same: bool, name_same: bool, row_age_days: u32) -> Self {
+        match (terrain_same, name_same) {
+            (true, true) => Self::NoChange(row_age_days),
+            (true, false) => Self::Renamed(row_age_days),
+            (false, _) => Self::Changed(row_age_days),
+        }
+    }
 }
-impl TerrainUploadHandler {
-    /// Elevation error tolerance. Elevations are equal if within this tolerance.
-    /// LSL llGround is slightly noisy.
-    const ELEV_ERROR_TOLERANCE: f32 = 0.5;
 
-    /// Usual new. Saves connection pool for use.
-    pub fn new(pool: Pool) -> Result<Self, Error> {
-        let conn = pool.get_conn()?;
-        Ok(Self { pool, conn, owner_name: None  })
-    }
+/// LSL scripts can easily parse replies up to this size.
+const MAX_ACK_BYTES: usize = 2048;
+/// Strings in a reply are cut to this many characters, which keeps it well under MAX_ACK_BYTES.
+const MAX_ACK_FIELD_CHARS: usize = 256;
+/// An echoed nonce is cut to this many. Good nonces are shorter, and a bad one may be junk.
+const MAX_ACK_NONCE_CHARS: usize = 64;
 
-    /// SQL insert for new item
-    fn do_sql_insert(
-        &mut self,
-        region_info: &UploadedRegionInfo,
-        params: &HashMap<String, String>,
-    ) -> Result<(), Error> {
-        const SQL_INSERT: &str = r"INSERT INTO raw_terrain_heights (grid, region_loc_x, region_loc_y, samples_x, samples_y, region_size_x, region_size_y, name, scale, offset, elevs,  water_level, creator) 
-            VALUES
-            (:grid, :region_loc_x, :region_loc_y, :samples_x, :samples_y, :region_size_x, :region_size_y, :name, :scale, :offset, :elevs, :water_level, :creator)";
-        let creator = &self.owner_name
-            .as_ref()
-            .ok_or_else(|| anyhow!("No owner name from auth"))?;    // should fail upstream, not here.
+/// Cut a string to at most max_chars characters.
+fn truncate_chars(s: &str, max_chars: usize) -> String {
+    s.chars().take(max_chars).collect()
+}
+
+/// What happened to an uploaded region.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct RegionAck {
+    grid: String,
+    /// Region location, meters
+    region: [GlobalMeters; 2],
+    /// Samples, X and Y
+    samples: [u32; 2],
+    /// Days since the stored row was last uploaded or confirmed. 0 if new.
+    row_age_days: u32,
+    /// Elevation tolerance used for this grid, meters
+    elev_tolerance: f32,
+}
+
+/// What happened to a deleted region.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct DeletionAck {
+    grid: String,
+    /// Region location, meters
+    region: [GlobalMeters; 2],
+}
+
+/// Reply body for the LSL script, for every outcome.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum UploadAck {
+    Inserted(RegionAck),
+    Updated(RegionAck),
+    Unchanged(RegionAck),
+    Renamed(RegionAck),
+    Deleted(DeletionAck),
+    Error { reason: String },
+}
+
+/// The ack as sent, with the upload's nonce after it.
+#[derive(Serialize)]
+struct AckReply<'a> {
+    #[serde(flatten)]
+    ack: &'a UploadAck,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+}
+
+impl UploadAck {
+    /// Ack for an uploaded region.
+    fn new_region(change_status: &ChangeStatus, region_info: &UploadedRegionInfo, elev_tolerance: f32) -> Result<Self, Error> {
         let samples = region_info.get_samples()?;
-        let values = params! {
-        //////"table" => RAW_TERRAIN_HEIGHTS,
-        "grid" => region_info.grid.clone(),
-        "region_loc_x" => region_info.region_coords[0],
-        "region_loc_y" => region_info.region_coords[1],
-        "region_size_x" => region_info.get_size()[0],
-        "region_size_y" => region_info.get_size()[1],
-        "name" => region_info.name.clone(),
-        "scale" => region_info.scale,
-        "offset" => region_info.offset,	
-        "elevs" => region_info.get_elevs_as_blob()?,
-        "samples_x" => samples[0],
-        "samples_y" => samples[1],
-        "water_level" => region_info.water_lev,
-        "creator" => creator };
-        log::debug!("SQL insert: {:?}", values);
-        self.conn.exec_drop(SQL_INSERT, values)?;
-        log::debug!("SQL insert succeeded.");
+        let ack = |row_age_days| RegionAck {
+            grid: truncate_chars(&region_info.grid, MAX_ACK_FIELD_CHARS),
+            region: region_info.region_coords,
+            samples,
+            row_age_days,
+            elev_tolerance,
+        };
+        Ok(match change_status {
+            ChangeStatus::None => Self::Inserted(ack(0)),
+            ChangeStatus::NoChange(age) => Self::Unchanged(ack(*age)),
+            ChangeStatus::Changed(age) => Self::Updated(ack(*age)),
+            ChangeStatus::Renamed(age) => Self::Renamed(ack(*age)),
+        })
+    }
+
+    /// Ack for a deleted region.
+    fn new_deleted(deletion: &RegionDeletion) -> Self {
+        Self::Deleted(DeletionAck { grid: truncate_chars(&deletion.grid, MAX_ACK_FIELD_CHARS), region: deletion.region_coords })
+    }
+
+    /// Ack for a failure.
+    fn new_error(reason: &str) -> Self {
+        Self::Error { reason: truncate_chars(reason, MAX_ACK_FIELD_CHARS) }
+    }
+
+    /// As JSON, for the reply body. The upload's nonce, if any, is echoed.
+    fn to_json(&self, nonce: Option<&str>) -> Result<String, Error> {
+        let nonce = nonce.map(|n| truncate_chars(n, MAX_ACK_NONCE_CHARS));
+        let json = serde_json::to_string(&AckReply { ack: self, nonce: nonce.as_deref() })?;
+        if json.len() > MAX_ACK_BYTES {
+            return Err(anyhow!("Reply is {} bytes, over the {} byte limit", json.len(), MAX_ACK_BYTES));
+        }
+        Ok(json)
+    }
+}
+
+/// Checks on the body as it arrives, so a big upload that's plainly not ours
+/// fails before the rest of it lands. The body must start with a JSON object and be UTF-8.
+/// The full parse comes later, once it's all here.
+#[derive(Debug, Default)]
+struct UploadStreamCheck {
+    /// Seen the first byte that isn't white space.
+    started: bool,
+    /// Bytes of a UTF-8 sequence split across chunks.
+    utf8_carry: Vec<u8>,
+    /// Bytes so far
+    bytes: usize,
+}
+
+impl UploadStreamCheck {
+    /// Check the next chunk of the body.
+    fn add(&mut self, chunk: &[u8]) -> Result<(), common::Error> {
+        let offset = self.bytes;
+        self.bytes += chunk.len();
+        if !self.started {
+            //  A byte order mark is not white space, so it is refused here, as serde_json refuses it.
+            if let Some(&first) = chunk.iter().find(|b| !b.is_ascii_whitespace()) {
+                if first != b'{' {
+                    return Err(common::Error::BadRequest(format!("Request does not start with a JSON object. First byte is {:?}", first as char)));
+                }
+                self.started = true;
+            }
+        }
+        //  Only the incomplete sequence at the end carries over.
+        let mut bytes = std::mem::take(&mut self.utf8_carry);
+        bytes.extend_from_slice(chunk);
+        if let Err(e) = std::str::from_utf8(&bytes) {
+            if e.error_len().is_some() {
+                let position = offset + e.valid_up_to() - (bytes.len() - chunk.len());
+                return Err(common::Error::BadRequest(format!("Request is not UTF-8 at byte {}", position)));
+            }
+            self.utf8_carry = bytes[e.valid_up_to()..].to_vec();
+        }
         Ok(())
     }
-    
-    /// SQL insert for new item. Replaces entire record
-    fn do_sql_full_update(
-        &mut self,
+}
+
+///  Our handler
+pub(crate) struct TerrainUploadHandler<P = WatchedPool> {
+    /// MySQL connection pool. Each request gets a connection from it. A SharedFakeDb in tests.
+    pool: P,
+    /// Upload token secrets
+    secrets: WatchedCredentials,
+    /// Request counters, shared with the FCGI loop
+    metrics: Metrics,
+    /// Elevation tolerance, by grid
+    elev_tolerance: ElevTolerance,
+    /// Checks on the body of the request now arriving
+    stream_check: UploadStreamCheck,
+    /// Nonces recently used, against replays
+    replay_guard: ReplayGuard,
+}
+impl<P: ConnSource + Clone> TerrainUploadHandler<P> {
+    /// Usual new. Saves connection pool, token secrets, and metrics for use.
+    /// The elevation tolerance and replay protection come from the credentials file.
+    pub fn new(pool: P, secrets: WatchedCredentials, metrics: Metrics) -> Result<Self, Error> {
+        let elev_tolerance = ElevTolerance::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", UPLOAD_CREDS_FILE, e))?;
+        log::info!("Elevation tolerance: {:?}", elev_tolerance);
+        let replay_guard = ReplayGuard::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", UPLOAD_CREDS_FILE, e))?;
+        log::info!("Replay protection: {:?}", replay_guard);
+        Ok(Self { pool, secrets, metrics, elev_tolerance, stream_check: UploadStreamCheck::default(), replay_guard })
+    }
+}
+
+//  Request processing. None of this uses the pool, so it's on the default type,
+//  and the generic handler below calls it as TerrainUploadHandler::.
+impl TerrainUploadHandler {
+
+    /// SQL insert for new item, or replace the entire record.
+    /// One statement, so a region inserted by someone else meanwhile is replaced, not an error.
+    fn do_sql_upsert(
+        conn: &mut dyn Db,
         region_info: &UploadedRegionInfo,
-        params: &HashMap<String, String>,
+        creator: &str,
     ) -> Result<(), Error> {
-        const SQL_FULL_UPDATE: &str = r"UPDATE raw_terrain_heights 
-            SET samples_x = :samples_x, samples_y = :samples_y, scale = :scale, offset = :offset, elevs = :elevs, water_level = :water_level, creator = :creator,
-                region_size_x = :region_size_x, region_size_y = :region_size_y, name = :name, confirmation_time = NOW(), confirmer = NULL
-            WHERE LOWER(grid) = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";           
-        let creator = &self.owner_name
-            .as_ref()
-            .ok_or_else(|| anyhow!("No owner name from auth"))?;    // should fail upstream, not here.
-        let samples = region_info.get_samples()?;
-        let values = params! {
-        "grid" => region_info.grid.clone(),
-        "region_loc_x" => region_info.region_coords[0],
-        "region_loc_y" => region_info.region_coords[1],
-        "region_size_x" => region_info.get_size()[0],
-        "region_size_y" => region_info.get_size()[1],
-        "name" => region_info.name.clone(),
-        "scale" => region_info.scale,
-        "offset" => region_info.offset,	
-        "elevs" => region_info.get_elevs_as_blob()?,
-        "samples_x" => samples[0],
-        "samples_y" => samples[1],
-        "water_level" => region_info.water_lev,
-        "creator" => creator };
-        log::debug!("SQL update: {:?}", values);
-        self.conn.exec_drop(SQL_FULL_UPDATE, values)?;
-        log::debug!("SQL update succeeded.");
+        RawTerrainHeights::new_from_uploaded(region_info, creator)?.upsert(conn)?;
         Ok(())
     }
     
-    /// Compare elevations within tolerance.
-    /// LSL llGround is not totally repeatable.  We have to allow some error.
-    fn check_elev_err_within_tolerance(elevs0: &[u8], elevs1: &[u8], scale: f32, offset: f32, tolerance: f32) -> bool {
-        let elev_err = |a: u8, b: u8| (u8_to_elev(a, scale, offset) - u8_to_elev(b, scale, offset)).abs();
-        let max_err_item_opt = elevs0.iter().zip(elevs1).max_by(|(a0, b0), (a1, b1)| {
-            let aerr = elev_err(**a0, **b0);
-            let berr = elev_err(**a1, **b1);
-            aerr.total_cmp(&berr)
-        });
-        if let Some(max_err_item) = max_err_item_opt {
-            let max_err = elev_err(*max_err_item.0, *max_err_item.1);
-            if max_err > tolerance {
-                log::warn!("Elevations differ by {:5}", max_err);
-            }
-            max_err < tolerance
-        } else {
-            // Not equal
-            false 
-        }
-    }
-    
     fn do_sql_confirmation_update(
-        &mut self,
+        conn: &mut dyn Db,
         region_info: &UploadedRegionInfo,
-        params: &HashMap<String, String>,
+        confirmer: &str,
     ) -> Result<(), Error> {
         const SQL_CONFIRMATION_UPDATE: &str = r"UPDATE raw_terrain_heights
-            SET confirmation_time = NOW(), confirmer = :confirmer
-            WHERE LOWER(grid) = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";           
-        let confirmer = &self.owner_name
-            .as_ref()
-            .ok_or_else(|| anyhow!("No owner name from auth"))?;    // should fail upstream, not here.
+            SET confirmation_time = NOW(), confirmer = :confirmer, last_confirmed = NOW(), deleted = FALSE
+            WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";           
         let values = params! {
-        "grid" => region_info.grid.clone(),
-        "region_loc_x" => region_info.region_coords[0],
-        "region_loc_y" => region_info.region_coords[1],
+        "grid" => region_info.get_grid(),
+        "region_loc_x" => region_info.region_coords[0].meters(),
+        "region_loc_y" => region_info.region_coords[1].meters(),
         "confirmer" => confirmer };
         log::debug!("SQL confirmation update: {:?}", values);
-        self.conn.exec_drop(SQL_CONFIRMATION_UPDATE, values)?;
+        conn.exec_drop(SQL_CONFIRMATION_UPDATE, values)?;
         log::debug!("SQL confirmation update succeeded.");
         Ok(())
     }
     
-    /// Is this a duplicate?
+    /// Is this a duplicate? Elevations within elev_tolerance meters are the same.
+    /// Locks the region's row until the transaction ends.
     fn do_sql_unchanged_check(
-        &mut self,
+        conn: &mut dyn Db,
         region_info: &UploadedRegionInfo,
+        elev_tolerance: f32,
     ) -> Result<ChangeStatus, Error> {
         
-        let samples = region_info.get_samples()?;
-        let grid = &region_info.grid;
-        let region_loc_x = region_info.region_coords[0];
-        let region_loc_y = region_info.region_coords[1];
+        let grid = &region_info.get_grid();
+        let region_loc_x = region_info.region_coords[0].meters();
+        let region_loc_y = region_info.region_coords[1].meters();
         let new_elevs= region_info.get_elevs_as_blob()?;
-        const SQL_SELECT: &str = r"SELECT region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level
+        let new_height_field = region_info.to_height_field()?;
+        const SQL_SELECT: &str = r"SELECT region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level,
+                GREATEST(0, DATEDIFF(NOW(), COALESCE(last_confirmed, last_uploaded))), sample_spacing_x, sample_spacing_y
             FROM raw_terrain_heights
-            WHERE LOWER(grid) = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y";
-        let is_sames = self.conn.exec_map(
+            WHERE grid = :grid AND region_loc_x = :region_loc_x AND region_loc_y = :region_loc_y
+            FOR UPDATE";
+        //  The unique index is used, so only this region is locked.
+        //  The grid is in canonical lower case form, as stored.
+        let statuses = conn.exec_map(
             SQL_SELECT,
             params! { grid, region_loc_x, region_loc_y },
-            |(region_size_x, region_size_y, samples_x, samples_y, scale, offset, elevs, name, water_level) : (u32, u32, u32, u32, f32, f32, Vec<u8>, String, f32)| {
-                //  Is the stored data identical to what we just read from the region?
+            |row| {
+                let (region_size_x, region_size_y, samples_x, samples_y): (u32, u32, u32, u32) = (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+                let (scale, offset, elevs, name, water_level, row_age_days): (f32, f32, Vec<u8>, String, f32, u32) =
+                    (row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?, row.get(9)?);
+                let sample_spacing = match (row.get::<Option<f32>>(10)?, row.get::<Option<f32>>(11)?) {
+                    (Some(x), Some(y)) => Some([x, y]),
+                    _ => None,
+                };
+                //  Is the stored terrain identical to what we just read from the region?
                 log::trace!("Elevs:\n{:?} vs\n{:?}", elevs, new_elevs); // ***TEMP***
-                let is_same = 
+                let terrain_same = 
                     region_size_x == region_info.get_size()[0] && 
                     region_size_y == region_info.get_size()[1] &&
-                    samples_x == samples[0] && 
-                    samples_y == samples[1] &&
-                    (scale - region_info.scale).abs() < Self::ELEV_ERROR_TOLERANCE  &&
-                    (offset - region_info.offset).abs() < Self::ELEV_ERROR_TOLERANCE &&
-                    Self::check_elev_err_within_tolerance(&elevs, &new_elevs, scale, offset, Self::ELEV_ERROR_TOLERANCE) &&
-                    name == region_info.name &&
+                    HeightField::new_from_elevs_blob(&elevs, samples_x, samples_y, region_size_x, region_size_y, scale, offset, water_level)
+                        .and_then(|stored| stored.with_spacing(sample_spacing))
+                        .is_ok_and(|stored| stored.same_terrain(&new_height_field, elev_tolerance)) &&
                     water_level == region_info.water_lev;                    
-                is_same
+                Ok(ChangeStatus::for_stored(terrain_same, name == region_info.name, row_age_days))
             },
         )?;
-        //  Changed?
-        Ok(if is_sames.is_empty() {
-            ChangeStatus::None
-        } else {
-            //  Must be 1, because of SELECT on unique key.
-            assert!(is_sames.len() == 1);
-            if is_sames[0] {
-                ChangeStatus::NoChange
-            } else {
-                ChangeStatus::Changed
-            }
-        })
+        //  Must be at most 1, because of SELECT on unique key.
+        assert!(statuses.len() <= 1);
+        Ok(statuses.into_iter().next().unwrap_or(ChangeStatus::None))
     }  
 
-    /// Parse a request
+    /// Parse a request. All errors here are the client's.
     fn parse_request(
         b: &[u8],
         _env: &HashMap<String, String>,
-    ) -> Result<UploadedRegionInfo, Error> {
+    ) -> Result<TerrainUpload, common::Error> {
         //  Should be UTF-8. Check.
-        let s = core::str::from_utf8(b)?;
+        let s = core::str::from_utf8(b).map_err(|e| common::Error::BadRequest(format!("Request is not UTF-8: {}", e)))?;
         if s.trim().is_empty() {
-            return Err(anyhow!("Empty request. JSON was expected"));
+            return Err(common::Error::BadRequest("Empty request. JSON was expected".to_string()));
         }
         log::info!("Uploaded JSON:\n{}", s);
         //  Should be valid JSON
-        Ok(UploadedRegionInfo::parse(s)?)
+        TerrainUpload::parse(s)
     }
 
-    /// Handle request.
+    /// Handle request, for an authorized owner.
+    fn process_request(
+        conn: &mut dyn Db,
+        upload: &TerrainUpload,
+        owner_name: &str,
+        elev_tolerance: &ElevTolerance,
+    ) -> Result<(usize, UploadAck), Error> {
+        match upload {
+            TerrainUpload::Region(region_info) => {
+                Self::process_region_upload(conn, region_info, owner_name, elev_tolerance.for_grid(&region_info.grid))
+            }
+            TerrainUpload::Deletion(deletion) => Self::process_deletion(conn, deletion, owner_name),
+        }
+    }
+
+    /// Mark a region deleted. Error 404 if there's no such region.
+    fn process_deletion(
+        conn: &mut dyn Db,
+        deletion: &RegionDeletion,
+        confirmer: &str,
+    ) -> Result<(usize, UploadAck), Error> {
+        if mark_region_deleted(conn, &deletion.grid, deletion.region_coords, confirmer)? {
+            Ok((200, UploadAck::new_deleted(deletion)))
+        } else {
+            Ok((404, UploadAck::new_error("No such region")))
+        }
+    }
+
+    /// Handle a terrain upload.
     ///
-    /// Start a database transaction.
     /// Check if this data is the same as any stored data for this region.
     /// If yes, just update confirmation user and time.
     /// If no, replace old data entirely.
-    fn process_request(
-        &mut self,
-        region_info: UploadedRegionInfo,
-        params: &HashMap<String, String>,
-    ) -> Result<(usize, String), Error> {
-        let change_status = self.do_sql_unchanged_check(&region_info)?;
+    /// Either way, a deleted region is undeleted.
+    ///
+    /// Check and update are one transaction, so two bots uploading the same region
+    /// at once are handled one after the other. Any error rolls back, so the
+    /// connection goes back to the pool clean.
+    ///
+    /// The row lock on a region not in the table yet is a gap lock, which doesn't
+    /// stop another transaction taking the same gap. So two first uploads of nearby
+    /// regions can deadlock when both insert. MySQL picks one to fail, and that one
+    /// is run again, up to MAX_UPLOAD_ATTEMPTS in all.
+    fn process_region_upload(
+        conn: &mut dyn Db,
+        region_info: &UploadedRegionInfo,
+        owner_name: &str,
+        elev_tolerance: f32,
+    ) -> Result<(usize, UploadAck), Error> {
+        let mut attempt = 1;
+        loop {
+            conn.start_transaction()?;
+            match Self::process_region_upload_in_transaction(conn, region_info, owner_name, elev_tolerance) {
+                Ok(result) => {
+                    conn.commit()?;
+                    return Ok(result);
+                }
+                Err(e) => {
+                    conn.rollback()?;
+                    if attempt >= MAX_UPLOAD_ATTEMPTS || !is_lock_conflict(&e) {
+                        return Err(e);
+                    }
+                    log::warn!("Region \"{}\" upload lost a lock conflict, attempt {} of {}: {}", region_info.name, attempt, MAX_UPLOAD_ATTEMPTS, e);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Handle a terrain upload, inside the transaction.
+    fn process_region_upload_in_transaction(
+        conn: &mut dyn Db,
+        region_info: &UploadedRegionInfo,
+        owner_name: &str,
+        elev_tolerance: f32,
+    ) -> Result<(usize, UploadAck), Error> {
+        let change_status = Self::do_sql_unchanged_check(conn, region_info, elev_tolerance)?;
         log::warn!("Changed status for region {}: {:?}", region_info.name, change_status);
-        match change_status {
+        let status = match change_status {
             ChangeStatus::None => {
                 //  New region, add region
-                log::info!("Region \"{}\") is new.", region_info.name);
-                self.do_sql_insert(&region_info, params)?; 
-                Ok((201, "Added region".to_string()))    
+                log::info!("Region \"{}\" is new.", region_info.name);
+                Self::do_sql_upsert(conn, region_info, owner_name)?; 
+                201
             }
-            ChangeStatus::NoChange  => {
-                //  Existing region, same values as last time
-                log::info!("Region \"{}\") is unchanged.", region_info.name);
-                self.do_sql_confirmation_update(&region_info, params)?; 
-                Ok((204, "No change to region".to_string()))
+            ChangeStatus::NoChange(_)  => {
+                //  Existing region, same values as last time.
+                //  200, not 204, because the script needs the reply body.
+                log::info!("Region \"{}\" is unchanged.", region_info.name);
+                Self::do_sql_confirmation_update(conn, region_info, owner_name)?; 
+                200
             }
-            ChangeStatus::Changed => {
-                log::info!("Region \"{}\") changed", region_info.name);
-                self.do_sql_full_update(&region_info, params)?; 
-                Ok((200, "Change to region".to_string()))
+            ChangeStatus::Changed(_) => {
+                log::info!("Region \"{}\" changed.", region_info.name);
+                Self::do_sql_upsert(conn, region_info, owner_name)?; 
+                200
             }
-        }
+            ChangeStatus::Renamed(_) => {
+                //  Confirmed, and relabeled. Impostors keep their assets.
+                log::info!("Region \"{}\" renamed, terrain unchanged.", region_info.name);
+                Self::do_sql_confirmation_update(conn, region_info, owner_name)?; 
+                rename_region(conn, &region_info.grid, region_info.region_coords, &region_info.name)?;
+                200
+            }
+        };
+        Ok((status, UploadAck::new_region(&change_status, region_info, elev_tolerance)?))
+    }
+}
+//  Reply writing
+impl TerrainUploadHandler {
+    /// Send the JSON ack as the reply body.
+    fn write_ack(out: &mut dyn Write, request: &Request, status: usize, msg: &str, ack: &UploadAck) -> Result<(), Error> {
+        Self::write_ack_with_nonce(out, request, status, msg, ack, None)
+    }
+
+    /// Send the JSON ack as the reply body, echoing the upload's nonce.
+    fn write_ack_with_nonce(out: &mut dyn Write, request: &Request, status: usize, msg: &str, ack: &UploadAck, nonce: Option<&str>) -> Result<(), Error> {
+        let http_response = Response::http_response("application/json", status, msg);
+        Response::write_response(out, request, http_response.as_slice(), ack.to_json(nonce)?.as_bytes())
     }
 }
+
 //  Our "handler"
-impl Handler for TerrainUploadHandler {
+impl<P: ConnSource + Clone> Handler for TerrainUploadHandler<P> {
     fn handler(
         &mut self,
         out: &mut dyn Write,
         request: &Request,
         env: &HashMap<String, String>,
     ) -> Result<(), Error> {
+        //  Body complete. Start over for the next one.
+        self.stream_check = UploadStreamCheck::default();
+        //  Error 400, naming all of them, if params needed here are missing.
+        let mut required = RequiredParams::new(request);
+        let request_method = required.require("REQUEST_METHOD");
+        required.require("CONTENT_TYPE");
+        if let Err(e) = required.finish() {
+            let msg = format!("Incorrect request: {}", e);
+            return TerrainUploadHandler::write_ack(out, request, e.http_status().into(), &msg, &UploadAck::new_error(&msg));
+        }
         //  We have a request. It's supposed to be in JSON.
+        //  Error 415 if it says it isn't.
+        if let Err(e) = request.check_json_content() {
+            let msg = format!("Incorrect request: {}", e);
+            return TerrainUploadHandler::write_ack(out, request, e.http_status().into(), &msg, &UploadAck::new_error(&msg));
+        }
         //  Parse. Error 400 with message if fail.
-        match Self::parse_request(&request.standard_input, env) {
+        let parsed = TerrainUploadHandler::parse_request(&request.standard_input, env);
+        request.phase_timer().mark("parse");
+        match parsed {
             Ok(req) => {
                 log::info!("Request made: {:?} env {:?}", req, env);
-                let params = request
-                    .params
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("No HTTP parameters found"))?;
                 //  This must be a POST
-                if let Some(request_method) = params.get("REQUEST_METHOD") {  
-                    if request_method.to_uppercase().trim() != "POST" {             
-                        return Err(anyhow!("Request method \"{}\" was not POST.", request_method));
-                    }
-                } else {
-                    return Err(anyhow!("No HTTP request method."));
+                if request_method.to_uppercase().trim() != "POST" {
+                    return Err(anyhow!("Request method \"{}\" was not POST.", request_method));
+                }
+                //  Headers used here must have arrived intact. Error 400 if not. Others, like User-Agent, don't matter.
+                if let Some(key) = request.malformed_params().iter().find(|k| NEEDED_PARAM_PREFIXES.iter().any(|p| k.starts_with(p))) {
+                    let msg = format!("Incorrect request: {} is not UTF-8", key);
+                    return TerrainUploadHandler::write_ack(out, request, 400, &msg, &UploadAck::new_error(&msg));
                 }
-                //  Authorize
-                self.owner_name = Some(Authorizer::authorize(AuthorizeType::UploadTerrain, env, params)?);
-                //  Process. Error 500 if fail.
-                match self.process_request(req, &params) {
-                    Ok((status, msg)) => {
-                        //  Success. Send a plain "OK"
-                        let http_response = Response::http_response("text/plain", status, "OK");
-                        //  Return something useful.
-                        let b = msg.into_bytes();
-                        Response::write_response(out, request, http_response.as_slice(), &b)?;
+                //  Authorize. Error 401 if fail. Nothing is touched in the database before this.
+                let owner_name = match Authorizer::authorize_signed(AuthorizeType::UploadTerrain, request, |k| self.secrets.get_fresh(k)) {
+                    Ok(owner_name) => owner_name,
+                    Err(e) => {
+                        //  Why is logged, not sent. The reply doesn't say which token was tried.
+                        self.metrics.observe_auth_failure();
+                        log::warn!("Terrain upload rejected: {}", e);
+                        let msg = "Not authorized";
+                        return TerrainUploadHandler::write_ack(out, request, 401, msg, &UploadAck::new_error(msg));
                     }
+                };
+                //  Grid must be the one the request came from. Error 403 if not.
+                if let Err(e) = RequestOrigin::new_from_request(request).check_grid(req.grid()) {
+                    let msg = format!("Wrong grid: {}", e);
+                    return TerrainUploadHandler::write_ack(out, request, 403, &msg, &UploadAck::new_error(&msg));
+                }
+                //  Not a replay. Error 409 if the nonce was used, 400 if missing or sent_at is too far off.
+                //  Only checked once the signature is good, so nobody else can use up a token's nonces.
+                let nonce = req.nonce();
+                let token_name = Authorizer::token_name(request).unwrap_or_default();
+                if let Err(e) = self.replay_guard.check(&token_name, nonce, req.sent_at(), unix_time_now()) {
+                    let msg = format!("Rejected: {}", e);
+                    return TerrainUploadHandler::write_ack_with_nonce(out, request, e.http_status().into(), &msg, &UploadAck::new_error(&msg), nonce);
+                }
+                request.phase_timer().mark("auth");
+                //  Process. Error 400 if the upload was bad, 500 if we failed.
+                //  Retried once if the database connection was lost.
+                let pool = self.pool.clone();
+                let result = with_conn(&pool, |conn| TerrainUploadHandler::process_request(conn, &req, &owner_name, &self.elev_tolerance));
+                request.phase_timer().mark("sql");
+                match result {
+                    Ok((status, ack)) => TerrainUploadHandler::write_ack_with_nonce(out, request, status, "OK", &ack, nonce)?,
                     Err(e) => {
-                        let http_response = Response::http_response(
-                            "text/plain",
-                            500,
-                            format!("Problem processing request: {:?}", e).as_str(),
-                        );
-                        Response::write_response(out, request, http_response.as_slice(), &[])?;
+                        self.metrics.observe_error(&e);
+                        let status = status_for(&e);
+                        //  Our failure, not the client's. The same upload may be retried.
+                        if let Some(nonce) = nonce.filter(|_| status >= 500) {
+                            self.replay_guard.forget(&token_name, nonce);
+                        }
+                        let msg = format!("Problem processing request: {}", e);
+                        TerrainUploadHandler::write_ack_with_nonce(out, request, status.into(), &msg, &UploadAck::new_error(&msg), nonce)?;
                     }
                 }
             }
             Err(e) => {
-                let http_response = Response::http_response(
-                    "text/plain",
-                    400,
-                    format!("Incorrect request: {:?}", e).as_str(),
-                );
-                //  Return something useful.
-                //////let b = format!("Env: {:?}\nParams: {:?}\n", env, request.params).into_bytes();
-                let b = [];
-                Response::write_response(out, request, http_response.as_slice(), &b)?;
+                let msg = format!("Incorrect request: {}", e);
+                TerrainUploadHandler::write_ack(out, request, e.http_status().into(), &msg, &UploadAck::new_error(&msg))?;
             }
         }
         Ok(())
     }
+
+    /// Check the body as it arrives. A big upload that isn't JSON fails on its first chunk,
+    /// long before LSL's 60 second HTTP timeout.
+    fn on_stdin_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        Ok(self.stream_check.add(chunk)?)
+    }
+
+    /// Rejected while arriving. Same 400 reply as a parse failure.
+    fn stdin_rejected(&mut self, out: &mut dyn Write, request: &Request, error: &Error) -> Result<(), Error> {
+        self.stream_check = UploadStreamCheck::default();
+        let msg = format!("Incorrect request: {}", error);
+        TerrainUploadHandler::write_ack(out, request, status_for(error).into(), &msg, &UploadAck::new_error(&msg))
+    }
+
+    /// Pick up rotated token secrets.
+    fn between_requests(&mut self) {
+        if let Err(e) = self.secrets.reload() {
+            log::warn!("Keeping previous credentials: {:?}", e);
+        }
+    }
 }
 
 /// Run the responder.
 pub fn run_responder() -> Result<(), Error> {
-    log::info!("Environment: {:?}", std::env::vars());
+    log::info!("Environment: {:?}", common::environment_names());
     //  Set up in and out sockets.
     //  Communication with the parent process is via a UNIX socket.
     //  This is a pain to set up, because UNIX sockets are badly mis-matched
     //  to parent/child process communication.
     //  See init_fcgi for how it is done.
     let listener = init_fcgi()?;
-    //  Accept a connection on the listener socket. This hooks up
-    //  input and output to the parent process.
-    let (socket, _addr) = listener.accept()?;
-    let outsocket = socket.try_clone()?;
-    let mut instream = std::io::BufReader::new(socket);
-    let mut outio = std::io::BufWriter::new(outsocket);
-    //  Connect to the database
-    let creds = Credentials::new(UPLOAD_CREDS_FILE)?;
-    //  Optional MySQL port number
-    let portnum = if let Some(port) = creds.get("DB_PORT") {
-        port.parse::<u16>()?
-    } else {
-        //  Use MySQL default
-        3306
-    };
-    let opts = mysql::OptsBuilder::new()
-        //  Dreamhost is still using old authentication
-        .secure_auth(false)
-        .ip_or_hostname(creds.get("DB_HOST"))
-        .tcp_port(portnum)
-        .user(creds.get("DB_USER"))
-        .pass(creds.get("DB_PASS"))
-        .db_name(creds.get("DB_NAME"));
-    drop(creds);
-    //////log::info!("Opts: {:?}", opts);
-    let pool = Pool::new(opts)?;
-    log::info!("Connected to database.");
-    let mut terrain_upload_handler = TerrainUploadHandler::new(pool)?;
-    //  Run the FCGI server.
-    common::run(&mut instream, &mut outio, &mut terrain_upload_handler)
+    //  Connect to the database. Secrets and connections follow changes to the credentials file,
+    //  so secrets can be rotated without a restart.
+    let secrets = Credentials::watch_with_env(UPLOAD_CREDS_FILE, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let pool = common::db::connect_watched(&secrets)?;
+    common::db::migrations::check_schema_if_enabled(&mut pool.get_conn()?);
+    let limits = RequestLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", UPLOAD_CREDS_FILE, e))?;
+    let connection_limits = ConnectionLimits::new_from_lookup(|k| secrets.get(k)).map_err(|e| anyhow!("{}: {}", UPLOAD_CREDS_FILE, e))?;
+    let metrics = Metrics::new_with_log_interval(Some(METRICS_LOG_INTERVAL)).with_limits(limits);
+    let mut terrain_upload_handler = TerrainUploadHandler::new(pool, secrets, metrics.clone())?;
+    //  Run the FCGI server, one connection from the parent process at a time.
+    common::serve(&listener, &mut terrain_upload_handler, &metrics, &connection_limits)
 }
 
 /// Main program
@@ -409,3 +715,318 @@ fn parse_terrain() {
     println!("Parsed JSON: {:?}", parsed);
     println!("Elevs: {:?}", parsed.get_unscaled_elevs());
 }
+
+#[test]
+fn parse_terrain_meters() {
+    //  The upload in parse_terrain, from an uploader sending llGetRegionCorner meters instead of region grid units.
+    const GRID_UNITS_JSON: &str = "{\"grid\":\"agni\",\"name\":\"Vallone\",\"scale\":1.092822,\"offset\":33.500740,\"water_lev\":20.000000,\"region_coords\":[1807,1199],\"elevs\":[\"E7CAACA3A5A8ACAEB0B2B5B9BDC0C4C5C5C3C0BDB9B6B3B2B2B3B4B7BBBFC3C7CBCED1D3\"]}";
+    const METERS_JSON: &str = "{\"grid\":\"agni\",\"name\":\"Vallone\",\"scale\":1.092822,\"offset\":33.500740,\"water_lev\":20.000000,\"region_coords\":[462592,306944],\"elevs\":[\"E7CAACA3A5A8ACAEB0B2B5B9BDC0C4C5C5C3C0BDB9B6B3B2B2B3B4B7BBBFC3C7CBCED1D3\"]}";
+    //  Both are stored in meters.
+    let from_grid_units = UploadedRegionInfo::parse(GRID_UNITS_JSON).expect("grid units");
+    let from_meters = UploadedRegionInfo::parse(METERS_JSON).expect("meters");
+    assert_eq!(from_grid_units.region_coords, [GlobalMeters(462592), GlobalMeters(306944)]);
+    assert_eq!(from_grid_units, from_meters);
+}
+
+#[test]
+fn test_upload_ack_json() {
+    use common::CoordUnits;
+    let region_info = UploadedRegionInfo { grid: "agni".to_string(), name: "Vallone".to_string(), scale: 1.0, offset: 0.0, water_lev: 20.0,
+        region_coords: [GlobalMeters(462592), GlobalMeters(306944)], region_coords_units: Some(CoordUnits::Meters), size: None, elevs: vec!["000102".to_string(), "030405".to_string()], nonce: None, sent_at: None, sample_spacing: None };
+    let ack = |change_status| UploadAck::new_region(&change_status, &region_info, 0.5).unwrap().to_json(None).unwrap();
+    assert_eq!(ack(ChangeStatus::None), r#"{"status":"inserted","grid":"agni","region":[462592,306944],"samples":[2,3],"row_age_days":0,"elev_tolerance":0.5}"#);
+    assert_eq!(ack(ChangeStatus::Changed(40)), r#"{"status":"updated","grid":"agni","region":[462592,306944],"samples":[2,3],"row_age_days":40,"elev_tolerance":0.5}"#);
+    assert_eq!(ack(ChangeStatus::NoChange(7)), r#"{"status":"unchanged","grid":"agni","region":[462592,306944],"samples":[2,3],"row_age_days":7,"elev_tolerance":0.5}"#);
+    assert_eq!(ack(ChangeStatus::Renamed(3)), r#"{"status":"renamed","grid":"agni","region":[462592,306944],"samples":[2,3],"row_age_days":3,"elev_tolerance":0.5}"#);
+    let deletion = RegionDeletion { grid: "agni".to_string(), region_coords: [GlobalMeters(462592), GlobalMeters(306944)], region_coords_units: Some(CoordUnits::Meters), deleted: true, nonce: None, sent_at: None };
+    assert_eq!(UploadAck::new_deleted(&deletion).to_json(None).unwrap(), r#"{"status":"deleted","grid":"agni","region":[462592,306944]}"#);
+    assert_eq!(UploadAck::new_error("No such region").to_json(None).unwrap(), r#"{"status":"error","reason":"No such region"}"#);
+    //  The upload's nonce is echoed, after the rest.
+    assert_eq!(UploadAck::new_deleted(&deletion).to_json(Some("9f3c1a")).unwrap(),
+        r#"{"status":"deleted","grid":"agni","region":[462592,306944],"nonce":"9f3c1a"}"#);
+    assert_eq!(UploadAck::new_error("Nonce \"9f3c1a\" was already used").to_json(Some("9f3c1a")).unwrap(),
+        r#"{"status":"error","reason":"Nonce \"9f3c1a\" was already used","nonce":"9f3c1a"}"#);
+}
+
+#[test]
+fn test_upload_ack_size() {
+    use common::CoordUnits;
+    //  Huge names, worst case for JSON escaping, still fit.
+    let long = "\u{1}\"".repeat(5000);
+    let region_info = UploadedRegionInfo { grid: long.clone(), name: long.clone(), scale: 1.0, offset: 0.0, water_lev: 20.0,
+        region_coords: [GlobalMeters(u32::MAX), GlobalMeters(u32::MAX)], region_coords_units: Some(CoordUnits::Meters), size: None, elevs: vec!["00".repeat(256); 256], nonce: None, sent_at: None, sample_spacing: None };
+    let acks = [
+        UploadAck::new_region(&ChangeStatus::Changed(u32::MAX), &region_info, f32::MAX).unwrap(),
+        UploadAck::new_deleted(&RegionDeletion { grid: long.clone(), region_coords: [GlobalMeters(u32::MAX), GlobalMeters(u32::MAX)], region_coords_units: Some(CoordUnits::Meters), deleted: true, nonce: None, sent_at: None }),
+        UploadAck::new_error(&format!("Region \"{}\" is bad", long)),
+    ];
+    for ack in acks {
+        let json = ack.to_json(Some(&long)).expect("ack too big");
+        assert!(json.len() <= MAX_ACK_BYTES, "{} bytes", json.len());
+    }
+}
+
+#[test]
+fn test_upload_error_status() {
+    use common::CoordUnits;
+    //  Bad requests are the client's fault.
+    for body in [&b""[..], &b"  \n"[..], &[0xff, 0xfe][..]] {
+        let e = TerrainUploadHandler::parse_request(body, &HashMap::new()).err().expect("bad request accepted");
+        assert!(matches!(e, common::Error::BadRequest(_)), "{:?}", e);
+        assert_eq!(e.http_status(), 400);
+    }
+    //  So is bad elevation data found while processing.
+    let region_info = UploadedRegionInfo { grid: "agni".to_string(), name: "Vallone".to_string(), scale: 1.0, offset: 0.0, water_lev: 20.0,
+        region_coords: [GlobalMeters(462592), GlobalMeters(306944)], region_coords_units: Some(CoordUnits::Meters), size: None, elevs: vec!["00ZZ".to_string(), "0102".to_string()], nonce: None, sent_at: None, sample_spacing: None };
+    let e: Error = region_info.get_elevs_as_blob().map_err(Error::from).err().expect("bad hex accepted");
+    assert_eq!(status_for(&e.context("Region upload")), 400);
+}
+
+#[test]
+fn test_upload_decision_flow() {
+    use common::CoordUnits;
+    use common::db::{DbRow, DbValue, FakeDb};
+    let height_field = HeightField::new_from_fn(4, 4, 256, 256, 20.0, |x, y| 20.0 + (x * 4 + y) as f32).unwrap();
+    let region_info = UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(1024), GlobalMeters(2048)).name("Vallone")
+        .from_height_field(&height_field).build().unwrap();
+    //  The stored row, as SQL_SELECT in do_sql_unchanged_check returns it.
+    let stored_with_water = |name: &str, age: u32, water_level: f64| {
+        let samples = region_info.get_samples().unwrap();
+        DbRow(vec![DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(samples[0] as u64), DbValue::UInt(samples[1] as u64),
+            DbValue::Float(region_info.elev_scale() as f64), DbValue::Float(region_info.offset as f64),
+            DbValue::Bytes(region_info.get_elevs_as_blob().unwrap()), DbValue::text(name), DbValue::Float(water_level), DbValue::Int(age as i64),
+            DbValue::Null, DbValue::Null])
+    };
+    let stored = |name: &str, age: u32| stored_with_water(name, age, 20.0);
+    let upload = TerrainUpload::Region(region_info.clone());
+    //  New region is inserted.
+    let mut fake = FakeDb::default();
+    let (status, ack) = TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(status, 201);
+    assert!(matches!(ack, UploadAck::Inserted(RegionAck { row_age_days: 0, .. })));
+    //  Checked and written in one transaction, with the row locked.
+    let sql = fake.sql();
+    assert_eq!(sql.len(), 4, "{:?}", sql);
+    assert_eq!((sql[0], sql[3]), ("START TRANSACTION", "COMMIT"));
+    assert!(sql[1].starts_with("SELECT") && sql[1].ends_with("FOR UPDATE"), "{:?}", sql);
+    assert!(sql[1].contains("WHERE grid = :grid AND region_loc_x"), "{:?}", sql);
+    assert!(sql[2].starts_with("INSERT INTO raw_terrain_heights") && sql[2].contains("ON DUPLICATE KEY UPDATE"), "{:?}", sql);
+    //  Same terrain is confirmed.
+    let mut fake = FakeDb::new_with_results(vec![vec![stored("Vallone", 12)]]);
+    let (status, ack) = TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(status, 200);
+    assert!(matches!(ack, UploadAck::Unchanged(RegionAck { row_age_days: 12, .. })));
+    assert!(fake.sql()[2].contains("SET confirmation_time = NOW(), confirmer = :confirmer"), "{:?}", fake.sql());
+    assert_eq!(fake.sql()[3], "COMMIT");
+    //  Same terrain, new name, is confirmed and renamed, impostors included.
+    let mut fake = FakeDb::new_with_results(vec![vec![stored("Old Name", 40)]]);
+    let (status, ack) = TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(status, 200);
+    assert!(matches!(ack, UploadAck::Renamed(RegionAck { row_age_days: 40, .. })));
+    assert!(fake.sql()[2].contains("SET confirmation_time = NOW(), confirmer = :confirmer"), "{:?}", fake.sql());
+    assert!(fake.sql()[3].starts_with("UPDATE raw_terrain_heights SET name = :name"), "{:?}", fake.sql());
+    assert!(fake.sql()[5].starts_with("UPDATE region_impostors SET name = :name"), "{:?}", fake.sql());
+    assert_eq!(fake.sql()[6], "COMMIT");
+    //  Different terrain replaces the row, whether or not the name changed.
+    for name in ["Vallone", "Old Name"] {
+        let mut fake = FakeDb::new_with_results(vec![vec![stored_with_water(name, 40, 25.0)]]);
+        let (status, ack) = TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).unwrap();
+        assert_eq!(status, 200);
+        assert!(matches!(ack, UploadAck::Updated(RegionAck { row_age_days: 40, .. })), "{}", name);
+        assert!(fake.sql()[2].contains("ON DUPLICATE KEY UPDATE"), "{:?}", fake.sql());
+        assert_eq!(fake.statements.len(), 4);
+    }
+    //  Deleting a region which isn't there is 404, and changes nothing.
+    let deletion = TerrainUpload::Deletion(RegionDeletion { grid: "agni".to_string(), region_coords: [GlobalMeters(1024), GlobalMeters(2048)], region_coords_units: Some(CoordUnits::Meters), deleted: true, nonce: None, sent_at: None });
+    let mut fake = FakeDb::default();
+    let (status, _) = TerrainUploadHandler::process_request(&mut fake, &deletion, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(status, 404);
+    assert_eq!(fake.statements.len(), 1);
+    //  Database errors are returned, and the transaction is rolled back.
+    for fail_on in ["INSERT", "FOR UPDATE"] {
+        let mut fake = FakeDb { fail_on: Some(fail_on.to_string()), ..Default::default() };
+        assert!(TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).is_err());
+        let sql = fake.sql();
+        assert_eq!(sql.last().copied(), Some("ROLLBACK"), "{:?}", sql);
+        assert!(!sql.iter().any(|s| *s == "COMMIT"), "{:?}", sql);
+    }
+    let mut fake = FakeDb { fail_on: Some("region_impostors".to_string()), ..FakeDb::new_with_results(vec![vec![stored("Old Name", 40)]]) };
+    assert!(TerrainUploadHandler::process_request(&mut fake, &upload, "uploader", &ElevTolerance::default()).is_err());
+    assert_eq!(fake.sql().last().copied(), Some("ROLLBACK"));
+}
+
+#[test]
+fn test_upload_lock_conflict_retry() {
+    use common::db::FakeDb;
+    use mysql::Params;
+    //  Inserts lose a deadlock this many times, then work.
+    struct Deadlocking {
+        fake: FakeDb,
+        deadlocks: usize,
+    }
+    impl Db for Deadlocking {
+        fn exec_drop(&mut self, sql: &str, params: Params) -> Result<u64, Error> {
+            let affected = self.fake.exec_drop(sql, params)?;
+            if sql.trim_start().starts_with("INSERT") && self.deadlocks > 0 {
+                self.deadlocks -= 1;
+                let deadlock = mysql::MySqlError { state: "40001".to_string(), message: "Deadlock found when trying to get lock".to_string(), code: 1213 };
+                return Err(Error::from(mysql::Error::MySqlError(deadlock)));
+            }
+            Ok(affected)
+        }
+        fn exec_rows(&mut self, sql: &str, params: Params) -> Result<Vec<common::db::DbRow>, Error> {
+            self.fake.exec_rows(sql, params)
+        }
+        fn start_transaction(&mut self) -> Result<(), Error> {
+            self.fake.start_transaction()
+        }
+        fn commit(&mut self) -> Result<(), Error> {
+            self.fake.commit()
+        }
+        fn rollback(&mut self) -> Result<(), Error> {
+            self.fake.rollback()
+        }
+    }
+    let height_field = HeightField::new_from_fn(4, 4, 256, 256, 20.0, |x, y| 20.0 + (x * 4 + y) as f32).unwrap();
+    let region_info = UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(1024), GlobalMeters(2048)).name("Vallone")
+        .from_height_field(&height_field).build().unwrap();
+    let upload = TerrainUpload::Region(region_info);
+    //  Two concurrent first uploads. This one loses once, and is run again from the start.
+    let mut db = Deadlocking { fake: FakeDb::default(), deadlocks: 1 };
+    let (status, _) = TerrainUploadHandler::process_request(&mut db, &upload, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(status, 201);
+    let sql = db.fake.sql();
+    assert_eq!(sql.iter().filter(|s| **s == "ROLLBACK").count(), 1, "{:?}", sql);
+    assert_eq!(sql.iter().filter(|s| **s == "START TRANSACTION").count(), 2, "{:?}", sql);
+    assert_eq!(sql.last().copied(), Some("COMMIT"));
+    //  One that keeps losing gives up, after MAX_UPLOAD_ATTEMPTS.
+    let mut db = Deadlocking { fake: FakeDb::default(), deadlocks: usize::MAX };
+    let e = TerrainUploadHandler::process_request(&mut db, &upload, "uploader", &ElevTolerance::default()).unwrap_err();
+    assert!(is_lock_conflict(&e));
+    assert_eq!(db.fake.sql().iter().filter(|s| **s == "ROLLBACK").count(), MAX_UPLOAD_ATTEMPTS);
+    assert!(!db.fake.sql().contains(&"COMMIT"));
+}
+
+#[test]
+fn test_change_status_matrix() {
+    assert!(matches!(ChangeStatus::for_stored(true, true, 5), ChangeStatus::NoChange(5)));
+    assert!(matches!(ChangeStatus::for_stored(true, false, 5), ChangeStatus::Renamed(5)));
+    assert!(matches!(ChangeStatus::for_stored(false, true, 5), ChangeStatus::Changed(5)));
+    assert!(matches!(ChangeStatus::for_stored(false, false, 5), ChangeStatus::Changed(5)));
+}
+
+#[test]
+fn test_mixed_case_grid() {
+    use common::CoordUnits;
+    use common::db::{DbRow, DbValue, FakeDb};
+    use mysql::Params;
+    let grid_param = |params: &Params| match params {
+        Params::Named(named) => DbValue::from(named.get("grid".as_bytes()).expect("grid").clone()),
+        _ => panic!("Expected named parameters"),
+    };
+    let height_field = HeightField::new_from_fn(3, 3, 256, 256, 20.0, |x, y| 20.0 + (x + y) as f32).unwrap();
+    let region_info = UploadedRegionInfo::builder().grid(" AGNI").coords(GlobalMeters(1024), GlobalMeters(2048)).name("Vallone")
+        .from_height_field(&height_field).build().unwrap();
+    //  Uploaded as " AGNI", checked and stored as "agni".
+    let mut fake = FakeDb::default();
+    TerrainUploadHandler::process_request(&mut fake, &TerrainUpload::Region(region_info.clone()), "uploader", &ElevTolerance::default()).unwrap();
+    let stored_grid = grid_param(&fake.statements[2].1);
+    assert_eq!(stored_grid, DbValue::text("agni"));
+    assert_eq!(grid_param(&fake.statements[1].1), stored_grid);
+    //  A download for "agni" looks up the same row.
+    let row = DbRow(vec![DbValue::UInt(256), DbValue::UInt(256), DbValue::text("Vallone"), DbValue::UInt(3), DbValue::UInt(3),
+        DbValue::Float(1.0), DbValue::Float(20.0), DbValue::Bytes(vec![0; 9]), DbValue::Float(20.0), DbValue::text("uploader"),
+        DbValue::Null, DbValue::Null]);
+    let mut download = FakeDb::new_with_results(vec![vec![row]]);
+    let found = RawTerrainHeights::get(&mut download, "agni", [1024, 2048]).unwrap().expect("row");
+    assert_eq!(grid_param(&download.statements[0].1), stored_grid);
+    assert_eq!(found.grid, "agni");
+    //  Deletions too.
+    let deletion = TerrainUpload::Deletion(RegionDeletion { grid: "Agni".to_string(), region_coords: [GlobalMeters(1024), GlobalMeters(2048)], region_coords_units: Some(CoordUnits::Meters), deleted: true, nonce: None, sent_at: None });
+    let mut fake = FakeDb::default();
+    TerrainUploadHandler::process_request(&mut fake, &deletion, "uploader", &ElevTolerance::default()).unwrap();
+    assert_eq!(grid_param(&fake.statements[0].1), stored_grid);
+}
+
+#[test]
+fn test_elev_tolerance_config() {
+    let lookup = |pairs: &[(&str, &str)]| {
+        let map: HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |k: &str| map.get(k).cloned()
+    };
+    assert_eq!(ElevTolerance::new_from_lookup(lookup(&[])).unwrap(), ElevTolerance::default());
+    let tolerance = ElevTolerance::new_from_lookup(lookup(&[("ELEV_TOLERANCE", " 0.4 "), ("ELEV_TOLERANCE_GRIDS", "OSGrid=0.1, agni = 1.0,")])).unwrap();
+    assert_eq!(tolerance.for_grid("osgrid"), 0.1);
+    assert_eq!(tolerance.for_grid("Agni"), 1.0);
+    assert_eq!(tolerance.for_grid("aditi"), 0.4);
+    //  Only overrides. The default stays.
+    let tolerance = ElevTolerance::new_from_lookup(lookup(&[("ELEV_TOLERANCE_GRIDS", "agni=1")])).unwrap();
+    assert_eq!(tolerance.for_grid("aditi"), ElevTolerance::DEFAULT_TOLERANCE);
+    for bad in [[("ELEV_TOLERANCE", "half")], [("ELEV_TOLERANCE", "-1")], [("ELEV_TOLERANCE_GRIDS", "agni")], [("ELEV_TOLERANCE_GRIDS", "agni=NaN")]] {
+        assert!(ElevTolerance::new_from_lookup(lookup(&bad)).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_elev_tolerance_flapping() {
+    use common::db::{DbRow, DbValue, FakeDb};
+    //  Stored terrain, and re-surveys with noise just over the default tolerance, alternating in sign.
+    let terrain = |noise: f32| HeightField::new_from_fn(9, 9, 256, 256, 20.0,
+        |x, y| 20.0 + (x + y) as f32 + if (x + y) % 2 == 0 { noise } else { -noise }).unwrap();
+    let stored = terrain(0.0);
+    let (scale, offset, rows) = stored.into_sculpt_array().unwrap();
+    let stored_row = || DbRow(vec![DbValue::UInt(256), DbValue::UInt(256), DbValue::UInt(9), DbValue::UInt(9),
+        DbValue::Float(scale as f64), DbValue::Float(offset as f64), DbValue::Bytes(rows.concat()),
+        DbValue::text("Vallone"), DbValue::Float(20.0), DbValue::Int(3), DbValue::Null, DbValue::Null]);
+    let upload = |noise: f32| TerrainUpload::Region(UploadedRegionInfo::builder().grid("agni").coords(GlobalMeters(1024), GlobalMeters(2048)).name("Vallone")
+        .from_height_field(&terrain(noise)).build().unwrap());
+    let mainland = ElevTolerance::new_from_lookup(|k| (k == "ELEV_TOLERANCE_GRIDS").then(|| "agni=1.0".to_string())).unwrap();
+    for noise in [0.6, -0.6, 0.6] {
+        //  Default tolerance, every re-survey is a change.
+        let mut fake = FakeDb::new_with_results(vec![vec![stored_row()]]);
+        let (_, ack) = TerrainUploadHandler::process_request(&mut fake, &upload(noise), "bot", &ElevTolerance::default()).unwrap();
+        assert!(matches!(ack, UploadAck::Updated(RegionAck { elev_tolerance: 0.5, .. })), "{:?}", ack);
+        //  With the override, it's the same terrain every time.
+        let mut fake = FakeDb::new_with_results(vec![vec![stored_row()]]);
+        let (_, ack) = TerrainUploadHandler::process_request(&mut fake, &upload(noise), "bot", &mainland).unwrap();
+        assert!(matches!(ack, UploadAck::Unchanged(RegionAck { elev_tolerance: 1.0, .. })), "{:?}", ack);
+    }
+    //  Noise well under the default tolerance is unchanged either way.
+    let mut fake = FakeDb::new_with_results(vec![vec![stored_row()]]);
+    let (_, ack) = TerrainUploadHandler::process_request(&mut fake, &upload(0.2), "bot", &ElevTolerance::default()).unwrap();
+    assert!(matches!(ack, UploadAck::Unchanged(_)), "{:?}", ack);
+}
+
+#[test]
+fn test_upload_stream_check() {
+    //  Good JSON, split every which way, including inside a multibyte character.
+    let body = " \n{\"grid\":\"agni\",\"name\":\"K\u{00e4}se\u{1F600}\"}".as_bytes();
+    for chunk_size in [1, 2, 3, 7, body.len()] {
+        let mut check = UploadStreamCheck::default();
+        for chunk in body.chunks(chunk_size) {
+            check.add(chunk).unwrap_or_else(|e| panic!("chunk size {}: {}", chunk_size, e));
+        }
+        assert!(check.started);
+        assert!(check.utf8_carry.is_empty());
+    }
+    //  White space alone decides nothing yet.
+    let mut check = UploadStreamCheck::default();
+    check.add(b"  \r\n").unwrap();
+    assert!(!check.started);
+    check.add(b"{").unwrap();
+    //  Not JSON, rejected on the first chunk of many.
+    let html = vec![b"<html><body>".to_vec(); 100];
+    let mut check = UploadStreamCheck::default();
+    let e = check.add(&html[0]).expect_err("HTML accepted");
+    assert_eq!(e.http_status(), 400);
+    assert!(e.to_string().contains("does not start with a JSON object"), "{}", e);
+    //  A JSON array isn't ours either, and nor is a byte order mark, which the final parse would refuse.
+    assert!(UploadStreamCheck::default().add("\u{feff}{}".as_bytes()).is_err());
+    assert!(TerrainUploadHandler::parse_request("\u{feff}{}".as_bytes(), &HashMap::new()).is_err());
+    assert!(UploadStreamCheck::default().add(b"[1,2]").is_err());
+    //  Bad UTF-8 found in the chunk it arrives in, with its position.
+    let mut check = UploadStreamCheck::default();
+    check.add(b"{\"grid\":").unwrap();
+    let e = check.add(b"\"ag\xFFni\"}").expect_err("bad UTF-8 accepted");
+    assert!(e.to_string().contains("not UTF-8 at byte 11"), "{}", e);
+}
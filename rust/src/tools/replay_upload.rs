@@ -0,0 +1,154 @@
+//! replay_upload -- run saved terrain uploads through the upload handler.
+//! Part of the Animats impostor system
+//!
+//! Debugging uploadterrain used to need an in-world script. This reads
+//! upload JSON saved from a script, or from the upload log, and sends it
+//! to TerrainUploadHandler through the FCGI request loop, in process, with
+//! the headers the simulator and Apache would have added:
+//!
+//!     replay_upload --credentials /home/maptools/upload_credentials.txt --token UPLOADER_1 vallone.json
+//!
+//! The handler uses the database in the credentials file, so use a test database.
+//! The token's secret is AUTH_name from the same file, unless --secret is given.
+//! The region header is the uploaded region, as if the script were in it.
+//!
+//! For load and race testing, --loop sends each file again and again, and
+//! --jitter waits a random time first. Run several copies at once for races.
+//! Uploads with a nonce are rejected as replays after the first.
+//!
+//! The status and body of each reply go to stdout.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     April, 2026.
+//
+#![forbid(unsafe_code)]
+use anyhow::{Error, anyhow};
+use log::LevelFilter;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use common::{Credentials, SimulatedHeaders, TerrainUpload, fcgi_transaction, read_reply};
+use common::metrics::Metrics;
+
+//  The handler under test, built in here.
+#[path = "../server/uploadterrain.rs"]
+#[allow(dead_code)]
+mod uploadterrain;
+
+/// Default credentials file, as uploadterrain uses.
+const DEFAULT_CREDS_FILE: &str = "upload_credentials.txt";
+/// Default owner name, as sent in X-SecondLife-Owner-Name.
+const DEFAULT_OWNER_NAME: &str = "Replay Uploader";
+/// Default longest random wait before each send, milliseconds.
+const DEFAULT_JITTER_MS: u64 = 250;
+/// Request ID. Anything but 0, which is for management records.
+const REQUEST_ID: u16 = 1;
+
+/// Random enough for spreading out sends. Xorshift, seeded from the clock.
+struct Jitter {
+    /// Longest wait
+    max: Duration,
+    /// Generator state. Never zero.
+    state: u64,
+}
+
+impl Jitter {
+    /// Usual new.
+    fn new(max: Duration) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0) ^ std::process::id() as u64;
+        Self { max, state: seed | 1 }
+    }
+
+    /// Wait a random time, up to max.
+    fn wait(&mut self) {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let max_ms = self.max.as_millis() as u64;
+        if max_ms > 0 {
+            std::thread::sleep(Duration::from_millis(self.state % (max_ms + 1)));
+        }
+    }
+}
+
+/// Headers for one upload. The region header is the uploaded region, if the body says which.
+fn headers_for(base: &SimulatedHeaders, body: &str) -> SimulatedHeaders {
+    let uploaded = match TerrainUpload::parse(body) {
+        Ok(TerrainUpload::Region(region_info)) => Some((region_info.name.clone(), [region_info.region_coords[0].meters(), region_info.region_coords[1].meters()])),
+        _ => None,
+    };
+    SimulatedHeaders { region: base.region.clone().or(uploaded), ..base.clone() }
+}
+
+/// Send everything, and print the replies.
+fn run(args: &[String]) -> Result<(), Error> {
+    let mut opts = getopts::Options::new();
+    opts.optopt("c", "credentials", &format!("Credentials file, default {}.", DEFAULT_CREDS_FILE), "FILE");
+    opts.optopt("o", "owner", &format!("Object owner name, default \"{}\".", DEFAULT_OWNER_NAME), "NAME");
+    opts.optopt("s", "shard", "Shard the request comes from, such as Production. Default none, so the grid isn't checked.", "SHARD");
+    opts.optopt("t", "token", "Sign with this token, from AUTH_name in the credentials.", "NAME");
+    opts.optopt("", "secret", "Sign with this secret instead of the token's own.", "SECRET");
+    opts.optopt("n", "loop", "Send each file this many times. Default 1.", "N");
+    opts.optflagopt("j", "jitter", &format!("Wait up to this long before each send. Default {} ms.", DEFAULT_JITTER_MS), "MS");
+    opts.optflag("v", "verbose", "Log to stderr in detail.");
+    let matches = opts.parse(&args[1..])?;
+    if matches.free.is_empty() {
+        return Err(anyhow!("{}", opts.usage(&format!("Usage: {} [options] UPLOAD.json...", args[0]))));
+    }
+    let _ = simplelog::SimpleLogger::init(if matches.opt_present("verbose") { LevelFilter::Debug } else { LevelFilter::Warn }, simplelog::Config::default());
+    let creds_file = matches.opt_str("credentials").unwrap_or_else(|| DEFAULT_CREDS_FILE.to_string());
+    let loops: usize = matches.opt_str("loop").map(|s| s.parse().map_err(|_| anyhow!("Loop count \"{}\" is not a number", s))).transpose()?.unwrap_or(1);
+    let mut jitter = if matches.opt_present("jitter") {
+        let ms: u64 = matches.opt_str("jitter").map(|s| s.parse().map_err(|_| anyhow!("Jitter \"{}\" is not milliseconds", s))).transpose()?.unwrap_or(DEFAULT_JITTER_MS);
+        Some(Jitter::new(Duration::from_millis(ms)))
+    } else {
+        None
+    };
+    let secrets = Credentials::watch_with_env(&creds_file, common::db::CREDENTIALS_ENV_PREFIX)?;
+    let token = match matches.opt_str("token") {
+        Some(token_name) => {
+            let secret = matches.opt_str("secret").or_else(|| secrets.get(&format!("AUTH_{}", token_name.to_uppercase())))
+                .ok_or_else(|| anyhow!("{}: no secret for token \"{}\"", creds_file, token_name))?;
+            Some((token_name, secret))
+        }
+        None => None,
+    };
+    let base = SimulatedHeaders {
+        owner_name: matches.opt_str("owner").unwrap_or_else(|| DEFAULT_OWNER_NAME.to_string()),
+        shard: matches.opt_str("shard"),
+        region: None,
+        token,
+    };
+    let bodies = matches.free.iter().map(|path| Ok((path, std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path, e))?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    //  Same setup as uploadterrain, but no FCGI socket.
+    let pool = common::db::connect_watched(&secrets)?;
+    let metrics = Metrics::new();
+    let mut handler = uploadterrain::TerrainUploadHandler::new(pool, secrets, metrics.clone())?;
+    for n in 0..loops {
+        for (path, body) in &bodies {
+            if let Some(jitter) = jitter.as_mut() {
+                jitter.wait();
+            }
+            let params = headers_for(&base, body).post_params(body.as_bytes());
+            let params: Vec<(&str, &str)> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            let start = Instant::now();
+            let mut out = Vec::new();
+            let transaction = fcgi_transaction(REQUEST_ID, &params, body.as_bytes());
+            common::run_with_metrics(&mut std::io::Cursor::new(transaction), &mut out, &mut handler, &metrics)?;
+            let reply = read_reply(&mut std::io::Cursor::new(out), REQUEST_ID)?;
+            println!("{} #{}: Status {}, {:.3} secs", path, n + 1, reply.status, start.elapsed().as_secs_f64());
+            println!("{}", String::from_utf8_lossy(&reply.body));
+        }
+    }
+    eprintln!("{:?}", metrics.snapshot());
+    Ok(())
+}
+
+/// Main program
+pub fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,305 @@
+//! integration.rs -- upload, generate, and download, end to end, without a web server.
+//! Part of the Animats impostor system
+//!
+//! Terrain for one region goes in as a complete FCGI transaction, is stored,
+//! is made into a sculpt, is recorded as a new impostor, and comes back out
+//! as the JSON the viewer gets. The database is FakeDb, so no MySQL server is needed.
+//!
+//! The FCGI handlers and the generator are binaries, so this can't link them.
+//! The upload handler is compiled in here, and gets its connections from a
+//! SharedFakeDb. Download goes through the same library calls its handler makes.
+//! The generator modules which only use the library are compiled in too.
+//!
+//! The echo example's handler is compiled in too, and run over a socket pair
+//! against the client side encoder, to check minifcgi end to end. It's also
+//! served from a listener, to check that a stalled connection is dropped.
+//!
+//!     License: LGPL.
+//!     Animats
+//!     March, 2026.
+//
+use anyhow::{anyhow, Error};
+use common::db::{Db, DbRow, DbValue, FakeDb, FromDbValue, SharedFakeDb};
+use common::{fcgi_record, fcgi_transaction, read_reply, GlobalMeters, HeightField, RawTerrainHeights, RegionData, RegionImpostorData, RegionImpostorReply, ReplyFormatter,
+    SimulatedHeaders, UploadedRegionInfo, REGION_IMPOSTOR_COLUMNS};
+use mysql::Params;
+use std::io::Write;
+
+#[path = "../src/generator/sculptmaker.rs"]
+#[allow(dead_code)]
+mod sculptmaker;
+#[path = "../src/generator/initialimpostors.rs"]
+#[allow(dead_code)]
+mod initialimpostors;
+#[path = "../src/examples/echo/echohandler.rs"]
+#[allow(dead_code)]
+mod echohandler;
+#[path = "../src/server/uploadterrain.rs"]
+#[allow(dead_code)]
+mod uploadterrain;
+
+use initialimpostors::{InitialImpostors, assemble_region_impostor_data};
+use sculptmaker::{SCULPTDIM, TerrainSculpt};
+
+/// FCGI record types used here.
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_STDOUT: u8 = 6;
+
+/// The HTTP response in FCGI output, as header and body.
+/// Checks that the transaction was ended.
+fn fcgi_response(mut out: &[u8]) -> Result<(String, String), Error> {
+    let mut stdout = Vec::new();
+    let mut ended = false;
+    while !out.is_empty() {
+        if out.len() < 8 {
+            return Err(anyhow!("Short FCGI header"));
+        }
+        let content_length = u16::from_be_bytes([out[4], out[5]]) as usize;
+        let record_length = 8 + content_length + out[6] as usize;
+        let content = out.get(8..8 + content_length).ok_or_else(|| anyhow!("Short FCGI record"))?;
+        match out[1] {
+            FCGI_STDOUT => stdout.extend(content),
+            FCGI_END_REQUEST => ended = true,
+            rec_type => return Err(anyhow!("Unexpected FCGI record type {}", rec_type)),
+        }
+        out = out.get(record_length..).ok_or_else(|| anyhow!("Short FCGI padding"))?;
+    }
+    if !ended {
+        return Err(anyhow!("No FCGI end of request"));
+    }
+    let stdout = String::from_utf8(stdout)?;
+    let (header, body) = stdout.split_once("\n\n").ok_or_else(|| anyhow!("No end of HTTP header: {}", stdout))?;
+    Ok((header.to_string(), body.to_string()))
+}
+
+/// A named parameter of a recorded statement.
+fn param<T: FromDbValue>(params: &Params, name: &str) -> Result<T, Error> {
+    let value = match params {
+        Params::Named(named) => named.get(name.as_bytes()).ok_or_else(|| anyhow!("No parameter {}", name))?,
+        _ => return Err(anyhow!("No named parameters")),
+    };
+    T::from_db_value(&DbValue::from(value.clone())).map_err(|e| anyhow!("Parameter {}: {}", name, e))
+}
+
+/// Parameters of the one recorded statement starting with this.
+fn statement_params<'a>(db: &'a FakeDb, prefix: &str) -> &'a Params {
+    let found: Vec<_> = db.statements.iter().filter(|(sql, _)| sql.starts_with(prefix)).collect();
+    assert_eq!(found.len(), 1, "{:?}", db.sql());
+    &found[0].1
+}
+
+/// The test region. Sloped, with a bump, so every elevation differs.
+fn test_height_field() -> HeightField {
+    HeightField::new_from_fn(32, 32, 256, 256, 20.0, |x, y| 15.0 + x as f32 + 0.5 * y as f32 + if (x, y) == (20, 10) { 8.0 } else { 0.0 })
+        .expect("height field")
+}
+
+#[test]
+fn test_upload_generate_download() {
+    const GRID: &str = "Agni";
+    const OWNER: &str = "Test Uploader";
+    const SCULPT_UUID: &str = "64604b5c-461e-dd72-52a9-3d464abf78aa";
+    const SECRET: &str = "integration secret";
+    let mut region_info = UploadedRegionInfo::builder().grid(GRID).coords(GlobalMeters(256000), GlobalMeters(256512)).name("Ahern")
+        .from_height_field(&test_height_field()).build().expect("upload");
+    region_info.nonce = Some("integration-1".to_string());
+    region_info.sent_at = Some(common::unix_time_now());
+    let uploaded_height_field = region_info.to_height_field().expect("uploaded height field");
+    let workdir = std::env::temp_dir().join(format!("integration_{}", std::process::id()));
+    std::fs::create_dir_all(&workdir).unwrap();
+
+    //  Upload. One signed FCGI transaction, through the real request loop and the uploadterrain handler.
+    let creds_path = workdir.join("upload_credentials.txt");
+    std::fs::write(&creds_path, format!("AUTH_INTEGRATION = {}\n", SECRET)).unwrap();
+    let secrets = common::Credentials::watch(creds_path.to_str().unwrap()).expect("credentials");
+    let upload_db = SharedFakeDb::default();
+    let mut upload = uploadterrain::TerrainUploadHandler::new(upload_db.clone(), secrets, common::metrics::Metrics::new()).expect("handler");
+    let body = region_info.to_json().expect("upload JSON");
+    //  A token that isn't in the credentials gets a plain 401, which doesn't name it, and touches nothing.
+    let guessed = SimulatedHeaders { owner_name: OWNER.to_string(), token: Some(("guessed".to_string(), "guess".to_string())), ..Default::default() };
+    let params = guessed.post_params(body.as_bytes());
+    let params: Vec<(&str, &str)> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let mut out = Vec::new();
+    common::run(&mut std::io::Cursor::new(fcgi_transaction(1, &params, body.as_bytes())), &mut out, &mut upload).expect("FCGI run");
+    let (header, reply) = fcgi_response(&out).expect("FCGI response");
+    assert!(header.starts_with("Status: 401"), "{} {}", header, reply);
+    assert!(reply.contains("Not authorized") && !reply.contains("guessed"), "{}", reply);
+    assert!(upload_db.lock().sql().is_empty());
+    let headers = SimulatedHeaders { owner_name: OWNER.to_string(), token: Some(("integration".to_string(), SECRET.to_string())), ..Default::default() };
+    let params = headers.post_params(body.as_bytes());
+    let params: Vec<(&str, &str)> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let mut out = Vec::new();
+    common::run(&mut std::io::Cursor::new(fcgi_transaction(1, &params, body.as_bytes())), &mut out, &mut upload).expect("FCGI run");
+    let (header, reply) = fcgi_response(&out).expect("FCGI response");
+    assert!(header.starts_with("Status: 201"), "{} {}", header, reply);
+    let ack: serde_json::Value = serde_json::from_str(&reply).expect("ack JSON");
+    assert_eq!(ack["nonce"], "integration-1", "{}", reply);
+    //  Region was checked for, with its row locked, then inserted, in one transaction.
+    let upload_db = upload_db.lock();
+    let sql = upload_db.sql();
+    assert_eq!(sql.len(), 4, "{:?}", sql);
+    assert_eq!((sql[0], sql[3]), ("START TRANSACTION", "COMMIT"));
+    assert!(sql[1].starts_with("SELECT") && sql[1].ends_with("FOR UPDATE"), "{}", sql[1]);
+
+    //  The stored blob reads back as the uploaded terrain.
+    let stored_params = statement_params(&upload_db, "INSERT INTO raw_terrain_heights");
+    let stored = RawTerrainHeights {
+        grid: param(stored_params, "grid").unwrap(),
+        region_loc: [param(stored_params, "region_loc_x").unwrap(), param(stored_params, "region_loc_y").unwrap()],
+        region_size: [param(stored_params, "region_size_x").unwrap(), param(stored_params, "region_size_y").unwrap()],
+        name: param(stored_params, "name").unwrap(),
+        samples: [param(stored_params, "samples_x").unwrap(), param(stored_params, "samples_y").unwrap()],
+        scale: param(stored_params, "scale").unwrap(),
+        offset: param(stored_params, "offset").unwrap(),
+        elevs: param(stored_params, "elevs").unwrap(),
+        water_level: param(stored_params, "water_level").unwrap(),
+        creator: param(stored_params, "creator").unwrap(),
+    };
+    assert_eq!(stored, RawTerrainHeights::new_from_uploaded(&region_info, OWNER).unwrap());
+    let height_field = stored.height_field().expect("stored height field");
+    assert!(height_field.diff(&uploaded_height_field, 0.001).unwrap().is_unchanged());
+
+    //  Generate. Sculpt as generateterrain makes it, saved as PNG.
+    let region = RegionData { grid: stored.grid.clone(), name: stored.name.clone(), region_loc_x: stored.region_loc[0], region_loc_y: stored.region_loc[1],
+        region_size_x: stored.region_size[0], region_size_y: stored.region_size[1], lod: 0, children: Vec::new(), is_water: false, is_placeholder: false };
+    let mut terrain_sculpt = TerrainSculpt::new(&region.name, SCULPTDIM);
+    let (scale, offset, elevs) = height_field.into_sculpt_array().unwrap();
+    terrain_sculpt.setelevs(elevs, scale as f64, offset as f64);
+    terrain_sculpt.makeimage();
+    let sculpt_hash = terrain_sculpt.get_hash().unwrap();
+    let sculpt_path = workdir.join(format!("{:08x}.png", sculpt_hash));
+    terrain_sculpt.image.take().expect("sculpt image").save(&sculpt_path).expect("sculpt save");
+    let sculpt_image = image::open(&sculpt_path).expect("sculpt read");
+    assert_eq!((sculpt_image.width(), sculpt_image.height()), (SCULPTDIM as u32, SCULPTDIM as u32));
+
+    //  Record the new impostor.
+    let impostor = assemble_region_impostor_data(&region, &height_field, 1, Some(format!("{:08x}", sculpt_hash)), None, Vec::new(),
+        common::DEFAULT_LOD_QUALITY).unwrap();
+    let mut generate_db = FakeDb::default();
+    InitialImpostors::clear_grid(&mut generate_db, GRID).unwrap();
+    InitialImpostors::add_group(&mut generate_db, std::slice::from_ref(&impostor), &[]).unwrap();
+    assert_eq!(generate_db.sql().last(), Some(&"COMMIT"));
+
+    //  Download. The row is what was recorded, plus the UUID the asset upload fills in,
+    //  as promote copies it to region_impostors.
+    let recorded_params = statement_params(&generate_db, "INSERT INTO initial_impostors");
+    let row = DbRow(REGION_IMPOSTOR_COLUMNS.split(',').map(|column| match column.trim() {
+        "sculpt_uuid" => DbValue::text(SCULPT_UUID),
+        "creation_time" => DbValue::text("2026-03-01 00:00:00"),
+        column => param::<DbValue>(recorded_params, column).expect("recorded column"),
+    }).collect());
+    let mut download_db = FakeDb::new_with_results(vec![vec![row]]);
+    let db: &mut dyn Db = &mut download_db;
+    let impostors = db.exec_map(&format!("SELECT {} FROM region_impostors", REGION_IMPOSTOR_COLUMNS), Params::Empty,
+        |row| RegionImpostorData::from_db_row(&row)).expect("download rows");
+    let reply = RegionImpostorReply { version: 0, impostors, errors: Vec::new() };
+    let json = ReplyFormatter::new(2).unwrap().format(&reply).expect("reply JSON");
+    let value: serde_json::Value = serde_json::from_str(&json).expect("reply parse");
+    let downloaded = &value["impostors"][0];
+    let floats = |v: &serde_json::Value| -> Vec<f32> { v.as_array().expect("array").iter().map(|f| f.as_f64().unwrap() as f32).collect() };
+    let (scale_z, elevation_offset) = height_field.get_scale_offset().unwrap();
+    assert_eq!(downloaded["grid"], "agni");
+    assert_eq!(downloaded["name"], "Ahern");
+    assert_eq!(floats(&downloaded["region_loc"]), vec![256000.0, 256512.0]);
+    assert_eq!(floats(&downloaded["scale"]), vec![256.0, 256.0, scale_z]);
+    assert_eq!(downloaded["elevation_offset"].as_f64().unwrap() as f32, elevation_offset);
+    assert_eq!(downloaded["water_height"].as_f64().unwrap() as f32, 20.0);
+    assert_eq!(downloaded["sculpt_uuid"], SCULPT_UUID);
+    assert_eq!(downloaded["impostor_lod"], 0);
+    assert_eq!(downloaded["lod_distance"].as_f64().unwrap() as f32, common::lod_distance([256.0, 256.0, scale_z], common::DEFAULT_LOD_QUALITY));
+    std::fs::remove_dir_all(&workdir).unwrap();
+}
+
+#[test]
+fn test_echo_over_socket() {
+    use echohandler::{EchoHandler, generated_bytes};
+    use std::io::BufReader;
+    use std::os::unix::net::UnixStream;
+    use std::time::{Duration, Instant};
+    let (client, server) = UnixStream::pair().expect("socket pair");
+    let responder = std::thread::spawn(move || -> Result<(), Error> {
+        let mut out = server.try_clone()?;
+        common::run(&mut BufReader::new(server), &mut out, &mut EchoHandler::new())
+    });
+    let mut to_server = client.try_clone().expect("clone");
+    let mut from_server = BufReader::new(client);
+    //  One transaction at a time, as a web server does on one connection.
+    let mut transact = |id: u16, query: &str, headers: &[(&str, &str)], body: &[u8]| {
+        let mut params = vec![("REQUEST_METHOD", if body.is_empty() { "GET" } else { "POST" }), ("QUERY_STRING", query)];
+        params.extend_from_slice(headers);
+        to_server.write_all(&fcgi_transaction(id, &params, body)).expect("send");
+        read_reply(&mut from_server, id).expect("reply")
+    };
+    //  A body, over many Stdin records, comes back byte for byte.
+    let body: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 256) as u8).collect();
+    let reply = transact(1, "", &[], &body);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.header("Content-Type"), Some("application/octet-stream; charset=utf-8"));
+    assert_eq!(reply.header("X-Echo-Tally"), Some("1"));
+    assert!(reply.body == body, "body differs");
+    //  Any status.
+    assert_eq!(transact(2, "status=418", &[], b"").status, 418);
+    //  Generated data, in 2048 byte Stdout records, between the header records and the end record.
+    let reply = transact(3, "size=100000", &[], b"");
+    assert!(reply.body == generated_bytes(100_000), "generated body differs");
+    assert_eq!(reply.stdout_records, 2 + 100_000usize.div_ceil(2048) + 1);
+    //  A slow handler.
+    let start = Instant::now();
+    assert_eq!(transact(4, "sleep=50", &[], b"").status, 200);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    //  A param long enough for a 4 byte length.
+    let long = "x".repeat(300);
+    let reply = transact(5, "", &[("HTTP_X_LONG", &long)], b"");
+    assert!(String::from_utf8_lossy(&reply.body).contains(&format!("(\"HTTP_X_LONG\", \"{}\")", long)));
+    assert_eq!(reply.header("X-Echo-Tally"), Some("5"));
+    assert_eq!(transact(6, "status=1000", &[], b"").status, 400);
+    //  End of input ends the responder cleanly.
+    to_server.shutdown(std::net::Shutdown::Write).expect("shutdown");
+    responder.join().expect("responder panicked").expect("responder");
+}
+
+#[test]
+fn test_stalled_connection() {
+    use common::metrics::{ConnectionLimits, Metrics};
+    use echohandler::EchoHandler;
+    use std::io::{BufReader, Read};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::{Duration, Instant};
+    const FCGI_STDIN: u8 = 5;
+    let path = std::env::temp_dir().join(format!("maptools-stall-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).expect("bind");
+    let limits = ConnectionLimits { read_timeout: Some(Duration::from_millis(200)), max_request_bytes: Some(4096) };
+    //  Serves until the test process ends.
+    std::thread::spawn(move || common::serve(&listener, &mut EchoHandler::new(), &Metrics::new(), &limits));
+    //  A client which gives up, rather than hangs, if the server does.
+    let connect = || {
+        let stream = UnixStream::connect(&path).expect("connect");
+        stream.set_read_timeout(Some(Duration::from_secs(10))).expect("timeout");
+        stream
+    };
+    let start = Instant::now();
+    //  First peer declares 1000 bytes of body, sends 10, and stalls.
+    let mut stalled = connect();
+    let head = fcgi_transaction(1, &[("REQUEST_METHOD", "POST")], b"");
+    let partial = fcgi_record(FCGI_STDIN, 1, &[b'x'; 1000]);
+    stalled.write_all(&[&head[..head.len() - 8], &partial[..8 + 10]].concat()).expect("send");
+    //  The next connection waits for that one to time out, then is served.
+    let mut next = connect();
+    next.write_all(&fcgi_transaction(2, &[("REQUEST_METHOD", "GET"), ("QUERY_STRING", "status=200")], b"")).expect("send");
+    let reply = read_reply(&mut BufReader::new(next.try_clone().unwrap()), 2).expect("reply after stall");
+    assert_eq!(reply.status, 200);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    //  The stalled peer got no reply, just a closed connection.
+    let mut rest = Vec::new();
+    stalled.read_to_end(&mut rest).expect("stalled connection closed");
+    assert!(rest.is_empty(), "{} bytes sent to stalled peer", rest.len());
+    //  A request over the size limit is refused before its body is read in, and the server goes on.
+    let mut big = connect();
+    big.write_all(&fcgi_transaction(3, &[("REQUEST_METHOD", "POST")], &[b'y'; 10_000])).expect("send");
+    assert_eq!(read_reply(&mut BufReader::new(big), 3).expect("too large reply").status, 413);
+    let mut last = connect();
+    last.write_all(&fcgi_transaction(4, &[("REQUEST_METHOD", "GET"), ("QUERY_STRING", "status=418")], b"")).expect("send");
+    assert_eq!(read_reply(&mut BufReader::new(last), 4).expect("reply").status, 418);
+    let _ = std::fs::remove_file(&path);
+}